@@ -0,0 +1,285 @@
+//! Streaming path benchmarks
+//!
+//! Runs the proxy binary in front of its own synthetic upstream
+//! (`--bench-server`) and reports:
+//!
+//! - pass-through overhead per MiB streamed, against fetching directly from
+//!   the synthetic upstream
+//! - cache-hit latency for small blobs
+//! - range serving throughput from the cache, single and multi-range
+//!
+//! ```sh
+//! cargo bench --bench streaming
+//! ```
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+const MIB: u64 = 1024 * 1024;
+const STREAM_SIZE: u64 = 64 * MIB;
+const STREAM_ITERATIONS: usize = 5;
+const SMALL_BLOB: u64 = 4096;
+const LATENCY_ITERATIONS: usize = 200;
+const RANGE_ITERATIONS: usize = 200;
+const RANGE_LEN: u64 = MIB;
+
+/// A child process killed on drop, with its scratch directory
+struct Process {
+    child: Child,
+    dir: Option<PathBuf>,
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if let Some(dir) = &self.dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .map(|a| a.port())
+        .expect("no free port")
+}
+
+fn start_upstream() -> (Process, String) {
+    let addr = format!("127.0.0.1:{}", free_port());
+    let child = Command::new(env!("CARGO_BIN_EXE_docker-proxy"))
+        .args(["--bench-server", &addr])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start bench server");
+    (Process { child, dir: None }, format!("http://{}", addr))
+}
+
+fn start_proxy(upstream: &str, cache: bool) -> (Process, String) {
+    let port = free_port();
+    let dir = std::env::temp_dir().join(format!("docker-proxy-bench-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(dir.join("config")).unwrap();
+
+    let mut config = format!(
+        "[server]\nhost = \"127.0.0.1\"\nport = {}\n\n[log]\nlogFilePath = \"{}\"\nlevel = \"warn\"\n\n[proxy]\ndefault = \"{}\"\n\n[auth]\nghcr-token = \"\"\n",
+        port,
+        dir.join("proxy.log").display(),
+        upstream
+    );
+    if cache {
+        config.push_str(&format!(
+            "\n[cache]\nenabled = true\ndir = \"{}\"\nmax_size_mb = 0\n",
+            dir.join("cache").display()
+        ));
+    }
+    std::fs::write(dir.join("config/config.toml"), config).unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_docker-proxy"))
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start docker-proxy");
+    (
+        Process {
+            child,
+            dir: Some(dir),
+        },
+        format!("http://127.0.0.1:{}", port),
+    )
+}
+
+async fn wait_ready(client: &reqwest::Client, url: &str) {
+    for _ in 0..100 {
+        if client.get(url).send().await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{} did not become ready", url);
+}
+
+/// Digest of the synthetic blob of `size` bytes (byte `i` is `i % 251`)
+fn synthetic_digest(size: u64) -> String {
+    let mut hasher = Sha256::new();
+    let chunk: Vec<u8> = (0..251 * 256).map(|i| (i % 251) as u8).collect();
+    let mut left = size;
+    while left > 0 {
+        let len = left.min(chunk.len() as u64) as usize;
+        hasher.update(&chunk[..len]);
+        left -= len as u64;
+    }
+    format!("sha256:{}", hex::encode(hasher.finalize()))
+}
+
+fn blob_url(base: &str, size: u64) -> String {
+    format!(
+        "{}/v2/bench/{}/blobs/{}",
+        base,
+        size,
+        synthetic_digest(size)
+    )
+}
+
+/// Download `url` fully, returning the number of body bytes
+async fn fetch(client: &reqwest::Client, url: &str, range: Option<&str>) -> u64 {
+    let mut req = client.get(url);
+    if let Some(range) = range {
+        req = req.header("Range", range);
+    }
+    let mut resp = req.send().await.expect("request failed");
+    assert!(resp.status().is_success(), "{} -> {}", url, resp.status());
+    let mut total = 0;
+    while let Some(chunk) = resp.chunk().await.expect("body failed") {
+        total += chunk.len() as u64;
+    }
+    total
+}
+
+/// Pull `url` through the proxy and wait until it is served from the cache
+/// (the cache entry is committed after the response has been sent)
+async fn warm_cache(client: &reqwest::Client, url: &str) {
+    fetch(client, url, None).await;
+    for _ in 0..100 {
+        let resp = client
+            .get(url)
+            .header("Range", "bytes=0-0")
+            .send()
+            .await
+            .expect("request failed");
+        if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} was not cached", url);
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    sorted[index]
+}
+
+// Deterministic pseudo-random offsets (LCG) so runs are comparable
+fn offsets(count: usize, max: u64) -> Vec<u64> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..count)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 11) % max
+        })
+        .collect()
+}
+
+async fn throughput(client: &reqwest::Client, url: &str) -> f64 {
+    let start = Instant::now();
+    for _ in 0..STREAM_ITERATIONS {
+        assert_eq!(fetch(client, url, None).await, STREAM_SIZE);
+    }
+    start.elapsed().as_secs_f64() / STREAM_ITERATIONS as f64
+}
+
+async fn bench_passthrough(client: &reqwest::Client, upstream: &str, proxy: &str) {
+    let direct = throughput(client, &blob_url(upstream, STREAM_SIZE)).await;
+    let proxied = throughput(client, &blob_url(proxy, STREAM_SIZE)).await;
+    let mib = (STREAM_SIZE / MIB) as f64;
+    println!("pass-through ({} MiB x {})", mib, STREAM_ITERATIONS);
+    println!("  direct    {:>9.1} MiB/s", mib / direct);
+    println!("  proxied   {:>9.1} MiB/s", mib / proxied);
+    println!(
+        "  overhead  {:>9.3} ms/MiB",
+        (proxied - direct).max(0.0) * 1000.0 / mib
+    );
+}
+
+async fn bench_cache_hits(client: &reqwest::Client, proxy: &str) {
+    let url = blob_url(proxy, SMALL_BLOB);
+    warm_cache(client, &url).await;
+
+    let mut samples = Vec::with_capacity(LATENCY_ITERATIONS);
+    for _ in 0..LATENCY_ITERATIONS {
+        let start = Instant::now();
+        assert_eq!(fetch(client, &url, None).await, SMALL_BLOB);
+        samples.push(start.elapsed());
+    }
+    samples.sort();
+    println!(
+        "cache hit latency ({} B x {})",
+        SMALL_BLOB, LATENCY_ITERATIONS
+    );
+    for (label, p) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+        println!(
+            "  {}       {:>9.1} us",
+            label,
+            percentile(&samples, p).as_secs_f64() * 1e6
+        );
+    }
+}
+
+async fn bench_ranges(client: &reqwest::Client, proxy: &str) {
+    let url = blob_url(proxy, STREAM_SIZE);
+    warm_cache(client, &url).await;
+
+    let starts = offsets(RANGE_ITERATIONS, STREAM_SIZE - RANGE_LEN);
+    let start = Instant::now();
+    for offset in &starts {
+        let range = format!("bytes={}-{}", offset, offset + RANGE_LEN - 1);
+        assert_eq!(fetch(client, &url, Some(&range)).await, RANGE_LEN);
+    }
+    let single = start.elapsed().as_secs_f64();
+
+    let quarter = RANGE_LEN / 4;
+    let start = Instant::now();
+    for offset in &starts {
+        let range = (0..4)
+            .map(|i| {
+                // gaps between the parts keep them from being merged
+                let from = offset / 2 + i * quarter * 2;
+                format!("{}-{}", from, from + quarter - 1)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        fetch(client, &url, Some(&format!("bytes={}", range))).await;
+    }
+    let multi = start.elapsed().as_secs_f64();
+
+    let n = RANGE_ITERATIONS as f64;
+    let mib = (RANGE_LEN / MIB) as f64;
+    println!("cached ranges ({} x {} MiB)", RANGE_ITERATIONS, mib);
+    println!(
+        "  single    {:>9.1} req/s {:>9.1} MiB/s",
+        n / single,
+        n * mib / single
+    );
+    println!("  4 ranges  {:>9.1} req/s", n / multi);
+}
+
+fn main() {
+    // `cargo test --benches` runs bench targets once as smoke tests; skip the work there
+    if !std::env::args().any(|a| a == "--bench") {
+        return;
+    }
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let client = reqwest::Client::builder().no_gzip().build().unwrap();
+        let (_upstream_process, upstream) = start_upstream();
+        let (_direct_process, direct_proxy) = start_proxy(&upstream, false);
+        let (_cache_process, cache_proxy) = start_proxy(&upstream, true);
+        for url in [&upstream, &direct_proxy, &cache_proxy] {
+            wait_ready(&client, &format!("{}/v2/", url)).await;
+        }
+
+        bench_passthrough(&client, &upstream, &direct_proxy).await;
+        bench_cache_hits(&client, &cache_proxy).await;
+        bench_ranges(&client, &cache_proxy).await;
+    });
+}
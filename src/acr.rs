@@ -0,0 +1,426 @@
+/// Authentication with Azure Container Registry upstreams
+///
+/// ACR registries (`<name>.azurecr.io`) issue their bearer tokens for a
+/// refresh token, which the registry's `/oauth2/exchange` endpoint grants for
+/// a Microsoft Entra (AAD) access token. The proxy obtains the AAD token with
+/// the Azure SDKs' environment variables: a service principal from
+/// `AZURE_TENANT_ID`, `AZURE_CLIENT_ID` and `AZURE_CLIENT_SECRET` (or
+/// `AZURE_FEDERATED_TOKEN_FILE`, for workload identity), or else the managed
+/// identity of the VM. The refresh token is then used as the password of
+/// the all-zero GUID user in the registry's usual token flow, and cached
+/// until shortly before it expires.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use serde::Deserialize;
+
+use crate::clock::Clock;
+use crate::config::RegistryCredentials;
+
+/// User name ACR expects with a refresh token as password
+const REFRESH_TOKEN_USER: &str = "00000000-0000-0000-0000-000000000000";
+/// A refresh token is replaced once less than this much of its lifetime is left
+const REFRESH_MARGIN_SECS: u64 = 5 * 60;
+/// Lifetime assumed for refresh tokens without a readable expiry
+const DEFAULT_LIFETIME_SECS: u64 = 60 * 60;
+/// After a failed exchange, the next attempt waits at least this long
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(30);
+const ARM_RESOURCE: &str = "https://management.azure.com/";
+const DEFAULT_AUTHORITY_HOST: &str = "https://login.microsoftonline.com";
+const DEFAULT_IMDS_ENDPOINT: &str = "http://169.254.169.254";
+const IMDS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether `host` is an Azure Container Registry
+pub fn is_acr_host(host: &str) -> bool {
+    host.strip_suffix(".azurecr.io")
+        .is_some_and(|name| !name.is_empty() && !name.contains(['/', ':']))
+}
+
+/// How the AAD access token is obtained
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Identity {
+    /// A service principal with a client secret
+    Secret {
+        tenant: String,
+        client_id: String,
+        secret: String,
+    },
+    /// Workload identity: a federated token read from a file on each use
+    Federated {
+        tenant: String,
+        client_id: String,
+        token_file: String,
+    },
+    /// The managed identity from the instance metadata service, optionally
+    /// a user-assigned one
+    Managed { client_id: Option<String> },
+}
+
+#[derive(Default)]
+struct RefreshTokens {
+    /// Refresh token and expiry (Unix seconds) by registry host
+    valid: HashMap<String, (String, u64)>,
+    /// When an exchange for a registry host last failed
+    failed: HashMap<String, Instant>,
+}
+
+pub struct AcrAuth {
+    client: reqwest::Client,
+    clock: Arc<dyn Clock>,
+    identity: Identity,
+    /// Tenant passed to the exchange, when known
+    tenant: Option<String>,
+    authority_host: String,
+    imds_endpoint: String,
+    tokens: Mutex<RefreshTokens>,
+    /// Held while exchanging, so concurrent pulls share one exchange
+    refresh: tokio::sync::Mutex<()>,
+}
+
+impl AcrAuth {
+    /// Provider reading its settings from the process environment
+    pub fn new(client: reqwest::Client, clock: Arc<dyn Clock>) -> Self {
+        Self::with_env(client, clock, |name| std::env::var(name).ok())
+    }
+
+    fn with_env(
+        client: reqwest::Client,
+        clock: Arc<dyn Clock>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let env = |name: &str| env(name).filter(|value| !value.is_empty());
+        let tenant = env("AZURE_TENANT_ID");
+        let client_id = env("AZURE_CLIENT_ID");
+        let identity = match (&tenant, &client_id) {
+            (Some(tenant), Some(client_id)) => {
+                match (
+                    env("AZURE_CLIENT_SECRET"),
+                    env("AZURE_FEDERATED_TOKEN_FILE"),
+                ) {
+                    (Some(secret), _) => Identity::Secret {
+                        tenant: tenant.clone(),
+                        client_id: client_id.clone(),
+                        secret,
+                    },
+                    (None, Some(token_file)) => Identity::Federated {
+                        tenant: tenant.clone(),
+                        client_id: client_id.clone(),
+                        token_file,
+                    },
+                    (None, None) => Identity::Managed {
+                        client_id: Some(client_id.clone()),
+                    },
+                }
+            }
+            _ => Identity::Managed { client_id },
+        };
+        Self {
+            client,
+            clock,
+            identity,
+            tenant,
+            authority_host: env("AZURE_AUTHORITY_HOST")
+                .unwrap_or_else(|| DEFAULT_AUTHORITY_HOST.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            imds_endpoint: env("AZURE_POD_IDENTITY_AUTHORITY_HOST")
+                .unwrap_or_else(|| DEFAULT_IMDS_ENDPOINT.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            tokens: Mutex::new(RefreshTokens::default()),
+            refresh: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Credentials for the token endpoint of `host`; `None` for hosts that
+    /// are not ACR registries or when the exchange fails
+    pub async fn credentials(&self, host: &str) -> Option<RegistryCredentials> {
+        if !is_acr_host(host) {
+            return None;
+        }
+        let refresh_token = match self.cached(host) {
+            Some(token) => token,
+            None => self.exchange(host).await?,
+        };
+        Some(RegistryCredentials {
+            username: REFRESH_TOKEN_USER.to_string(),
+            password: refresh_token,
+        })
+    }
+
+    /// Forget the refresh token of `host` after the registry rejected it
+    pub fn invalidate(&self, host: &str) {
+        self.lock().valid.remove(host);
+    }
+
+    fn cached(&self, host: &str) -> Option<String> {
+        let now = self.clock.now_secs();
+        self.lock()
+            .valid
+            .get(host)
+            .filter(|(_, expires_at)| now + REFRESH_MARGIN_SECS < *expires_at)
+            .map(|(token, _)| token.clone())
+    }
+
+    async fn exchange(&self, host: &str) -> Option<String> {
+        let _refresh = self.refresh.lock().await;
+        if let Some(token) = self.cached(host) {
+            return Some(token);
+        }
+        if self.lock().failed.get(host).is_some_and(|failed| {
+            self.clock.instant().duration_since(*failed) < RETRY_AFTER_FAILURE
+        }) {
+            return None;
+        }
+        match self.request_refresh_token(host).await {
+            Ok(token) => {
+                tracing::info!(registry = %host, "Obtained ACR refresh token");
+                let expires_at = expiry_of(&token)
+                    .unwrap_or_else(|| self.clock.now_secs() + DEFAULT_LIFETIME_SECS);
+                let mut tokens = self.lock();
+                tokens.failed.remove(host);
+                tokens
+                    .valid
+                    .insert(host.to_string(), (token.clone(), expires_at));
+                Some(token)
+            }
+            Err(e) => {
+                tracing::warn!(registry = %host, "ACR token exchange failed: {}", e);
+                self.lock()
+                    .failed
+                    .insert(host.to_string(), self.clock.instant());
+                None
+            }
+        }
+    }
+
+    // Exchange an AAD access token for a refresh token of the registry
+    async fn request_refresh_token(&self, host: &str) -> Result<String, String> {
+        #[derive(Deserialize)]
+        struct Response {
+            refresh_token: String,
+        }
+
+        let access_token = self.aad_token().await?;
+        let mut form = vec![
+            ("grant_type", "access_token"),
+            ("service", host),
+            ("access_token", access_token.as_str()),
+        ];
+        if let Some(tenant) = &self.tenant {
+            form.push(("tenant", tenant));
+        }
+        let response: Response = self
+            .client
+            .post(format!("https://{}/oauth2/exchange", host))
+            .form(&form)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response.refresh_token)
+    }
+
+    // Access token for Azure Resource Manager, which ACR accepts
+    async fn aad_token(&self) -> Result<String, String> {
+        #[derive(Deserialize)]
+        struct Response {
+            access_token: String,
+        }
+
+        let request = match &self.identity {
+            Identity::Secret {
+                tenant,
+                client_id,
+                secret,
+            } => self.client_credentials(
+                tenant,
+                &[
+                    ("client_id", client_id.as_str()),
+                    ("client_secret", secret.as_str()),
+                ],
+            ),
+            Identity::Federated {
+                tenant,
+                client_id,
+                token_file,
+            } => {
+                // the file is rotated by the platform, so it is read each time
+                let assertion = std::fs::read_to_string(token_file)
+                    .map_err(|e| format!("Failed to read {}: {}", token_file, e))?;
+                self.client_credentials(
+                    tenant,
+                    &[
+                        ("client_id", client_id.as_str()),
+                        ("client_assertion", assertion.trim()),
+                        (
+                            "client_assertion_type",
+                            "urn:ietf:params:oauth:client-assertion-type:jwt-bearer",
+                        ),
+                    ],
+                )
+            }
+            Identity::Managed { client_id } => {
+                let mut query = vec![("api-version", "2018-02-01"), ("resource", ARM_RESOURCE)];
+                if let Some(client_id) = client_id {
+                    query.push(("client_id", client_id));
+                }
+                self.client
+                    .get(format!(
+                        "{}/metadata/identity/oauth2/token",
+                        self.imds_endpoint
+                    ))
+                    .query(&query)
+                    .header("Metadata", "true")
+                    .timeout(IMDS_TIMEOUT)
+            }
+        };
+        let response: Response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("no Azure access token: {}", e))?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response.access_token)
+    }
+
+    fn client_credentials(&self, tenant: &str, fields: &[(&str, &str)]) -> reqwest::RequestBuilder {
+        let scope = format!("{}.default", ARM_RESOURCE);
+        let mut form = vec![("grant_type", "client_credentials"), ("scope", &scope)];
+        form.extend_from_slice(fields);
+        self.client
+            .post(format!(
+                "{}/{}/oauth2/v2.0/token",
+                self.authority_host, tenant
+            ))
+            .form(&form)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RefreshTokens> {
+        self.tokens.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// The `exp` claim of a JWT, read without verifying it: the token is only
+// passed back to the registry that issued it
+fn expiry_of(token: &str) -> Option<u64> {
+    #[derive(Deserialize)]
+    struct Claims {
+        exp: u64,
+    }
+
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    Some(serde_json::from_slice::<Claims>(&payload).ok()?.exp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn provider(clock: Arc<ManualClock>, env: &[(&str, &str)]) -> AcrAuth {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        AcrAuth::with_env(reqwest::Client::new(), clock, |name| env.get(name).cloned())
+    }
+
+    #[test]
+    fn test_is_acr_host() {
+        assert!(is_acr_host("contoso.azurecr.io"));
+        assert!(!is_acr_host("azurecr.io"));
+        assert!(!is_acr_host(".azurecr.io"));
+        assert!(!is_acr_host("contoso.azurecr.io.example.com"));
+        assert!(!is_acr_host("ghcr.io"));
+    }
+
+    #[test]
+    fn test_identity() {
+        let clock = ManualClock::new(0);
+        let secret = provider(
+            clock.clone(),
+            &[
+                ("AZURE_TENANT_ID", "tenant"),
+                ("AZURE_CLIENT_ID", "app"),
+                ("AZURE_CLIENT_SECRET", "secret"),
+                ("AZURE_FEDERATED_TOKEN_FILE", "/var/run/token"),
+            ],
+        );
+        assert!(matches!(secret.identity, Identity::Secret { .. }));
+        assert_eq!(secret.tenant.as_deref(), Some("tenant"));
+
+        let federated = provider(
+            clock.clone(),
+            &[
+                ("AZURE_TENANT_ID", "tenant"),
+                ("AZURE_CLIENT_ID", "app"),
+                ("AZURE_FEDERATED_TOKEN_FILE", "/var/run/token"),
+            ],
+        );
+        assert!(matches!(federated.identity, Identity::Federated { .. }));
+
+        let user_assigned = provider(clock.clone(), &[("AZURE_CLIENT_ID", "identity")]);
+        assert_eq!(
+            user_assigned.identity,
+            Identity::Managed {
+                client_id: Some("identity".to_string())
+            }
+        );
+        let system_assigned = provider(clock, &[]);
+        assert_eq!(
+            system_assigned.identity,
+            Identity::Managed { client_id: None }
+        );
+        assert_eq!(system_assigned.authority_host, DEFAULT_AUTHORITY_HOST);
+    }
+
+    #[test]
+    fn test_expiry_of() {
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(r#"{"exp":1700010800,"grant_type":"refresh_token"}"#);
+        assert_eq!(
+            expiry_of(&format!("eyJhbGciOiJSUzI1NiJ9.{}.c2ln", payload)),
+            Some(1_700_010_800)
+        );
+        assert_eq!(expiry_of("opaque-token"), None);
+    }
+
+    #[tokio::test]
+    async fn test_credentials() {
+        let host = "contoso.azurecr.io";
+        let clock = ManualClock::new(1_000_000);
+        // nothing listens on the metadata endpoint, so exchanges fail
+        let acr = provider(
+            clock.clone(),
+            &[("AZURE_POD_IDENTITY_AUTHORITY_HOST", "http://127.0.0.1:9")],
+        );
+        assert!(acr.credentials("ghcr.io").await.is_none());
+        assert!(acr.credentials(host).await.is_none());
+        assert!(acr.lock().failed.contains_key(host));
+
+        acr.lock()
+            .valid
+            .insert(host.to_string(), ("refresh".to_string(), 1_000_000 + 3600));
+        let credentials = acr.credentials(host).await.unwrap();
+        assert_eq!(credentials.username, REFRESH_TOKEN_USER);
+        assert_eq!(credentials.password, "refresh");
+
+        // close to expiry, a new refresh token is needed
+        clock.advance(Duration::from_secs(3600 - REFRESH_MARGIN_SECS));
+        assert!(acr.credentials(host).await.is_none());
+
+        acr.invalidate(host);
+        assert!(acr.lock().valid.is_empty());
+    }
+}
@@ -2,17 +2,43 @@ use std::sync::Arc;
 
 use axum::{
     body::Body,
-    extract::{Path, State},
+    extract::{Path, RawQuery, State},
     http::{HeaderMap, HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
 
+use futures_util::{Stream, StreamExt, stream};
+use sha2::{Digest, Sha256};
+use tokio_util::io::ReaderStream;
+
 use crate::{
-    error,
-    proxy::DockerProxy,
+    cache::{self, BlobCache},
+    chain, client_auth,
+    config::PolicyOperation,
+    connections, diagnose, egress, error, error_reporting, import, local_registry, prefetch,
+    proxy::{self, DigestVerifier, DockerProxy},
+    pull_stats, range, rate_limits,
     router::{self, V2Endpoint},
+    signing,
+    spill::{SpillBuffer, SpillPolicy, Spilled},
+    upstream_metrics,
 };
 
+/// Manifests larger than this are rejected on push (matches the distribution spec's 4 MiB limit)
+const MAX_MANIFEST_SIZE: usize = 4 * 1024 * 1024;
+
+/// Upstream headers relayed in answers to HEAD requests for blobs
+const BLOB_HEAD_HEADERS: &[&str] = &[
+    "content-type",
+    "content-length",
+    "docker-content-digest",
+    "accept-ranges",
+    "etag",
+    "last-modified",
+    "cache-control",
+    "expires",
+];
+
 // 验证Docker Registry V2 API
 pub async fn handle_v2_check() -> impl IntoResponse {
     let mut headers = HeaderMap::new();
@@ -24,13 +50,15 @@ pub async fn handle_v2_check() -> impl IntoResponse {
     (StatusCode::OK, headers)
 }
 
-// 健康检查：返回服务状态、版本信息和上游 registry 连通性
+// 健康检查：返回服务状态、版本信息、上游 registry 连通性和各上游报告的限流配额（如 Docker Hub 剩余拉取次数）。
+// 连通性取后台定期检查的结果（health_check_interval_secs），探针频繁调用时不会放大上游流量
 pub async fn healthz(State(proxy): State<Arc<DockerProxy>>) -> impl IntoResponse {
     use serde_json::json;
 
     const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-    let registry_healthy = proxy.check_registry_health().await;
+    let health = proxy.registry_health().await;
+    let registry_healthy = health.healthy;
     let registry_url = proxy.get_registry_url();
 
     let status = if registry_healthy {
@@ -44,21 +72,17 @@ pub async fn healthz(State(proxy): State<Arc<DockerProxy>>) -> impl IntoResponse
         StatusCode::SERVICE_UNAVAILABLE
     };
 
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_else(|e| {
-            tracing::warn!("System time error, using 0: {}", e);
-            std::time::Duration::from_secs(0)
-        })
-        .as_secs();
+    let timestamp = proxy.clock().now_secs();
 
     let response = json!({
         "status": status,
         "version": VERSION,
         "registry": {
             "url": registry_url,
-            "healthy": registry_healthy
+            "healthy": registry_healthy,
+            "checked_at": health.checked_at
         },
+        "rate_limits": proxy.rate_limits().snapshot(),
         "timestamp": timestamp
     });
 
@@ -69,6 +93,929 @@ pub async fn healthz(State(proxy): State<Arc<DockerProxy>>) -> impl IntoResponse
     )
 }
 
+// 上游认证失败统计：按上游汇总失败次数、类型和最近样本
+pub async fn auth_status(State(proxy): State<Arc<DockerProxy>>) -> impl IntoResponse {
+    use serde_json::json;
+
+    let timestamp = proxy.clock().now_secs();
+
+    let body = json!({
+        "upstreams": proxy.auth_monitor().snapshot(),
+        "timestamp": timestamp,
+    });
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+}
+
+// 进行中的 blob 上传会话及已接收字节数，供 Web 界面展示推送进度
+pub async fn uploads_status(State(proxy): State<Arc<DockerProxy>>) -> impl IntoResponse {
+    use serde_json::json;
+
+    let timestamp = proxy.clock().now_secs();
+
+    let body = json!({
+        "uploads": proxy.uploads().snapshot(),
+        "timestamp": timestamp,
+    });
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+}
+
+// 内置 token 服务：客户端用 Basic 凭据换取短期 Bearer token（docker 按 /v2/ 质询中的 realm 自动请求）。
+// scope 参数可重复，也可在一个参数内以空格分隔多个 scope
+pub async fn token(
+    State(proxy): State<Arc<DockerProxy>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let Some(auth) = proxy.client_auth().filter(|auth| auth.issues_tokens()) else {
+        return (StatusCode::NOT_FOUND, "Token service is not enabled").into_response();
+    };
+    // 未携带凭据时，按匿名规则签发只含允许匿名的 scope 的 token
+    let anonymous = proxy
+        .anonymous()
+        .filter(|_| !headers.contains_key(header::AUTHORIZATION));
+    let Some(user) = auth
+        .authenticate(&headers)
+        .or_else(|| anonymous.map(|_| String::new()))
+    else {
+        let body = serde_json::json!({
+            "errors": [{
+                "code": "UNAUTHORIZED",
+                "message": "authentication required",
+            }]
+        });
+        return (
+            StatusCode::UNAUTHORIZED,
+            [
+                (header::CONTENT_TYPE, "application/json".to_string()),
+                (header::WWW_AUTHENTICATE, auth.basic_challenge()),
+            ],
+            body.to_string(),
+        )
+            .into_response();
+    };
+    let scopes: Vec<String> =
+        reqwest::Url::parse(&format!("http://proxy/token?{}", query.unwrap_or_default()))
+            .map(|url| {
+                url.query_pairs()
+                    .filter(|(key, _)| key == "scope")
+                    .map(|(_, scope)| scope.into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+    let scopes = match anonymous {
+        Some(anonymous) => anonymous.narrow(&scopes),
+        None => scopes,
+    };
+    let Some(grant) = auth.issue_token(&user, &scopes) else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to sign token").into_response();
+    };
+    tracing::debug!(user = %user, scopes = ?scopes, "Issued client token");
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        serde_json::to_string(&grant).unwrap_or_default(),
+    )
+        .into_response()
+}
+
+// 运行统计：缓存占用、固定的 manifest 数量、故障期间返回的过期 manifest 数和进行中的上传数
+pub async fn stats(State(proxy): State<Arc<DockerProxy>>) -> impl IntoResponse {
+    use serde_json::json;
+
+    let timestamp = proxy.clock().now_secs();
+
+    let cache = proxy.cache().map(|cache| {
+        let (entries, bytes) = cache.usage();
+        json!({
+            "entries": entries,
+            "bytes": bytes,
+            "max_bytes": cache.max_size(),
+            "pinned_manifests": cache.pinned_manifests().len(),
+            "stale_manifests_served": cache.stale_served(),
+        })
+    });
+    let egress: serde_json::Map<_, _> = proxy
+        .egress()
+        .snapshot()
+        .into_iter()
+        .map(|(registry, egress)| {
+            let saved_ratio = egress.saved_ratio();
+            let mut value = json!(egress);
+            value["saved_ratio"] = json!(saved_ratio);
+            (registry, value)
+        })
+        .collect();
+    let body = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "registry": proxy.get_registry_url(),
+        "cache": cache,
+        "egress": egress,
+        "uploads": proxy.uploads().snapshot().len(),
+        "timestamp": timestamp,
+    });
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+}
+
+// Web 界面实时面板：最近一小时每分钟请求数、今日拉取最多的镜像、缓存用量与命中率、
+// 各上游 registry 的请求数、错误率、平均延迟，以及最近一次请求是否失败
+pub async fn dashboard(State(proxy): State<Arc<DockerProxy>>) -> impl IntoResponse {
+    use serde_json::json;
+
+    let per_minute = proxy.request_rates().per_minute();
+    // 当前分钟尚未结束，取上一个完整分钟
+    let last_minute = per_minute
+        .iter()
+        .rev()
+        .nth(1)
+        .map_or(0, |minute| minute.requests);
+
+    let top_images = proxy.pull_stats().map(|stats| {
+        let mut summary = stats.summary(1);
+        summary.truncate(10);
+        summary
+    });
+
+    let egress = proxy.egress().snapshot();
+    let cached: u64 = egress.values().map(|e| e.cached_bytes).sum();
+    let upstream: u64 = egress.values().map(|e| e.upstream_bytes).sum();
+    let cache = proxy.cache().map(|cache| {
+        let (entries, bytes) = cache.usage();
+        json!({
+            "entries": entries,
+            "bytes": bytes,
+            "max_bytes": cache.max_size(),
+            "served_bytes": cached,
+            "hit_ratio": if cached + upstream == 0 {
+                0.0
+            } else {
+                cached as f64 / (cached + upstream) as f64
+            },
+        })
+    });
+
+    let upstreams: Vec<_> = proxy
+        .upstream_metrics()
+        .snapshot()
+        .into_iter()
+        .map(|(registry, metrics)| {
+            let errors = metrics.total_errors();
+            json!({
+                "registry": registry,
+                "status": if metrics.last_error.is_some() { "degraded" } else { "healthy" },
+                "requests": metrics.count,
+                "errors": errors,
+                "error_rate": errors as f64 / metrics.count.max(1) as f64,
+                "avg_latency_ms": metrics.sum_secs * 1000.0 / metrics.count.max(1) as f64,
+                "last_error": metrics.last_error.map(|e| e.label()),
+            })
+        })
+        .collect();
+
+    let body = json!({
+        "requests": {
+            "last_minute": last_minute,
+            "per_minute": per_minute,
+        },
+        "top_images": top_images,
+        "cache": cache,
+        "upstreams": upstreams,
+        "timestamp": proxy.clock().now_secs(),
+    });
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+}
+
+// Prometheus 文本格式的指标：各上游 registry 来自缓存 / 上游的字节数与 manifest 拉取数，
+// 以及上游请求延迟直方图、错误计数（超时、网络错误、5xx、认证失败）、占用中的上游连接数、排队中的拉取数
+// 和上游报告的限流配额（剩余次数、429 次数、退避剩余秒数）；启用 [load_shedding] 时另有处理中的请求数、
+// 内存、文件描述符和被拒绝的低优先级请求数
+pub async fn metrics(State(proxy): State<Arc<DockerProxy>>) -> impl IntoResponse {
+    let mut body = egress::to_prometheus(&proxy.egress().snapshot());
+    body.push_str(&upstream_metrics::to_prometheus(
+        &proxy.upstream_metrics().snapshot(),
+    ));
+    body.push_str(&connections::to_prometheus(
+        &proxy.connections().snapshot(),
+        &proxy.connections().fetches(),
+    ));
+    body.push_str(&rate_limits::to_prometheus(&proxy.rate_limits().snapshot()));
+    if let Some(shedder) = proxy.load_shedder() {
+        body.push_str(&shedder.to_prometheus());
+    }
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        body,
+    )
+}
+
+// 按仓库导出拉取次数、流量和独立客户端数，range 为天数（d）或周数（w），默认 30d
+// 调用示例：
+//   curl '/api/stats/export?format=csv&range=30d' -o pulls.csv
+pub async fn stats_export(
+    State(proxy): State<Arc<DockerProxy>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let Some(stats) = proxy.pull_stats() else {
+        return (StatusCode::NOT_FOUND, "Pull statistics are disabled").into_response();
+    };
+    let range = params.get("range").map(String::as_str).unwrap_or("30d");
+    let Some(days) = pull_stats::parse_range(range) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Invalid 'range', expected e.g. 30d or 4w",
+        )
+            .into_response();
+    };
+    let rows = stats.summary(days);
+
+    match params.get("format").map(String::as_str).unwrap_or("csv") {
+        "csv" => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"pulls-{}.csv\"", range),
+                ),
+            ],
+            pull_stats::to_csv(&rows),
+        )
+            .into_response(),
+        "json" => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/json")],
+            serde_json::to_string(&rows).unwrap_or_default(),
+        )
+            .into_response(),
+        _ => (
+            StatusCode::BAD_REQUEST,
+            "Invalid 'format', expected csv or json",
+        )
+            .into_response(),
+    }
+}
+
+// 各用户本日 / 本月的拉取次数、流量及配额上限
+pub async fn quotas_status(State(proxy): State<Arc<DockerProxy>>) -> Response {
+    let Some(quotas) = proxy.quotas() else {
+        return (StatusCode::NOT_FOUND, "Pull quotas are disabled").into_response();
+    };
+    let body = serde_json::json!({
+        "users": quotas.report(),
+        "timestamp": proxy.clock().now_secs(),
+    });
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+// 缓存内容：blob 按最近访问排序，以及固定的 manifest
+pub async fn cache_contents(
+    State(proxy): State<Arc<DockerProxy>>,
+    user: Option<axum::Extension<client_auth::AuthenticatedUser>>,
+) -> Response {
+    use serde_json::json;
+
+    let Some(cache) = proxy.cache() else {
+        return (StatusCode::BAD_REQUEST, "Blob cache is disabled").into_response();
+    };
+    // 受 grants 限制的用户只能看到其有拉取权限的仓库中的内容
+    let restricted = match (proxy.client_auth(), &user) {
+        (Some(auth), Some(axum::Extension(user))) if auth.has_grants() => Some((auth, &user.0)),
+        _ => None,
+    };
+    let visible = |repository: &str| {
+        restricted.is_none_or(|(auth, user)| auth.permits(user, PolicyOperation::Pull, repository))
+    };
+    let blobs: Vec<_> = cache
+        .entries()
+        .into_iter()
+        .filter(|(_, entry)| restricted.is_none() || entry.repositories.iter().any(|r| visible(r)))
+        .map(|(digest, entry)| {
+            json!({
+                "digest": digest,
+                "size": entry.size,
+                "created_at": entry.created_at,
+                "last_access": entry.last_access,
+                "retained": entry.retained,
+                "repositories": entry.repositories,
+            })
+        })
+        .collect();
+    let manifests: Vec<_> = cache
+        .pinned_manifests()
+        .into_iter()
+        .filter(|(reference, _)| {
+            let end = reference.find('@').or_else(|| reference.rfind(':'));
+            visible(end.map_or(reference.as_str(), |end| &reference[..end]))
+        })
+        .map(|(reference, manifest)| {
+            json!({
+                "reference": reference,
+                "digest": manifest.digest,
+                "media_type": manifest.media_type,
+            })
+        })
+        .collect();
+    let body = json!({ "blobs": blobs, "manifests": manifests });
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+// 预取镜像到缓存；多架构镜像按 platform（逗号分隔，默认 linux/amd64）筛选
+// 调用示例：
+//   curl -X POST '/admin/prefetch?image=library/nginx:1.27&platform=linux/amd64,linux/arm64'
+pub async fn admin_prefetch(
+    State(proxy): State<Arc<DockerProxy>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let Some((name, reference)) = params
+        .get("image")
+        .and_then(|image| router::parse_image_reference(image))
+    else {
+        return (StatusCode::BAD_REQUEST, "Missing or invalid 'image'").into_response();
+    };
+    let platforms: Vec<String> = params
+        .get("platform")
+        .map(|p| p.split(',').map(|s| s.trim().to_string()).collect())
+        .unwrap_or_else(|| vec!["linux/amd64".to_string()]);
+
+    if let Some(response) = maintenance_response(&proxy) {
+        return response;
+    }
+    let result = prefetch::prefetch_image(&proxy, &name, &reference, &platforms).await;
+    image_operation_response("Prefetch", &proxy.upstream_host(&name), result)
+}
+
+// 从缓存清除镜像：manifest、各平台的 config / layer blob 以及固定引用
+// 调用示例：
+//   curl -X POST '/admin/cache/purge?image=library/nginx:1.27'
+pub async fn admin_purge(
+    State(proxy): State<Arc<DockerProxy>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let Some((name, reference)) = params
+        .get("image")
+        .and_then(|image| router::parse_image_reference(image))
+    else {
+        return (StatusCode::BAD_REQUEST, "Missing or invalid 'image'").into_response();
+    };
+
+    let result = prefetch::purge_image(&proxy, &name, &reference).await;
+    if result.is_ok()
+        && let Some(cache) = proxy.cache()
+        && let Err(e) = cache.persist()
+    {
+        tracing::warn!("Failed to persist blob cache index after purge: {}", e);
+    }
+    image_operation_response("Purge", &proxy.upstream_host(&name), result)
+}
+
+// 预取 / 清除结果：成功返回 JSON 摘要，否则按错误类型映射状态码
+fn image_operation_response<T: serde::Serialize>(
+    operation: &str,
+    host: &str,
+    result: error::ProxyResult<T>,
+) -> Response {
+    match result {
+        Ok(summary) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/json")],
+            serde_json::to_string(&summary).unwrap_or_default(),
+        )
+            .into_response(),
+        Err(e) => {
+            let status = match e {
+                error::ProxyError::ManifestNotFound { .. } => StatusCode::NOT_FOUND,
+                error::ProxyError::BlobNotFound { .. } => StatusCode::NOT_FOUND,
+                error::ProxyError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => return upstream_error_response(&format!("{} failed", operation), host, &e),
+            };
+            tracing::error!("{} failed: {}", operation, e);
+            (status, format!("Error: {}", e)).into_response()
+        }
+    }
+}
+
+// 候选配置影子评估：与当前配置决策不同的请求统计
+pub async fn shadow_report(State(proxy): State<Arc<DockerProxy>>) -> Response {
+    let Some(shadow) = proxy.shadow() else {
+        return (StatusCode::NOT_FOUND, "Shadow evaluation is not configured").into_response();
+    };
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        serde_json::to_string(&shadow.report()).unwrap_or_default(),
+    )
+        .into_response()
+}
+
+// 当前生效的配置，凭据以 ******** 代替
+pub async fn admin_config(State(proxy): State<Arc<DockerProxy>>) -> Response {
+    config_response(&proxy)
+}
+
+// 运行时调整配置（JSON merge patch），仅限日志级别、缓存上限与拉取配额，不写回配置文件
+// 调用示例：
+//   curl -X PATCH '/admin/config' -d '{"log": {"level": "debug"}, "cache": {"max_size_mb": 20480}}'
+//   curl -X PATCH '/admin/config' -d '{"quotas": {"users": {"ci": {"daily_pulls": 500}}}}'
+pub async fn admin_patch_config(
+    State(proxy): State<Arc<DockerProxy>>,
+    body: axum::body::Bytes,
+) -> Response {
+    let patch: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(patch) => patch,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid JSON: {}", e)).into_response();
+        }
+    };
+    if let Err(e) = proxy.patch_config(&patch) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    tracing::warn!(patch = %patch, "Configuration changed at runtime");
+    config_response(&proxy)
+}
+
+fn config_response(proxy: &DockerProxy) -> Response {
+    match proxy.runtime_config().masked() {
+        Ok(config) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/json")],
+            config.to_string(),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)).into_response(),
+    }
+}
+
+// 维护模式状态；未处于维护时 maintenance 为 null
+pub async fn maintenance_status(State(proxy): State<Arc<DockerProxy>>) -> Response {
+    maintenance_state_response(&proxy)
+}
+
+// 开启 / 关闭维护模式：维护期间只返回缓存命中，其余请求返回 503
+// 调用示例：
+//   curl -X POST '/admin/maintenance?enabled=true&duration_secs=1800&reason=rotating%20credentials'
+//   curl -X POST '/admin/maintenance?enabled=false'
+pub async fn admin_maintenance(
+    State(proxy): State<Arc<DockerProxy>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let duration_secs = match params.get("duration_secs").map(|d| d.parse::<u64>()) {
+        Some(Ok(0)) | Some(Err(_)) => {
+            return (StatusCode::BAD_REQUEST, "Invalid 'duration_secs'").into_response();
+        }
+        Some(Ok(secs)) => Some(secs),
+        None => None,
+    };
+    match params.get("enabled").map(String::as_str) {
+        Some("true") => {
+            let reason = params.get("reason").filter(|r| !r.is_empty()).cloned();
+            tracing::warn!(reason = ?reason, duration_secs = ?duration_secs, "Entering maintenance mode");
+            proxy.maintenance().enable(reason, duration_secs);
+        }
+        Some("false") => {
+            tracing::warn!("Leaving maintenance mode");
+            proxy.maintenance().disable();
+        }
+        _ => {
+            return (StatusCode::BAD_REQUEST, "Missing or invalid 'enabled'").into_response();
+        }
+    }
+    maintenance_state_response(&proxy)
+}
+
+fn maintenance_state_response(proxy: &DockerProxy) -> Response {
+    let body = serde_json::json!({ "maintenance": proxy.maintenance().status() });
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+// 维护期间拒绝缓存未命中和写请求：503 + Retry-After，JSON 中给出原因
+fn maintenance_response(proxy: &DockerProxy) -> Option<Response> {
+    let status = proxy.maintenance().status()?;
+    let body = serde_json::json!({
+        "errors": [{
+            "code": "UNAVAILABLE",
+            "message": format!("registry is in maintenance: {}", status.reason),
+            "detail": status,
+        }]
+    });
+    Some(
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [
+                (header::CONTENT_TYPE, "application/json".to_string()),
+                (header::RETRY_AFTER, status.retry_after.to_string()),
+            ],
+            body.to_string(),
+        )
+            .into_response(),
+    )
+}
+
+// 按 [policy] 规则拒绝的请求返回 403 DENIED，消息中说明命中的规则及原因
+fn policy_response(
+    proxy: &DockerProxy,
+    method: &reqwest::Method,
+    endpoint: &V2Endpoint,
+) -> Option<Response> {
+    let (name, reference) = match endpoint {
+        V2Endpoint::Manifest { name, reference } => (name, Some(reference.as_str())),
+        V2Endpoint::Blob { name, .. }
+        | V2Endpoint::BlobUploadInit { name }
+        | V2Endpoint::BlobUploadComplete { name, .. }
+        | V2Endpoint::BlobUploadChunk { name, .. }
+        | V2Endpoint::BlobUploadStatus { name, .. }
+        | V2Endpoint::TagList { name } => (name, None),
+        _ => return None,
+    };
+    let denial = proxy.check_policy(method, name, reference).err()?;
+    tracing::warn!(
+        method = %method,
+        subject = %denial.subject,
+        rule = ?denial.rule,
+        "Request denied by policy"
+    );
+    let body = serde_json::json!({
+        "errors": [{
+            "code": "DENIED",
+            "message": denial.message(),
+            "detail": denial,
+        }]
+    });
+    Some(
+        (
+            StatusCode::FORBIDDEN,
+            [(header::CONTENT_TYPE, "application/json")],
+            body.to_string(),
+        )
+            .into_response(),
+    )
+}
+
+// 上游失败时返回 502：registry 错误格式的 JSON，说明上游主机、失败阶段、是否可重试及请求 ID。
+// 原始错误只写入日志，其中可能包含带签名的 URL 等敏感信息
+fn upstream_error_response(context: &str, host: &str, error: &error::ProxyError) -> Response {
+    if let Some(response) = rate_limited_response(error) {
+        return response;
+    }
+    let request_id = crate::log::current_request_id()
+        .map(|id| id.to_string())
+        .unwrap_or_default();
+    let host = error.upstream_host().unwrap_or_else(|| host.to_string());
+    let phase = error.phase();
+    tracing::error!(
+        request_id = %request_id,
+        upstream = %host,
+        phase = ?phase,
+        "{}: {}",
+        context,
+        error
+    );
+    let body = serde_json::json!({
+        "errors": [{
+            "code": "UNKNOWN",
+            "message": format!("{} (request id {})", context.to_lowercase(), request_id),
+            "detail": {
+                "upstream": host,
+                "phase": phase,
+                "retryable": phase.retryable(),
+                "advice": phase.advice(),
+                "request_id": request_id,
+            },
+        }]
+    });
+    let mut response = (
+        StatusCode::BAD_GATEWAY,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response();
+    response
+        .extensions_mut()
+        .insert(error_reporting::ReportedError(format!(
+            "{} ({}, {:?}): {}",
+            context, host, phase, error
+        )));
+    response
+}
+
+// 上游限流（429 或退避中）时返回 429 TOOMANYREQUESTS，附带 Retry-After 和上游的 RateLimit-* 等头，
+// 客户端据此稍后重试而不是当作镜像不存在
+fn rate_limited_response(error: &error::ProxyError) -> Option<Response> {
+    let error::ProxyError::RateLimited {
+        host,
+        retry_after_secs,
+        headers,
+    } = error
+    else {
+        return None;
+    };
+    tracing::warn!(upstream = %host, "{}", error);
+    let body = serde_json::json!({
+        "errors": [{
+            "code": "TOOMANYREQUESTS",
+            "message": format!("upstream {} rate limit reached, retry after {}s", host, retry_after_secs),
+            "detail": {
+                "upstream": host,
+                "retry_after": retry_after_secs,
+            },
+        }]
+    });
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::RETRY_AFTER, retry_after_secs.to_string()),
+        ],
+        body.to_string(),
+    )
+        .into_response();
+    for (name, value) in headers {
+        if let Ok(name) = axum::http::HeaderName::from_bytes(name.as_bytes())
+            && let Ok(value) = HeaderValue::from_str(value)
+        {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    Some(response)
+}
+
+// 按 (registry, repository, tag) 统计拉取次数与流量，找出占用流量最多的镜像
+// 时间窗口：range（天数 d / 周数 w，默认 7d）或 since / until（Unix 秒，按 UTC 天计）
+// 排序：sort=pulls|bytes（默认 pulls），order=desc|asc；limit 默认 100
+// 过滤：registry 精确匹配，repository 子串匹配
+// 调用示例：
+//   /admin/stats/pulls?range=30d&sort=bytes&limit=20
+//   /admin/stats/pulls?since=1760000000&registry=ghcr.io
+pub async fn admin_pull_stats(
+    State(proxy): State<Arc<DockerProxy>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    let Some(stats) = proxy.pull_stats() else {
+        return (StatusCode::NOT_FOUND, "Pull statistics are disabled").into_response();
+    };
+    let seconds = |key: &'static str| params.get(key).map(|v| v.parse::<u64>().map_err(|_| key));
+    let (first, last) = match (seconds("since"), seconds("until")) {
+        (None, None) => {
+            let range = params.get("range").map(String::as_str).unwrap_or("7d");
+            let Some(days) = pull_stats::parse_range(range) else {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "Invalid 'range', expected e.g. 7d or 4w",
+                )
+                    .into_response();
+            };
+            let today = stats.today();
+            (today.saturating_sub(days - 1), today)
+        }
+        (since, until) => match (since.transpose(), until.transpose()) {
+            (Ok(Some(since)), Ok(Some(until))) if since > until => {
+                return (StatusCode::BAD_REQUEST, "'since' is after 'until'").into_response();
+            }
+            (Ok(since), Ok(until)) => (
+                since.map_or(0, pull_stats::PullStats::day_of),
+                until.map_or_else(|| stats.today(), pull_stats::PullStats::day_of),
+            ),
+            (Err(key), _) | (_, Err(key)) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid '{}', expected Unix seconds", key),
+                )
+                    .into_response();
+            }
+        },
+    };
+    let by_bytes = match params.get("sort").map(String::as_str).unwrap_or("pulls") {
+        "pulls" => false,
+        "bytes" => true,
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Invalid 'sort', expected pulls or bytes",
+            )
+                .into_response();
+        }
+    };
+    let ascending = match params.get("order").map(String::as_str).unwrap_or("desc") {
+        "desc" => false,
+        "asc" => true,
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Invalid 'order', expected desc or asc",
+            )
+                .into_response();
+        }
+    };
+    let limit = match params.get("limit").map(|v| v.parse::<usize>()) {
+        None => 100,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => {
+            return (StatusCode::BAD_REQUEST, "Invalid 'limit'").into_response();
+        }
+    };
+
+    let mut rows: Vec<_> = stats
+        .tag_summary(first, last, |name| proxy.upstream_repository(name))
+        .into_iter()
+        .filter(|row| params.get("registry").is_none_or(|r| row.registry == *r))
+        .filter(|row| {
+            params
+                .get("repository")
+                .is_none_or(|r| row.repository.contains(r.as_str()))
+        })
+        .collect();
+    rows.sort_by(|a, b| {
+        let key = |row: &pull_stats::TagPulls| {
+            if by_bytes {
+                (row.bytes, row.pulls)
+            } else {
+                (row.pulls, row.bytes)
+            }
+        };
+        if ascending {
+            key(a).cmp(&key(b))
+        } else {
+            key(b).cmp(&key(a))
+        }
+    });
+    let total = rows.len();
+    rows.truncate(limit);
+
+    let body = serde_json::json!({
+        "since": first.saturating_mul(pull_stats::SECS_PER_DAY),
+        "until": last.saturating_add(1).saturating_mul(pull_stats::SECS_PER_DAY),
+        "total": total,
+        "images": rows,
+    });
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+// 连通性诊断：DNS、TCP（IPv4/IPv6）、TLS 握手与 /v2/ 探测，返回各阶段耗时
+// 调用示例：
+//   /admin/diagnose?host=registry-1.docker.io
+pub async fn admin_diagnose(
+    State(proxy): State<Arc<DockerProxy>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> impl IntoResponse {
+    let target = match params.get("host") {
+        Some(v) if !v.is_empty() => v.clone(),
+        _ => proxy
+            .get_registry_url()
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .to_string(),
+    };
+
+    let (host, port) = match diagnose::parse_target(&target) {
+        Ok(v) => v,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let report = diagnose::run(&proxy, &host, port).await;
+    match serde_json::to_string(&report) {
+        Ok(body) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/json")],
+            body,
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to serialize diagnostic report: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
+
+// 导入 docker save 归档或 OCI layout 到缓存，manifest 固定在指定名称下，可离线拉取
+// 调用示例：
+//   curl --data-binary @nginx.tar '/admin/import?name=library/nginx&tag=1.27'
+pub async fn admin_import(
+    State(proxy): State<Arc<DockerProxy>>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+    body: Body,
+) -> Response {
+    use tokio::io::AsyncWriteExt;
+
+    let Some(cache) = proxy.cache().cloned() else {
+        return (StatusCode::BAD_REQUEST, "Blob cache is disabled").into_response();
+    };
+    let name = match params.get("name") {
+        Some(name) if is_repository_name(name) => name.clone(),
+        _ => return (StatusCode::BAD_REQUEST, "Missing or invalid 'name'").into_response(),
+    };
+    let tag = params.get("tag").cloned();
+    if let Some(tag) = &tag
+        && !is_tag(tag)
+    {
+        return (StatusCode::BAD_REQUEST, "Invalid 'tag'").into_response();
+    }
+
+    // tar 需要两遍读取，先落盘到缓存临时目录
+    let archive = cache.temp_path();
+    let spooled = async {
+        let mut file = tokio::fs::File::create(&archive).await?;
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk.map_err(std::io::Error::other)?)
+                .await?;
+        }
+        file.flush().await
+    }
+    .await;
+    if let Err(e) = spooled {
+        let _ = tokio::fs::remove_file(&archive).await;
+        tracing::warn!("Failed to receive import archive: {}", e);
+        return (StatusCode::BAD_REQUEST, format!("Upload failed: {}", e)).into_response();
+    }
+
+    let path = archive.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let summary = import::import_archive(&cache, &path, &name, tag.as_deref())?;
+        if let Err(e) = cache.persist() {
+            tracing::warn!("Failed to persist blob cache index after import: {}", e);
+        }
+        Ok(summary)
+    })
+    .await
+    .unwrap_or_else(|e| Err(error::ProxyError::InternalError(e.to_string())));
+    let _ = tokio::fs::remove_file(&archive).await;
+
+    match result {
+        Ok(summary) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/json")],
+            serde_json::to_string(&summary).unwrap_or_default(),
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!("Import failed: {}", e);
+            let status = match e {
+                error::ProxyError::InvalidArchive(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, format!("Error: {}", e)).into_response()
+        }
+    }
+}
+
+// 仓库名需能被 /v2/ 路由解析
+fn is_repository_name(name: &str) -> bool {
+    matches!(
+        router::parse_v2_path(&format!("{}/manifests/latest", name)),
+        V2Endpoint::Manifest { name: parsed, .. } if parsed == name
+    )
+}
+
+// tag 规则：[A-Za-z0-9_][A-Za-z0-9_.-]{0,127}
+fn is_tag(tag: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-');
+    tag.len() <= 128
+        && tag
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        && tag.chars().all(valid_char)
+}
+
 // 调试接口：返回 manifest 中的 layer size 与实际 blob 大小
 // 调用示例：
 //   /debug/blob-info?name=library/debian&reference=latest&digest=sha256:...
@@ -129,13 +1076,28 @@ pub async fn debug_blob_info(
     }
 }
 
-// 获取镜像manifest
+// 获取镜像manifest：导入时固定的 manifest 优先从缓存返回；
+// 客户端的 Accept 原样转发给上游，以便协商 OCI / 多架构索引格式
 async fn get_manifest(
     State(proxy): State<Arc<DockerProxy>>,
     Path((name, reference)): Path<(String, String)>,
+    headers: &HeaderMap,
 ) -> Response {
-    match proxy.get_manifest(&name, &reference).await {
-        Ok((content_type, body)) => {
+    if let Some(cache) = proxy.cache()
+        && let Some(response) = serve_pinned_manifest(cache, &name, &reference, false).await
+    {
+        return response;
+    }
+    if let Some(response) = maintenance_response(&proxy) {
+        return response;
+    }
+
+    match proxy
+        .get_manifest_stream(&name, &reference, &accept_values(headers))
+        .await
+    {
+        Ok(upstream_resp) => {
+            let content_type = proxy::manifest_content_type(&upstream_resp);
             let mut headers = HeaderMap::new();
             let ct_value = content_type
                 .parse()
@@ -145,8 +1107,98 @@ async fn get_manifest(
                     HeaderValue::from_static("application/json")
                 });
             headers.insert(header::CONTENT_TYPE, ct_value);
+            chain::set_cache_status(&mut headers, "miss");
+            // 转发上游的 RateLimit-* 头，客户端可看到剩余拉取配额
+            for (name, value) in rate_limits::forwarded_headers(upstream_resp.headers()) {
+                if let Ok(name) = axum::http::HeaderName::from_bytes(name.as_bytes())
+                    && let Ok(value) = HeaderValue::from_str(&value)
+                {
+                    headers.insert(name, value);
+                }
+            }
+            // 按 digest 请求时 digest 即引用本身（边转发边校验）；按标签请求时沿用上游的
+            // Docker-Content-Digest（oras 等客户端依赖此头）
+            let verifier = DigestVerifier::for_reference(&reference);
+            let digest = if verifier.is_some() {
+                Some(reference.clone())
+            } else {
+                upstream_resp
+                    .headers()
+                    .get("docker-content-digest")
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string)
+            };
+            // 记录标签最近一次拉取的 manifest，上游故障时可按 serve_stale_on_error 返回
+            let keep = proxy
+                .cache()
+                .filter(|cache| cache.keeps_stale_manifests() && !reference.contains(':'))
+                .map(|cache| {
+                    let cache = Arc::clone(cache);
+                    let (name, reference) = (name.clone(), reference.clone());
+                    let content_type = content_type.clone();
+                    move |body: Spilled| {
+                        tokio::task::spawn_blocking(move || {
+                            let result = body.reader().and_then(|mut reader| {
+                                cache.record_fetched_manifest(
+                                    &name,
+                                    &reference,
+                                    &content_type,
+                                    &mut reader,
+                                )
+                            });
+                            if let Err(e) = result {
+                                tracing::warn!("Failed to keep fetched manifest: {}", e);
+                            }
+                        });
+                    }
+                });
+
+            let Some(digest) = digest else {
+                // 上游未给出 digest 时读完正文，按内容计算（超过阈值的部分暂存到磁盘）
+                let (digest, body) = match read_spilled(proxy.spill_policy(), upstream_resp).await {
+                    Ok(read) => read,
+                    Err(e) => {
+                        tracing::error!("Error getting manifest: {}", e);
+                        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e))
+                            .into_response();
+                    }
+                };
+                if let Ok(value) = HeaderValue::from_str(&digest) {
+                    headers.insert("Docker-Content-Digest", value);
+                }
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.size()));
+                if let Some(keep) = keep {
+                    keep(body.clone());
+                }
+                return match body.into_body().await {
+                    Ok(body) => (StatusCode::OK, headers, body).into_response(),
+                    Err(e) => {
+                        tracing::error!("Error getting manifest: {}", e);
+                        (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)).into_response()
+                    }
+                };
+            };
+            if let Ok(value) = HeaderValue::from_str(&digest) {
+                headers.insert("Docker-Content-Digest", value);
+            }
+            if let Some(value) = upstream_resp.headers().get(header::CONTENT_LENGTH) {
+                headers.insert(header::CONTENT_LENGTH, value.clone());
+            }
+            let body = Body::from_stream(manifest_stream(
+                upstream_resp.bytes_stream(),
+                verifier,
+                keep.map(|keep| (proxy.spill_policy().buffer(), keep)),
+            ));
             (StatusCode::OK, headers, body).into_response()
         }
+        Err(e)
+            if e.is_upstream_outage()
+                && let Some(response) =
+                    serve_stale_manifest(&proxy, &name, &reference, false, &e).await =>
+        {
+            response
+        }
+        Err(e) if let Some(response) = rate_limited_response(&e) => response,
         Err(e) => {
             tracing::error!("Error getting manifest: {}", e);
             let status = match e {
@@ -159,13 +1211,108 @@ async fn get_manifest(
     }
 }
 
+// Read a whole upstream body, hashing it on the way
+async fn read_spilled(
+    policy: &SpillPolicy,
+    response: reqwest::Response,
+) -> error::ProxyResult<(String, Spilled)> {
+    let read_error = |e: String| error::ProxyError::ResponseReadError(e);
+    let mut buffer = policy.buffer();
+    let mut hasher = Sha256::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| read_error(e.to_string()))?;
+        hasher.update(&chunk);
+        buffer
+            .write(&chunk)
+            .await
+            .map_err(|e| read_error(e.to_string()))?;
+    }
+    let body = buffer
+        .finish()
+        .await
+        .map_err(|e| read_error(e.to_string()))?;
+    Ok((format!("sha256:{}", hex::encode(hasher.finalize())), body))
+}
+
+// Pass a manifest body through while checking it against a digest reference
+// and collecting it into a spill buffer for `on_complete`. The last chunk is
+// held back until the digest checks out, so that a mismatch ends the body
+// with an error instead (the response headers are already sent by then).
+fn manifest_stream<S, E>(
+    stream: S,
+    verifier: Option<DigestVerifier>,
+    collected: Option<(SpillBuffer, impl FnOnce(Spilled) + Send + 'static)>,
+) -> impl Stream<Item = Result<bytes::Bytes, std::io::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<bytes::Bytes, E>> + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    stream::unfold(
+        Some((Box::pin(stream), verifier, collected, None)),
+        |state| async move {
+            let (mut inner, mut verifier, mut collected, mut pending) = state?;
+            loop {
+                match inner.next().await {
+                    Some(Ok(chunk)) => {
+                        if let Some(v) = verifier.as_mut() {
+                            v.update(&chunk);
+                        }
+                        if let Some((body, _)) = collected.as_mut()
+                            && let Err(e) = body.write(&chunk).await
+                        {
+                            tracing::warn!("Failed to buffer fetched manifest: {}", e);
+                            collected = None;
+                        }
+                        if let Some(previous) = pending.replace(chunk) {
+                            return Some((
+                                Ok(previous),
+                                Some((inner, verifier, collected, pending)),
+                            ));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(std::io::Error::other(e)), None)),
+                    None => {
+                        if let Some(Err(e)) = verifier.map(DigestVerifier::finish) {
+                            tracing::error!("Upstream manifest error: {}", e);
+                            return Some((Err(std::io::Error::other(e)), None));
+                        }
+                        if let Some((body, on_complete)) = collected {
+                            match body.finish().await {
+                                Ok(body) => on_complete(body),
+                                Err(e) => {
+                                    tracing::warn!("Failed to buffer fetched manifest: {}", e)
+                                }
+                            }
+                        }
+                        return pending.map(|last| (Ok(last), None));
+                    }
+                }
+            }
+        },
+    )
+}
+
 // HEAD 请求 manifest
 async fn head_manifest(
     State(proxy): State<Arc<DockerProxy>>,
     Path((name, reference)): Path<(String, String)>,
+    headers: &HeaderMap,
 ) -> Response {
-    match proxy.head_manifest(&name, &reference).await {
-        Ok((content_type, content_length)) => {
+    if let Some(cache) = proxy.cache()
+        && let Some(response) = serve_pinned_manifest(cache, &name, &reference, true).await
+    {
+        return response;
+    }
+    if let Some(response) = maintenance_response(&proxy) {
+        return response;
+    }
+
+    match proxy
+        .head_manifest(&name, &reference, &accept_values(headers))
+        .await
+    {
+        Ok((content_type, content_length, digest)) => {
             let mut headers = HeaderMap::new();
             let ct_value = content_type
                 .parse()
@@ -181,8 +1328,20 @@ async fn head_manifest(
             } else {
                 tracing::warn!("Failed to parse content length: {}", content_length);
             }
+            if let Some(value) = digest.and_then(|d| HeaderValue::from_str(&d).ok()) {
+                headers.insert("Docker-Content-Digest", value);
+            }
+            chain::set_cache_status(&mut headers, "miss");
             (StatusCode::OK, headers).into_response()
         }
+        Err(e)
+            if e.is_upstream_outage()
+                && let Some(response) =
+                    serve_stale_manifest(&proxy, &name, &reference, true, &e).await =>
+        {
+            response
+        }
+        Err(e) if let Some(response) = rate_limited_response(&e) => response,
         Err(e) => {
             tracing::error!("Error heading manifest: {}", e);
             let status = match e {
@@ -195,63 +1354,363 @@ async fn head_manifest(
     }
 }
 
-// 获取 blob：完全透传上游响应（包括头和流式 body）
+// 客户端请求的 Accept 头（可能有多个）；只有 */* 时按未指定处理
+fn accept_values(headers: &HeaderMap) -> Vec<&str> {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter(|value| value.trim() != "*/*")
+        .collect()
+}
+
+// 获取 blob：优先从本地缓存返回（支持 Range），否则透传上游响应（包括头和流式 body）；
+// 上游返回完整内容（200）时写入缓存
 async fn get_blob(
     State(proxy): State<Arc<DockerProxy>>,
     Path((name, digest)): Path<(String, String)>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    match proxy.get_blob(&name, &digest).await {
+    if let Some(cache) = proxy.cache()
+        && cached_blob_visible(&proxy, cache, &name, &digest).await
+        && let Some(response) = serve_cached_blob(cache, &proxy, &digest, &headers).await
+    {
+        return response;
+    }
+    // 同一 blob 正在回源时等待其写入缓存，避免重复拉取（级联时上游也只拉一次）
+    if let Some(cache) = proxy.cache()
+        && cache.wait_for_fill(&digest).await
+        && cached_blob_visible(&proxy, cache, &name, &digest).await
+        && let Some(mut response) = serve_cached_blob(cache, &proxy, &digest, &headers).await
+    {
+        chain::set_cache_status(response.headers_mut(), "coalesced");
+        return response;
+    }
+    if let Some(response) = maintenance_response(&proxy) {
+        return response;
+    }
+
+    // 透传客户端的 Range / If-Range：中断的下载可续传，部分读取由上游返回 206 + Content-Range
+    let range_headers: Vec<(&str, &str)> = ["range", "if-range"]
+        .into_iter()
+        .filter_map(|name| Some((name, headers.get(name)?.to_str().ok()?)))
+        .collect();
+
+    match proxy.get_blob(&name, &digest, range_headers).await {
         Ok(upstream_resp) => {
             let status = axum::http::StatusCode::from_u16(upstream_resp.status().as_u16())
                 .unwrap_or(StatusCode::OK);
-            let mut headers = HeaderMap::new();
-
-            for (key, value) in upstream_resp.headers().iter() {
-                let key_str = key.as_str();
-                if key_str.eq_ignore_ascii_case("connection")
-                    || key_str.eq_ignore_ascii_case("transfer-encoding")
-                    || key_str.eq_ignore_ascii_case("upgrade")
-                {
-                    continue;
-                }
+            let mut headers = copy_upstream_headers(upstream_resp.headers());
+            let content_length = upstream_resp.content_length();
+            // 上游 docker-proxy 对部分响应也按完整长度签名
+            let signed_length = if status == StatusCode::PARTIAL_CONTENT {
+                headers
+                    .get(header::CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(range::content_range_size)
+            } else {
+                content_length
+            };
 
-                if let Ok(ax_key) = axum::http::HeaderName::from_bytes(key_str.as_bytes())
-                    && let Ok(ax_val) = axum::http::HeaderValue::from_bytes(value.as_bytes())
-                {
-                    headers.insert(ax_key, ax_val);
-                }
+            // 级联模式：校验上游 docker-proxy 的缓存命中签名，转发其缓存提示
+            if let Some(upstream) = proxy.upstream_proxy(&name)
+                && let Err(e) = upstream.check_blob(&digest, signed_length, &mut headers)
+            {
+                return upstream_error_response(
+                    "Upstream blob error",
+                    &proxy.upstream_host(&name),
+                    &error::ProxyError::UpstreamRejected(e),
+                );
             }
+            chain::set_cache_status(&mut headers, "miss");
 
-            let stream = upstream_resp.bytes_stream();
-            let body = Body::from_stream(stream);
+            // 启用 verify_blob_digests 时边转发边校验 digest，不一致时中断传输且不写入缓存
+            let stream = proxy.blob_body(&digest, upstream_resp);
+            let writer = match proxy.cache() {
+                Some(cache) if status == StatusCode::OK => cache
+                    .writer(&digest, content_length)
+                    .await
+                    .map(|writer| writer.for_repository(&name)),
+                _ => None,
+            };
+            let body = match writer {
+                Some(writer) => Body::from_stream(cache::tee(stream, writer)),
+                None => Body::from_stream(stream),
+            };
 
             (status, headers, body).into_response()
         }
+        Err(e) => upstream_error_response("Upstream blob error", &proxy.upstream_host(&name), &e),
+    }
+}
+
+// 客户端只能访问部分仓库时，缓存的 blob 只在已知属于所请求仓库时直接返回（digest 相同
+// 不代表有权访问）；否则先向上游确认该仓库中存在此 blob，确认后记录
+async fn cached_blob_visible(
+    proxy: &DockerProxy,
+    cache: &BlobCache,
+    name: &str,
+    digest: &str,
+) -> bool {
+    if !proxy.restricts_repositories() || cache.belongs_to(digest, name) {
+        return true;
+    }
+    if cache.lookup(digest).is_none() || maintenance_response(proxy).is_some() {
+        return false;
+    }
+    match proxy.head_blob(name, digest).await {
+        Ok(_) => {
+            cache.record_repository(digest, name);
+            true
+        }
+        Err(e) => {
+            tracing::debug!(digest = %digest, name = %name, "Cached blob not confirmed upstream: {}", e);
+            false
+        }
+    }
+}
+
+// 从本地缓存返回 blob；文件丢失时移除索引项并回退到上游。
+// 以 digest 作为强 ETag，支持 If-Range 续传。
+// 配置了签名密钥时附带对 digest+长度 的签名头
+async fn serve_cached_blob(
+    cache: &Arc<BlobCache>,
+    proxy: &DockerProxy,
+    digest: &str,
+    request_headers: &HeaderMap,
+) -> Option<Response> {
+    // 租约在响应流结束前一直持有，防止淘汰删除正在传输的文件
+    let blob = cache.acquire(digest)?;
+    let file = match tokio::fs::File::open(&blob.path).await {
+        Ok(file) => file,
         Err(e) => {
-            tracing::error!("Error getting blob: {}", e);
+            tracing::warn!(digest = %digest, "Cached blob unreadable, refetching: {}", e);
+            cache.forget(digest);
+            return None;
+        }
+    };
+
+    tracing::info!(digest = %digest, size = blob.size, "Serving blob from cache");
+
+    // blob 内容由 digest 唯一确定，digest 即强 ETag
+    let validators = range::Validators {
+        etag: Some(format!("\"{}\"", digest)),
+        last_modified: None,
+    };
+    let range_request = range::evaluate_request(request_headers, blob.size, &validators);
+    let (status, mut headers, body) = match range_request {
+        range::RangeRequest::Full => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            );
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(blob.size));
+            headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
             (
-                StatusCode::BAD_GATEWAY,
-                format!("Upstream blob error: {}", e),
+                StatusCode::OK,
+                headers,
+                Body::from_stream(ReaderStream::with_capacity(
+                    file,
+                    cache.stream_buffer_size(),
+                )),
             )
-                .into_response()
         }
+        // 懒加载客户端（eStargz/SOCI）的小范围读取从内存中的热点分块返回
+        range::RangeRequest::Partial(ref ranges)
+            if let [range] = ranges.as_slice()
+                && let Some(hot) = proxy.hot_ranges()
+                && hot.accepts(range) =>
+        {
+            let (status, headers) =
+                match range::create_range_headers(range, blob.size, "application/octet-stream") {
+                    Ok(response) => response,
+                    Err(_) => {
+                        tracing::error!("Failed to create range headers");
+                        return Some(
+                            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                                .into_response(),
+                        );
+                    }
+                };
+            match hot.read(digest, &blob.path, range.clone(), blob.size).await {
+                Ok(bytes) => (status, headers, Body::from(bytes)),
+                Err(e) => {
+                    tracing::warn!(digest = %digest, "Cached blob unreadable, refetching: {}", e);
+                    cache.forget(digest);
+                    return None;
+                }
+            }
+        }
+        range::RangeRequest::Partial(ranges) => {
+            match range::range_response(
+                file,
+                ranges,
+                blob.size,
+                "application/octet-stream",
+                cache.stream_buffer_size(),
+            ) {
+                Ok(response) => response,
+                Err(_) => {
+                    tracing::error!("Failed to create range headers");
+                    return Some(
+                        (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                            .into_response(),
+                    );
+                }
+            }
+        }
+        range::RangeRequest::Unsatisfiable => {
+            return Some(
+                (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    range::unsatisfiable_headers(blob.size),
+                )
+                    .into_response(),
+            );
+        }
+    };
+
+    if let Ok(value) = HeaderValue::from_str(digest) {
+        headers.insert("Docker-Content-Digest", value);
+    }
+    validators.insert_headers(&mut headers);
+    if let Some(signer) = proxy.signer()
+        && let Ok(value) = HeaderValue::from_str(&signer.sign(digest, blob.size))
+    {
+        headers.insert(signing::SIGNATURE_HEADER, value);
+    }
+
+    chain::set_cache_status(&mut headers, "hit");
+
+    let stream = body.into_data_stream().map(move |chunk| {
+        let _lease = &blob;
+        chunk
+    });
+    Some((status, headers, Body::from_stream(stream)).into_response())
+}
+
+// 返回导入时固定在 name:reference 下的 manifest
+async fn serve_pinned_manifest(
+    cache: &Arc<BlobCache>,
+    name: &str,
+    reference: &str,
+    head: bool,
+) -> Option<Response> {
+    let (manifest, _) = cache.lookup_manifest(name, reference)?;
+    let blob = cache.acquire(&manifest.digest)?;
+    let body = match tokio::fs::read(&blob.path).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::warn!(digest = %manifest.digest, "Pinned manifest unreadable: {}", e);
+            cache.forget(&manifest.digest);
+            return None;
+        }
+    };
+
+    tracing::info!(name = %name, reference = %reference, "Serving pinned manifest from cache");
+
+    let mut headers = HeaderMap::new();
+    if let Ok(value) = HeaderValue::from_str(&manifest.media_type) {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    if let Ok(value) = HeaderValue::from_str(&manifest.digest) {
+        headers.insert("Docker-Content-Digest", value);
+    }
+    chain::set_cache_status(&mut headers, "hit");
+
+    if head {
+        Some((StatusCode::OK, headers).into_response())
+    } else {
+        Some((StatusCode::OK, headers, body).into_response())
+    }
+}
+
+// 上游不可用或限流时返回标签最近一次拉取的 manifest（不超过 serve_stale_on_error），
+// 附带 Warning 和 Age 头
+async fn serve_stale_manifest(
+    proxy: &DockerProxy,
+    name: &str,
+    reference: &str,
+    head: bool,
+    error: &error::ProxyError,
+) -> Option<Response> {
+    let cache = proxy.cache()?;
+    let (manifest, blob) = cache.stale_manifest(name, reference)?;
+    let body = match tokio::fs::read(&blob.path).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::warn!(digest = %manifest.digest, "Stale manifest unreadable: {}", e);
+            cache.forget(&manifest.digest);
+            return None;
+        }
+    };
+    let age = proxy.clock().now_secs().saturating_sub(manifest.fetched_at);
+
+    tracing::warn!(
+        name = %name,
+        reference = %reference,
+        age_secs = age,
+        "Upstream unavailable, serving stale manifest: {}",
+        error
+    );
+
+    let mut headers = HeaderMap::new();
+    if let Ok(value) = HeaderValue::from_str(&manifest.media_type) {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    if let Ok(value) = HeaderValue::from_str(&manifest.digest) {
+        headers.insert("Docker-Content-Digest", value);
+    }
+    headers.insert(header::AGE, HeaderValue::from(age));
+    headers.insert(
+        header::WARNING,
+        HeaderValue::from_static("110 docker-proxy \"Response is Stale\""),
+    );
+    chain::set_cache_status(&mut headers, "stale");
+
+    if head {
+        Some((StatusCode::OK, headers).into_response())
+    } else {
+        Some((StatusCode::OK, headers, body).into_response())
     }
 }
 
-// HEAD 请求 blob
+// HEAD 请求 blob：转发上游的类型、长度、digest 及缓存相关头（BLOB_HEAD_HEADERS）；
+// 维护期间缓存命中时由缓存回答，两种情况的响应头一致（见 blob_head_headers）
 async fn head_blob(
     State(proxy): State<Arc<DockerProxy>>,
     Path((name, digest)): Path<(String, String)>,
 ) -> impl IntoResponse {
+    if let Some(local) = proxy.local_registry() {
+        return local_registry::head_blob(local, &name, &digest);
+    }
+    if let Some(response) = maintenance_response(&proxy) {
+        let visible = |cache: &&Arc<BlobCache>| {
+            !proxy.restricts_repositories() || cache.belongs_to(&digest, &name)
+        };
+        return match proxy
+            .cache()
+            .filter(visible)
+            .and_then(|cache| cache.lookup(&digest))
+        {
+            Some(blob) => {
+                let mut cached = HeaderMap::new();
+                cached.insert(header::CONTENT_LENGTH, HeaderValue::from(blob.size));
+                (StatusCode::OK, blob_head_headers(&cached, &digest)).into_response()
+            }
+            None => response,
+        };
+    }
     match proxy.head_blob(&name, &digest).await {
-        Ok(content_length) => (
+        Ok(upstream_headers) => (
             StatusCode::OK,
-            [
-                (header::CONTENT_TYPE, "application/octet-stream"),
-                (header::CONTENT_LENGTH, content_length.to_string().as_str()),
-            ],
+            blob_head_headers(&upstream_headers, &digest),
         )
             .into_response(),
+        Err(e) if let Some(response) = rate_limited_response(&e) => response,
         Err(e) => {
             tracing::error!("Error heading blob: {}", e);
             let status = match e {
@@ -264,55 +1723,460 @@ async fn head_blob(
     }
 }
 
-// 初始化 blob 上传
-async fn initiate_blob_upload(
-    State(proxy): State<Arc<DockerProxy>>,
-    Path(name): Path<String>,
+// HEAD blob 的响应头：取上游给出的 BLOB_HEAD_HEADERS，未给出的（如重定向后的 CDN，
+// 或缓存命中时）按内容寻址 blob 的常规值补齐：application/octet-stream、请求的 digest、
+// 以 digest 为 ETag、长期可缓存
+fn blob_head_headers(upstream: &HeaderMap, digest: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for name in BLOB_HEAD_HEADERS {
+        if let Some(value) = upstream.get(*name) {
+            headers.insert(*name, value.clone());
+        }
+    }
+    let fallbacks = [
+        (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+        (
+            header::HeaderName::from_static("docker-content-digest"),
+            digest.to_string(),
+        ),
+        (header::ETAG, format!("\"{}\"", digest)),
+        (header::CACHE_CONTROL, "max-age=31536000".to_string()),
+    ];
+    for (name, value) in fallbacks {
+        if !headers.contains_key(&name)
+            && let Ok(value) = HeaderValue::from_str(&value)
+        {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+// 标签列表：透传 n / last 分页参数，上游 Link 头改写为代理地址
+// 调用示例：GET /v2/<name>/tags/list?n=100&last=v1.2
+async fn get_tags(proxy: &DockerProxy, name: &str, query: Option<&str>) -> Response {
+    if let Some(local) = proxy.local_registry() {
+        return local_registry::tag_list(local, name, query);
+    }
+    if let Some(response) = maintenance_response(proxy) {
+        return response;
+    }
+    let result = proxy.tags_page(name, query).await;
+    relay_page(proxy, Some(name), result)
+}
+
+// 仓库目录：转发到默认上游（分页同标签列表）；本地仓库模式下列出本地仓库
+// 调用示例：GET /v2/_catalog?n=100
+async fn get_catalog(proxy: &DockerProxy, query: Option<&str>) -> Response {
+    if let Some(local) = proxy.local_registry() {
+        return local_registry::catalog(local, query);
+    }
+    if let Some(response) = maintenance_response(proxy) {
+        return response;
+    }
+    let result = proxy.catalog_page(query).await;
+    relay_page(proxy, None, result)
+}
+
+// 内容信任（TUF）元数据：转发到配置的 Notary 服务器，正文原样返回；
+// 按上游 Cache-Control 在内存中缓存
+// 调用示例：GET /v2/docker.io/library/nginx/_trust/tuf/root.json
+async fn get_trust_metadata(proxy: &DockerProxy, gun: &str, file: &str) -> Response {
+    let Some(trust) = proxy.trust() else {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    };
+    if let Some(cached) = trust.lookup(gun, file) {
+        let mut headers = cached.headers;
+        chain::set_cache_status(&mut headers, "hit");
+        return (cached.status, headers, cached.body).into_response();
+    }
+    if let Some(response) = maintenance_response(proxy) {
+        return response;
+    }
+
+    let result = match proxy.get_trust_metadata(trust, gun, file).await {
+        Ok(upstream_resp) => {
+            let status = StatusCode::from_u16(upstream_resp.status().as_u16())
+                .unwrap_or(StatusCode::BAD_GATEWAY);
+            let upstream_headers = upstream_resp.headers().clone();
+            upstream_resp
+                .bytes()
+                .await
+                .map(|body| trust.store(gun, file, status, &upstream_headers, body))
+                .map_err(|e| error::ProxyError::ResponseReadError(e.to_string()))
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(response) => {
+            let mut headers = response.headers;
+            chain::set_cache_status(&mut headers, "miss");
+            (response.status, headers, response.body).into_response()
+        }
+        Err(e) => upstream_error_response("Upstream trust metadata error", trust.server(), &e),
+    }
+}
+
+// 透传分页列表响应，Link 头改写为代理地址
+fn relay_page(
+    proxy: &DockerProxy,
+    name: Option<&str>,
+    result: error::ProxyResult<reqwest::Response>,
 ) -> Response {
-    match proxy.initiate_blob_upload(&name).await {
-        Ok(upload_id) => {
-            let mut headers = HeaderMap::new();
-            let location = format!("/v2/{}/blobs/uploads/{}", name, upload_id);
-            if let Ok(loc_value) = location.parse() {
-                headers.insert(header::LOCATION, loc_value);
-            } else {
-                tracing::warn!("Failed to parse location header: {}", location);
+    match result {
+        Ok(upstream_resp) => {
+            let link = proxy.client_next_link(name, &upstream_resp);
+            let mut response =
+                relay_upstream_response(proxy, name.unwrap_or_default(), upstream_resp);
+            response.headers_mut().remove(header::LINK);
+            if let Some(value) = link.and_then(|l| HeaderValue::from_str(&l).ok()) {
+                response.headers_mut().insert(header::LINK, value);
+            }
+            response
+        }
+        Err(e) => upstream_error_response(
+            "Upstream list error",
+            &proxy.upstream_host(name.unwrap_or_default()),
+            &e,
+        ),
+    }
+}
+
+// 复制上游响应头，去掉逐跳（hop-by-hop）头
+fn copy_upstream_headers(upstream: &reqwest::header::HeaderMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (key, value) in upstream.iter() {
+        let key_str = key.as_str();
+        if key_str.eq_ignore_ascii_case("connection")
+            || key_str.eq_ignore_ascii_case("transfer-encoding")
+            || key_str.eq_ignore_ascii_case("upgrade")
+        {
+            continue;
+        }
+
+        if let Ok(ax_key) = axum::http::HeaderName::from_bytes(key_str.as_bytes())
+            && let Ok(ax_val) = axum::http::HeaderValue::from_bytes(value.as_bytes())
+        {
+            headers.append(ax_key, ax_val);
+        }
+    }
+    headers
+}
+
+// 透传上游写操作的响应：状态码、响应头（Location 改写为代理地址）和 body
+fn relay_upstream_response(
+    proxy: &DockerProxy,
+    name: &str,
+    upstream_resp: reqwest::Response,
+) -> Response {
+    let status =
+        StatusCode::from_u16(upstream_resp.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut headers = copy_upstream_headers(upstream_resp.headers());
+    if let Some(location) = proxy.client_location(name, &upstream_resp) {
+        match HeaderValue::from_str(&location) {
+            Ok(value) => {
+                headers.insert(header::LOCATION, value);
             }
-            (StatusCode::ACCEPTED, headers).into_response()
+            Err(_) => tracing::warn!("Failed to parse location header: {}", location),
         }
+    }
+
+    let body = Body::from_stream(upstream_resp.bytes_stream());
+    (status, headers, body).into_response()
+}
+
+// 上传请求中需要转发给上游的请求头；客户端凭据一并转发，由上游鉴权
+fn upload_request_headers(headers: &HeaderMap) -> Vec<(&'static str, &str)> {
+    const FORWARDED: &[&str] = &[
+        "content-type",
+        "content-length",
+        "content-range",
+        "authorization",
+    ];
+    FORWARDED
+        .iter()
+        .filter_map(|name| {
+            headers
+                .get(*name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| (*name, v))
+        })
+        .collect()
+}
+
+// 转发 blob 上传（POST 初始化 / PATCH 分块 / PUT 完成），请求体以流的方式透传到上游
+async fn forward_blob_upload(
+    proxy: &DockerProxy,
+    method: reqwest::Method,
+    name: &str,
+    uuid: Option<&str>,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    body: Body,
+) -> Response {
+    let upstream_body = reqwest::Body::wrap_stream(body.into_data_stream());
+    match proxy
+        .forward_blob_upload(
+            method,
+            name,
+            uuid,
+            query,
+            upload_request_headers(headers),
+            upstream_body,
+        )
+        .await
+    {
+        Ok(upstream_resp) => relay_upstream_response(proxy, name, upstream_resp),
+        Err(e) => upstream_error_response("Upstream upload error", &proxy.upstream_host(name), &e),
+    }
+}
+
+// 单请求（monolithic）上传：在上游创建上传会话后用一次 PUT 流式转发 blob，
+// 兼容不支持 POST ?digest= 的上游
+// 调用示例：POST /v2/<name>/blobs/uploads/?digest=sha256:<hex>（请求体为 blob）
+async fn monolithic_upload(
+    proxy: &DockerProxy,
+    name: &str,
+    digest: &str,
+    headers: &HeaderMap,
+    body: Body,
+) -> Response {
+    let upstream_body = reqwest::Body::wrap_stream(body.into_data_stream());
+    match proxy
+        .monolithic_upload(name, digest, upload_request_headers(headers), upstream_body)
+        .await
+    {
+        Ok(upstream_resp) => relay_upstream_response(proxy, name, upstream_resp),
+        Err(e) => upstream_error_response("Upstream upload error", &proxy.upstream_host(name), &e),
+    }
+}
+
+// 分块上传：校验 Content-Range 后以流的方式转发到上游上传会话，
+// 上游返回的 Range / Location 原样（改写后）返回给客户端
+async fn upload_blob_chunk(
+    proxy: &DockerProxy,
+    name: &str,
+    uuid: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    body: Body,
+) -> Response {
+    if let Some(value) = headers.get(header::CONTENT_RANGE) {
+        let range = value.to_str().ok().and_then(router::parse_content_range);
+        let Some((start, end)) = range else {
+            tracing::warn!("Rejected chunk with invalid Content-Range: {:?}", value);
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "Invalid Content-Range for chunk upload",
+            )
+                .into_response();
+        };
+        tracing::debug!(
+            upload = %uuid,
+            range_start = start,
+            range_end = end,
+            "Forwarding upload chunk"
+        );
+    }
+
+    forward_blob_upload(
+        proxy,
+        reqwest::Method::PATCH,
+        name,
+        Some(uuid),
+        query,
+        headers,
+        body,
+    )
+    .await
+}
+
+// 查询上传进度：转发到上游会话；上游不可达时用本地记录的会话状态应答，
+// 返回 204 + Range，客户端据此从断点继续推送
+// 调用示例：GET /v2/<name>/blobs/uploads/<uuid>
+async fn upload_status(
+    proxy: &DockerProxy,
+    name: &str,
+    uuid: &str,
+    query: Option<&str>,
+) -> Response {
+    let result = proxy
+        .forward_blob_upload(
+            reqwest::Method::GET,
+            name,
+            Some(uuid),
+            query,
+            Vec::new(),
+            reqwest::Body::from(Vec::new()),
+        )
+        .await;
+    let error = match result {
+        Ok(upstream_resp) => return relay_upstream_response(proxy, name, upstream_resp),
+        Err(e) => e,
+    };
+
+    let Some(session) = proxy.uploads().get(name, uuid) else {
+        return upstream_error_response(
+            "Upstream upload error",
+            &proxy.upstream_host(name),
+            &error,
+        );
+    };
+    tracing::warn!(
+        upload = %uuid,
+        "Upstream unreachable, answering upload status from session store: {}",
+        error
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("0"));
+    if let Ok(value) = HeaderValue::from_str(&session.range()) {
+        headers.insert(header::RANGE, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(uuid) {
+        headers.insert("Docker-Upload-UUID", value);
+    }
+    if let Some(value) = proxy
+        .client_upload_location(&session)
+        .and_then(|l| HeaderValue::from_str(&l).ok())
+    {
+        headers.insert(header::LOCATION, value);
+    }
+    (StatusCode::NO_CONTENT, headers).into_response()
+}
+
+// 推送 manifest
+async fn put_manifest(
+    proxy: &DockerProxy,
+    name: &str,
+    reference: &str,
+    headers: &HeaderMap,
+    body: Body,
+) -> Response {
+    let body = match axum::body::to_bytes(body, MAX_MANIFEST_SIZE).await {
+        Ok(body) => body,
         Err(e) => {
-            tracing::error!("Error initiating blob upload: {}", e);
-            let status = match e {
-                error::ProxyError::BlobUploadNotSupported => StatusCode::METHOD_NOT_ALLOWED,
-                _ => StatusCode::INTERNAL_SERVER_ERROR,
-            };
-            (status, format!("Error: {}", e)).into_response()
+            tracing::warn!("Rejected manifest upload: {}", e);
+            return (StatusCode::PAYLOAD_TOO_LARGE, "Manifest too large").into_response();
+        }
+    };
+    if let Some(local) = proxy.local_registry() {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok());
+        return local_registry::put_manifest(local, name, reference, content_type, body).await;
+    }
+    // 未带 Content-Type 时按 manifest 自身的 mediaType（OCI 制品等）推送
+    let media_type = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|m| m.get("mediaType")?.as_str().map(String::from));
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .or(media_type.as_deref())
+        .unwrap_or("application/vnd.docker.distribution.manifest.v2+json");
+
+    match proxy
+        .put_manifest(
+            name,
+            reference,
+            content_type,
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok()),
+            body,
+        )
+        .await
+    {
+        Ok(upstream_resp) => relay_upstream_response(proxy, name, upstream_resp),
+        Err(e) => {
+            upstream_error_response("Upstream manifest error", &proxy.upstream_host(name), &e)
         }
     }
 }
 
-// 完成 blob 上传
-async fn complete_blob_upload() -> impl IntoResponse {
-    (StatusCode::CREATED, "Upload complete")
+// 删除 manifest 或 blob：需配置 allow_delete，转发客户端凭据由上游鉴权，成功后清理本地缓存
+async fn delete_object(
+    proxy: &DockerProxy,
+    name: &str,
+    endpoint: &str,
+    reference: &str,
+    headers: &HeaderMap,
+) -> Response {
+    if !proxy.deletes_allowed() {
+        return (StatusCode::METHOD_NOT_ALLOWED, "Deletes are disabled").into_response();
+    }
+    if let Some(local) = proxy.local_registry() {
+        return local_registry::delete(local, name, endpoint, reference);
+    }
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+
+    match proxy.delete(name, endpoint, reference, authorization).await {
+        Ok(upstream_resp) => {
+            if upstream_resp.status().is_success()
+                && let Some(cache) = proxy.cache()
+            {
+                if endpoint == "blobs" {
+                    // the blob stays cached for the other repositories holding it
+                    if cache.belongs_to(reference, name) && cache.drop_repository(reference, name) {
+                        cache.remove(reference);
+                    }
+                } else {
+                    cache.unpin_manifest(name, reference);
+                }
+            }
+            relay_upstream_response(proxy, name, upstream_resp)
+        }
+        Err(e) => upstream_error_response("Upstream delete error", &proxy.upstream_host(name), &e),
+    }
 }
 
 // Wildcard dispatch handlers for /v2/*rest to support repository names containing '/'
-pub async fn v2_get(State(proxy): State<Arc<DockerProxy>>, Path(rest): Path<String>) -> Response {
-    match router::parse_v2_path(&rest) {
+pub async fn v2_get(
+    State(proxy): State<Arc<DockerProxy>>,
+    Path(rest): Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let endpoint = router::parse_v2_request(&reqwest::Method::GET, &rest);
+    if let Some(response) = policy_response(&proxy, &reqwest::Method::GET, &endpoint) {
+        return response;
+    }
+    match endpoint {
         V2Endpoint::Manifest { name, reference } => {
-            get_manifest(State(proxy), Path((name, reference))).await
+            get_manifest(State(proxy), Path((name, reference)), &headers).await
         }
-        V2Endpoint::Blob { name, digest } => get_blob(State(proxy), Path((name, digest)))
+        V2Endpoint::Blob { name, digest } => get_blob(State(proxy), Path((name, digest)), headers)
             .await
             .into_response(),
+        V2Endpoint::BlobUploadStatus { name, uuid } => match proxy.local_registry() {
+            Some(local) => local_registry::upload_status(local, &name, &uuid),
+            None => match maintenance_response(&proxy) {
+                Some(response) => response,
+                None => upload_status(&proxy, &name, &uuid, query.as_deref()).await,
+            },
+        },
+        V2Endpoint::TagList { name } => get_tags(&proxy, &name, query.as_deref()).await,
+        V2Endpoint::Catalog => get_catalog(&proxy, query.as_deref()).await,
+        V2Endpoint::TrustMetadata { gun, file } => get_trust_metadata(&proxy, &gun, &file).await,
         _ => (StatusCode::NOT_FOUND, "Not Found").into_response(),
     }
 }
 
-pub async fn v2_head(State(proxy): State<Arc<DockerProxy>>, Path(rest): Path<String>) -> Response {
-    match router::parse_v2_path(&rest) {
+pub async fn v2_head(
+    State(proxy): State<Arc<DockerProxy>>,
+    Path(rest): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let endpoint = router::parse_v2_path(&rest);
+    if let Some(response) = policy_response(&proxy, &reqwest::Method::HEAD, &endpoint) {
+        return response;
+    }
+    match endpoint {
         V2Endpoint::Manifest { name, reference } => {
-            head_manifest(State(proxy), Path((name, reference))).await
+            head_manifest(State(proxy), Path((name, reference)), &headers).await
         }
         V2Endpoint::Blob { name, digest } => head_blob(State(proxy), Path((name, digest)))
             .await
@@ -321,16 +2185,133 @@ pub async fn v2_head(State(proxy): State<Arc<DockerProxy>>, Path(rest): Path<Str
     }
 }
 
-pub async fn v2_post(State(proxy): State<Arc<DockerProxy>>, Path(rest): Path<String>) -> Response {
-    match router::parse_v2_path(&rest) {
-        V2Endpoint::BlobUploadInit { name } => initiate_blob_upload(State(proxy), Path(name)).await,
+pub async fn v2_post(
+    State(proxy): State<Arc<DockerProxy>>,
+    Path(rest): Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Some(response) = maintenance_response(&proxy) {
+        return response;
+    }
+    let endpoint = router::parse_v2_path(&rest);
+    if let Some(response) = policy_response(&proxy, &reqwest::Method::POST, &endpoint) {
+        return response;
+    }
+    match endpoint {
+        V2Endpoint::BlobUploadInit { name } => {
+            let query = query.as_deref().unwrap_or_default();
+            if let Some(local) = proxy.local_registry() {
+                return local_registry::upload_init(local, &name, query, body).await;
+            }
+            // 单请求上传：POST ?digest=... 携带 blob；跨仓库挂载（mount）原样转发
+            if router::query_param(query, "mount").is_none()
+                && let Some(digest) = router::query_param(query, "digest").filter(|d| !d.is_empty())
+            {
+                return monolithic_upload(&proxy, &name, &digest, &headers, body).await;
+            }
+            forward_blob_upload(
+                &proxy,
+                reqwest::Method::POST,
+                &name,
+                None,
+                Some(query),
+                &headers,
+                body,
+            )
+            .await
+        }
+        _ => (StatusCode::NOT_FOUND, "Not Found").into_response(),
+    }
+}
+
+pub async fn v2_patch(
+    State(proxy): State<Arc<DockerProxy>>,
+    Path(rest): Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Some(response) = maintenance_response(&proxy) {
+        return response;
+    }
+    let endpoint = router::parse_v2_request(&reqwest::Method::PATCH, &rest);
+    if let Some(response) = policy_response(&proxy, &reqwest::Method::PATCH, &endpoint) {
+        return response;
+    }
+    match endpoint {
+        V2Endpoint::BlobUploadChunk { name, uuid } => match proxy.local_registry() {
+            Some(local) => local_registry::upload_chunk(local, &name, &uuid, &headers, body).await,
+            None => upload_blob_chunk(&proxy, &name, &uuid, query.as_deref(), &headers, body).await,
+        },
+        _ => (StatusCode::NOT_FOUND, "Not Found").into_response(),
+    }
+}
+
+pub async fn v2_put(
+    State(proxy): State<Arc<DockerProxy>>,
+    Path(rest): Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Some(response) = maintenance_response(&proxy) {
+        return response;
+    }
+    let endpoint = router::parse_v2_path(&rest);
+    if let Some(response) = policy_response(&proxy, &reqwest::Method::PUT, &endpoint) {
+        return response;
+    }
+    match endpoint {
+        V2Endpoint::BlobUploadComplete { name, uuid } => {
+            if let Some(local) = proxy.local_registry() {
+                return local_registry::upload_complete(
+                    local,
+                    &name,
+                    &uuid,
+                    query.as_deref(),
+                    body,
+                )
+                .await;
+            }
+            forward_blob_upload(
+                &proxy,
+                reqwest::Method::PUT,
+                &name,
+                Some(&uuid),
+                query.as_deref(),
+                &headers,
+                body,
+            )
+            .await
+        }
+        V2Endpoint::Manifest { name, reference } => {
+            put_manifest(&proxy, &name, &reference, &headers, body).await
+        }
         _ => (StatusCode::NOT_FOUND, "Not Found").into_response(),
     }
 }
 
-pub async fn v2_put(State(_proxy): State<Arc<DockerProxy>>, Path(rest): Path<String>) -> Response {
-    match router::parse_v2_path(&rest) {
-        V2Endpoint::BlobUploadComplete { .. } => complete_blob_upload().await.into_response(),
+pub async fn v2_delete(
+    State(proxy): State<Arc<DockerProxy>>,
+    Path(rest): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = maintenance_response(&proxy) {
+        return response;
+    }
+    let endpoint = router::parse_v2_path(&rest);
+    if let Some(response) = policy_response(&proxy, &reqwest::Method::DELETE, &endpoint) {
+        return response;
+    }
+    match endpoint {
+        V2Endpoint::Manifest { name, reference } => {
+            delete_object(&proxy, &name, "manifests", &reference, &headers).await
+        }
+        V2Endpoint::Blob { name, digest } => {
+            delete_object(&proxy, &name, "blobs", &digest, &headers).await
+        }
         _ => (StatusCode::NOT_FOUND, "Not Found").into_response(),
     }
 }
@@ -0,0 +1,99 @@
+/// API keys guarding the `/admin/`, `/debug/` and `/api/` endpoints
+///
+/// With `[admin] api_keys` set, those endpoints require
+/// `Authorization: Bearer <key>` with one of the keys. Keys may be given as
+/// "sha256:<hex>" so the config file does not hold them in plain text; plain
+/// keys are hashed on load, and presented keys are compared by their SHA-256
+/// in constant time.
+use axum::http::{HeaderMap, header};
+use sha2::{Digest, Sha256};
+
+use crate::config::AdminConfig;
+
+pub struct ApiKeys {
+    hashes: Vec<[u8; 32]>,
+}
+
+/// Request extension marking a request authorized by an API key
+#[derive(Debug, Clone, Copy)]
+pub struct ApiKeyAuthorized;
+
+impl ApiKeys {
+    /// `None` when no keys are configured
+    pub fn from_config(config: &AdminConfig) -> Option<Self> {
+        if config.api_keys.is_empty() {
+            return None;
+        }
+        let hashes = config
+            .api_keys
+            .iter()
+            .filter_map(|key| match key.strip_prefix("sha256:") {
+                Some(hex) => hex::decode(hex).ok()?.try_into().ok(),
+                None => Some(Sha256::digest(key.as_bytes()).into()),
+            })
+            .collect();
+        Some(Self { hashes })
+    }
+
+    /// Whether the request carries one of the keys as a Bearer token
+    pub fn verify(&self, headers: &HeaderMap) -> bool {
+        let Some(key) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, key)| key.trim())
+        else {
+            return false;
+        };
+        let presented: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+        // check every key so the time taken does not reveal which one matched
+        self.hashes.iter().fold(false, |matched, hash| {
+            matched
+                | (hash
+                    .iter()
+                    .zip(&presented)
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", key).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_verify() {
+        let hashed = hex::encode(Sha256::digest(b"hashed-key"));
+        let config = AdminConfig {
+            api_keys: vec!["plain-key".to_string(), format!("sha256:{}", hashed)],
+        };
+        config.validate().unwrap();
+        let keys = ApiKeys::from_config(&config).unwrap();
+        assert!(keys.verify(&bearer("plain-key")));
+        assert!(keys.verify(&bearer("hashed-key")));
+        assert!(!keys.verify(&bearer("wrong-key")));
+        assert!(!keys.verify(&bearer(&format!("sha256:{}", hashed))));
+        assert!(!keys.verify(&HeaderMap::new()));
+
+        let mut basic = HeaderMap::new();
+        basic.insert(header::AUTHORIZATION, "Basic plain-key".parse().unwrap());
+        assert!(!keys.verify(&basic));
+
+        assert!(ApiKeys::from_config(&AdminConfig::default()).is_none());
+        let invalid = AdminConfig {
+            api_keys: vec!["sha256:abc".to_string()],
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,144 @@
+/// Command line arguments
+///
+/// The configuration file is `--config`, or else the first of
+/// `/config/config.toml` (containers) and `./config/config.toml` (local runs)
+/// that exists; without one the defaults apply. `--port`, `--registry` and `--log-level` take precedence over
+/// both the file and `DOCKER_PROXY__*` environment variables.
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+
+use crate::config::Config;
+use crate::env_overrides::PREFIX;
+
+/// Configuration files tried without `--config`
+pub const DEFAULT_CONFIG_PATHS: [&str; 2] = ["/config/config.toml", "./config/config.toml"];
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "docker-proxy",
+    version,
+    about = "Docker registry pull-through proxy",
+    after_help = "Without a command the proxy server is started."
+)]
+pub struct Args {
+    /// Configuration file [default: /config/config.toml, then ./config/config.toml, if any]
+    #[arg(short, long, value_name = "PATH", env = "DOCKER_PROXY_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Listen port, overriding [server] port
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Default upstream registry URL, overriding [proxy] default
+    #[arg(long, value_name = "URL")]
+    pub registry: Option<String>,
+    /// Log level (trace, debug, info, warn, error), overriding [log] level
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// Run the synthetic upstream registry for benchmarks instead of the proxy
+    #[arg(
+        long,
+        value_name = "ADDR",
+        num_args = 0..=1,
+        default_missing_value = "127.0.0.1:5099"
+    )]
+    pub bench_server: Option<String>,
+    #[cfg(feature = "client")]
+    #[command(flatten)]
+    pub connection: crate::cli::Connection,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Write an annotated config file with every option and its default
+    Init(crate::sample_config::InitArgs),
+    #[cfg(feature = "client")]
+    #[command(flatten)]
+    Admin(crate::cli::Command),
+}
+
+impl Args {
+    /// Load the configuration with the options given on the command line
+    pub fn load_config(&self) -> Result<Config, Box<dyn std::error::Error>> {
+        let path = match &self.config {
+            Some(path) => Some(path.as_path()),
+            None => DEFAULT_CONFIG_PATHS
+                .iter()
+                .map(Path::new)
+                .find(|path| path.exists()),
+        };
+        match path {
+            Some(path) => Config::from_file_with_overrides(path, self.overrides()),
+            None => Config::from_str_with_env("", std::env::vars().chain(self.overrides())),
+        }
+    }
+
+    // The options given, named like the environment variables they outrank
+    fn overrides(&self) -> Vec<(String, String)> {
+        let options = [
+            ("SERVER__PORT", self.port.map(|port| port.to_string())),
+            ("PROXY__DEFAULT", self.registry.clone()),
+            ("LOG__LEVEL", self.log_level.clone()),
+        ];
+        options
+            .into_iter()
+            .filter_map(|(name, value)| Some((format!("{}{}", PREFIX, name), value?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let args = Args::try_parse_from([
+            "docker-proxy",
+            "--config",
+            "/etc/docker-proxy.toml",
+            "-p",
+            "9090",
+            "--registry",
+            "https://mirror.gcr.io",
+            "--log-level",
+            "debug",
+        ])
+        .unwrap();
+        assert_eq!(args.config, Some(PathBuf::from("/etc/docker-proxy.toml")));
+        assert_eq!(
+            args.overrides(),
+            vec![
+                ("DOCKER_PROXY__SERVER__PORT".to_string(), "9090".to_string()),
+                (
+                    "DOCKER_PROXY__PROXY__DEFAULT".to_string(),
+                    "https://mirror.gcr.io".to_string()
+                ),
+                ("DOCKER_PROXY__LOG__LEVEL".to_string(), "debug".to_string()),
+            ]
+        );
+
+        let args = Args::try_parse_from(["docker-proxy"]).unwrap();
+        assert!(args.overrides().is_empty());
+        assert_eq!(args.bench_server, None);
+        let args = Args::try_parse_from(["docker-proxy", "--bench-server"]).unwrap();
+        assert_eq!(args.bench_server.as_deref(), Some("127.0.0.1:5099"));
+        let args =
+            Args::try_parse_from(["docker-proxy", "--bench-server", "127.0.0.1:6000"]).unwrap();
+        assert_eq!(args.bench_server.as_deref(), Some("127.0.0.1:6000"));
+        assert!(Args::try_parse_from(["docker-proxy", "--port", "http"]).is_err());
+    }
+
+    #[test]
+    fn test_defaults_without_config_file() {
+        let args = Args::try_parse_from(["docker-proxy", "--port", "5000"]).unwrap();
+        let config = Config::from_str_with_env("", args.overrides()).unwrap();
+        assert_eq!(config.server_addr(), "0.0.0.0:5000");
+        assert_eq!(
+            config.default_registry_url(),
+            "https://registry-1.docker.io"
+        );
+        assert!(config.logs_to_console());
+    }
+}
@@ -0,0 +1,608 @@
+/// Bearer token negotiation with upstream registries
+///
+/// Registries answer unauthenticated requests with a `WWW-Authenticate:
+/// Bearer realm=...,service=...` challenge. Tokens are requested from the
+/// realm for the repository scope the request needs (`pull` for reads,
+/// `push,pull` for writes) and cached per registry and scope until shortly
+/// before they expire. Logins with credentials ask for an offline token; a
+/// registry that issues one gets later tokens requested with the OAuth2
+/// `refresh_token` grant instead of the credentials.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::Method;
+use serde::Deserialize;
+
+use crate::clock::Clock;
+use crate::router::{self, V2Endpoint};
+
+/// Lifetime assumed when the token response has no `expires_in` (per the
+/// token spec)
+const DEFAULT_TOKEN_LIFETIME_SECS: u64 = 60;
+
+/// Tokens are refreshed this long before they expire
+const EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// Client name sent with offline token and refresh token requests
+pub const CLIENT_ID: &str = "docker-proxy";
+
+/// User name with which credential helpers return a refresh (identity)
+/// token instead of a password
+pub const IDENTITY_TOKEN_USER: &str = "<token>";
+
+/// A parsed `WWW-Authenticate` challenge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    /// Lowercased auth scheme, e.g. "bearer"
+    pub scheme: String,
+    pub params: HashMap<String, String>,
+}
+
+impl Challenge {
+    /// Parameter value by case-insensitive name
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+}
+
+/// Parse a `WWW-Authenticate` header value: its Bearer challenge if it has
+/// several, else its first
+pub fn parse_www_authenticate(header: &str) -> Option<Challenge> {
+    let mut challenges = parse_challenges(header);
+    let bearer = challenges.iter().position(|c| c.scheme == "bearer");
+    match bearer {
+        Some(index) => Some(challenges.swap_remove(index)),
+        None => challenges.into_iter().next(),
+    }
+}
+
+/// Parse every challenge of a `WWW-Authenticate` header value (RFC 7235),
+/// e.g. `Basic realm="r", Bearer realm="https://auth/token",scope="a,b"`.
+/// Quoted values may hold commas and escaped quotes; token68 credentials
+/// are skipped.
+pub fn parse_challenges(header: &str) -> Vec<Challenge> {
+    let mut parser = ChallengeParser {
+        chars: header.chars().collect(),
+        pos: 0,
+    };
+    let mut challenges = Vec::new();
+    loop {
+        parser.skip_while(|c| c == ',' || c == ' ' || c == '\t');
+        let Some(scheme) = parser.token() else {
+            break;
+        };
+        let mut challenge = Challenge {
+            scheme: scheme.to_ascii_lowercase(),
+            params: HashMap::new(),
+        };
+        parser.skip_whitespace();
+        if !parser.token68() {
+            while let Some((name, value)) = parser.param() {
+                challenge.params.insert(name.to_ascii_lowercase(), value);
+                // a comma separates both parameters and challenges
+                let start = parser.pos;
+                parser.skip_while(|c| c == ',' || c == ' ' || c == '\t');
+                if !parser.at_param() {
+                    parser.pos = start;
+                    break;
+                }
+            }
+        }
+        challenges.push(challenge);
+        // anything else up to the next comma is malformed
+        parser.skip_while(|c| c != ',');
+    }
+    challenges
+}
+
+struct ChallengeParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl ChallengeParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_while(&mut self, f: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&f) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.skip_while(|c| c == ' ' || c == '\t');
+    }
+
+    fn token(&mut self) -> Option<String> {
+        let start = self.pos;
+        self.skip_while(is_tchar);
+        (self.pos > start).then(|| self.chars[start..self.pos].iter().collect())
+    }
+
+    // Skip a token68 (e.g. "abc123==") if one ends the challenge here
+    fn token68(&mut self) -> bool {
+        let start = self.pos;
+        self.skip_while(|c| c.is_ascii_alphanumeric() || "-._~+/".contains(c));
+        if self.pos > start {
+            self.skip_while(|c| c == '=');
+            self.skip_whitespace();
+            if matches!(self.peek(), None | Some(',')) {
+                return true;
+            }
+        }
+        self.pos = start;
+        false
+    }
+
+    // Whether a `name=` parameter starts here, rather than a new challenge
+    fn at_param(&mut self) -> bool {
+        let start = self.pos;
+        let found = self.token().is_some() && {
+            self.skip_whitespace();
+            self.peek() == Some('=')
+        };
+        self.pos = start;
+        found
+    }
+
+    // `name = value`, the value a token or a quoted string
+    fn param(&mut self) -> Option<(String, String)> {
+        let start = self.pos;
+        let param = self.token().and_then(|name| {
+            self.skip_whitespace();
+            if self.peek() != Some('=') {
+                return None;
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let value = match self.peek() {
+                Some('"') => self.quoted_string(),
+                _ => self.token()?,
+            };
+            self.skip_whitespace();
+            Some((name, value))
+        });
+        if param.is_none() {
+            self.pos = start;
+        }
+        param
+    }
+
+    fn quoted_string(&mut self) -> String {
+        let mut value = String::new();
+        self.pos += 1;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '"' => break,
+                '\\' => {
+                    if let Some(escaped) = self.peek() {
+                        value.push(escaped);
+                        self.pos += 1;
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+        value
+    }
+}
+
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// Repository actions a request needs, by HTTP method
+pub fn actions_for(method: &Method) -> &'static str {
+    if method == Method::GET || method == Method::HEAD {
+        "pull"
+    } else if method == Method::DELETE {
+        "delete"
+    } else {
+        "push,pull"
+    }
+}
+
+/// Token scope for an upstream registry API URL, e.g.
+/// `repository:library/nginx:pull`. Cross-repository mounts also need pull
+/// access to the source repository.
+pub fn scope_for(method: &Method, url: &reqwest::Url) -> Option<String> {
+    let path = url.path().strip_prefix("/v2/")?;
+    let name = match router::parse_v2_path(path) {
+        V2Endpoint::Manifest { name, .. }
+        | V2Endpoint::Blob { name, .. }
+        | V2Endpoint::BlobUploadInit { name }
+        | V2Endpoint::BlobUploadComplete { name, .. }
+        | V2Endpoint::BlobUploadChunk { name, .. }
+        | V2Endpoint::BlobUploadStatus { name, .. }
+        | V2Endpoint::TagList { name }
+        | V2Endpoint::TrustMetadata { gun: name, .. } => name,
+        V2Endpoint::Catalog => return Some("registry:catalog:*".to_string()),
+        V2Endpoint::Unknown => return None,
+    };
+    let repository = percent_encoding::percent_decode_str(&name).decode_utf8_lossy();
+
+    let mut scope = format!("repository:{}:{}", repository, actions_for(method));
+    let source = url
+        .query_pairs()
+        .find(|(key, _)| key == "from")
+        .map(|(_, from)| from.into_owned());
+    if let Some(source) = source.filter(|s| *s != repository) {
+        scope.push_str(&format!(" repository:{}:pull", source));
+    }
+    Some(scope)
+}
+
+/// The repository of a scope's first `repository:` entry
+pub fn scope_repository(scope: &str) -> Option<&str> {
+    let (repository, _actions) = scope
+        .split(' ')
+        .next()?
+        .strip_prefix("repository:")?
+        .rsplit_once(':')?;
+    Some(repository)
+}
+
+/// Whether a scope asks for more than pull access to a repository, i.e.
+/// push or delete
+pub fn is_write_scope(scope: &str) -> bool {
+    scope.split(' ').any(|entry| {
+        entry
+            .strip_prefix("repository:")
+            .and_then(|entry| entry.rsplit_once(':'))
+            .is_some_and(|(_, actions)| actions.split(',').any(|action| action != "pull"))
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+/// Extract the token and its lifetime from a token endpoint response body
+pub fn parse_token_response(body: &str) -> Option<(String, Duration)> {
+    let response: TokenResponse = serde_json::from_str(body).ok()?;
+    let token = response
+        .token
+        .filter(|t| !t.is_empty())
+        .or(response.access_token.filter(|t| !t.is_empty()))?;
+    let lifetime = response.expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECS);
+    Some((token, Duration::from_secs(lifetime)))
+}
+
+/// The refresh token of a token endpoint response body, if it has one
+pub fn parse_refresh_token(body: &str) -> Option<String> {
+    serde_json::from_str::<TokenResponse>(body)
+        .ok()?
+        .refresh_token
+        .filter(|t| !t.is_empty())
+}
+
+/// Token URL for a bearer challenge and scope; `offline` asks for a refresh
+/// token along with the access token
+pub fn token_url(challenge: &Challenge, scope: &str, offline: bool) -> Option<reqwest::Url> {
+    let mut url = reqwest::Url::parse(challenge.param("realm")?).ok()?;
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(service) = challenge.param("service") {
+            query.append_pair("service", service);
+        }
+        for scope in scope.split(' ') {
+            query.append_pair("scope", scope);
+        }
+        if offline {
+            query.append_pair("offline_token", "true");
+            query.append_pair("client_id", CLIENT_ID);
+        }
+    }
+    Some(url)
+}
+
+/// Form for a `refresh_token` grant POSTed to the challenge's realm
+pub fn refresh_form(
+    challenge: &Challenge,
+    scope: &str,
+    refresh_token: &str,
+) -> Vec<(&'static str, String)> {
+    let mut form = vec![
+        ("grant_type", "refresh_token".to_string()),
+        ("client_id", CLIENT_ID.to_string()),
+        ("refresh_token", refresh_token.to_string()),
+        ("scope", scope.to_string()),
+    ];
+    if let Some(service) = challenge.param("service") {
+        form.push(("service", service.to_string()));
+    }
+    form
+}
+
+/// Cached bearer tokens keyed by registry origin and scope, and refresh
+/// tokens keyed by registry origin (and credential namespace)
+pub struct TokenCache {
+    tokens: Mutex<HashMap<(String, String), (String, Instant)>>,
+    refresh_tokens: Mutex<HashMap<String, String>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl TokenCache {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            tokens: Mutex::new(HashMap::new()),
+            refresh_tokens: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// A still valid token for `registry` and `scope`
+    pub fn get(&self, registry: &str, scope: &str) -> Option<String> {
+        let mut tokens = self.lock();
+        let key = (registry.to_string(), scope.to_string());
+        match tokens.get(&key) {
+            Some((token, expires)) if self.clock.instant() < *expires => Some(token.clone()),
+            Some(_) => {
+                tokens.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, registry: &str, scope: &str, token: String, lifetime: Duration) {
+        let expires = self.clock.instant() + lifetime.saturating_sub(EXPIRY_MARGIN);
+        self.lock()
+            .insert((registry.to_string(), scope.to_string()), (token, expires));
+    }
+
+    /// Drop a token the registry rejected
+    pub fn invalidate(&self, registry: &str, scope: &str) {
+        self.lock()
+            .remove(&(registry.to_string(), scope.to_string()));
+    }
+
+    /// The refresh token issued by `registry`, if any
+    pub fn refresh_token(&self, registry: &str) -> Option<String> {
+        self.refresh_lock().get(registry).cloned()
+    }
+
+    pub fn set_refresh_token(&self, registry: &str, refresh_token: String) {
+        self.refresh_lock()
+            .insert(registry.to_string(), refresh_token);
+    }
+
+    /// Drop a refresh token the registry no longer accepts
+    pub fn forget_refresh_token(&self, registry: &str) {
+        self.refresh_lock().remove(registry);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(String, String), (String, Instant)>> {
+        self.tokens.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn refresh_lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, String>> {
+        self.refresh_tokens
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn url(s: &str) -> reqwest::Url {
+        reqwest::Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_www_authenticate() {
+        let challenge = parse_www_authenticate(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/nginx:pull""#,
+        )
+        .unwrap();
+        assert_eq!(challenge.scheme, "bearer");
+        assert_eq!(
+            challenge.param("realm"),
+            Some("https://auth.docker.io/token")
+        );
+        assert_eq!(challenge.param("Service"), Some("registry.docker.io"));
+
+        let basic = parse_www_authenticate(r#"Basic realm="registry""#).unwrap();
+        assert_eq!(basic.scheme, "basic");
+        assert!(parse_www_authenticate("").is_none());
+
+        // Commas and escaped quotes inside quoted values
+        let challenge = parse_www_authenticate(
+            r#"Bearer realm="https://auth.example.com/token?a=1,b=2",scope="repository:a:pull,push repository:b:pull",service = registry , error_description="say \"hi\"""#,
+        )
+        .unwrap();
+        assert_eq!(
+            challenge.param("realm"),
+            Some("https://auth.example.com/token?a=1,b=2")
+        );
+        assert_eq!(
+            challenge.param("scope"),
+            Some("repository:a:pull,push repository:b:pull")
+        );
+        assert_eq!(challenge.param("service"), Some("registry"));
+        assert_eq!(challenge.param("error_description"), Some(r#"say "hi""#));
+
+        // Several challenges, the Bearer one preferred
+        let header = r#"Negotiate abc123==, Basic realm="a, b", Bearer realm="https://auth/token",service="reg""#;
+        let challenges = parse_challenges(header);
+        let schemes: Vec<&str> = challenges.iter().map(|c| c.scheme.as_str()).collect();
+        assert_eq!(schemes, ["negotiate", "basic", "bearer"]);
+        assert!(challenges[0].params.is_empty());
+        assert_eq!(challenges[1].param("realm"), Some("a, b"));
+        let bearer = parse_www_authenticate(header).unwrap();
+        assert_eq!(bearer.scheme, "bearer");
+        assert_eq!(bearer.param("realm"), Some("https://auth/token"));
+        assert_eq!(bearer.param("service"), Some("reg"));
+    }
+
+    #[test]
+    fn test_is_write_scope() {
+        assert!(!is_write_scope("repository:owner/repo:pull"));
+        assert!(!is_write_scope("registry:catalog:*"));
+        assert!(is_write_scope("repository:owner/repo:push,pull"));
+        assert!(is_write_scope("repository:owner/repo:delete"));
+        assert!(is_write_scope(
+            "repository:owner/repo:push,pull repository:owner/base:pull"
+        ));
+    }
+
+    #[test]
+    fn test_scope_for() {
+        assert_eq!(
+            scope_for(
+                &Method::GET,
+                &url("https://ghcr.io/v2/owner/repo/manifests/latest")
+            ),
+            Some("repository:owner/repo:pull".to_string())
+        );
+        assert_eq!(
+            scope_for(
+                &Method::PATCH,
+                &url("https://ghcr.io/v2/owner/repo/blobs/uploads/abc?_state=x")
+            ),
+            Some("repository:owner/repo:push,pull".to_string())
+        );
+        assert_eq!(
+            scope_for(
+                &Method::DELETE,
+                &url("https://ghcr.io/v2/owner/repo/manifests/sha256:abc")
+            ),
+            Some("repository:owner/repo:delete".to_string())
+        );
+        // a repository segment named like an endpoint does not confuse the split
+        assert_eq!(
+            scope_for(
+                &Method::GET,
+                &url("https://ghcr.io/v2/owner/blobs/app/tags/list")
+            ),
+            Some("repository:owner/blobs/app:pull".to_string())
+        );
+        assert_eq!(
+            scope_for(
+                &Method::POST,
+                &url(
+                    "https://ghcr.io/v2/owner/repo/blobs/uploads/?mount=sha256:abc&from=owner/base"
+                )
+            ),
+            Some("repository:owner/repo:push,pull repository:owner/base:pull".to_string())
+        );
+        assert_eq!(
+            scope_for(&Method::GET, &url("https://ghcr.io/v2/_catalog?n=10")),
+            Some("registry:catalog:*".to_string())
+        );
+        assert_eq!(scope_for(&Method::GET, &url("https://ghcr.io/v2/")), None);
+
+        assert_eq!(
+            scope_repository("repository:owner/repo:push,pull repository:owner/base:pull"),
+            Some("owner/repo")
+        );
+        assert_eq!(scope_repository("registry:catalog:*"), None);
+    }
+
+    #[test]
+    fn test_parse_token_response() {
+        let (token, lifetime) =
+            parse_token_response(r#"{"token": "abc", "expires_in": 300}"#).unwrap();
+        assert_eq!(token, "abc");
+        assert_eq!(lifetime, Duration::from_secs(300));
+
+        let (token, lifetime) = parse_token_response(r#"{"access_token": "xyz"}"#).unwrap();
+        assert_eq!(token, "xyz");
+        assert_eq!(lifetime, Duration::from_secs(DEFAULT_TOKEN_LIFETIME_SECS));
+
+        assert!(parse_token_response(r#"{"token": ""}"#).is_none());
+        assert!(parse_token_response("not json").is_none());
+
+        assert_eq!(
+            parse_refresh_token(r#"{"token": "abc", "refresh_token": "r1"}"#).as_deref(),
+            Some("r1")
+        );
+        assert!(parse_refresh_token(r#"{"token": "abc"}"#).is_none());
+    }
+
+    #[test]
+    fn test_token_url() {
+        let challenge = parse_www_authenticate(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io""#,
+        )
+        .unwrap();
+        let url = token_url(
+            &challenge,
+            "repository:owner/repo:push,pull repository:owner/base:pull",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://auth.docker.io/token?service=registry.docker.io&scope=repository%3Aowner%2Frepo%3Apush%2Cpull&scope=repository%3Aowner%2Fbase%3Apull"
+        );
+        let offline = token_url(&challenge, "repository:owner/repo:pull", true).unwrap();
+        assert_eq!(
+            offline.as_str(),
+            "https://auth.docker.io/token?service=registry.docker.io&scope=repository%3Aowner%2Frepo%3Apull&offline_token=true&client_id=docker-proxy"
+        );
+
+        let form = refresh_form(&challenge, "repository:owner/repo:pull", "r1");
+        assert!(form.contains(&("grant_type", "refresh_token".to_string())));
+        assert!(form.contains(&("refresh_token", "r1".to_string())));
+        assert!(form.contains(&("service", "registry.docker.io".to_string())));
+    }
+
+    #[test]
+    fn test_token_cache() {
+        let clock = ManualClock::new(0);
+        let cache = TokenCache::new(clock.clone());
+        cache.insert(
+            "https://ghcr.io",
+            "repository:a:pull",
+            "t1".to_string(),
+            Duration::from_secs(300),
+        );
+        assert_eq!(
+            cache.get("https://ghcr.io", "repository:a:pull"),
+            Some("t1".to_string())
+        );
+        assert_eq!(cache.get("https://ghcr.io", "repository:a:push,pull"), None);
+
+        // tokens about to expire are not handed out
+        cache.insert(
+            "https://ghcr.io",
+            "repository:b:pull",
+            "t2".to_string(),
+            Duration::from_secs(5),
+        );
+        assert_eq!(cache.get("https://ghcr.io", "repository:b:pull"), None);
+
+        // refreshed once within the margin before expiry
+        clock.advance(Duration::from_secs(289));
+        assert!(cache.get("https://ghcr.io", "repository:a:pull").is_some());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.get("https://ghcr.io", "repository:a:pull"), None);
+
+        cache.insert(
+            "https://ghcr.io",
+            "repository:a:pull",
+            "t3".to_string(),
+            Duration::from_secs(300),
+        );
+        cache.invalidate("https://ghcr.io", "repository:a:pull");
+        assert_eq!(cache.get("https://ghcr.io", "repository:a:pull"), None);
+    }
+}
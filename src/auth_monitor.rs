@@ -0,0 +1,285 @@
+/// Tracking of upstream authentication failures
+///
+/// Every 401/403 from an upstream registry is classified (missing credentials,
+/// invalid or expired token, insufficient scope, forbidden) and recorded per
+/// upstream host together with a few recent samples, so a revoked token shows
+/// up in `/api/auth/status` before users start reporting failed pulls.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::auth;
+use crate::clock::Clock;
+
+/// Number of recent failure samples kept per upstream
+const MAX_SAMPLES: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthFailureKind {
+    /// 401 without a token error, usually missing credentials
+    Unauthorized,
+    /// Token rejected as invalid or expired
+    InvalidToken,
+    /// Token valid but lacks the requested scope
+    InsufficientScope,
+    /// 403 from the registry
+    Forbidden,
+}
+
+impl AuthFailureKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuthFailureKind::Unauthorized => "unauthorized",
+            AuthFailureKind::InvalidToken => "invalid_token",
+            AuthFailureKind::InsufficientScope => "insufficient_scope",
+            AuthFailureKind::Forbidden => "forbidden",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthFailureSample {
+    pub timestamp: u64,
+    pub kind: AuthFailureKind,
+    pub status: u16,
+    pub method: String,
+    /// Request path without query string (upload state may be sensitive)
+    pub path: String,
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct UpstreamAuthStatus {
+    pub host: String,
+    pub total_failures: u64,
+    /// Failures since the last successful request to this upstream
+    pub consecutive_failures: u64,
+    pub failures_by_kind: BTreeMap<&'static str, u64>,
+    pub last_failure: Option<u64>,
+    pub last_success: Option<u64>,
+    pub recent: VecDeque<AuthFailureSample>,
+}
+
+pub struct AuthMonitor {
+    upstreams: Mutex<HashMap<String, UpstreamAuthStatus>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AuthMonitor {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            upstreams: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Record the outcome of an upstream request
+    pub fn observe(&self, method: &reqwest::Method, response: &reqwest::Response) {
+        let status = response.status();
+        let url = response.url();
+        let Some(host) = url.host_str() else {
+            return;
+        };
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+
+        let challenge = response
+            .headers()
+            .get(reqwest::header::WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok());
+
+        match classify(status, challenge) {
+            Some((kind, detail)) => {
+                tracing::warn!(
+                    upstream = %host,
+                    status = status.as_u16(),
+                    kind = kind.as_str(),
+                    "Upstream authentication failure"
+                );
+                self.record_failure(
+                    &host,
+                    AuthFailureSample {
+                        timestamp: self.clock.now_secs(),
+                        kind,
+                        status: status.as_u16(),
+                        method: method.to_string(),
+                        path: url.path().to_string(),
+                        detail,
+                    },
+                );
+            }
+            None if status.is_success() => self.record_success(&host),
+            None => {}
+        }
+    }
+
+    /// Snapshot of all upstreams, most recently failing first
+    pub fn snapshot(&self) -> Vec<UpstreamAuthStatus> {
+        let mut upstreams: Vec<UpstreamAuthStatus> = self.lock().values().cloned().collect();
+        upstreams.sort_by(|a, b| {
+            b.last_failure
+                .cmp(&a.last_failure)
+                .then_with(|| a.host.cmp(&b.host))
+        });
+        upstreams
+    }
+
+    fn record_failure(&self, host: &str, sample: AuthFailureSample) {
+        let mut upstreams = self.lock();
+        let entry = upstreams
+            .entry(host.to_string())
+            .or_insert_with(|| UpstreamAuthStatus {
+                host: host.to_string(),
+                ..Default::default()
+            });
+        entry.total_failures += 1;
+        entry.consecutive_failures += 1;
+        *entry
+            .failures_by_kind
+            .entry(sample.kind.as_str())
+            .or_default() += 1;
+        entry.last_failure = Some(sample.timestamp);
+        if entry.recent.len() >= MAX_SAMPLES {
+            entry.recent.pop_front();
+        }
+        entry.recent.push_back(sample);
+    }
+
+    fn record_success(&self, host: &str) {
+        // Only upstreams that have failed before are tracked
+        if let Some(entry) = self.lock().get_mut(host) {
+            entry.consecutive_failures = 0;
+            entry.last_success = Some(self.clock.now_secs());
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, UpstreamAuthStatus>> {
+        self.upstreams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Classify an upstream response as an authentication failure, returning the
+/// kind and the challenge's error description if any
+fn classify(
+    status: StatusCode,
+    challenge: Option<&str>,
+) -> Option<(AuthFailureKind, Option<String>)> {
+    match status {
+        StatusCode::UNAUTHORIZED => {
+            let challenge = challenge.and_then(auth::parse_www_authenticate);
+            let param = |name: &str| {
+                challenge
+                    .as_ref()
+                    .and_then(|c| c.param(name))
+                    .map(String::from)
+            };
+            let error = param("error");
+            let description = param("error_description");
+            let kind = match error.as_deref() {
+                Some("invalid_token") => AuthFailureKind::InvalidToken,
+                Some("insufficient_scope") => AuthFailureKind::InsufficientScope,
+                _ => AuthFailureKind::Unauthorized,
+            };
+            Some((kind, description.or(error)))
+        }
+        StatusCode::FORBIDDEN => Some((AuthFailureKind::Forbidden, None)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn sample(kind: AuthFailureKind) -> AuthFailureSample {
+        AuthFailureSample {
+            timestamp: 1_700_000_000,
+            kind,
+            status: 401,
+            method: "GET".to_string(),
+            path: "/v2/owner/repo/manifests/latest".to_string(),
+            detail: None,
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            classify(
+                StatusCode::UNAUTHORIZED,
+                Some(
+                    r#"Bearer realm="https://ghcr.io/token",error="invalid_token",error_description="token expired""#
+                )
+            ),
+            Some((
+                AuthFailureKind::InvalidToken,
+                Some("token expired".to_string())
+            ))
+        );
+        assert_eq!(
+            classify(
+                StatusCode::UNAUTHORIZED,
+                Some(r#"Bearer realm="https://auth.docker.io/token",error="insufficient_scope""#)
+            ),
+            Some((
+                AuthFailureKind::InsufficientScope,
+                Some("insufficient_scope".to_string())
+            ))
+        );
+        assert_eq!(
+            classify(
+                StatusCode::UNAUTHORIZED,
+                Some(r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io""#)
+            ),
+            Some((AuthFailureKind::Unauthorized, None))
+        );
+        assert_eq!(
+            classify(StatusCode::FORBIDDEN, None),
+            Some((AuthFailureKind::Forbidden, None))
+        );
+        assert_eq!(classify(StatusCode::OK, None), None);
+        assert_eq!(classify(StatusCode::NOT_FOUND, None), None);
+    }
+
+    #[test]
+    fn test_record_failures_and_success() {
+        let clock = ManualClock::new(1_700_000_000);
+        let monitor = AuthMonitor::new(clock.clone());
+        monitor.record_failure("ghcr.io", sample(AuthFailureKind::InvalidToken));
+        monitor.record_failure("ghcr.io", sample(AuthFailureKind::InvalidToken));
+        monitor.record_failure("ghcr.io", sample(AuthFailureKind::Forbidden));
+        // upstreams without failures are not tracked
+        monitor.record_success("quay.io");
+
+        let snapshot = monitor.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].total_failures, 3);
+        assert_eq!(snapshot[0].consecutive_failures, 3);
+        assert_eq!(snapshot[0].failures_by_kind.get("invalid_token"), Some(&2));
+        assert_eq!(snapshot[0].failures_by_kind.get("forbidden"), Some(&1));
+
+        clock.advance(std::time::Duration::from_secs(30));
+        monitor.record_success("ghcr.io");
+        let snapshot = monitor.snapshot();
+        assert_eq!(snapshot[0].consecutive_failures, 0);
+        assert_eq!(snapshot[0].total_failures, 3);
+        assert_eq!(snapshot[0].last_success, Some(1_700_000_030));
+    }
+
+    #[test]
+    fn test_recent_samples_are_bounded() {
+        let monitor = AuthMonitor::new(ManualClock::new(1_700_000_000));
+        for _ in 0..(MAX_SAMPLES + 5) {
+            monitor.record_failure("ghcr.io", sample(AuthFailureKind::Unauthorized));
+        }
+        let snapshot = monitor.snapshot();
+        assert_eq!(snapshot[0].recent.len(), MAX_SAMPLES);
+        assert_eq!(snapshot[0].total_failures, (MAX_SAMPLES + 5) as u64);
+    }
+}
@@ -0,0 +1,100 @@
+/// Synthetic upstream registry for benchmarks
+///
+/// `docker-proxy --bench-server <addr>` serves blobs generated on the fly, so
+/// the streaming path can be measured without network or disk noise on the
+/// upstream side. The repository name encodes the blob size
+/// (`/v2/bench/<bytes>/blobs/<digest>`); the content is the byte pattern
+/// `i % 251`, which callers reproduce to compute the digest.
+use axum::{
+    Router,
+    body::Body,
+    extract::Path,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use bytes::Bytes;
+use futures_util::stream;
+
+use crate::router::{self, V2Endpoint};
+
+/// Chunk size of generated bodies; a multiple of 251 so every chunk carries
+/// the same bytes
+const CHUNK: usize = 251 * 256;
+
+/// Byte `i` of every synthetic blob
+pub fn pattern_byte(i: u64) -> u8 {
+    (i % 251) as u8
+}
+
+/// Run the synthetic registry until the process is stopped
+pub async fn run(addr: &str) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/v2/", get(|| async { StatusCode::OK }))
+        .route("/v2/{*rest}", get(serve_blob).head(serve_blob));
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("Benchmark registry listening on http://{}", addr);
+    axum::serve(listener, app).await
+}
+
+// 按仓库名中编码的大小生成 blob
+async fn serve_blob(Path(rest): Path<String>) -> Response {
+    let size = match router::parse_v2_path(&rest) {
+        V2Endpoint::Blob { name, .. } => name
+            .strip_prefix("bench/")
+            .and_then(|size| size.parse::<u64>().ok()),
+        _ => None,
+    };
+    let Some(size) = size else {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+    (
+        StatusCode::OK,
+        headers,
+        Body::from_stream(blob_stream(size)),
+    )
+        .into_response()
+}
+
+fn blob_stream(
+    size: u64,
+) -> impl futures_util::Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static {
+    let chunk = Bytes::from((0..CHUNK as u64).map(pattern_byte).collect::<Vec<u8>>());
+    stream::unfold(0u64, move |sent| {
+        let chunk = chunk.clone();
+        async move {
+            if sent >= size {
+                return None;
+            }
+            let len = (size - sent).min(CHUNK as u64) as usize;
+            Some((Ok(chunk.slice(..len)), sent + len as u64))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::TryStreamExt;
+
+    #[tokio::test]
+    async fn test_blob_stream() {
+        let size = CHUNK as u64 * 2 + 17;
+        let chunks: Vec<Bytes> = blob_stream(size).try_collect().await.unwrap();
+        let body: Vec<u8> = chunks.concat();
+        assert_eq!(body.len() as u64, size);
+        assert!(
+            body.iter()
+                .enumerate()
+                .all(|(i, b)| *b == pattern_byte(i as u64))
+        );
+    }
+}
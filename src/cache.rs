@@ -0,0 +1,1530 @@
+/// Content-addressed blob cache with a persistent metadata index
+///
+/// Layout under the cache directory:
+/// * `blobs/sha256/<hex>` - committed blob contents
+/// * `tmp/` - in-progress writes, cleared on startup
+/// * `index.json` - digests, sizes and access times used for LRU eviction,
+///   plus manifests pinned under a repository reference by imports and the
+///   last manifest fetched per tag, served stale during upstream outages
+///
+/// Readers hold a `BlobLease` while streaming a blob. Eviction skips leased
+/// blobs and explicit removals are deferred until the last lease is dropped.
+/// Retained blobs (pushed in local registry mode) are never evicted.
+///
+/// Only one fill per digest runs at a time; other requests for the blob can
+/// wait for it with `wait_for_fill` and then read the committed entry.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
+
+use crate::clock::{Clock, Random};
+use crate::config::CacheConfig;
+
+const INDEX_FILE: &str = "index.json";
+const INDEX_VERSION: u32 = 1;
+/// Repositories remembered per blob; the oldest is forgotten beyond this
+const MAX_ENTRY_REPOSITORIES: usize = 32;
+
+/// Metadata tracked for every cached blob
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub size: u64,
+    pub created_at: u64,
+    pub last_access: u64,
+    /// Exempt from eviction, only removed explicitly
+    #[serde(default)]
+    pub retained: bool,
+    /// Repositories, as clients name them, the blob was fetched or pushed
+    /// under; blobs are only known to belong to these
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub repositories: Vec<String>,
+}
+
+/// A manifest blob pinned under `name:tag` or `name@digest`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestRef {
+    pub digest: String,
+    pub media_type: String,
+}
+
+/// The manifest last fetched from upstream for a tag, kept as a blob so it
+/// can be served while the upstream is unavailable
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchedManifest {
+    pub digest: String,
+    pub media_type: String,
+    pub fetched_at: u64,
+}
+
+/// On-disk representation of the index file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheIndex {
+    version: u32,
+    entries: HashMap<String, CacheEntry>,
+    #[serde(default)]
+    manifests: HashMap<String, ManifestRef>,
+    #[serde(default)]
+    fetched: HashMap<String, FetchedManifest>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    manifests: HashMap<String, ManifestRef>,
+    fetched: HashMap<String, FetchedManifest>,
+    total_size: u64,
+    /// Active readers per digest
+    leases: HashMap<String, usize>,
+    /// Removed while leased, file deleted once the last lease is released
+    doomed: HashSet<String>,
+}
+
+impl CacheState {
+    fn insert(&mut self, digest: String, entry: CacheEntry) {
+        self.total_size += entry.size;
+        if let Some(old) = self.entries.insert(digest, entry) {
+            self.total_size -= old.size;
+        }
+    }
+
+    fn remove(&mut self, digest: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(digest)?;
+        self.total_size -= entry.size;
+        Some(entry)
+    }
+}
+
+/// A blob found in the cache
+#[derive(Debug, Clone)]
+pub struct CachedBlob {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+pub struct BlobCache {
+    root: PathBuf,
+    /// Maximum total size in bytes, 0 means unlimited
+    max_size: AtomicU64,
+    state: Mutex<CacheState>,
+    dirty: AtomicBool,
+    /// In-progress fills; the sender is dropped when the fill ends
+    fills: Mutex<HashMap<String, watch::Sender<()>>>,
+    coalesce_wait: Duration,
+    /// Set once another process has taken over the cache directory
+    detached: AtomicBool,
+    /// How stale a fetched manifest may be when served on upstream errors
+    stale_window: Option<Duration>,
+    stale_served: AtomicU64,
+    /// Read and write buffer size for streaming blobs, in bytes
+    stream_buffer: usize,
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn Random>,
+}
+
+impl BlobCache {
+    /// Open the cache directory, reconciling the persisted index with the
+    /// blobs actually present on disk. Access times come from `clock` and
+    /// temp file names from `random`.
+    pub fn open(
+        config: &CacheConfig,
+        clock: Arc<dyn Clock>,
+        random: Arc<dyn Random>,
+    ) -> io::Result<Self> {
+        let root = PathBuf::from(&config.dir);
+        fs::create_dir_all(root.join("blobs").join("sha256"))?;
+
+        // Leftovers from interrupted writes are never valid entries
+        let tmp_dir = root.join("tmp");
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+        fs::create_dir_all(&tmp_dir)?;
+
+        let persisted = load_index(&root.join(INDEX_FILE));
+        let (state, changed) = rebuild_state(&root, persisted, clock.now_secs())?;
+
+        let cache = Self {
+            root,
+            max_size: AtomicU64::new(config.max_size_mb * 1024 * 1024),
+            state: Mutex::new(state),
+            dirty: AtomicBool::new(changed),
+            fills: Mutex::new(HashMap::new()),
+            coalesce_wait: Duration::from_secs(config.coalesce_wait_secs),
+            detached: AtomicBool::new(false),
+            stale_window: config.stale_window(),
+            stale_served: AtomicU64::new(0),
+            stream_buffer: (config.stream_buffer_kb * 1024) as usize,
+            clock,
+            random,
+        };
+        cache.evict_to_fit();
+        if cache.dirty.load(Ordering::Relaxed) {
+            cache.persist()?;
+        }
+
+        let (entries, total_size) = cache.usage();
+        tracing::info!(
+            dir = %cache.root.display(),
+            entries = entries,
+            total_size = total_size,
+            "Blob cache ready"
+        );
+        Ok(cache)
+    }
+
+    /// Number of entries and total size in bytes
+    pub fn usage(&self) -> (usize, u64) {
+        let state = self.lock();
+        (state.entries.len(), state.total_size)
+    }
+
+    /// Total size of retained blobs in bytes
+    pub fn retained_size(&self) -> u64 {
+        self.lock()
+            .entries
+            .values()
+            .filter(|e| e.retained)
+            .map(|e| e.size)
+            .sum()
+    }
+
+    /// Maximum total size in bytes, 0 means unlimited
+    pub fn max_size(&self) -> u64 {
+        self.max_size.load(Ordering::Relaxed)
+    }
+
+    /// Change the maximum total size, evicting blobs down to a lower one
+    pub fn set_max_size(&self, max_size: u64) {
+        self.max_size.store(max_size, Ordering::Relaxed);
+        self.evict_to_fit();
+    }
+
+    /// All entries, most recently used first
+    pub fn entries(&self) -> Vec<(String, CacheEntry)> {
+        let mut entries: Vec<_> = self
+            .lock()
+            .entries
+            .iter()
+            .map(|(d, e)| (d.clone(), e.clone()))
+            .collect();
+        entries.sort_by(|a, b| b.1.last_access.cmp(&a.1.last_access).then(a.0.cmp(&b.0)));
+        entries
+    }
+
+    /// Pinned manifests keyed by `name:tag` or `name@digest`, sorted by key
+    pub fn pinned_manifests(&self) -> Vec<(String, ManifestRef)> {
+        let mut manifests: Vec<_> = self
+            .lock()
+            .manifests
+            .iter()
+            .map(|(k, m)| (k.clone(), m.clone()))
+            .collect();
+        manifests.sort_by(|a, b| a.0.cmp(&b.0));
+        manifests
+    }
+
+    /// Look up a blob by digest, updating its access time
+    pub fn lookup(&self, digest: &str) -> Option<CachedBlob> {
+        let path = self.blob_path(digest)?;
+        let mut state = self.lock();
+        let entry = state.entries.get_mut(digest)?;
+        entry.last_access = self.clock.now_secs();
+        self.dirty.store(true, Ordering::Relaxed);
+        Some(CachedBlob {
+            path,
+            size: entry.size,
+        })
+    }
+
+    /// Look up a blob and lease it for reading; the file is not deleted
+    /// until the lease is dropped
+    pub fn acquire(self: &Arc<Self>, digest: &str) -> Option<BlobLease> {
+        let blob = self.lookup(digest)?;
+        let mut state = self.lock();
+        // the entry may have been evicted between lookup and lease
+        if !state.entries.contains_key(digest) {
+            return None;
+        }
+        *state.leases.entry(digest.to_string()).or_default() += 1;
+        Some(BlobLease {
+            cache: Arc::clone(self),
+            digest: digest.to_string(),
+            path: blob.path,
+            size: blob.size,
+        })
+    }
+
+    /// Remember that a cached blob belongs to `repository`
+    pub fn record_repository(&self, digest: &str, repository: &str) {
+        let mut state = self.lock();
+        let Some(entry) = state.entries.get_mut(digest) else {
+            return;
+        };
+        if entry.repositories.iter().any(|r| r == repository) {
+            return;
+        }
+        if entry.repositories.len() >= MAX_ENTRY_REPOSITORIES {
+            entry.repositories.remove(0);
+        }
+        entry.repositories.push(repository.to_string());
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Forget that a cached blob belongs to `repository`; true when it
+    /// belonged to no other repository
+    pub fn drop_repository(&self, digest: &str, repository: &str) -> bool {
+        let mut state = self.lock();
+        let Some(entry) = state.entries.get_mut(digest) else {
+            return true;
+        };
+        entry.repositories.retain(|r| r != repository);
+        self.dirty.store(true, Ordering::Relaxed);
+        entry.repositories.is_empty()
+    }
+
+    /// Whether a cached blob was fetched or pushed under `repository`
+    pub fn belongs_to(&self, digest: &str, repository: &str) -> bool {
+        self.lock()
+            .entries
+            .get(digest)
+            .is_some_and(|entry| entry.repositories.iter().any(|r| r == repository))
+    }
+
+    /// Drop an entry whose file disappeared or could not be read
+    pub fn forget(&self, digest: &str) {
+        if self.lock().remove(digest).is_some() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Pin a manifest that is stored as a blob under `name` and `reference`
+    pub fn pin_manifest(&self, name: &str, reference: &str, manifest: ManifestRef) {
+        self.lock()
+            .manifests
+            .insert(manifest_key(name, reference), manifest);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Look up a pinned manifest, returning its reference and blob
+    pub fn lookup_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Option<(ManifestRef, CachedBlob)> {
+        let manifest = self
+            .lock()
+            .manifests
+            .get(&manifest_key(name, reference))
+            .cloned()?;
+        let blob = self.lookup(&manifest.digest)?;
+        Some((manifest, blob))
+    }
+
+    /// Remember the manifest just fetched for a tag, storing its body as a
+    /// blob; a no-op unless stale serving is enabled
+    pub fn record_fetched_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        body: &mut dyn Read,
+    ) -> io::Result<()> {
+        if self.stale_window.is_none() {
+            return Ok(());
+        }
+        let (digest, _) = self.store_blob(None, body, false)?;
+        self.lock().fetched.insert(
+            manifest_key(name, reference),
+            FetchedManifest {
+                digest,
+                media_type: media_type.to_string(),
+                fetched_at: self.clock.now_secs(),
+            },
+        );
+        self.dirty.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Buffer size for streaming blobs to clients, in bytes
+    pub fn stream_buffer_size(&self) -> usize {
+        self.stream_buffer
+    }
+
+    /// Whether fetched manifests are kept for `serve_stale_on_error`
+    pub fn keeps_stale_manifests(&self) -> bool {
+        self.stale_window.is_some()
+    }
+
+    /// The last fetched manifest of a tag, if it is within the stale window;
+    /// each hit is counted as a stale response served
+    pub fn stale_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Option<(FetchedManifest, CachedBlob)> {
+        let window = self.stale_window?;
+        let manifest = self
+            .lock()
+            .fetched
+            .get(&manifest_key(name, reference))
+            .cloned()?;
+        if self.clock.now_secs().saturating_sub(manifest.fetched_at) > window.as_secs() {
+            return None;
+        }
+        let blob = self.lookup(&manifest.digest)?;
+        self.stale_served.fetch_add(1, Ordering::Relaxed);
+        Some((manifest, blob))
+    }
+
+    /// Number of stale manifests served since startup
+    pub fn stale_served(&self) -> u64 {
+        self.stale_served.load(Ordering::Relaxed)
+    }
+
+    /// Remove pins for `name:reference`. A digest reference removes every pin
+    /// of `name` pointing at that manifest.
+    pub fn unpin_manifest(&self, name: &str, reference: &str) {
+        let mut state = self.lock();
+        let before = state.manifests.len();
+        if reference.contains(':') {
+            let prefix = format!("{}:", name);
+            let digest_key = manifest_key(name, reference);
+            state.manifests.retain(|key, m| {
+                !(key == &digest_key || (key.starts_with(&prefix) && m.digest == reference))
+            });
+        } else {
+            state.manifests.remove(&manifest_key(name, reference));
+        }
+        let fetched = state.fetched.len();
+        state.fetched.remove(&manifest_key(name, reference));
+        if state.manifests.len() != before || state.fetched.len() != fetched {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Delete a blob from disk and the index
+    pub fn remove(&self, digest: &str) {
+        let mut state = self.lock();
+        if state.remove(digest).is_none() {
+            return;
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+        if state.leases.contains_key(digest) {
+            state.doomed.insert(digest.to_string());
+            return;
+        }
+        drop(state);
+        self.delete_file(digest);
+    }
+
+    fn release(&self, digest: &str) {
+        let mut state = self.lock();
+        let Some(count) = state.leases.get_mut(digest) else {
+            return;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        state.leases.remove(digest);
+        let doomed = state.doomed.remove(digest);
+        let max_size = self.max_size();
+        let over_limit = max_size > 0 && state.total_size > max_size;
+        drop(state);
+
+        if doomed {
+            self.delete_file(digest);
+        }
+        // eviction may have been held back by this lease
+        if over_limit {
+            self.evict_to_fit();
+        }
+    }
+
+    fn delete_file(&self, digest: &str) {
+        if let Some(path) = self.blob_path(digest)
+            && let Err(e) = fs::remove_file(&path)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to remove blob {}: {}", digest, e);
+        }
+    }
+
+    /// Move a fully written and verified temp file into the cache as a
+    /// retained blob
+    pub fn adopt(&self, digest: &str, tmp_path: &Path, size: u64) -> io::Result<()> {
+        self.commit(digest, tmp_path, size, true)
+    }
+
+    /// Path for a new temp file inside the cache directory
+    pub fn temp_path(&self) -> PathBuf {
+        self.root.join("tmp").join(self.random.uuid().to_string())
+    }
+
+    /// Store a blob from a blocking reader. The content is hashed while it is
+    /// written; with `expected` set a mismatching digest is rejected, otherwise
+    /// the computed digest is used. A `retained` blob is exempt from eviction.
+    /// Returns the digest and size.
+    pub fn store_blob(
+        &self,
+        expected: Option<&str>,
+        reader: &mut dyn Read,
+        retained: bool,
+    ) -> io::Result<(String, u64)> {
+        if let Some(digest) = expected {
+            if self.blob_path(digest).is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid digest '{}'", digest),
+                ));
+            }
+            let mut state = self.lock();
+            if let Some(entry) = state.entries.get_mut(digest) {
+                if retained && !entry.retained {
+                    entry.retained = true;
+                    self.dirty.store(true, Ordering::Relaxed);
+                }
+                return Ok((digest.to_string(), entry.size));
+            }
+        }
+
+        let tmp_path = self.temp_path();
+        let result = (|| {
+            let mut file = fs::File::create(&tmp_path)?;
+            let mut hasher = Sha256::new();
+            let mut buf = vec![0u8; self.stream_buffer];
+            let mut size = 0u64;
+            loop {
+                let n = reader.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                file.write_all(&buf[..n])?;
+                size += n as u64;
+            }
+            file.sync_all()?;
+
+            let digest = format!("sha256:{}", hex::encode(hasher.finalize()));
+            if let Some(expected) = expected
+                && expected != digest
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("digest mismatch: expected {}, got {}", expected, digest),
+                ));
+            }
+            self.commit(&digest, &tmp_path, size, retained)?;
+            Ok((digest, size))
+        })();
+
+        if tmp_path.exists() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    /// Start writing a blob into the cache. Returns `None` when the digest is
+    /// not cacheable, already present or being filled, or the temp file
+    /// cannot be created.
+    pub async fn writer(
+        self: &Arc<Self>,
+        digest: &str,
+        expected_size: Option<u64>,
+    ) -> Option<CacheWriter> {
+        self.blob_path(digest)?;
+        if self.lock().entries.contains_key(digest) {
+            return None;
+        }
+        {
+            let mut fills = self.fills.lock().unwrap_or_else(|e| e.into_inner());
+            if fills.contains_key(digest) {
+                return None;
+            }
+            fills.insert(digest.to_string(), watch::channel(()).0);
+        }
+
+        let tmp_path = self.temp_path();
+        match tokio::fs::File::create(&tmp_path).await {
+            Ok(file) => Some(CacheWriter {
+                cache: Arc::clone(self),
+                digest: digest.to_string(),
+                tmp_path: Some(tmp_path),
+                file: Some(tokio::io::BufWriter::with_capacity(
+                    self.stream_buffer,
+                    file,
+                )),
+                written: 0,
+                expected_size,
+                repository: None,
+            }),
+            Err(e) => {
+                tracing::warn!("Failed to create cache temp file: {}", e);
+                self.end_fill(digest);
+                None
+            }
+        }
+    }
+
+    /// Wait, up to the configured coalescing time, for an in-progress fill
+    /// of `digest`. Returns true if there was one and it ended, after which
+    /// the blob is cached unless the fill failed.
+    pub async fn wait_for_fill(&self, digest: &str) -> bool {
+        if self.coalesce_wait.is_zero() {
+            return false;
+        }
+        let mut receiver = match self
+            .fills
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(digest)
+        {
+            Some(sender) => sender.subscribe(),
+            None => return false,
+        };
+        tracing::debug!(digest = %digest, "Waiting for in-progress cache fill");
+        tokio::time::timeout(self.coalesce_wait, receiver.changed())
+            .await
+            .is_ok()
+    }
+
+    // Dropping the sender wakes every waiter
+    fn end_fill(&self, digest: &str) {
+        self.fills
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(digest);
+    }
+
+    /// Write the index to disk atomically (temp file + rename)
+    pub fn persist(&self) -> io::Result<()> {
+        if self.detached.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.dirty.store(false, Ordering::Relaxed);
+        let index = {
+            let state = self.lock();
+            CacheIndex {
+                version: INDEX_VERSION,
+                entries: state.entries.clone(),
+                manifests: state.manifests.clone(),
+                fetched: state.fetched.clone(),
+            }
+        };
+
+        let result = serde_json::to_vec(&index)
+            .map_err(io::Error::other)
+            .and_then(|data| {
+                let tmp = self.root.join(format!("{}.tmp", INDEX_FILE));
+                fs::write(&tmp, data)?;
+                fs::rename(&tmp, self.root.join(INDEX_FILE))
+            });
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Leave the index and eviction to a process that took over the cache
+    /// directory (warm restart); this one keeps serving what it has
+    pub fn detach(&self) {
+        self.detached.store(true, Ordering::Relaxed);
+    }
+
+    /// Periodically persist the index while it has unsaved changes
+    pub fn spawn_flush_task(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if !self.dirty.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let cache = Arc::clone(&self);
+                match tokio::task::spawn_blocking(move || cache.persist()).await {
+                    Ok(Ok(())) => tracing::debug!("Blob cache index persisted"),
+                    Ok(Err(e)) => tracing::warn!("Failed to persist blob cache index: {}", e),
+                    Err(e) => tracing::warn!("Blob cache index flush task failed: {}", e),
+                }
+            }
+        });
+    }
+
+    // Move a temp file into the cache, or keep the copy already stored. The
+    // entry is inserted with its `retained` flag under the same lock, so the
+    // eviction below or a concurrent one cannot remove a blob about to be
+    // retained.
+    fn commit(&self, digest: &str, tmp_path: &Path, size: u64, retained: bool) -> io::Result<()> {
+        let final_path = self
+            .blob_path(digest)
+            .ok_or_else(|| io::Error::other("invalid digest"))?;
+
+        let now = self.clock.now_secs();
+        {
+            let mut state = self.lock();
+            if let Some(entry) = state.entries.get_mut(digest) {
+                entry.retained |= retained;
+                self.dirty.store(true, Ordering::Relaxed);
+                drop(state);
+                let _ = fs::remove_file(tmp_path);
+                return Ok(());
+            }
+            // rename under the lock so a pending deletion of an older copy
+            // cannot remove the new file
+            fs::rename(tmp_path, &final_path)?;
+            state.doomed.remove(digest);
+            state.insert(
+                digest.to_string(),
+                CacheEntry {
+                    size,
+                    created_at: now,
+                    last_access: now,
+                    retained,
+                    repositories: Vec::new(),
+                },
+            );
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+        self.evict_to_fit();
+        Ok(())
+    }
+
+    // Remove least recently used blobs until the cache fits into max_size.
+    // Retained and leased blobs are skipped; the cache may stay over the limit until
+    // their readers finish.
+    fn evict_to_fit(&self) {
+        let max_size = self.max_size();
+        if max_size == 0 || self.detached.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut state = self.lock();
+        while state.total_size > max_size {
+            let Some(digest) = state
+                .entries
+                .iter()
+                .filter(|(d, e)| !e.retained && !state.leases.contains_key(*d))
+                .min_by_key(|(_, e)| e.last_access)
+                .map(|(d, _)| d.clone())
+            else {
+                break;
+            };
+            state.remove(&digest);
+            self.dirty.store(true, Ordering::Relaxed);
+            if let Some(path) = self.blob_path(&digest)
+                && let Err(e) = fs::remove_file(&path)
+                && e.kind() != io::ErrorKind::NotFound
+            {
+                tracing::warn!("Failed to remove evicted blob {}: {}", digest, e);
+            }
+            tracing::debug!(digest = %digest, "Evicted blob from cache");
+        }
+    }
+
+    // Only well-formed sha256 digests map to a file, which also keeps
+    // client-supplied digests from escaping the cache directory
+    fn blob_path(&self, digest: &str) -> Option<PathBuf> {
+        let hex = parse_sha256_digest(digest)?;
+        Some(self.root.join("blobs").join("sha256").join(hex))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A leased cached blob, see `BlobCache::acquire`
+pub struct BlobLease {
+    cache: Arc<BlobCache>,
+    digest: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl Drop for BlobLease {
+    fn drop(&mut self) {
+        self.cache.release(&self.digest);
+    }
+}
+
+/// Streams a blob into the cache's temp directory, in writes of the cache's
+/// stream buffer size; the temp file is removed unless `commit` succeeds
+pub struct CacheWriter {
+    cache: Arc<BlobCache>,
+    digest: String,
+    tmp_path: Option<PathBuf>,
+    file: Option<tokio::io::BufWriter<tokio::fs::File>>,
+    written: u64,
+    expected_size: Option<u64>,
+    repository: Option<String>,
+}
+
+impl CacheWriter {
+    /// Record the committed blob as belonging to `repository`
+    pub fn for_repository(mut self, repository: &str) -> Self {
+        self.repository = Some(repository.to_string());
+        self
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| io::Error::other("cache writer already closed"))?;
+        file.write_all(chunk).await?;
+        self.written += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Whether the announced size has been written; the HTTP server stops
+    /// polling a body once its Content-Length is reached
+    fn is_complete(&self) -> bool {
+        self.expected_size == Some(self.written)
+    }
+
+    pub async fn commit(mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+            file.get_ref().sync_all().await?;
+        }
+        if let Some(expected) = self.expected_size
+            && expected != self.written
+        {
+            return Err(io::Error::other(format!(
+                "size mismatch: expected {} bytes, got {}",
+                expected, self.written
+            )));
+        }
+
+        let tmp_path = self
+            .tmp_path
+            .take()
+            .ok_or_else(|| io::Error::other("cache writer already committed"))?;
+        let cache = Arc::clone(&self.cache);
+        let digest = self.digest.clone();
+        let size = self.written;
+        let result = tokio::task::spawn_blocking(move || {
+            let result = cache.commit(&digest, &tmp_path, size, false);
+            if result.is_err() {
+                let _ = fs::remove_file(&tmp_path);
+            }
+            result
+        })
+        .await
+        .map_err(io::Error::other)?;
+
+        if result.is_ok() {
+            tracing::debug!(digest = %self.digest, size = size, "Blob stored in cache");
+            if let Some(repository) = &self.repository {
+                self.cache.record_repository(&self.digest, repository);
+            }
+        }
+        result
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        if let Some(path) = self.tmp_path.take() {
+            let _ = fs::remove_file(path);
+        }
+        self.cache.end_fill(&self.digest);
+    }
+}
+
+/// Pass a byte stream through unchanged while copying it into the cache.
+/// The entry is only committed once the stream ends without error, or as
+/// soon as the expected size has been written.
+pub fn tee<S, E>(
+    stream: S,
+    writer: CacheWriter,
+) -> impl Stream<Item = Result<Bytes, E>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Send + 'static,
+{
+    stream::unfold(
+        (Box::pin(stream), Some(writer)),
+        |(mut inner, mut writer)| async move {
+            match inner.next().await {
+                Some(Ok(chunk)) => {
+                    if let Some(w) = writer.as_mut()
+                        && let Err(e) = w.write(&chunk).await
+                    {
+                        tracing::warn!("Cache write failed, skipping cache fill: {}", e);
+                        writer = None;
+                    }
+                    if let Some(w) = writer.take_if(|w| w.is_complete()) {
+                        let digest = w.digest.clone();
+                        if let Err(e) = w.commit().await {
+                            tracing::warn!(digest = %digest, "Failed to commit cached blob: {}", e);
+                        }
+                    }
+                    Some((Ok(chunk), (inner, writer)))
+                }
+                Some(Err(e)) => Some((Err(e), (inner, None))),
+                None => {
+                    if let Some(w) = writer.take() {
+                        let digest = w.digest.clone();
+                        if let Err(e) = w.commit().await {
+                            tracing::warn!(digest = %digest, "Failed to commit cached blob: {}", e);
+                        }
+                    }
+                    None
+                }
+            }
+        },
+    )
+}
+
+// Index key of a pinned manifest: `name:tag` or `name@sha256:...`
+fn manifest_key(name: &str, reference: &str) -> String {
+    if reference.contains(':') {
+        format!("{}@{}", name, reference)
+    } else {
+        format!("{}:{}", name, reference)
+    }
+}
+
+/// Return the hex part of a `sha256:<64 hex>` digest
+pub fn parse_sha256_digest(digest: &str) -> Option<&str> {
+    let hex = digest.strip_prefix("sha256:")?;
+    if hex.len() == 64
+        && hex
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        Some(hex)
+    } else {
+        None
+    }
+}
+
+fn load_index(path: &Path) -> CacheIndex {
+    match fs::read(path) {
+        Ok(data) => match serde_json::from_slice::<CacheIndex>(&data) {
+            Ok(index) if index.version == INDEX_VERSION => index,
+            Ok(index) => {
+                tracing::warn!(
+                    "Ignoring cache index with unsupported version {}",
+                    index.version
+                );
+                CacheIndex::default()
+            }
+            Err(e) => {
+                tracing::warn!("Cache index is corrupt, rebuilding from disk: {}", e);
+                CacheIndex::default()
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => CacheIndex::default(),
+        Err(e) => {
+            tracing::warn!("Failed to read cache index, rebuilding from disk: {}", e);
+            CacheIndex::default()
+        }
+    }
+}
+
+// Scan the blob directory and merge it with the persisted index. Blobs
+// missing from the index are adopted using their mtime (or `now`), index entries
+// without a file are dropped. Returns the state and whether it differs
+// from the persisted index.
+fn rebuild_state(
+    root: &Path,
+    mut persisted: CacheIndex,
+    now: u64,
+) -> io::Result<(CacheState, bool)> {
+    let mut state = CacheState::default();
+    let mut changed = false;
+
+    for dir_entry in fs::read_dir(root.join("blobs").join("sha256"))? {
+        let dir_entry = dir_entry?;
+        let file_name = dir_entry.file_name();
+        let Some(hex) = file_name.to_str() else {
+            continue;
+        };
+        let digest = format!("sha256:{}", hex);
+        let metadata = dir_entry.metadata()?;
+        if !metadata.is_file() || parse_sha256_digest(&digest).is_none() {
+            tracing::warn!("Ignoring unexpected file in cache: {:?}", dir_entry.path());
+            continue;
+        }
+
+        match persisted.entries.remove(&digest) {
+            Some(entry) if entry.size == metadata.len() => state.insert(digest, entry),
+            _ => {
+                let mtime = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(now);
+                state.insert(
+                    digest,
+                    CacheEntry {
+                        size: metadata.len(),
+                        created_at: mtime,
+                        last_access: mtime,
+                        retained: false,
+                        repositories: Vec::new(),
+                    },
+                );
+                changed = true;
+            }
+        }
+    }
+
+    if !persisted.entries.is_empty() {
+        tracing::info!(
+            missing = persisted.entries.len(),
+            "Dropped cache index entries without blob files"
+        );
+        changed = true;
+    }
+
+    // Pins whose manifest blob is gone can never be served
+    let pinned = persisted.manifests.len();
+    state.manifests = persisted
+        .manifests
+        .into_iter()
+        .filter(|(_, m)| state.entries.contains_key(&m.digest))
+        .collect();
+    if state.manifests.len() != pinned {
+        changed = true;
+    }
+    let fetched = persisted.fetched.len();
+    state.fetched = persisted
+        .fetched
+        .into_iter()
+        .filter(|(_, m)| state.entries.contains_key(&m.digest))
+        .collect();
+    if state.fetched.len() != fetched {
+        changed = true;
+    }
+
+    Ok((state, changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, ManualClock};
+
+    fn test_config(max_size_mb: u64) -> CacheConfig {
+        let dir = std::env::temp_dir().join(format!("docker-proxy-cache-{}", uuid::Uuid::new_v4()));
+        CacheConfig {
+            enabled: true,
+            dir: dir.to_string_lossy().to_string(),
+            max_size_mb,
+            ..CacheConfig::default()
+        }
+    }
+
+    fn digest(n: u8) -> String {
+        format!("sha256:{}", format!("{:02x}", n).repeat(32))
+    }
+
+    async fn store(cache: &Arc<BlobCache>, digest: &str, data: &[u8]) {
+        let mut writer = cache
+            .writer(digest, Some(data.len() as u64))
+            .await
+            .expect("writer");
+        writer.write(data).await.unwrap();
+        writer.commit().await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_sha256_digest() {
+        assert!(parse_sha256_digest(&digest(1)).is_some());
+        assert!(parse_sha256_digest("sha256:abc").is_none());
+        assert!(parse_sha256_digest("sha512:00").is_none());
+        assert!(parse_sha256_digest(&format!("sha256:../{}", "a".repeat(61))).is_none());
+        assert!(parse_sha256_digest(&format!("sha256:{}", "A".repeat(64))).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tee_commits_at_expected_size() {
+        let cache = Arc::new(
+            BlobCache::open(&test_config(0), clock::system(), clock::os_random()).unwrap(),
+        );
+        let chunks: Vec<Result<Bytes, io::Error>> = vec![
+            Ok(Bytes::from_static(b"hel")),
+            Ok(Bytes::from_static(b"lo")),
+        ];
+        let writer = cache.writer(&digest(1), Some(5)).await.unwrap();
+        let mut body = Box::pin(tee(stream::iter(chunks), writer));
+
+        // the server stops polling once Content-Length bytes were sent
+        body.next().await.unwrap().unwrap();
+        assert!(cache.lookup(&digest(1)).is_none());
+        body.next().await.unwrap().unwrap();
+        assert_eq!(cache.lookup(&digest(1)).unwrap().size, 5);
+    }
+
+    #[tokio::test]
+    async fn test_index_persists_across_restarts() {
+        let config = test_config(0);
+        let cache =
+            Arc::new(BlobCache::open(&config, clock::system(), clock::os_random()).unwrap());
+        store(&cache, &digest(1), b"hello").await;
+        cache.record_repository(&digest(1), "library/nginx");
+        let before = cache.lock().entries.get(&digest(1)).cloned().unwrap();
+        cache.persist().unwrap();
+        drop(cache);
+
+        let reopened = BlobCache::open(&config, clock::system(), clock::os_random()).unwrap();
+        assert_eq!(reopened.usage(), (1, 5));
+        assert_eq!(reopened.lock().entries.get(&digest(1)), Some(&before));
+        assert!(reopened.lookup(&digest(1)).is_some());
+        assert!(reopened.belongs_to(&digest(1), "library/nginx"));
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[tokio::test]
+    async fn test_blob_repositories() {
+        let cache = Arc::new(
+            BlobCache::open(&test_config(0), clock::system(), clock::os_random()).unwrap(),
+        );
+        let writer = cache
+            .writer(&digest(1), Some(5))
+            .await
+            .unwrap()
+            .for_repository("team/app");
+        let chunks: Vec<Result<Bytes, io::Error>> = vec![Ok(Bytes::from_static(b"hello"))];
+        let mut body = Box::pin(tee(stream::iter(chunks), writer));
+        body.next().await.unwrap().unwrap();
+        assert!(cache.belongs_to(&digest(1), "team/app"));
+        assert!(!cache.belongs_to(&digest(1), "secret/app"));
+
+        // unknown blobs belong nowhere; the oldest repository makes room
+        cache.record_repository(&digest(2), "team/app");
+        assert!(!cache.belongs_to(&digest(2), "team/app"));
+        for i in 0..MAX_ENTRY_REPOSITORIES {
+            cache.record_repository(&digest(1), &format!("team/app-{}", i));
+        }
+        assert!(!cache.belongs_to(&digest(1), "team/app"));
+        assert!(cache.belongs_to(&digest(1), "team/app-0"));
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_adopts_orphans_and_drops_missing() {
+        let config = test_config(0);
+        let cache =
+            Arc::new(BlobCache::open(&config, clock::system(), clock::os_random()).unwrap());
+        store(&cache, &digest(1), b"kept").await;
+        store(&cache, &digest(2), b"deleted").await;
+        cache.persist().unwrap();
+        drop(cache);
+
+        let blobs = Path::new(&config.dir).join("blobs").join("sha256");
+        // blob removed behind the cache's back
+        fs::remove_file(blobs.join(parse_sha256_digest(&digest(2)).unwrap())).unwrap();
+        // blob written without an index entry (e.g. crash before flush)
+        fs::write(
+            blobs.join(parse_sha256_digest(&digest(3)).unwrap()),
+            b"orphan",
+        )
+        .unwrap();
+        // unrelated junk is ignored
+        fs::write(blobs.join("not-a-digest"), b"junk").unwrap();
+        // interrupted write
+        fs::write(Path::new(&config.dir).join("tmp").join("partial"), b"x").unwrap();
+
+        let reopened = BlobCache::open(&config, clock::system(), clock::os_random()).unwrap();
+        assert!(reopened.lookup(&digest(1)).is_some());
+        assert!(reopened.lookup(&digest(2)).is_none());
+        assert_eq!(reopened.lookup(&digest(3)).map(|b| b.size), Some(6));
+        assert_eq!(reopened.usage(), (2, 10));
+        assert_eq!(
+            fs::read_dir(Path::new(&config.dir).join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+
+        // the rebuilt index was written back
+        let index = load_index(&Path::new(&config.dir).join(INDEX_FILE));
+        assert_eq!(index.entries.len(), 2);
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let config = test_config(1);
+        let clock = ManualClock::new(1_000_000);
+        let cache = Arc::new(BlobCache::open(&config, clock.clone(), clock::os_random()).unwrap());
+        let chunk = vec![0u8; 400 * 1024];
+        store(&cache, &digest(1), &chunk).await;
+        clock.advance(Duration::from_secs(10));
+        store(&cache, &digest(2), &chunk).await;
+        // reading the first blob makes the second the least recently used
+        clock.advance(Duration::from_secs(10));
+        assert!(cache.lookup(&digest(1)).is_some());
+        clock.advance(Duration::from_secs(10));
+        store(&cache, &digest(3), &chunk).await;
+
+        assert!(cache.lookup(&digest(1)).is_some());
+        assert!(cache.lookup(&digest(2)).is_none());
+        assert!(cache.lookup(&digest(3)).is_some());
+        assert!(
+            !Path::new(&config.dir)
+                .join("blobs/sha256")
+                .join(parse_sha256_digest(&digest(2)).unwrap())
+                .exists()
+        );
+
+        // A lower limit applies at once
+        clock.advance(Duration::from_secs(10));
+        assert!(cache.lookup(&digest(3)).is_some());
+        cache.set_max_size(400 * 1024);
+        assert!(cache.lookup(&digest(1)).is_none());
+        assert!(cache.lookup(&digest(3)).is_some());
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[tokio::test]
+    async fn test_aborted_write_leaves_no_entry() {
+        let config = test_config(0);
+        let cache =
+            Arc::new(BlobCache::open(&config, clock::system(), clock::os_random()).unwrap());
+
+        let mut writer = cache.writer(&digest(1), Some(10)).await.unwrap();
+        writer.write(b"short").await.unwrap();
+        assert!(writer.commit().await.is_err());
+
+        let mut writer = cache.writer(&digest(1), None).await.unwrap();
+        writer.write(b"dropped").await.unwrap();
+        drop(writer);
+
+        assert!(cache.lookup(&digest(1)).is_none());
+        assert_eq!(
+            fs::read_dir(Path::new(&config.dir).join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_fill_is_coalesced() {
+        let config = test_config(0);
+        let cache =
+            Arc::new(BlobCache::open(&config, clock::system(), clock::os_random()).unwrap());
+        assert!(!cache.wait_for_fill(&digest(1)).await);
+
+        let mut writer = cache.writer(&digest(1), Some(5)).await.unwrap();
+        assert!(cache.writer(&digest(1), Some(5)).await.is_none());
+
+        let waiter = {
+            let cache = Arc::clone(&cache);
+            tokio::spawn(async move { cache.wait_for_fill(&digest(1)).await })
+        };
+        tokio::task::yield_now().await;
+        writer.write(b"hello").await.unwrap();
+        writer.commit().await.unwrap();
+        assert!(waiter.await.unwrap());
+        assert!(cache.lookup(&digest(1)).is_some());
+
+        // An aborted fill releases the digest for the next writer
+        let writer = cache.writer(&digest(2), None).await.unwrap();
+        drop(writer);
+        assert!(!cache.wait_for_fill(&digest(2)).await);
+        assert!(cache.writer(&digest(2), None).await.is_some());
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[test]
+    fn test_store_blob_verifies_digest() {
+        let config = test_config(0);
+        let cache = BlobCache::open(&config, clock::system(), clock::os_random()).unwrap();
+        let hello = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        assert_eq!(
+            cache.store_blob(None, &mut &b"hello"[..], false).unwrap(),
+            (hello.to_string(), 5)
+        );
+        assert!(cache.lookup(hello).is_some());
+        // already present
+        assert!(cache.store_blob(Some(hello), &mut &b""[..], false).is_ok());
+
+        let err = cache
+            .store_blob(Some(&digest(1)), &mut &b"not it"[..], false)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(cache.lookup(&digest(1)).is_none());
+        assert_eq!(
+            fs::read_dir(Path::new(&config.dir).join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[test]
+    fn test_pinned_manifests_persist() {
+        let config = test_config(0);
+        let cache = BlobCache::open(&config, clock::system(), clock::os_random()).unwrap();
+        let (manifest_digest, _) = cache.store_blob(None, &mut &b"{}"[..], false).unwrap();
+        let pinned = ManifestRef {
+            digest: manifest_digest.clone(),
+            media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+        };
+        cache.pin_manifest("internal/app", "1.0", pinned.clone());
+        cache.pin_manifest("internal/app", &manifest_digest, pinned.clone());
+        // pin whose blob does not exist is dropped on reopen
+        cache.pin_manifest(
+            "internal/app",
+            "gone",
+            ManifestRef {
+                digest: digest(9),
+                media_type: pinned.media_type.clone(),
+            },
+        );
+        cache.persist().unwrap();
+        drop(cache);
+
+        let reopened = BlobCache::open(&config, clock::system(), clock::os_random()).unwrap();
+        assert_eq!(
+            reopened
+                .lookup_manifest("internal/app", "1.0")
+                .map(|(m, _)| m),
+            Some(pinned.clone())
+        );
+        assert!(
+            reopened
+                .lookup_manifest("internal/app", &manifest_digest)
+                .is_some()
+        );
+        assert!(reopened.lookup_manifest("internal/app", "gone").is_none());
+        assert_eq!(reopened.lock().manifests.len(), 2);
+        assert!(reopened.lookup_manifest("other/app", "1.0").is_none());
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[test]
+    fn test_stale_manifest_window() {
+        let config = CacheConfig {
+            serve_stale_on_error: "1h".to_string(),
+            ..test_config(0)
+        };
+        let clock = ManualClock::new(1_000_000);
+        let cache = BlobCache::open(&config, clock.clone(), clock::os_random()).unwrap();
+        let body = br#"{"schemaVersion":2}"#;
+        cache
+            .record_fetched_manifest(
+                "library/nginx",
+                "latest",
+                "application/json",
+                &mut &body[..],
+            )
+            .unwrap();
+
+        let (manifest, blob) = cache.stale_manifest("library/nginx", "latest").unwrap();
+        assert_eq!(fs::read(&blob.path).unwrap(), body);
+        assert_eq!(manifest.media_type, "application/json");
+        assert!(cache.stale_manifest("library/nginx", "1.27").is_none());
+        assert_eq!(cache.stale_served(), 1);
+
+        // Kept across restarts, but only while within the window
+        cache.persist().unwrap();
+        let reopened = BlobCache::open(&config, clock.clone(), clock::os_random()).unwrap();
+        clock.advance(Duration::from_secs(3600));
+        assert!(reopened.stale_manifest("library/nginx", "latest").is_some());
+        clock.advance(Duration::from_secs(1));
+        assert!(reopened.stale_manifest("library/nginx", "latest").is_none());
+
+        // Nothing is kept while stale serving is disabled
+        let disabled_config = test_config(0);
+        let disabled =
+            BlobCache::open(&disabled_config, clock::system(), clock::os_random()).unwrap();
+        disabled
+            .record_fetched_manifest(
+                "library/nginx",
+                "latest",
+                "application/json",
+                &mut &body[..],
+            )
+            .unwrap();
+        assert_eq!(disabled.usage().0, 0);
+
+        let _ = fs::remove_dir_all(&config.dir);
+        let _ = fs::remove_dir_all(&disabled_config.dir);
+    }
+
+    #[test]
+    fn test_remove_and_unpin() {
+        let config = test_config(0);
+        let cache = BlobCache::open(&config, clock::system(), clock::os_random()).unwrap();
+        let (manifest_digest, _) = cache.store_blob(None, &mut &b"{}"[..], false).unwrap();
+        let pinned = ManifestRef {
+            digest: manifest_digest.clone(),
+            media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+        };
+        for reference in ["1.0", "latest", manifest_digest.as_str()] {
+            cache.pin_manifest("internal/app", reference, pinned.clone());
+        }
+        cache.pin_manifest("internal/other", "1.0", pinned.clone());
+
+        cache.unpin_manifest("internal/app", "latest");
+        assert!(cache.lookup_manifest("internal/app", "latest").is_none());
+        assert!(cache.lookup_manifest("internal/app", "1.0").is_some());
+
+        // deleting by digest drops the tags pointing at it, other repositories keep theirs
+        cache.unpin_manifest("internal/app", &manifest_digest);
+        assert!(cache.lookup_manifest("internal/app", "1.0").is_none());
+        assert!(
+            cache
+                .lookup_manifest("internal/app", &manifest_digest)
+                .is_none()
+        );
+        assert!(cache.lookup_manifest("internal/other", "1.0").is_some());
+
+        let path = cache.lookup(&manifest_digest).unwrap().path;
+        cache.remove(&manifest_digest);
+        assert!(cache.lookup(&manifest_digest).is_none());
+        assert!(!path.exists());
+        assert_eq!(cache.usage(), (0, 0));
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[tokio::test]
+    async fn test_eviction_skips_leased_blobs() {
+        let config = test_config(1);
+        let cache =
+            Arc::new(BlobCache::open(&config, clock::system(), clock::os_random()).unwrap());
+        let chunk = vec![0u8; 400 * 1024];
+
+        store(&cache, &digest(1), &chunk).await;
+        store(&cache, &digest(2), &chunk).await;
+        let lease = cache.acquire(&digest(1)).unwrap();
+        {
+            // digest(1) is least recently used, but being streamed
+            let mut state = cache.lock();
+            state.entries.get_mut(&digest(1)).unwrap().last_access = 1;
+            state.entries.get_mut(&digest(2)).unwrap().last_access = 2;
+        }
+        store(&cache, &digest(3), &chunk).await;
+
+        assert!(lease.path.exists());
+        assert!(cache.lookup(&digest(1)).is_some());
+        assert!(cache.lookup(&digest(2)).is_none());
+        assert!(cache.lookup(&digest(3)).is_some());
+
+        drop(lease);
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[tokio::test]
+    async fn test_eviction_skips_retained_blobs() {
+        let config = test_config(1);
+        let cache =
+            Arc::new(BlobCache::open(&config, clock::system(), clock::os_random()).unwrap());
+        let chunk = vec![0u8; 400 * 1024];
+
+        store(&cache, &digest(1), &chunk).await;
+        cache.lock().entries.get_mut(&digest(1)).unwrap().retained = true;
+        store(&cache, &digest(2), &chunk).await;
+        {
+            let mut state = cache.lock();
+            state.entries.get_mut(&digest(1)).unwrap().last_access = 1;
+            state.entries.get_mut(&digest(2)).unwrap().last_access = 2;
+        }
+        store(&cache, &digest(3), &chunk).await;
+
+        assert!(cache.lookup(&digest(1)).is_some());
+        assert!(cache.lookup(&digest(2)).is_none());
+
+        // the flag survives a restart
+        cache.persist().unwrap();
+        drop(cache);
+        let cache = BlobCache::open(&config, clock::system(), clock::os_random()).unwrap();
+        assert!(cache.lock().entries[&digest(1)].retained);
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[test]
+    fn test_retained_blobs_survive_a_full_cache() {
+        let config = test_config(1);
+        let cache = BlobCache::open(&config, clock::system(), clock::os_random()).unwrap();
+
+        // Retained blobs alone exceed max_size; each new one is still kept
+        let mut stored = Vec::new();
+        for byte in 0..3u8 {
+            let chunk = vec![byte; 600 * 1024];
+            let (digest, _) = cache.store_blob(None, &mut chunk.as_slice(), true).unwrap();
+            stored.push(digest);
+        }
+        let tmp = cache.temp_path();
+        let chunk = vec![3u8; 600 * 1024];
+        fs::write(&tmp, &chunk).unwrap();
+        let pushed = format!("sha256:{}", hex::encode(Sha256::digest(&chunk)));
+        cache.adopt(&pushed, &tmp, chunk.len() as u64).unwrap();
+        stored.push(pushed);
+        for digest in &stored {
+            assert!(cache.lock().entries[digest].retained);
+        }
+
+        // A fill is evicted at once while the same content pushed is kept
+        let (fetched, _) = cache.store_blob(None, &mut &b"fetched"[..], false).unwrap();
+        assert!(cache.lookup(&fetched).is_none());
+        let (fetched, _) = cache
+            .store_blob(Some(&fetched), &mut &b"fetched"[..], true)
+            .unwrap();
+        assert!(cache.lock().entries[&fetched].retained);
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+
+    #[tokio::test]
+    async fn test_remove_waits_for_readers() {
+        let config = test_config(0);
+        let cache =
+            Arc::new(BlobCache::open(&config, clock::system(), clock::os_random()).unwrap());
+        store(&cache, &digest(1), b"streaming").await;
+
+        let first = cache.acquire(&digest(1)).unwrap();
+        let second = cache.acquire(&digest(1)).unwrap();
+        cache.remove(&digest(1));
+        // no new readers, but the file stays for the active ones
+        assert!(cache.acquire(&digest(1)).is_none());
+        assert!(first.path.exists());
+
+        let path = first.path.clone();
+        drop(first);
+        assert!(path.exists());
+        drop(second);
+        assert!(!path.exists());
+
+        // a re-cached copy is not deleted by a stale pending removal
+        store(&cache, &digest(1), b"streaming").await;
+        let lease = cache.acquire(&digest(1)).unwrap();
+        cache.remove(&digest(1));
+        store(&cache, &digest(1), b"streaming").await;
+        drop(lease);
+        assert!(cache.lookup(&digest(1)).unwrap().path.exists());
+
+        let _ = fs::remove_dir_all(&config.dir);
+    }
+}
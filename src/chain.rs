@@ -0,0 +1,141 @@
+/// Chaining to another docker-proxy instance
+///
+/// Every blob and manifest response says whether it came from this
+/// instance's cache (`X-Docker-Proxy-Cache: hit`, `miss` or `coalesced` when
+/// it waited for another request's fetch). When the default upstream is
+/// itself a docker-proxy, its hint is relayed as
+/// `X-Docker-Proxy-Upstream-Cache` and, with the upstream's signing key
+/// configured, its cache hits are only trusted when correctly signed.
+use axum::http::{HeaderMap, HeaderValue};
+
+use crate::config::ChainConfig;
+use crate::signing::{ResponseSigner, SIGNATURE_HEADER};
+
+/// Whether a response was served from this instance's cache
+pub const CACHE_STATUS_HEADER: &str = "X-Docker-Proxy-Cache";
+/// The cache status reported by an upstream docker-proxy
+pub const UPSTREAM_CACHE_STATUS_HEADER: &str = "X-Docker-Proxy-Upstream-Cache";
+
+/// Set the cache status hint on a response
+pub fn set_cache_status(headers: &mut HeaderMap, status: &'static str) {
+    headers.insert(CACHE_STATUS_HEADER, HeaderValue::from_static(status));
+}
+
+/// The upstream docker-proxy of a chained instance
+pub struct UpstreamProxy {
+    verifier: Option<ResponseSigner>,
+}
+
+impl UpstreamProxy {
+    /// `None` unless chaining is enabled
+    pub fn from_config(config: &ChainConfig) -> Option<Self> {
+        config.enabled.then(|| Self {
+            verifier: (!config.upstream_signing_key.is_empty())
+                .then(|| ResponseSigner::from_key(&config.upstream_signing_key)),
+        })
+    }
+
+    /// Check the headers of a blob response relayed from the upstream proxy
+    /// and rename its cache hint. Its signature is verified when a key is
+    /// configured and removed either way, as it is not this instance's.
+    pub fn check_blob(
+        &self,
+        digest: &str,
+        length: Option<u64>,
+        headers: &mut HeaderMap,
+    ) -> Result<(), String> {
+        let hint = headers.remove(CACHE_STATUS_HEADER);
+        let signature = headers.remove(SIGNATURE_HEADER);
+        if let Some(verifier) = &self.verifier {
+            let is_hit = hint.as_ref().is_some_and(|h| h.as_bytes() == b"hit");
+            match (&signature, length) {
+                (Some(signature), Some(length)) => {
+                    let valid = signature
+                        .to_str()
+                        .is_ok_and(|s| verifier.verify(digest, length, s));
+                    if !valid {
+                        return Err(format!("invalid upstream signature for {}", digest));
+                    }
+                }
+                _ if is_hit => {
+                    return Err(format!("unsigned upstream cache hit for {}", digest));
+                }
+                _ => {}
+            }
+        }
+        if let Some(hint) = hint {
+            headers.insert(UPSTREAM_CACHE_STATUS_HEADER, hint);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0123456789abcdef";
+
+    fn upstream(key: &str) -> UpstreamProxy {
+        UpstreamProxy::from_config(&ChainConfig {
+            enabled: true,
+            upstream_signing_key: key.to_string(),
+        })
+        .unwrap()
+    }
+
+    fn hit_headers(signature: Option<String>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        set_cache_status(&mut headers, "hit");
+        if let Some(signature) = signature {
+            headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_check_blob() {
+        let digest = "sha256:abc";
+        let signature = ResponseSigner::from_key(KEY).sign(digest, 5);
+
+        let mut headers = hit_headers(Some(signature.clone()));
+        assert!(
+            upstream(KEY)
+                .check_blob(digest, Some(5), &mut headers)
+                .is_ok()
+        );
+        assert_eq!(headers[UPSTREAM_CACHE_STATUS_HEADER], "hit");
+        assert!(headers.get(CACHE_STATUS_HEADER).is_none());
+        assert!(headers.get(SIGNATURE_HEADER).is_none());
+
+        let mut headers = hit_headers(Some(signature));
+        assert!(
+            upstream(KEY)
+                .check_blob(digest, Some(6), &mut headers)
+                .is_err()
+        );
+        let mut headers = hit_headers(None);
+        assert!(
+            upstream(KEY)
+                .check_blob(digest, Some(5), &mut headers)
+                .is_err()
+        );
+
+        // Misses need no signature, and without a key nothing is verified
+        let mut headers = HeaderMap::new();
+        set_cache_status(&mut headers, "miss");
+        assert!(
+            upstream(KEY)
+                .check_blob(digest, Some(5), &mut headers)
+                .is_ok()
+        );
+        let mut headers = hit_headers(None);
+        assert!(
+            upstream("")
+                .check_blob(digest, Some(5), &mut headers)
+                .is_ok()
+        );
+
+        assert!(UpstreamProxy::from_config(&ChainConfig::default()).is_none());
+    }
+}
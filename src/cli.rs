@@ -0,0 +1,309 @@
+/// Operator subcommands that talk to a running instance's admin API
+///
+/// Without a subcommand the binary starts the proxy as before. The instance
+/// is taken from `--url`, then `DOCKER_PROXY_URL`, then the listen address in
+/// the local config file. An admin API key is taken from `--api-key`, then
+/// `DOCKER_PROXY_API_KEY`.
+use clap::Subcommand;
+
+use crate::args::Args;
+use docker_proxy::client::{Client, ClientResult};
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Cache usage and upload counters
+    Stats,
+    /// Inspect or purge the cache
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Pull an image into the cache
+    Prefetch {
+        image: String,
+        /// Only this platform; may be repeated
+        #[arg(long = "platform", value_name = "OS/ARCH")]
+        platforms: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// Cached blobs and pinned manifests
+    List,
+    /// Remove an image from the cache
+    Purge { image: String },
+}
+
+/// Instance the subcommands talk to
+#[derive(Debug, clap::Args)]
+pub struct Connection {
+    /// Admin API base URL [default: the listen address in the config file]
+    #[arg(long, global = true, env = "DOCKER_PROXY_URL")]
+    pub url: Option<String>,
+    /// Admin API key
+    #[arg(
+        long,
+        global = true,
+        env = "DOCKER_PROXY_API_KEY",
+        hide_env_values = true
+    )]
+    pub api_key: Option<String>,
+}
+
+/// Run a subcommand, returning the process exit code
+pub async fn run(args: &Args, command: &Command) -> i32 {
+    let url = args
+        .connection
+        .url
+        .clone()
+        .unwrap_or_else(|| default_url(args));
+    let client = match Client::new(&url) {
+        Ok(client) => match &args.connection.api_key {
+            Some(key) => client.with_api_key(key.clone()),
+            None => client,
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 2;
+        }
+    };
+
+    match execute(&client, command).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+async fn execute(client: &Client, command: &Command) -> ClientResult<()> {
+    match command {
+        Command::Stats => {
+            let stats = client.stats().await?;
+            println!("version     {}", stats.version);
+            println!("registry    {}", stats.registry);
+            match stats.cache {
+                Some(cache) => {
+                    let limit = match cache.max_bytes {
+                        0 => "unlimited".to_string(),
+                        max => format_bytes(max),
+                    };
+                    println!(
+                        "cache       {} blobs, {} of {}",
+                        cache.entries,
+                        format_bytes(cache.bytes),
+                        limit
+                    );
+                    println!("pinned      {} manifests", cache.pinned_manifests);
+                }
+                None => println!("cache       disabled"),
+            }
+            println!("uploads     {} in progress", stats.uploads);
+        }
+        Command::Cache(CacheCommand::List) => {
+            let contents = client.cache_contents().await?;
+            for blob in &contents.blobs {
+                println!(
+                    "{}  {:>10}{}",
+                    blob.digest,
+                    format_bytes(blob.size),
+                    if blob.retained { "  retained" } else { "" }
+                );
+            }
+            for manifest in &contents.manifests {
+                println!("{}  -> {}", manifest.reference, manifest.digest);
+            }
+        }
+        Command::Cache(CacheCommand::Purge { image }) => {
+            let summary = client.purge(image).await?;
+            println!(
+                "Purged {}: {} manifests, {} blobs removed, {} freed, {} kept",
+                image,
+                summary.manifests,
+                summary.blobs_removed,
+                format_bytes(summary.bytes_freed),
+                summary.blobs_kept
+            );
+        }
+        Command::Prefetch { image, platforms } => {
+            let summary = client.prefetch(image, platforms).await?;
+            println!(
+                "Prefetched {}: {} manifests, {} blobs fetched ({}), {} already cached",
+                image,
+                summary.manifests,
+                summary.blobs_fetched,
+                format_bytes(summary.bytes_fetched),
+                summary.blobs_present
+            );
+        }
+    }
+    Ok(())
+}
+
+// The local instance from the config file, reachable via loopback when it
+// listens on all interfaces
+fn default_url(args: &Args) -> String {
+    match args.load_config() {
+        Ok(config) => {
+            let host = match config.server.host.as_str() {
+                "0.0.0.0" | "::" | "[::]" => "127.0.0.1",
+                host => host,
+            };
+            format!("http://{}:{}", host, config.server.port)
+        }
+        Err(_) => "http://127.0.0.1:8080".to_string(),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+    use docker_proxy::client::{
+        AuthStatus, DiagnosticReport, ImportSummary, PrefetchSummary, PurgeSummary,
+    };
+
+    use crate::args::Command::Admin;
+
+    fn parse(list: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("docker-proxy").chain(list.iter().copied()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(parse(&[]).unwrap().command.is_none());
+        let args = parse(&["--url", "http://proxy:8080", "stats"]).unwrap();
+        assert_eq!(args.connection.url.as_deref(), Some("http://proxy:8080"));
+        assert!(matches!(args.command, Some(Admin(Command::Stats))));
+        assert!(matches!(
+            parse(&["cache", "purge", "library/nginx:1.27"]).unwrap().command,
+            Some(Admin(Command::Cache(CacheCommand::Purge { image }))) if image == "library/nginx:1.27"
+        ));
+        assert!(matches!(
+            parse(&[
+                "prefetch",
+                "app:v1",
+                "--platform",
+                "linux/amd64",
+                "--platform",
+                "linux/arm64"
+            ])
+            .unwrap()
+            .command,
+            Some(Admin(Command::Prefetch { image, platforms }))
+                if image == "app:v1" && platforms == ["linux/amd64", "linux/arm64"]
+        ));
+
+        // Global options follow the subcommand too
+        assert_eq!(
+            parse(&["cache", "list", "--api-key", "s3cret"])
+                .unwrap()
+                .connection
+                .api_key
+                .as_deref(),
+            Some("s3cret")
+        );
+
+        assert!(parse(&["cache"]).is_err());
+        assert!(parse(&["stats", "--platform", "linux/amd64"]).is_err());
+        assert!(parse(&["--url"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
+
+    // what the handlers serialize must parse into the client types
+    #[test]
+    fn test_payloads_match_server_types() {
+        let status = crate::auth_monitor::UpstreamAuthStatus {
+            host: "ghcr.io".to_string(),
+            total_failures: 1,
+            consecutive_failures: 1,
+            failures_by_kind: [("invalid_token", 1)].into_iter().collect(),
+            last_failure: Some(1),
+            last_success: None,
+            recent: [crate::auth_monitor::AuthFailureSample {
+                timestamp: 1,
+                kind: crate::auth_monitor::AuthFailureKind::InvalidToken,
+                status: 401,
+                method: "GET".to_string(),
+                path: "/v2/owner/repo/manifests/latest".to_string(),
+                detail: None,
+            }]
+            .into_iter()
+            .collect(),
+        };
+        let body = serde_json::json!({ "upstreams": [status], "timestamp": 2 });
+        let parsed: AuthStatus = serde_json::from_value(body).unwrap();
+        assert_eq!(parsed.upstreams[0].recent[0].kind, "invalid_token");
+        assert_eq!(parsed.upstreams[0].failures_by_kind["invalid_token"], 1);
+
+        let summary = crate::import::ImportSummary {
+            name: "library/nginx".to_string(),
+            tags: vec!["1.27".to_string()],
+            ..Default::default()
+        };
+        let parsed: ImportSummary =
+            serde_json::from_value(serde_json::to_value(&summary).unwrap()).unwrap();
+        assert_eq!(parsed.tags, vec!["1.27"]);
+
+        let report = crate::diagnose::DiagnosticReport {
+            host: "ghcr.io".to_string(),
+            port: 443,
+            dns: crate::diagnose::DnsResult {
+                duration_ms: 1.0,
+                addresses: vec!["140.82.112.33".to_string()],
+                error: None,
+            },
+            tcp: vec![crate::diagnose::TcpResult {
+                address: "140.82.112.33:443".to_string(),
+                family: "ipv4",
+                duration_ms: 2.0,
+                connected: true,
+                error: None,
+            }],
+            tls: None,
+            registry: None,
+            total_ms: 3.0,
+        };
+        let parsed: DiagnosticReport =
+            serde_json::from_value(serde_json::to_value(&report).unwrap()).unwrap();
+        assert_eq!(parsed.tcp[0].family, "ipv4");
+
+        let summary = crate::prefetch::PrefetchSummary {
+            blobs_fetched: 2,
+            ..Default::default()
+        };
+        let parsed: PrefetchSummary =
+            serde_json::from_value(serde_json::to_value(&summary).unwrap()).unwrap();
+        assert_eq!(parsed.blobs_fetched, 2);
+
+        let summary = crate::prefetch::PurgeSummary {
+            bytes_freed: 42,
+            ..Default::default()
+        };
+        let parsed: PurgeSummary =
+            serde_json::from_value(serde_json::to_value(&summary).unwrap()).unwrap();
+        assert_eq!(parsed.bytes_freed, 42);
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
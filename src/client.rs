@@ -0,0 +1,401 @@
+/// Typed client for a running proxy's admin and status APIs
+///
+/// Response types mirror the JSON the handlers in `api` produce, so the CLI
+/// subcommands and automation tooling share one definition of every payload
+/// instead of picking fields out of `serde_json::Value`. Built with the
+/// `client` feature.
+use std::collections::BTreeMap;
+
+use reqwest::{Method, RequestBuilder, StatusCode, Url};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Invalid proxy URL: {0}")]
+    InvalidUrl(String),
+
+    #[error("Request failed: {0}")]
+    Network(#[from] reqwest::Error),
+
+    #[error("Proxy returned {status}: {message}")]
+    Status { status: StatusCode, message: String },
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+/// `GET /healthz`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Health {
+    /// "healthy" or "degraded"
+    pub status: String,
+    pub version: String,
+    pub registry: RegistryHealth,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegistryHealth {
+    pub url: String,
+    pub healthy: bool,
+}
+
+/// `GET /api/auth/status`
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthStatus {
+    pub upstreams: Vec<UpstreamAuthStatus>,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamAuthStatus {
+    pub host: String,
+    pub total_failures: u64,
+    pub consecutive_failures: u64,
+    pub failures_by_kind: BTreeMap<String, u64>,
+    pub last_failure: Option<u64>,
+    pub last_success: Option<u64>,
+    pub recent: Vec<AuthFailureSample>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthFailureSample {
+    pub timestamp: u64,
+    /// e.g. "unauthorized", "invalid_token"
+    pub kind: String,
+    pub status: u16,
+    pub method: String,
+    pub path: String,
+    pub detail: Option<String>,
+}
+
+/// `GET /api/uploads`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Uploads {
+    pub uploads: Vec<Upload>,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Upload {
+    /// First characters of the upload UUID
+    pub uuid: String,
+    pub name: String,
+    pub offset: u64,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+/// `GET /admin/diagnose`
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiagnosticReport {
+    pub host: String,
+    pub port: u16,
+    pub dns: DnsResult,
+    pub tcp: Vec<TcpResult>,
+    pub tls: Option<PhaseResult>,
+    pub registry: Option<RegistryProbe>,
+    pub total_ms: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DnsResult {
+    pub duration_ms: f64,
+    pub addresses: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TcpResult {
+    pub address: String,
+    /// "ipv4" or "ipv6"
+    pub family: String,
+    pub duration_ms: f64,
+    pub connected: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PhaseResult {
+    pub address: String,
+    pub duration_ms: f64,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegistryProbe {
+    pub url: String,
+    pub duration_ms: f64,
+    pub status: Option<u16>,
+    pub reachable: bool,
+    pub api_version: Option<String>,
+    pub auth_challenge: Option<String>,
+    pub error: Option<String>,
+}
+
+/// `POST /admin/import`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImportSummary {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub manifests: usize,
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+/// `GET /api/stats`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Stats {
+    pub version: String,
+    pub registry: String,
+    /// `None` when the blob cache is disabled
+    pub cache: Option<CacheStats>,
+    /// Number of in-progress uploads
+    pub uploads: usize,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+    /// 0 means unlimited
+    pub max_bytes: u64,
+    pub pinned_manifests: usize,
+}
+
+/// `GET /api/cache`
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheContents {
+    /// Most recently used first
+    pub blobs: Vec<CachedBlob>,
+    pub manifests: Vec<PinnedManifest>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CachedBlob {
+    pub digest: String,
+    pub size: u64,
+    pub created_at: u64,
+    pub last_access: u64,
+    /// Stored by a local push, exempt from eviction
+    pub retained: bool,
+    /// Repositories the blob was fetched or pushed under
+    #[serde(default)]
+    pub repositories: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PinnedManifest {
+    /// `name:tag` or `name@digest`
+    pub reference: String,
+    pub digest: String,
+    pub media_type: String,
+}
+
+/// `POST /admin/prefetch`
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrefetchSummary {
+    pub manifests: usize,
+    pub blobs_fetched: usize,
+    pub blobs_present: usize,
+    pub bytes_fetched: u64,
+}
+
+/// `POST /admin/cache/purge`
+#[derive(Debug, Clone, Deserialize)]
+pub struct PurgeSummary {
+    pub manifests: usize,
+    pub blobs_removed: usize,
+    /// Pushed locally or shared with other pinned images
+    #[serde(default)]
+    pub blobs_kept: usize,
+    pub bytes_freed: u64,
+}
+
+/// Client for one proxy instance, e.g. `http://127.0.0.1:8080`
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base: Url,
+    /// Sent as a Bearer token, for instances with `[admin] api_keys`
+    api_key: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> ClientResult<Self> {
+        Self::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// Use a preconfigured HTTP client (timeouts, TLS settings)
+    pub fn with_http_client(base_url: &str, http: reqwest::Client) -> ClientResult<Self> {
+        let mut base = Url::parse(base_url)
+            .map_err(|e| ClientError::InvalidUrl(format!("{}: {}", base_url, e)))?;
+        if !matches!(base.scheme(), "http" | "https") {
+            return Err(ClientError::InvalidUrl(base_url.to_string()));
+        }
+        // keep a path prefix (reverse proxy mounts) when joining endpoints
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(Self {
+            http,
+            base,
+            api_key: None,
+        })
+    }
+
+    /// Authenticate with an admin API key
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// Service health. A degraded instance answers 503 with the same body,
+    /// which is returned rather than treated as an error.
+    pub async fn health(&self) -> ClientResult<Health> {
+        let response = self.request(Method::GET, "healthz")?.send().await?;
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+            return Ok(response.json().await?);
+        }
+        parse(response).await
+    }
+
+    /// Upstream authentication failure statistics
+    pub async fn auth_status(&self) -> ClientResult<AuthStatus> {
+        self.get_json("api/auth/status", &[]).await
+    }
+
+    /// In-progress blob uploads
+    pub async fn uploads(&self) -> ClientResult<Uploads> {
+        self.get_json("api/uploads", &[]).await
+    }
+
+    /// Cache usage and upload counters
+    pub async fn stats(&self) -> ClientResult<Stats> {
+        self.get_json("api/stats", &[]).await
+    }
+
+    /// Cached blobs and pinned manifests
+    pub async fn cache_contents(&self) -> ClientResult<CacheContents> {
+        self.get_json("api/cache", &[]).await
+    }
+
+    /// Pull an image (`name[:tag]` or `name@digest`) into the cache.
+    /// Multi-arch images are narrowed down to `platforms` ("os/arch[/variant]"),
+    /// the proxy defaults to linux/amd64 when empty.
+    pub async fn prefetch(
+        &self,
+        image: &str,
+        platforms: &[String],
+    ) -> ClientResult<PrefetchSummary> {
+        let mut query = vec![("image", image.to_string())];
+        if !platforms.is_empty() {
+            query.push(("platform", platforms.join(",")));
+        }
+        self.post_json("admin/prefetch", &query).await
+    }
+
+    /// Remove an image's manifests, blobs and pins from the cache
+    pub async fn purge(&self, image: &str) -> ClientResult<PurgeSummary> {
+        self.post_json("admin/cache/purge", &[("image", image.to_string())])
+            .await
+    }
+
+    /// Connectivity diagnostics for `host`, or the default registry
+    pub async fn diagnose(&self, host: Option<&str>) -> ClientResult<DiagnosticReport> {
+        let query: Vec<(&str, &str)> = host.map(|h| ("host", h)).into_iter().collect();
+        self.get_json("admin/diagnose", &query).await
+    }
+
+    /// Import a `docker save` archive or OCI layout tarball into the cache
+    pub async fn import(
+        &self,
+        archive: impl Into<reqwest::Body>,
+        name: &str,
+        tag: Option<&str>,
+    ) -> ClientResult<ImportSummary> {
+        let mut query = vec![("name", name)];
+        if let Some(tag) = tag {
+            query.push(("tag", tag));
+        }
+        let response = self
+            .request(Method::POST, "admin/import")?
+            .query(&query)
+            .body(archive)
+            .send()
+            .await?;
+        parse(response).await
+    }
+
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> ClientResult<T> {
+        let response = self.request(Method::GET, path)?.query(query).send().await?;
+        parse(response).await
+    }
+
+    async fn post_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> ClientResult<T> {
+        let response = self
+            .request(Method::POST, path)?
+            .query(query)
+            .send()
+            .await?;
+        parse(response).await
+    }
+
+    fn request(&self, method: Method, path: &str) -> ClientResult<RequestBuilder> {
+        let request = self.http.request(method, self.url(path)?);
+        Ok(match &self.api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        })
+    }
+
+    fn url(&self, path: &str) -> ClientResult<Url> {
+        self.base
+            .join(path)
+            .map_err(|e| ClientError::InvalidUrl(e.to_string()))
+    }
+}
+
+// Non-2xx responses become errors carrying the handler's plain-text message
+async fn parse<T: DeserializeOwned>(response: reqwest::Response) -> ClientResult<T> {
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        return Err(ClientError::Status {
+            status,
+            message: message.trim().to_string(),
+        });
+    }
+    Ok(response.json().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_url() {
+        let client = Client::new("http://127.0.0.1:8080").unwrap();
+        assert_eq!(
+            client.url("api/uploads").unwrap().as_str(),
+            "http://127.0.0.1:8080/api/uploads"
+        );
+        let client = Client::new("https://ops.example.com/proxy").unwrap();
+        assert_eq!(
+            client.url("healthz").unwrap().as_str(),
+            "https://ops.example.com/proxy/healthz"
+        );
+        assert!(Client::new("127.0.0.1:8080").is_err());
+    }
+}
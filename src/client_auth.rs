@@ -0,0 +1,984 @@
+/// Authentication of incoming registry requests
+///
+/// With `[client_auth]` users or an htpasswd file configured, every `/v2/`
+/// request must carry HTTP Basic credentials of one of those users, and is
+/// otherwise answered with a 401 and a `Basic` challenge so `docker login`
+/// works against the proxy. Passwords are bcrypt hashes (as written by
+/// `htpasswd -B`) or plain text from the config file. Verifying a bcrypt hash
+/// is slow on purpose, so accepted `Authorization` values are remembered by
+/// their SHA-256 until the users change. The htpasswd file is polled and
+/// re-read when its modification time or size changes, so accounts can be
+/// added and revoked with `htpasswd` without restarting, as with `registry:2`.
+///
+/// With `token_service` on, the proxy is its own token server: the challenge
+/// is `Bearer` with the realm pointing at `/token`, which exchanges Basic
+/// credentials for a short-lived HS256 JWT granting the requested scopes.
+/// `/v2/` requests are then accepted with a token whose access covers the
+/// request's scope, as well as with Basic credentials.
+///
+/// `[[client_auth.grants]]` restrict users, directly or through
+/// `[client_auth.groups]`, to the repositories and operations granted to
+/// them; other requests are refused with a 403, and tokens are only issued
+/// for granted actions. A cross-repository mount also needs pull access to
+/// its `from` repository, and the catalog only lists granted repositories.
+/// Client certificate identities from the TLS listener are users too.
+/// Without grants every user may access everything.
+///
+/// Without `[admin] api_keys`, `/api/` requests need the same credentials.
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::http::{HeaderMap, header};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use regex::Regex;
+
+use crate::clock::{Clock, Random};
+use crate::config::{ClientAuthConfig, PolicyOperation};
+use crate::policy;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issuer of the proxy's tokens
+const TOKEN_ISSUER: &str = "docker-proxy";
+
+/// Accepted credentials remembered at most; the set is cleared when full
+const MAX_VERIFIED: usize = 1024;
+
+enum Password {
+    Bcrypt(String),
+    Plain(String),
+}
+
+impl Password {
+    fn parse(value: &str) -> Self {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| value.starts_with(prefix))
+        {
+            Password::Bcrypt(value.to_string())
+        } else {
+            Password::Plain(value.to_string())
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Password::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Password::Plain(expected) => constant_time_eq(expected.as_bytes(), password.as_bytes()),
+        }
+    }
+}
+
+/// A resource and the actions granted on it, e.g. `repository:library/nginx:pull`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Access {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub actions: Vec<String>,
+}
+
+impl Access {
+    /// Parse one scope; the name may itself contain colons (a registry port)
+    pub fn parse(scope: &str) -> Option<Self> {
+        let (kind, rest) = scope.split_once(':')?;
+        let (name, actions) = rest.rsplit_once(':')?;
+        if kind.is_empty() || name.is_empty() {
+            return None;
+        }
+        Some(Self {
+            kind: kind.to_string(),
+            name: name.to_string(),
+            actions: actions
+                .split(',')
+                .filter(|a| !a.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: String,
+    iat: u64,
+    nbf: u64,
+    exp: u64,
+    jti: String,
+    access: Vec<Access>,
+}
+
+/// Response body of the token endpoint
+#[derive(Debug, Serialize)]
+pub struct TokenGrant {
+    pub token: String,
+    /// Same as `token`, for OAuth2 clients
+    pub access_token: String,
+    pub expires_in: u64,
+}
+
+struct TokenService {
+    key: Vec<u8>,
+    ttl_secs: u64,
+    /// Configured realm URL; derived from the request when empty
+    realm_url: String,
+}
+
+/// Request extension naming the user an authorized request came from
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser(pub String);
+
+// A compiled `[[client_auth.grants]]` entry, with groups expanded to users
+struct UserGrant {
+    users: HashSet<String>,
+    repositories: Regex,
+    operations: Vec<PolicyOperation>,
+}
+
+// An htpasswd file and the users last loaded from it
+struct Htpasswd {
+    path: String,
+    /// Modification time and size when last checked; `None` before the first
+    /// check
+    stamp: Mutex<Option<Result<(SystemTime, u64), io::ErrorKind>>>,
+    users: RwLock<Arc<HashMap<String, Password>>>,
+}
+
+pub struct ClientAuth {
+    realm: String,
+    /// Users from the config file; they take precedence over htpasswd entries
+    users: HashMap<String, Password>,
+    htpasswd: Option<Htpasswd>,
+    /// User of each `Authorization` value that passed verification, keyed by
+    /// its SHA-256
+    verified: Mutex<HashMap<[u8; 32], String>>,
+    tokens: Option<TokenService>,
+    grants: Vec<UserGrant>,
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn Random>,
+}
+
+impl ClientAuth {
+    /// `None` unless client authentication is configured. An htpasswd file
+    /// that cannot be read at startup is logged and contributes no users, so
+    /// requests are refused rather than let through.
+    pub fn from_config(
+        config: &ClientAuthConfig,
+        clock: Arc<dyn Clock>,
+        random: Arc<dyn Random>,
+    ) -> Option<Self> {
+        if !config.is_enabled() {
+            return None;
+        }
+        let users = config
+            .users
+            .iter()
+            .map(|(user, password)| (user.clone(), Password::parse(password)))
+            .collect();
+        let htpasswd = (!config.htpasswd.is_empty()).then(|| Htpasswd {
+            path: config.htpasswd.clone(),
+            stamp: Mutex::new(None),
+            users: RwLock::new(Arc::new(HashMap::new())),
+        });
+        let tokens = config.token_service.then(|| TokenService {
+            key: if config.token_key.is_empty() {
+                // tokens do not survive a restart; clients fetch new ones
+                let (a, b) = (random.uuid(), random.uuid());
+                [a.as_bytes().as_slice(), b.as_bytes().as_slice()].concat()
+            } else {
+                config.token_key.as_bytes().to_vec()
+            },
+            ttl_secs: config.token_ttl_secs,
+            realm_url: config.token_realm.clone(),
+        });
+        let grants = config
+            .grants
+            .iter()
+            .filter_map(|grant| {
+                let repositories = Regex::new(&policy::glob_to_regex(&grant.repositories))
+                    .inspect_err(|e| tracing::error!("Invalid client auth grant, ignored: {}", e))
+                    .ok()?;
+                let users = grant
+                    .users
+                    .iter()
+                    .chain(
+                        grant
+                            .groups
+                            .iter()
+                            .flat_map(|group| config.groups.get(group).into_iter().flatten()),
+                    )
+                    .cloned()
+                    .collect();
+                Some(UserGrant {
+                    users,
+                    repositories,
+                    operations: grant.operations.clone(),
+                })
+            })
+            .collect();
+        let auth = Self {
+            realm: config.realm.clone(),
+            users,
+            htpasswd,
+            verified: Mutex::new(HashMap::new()),
+            tokens,
+            grants,
+            clock,
+            random,
+        };
+        auth.reload_htpasswd();
+        tracing::info!(
+            "Client authentication enabled for {} user(s)",
+            auth.users.len() + auth.htpasswd_users().len()
+        );
+        Some(auth)
+    }
+
+    /// Re-read the htpasswd file if its modification time or size changed
+    /// since the last check; returns whether its users were replaced. When
+    /// the file cannot be read the users loaded before stay in effect.
+    pub fn reload_htpasswd(&self) -> bool {
+        let Some(htpasswd) = &self.htpasswd else {
+            return false;
+        };
+        let stamp = std::fs::metadata(&htpasswd.path)
+            .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+            .map_err(|e| e.kind());
+        let mut last = htpasswd.stamp.lock().unwrap_or_else(|e| e.into_inner());
+        if last.as_ref() == Some(&stamp) {
+            return false;
+        }
+        *last = Some(stamp);
+        let content = match stamp {
+            Ok(_) => std::fs::read_to_string(&htpasswd.path),
+            Err(kind) => Err(kind.into()),
+        };
+        match content {
+            Ok(content) => {
+                let users: HashMap<_, _> = parse_htpasswd(&content).collect();
+                tracing::info!(
+                    "Loaded {} user(s) from htpasswd file {}",
+                    users.len(),
+                    htpasswd.path
+                );
+                *htpasswd.users.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(users);
+                // revoked or changed passwords must not stay accepted
+                self.lock().clear();
+                true
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Failed to read htpasswd file {}, keeping {} user(s) loaded before: {}",
+                    htpasswd.path,
+                    self.htpasswd_users().len(),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Poll the htpasswd file for changes
+    pub fn spawn_reload_task(self: Arc<Self>, interval: Duration) {
+        if self.htpasswd.is_none() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let auth = Arc::clone(&self);
+                if let Err(e) = tokio::task::spawn_blocking(move || auth.reload_htpasswd()).await {
+                    tracing::warn!("htpasswd reload task failed: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Whether clients are sent to `/token` for Bearer tokens
+    pub fn issues_tokens(&self) -> bool {
+        self.tokens.is_some()
+    }
+
+    /// User of valid Basic credentials in the request, or of a token
+    /// granting `scope` (the space-separated scopes the request needs, `None`
+    /// when any valid token will do); empty for anonymous tokens
+    pub fn authorize(&self, headers: &HeaderMap, scope: Option<&str>) -> Option<String> {
+        let value = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())?;
+        match value.trim().split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => self
+                .verify_token(token.trim())
+                .filter(|claims| scope.is_none_or(|scope| covers(&claims.access, scope)))
+                // tokens of removed users stop working right away; anonymous
+                // tokens have no user
+                .filter(|claims| {
+                    claims.sub.is_empty()
+                        || self.users.contains_key(&claims.sub)
+                        || self.htpasswd_users().contains_key(&claims.sub)
+                })
+                .map(|claims| claims.sub),
+            _ => self.authenticate(headers),
+        }
+    }
+
+    /// User named by valid Basic credentials in the request
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<String> {
+        let value = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())?;
+        let fingerprint: [u8; 32] = Sha256::digest(value.as_bytes()).into();
+        if let Some(user) = self.lock().get(&fingerprint) {
+            return Some(user.clone());
+        }
+
+        let (user, password) = basic_credentials(value)?;
+        let htpasswd_users = self.htpasswd_users();
+        let valid = self
+            .users
+            .get(&user)
+            .or_else(|| htpasswd_users.get(&user))
+            .is_some_and(|expected| expected.verify(&password));
+        if !valid {
+            tracing::warn!(user = %user, "Client authentication failed");
+            return None;
+        }
+        let mut verified = self.lock();
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+        verified.insert(fingerprint, user.clone());
+        Some(user)
+    }
+
+    /// Whether users are limited to the repositories granted to them
+    pub fn has_grants(&self) -> bool {
+        !self.grants.is_empty()
+    }
+
+    /// Whether `user` may perform `operation` on `repository`; always true
+    /// without grants
+    pub fn permits(&self, user: &str, operation: PolicyOperation, repository: &str) -> bool {
+        self.grants.is_empty()
+            || self.grants.iter().any(|grant| {
+                grant.users.contains(user)
+                    && (grant.operations.is_empty() || grant.operations.contains(&operation))
+                    && grant.repositories.is_match(repository)
+            })
+    }
+
+    /// Sign a token for `user` (empty for anonymous clients) granting the
+    /// requested scopes, less repository actions the user is not granted;
+    /// `None` when the token service is off
+    pub fn issue_token(&self, user: &str, scopes: &[String]) -> Option<TokenGrant> {
+        let service = self.tokens.as_ref()?;
+        let now = self.clock.now_secs();
+        let claims = Claims {
+            iss: TOKEN_ISSUER.to_string(),
+            sub: user.to_string(),
+            aud: self.realm.clone(),
+            iat: now,
+            nbf: now,
+            exp: now + service.ttl_secs,
+            jti: self.random.uuid().to_string(),
+            access: scopes
+                .iter()
+                .flat_map(|scope| scope.split_whitespace())
+                .filter_map(Access::parse)
+                .filter_map(|mut access| {
+                    if access.kind == "repository" && !user.is_empty() && !self.grants.is_empty() {
+                        access.actions.retain(|action| {
+                            scope_operation(action).is_some_and(|operation| {
+                                self.permits(user, operation, &access.name)
+                            })
+                        });
+                    }
+                    (!access.actions.is_empty()).then_some(access)
+                })
+                .collect(),
+        };
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).ok()?);
+        let signing_input = format!("{}.{}", header, payload);
+        let mut mac = HmacSha256::new_from_slice(&service.key).ok()?;
+        mac.update(signing_input.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        let token = format!("{}.{}", signing_input, signature);
+        Some(TokenGrant {
+            access_token: token.clone(),
+            token,
+            expires_in: service.ttl_secs,
+        })
+    }
+
+    /// `WWW-Authenticate` value for rejected requests. With the token
+    /// service on, the realm is the absolute URL of `/token` on this proxy
+    /// as the client reached it, under the service's path `prefix`.
+    pub fn challenge(&self, headers: &HeaderMap, prefix: &str, scope: Option<&str>) -> String {
+        let Some(service) = &self.tokens else {
+            return self.basic_challenge();
+        };
+        let realm_url = if !service.realm_url.is_empty() {
+            service.realm_url.clone()
+        } else {
+            let forwarded = |name: &str| {
+                headers
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.split(',').next())
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty() && !v.contains(['"', '\\', ' ']))
+            };
+            let proto = forwarded("x-forwarded-proto").unwrap_or("http");
+            match forwarded("x-forwarded-host").or_else(|| forwarded(header::HOST.as_str())) {
+                Some(host) => format!("{}://{}{}/token", proto, host, prefix),
+                // root-relative; the path prefix is added to the response later
+                None => "/token".to_string(),
+            }
+        };
+        let mut challenge = format!("Bearer realm=\"{}\",service=\"{}\"", realm_url, self.realm);
+        if let Some(scope) = scope {
+            challenge.push_str(&format!(",scope=\"{}\"", scope));
+        }
+        challenge
+    }
+
+    /// `WWW-Authenticate` value asking for Basic credentials
+    pub fn basic_challenge(&self) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+    }
+
+    // Claims of a token signed with our key that is valid now
+    fn verify_token(&self, token: &str) -> Option<Claims> {
+        let service = self.tokens.as_ref()?;
+        let (signing_input, signature) = token.rsplit_once('.')?;
+        let (_, payload) = signing_input.split_once('.')?;
+        let mut mac = HmacSha256::new_from_slice(&service.key).ok()?;
+        mac.update(signing_input.as_bytes());
+        mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?)
+            .ok()?;
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        let now = self.clock.now_secs();
+        (claims.iss == TOKEN_ISSUER
+            && claims.aud == self.realm
+            && claims.nbf <= now
+            && now < claims.exp)
+            .then_some(claims)
+    }
+
+    fn htpasswd_users(&self) -> Arc<HashMap<String, Password>> {
+        match &self.htpasswd {
+            Some(htpasswd) => Arc::clone(&htpasswd.users.read().unwrap_or_else(|e| e.into_inner())),
+            None => Arc::default(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<[u8; 32], String>> {
+        self.verified.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Operations allowed without credentials, by repository prefix
+pub struct Anonymous {
+    /// Prefixes and their operations, longest prefix first
+    rules: Vec<(String, Vec<PolicyOperation>)>,
+}
+
+impl Anonymous {
+    /// `None` when every request needs credentials
+    pub fn from_config(config: &ClientAuthConfig) -> Option<Self> {
+        if config.anonymous.is_empty() {
+            return None;
+        }
+        let mut rules: Vec<_> = config
+            .anonymous
+            .iter()
+            .map(|rule| (rule.prefix.clone(), rule.operations.clone()))
+            .collect();
+        rules.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Some(Self { rules })
+    }
+
+    /// Whether anyone may perform `operation` on `repository`
+    pub fn allows(&self, operation: PolicyOperation, repository: &str) -> bool {
+        self.rules
+            .iter()
+            .find(|(prefix, _)| repository.starts_with(prefix.as_str()))
+            .is_some_and(|(_, operations)| operations.contains(&operation))
+    }
+
+    /// The requested token scopes narrowed to what is allowed anonymously
+    pub fn narrow(&self, scopes: &[String]) -> Vec<String> {
+        scopes
+            .iter()
+            .flat_map(|scope| scope.split_whitespace())
+            .filter_map(Access::parse)
+            .filter(|access| access.kind == "repository")
+            .filter_map(|access| {
+                let actions: Vec<&str> = access
+                    .actions
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|action| {
+                        scope_operation(action)
+                            .is_some_and(|operation| self.allows(operation, &access.name))
+                    })
+                    .collect();
+                (!actions.is_empty())
+                    .then(|| format!("repository:{}:{}", access.name, actions.join(",")))
+            })
+            .collect()
+    }
+}
+
+// Users from htpasswd lines ("user:hash"); comments and blank lines are skipped
+fn parse_htpasswd(content: &str) -> impl Iterator<Item = (String, Password)> + '_ {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (user, hash) = line.split_once(':')?;
+            match Password::parse(hash) {
+                Password::Bcrypt(hash) => Some((user.to_string(), Password::Bcrypt(hash))),
+                Password::Plain(_) => {
+                    tracing::warn!(user = %user, "Ignoring htpasswd entry that is not bcrypt");
+                    None
+                }
+            }
+        })
+}
+
+/// Username and password from a `Basic` Authorization value
+pub fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+// Operation of a repository scope action
+fn scope_operation(action: &str) -> Option<PolicyOperation> {
+    [
+        PolicyOperation::Pull,
+        PolicyOperation::Push,
+        PolicyOperation::Delete,
+    ]
+    .into_iter()
+    .find(|operation| policy::operation_name(*operation) == action)
+}
+
+// Whether every action of every scope in `scope` is granted
+fn covers(granted: &[Access], scope: &str) -> bool {
+    scope.split_whitespace().all(|scope| {
+        Access::parse(scope).is_some_and(|required| {
+            required.actions.iter().all(|action| {
+                granted.iter().any(|access| {
+                    access.kind == required.kind
+                        && access.name == required.name
+                        && access
+                            .actions
+                            .iter()
+                            .any(|granted| granted == action || granted == "*")
+                })
+            })
+        })
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SequentialRandom, os_random, system};
+
+    fn client_auth(config: &ClientAuthConfig) -> Option<ClientAuth> {
+        ClientAuth::from_config(config, system(), os_random())
+    }
+
+    fn basic(user: &str, password: &str) -> HeaderMap {
+        let encoded =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Basic {}", encoded).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        assert_eq!(
+            basic_credentials("Basic dXNlcjpwYTpzcw=="),
+            Some(("user".to_string(), "pa:ss".to_string()))
+        );
+        assert_eq!(basic_credentials("Bearer dXNlcjpwYXNz"), None);
+        assert_eq!(basic_credentials("Basic !!!"), None);
+        assert_eq!(basic_credentials("Basic dXNlcg=="), None);
+    }
+
+    #[test]
+    fn test_authorize_config_and_htpasswd_users() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let htpasswd =
+            std::env::temp_dir().join(format!("docker-proxy-htpasswd-{}", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &htpasswd,
+            format!("# team accounts\nci:{}\nlegacy:{{SHA}}abc\n", hash),
+        )
+        .unwrap();
+        let config = ClientAuthConfig {
+            users: HashMap::from([("admin".to_string(), "s3cret".to_string())]),
+            htpasswd: htpasswd.to_string_lossy().into_owned(),
+            ..ClientAuthConfig::default()
+        };
+        let auth = client_auth(&config).unwrap();
+        let _ = std::fs::remove_file(&htpasswd);
+
+        assert!(auth.authorize(&basic("admin", "s3cret"), None).is_some());
+        assert!(auth.authorize(&basic("ci", "hunter2"), None).is_some());
+        // served from the verified set the second time
+        assert!(auth.authorize(&basic("ci", "hunter2"), None).is_some());
+        assert_eq!(auth.lock().len(), 2);
+
+        assert!(auth.authorize(&basic("ci", "wrong"), None).is_none());
+        assert!(auth.authorize(&basic("admin", "s3cret "), None).is_none());
+        assert!(auth.authorize(&basic("legacy", "abc"), None).is_none());
+        assert!(auth.authorize(&HeaderMap::new(), None).is_none());
+        assert_eq!(
+            auth.challenge(&HeaderMap::new(), "", None),
+            "Basic realm=\"docker-proxy\", charset=\"UTF-8\""
+        );
+    }
+
+    #[test]
+    fn test_unreadable_htpasswd_denies() {
+        let config = ClientAuthConfig {
+            htpasswd: "/nonexistent/htpasswd".to_string(),
+            ..ClientAuthConfig::default()
+        };
+        let auth = client_auth(&config).unwrap();
+        assert!(auth.authorize(&basic("anyone", "anything"), None).is_none());
+        assert!(client_auth(&ClientAuthConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_htpasswd_reload() {
+        let htpasswd =
+            std::env::temp_dir().join(format!("docker-proxy-htpasswd-{}", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &htpasswd,
+            format!("ci:{}\n", bcrypt::hash("first", 4).unwrap()),
+        )
+        .unwrap();
+        let config = ClientAuthConfig {
+            htpasswd: htpasswd.to_string_lossy().into_owned(),
+            token_service: true,
+            ..ClientAuthConfig::default()
+        };
+        let auth = client_auth(&config).unwrap();
+        assert_eq!(
+            auth.authenticate(&basic("ci", "first")).as_deref(),
+            Some("ci")
+        );
+        let token = bearer(&auth.issue_token("ci", &[]).unwrap().token);
+        assert!(auth.authorize(&token, None).is_some());
+        assert!(!auth.reload_htpasswd());
+
+        // a changed password and a new user apply without a restart
+        std::fs::write(
+            &htpasswd,
+            format!(
+                "ci:{}\nrelease:{}\n",
+                bcrypt::hash("second", 4).unwrap(),
+                bcrypt::hash("r3lease", 4).unwrap()
+            ),
+        )
+        .unwrap();
+        assert!(auth.reload_htpasswd());
+        assert!(auth.authenticate(&basic("ci", "first")).is_none());
+        assert!(auth.authenticate(&basic("ci", "second")).is_some());
+        assert!(auth.authenticate(&basic("release", "r3lease")).is_some());
+
+        // removing a user also revokes their tokens
+        std::fs::write(
+            &htpasswd,
+            format!("release:{}\n", bcrypt::hash("r3lease", 4).unwrap()),
+        )
+        .unwrap();
+        assert!(auth.reload_htpasswd());
+        assert!(auth.authorize(&token, None).is_none());
+
+        // a file that disappears keeps the users loaded before
+        std::fs::remove_file(&htpasswd).unwrap();
+        assert!(!auth.reload_htpasswd());
+        assert!(auth.authenticate(&basic("release", "r3lease")).is_some());
+    }
+
+    #[test]
+    fn test_anonymous_access() {
+        let config: ClientAuthConfig = toml::from_str(
+            r#"
+users = { ci = "s3cret" }
+
+[[anonymous]]
+prefix = ""
+operations = ["pull"]
+
+[[anonymous]]
+prefix = "internal/"
+
+[[anonymous]]
+prefix = "scratch/"
+operations = ["pull", "push", "delete"]
+"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let anonymous = Anonymous::from_config(&config).unwrap();
+        assert!(anonymous.allows(PolicyOperation::Pull, "library/nginx"));
+        assert!(!anonymous.allows(PolicyOperation::Push, "library/nginx"));
+        assert!(!anonymous.allows(PolicyOperation::Pull, "internal/app"));
+        assert!(anonymous.allows(PolicyOperation::Delete, "scratch/tmp"));
+        assert_eq!(
+            anonymous.narrow(&[
+                "repository:team/app:push,pull repository:internal/app:pull".to_string(),
+                "registry:catalog:*".to_string(),
+                "repository:scratch/tmp:*,delete".to_string(),
+            ]),
+            vec![
+                "repository:team/app:pull".to_string(),
+                "repository:scratch/tmp:delete".to_string(),
+            ]
+        );
+        assert!(Anonymous::from_config(&ClientAuthConfig::default()).is_none());
+
+        let duplicate = ClientAuthConfig {
+            anonymous: vec![config.anonymous[0].clone(), config.anonymous[0].clone()],
+            ..config
+        };
+        assert!(duplicate.validate().is_err());
+    }
+
+    #[test]
+    fn test_grants() {
+        let config: ClientAuthConfig = toml::from_str(
+            r#"
+users = { alice = "a", bob = "b", carol = "c" }
+token_service = true
+token_key = "token signing key"
+
+[groups]
+team-a = ["alice", "carol"]
+
+[[grants]]
+groups = ["team-a"]
+repositories = "team-a/**"
+
+[[grants]]
+users = ["bob"]
+repositories = "team-b/**"
+operations = ["pull", "push"]
+
+[[grants]]
+users = ["carol"]
+repositories = "library/*"
+operations = ["pull"]
+"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let auth = ClientAuth::from_config(
+            &config,
+            ManualClock::new(1_700_000_000),
+            Arc::new(SequentialRandom::default()),
+        )
+        .unwrap();
+        assert!(auth.permits("alice", PolicyOperation::Delete, "team-a/app"));
+        assert!(!auth.permits("alice", PolicyOperation::Pull, "team-b/app"));
+        assert!(auth.permits("bob", PolicyOperation::Push, "team-b/app"));
+        assert!(!auth.permits("bob", PolicyOperation::Delete, "team-b/app"));
+        assert!(auth.permits("carol", PolicyOperation::Pull, "library/nginx"));
+        assert!(!auth.permits("carol", PolicyOperation::Push, "library/nginx"));
+        assert!(!auth.permits("dave", PolicyOperation::Pull, "team-a/app"));
+
+        let grant = auth
+            .issue_token(
+                "bob",
+                &["repository:team-b/app:push,pull,delete repository:team-a/app:pull".to_string()],
+            )
+            .unwrap();
+        let token = bearer(&grant.token);
+        assert!(
+            auth.authorize(&token, Some("repository:team-b/app:push,pull"))
+                .is_some()
+        );
+        assert!(
+            auth.authorize(&token, Some("repository:team-b/app:delete"))
+                .is_none()
+        );
+        assert!(
+            auth.authorize(&token, Some("repository:team-a/app:pull"))
+                .is_none()
+        );
+
+        let mut unknown_group = config.clone();
+        unknown_group.grants[0].groups = vec!["team-c".to_string()];
+        assert!(unknown_group.validate().is_err());
+        let mut nobody = config;
+        nobody.grants[0].groups.clear();
+        assert!(nobody.validate().is_err());
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_access_parse() {
+        assert_eq!(
+            Access::parse("repository:localhost:5000/team/app:push,pull"),
+            Some(Access {
+                kind: "repository".to_string(),
+                name: "localhost:5000/team/app".to_string(),
+                actions: vec!["push".to_string(), "pull".to_string()],
+            })
+        );
+        assert_eq!(Access::parse("repository:nginx"), None);
+        assert_eq!(Access::parse("registry::*"), None);
+    }
+
+    #[test]
+    fn test_token_service() {
+        let config = ClientAuthConfig {
+            users: HashMap::from([("ci".to_string(), "s3cret".to_string())]),
+            token_service: true,
+            token_key: "token signing key".to_string(),
+            ..ClientAuthConfig::default()
+        };
+        let clock = ManualClock::new(1_700_000_000);
+        let auth = ClientAuth::from_config(
+            &config,
+            clock.clone(),
+            Arc::new(SequentialRandom::default()),
+        )
+        .unwrap();
+        assert!(auth.issues_tokens());
+        assert_eq!(
+            auth.authenticate(&basic("ci", "s3cret")).as_deref(),
+            Some("ci")
+        );
+        assert_eq!(auth.authenticate(&basic("ci", "wrong")), None);
+
+        let grant = auth
+            .issue_token(
+                "ci",
+                &["repository:team/app:pull repository:team/base:pull".to_string()],
+            )
+            .unwrap();
+        assert_eq!(grant.expires_in, 300);
+        assert_eq!(grant.token, grant.access_token);
+        let token = bearer(&grant.token);
+        assert_eq!(auth.authorize(&token, None).as_deref(), Some("ci"));
+        assert!(
+            auth.authorize(&token, Some("repository:team/app:pull"))
+                .is_some()
+        );
+        assert!(
+            auth.authorize(&token, Some("repository:team/app:push,pull"))
+                .is_none()
+        );
+        assert!(
+            auth.authorize(&token, Some("repository:team/other:pull"))
+                .is_none()
+        );
+        assert!(auth.authorize(&token, Some("registry:catalog:*")).is_none());
+        // Basic credentials are still accepted
+        assert!(
+            auth.authorize(&basic("ci", "s3cret"), Some("registry:catalog:*"))
+                .is_some()
+        );
+
+        // tampered or foreign tokens are refused
+        let (signing_input, _) = grant.token.rsplit_once('.').unwrap();
+        assert!(
+            auth.authorize(&bearer(&format!("{}.AAAA", signing_input)), None)
+                .is_none()
+        );
+        let other = ClientAuth::from_config(
+            &ClientAuthConfig {
+                token_key: "another key".to_string(),
+                ..config.clone()
+            },
+            clock.clone(),
+            os_random(),
+        )
+        .unwrap();
+        assert!(other.authorize(&token, None).is_none());
+
+        clock.advance(std::time::Duration::from_secs(300));
+        assert!(auth.authorize(&token, None).is_none());
+    }
+
+    #[test]
+    fn test_bearer_challenge() {
+        let config = ClientAuthConfig {
+            users: HashMap::from([("ci".to_string(), "s3cret".to_string())]),
+            token_service: true,
+            ..ClientAuthConfig::default()
+        };
+        let auth = client_auth(&config).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "proxy.lan:8080".parse().unwrap());
+        assert_eq!(
+            auth.challenge(&headers, "", Some("repository:team/app:pull")),
+            "Bearer realm=\"http://proxy.lan:8080/token\",service=\"docker-proxy\",scope=\"repository:team/app:pull\""
+        );
+        headers.insert("x-forwarded-proto", "https, http".parse().unwrap());
+        headers.insert("x-forwarded-host", "registry.example.com".parse().unwrap());
+        assert_eq!(
+            auth.challenge(&headers, "/mirror", None),
+            "Bearer realm=\"https://registry.example.com/mirror/token\",service=\"docker-proxy\""
+        );
+        assert_eq!(
+            auth.challenge(&HeaderMap::new(), "/mirror", None),
+            "Bearer realm=\"/token\",service=\"docker-proxy\""
+        );
+
+        let auth = client_auth(&ClientAuthConfig {
+            token_realm: "https://auth.example.com/token".to_string(),
+            ..config
+        })
+        .unwrap();
+        assert_eq!(
+            auth.challenge(&headers, "/mirror", None),
+            "Bearer realm=\"https://auth.example.com/token\",service=\"docker-proxy\""
+        );
+    }
+}
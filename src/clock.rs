@@ -0,0 +1,107 @@
+/// Injectable time and randomness
+///
+/// Subsystems with timestamps, TTLs or generated identifiers take a `Clock`
+/// and a `Random` from `DockerProxy` instead of calling `SystemTime::now`,
+/// `Instant::now` or `Uuid::new_v4` themselves, so expiry and eviction can
+/// be tested deterministically with `ManualClock` and `SequentialRandom`.
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use uuid::Uuid;
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    /// Wall-clock seconds since the Unix epoch, for persisted timestamps
+    fn now_secs(&self) -> u64;
+    /// Monotonic time, for in-memory TTLs
+    fn instant(&self) -> Instant;
+}
+
+/// Source of identifiers
+pub trait Random: Send + Sync {
+    fn uuid(&self) -> Uuid;
+}
+
+/// The operating system's clocks
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_secs(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Random (v4) UUIDs
+pub struct OsRandom;
+
+impl Random for OsRandom {
+    fn uuid(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// The clock used outside of tests
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// The identifier source used outside of tests
+pub fn os_random() -> Arc<dyn Random> {
+    Arc::new(OsRandom)
+}
+
+/// A clock that only moves when told to
+#[cfg(test)]
+pub struct ManualClock {
+    start: Instant,
+    start_secs: u64,
+    elapsed: std::sync::Mutex<std::time::Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(start_secs: u64) -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            start_secs,
+            elapsed: std::sync::Mutex::new(std::time::Duration::ZERO),
+        })
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now_secs(&self) -> u64 {
+        self.start_secs + self.elapsed.lock().unwrap().as_secs()
+    }
+
+    fn instant(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+}
+
+/// UUIDs 00000000-0000-0000-0000-000000000001, ...2 and so on
+#[cfg(test)]
+#[derive(Default)]
+pub struct SequentialRandom {
+    next: std::sync::atomic::AtomicU64,
+}
+
+#[cfg(test)]
+impl Random for SequentialRandom {
+    fn uuid(&self) -> Uuid {
+        let n = self.next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Uuid::from_u128(u128::from(n) + 1)
+    }
+}
@@ -0,0 +1,117 @@
+/// Response compression
+///
+/// Responses are compressed with zstd, brotli or gzip when the client
+/// accepts it. Blobs never are: layers are compressed already, recompressing
+/// them only burns CPU, and clients rely on their Content-Length and Range
+/// semantics. `[compression] registry = false` leaves every `/v2/` response
+/// alone while the web UI and the APIs are still compressed.
+use axum::body::HttpBody;
+use axum::http::{Response, header};
+use tower_http::compression::CompressionLayer;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate};
+
+use crate::config::CompressionConfig;
+
+/// Marks a response as answering a registry API (`/v2/`) request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryResponse {
+    Blob,
+    Other,
+}
+
+/// Content types never compressed; they also catch blobs relayed from a
+/// cascaded docker-proxy
+const PRECOMPRESSED_TYPES: [&str; 3] = [
+    "application/octet-stream",
+    "application/vnd.oci.image.layer",
+    "application/vnd.docker.image.rootfs",
+];
+
+/// When a response is compressed
+#[derive(Clone)]
+pub struct CompressionPolicy {
+    enabled: bool,
+    registry: bool,
+}
+
+impl CompressionPolicy {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            registry: config.registry,
+        }
+    }
+}
+
+impl Predicate for CompressionPolicy {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        if !self.enabled {
+            return false;
+        }
+        match response.extensions().get::<RegistryResponse>() {
+            Some(RegistryResponse::Blob) => return false,
+            Some(RegistryResponse::Other) if !self.registry => return false,
+            _ => {}
+        }
+        let precompressed = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| PRECOMPRESSED_TYPES.iter().any(|t| ct.starts_with(t)));
+        !precompressed && DefaultPredicate::new().should_compress(response)
+    }
+}
+
+/// The compression layer for `config`
+pub fn layer(config: &CompressionConfig) -> CompressionLayer<CompressionPolicy> {
+    CompressionLayer::new()
+        .gzip(config.gzip)
+        .br(config.br)
+        .zstd(config.zstd)
+        .compress_when(CompressionPolicy::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+
+    fn response(content_type: &str, marker: Option<RegistryResponse>) -> Response<Body> {
+        let mut response = Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(vec![b'a'; 4096]))
+            .unwrap();
+        if let Some(marker) = marker {
+            response.extensions_mut().insert(marker);
+        }
+        response
+    }
+
+    #[test]
+    fn test_compression_policy() {
+        let manifest = "application/vnd.oci.image.manifest.v1+json";
+        let policy = CompressionPolicy::new(&CompressionConfig::default());
+        assert!(policy.should_compress(&response(manifest, Some(RegistryResponse::Other))));
+        assert!(policy.should_compress(&response("text/html", None)));
+        // Blobs stay as they are whatever type the upstream reports
+        assert!(!policy.should_compress(&response("text/plain", Some(RegistryResponse::Blob))));
+        assert!(!policy.should_compress(&response("application/octet-stream", None)));
+
+        let policy = CompressionPolicy::new(&CompressionConfig {
+            registry: false,
+            ..CompressionConfig::default()
+        });
+        assert!(!policy.should_compress(&response(manifest, Some(RegistryResponse::Other))));
+        assert!(policy.should_compress(&response("text/html", None)));
+
+        let policy = CompressionPolicy::new(&CompressionConfig {
+            enabled: false,
+            ..CompressionConfig::default()
+        });
+        assert!(!policy.should_compress(&response("text/html", None)));
+    }
+}
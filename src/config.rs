@@ -1,12 +1,157 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config_fragments;
+use crate::docker_config;
+use crate::env_overrides;
+use crate::maintenance::DailyWindow;
+use crate::policy;
+use crate::router;
+use crate::secrets;
+
+/// Deployment preset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// Local development: console logging, `./web`, a throwaway cache
+    /// directory and plain HTTP to upstreams on localhost
+    Dev,
+    /// Container deployment: absolute paths and HTTPS upstreams required
+    Prod,
+}
+
+// Per-process scratch directory of the dev profile
+fn dev_scratch_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("docker-proxy-dev-{}", std::process::id()))
+}
 
 /// Server configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ServerConfig {
+    /// Address to listen on
+    #[serde(default = "default_host")]
     pub host: String,
+    /// Port to listen on
+    #[serde(default = "default_port")]
     pub port: u16,
+    /// Directory the web UI is served from (empty = profile default)
+    #[serde(default)]
+    pub web_root: String,
+    /// URL prefix the whole service is mounted under, e.g. "/registry-proxy"
+    /// (empty = served at the root)
+    #[serde(default)]
+    pub path_prefix: String,
+    /// HTTPS on the listener
+    #[serde(default)]
+    pub tls: ServerTlsConfig,
+    /// Every connection starts with a PROXY protocol (v1 or v2) header from
+    /// a TCP load balancer, naming the real client address
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Inherited descriptor of an already listening socket, used instead of
+    /// binding host and port (Unix only)
+    #[serde(default)]
+    pub listen_fd: Option<i32>,
+    /// Seconds the upstream health reported by `/healthz` is reused while a
+    /// background task refreshes it (0 = check on every request)
+    #[serde(default = "default_health_check_interval_secs")]
+    pub health_check_interval_secs: u64,
+}
+
+/// Where the identity of a client certificate is taken from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CertIdentity {
+    /// Common name of the subject
+    #[default]
+    Cn,
+    /// First DNS name, email address or URI among the subject alternative names
+    San,
+}
+
+/// TLS settings of the listener
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ServerTlsConfig {
+    /// PEM certificate chain (empty = plain HTTP)
+    pub cert_file: String,
+    /// PEM private key of the certificate
+    pub key_file: String,
+    /// PEM bundle of CAs client certificates are verified against (empty =
+    /// no client certificates requested)
+    pub client_ca_file: String,
+    /// Refuse connections without a valid client certificate; otherwise
+    /// clients without one fall back to the other authentication methods
+    pub require_client_cert: bool,
+    /// Part of a client certificate used as the user name
+    pub identity: CertIdentity,
+}
+
+impl Default for ServerTlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_file: String::new(),
+            key_file: String::new(),
+            client_ca_file: String::new(),
+            require_client_cert: true,
+            identity: CertIdentity::Cn,
+        }
+    }
+}
+
+impl ServerTlsConfig {
+    /// Whether the listener serves HTTPS
+    pub fn is_enabled(&self) -> bool {
+        !self.cert_file.is_empty()
+    }
+
+    /// Whether clients are asked for certificates
+    pub fn verifies_clients(&self) -> bool {
+        self.is_enabled() && !self.client_ca_file.is_empty()
+    }
+
+    /// Validate listener TLS settings
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cert_file.is_empty() != self.key_file.is_empty() {
+            return Err("Server TLS needs both cert_file and key_file".to_string());
+        }
+        if !self.client_ca_file.is_empty() && !self.is_enabled() {
+            return Err("Server TLS client_ca_file requires cert_file and key_file".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: default_host(),
+            port: default_port(),
+            web_root: String::new(),
+            path_prefix: String::new(),
+            tls: ServerTlsConfig::default(),
+            proxy_protocol: false,
+            listen_fd: None,
+            health_check_interval_secs: default_health_check_interval_secs(),
+        }
+    }
+}
+
+fn default_host() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_port() -> u16 {
+    8080
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    10
 }
 
 impl ServerConfig {
@@ -18,7 +163,24 @@ impl ServerConfig {
         if self.port == 0 {
             return Err("Server port must be greater than 0".to_string());
         }
-        Ok(())
+        if self.listen_fd.is_some_and(|fd| fd < 0) {
+            return Err("Server listen_fd cannot be negative".to_string());
+        }
+        let prefix = self.path_prefix();
+        if !prefix.is_empty()
+            && (!prefix.starts_with('/') || prefix.contains(['?', '#', '{', '}', '*', '"', ' ']))
+        {
+            return Err(format!(
+                "Server path prefix must be an absolute URL path: {}",
+                self.path_prefix
+            ));
+        }
+        self.tls.validate()
+    }
+
+    /// Path prefix without a trailing slash; empty when served at the root
+    pub fn path_prefix(&self) -> &str {
+        self.path_prefix.trim_end_matches('/')
     }
 
     /// Get socket address
@@ -27,12 +189,66 @@ impl ServerConfig {
     }
 }
 
+/// How often the log file is rotated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// Append to a single file
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+/// Line format of the access log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Apache/nginx "combined" log format
+    #[default]
+    Combined,
+    /// One JSON object per request
+    Json,
+}
+
 /// Logging configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct LogConfig {
-    #[serde(rename = "logFilePath")]
+    /// Log file (empty = the console)
+    // the alias is what a DOCKER_PROXY__LOG__LOGFILEPATH override sets
+    #[serde(rename = "logFilePath", alias = "logfilepath", default)]
     pub log_file_path: String,
+    /// trace, debug, info, warn or error
+    #[serde(default = "default_log_level")]
     pub level: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// Delete rotated files older than this many days (0 = keep)
+    #[serde(default)]
+    pub max_age_days: u64,
+    /// Delete the oldest rotated files beyond this total size (0 = unlimited)
+    #[serde(default)]
+    pub max_total_mb: u64,
+    /// File requests are logged to, one line each, apart from the
+    /// application log (empty = no access log); rotated and pruned alike
+    #[serde(default)]
+    pub access_log_path: String,
+    #[serde(default)]
+    pub access_log_format: AccessLogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            log_file_path: String::new(),
+            level: default_log_level(),
+            rotation: LogRotation::default(),
+            max_age_days: 0,
+            max_total_mb: 0,
+            access_log_path: String::new(),
+            access_log_format: AccessLogFormat::default(),
+        }
+    }
 }
 
 impl LogConfig {
@@ -45,22 +261,160 @@ impl LogConfig {
                 self.level, valid_levels
             ));
         }
-        if self.log_file_path.is_empty() {
-            return Err("Log file path cannot be empty".to_string());
+        if self.has_retention() && self.rotation == LogRotation::Never {
+            return Err("Log retention requires log rotation to be enabled".to_string());
+        }
+        if !self.access_log_path.is_empty() && self.access_log_path == self.log_file_path {
+            return Err("Access log path must differ from the log file path".to_string());
         }
         Ok(())
     }
 
+    /// Whether rotated log files are pruned
+    pub fn has_retention(&self) -> bool {
+        self.max_age_days > 0 || self.max_total_mb > 0
+    }
+
     /// Get normalized log level
     pub fn normalized_level(&self) -> String {
         self.level.to_lowercase()
     }
 }
 
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+/// Where pushed blobs and manifests go
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PushMode {
+    /// Forward pushes to the upstream registry
+    #[default]
+    Forward,
+    /// Store pushes in the local blob cache (standalone registry)
+    Local,
+}
+
 /// Proxy configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ProxyConfig {
+    /// Registry pulled from for names without a registry host, e.g.
+    /// "registry-1.docker.io" or "https://ghcr.io"
+    #[serde(default = "default_registry")]
     pub default: String,
+    /// Forward DELETE requests for manifests and blobs to the upstream registry
+    #[serde(default)]
+    pub allow_delete: bool,
+    #[serde(default)]
+    pub push_mode: PushMode,
+    /// Largest blob a local push accepts, in MiB (0 = unlimited)
+    #[serde(default = "default_max_upload_mb")]
+    pub max_upload_mb: u64,
+    /// Total size of locally pushed blobs and manifests, which eviction never
+    /// removes, in MiB (0 = unlimited)
+    #[serde(default)]
+    pub max_pushed_mb: u64,
+    /// Ordered upstream URLs to pull through instead of a registry, keyed by
+    /// registry host; the next one is tried on a 5xx or timeout
+    #[serde(default)]
+    pub mirrors: HashMap<String, Vec<String>>,
+    /// How long a mirror may take to respond before the next one is tried
+    #[serde(default = "default_mirror_timeout_secs")]
+    pub mirror_timeout_secs: u64,
+    /// Milliseconds after which a manifest fetch the first mirror has not
+    /// answered is also sent to the next one, the first usable response
+    /// winning (0 = never)
+    #[serde(default)]
+    pub hedge_delay_ms: u64,
+    /// Upstream registry URLs keyed by the first segment of client-facing
+    /// names, e.g. `ghcr` so that `ghcr/owner/repo` pulls `ghcr.io/owner/repo`
+    #[serde(default)]
+    pub routes: HashMap<String, String>,
+    /// Per-registry options keyed by registry host, e.g. `localhost:5000`
+    #[serde(default)]
+    pub registries: HashMap<String, RegistryOptions>,
+    /// PEM bundle of additional root CAs trusted for every upstream
+    #[serde(default)]
+    pub ca_file: String,
+    /// Bodies read in full (e.g. to compute a manifest digest) are kept in
+    /// memory up to this size in MiB and spill to a temp file beyond it
+    #[serde(default = "default_spill_threshold_mb")]
+    pub spill_threshold_mb: u64,
+    /// Directory for spilled bodies (empty = the system temp directory)
+    #[serde(default)]
+    pub spill_dir: String,
+    /// Hash blobs as they stream from the upstream; content not matching the
+    /// requested digest breaks off the transfer and is not cached
+    #[serde(default)]
+    pub verify_blob_digests: bool,
+}
+
+/// Options for one upstream registry
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct RegistryOptions {
+    /// Talk plain HTTP to the registry instead of HTTPS
+    pub insecure: bool,
+    /// PEM bundle of additional root CAs trusted for this registry
+    pub ca_file: String,
+    /// Accept any certificate from this registry
+    pub skip_tls_verify: bool,
+    /// PEM certificate (chain) presented to registries requiring mutual TLS
+    pub client_cert_file: String,
+    /// PKCS#8 PEM private key of `client_cert_file`
+    pub client_key_file: String,
+    /// Connect to this registry directly, bypassing any outbound proxy
+    pub direct: bool,
+    /// HTTP version used with this registry instead of `[client] http_version`
+    pub http_version: Option<HttpVersion>,
+}
+
+/// URL of a registry host: http:// when it is configured as insecure,
+/// https:// otherwise
+pub fn registry_host_url(registries: &HashMap<String, RegistryOptions>, host: &str) -> String {
+    if registries.get(host).is_some_and(|r| r.insecure) {
+        format!("http://{}", host)
+    } else {
+        format!("https://{}", host)
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            default: default_registry(),
+            allow_delete: false,
+            push_mode: PushMode::default(),
+            max_upload_mb: default_max_upload_mb(),
+            max_pushed_mb: 0,
+            mirrors: HashMap::new(),
+            mirror_timeout_secs: default_mirror_timeout_secs(),
+            hedge_delay_ms: 0,
+            routes: HashMap::new(),
+            registries: HashMap::new(),
+            ca_file: String::new(),
+            spill_threshold_mb: default_spill_threshold_mb(),
+            spill_dir: String::new(),
+            verify_blob_digests: false,
+        }
+    }
+}
+
+fn default_registry() -> String {
+    "registry-1.docker.io".to_string()
+}
+
+fn default_max_upload_mb() -> u64 {
+    10 * 1024
+}
+
+fn default_mirror_timeout_secs() -> u64 {
+    10
+}
+
+fn default_spill_threshold_mb() -> u64 {
+    8
 }
 
 impl ProxyConfig {
@@ -69,55 +423,1640 @@ impl ProxyConfig {
         if self.default.is_empty() {
             return Err("Default proxy registry cannot be empty".to_string());
         }
+        for (host, mirrors) in &self.mirrors {
+            if host.is_empty() || host.contains('/') {
+                return Err(format!("Invalid mirror registry host: {:?}", host));
+            }
+            if mirrors.is_empty() {
+                return Err(format!("Mirror list for {} cannot be empty", host));
+            }
+            if let Some(url) = mirrors
+                .iter()
+                .find(|url| !(url.starts_with("https://") || url.starts_with("http://")))
+            {
+                return Err(format!(
+                    "Mirror for {} must be an http(s) URL: {}",
+                    host, url
+                ));
+            }
+        }
+        if self.mirror_timeout_secs == 0 {
+            return Err("Mirror timeout must be greater than 0".to_string());
+        }
+        if self.hedge_delay_ms >= self.mirror_timeout_secs * 1000 {
+            return Err("Hedge delay must be shorter than the mirror timeout".to_string());
+        }
+        if self.spill_threshold_mb > 4096 {
+            return Err("Spill threshold cannot be more than 4096 MiB".to_string());
+        }
+        if let Some(host) = self
+            .registries
+            .keys()
+            .find(|host| host.is_empty() || host.contains('/'))
+        {
+            return Err(format!("Invalid registry host: {:?}", host));
+        }
+        if let Some(host) = self.registries.iter().find_map(|(host, options)| {
+            (options.client_cert_file.is_empty() != options.client_key_file.is_empty())
+                .then_some(host)
+        }) {
+            return Err(format!(
+                "Registry {} needs both client_cert_file and client_key_file",
+                host
+            ));
+        }
+        for (prefix, url) in &self.routes {
+            // Prefixes that look like a host would be shadowed by host names
+            if prefix.is_empty()
+                || prefix.contains(['/', '.', ':'])
+                || prefix == "localhost"
+                || prefix == "library"
+            {
+                return Err(format!("Invalid route prefix: {:?}", prefix));
+            }
+            if !(url.starts_with("https://") || url.starts_with("http://")) {
+                return Err(format!(
+                    "Route {} must point to an http(s) URL: {}",
+                    prefix, url
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// HTTP version used with upstream registries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    /// HTTP/2 when the registry offers it during the TLS handshake (ALPN),
+    /// with the fetches of a pull multiplexed over one connection
+    #[default]
+    Auto,
+    /// HTTP/1.1 only
+    Http1,
+    /// HTTP/2 from the start (prior knowledge), also over plain HTTP
+    Http2,
+}
+
+/// HTTP client used for upstream registries
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// Seconds to wait for a connection to be established (0 = no limit)
+    pub connect_timeout_secs: u64,
+    /// Seconds a request may take, response body included (0 = no limit);
+    /// the operations below have their own
+    pub timeout_secs: u64,
+    /// Seconds a token request to a registry's auth service may take
+    /// (0 = `timeout_secs`)
+    pub token_timeout_secs: u64,
+    /// Seconds a manifest fetch may take, response body included
+    /// (0 = `timeout_secs`)
+    pub manifest_timeout_secs: u64,
+    /// Seconds to wait for the response to a blob fetch to start
+    /// (0 = no limit)
+    pub blob_first_byte_timeout_secs: u64,
+    /// Seconds a blob fetch may take, response body included; layers can be
+    /// several GB, so leave it generous (0 = `timeout_secs`)
+    pub blob_timeout_secs: u64,
+    /// Idle connections kept open per registry host (unset = no limit)
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Seconds an idle connection is kept open (0 = no limit)
+    pub pool_idle_timeout_secs: u64,
+    /// Requests in flight, and so connections, per registry host; further
+    /// requests wait for one to finish (0 = no limit)
+    pub max_connections_per_host: usize,
+    /// Manifest fetches in flight across all registries; further fetches
+    /// queue (0 = no limit)
+    pub max_manifest_fetches: usize,
+    /// Blob downloads in flight across all registries; further downloads
+    /// queue (0 = no limit)
+    pub max_blob_fetches: usize,
+    /// Seconds requests to a registry fail at once after it answered 429
+    /// without a `Retry-After` (0 = pass 429s on without backing off)
+    pub rate_limit_backoff_secs: u64,
+    pub http_version: HttpVersion,
+    /// User-Agent sent upstream (empty = none)
+    pub user_agent: String,
+    /// Proxy for upstream connections: http://, https://, socks5:// or
+    /// socks5h:// (the proxy resolves names), credentials in the URL if
+    /// needed (empty = HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY from
+    /// the environment)
+    pub outbound_proxy: String,
+    /// Hosts, domains (`.corp.example`) and IP ranges reached without
+    /// `outbound_proxy`
+    pub outbound_no_proxy: Vec<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: 30,
+            timeout_secs: 0,
+            token_timeout_secs: 30,
+            manifest_timeout_secs: 60,
+            blob_first_byte_timeout_secs: 60,
+            blob_timeout_secs: 0,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout_secs: 90,
+            max_connections_per_host: 0,
+            max_manifest_fetches: 0,
+            max_blob_fetches: 0,
+            rate_limit_backoff_secs: 60,
+            http_version: HttpVersion::Auto,
+            user_agent: concat!("docker-proxy/", env!("CARGO_PKG_VERSION")).to_string(),
+            outbound_proxy: String::new(),
+            outbound_no_proxy: Vec::new(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Validate HTTP client settings
+    pub fn validate(&self) -> Result<(), String> {
+        if reqwest::header::HeaderValue::from_str(&self.user_agent).is_err() {
+            return Err(format!("Invalid client user_agent: {:?}", self.user_agent));
+        }
+        let blob_timeout_secs = match self.blob_timeout_secs {
+            0 => self.timeout_secs,
+            secs => secs,
+        };
+        if blob_timeout_secs > 0 && self.blob_first_byte_timeout_secs > blob_timeout_secs {
+            return Err(format!(
+                "Blob first byte timeout ({}s) exceeds the blob timeout ({}s)",
+                self.blob_first_byte_timeout_secs, blob_timeout_secs
+            ));
+        }
+        if !self.outbound_proxy.is_empty() {
+            let scheme = reqwest::Url::parse(&self.outbound_proxy)
+                .map(|url| url.scheme().to_string())
+                .unwrap_or_default();
+            if !["http", "https", "socks5", "socks5h"].contains(&scheme.as_str()) {
+                return Err("Outbound proxy must be an http(s):// or socks5(h):// URL".to_string());
+            }
+        } else if !self.outbound_no_proxy.is_empty() {
+            return Err("outbound_no_proxy requires outbound_proxy".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Name resolution for upstream connections
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ResolveConfig {
+    /// Seconds a looked up address is reused (0 = no caching)
+    pub cache_ttl_secs: u64,
+    /// Static addresses keyed by host name, used instead of DNS, e.g.
+    /// `"registry-1.docker.io" = ["203.0.113.7"]`
+    pub hosts: HashMap<String, Vec<String>>,
+}
+
+impl Default for ResolveConfig {
+    fn default() -> Self {
+        Self {
+            cache_ttl_secs: 60,
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+impl ResolveConfig {
+    /// Validate resolver settings
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cache_ttl_secs > 86400 {
+            return Err(format!(
+                "DNS cache TTL must be at most 86400 seconds, got {}",
+                self.cache_ttl_secs
+            ));
+        }
+        for (host, addresses) in &self.hosts {
+            if addresses.is_empty() {
+                return Err(format!("No addresses given for resolve host {}", host));
+            }
+            if let Some(address) = addresses.iter().find(|a| a.parse::<IpAddr>().is_err()) {
+                return Err(format!(
+                    "Invalid IP address for resolve host {}: {}",
+                    host, address
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compression of responses to clients
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Compress responses for clients that accept it
+    pub enabled: bool,
+    /// Compress `/v2/` responses other than blobs, e.g. manifests and tag
+    /// lists; blobs are never compressed
+    pub registry: bool,
+    /// Offer zstd
+    pub zstd: bool,
+    /// Offer Brotli
+    pub br: bool,
+    /// Offer gzip
+    pub gzip: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            registry: true,
+            zstd: true,
+            br: true,
+            gzip: true,
+        }
+    }
+}
+
+/// Blob cache configuration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Keep pulled blobs on disk and serve repeat pulls from there
+    pub enabled: bool,
+    /// Directory of cached blobs and the cache index
+    pub dir: String,
+    /// Maximum total size of cached blobs in MiB (0 = unlimited)
+    pub max_size_mb: u64,
+    /// How often the cache index is written to disk
+    pub index_flush_secs: u64,
+    /// HMAC key for signing cache-served blobs (empty = signing disabled)
+    pub signing_key: String,
+    /// Key identifier included in the signature header, for key rotation
+    pub signing_key_id: String,
+    /// How long a request for a blob that is being fetched waits for that
+    /// fetch instead of starting its own (0 = never wait)
+    pub coalesce_wait_secs: u64,
+    /// Range requests up to this size in KiB are served from an in-memory
+    /// chunk cache of hot regions, for lazy-pulling clients (0 = disabled)
+    pub hot_range_max_kb: u64,
+    /// Memory used by the hot range chunk cache in MiB
+    pub hot_range_memory_mb: u64,
+    /// How stale a tag's last fetched manifest may be and still be served
+    /// when the upstream is down or rate limiting, e.g. "6h" (empty = never)
+    pub serve_stale_on_error: String,
+    /// Buffer size in KiB for streaming blobs out of and into the cache;
+    /// larger buffers mean fewer reads and writes per blob, smaller ones
+    /// less memory per transfer
+    pub stream_buffer_kb: u64,
+    /// Blobs of an image downloaded at once when pre-fetching
+    pub prefetch_concurrency: usize,
+    /// Extra attempts for a blob whose pre-fetch hit an upstream outage
+    pub prefetch_retries: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: "/app/cache".to_string(),
+            max_size_mb: 10240,
+            index_flush_secs: 30,
+            signing_key: String::new(),
+            signing_key_id: "default".to_string(),
+            coalesce_wait_secs: 30,
+            hot_range_max_kb: 512,
+            hot_range_memory_mb: 64,
+            serve_stale_on_error: String::new(),
+            stream_buffer_kb: 64,
+            prefetch_concurrency: 4,
+            prefetch_retries: 2,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Validate cache configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.dir.is_empty() {
+            return Err("Cache directory cannot be empty".to_string());
+        }
+        if self.index_flush_secs == 0 {
+            return Err("Cache index flush interval must be greater than 0".to_string());
+        }
+        if !self.signing_key.is_empty() && self.signing_key.len() < 16 {
+            return Err("Cache signing key must be at least 16 bytes".to_string());
+        }
+        if self.signing_key_id.contains('"') {
+            return Err("Cache signing key id cannot contain quotes".to_string());
+        }
+        if self.hot_range_max_kb > 0 && self.hot_range_memory_mb == 0 {
+            return Err(
+                "Hot range memory must be greater than 0 when hot ranges are enabled".to_string(),
+            );
+        }
+        if !(4..=16384).contains(&self.stream_buffer_kb) {
+            return Err(format!(
+                "Cache stream_buffer_kb must be between 4 and 16384, got {}",
+                self.stream_buffer_kb
+            ));
+        }
+        if !(1..=64).contains(&self.prefetch_concurrency) {
+            return Err(format!(
+                "Cache prefetch_concurrency must be between 1 and 64, got {}",
+                self.prefetch_concurrency
+            ));
+        }
+        if self.prefetch_retries > 10 {
+            return Err("Cache prefetch_retries cannot be more than 10".to_string());
+        }
+        if !self.serve_stale_on_error.is_empty() && self.stale_window().is_none() {
+            return Err(format!(
+                "Invalid serve_stale_on_error duration: {:?} (expected e.g. 30m, 6h or 2d)",
+                self.serve_stale_on_error
+            ));
+        }
+        Ok(())
+    }
+
+    /// Staleness allowed for manifests served during upstream outages
+    pub fn stale_window(&self) -> Option<Duration> {
+        parse_duration(&self.serve_stale_on_error)
+    }
+}
+
+// Parse a positive duration such as "90s", "30m", "6h" or "2d"
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (count, unit) = value.split_at(value.len().checked_sub(1)?);
+    let count: u64 = count.parse().ok()?;
+    let secs = match unit {
+        "s" => count,
+        "m" => count.checked_mul(60)?,
+        "h" => count.checked_mul(3600)?,
+        "d" => count.checked_mul(86_400)?,
+        _ => return None,
+    };
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Chaining to another docker-proxy instance as the default upstream
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ChainConfig {
+    /// The default upstream is another docker-proxy (edge -> regional -> origin)
+    pub enabled: bool,
+    /// The upstream proxy's cache signing key; its cache hits must then carry
+    /// a valid signature (empty = not verified)
+    pub upstream_signing_key: String,
+}
+
+impl ChainConfig {
+    /// Validate chain configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.upstream_signing_key.is_empty() {
+            if !self.enabled {
+                return Err("Upstream signing key requires [chain] enabled".to_string());
+            }
+            if self.upstream_signing_key.len() < 16 {
+                return Err("Upstream signing key must be at least 16 bytes".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Content trust (TUF) metadata passthrough
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct TrustConfig {
+    /// Serve `/v2/<gun>/_trust/tuf/...` requests from the trust server
+    pub enabled: bool,
+    /// Notary server the metadata is fetched from
+    pub server: String,
+    /// Metadata files kept in memory, as long as their Cache-Control allows
+    pub cache_entries: usize,
+}
+
+impl Default for TrustConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            server: "https://notary.docker.io".to_string(),
+            cache_entries: 1000,
+        }
+    }
+}
+
+impl TrustConfig {
+    /// Validate trust configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled
+            && !(self.server.starts_with("https://") || self.server.starts_with("http://"))
+        {
+            return Err("Trust server must be an http:// or https:// URL".to_string());
+        }
+        Ok(())
+    }
+
+    /// Trust server URL without a trailing slash
+    pub fn server_url(&self) -> &str {
+        self.server.trim_end_matches('/')
+    }
+}
+
+/// Persisted per-repository pull statistics
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct StatsConfig {
+    /// Record pulls, bytes served and clients per repository and day
+    pub enabled: bool,
+    /// JSON file the statistics are kept in
+    pub file: String,
+    /// Days of statistics kept; older days are dropped when saving
+    pub retention_days: u64,
+    /// How often the statistics are written to disk
+    pub flush_secs: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file: "/app/data/stats.json".to_string(),
+            retention_days: 90,
+            flush_secs: 60,
+        }
+    }
+}
+
+impl StatsConfig {
+    /// Validate stats configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.file.is_empty() {
+            return Err("Stats file cannot be empty".to_string());
+        }
+        if self.retention_days == 0 {
+            return Err("Stats retention must be at least 1 day".to_string());
+        }
+        if self.flush_secs == 0 {
+            return Err("Stats flush interval must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Validation of bearer tokens from an external OpenID Connect provider
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct OidcConfig {
+    /// Issuer URL the tokens' `iss` claim must equal (empty = disabled)
+    pub issuer: String,
+    /// Value the tokens' `aud` claim must contain (empty = not checked)
+    pub audience: String,
+    /// JWKS URL (empty = discovered from the issuer's openid-configuration)
+    pub jwks_url: String,
+    /// How often the signing keys are fetched again
+    pub jwks_refresh_secs: u64,
+    /// Claim naming the user in logs and quotas
+    pub username_claim: String,
+    /// Rules granting access by claim value; a token no rule matches is
+    /// refused
+    pub rules: Vec<OidcRule>,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            audience: String::new(),
+            jwks_url: String::new(),
+            jwks_refresh_secs: 3600,
+            username_claim: "preferred_username".to_string(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl OidcConfig {
+    /// Whether tokens are validated at all
+    pub fn is_enabled(&self) -> bool {
+        !self.issuer.is_empty()
+    }
+
+    /// Validate OIDC configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        for (what, url) in [("issuer", &self.issuer), ("JWKS URL", &self.jwks_url)] {
+            if !url.is_empty() && reqwest::Url::parse(url).is_err() {
+                return Err(format!("Invalid OIDC {}: {}", what, url));
+            }
+        }
+        if self.jwks_refresh_secs == 0 {
+            return Err("OIDC JWKS refresh interval must be greater than 0".to_string());
+        }
+        if self.username_claim.is_empty() {
+            return Err("OIDC username claim cannot be empty".to_string());
+        }
+        if self.rules.is_empty() {
+            return Err("OIDC needs at least one rule granting access".to_string());
+        }
+        if let Some(number) = self
+            .rules
+            .iter()
+            .position(|rule| rule.claim.is_empty() || rule.value.is_empty())
+        {
+            return Err(format!(
+                "OIDC rule {} needs a claim and a value",
+                number + 1
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Access granted to tokens whose claim has a given value
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct OidcRule {
+    /// Claim to look at, e.g. "groups"
+    pub claim: String,
+    /// Value the claim must equal or, for a list claim, contain
+    pub value: String,
+    /// Repositories the rule covers, as a `[policy]` glob
+    pub repositories: String,
+    /// Operations granted on them (empty = all)
+    pub operations: Vec<PolicyOperation>,
+    /// Also grant the `/admin/` endpoints
+    pub admin: bool,
+}
+
+impl Default for OidcRule {
+    fn default() -> Self {
+        Self {
+            claim: String::new(),
+            value: String::new(),
+            repositories: "**".to_string(),
+            operations: Vec::new(),
+            admin: false,
+        }
+    }
+}
+
+/// Pull quotas of authenticated clients
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Count pulls per authenticated user and refuse them past the limits
+    pub enabled: bool,
+    /// JSON file the usage is kept in
+    pub file: String,
+    /// How often the usage is written to disk
+    pub flush_secs: u64,
+    /// Limits of users without an entry in `users`
+    pub default: QuotaLimits,
+    /// Limits by username, replacing the default ones
+    pub users: HashMap<String, QuotaLimits>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file: "/app/data/quotas.json".to_string(),
+            flush_secs: 60,
+            default: QuotaLimits::default(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl QuotaConfig {
+    /// Validate quota configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.file.is_empty() {
+            return Err("Quota file cannot be empty".to_string());
+        }
+        if self.flush_secs == 0 {
+            return Err("Quota flush interval must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Pull limits per UTC day and calendar month (0 = unlimited)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct QuotaLimits {
+    /// Manifest pulls per day
+    pub daily_pulls: u64,
+    /// Manifest pulls per month
+    pub monthly_pulls: u64,
+    /// Blob data served per day, in MiB
+    pub daily_mb: u64,
+    /// Blob data served per month, in MiB
+    pub monthly_mb: u64,
+}
+
+/// What a policy rule does with the requests it matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Kind of registry request a policy rule applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyOperation {
+    /// GET and HEAD
+    Pull,
+    /// Uploads and manifest PUTs
+    Push,
+    Delete,
+}
+
+/// One repository allow/deny rule
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct PolicyRule {
+    pub action: PolicyAction,
+    /// Glob such as "ghcr.io/myorg/*"; `*` stays within a path segment,
+    /// `**` crosses segments
+    pub glob: String,
+    /// Regular expression such as ".*:latest", matched against the whole name
+    pub regex: String,
+    /// Operations the rule applies to (empty = all)
+    pub operations: Vec<PolicyOperation>,
+    /// Explanation included in the error returned for denied requests
+    pub reason: String,
+}
+
+/// Repository allow/deny policy
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// Action for requests no rule matches
+    pub default: PolicyAction,
+    /// Rules checked in order; the first match decides
+    pub rules: Vec<PolicyRule>,
+}
+
+impl PolicyConfig {
+    /// Validate policy configuration
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            policy::Rule::compile(index, rule)?;
+        }
+        Ok(())
+    }
+}
+
+/// Upstream tag watcher configuration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct WatchConfig {
+    /// Repositories to watch, e.g. "library/nginx" or "ghcr.io/owner/repo"
+    pub repositories: Vec<String>,
+    /// How often the watched repositories' tags are listed
+    pub interval_secs: u64,
+    /// Endpoint receiving new-tag events as JSON (empty = log only)
+    pub webhook_url: String,
+    /// Pull new tags into the blob cache as soon as they are seen
+    pub prefetch: bool,
+    /// Platforms pre-fetched from multi-arch images, as "os/arch[/variant]"
+    pub prefetch_platforms: Vec<String>,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            repositories: Vec::new(),
+            interval_secs: 3600,
+            webhook_url: String::new(),
+            prefetch: false,
+            prefetch_platforms: vec!["linux/amd64".to_string()],
+        }
+    }
+}
+
+impl WatchConfig {
+    /// Validate watch configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.repositories.iter().any(|r| r.trim().is_empty()) {
+            return Err("Watched repository names cannot be empty".to_string());
+        }
+        if self.interval_secs < 60 {
+            return Err("Watch interval must be at least 60 seconds".to_string());
+        }
+        if !self.webhook_url.is_empty()
+            && !self.webhook_url.starts_with("http://")
+            && !self.webhook_url.starts_with("https://")
+        {
+            return Err(format!(
+                "Invalid watch webhook URL '{}': must start with http:// or https://",
+                self.webhook_url
+            ));
+        }
+        for platform in &self.prefetch_platforms {
+            let parts = platform.split('/').count();
+            if !(2..=3).contains(&parts) || platform.split('/').any(str::is_empty) {
+                return Err(format!(
+                    "Invalid prefetch platform '{}': expected os/arch[/variant]",
+                    platform
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any repository is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.repositories.is_empty()
+    }
+}
+
+/// How client identifiers appear in logs and records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientIdMode {
+    /// Recorded as received
+    #[default]
+    Plain,
+    /// Replaced by a salted hash that is stable within the rotation window
+    Hashed,
+    /// Not recorded at all
+    Omit,
+}
+
+/// Client identifier privacy configuration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    pub client_ids: ClientIdMode,
+    /// Hashing key; a random key is generated at startup when empty, so
+    /// hashes do not correlate across restarts
+    pub salt: String,
+    /// Hashes of the same client match within a window of this many hours
+    /// (0 = never rotate)
+    pub rotation_hours: u64,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self {
+            client_ids: ClientIdMode::Plain,
+            salt: String::new(),
+            rotation_hours: 24,
+        }
+    }
+}
+
+impl PrivacyConfig {
+    /// Validate privacy configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.client_ids == ClientIdMode::Hashed && !self.salt.is_empty() && self.salt.len() < 16
+        {
+            return Err("Client id salt must be at least 16 characters".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Authentication of incoming registry requests
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ClientAuthConfig {
+    /// Passwords keyed by username, as bcrypt hashes or in plain text
+    pub users: HashMap<String, String>,
+    /// htpasswd file with bcrypt entries, e.g. from `htpasswd -B`
+    pub htpasswd: String,
+    /// How often the htpasswd file is checked for changes (0 = read once)
+    pub htpasswd_reload_secs: u64,
+    /// Realm sent in the Basic challenge, and the service tokens are issued for
+    pub realm: String,
+    /// Issue Bearer tokens at `/token` and send a Bearer challenge pointing
+    /// there, like a registry with a separate token server
+    pub token_service: bool,
+    /// HMAC key signing issued tokens (empty = random per process)
+    pub token_key: String,
+    /// Lifetime of issued tokens
+    pub token_ttl_secs: u64,
+    /// Absolute URL of `/token` sent as the challenge realm (empty = built
+    /// from the request's Host and X-Forwarded-Proto headers)
+    pub token_realm: String,
+    /// Operations allowed without credentials, by repository prefix
+    pub anonymous: Vec<AnonymousRule>,
+    /// Usernames by group name, for use in grants
+    pub groups: HashMap<String, Vec<String>>,
+    /// Repository access of users; when empty every user may do anything
+    pub grants: Vec<ClientGrant>,
+}
+
+/// Repositories some users and groups may access
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ClientGrant {
+    /// Users granted access
+    pub users: Vec<String>,
+    /// Groups granted access
+    pub groups: Vec<String>,
+    /// Repositories covered, as a `[policy]` glob
+    pub repositories: String,
+    /// Operations granted on them (empty = all)
+    pub operations: Vec<PolicyOperation>,
+}
+
+impl Default for ClientGrant {
+    fn default() -> Self {
+        Self {
+            users: Vec::new(),
+            groups: Vec::new(),
+            repositories: "**".to_string(),
+            operations: Vec::new(),
+        }
+    }
+}
+
+/// Operations anyone may perform on repositories under a prefix
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct AnonymousRule {
+    /// Repository name prefix as clients send it, e.g. "public/" (empty =
+    /// every repository); the longest matching prefix applies
+    pub prefix: String,
+    /// Operations allowed anonymously (empty = none)
+    pub operations: Vec<PolicyOperation>,
+}
+
+impl Default for ClientAuthConfig {
+    fn default() -> Self {
+        Self {
+            users: HashMap::new(),
+            htpasswd: String::new(),
+            htpasswd_reload_secs: 5,
+            realm: "docker-proxy".to_string(),
+            token_service: false,
+            token_key: String::new(),
+            token_ttl_secs: 300,
+            token_realm: String::new(),
+            anonymous: Vec::new(),
+            groups: HashMap::new(),
+            grants: Vec::new(),
+        }
+    }
+}
+
+impl ClientAuthConfig {
+    /// Whether `/v2/` requests must come from a known user
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty() || !self.htpasswd.is_empty() || !self.grants.is_empty()
+    }
+
+    /// Validate client authentication configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(user) = self
+            .users
+            .keys()
+            .find(|user| user.is_empty() || user.contains(':'))
+        {
+            return Err(format!("Invalid client auth username: {:?}", user));
+        }
+        if self.realm.is_empty() || self.realm.contains(['"', '\\']) {
+            return Err(format!("Invalid client auth realm: {:?}", self.realm));
+        }
+        if self.token_service {
+            if !self.is_enabled() {
+                return Err("Client auth token service needs users or an htpasswd file".to_string());
+            }
+            if self.token_ttl_secs == 0 || self.token_ttl_secs > 86400 {
+                return Err(format!(
+                    "Client auth token TTL must be between 1 and 86400 seconds: {}",
+                    self.token_ttl_secs
+                ));
+            }
+        }
+        if !self.token_realm.is_empty()
+            && (!(self.token_realm.starts_with("http://")
+                || self.token_realm.starts_with("https://"))
+                || self.token_realm.contains(['"', '\\', ' ']))
+        {
+            return Err(format!(
+                "Client auth token realm must be an absolute URL: {}",
+                self.token_realm
+            ));
+        }
+        for (index, rule) in self.anonymous.iter().enumerate() {
+            if self.anonymous[..index]
+                .iter()
+                .any(|other| other.prefix == rule.prefix)
+            {
+                return Err(format!(
+                    "Duplicate anonymous access prefix: {:?}",
+                    rule.prefix
+                ));
+            }
+        }
+        for (index, grant) in self.grants.iter().enumerate() {
+            if grant.users.is_empty() && grant.groups.is_empty() {
+                return Err(format!(
+                    "Client auth grant {} needs users or groups",
+                    index + 1
+                ));
+            }
+            if let Some(group) = grant
+                .groups
+                .iter()
+                .find(|group| !self.groups.contains_key(*group))
+            {
+                return Err(format!(
+                    "Client auth grant {} names an unknown group: {}",
+                    index + 1,
+                    group
+                ));
+            }
+        }
         Ok(())
     }
 }
 
 /// Authentication configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct AuthConfig {
-    #[serde(rename = "ghcr-token")]
+    /// Personal access token used for ghcr.io when no credentials are set
+    // the alias is what a DOCKER_PROXY__AUTH__GHCR_TOKEN override sets
+    // without `ghcr-token` in the file
+    #[serde(rename = "ghcr-token", alias = "ghcr_token", default)]
     pub ghcr_token: String,
+    /// Docker Hub account (password or personal access token) used for
+    /// pulls (and pushes with `write_with_credentials`), so pulls count against the account's rate limit
+    /// instead of the anonymous per-IP one
+    #[serde(default)]
+    pub dockerhub: Option<RegistryCredentials>,
+    /// Credentials used when requesting registry tokens, keyed by registry
+    /// host, or by "host/namespace" for the repositories under a namespace
+    #[serde(default)]
+    pub credentials: HashMap<String, RegistryCredentials>,
+    /// Docker CLI `config.json` whose `auths` are used for registries without
+    /// credentials above
+    #[serde(default)]
+    pub docker_config: String,
+    /// Credentials read from `docker_config`
+    #[serde(skip)]
+    docker_credentials: HashMap<String, RegistryCredentials>,
+    /// Credential helpers for registries without credentials above, keyed by
+    /// registry host: the name of a `docker-credential-<name>` program on the
+    /// PATH, or a path to the program
+    #[serde(default)]
+    pub credential_helpers: HashMap<String, String>,
+    /// Credential helpers named in `docker_config`
+    #[serde(skip)]
+    docker_helpers: HashMap<String, String>,
+    /// Also use the credentials above for pushes and deletes; otherwise
+    /// writes carry only the client's own Authorization, so anonymous
+    /// clients cannot write with the operator's account
+    #[serde(default)]
+    pub write_with_credentials: bool,
+}
+
+/// Username and password (or access token) for an upstream registry
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RegistryCredentials {
+    pub username: String,
+    /// Password or access token
+    pub password: String,
+}
+
+impl AuthConfig {
+    /// Validate authentication configuration
+    pub fn validate(&self) -> Result<(), String> {
+        for (key, credentials) in &self.credentials {
+            let (host, namespace) = split_namespace(key);
+            if host.is_empty() || namespace.is_some_and(str::is_empty) {
+                return Err(format!("Invalid credentials registry host: {:?}", key));
+            }
+            if credentials.username.is_empty() {
+                return Err(format!("Credentials for {} need a username", key));
+            }
+        }
+        if let Some(dockerhub) = &self.dockerhub
+            && (dockerhub.username.is_empty() || dockerhub.password.is_empty())
+        {
+            return Err("Docker Hub credentials need a username and password".to_string());
+        }
+        for (host, helper) in &self.credential_helpers {
+            if host.is_empty() || host.contains('/') {
+                return Err(format!(
+                    "Invalid credential helper registry host: {:?}",
+                    host
+                ));
+            }
+            if helper.is_empty() {
+                return Err(format!("Credential helper for {} is empty", host));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the credentials and helpers of `docker_config`, if set
+    pub fn load_docker_config(&mut self) -> Result<(), String> {
+        if !self.docker_config.is_empty() {
+            let docker_config = docker_config::load(&self.docker_config)?;
+            self.docker_credentials = docker_config.credentials;
+            self.docker_helpers = docker_config.helpers;
+        }
+        Ok(())
+    }
+
+    /// Credentials for a repository of a registry host: those of the longest
+    /// namespace containing it, else the registry's
+    pub fn credentials_for_repository(
+        &self,
+        host: &str,
+        repository: &str,
+    ) -> Option<RegistryCredentials> {
+        match self.credential_namespace(host, repository) {
+            Some(key) => self.credentials.get(key).cloned(),
+            None => self.credentials_for(host),
+        }
+    }
+
+    /// Key of the namespaced credentials used for a repository, if any
+    pub fn credential_namespace(&self, host: &str, repository: &str) -> Option<&str> {
+        let is_docker_hub =
+            |host: &str| host == router::DOCKER_HUB_HOST || router::is_docker_hub_alias(host);
+        self.credentials
+            .keys()
+            .filter_map(|key| {
+                let (key_host, namespace) = split_namespace(key);
+                let namespace = namespace?;
+                let same_host =
+                    key_host == host || (is_docker_hub(key_host) && is_docker_hub(host));
+                let contains = repository
+                    .strip_prefix(namespace)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+                (same_host && contains).then_some((key.as_str(), namespace.len()))
+            })
+            .max_by_key(|(_, len)| *len)
+            .map(|(key, _)| key)
+    }
+
+    /// Credential helper for a registry host, from this section or else the
+    /// Docker config; Docker Hub's may be keyed by `docker.io`
+    pub fn credential_helper(&self, host: &str) -> Option<&str> {
+        let is_docker_hub = host == router::DOCKER_HUB_HOST || router::is_docker_hub_alias(host);
+        self.credential_helpers
+            .get(host)
+            .or_else(|| {
+                is_docker_hub
+                    .then(|| self.credential_helpers.get("docker.io"))
+                    .flatten()
+            })
+            .or_else(|| {
+                self.docker_helpers.get(if is_docker_hub {
+                    router::DOCKER_HUB_HOST
+                } else {
+                    host
+                })
+            })
+            .map(String::as_str)
+    }
+
+    /// Credentials for a registry host, from this section or else the Docker
+    /// config. Docker Hub falls back to credentials under `docker.io` and then
+    /// `[auth.dockerhub]`, ghcr.io to `ghcr-token`.
+    pub fn credentials_for(&self, host: &str) -> Option<RegistryCredentials> {
+        if let Some(credentials) = self
+            .credentials
+            .get(host)
+            .or_else(|| self.docker_credentials.get(host))
+        {
+            return Some(credentials.clone());
+        }
+        if host == router::DOCKER_HUB_HOST || router::is_docker_hub_alias(host) {
+            return self
+                .credentials
+                .get("docker.io")
+                .or_else(|| self.docker_credentials.get(router::DOCKER_HUB_HOST))
+                .or(self.dockerhub.as_ref())
+                .cloned();
+        }
+        (host == "ghcr.io" && !self.ghcr_token.is_empty()).then(|| RegistryCredentials {
+            username: "token".to_string(),
+            password: self.ghcr_token.clone(),
+        })
+    }
+}
+
+// "ghcr.io/org-a/*" -> ("ghcr.io", Some("org-a")), "ghcr.io" -> ("ghcr.io", None)
+fn split_namespace(key: &str) -> (&str, Option<&str>) {
+    match key.split_once('/') {
+        Some((host, namespace)) => (
+            host,
+            Some(namespace.trim_end_matches("/*").trim_end_matches('/')),
+        ),
+        None => (key, None),
+    }
+}
+
+/// Maintenance mode configuration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct MaintenanceConfig {
+    /// Daily maintenance windows in UTC, as "HH:MM-HH:MM"
+    pub windows: Vec<String>,
+    /// Reason reported to clients during scheduled windows
+    pub reason: String,
+    /// Retry-After sent when maintenance has no known end
+    pub retry_after_secs: u64,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            windows: Vec::new(),
+            reason: "Scheduled maintenance".to_string(),
+            retry_after_secs: 300,
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    /// Validate maintenance configuration
+    pub fn validate(&self) -> Result<(), String> {
+        for window in &self.windows {
+            DailyWindow::parse(window)?;
+        }
+        if self.retry_after_secs == 0 {
+            return Err("Maintenance retry_after_secs must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Refusing low-priority requests under resource pressure
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct LoadSheddingConfig {
+    /// Resident memory, in MiB, past which catalog and tag listings are
+    /// refused; keep it below the container's memory limit (0 = not checked)
+    pub max_memory_mb: u64,
+    /// Open file descriptors past which listings are refused (0 = not
+    /// checked)
+    pub max_open_files: u64,
+    /// Requests being handled past which listings are refused (0 = not
+    /// checked)
+    pub max_in_flight: usize,
+    /// How often memory and file descriptors are sampled, in milliseconds
+    pub sample_interval_ms: u64,
+    /// Retry-After sent with refused requests
+    pub retry_after_secs: u64,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        Self {
+            max_memory_mb: 0,
+            max_open_files: 0,
+            max_in_flight: 0,
+            sample_interval_ms: 1000,
+            retry_after_secs: 10,
+        }
+    }
+}
+
+impl LoadSheddingConfig {
+    /// Whether any threshold is set
+    pub fn is_enabled(&self) -> bool {
+        self.max_memory_mb > 0 || self.max_open_files > 0 || self.max_in_flight > 0
+    }
+
+    /// Validate load shedding configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sample_interval_ms == 0 {
+            return Err("Load shedding sample_interval_ms must be greater than 0".to_string());
+        }
+        if self.retry_after_secs == 0 {
+            return Err("Load shedding retry_after_secs must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Protection of the `/admin/`, `/debug/` and `/api/` endpoints
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Keys accepted as `Authorization: Bearer <key>`, in plain text or as
+    /// "sha256:<hex>" of the key (empty = endpoints are open)
+    pub api_keys: Vec<String>,
+}
+
+impl AdminConfig {
+    /// Validate admin settings
+    pub fn validate(&self) -> Result<(), String> {
+        for key in &self.api_keys {
+            match key.strip_prefix("sha256:") {
+                Some(hex) if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    return Err(format!("Invalid admin API key hash: {}", key));
+                }
+                None if key.trim().is_empty() => {
+                    return Err("Admin API keys cannot be empty".to_string());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Export of request traces over OTLP
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Export request traces over OTLP
+    pub enabled: bool,
+    /// OTLP/HTTP collector URL; "/v1/traces" is appended when it has no path
+    pub endpoint: String,
+    /// Extra headers sent to the collector, e.g. for authentication
+    pub headers: HashMap<String, String>,
+    /// `service.name` of the exported spans
+    pub service_name: String,
+    /// Fraction of new traces recorded; requests continuing a client's trace
+    /// follow the client's sampling decision
+    pub sample_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "http://localhost:4318".to_string(),
+            headers: HashMap::new(),
+            service_name: "docker-proxy".to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+impl TelemetryConfig {
+    /// Validate telemetry settings
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !(self.endpoint.starts_with("http://") || self.endpoint.starts_with("https://")) {
+            return Err(format!("Invalid telemetry endpoint: {}", self.endpoint));
+        }
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            return Err("Telemetry sample_ratio must be between 0 and 1".to_string());
+        }
+        if self.service_name.is_empty() {
+            return Err("Telemetry service_name cannot be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Reporting of server errors and panics to Sentry
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct SentryConfig {
+    /// Project DSN events are sent to (empty = disabled)
+    pub dsn: String,
+    /// Environment the events are tagged with, e.g. "production"
+    pub environment: String,
+    /// Fraction of events sent
+    pub sample_rate: f32,
+}
+
+impl Default for SentryConfig {
+    fn default() -> Self {
+        Self {
+            dsn: String::new(),
+            environment: String::new(),
+            sample_rate: 1.0,
+        }
+    }
+}
+
+impl SentryConfig {
+    /// Whether a DSN is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.dsn.is_empty()
+    }
+
+    /// Validate Sentry settings
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        self.dsn
+            .parse::<sentry::types::Dsn>()
+            .map_err(|e| format!("Invalid Sentry DSN: {}", e))?;
+        if !(0.0..=1.0).contains(&self.sample_rate) {
+            return Err("Sentry sample_rate must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Shadow evaluation of a candidate configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ShadowConfig {
+    /// Candidate config file evaluated alongside this one (empty = disabled)
+    pub candidate: String,
+    /// Stop evaluating after this many hours (0 = until restart)
+    pub duration_hours: u64,
+}
+
+impl ShadowConfig {
+    /// Whether a candidate config is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.candidate.is_empty()
+    }
 }
 
 /// Root configuration structure
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct Config {
+    /// Deployment preset; unset applies neither the dev defaults nor the
+    /// prod checks
+    #[serde(default)]
+    pub profile: Option<Profile>,
+    #[serde(default)]
     pub server: ServerConfig,
+    #[serde(default)]
     pub log: LogConfig,
+    #[serde(default)]
     pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub client: ClientConfig,
+    #[serde(default)]
+    pub resolve: ResolveConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub watch: WatchConfig,
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    #[serde(default)]
+    pub load_shedding: LoadSheddingConfig,
+    #[serde(default)]
+    pub shadow: ShadowConfig,
+    #[serde(default)]
+    pub chain: ChainConfig,
+    #[serde(default)]
+    pub trust: TrustConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    #[serde(default)]
+    pub client_auth: ClientAuthConfig,
+    #[serde(default)]
+    pub oidc: OidcConfig,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub sentry: SentryConfig,
 }
 
 impl Config {
-    /// Load configuration from a TOML file
+    /// Load configuration from a TOML file, overridden by `DOCKER_PROXY__*`
+    /// environment variables
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_file_with_overrides(path, Vec::new())
+    }
+
+    /// Load configuration from a TOML file and the fragments in its `.d`
+    /// directory, overridden by `DOCKER_PROXY__*` environment variables and
+    /// then by `overrides`, named the same way
+    pub fn from_file_with_overrides<P: AsRef<Path>>(
+        path: P,
+        overrides: Vec<(String, String)>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let path = path.as_ref();
         if !path.exists() {
             return Err(format!("Configuration file not found: {:?}", path).into());
         }
         let content = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
-        config.validate()?;
-        Ok(config)
+        let vars = std::env::vars().chain(overrides);
+        let fragments = config_fragments::load(&config_fragments::dir_for(path))?;
+        if fragments.is_empty() {
+            return Self::from_str_with_env(&content, vars);
+        }
+        let mut table: toml::Table = toml::from_str(&content)?;
+        for (_, fragment) in fragments {
+            config_fragments::merge(&mut table, fragment);
+        }
+        Self::prepare(&mut table, vars)?;
+        Self::from_config(table.try_into()?)
     }
 
     /// Load configuration from a string
-    #[allow(dead_code)]
     pub fn from_str(content: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let config: Config = toml::from_str(content)?;
+        Self::from_config(toml::from_str(content)?)
+    }
+
+    /// Load configuration from a string, overridden by the
+    /// `DOCKER_PROXY__*` variables among `vars`
+    pub fn from_str_with_env(
+        content: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut table: toml::Table = toml::from_str(content)?;
+        if !Self::prepare(&mut table, vars)? {
+            return Self::from_str(content);
+        }
+        Self::from_config(table.try_into()?)
+    }
+
+    // Apply environment overrides and read secrets, returning whether the
+    // table changed
+    fn prepare(
+        table: &mut toml::Table,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<bool, String> {
+        let overridden = !env_overrides::apply(table, vars)?.is_empty();
+        Ok(secrets::resolve(table)? || overridden)
+    }
+
+    fn from_config(mut config: Config) -> Result<Self, Box<dyn std::error::Error>> {
+        config.apply_profile();
         config.validate()?;
+        config.auth.load_docker_config()?;
         Ok(config)
     }
 
+    // Fill in the dev profile's defaults for options left unset; state goes to
+    // a directory of this process so every run starts from an empty cache
+    fn apply_profile(&mut self) {
+        if self.profile != Some(Profile::Dev) {
+            return;
+        }
+        if self.cache.dir == CacheConfig::default().dir {
+            self.cache.dir = dev_scratch_dir()
+                .join("cache")
+                .to_string_lossy()
+                .into_owned();
+        }
+        if self.stats.file == StatsConfig::default().file {
+            self.stats.file = dev_scratch_dir()
+                .join("stats.json")
+                .to_string_lossy()
+                .into_owned();
+        }
+        if self.quotas.file == QuotaConfig::default().file {
+            self.quotas.file = dev_scratch_dir()
+                .join("quotas.json")
+                .to_string_lossy()
+                .into_owned();
+        }
+    }
+
     /// Validate the entire configuration
     pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
         self.server.validate()?;
         self.log.validate()?;
         self.proxy.validate()?;
+        self.client.validate()?;
+        self.resolve.validate()?;
+        self.auth.validate()?;
+        self.cache.validate()?;
+        self.watch.validate()?;
+        self.privacy.validate()?;
+        self.maintenance.validate()?;
+        self.load_shedding.validate()?;
+        self.chain.validate()?;
+        self.trust.validate()?;
+        self.stats.validate()?;
+        self.policy.validate()?;
+        self.client_auth.validate()?;
+        self.oidc.validate()?;
+        if !self.client_auth.anonymous.is_empty()
+            && !self.client_auth.is_enabled()
+            && !self.oidc.is_enabled()
+        {
+            return Err("Anonymous access rules need [client_auth] users or [oidc]".into());
+        }
+        if self.auth.write_with_credentials {
+            if !self.client_auth.is_enabled() && !self.oidc.is_enabled() {
+                return Err(
+                    "auth.write_with_credentials needs [client_auth] users or [oidc]".into(),
+                );
+            }
+            if self.client_auth.anonymous.iter().any(|rule| {
+                rule.operations
+                    .iter()
+                    .any(|op| *op != PolicyOperation::Pull)
+            }) {
+                return Err(
+                    "auth.write_with_credentials cannot be used with anonymous push or delete"
+                        .into(),
+                );
+            }
+        }
+        if !self.client_auth.grants.is_empty()
+            && self.client_auth.users.is_empty()
+            && self.client_auth.htpasswd.is_empty()
+            && !self.server.tls.verifies_clients()
+        {
+            return Err("Client auth grants need users or client certificates".into());
+        }
+        self.quotas.validate()?;
+        if self.quotas.enabled && !self.client_auth.is_enabled() && !self.oidc.is_enabled() {
+            return Err("Pull quotas need [client_auth] users or [oidc] to count pulls for".into());
+        }
+        self.admin.validate()?;
+        self.telemetry.validate()?;
+        self.sentry.validate()?;
+        if self.proxy.push_mode == PushMode::Local && !self.cache.enabled {
+            return Err("Local push mode requires the blob cache to be enabled".into());
+        }
+        if self.watch.prefetch && !self.cache.enabled {
+            return Err("Watch prefetch requires the blob cache to be enabled".into());
+        }
+        if self.profile == Some(Profile::Prod) {
+            self.validate_prod()?;
+        }
+        Ok(())
+    }
+
+    // The prod profile rules out setups that only work from a source checkout
+    fn validate_prod(&self) -> Result<(), String> {
+        let mut paths = vec![
+            ("Log file path", self.log.log_file_path.as_str()),
+            ("Access log path", self.log.access_log_path.as_str()),
+            ("Web root", self.server.web_root.as_str()),
+            ("Spill directory", self.proxy.spill_dir.as_str()),
+            (
+                "Client auth htpasswd file",
+                self.client_auth.htpasswd.as_str(),
+            ),
+            ("Server TLS certificate", self.server.tls.cert_file.as_str()),
+            ("Server TLS key", self.server.tls.key_file.as_str()),
+            (
+                "Server TLS client CA",
+                self.server.tls.client_ca_file.as_str(),
+            ),
+        ];
+        if self.cache.enabled {
+            paths.push(("Cache directory", self.cache.dir.as_str()));
+        }
+        if self.stats.enabled {
+            paths.push(("Stats file", self.stats.file.as_str()));
+        }
+        if self.quotas.enabled {
+            paths.push(("Quota file", self.quotas.file.as_str()));
+        }
+        for (what, path) in paths {
+            if !path.is_empty() && !Path::new(path).is_absolute() {
+                return Err(format!(
+                    "{} must be absolute in the prod profile: {}",
+                    what, path
+                ));
+            }
+        }
+        if self.default_registry().starts_with("http://") {
+            return Err("The prod profile requires an HTTPS default registry".to_string());
+        }
         Ok(())
     }
 
+    /// Directory holding the dev profile's cache, statistics and quota usage,
+    /// removed when the process stops; None outside the dev profile
+    pub fn scratch_dir(&self) -> Option<PathBuf> {
+        (self.profile == Some(Profile::Dev)).then(dev_scratch_dir)
+    }
+
+    /// Whether logs go to the console instead of a log file: in the dev
+    /// profile or without `logFilePath`
+    pub fn logs_to_console(&self) -> bool {
+        self.profile == Some(Profile::Dev) || self.log.log_file_path.is_empty()
+    }
+
+    /// Directory the web UI is served from
+    pub fn web_root(&self) -> PathBuf {
+        match (self.server.web_root.as_str(), self.profile) {
+            ("", Some(Profile::Dev)) => PathBuf::from("./web"),
+            ("", _) => PathBuf::from("/app/web"),
+            (root, _) => PathBuf::from(root),
+        }
+    }
+
     /// Get the server address as a string
     pub fn server_addr(&self) -> String {
         self.server.socket_addr()
@@ -128,6 +2067,22 @@ impl Config {
         &self.proxy.default
     }
 
+    /// The default registry as a URL, https unless a scheme is given, the
+    /// registry is configured as insecure or, in the dev profile, it runs on
+    /// localhost
+    pub fn default_registry_url(&self) -> String {
+        let registry = self.default_registry();
+        if registry.starts_with("http://") || registry.starts_with("https://") {
+            return registry.to_string();
+        }
+        let host = registry.split(':').next().unwrap_or_default();
+        if self.profile == Some(Profile::Dev) && matches!(host, "localhost" | "127.0.0.1") {
+            format!("http://{}", registry)
+        } else {
+            registry_host_url(&self.proxy.registries, registry)
+        }
+    }
+
     /// Get the logging level
     pub fn log_level(&self) -> &str {
         &self.log.level
@@ -143,16 +2098,31 @@ impl Config {
         &self.log.log_file_path
     }
 
-    // ghcr_token is no longer used because proxy does not handle auth
-
     /// Convert to a display string with masked sensitive data
     pub fn to_display_string(&self) -> String {
+        let cache = if self.cache.enabled {
+            format!("{} (max {} MiB)", self.cache.dir, self.cache.max_size_mb)
+        } else {
+            "disabled".to_string()
+        };
+        let profile = match self.profile {
+            Some(Profile::Dev) => "dev",
+            Some(Profile::Prod) => "prod",
+            None => "none",
+        };
+        let log_path = if self.logs_to_console() {
+            "console"
+        } else {
+            self.log_file_path()
+        };
         format!(
-            "Server: {} | Log Level: {} | Log Path: {} | Default Registry: {}",
+            "Profile: {} | Server: {} | Log Level: {} | Log Path: {} | Default Registry: {} | Cache: {}",
+            profile,
             self.server_addr(),
             self.log_level(),
-            self.log_file_path(),
-            self.default_registry()
+            log_path,
+            self.default_registry(),
+            cache
         )
     }
 }
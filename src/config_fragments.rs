@@ -0,0 +1,138 @@
+/// Configuration fragments merged over the base file
+///
+/// Credentials are best mounted as their own secret files, apart from the
+/// registry and policy configuration. The `*.toml` files in the directory
+/// named after the config file with a `.d` extension (`config.d/` next to
+/// `config.toml`) are merged over it in file name order: tables are merged
+/// key by key, any other value (arrays included) replaces the one before.
+/// Hidden files are skipped, such as the `..data` links of Kubernetes secret
+/// volumes.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml::{Table, Value};
+
+/// Fragment directory of the config file at `path`
+pub fn dir_for(path: &Path) -> PathBuf {
+    path.with_extension("d")
+}
+
+/// Parsed fragments in `dir` in merge order; none when `dir` does not exist
+pub fn load(dir: &Path) -> Result<Vec<(PathBuf, Table)>, String> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension().is_some_and(|ext| ext == "toml")
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| !name.starts_with('.'))
+                && path.is_file()
+        })
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let fragment = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| content.parse::<Table>().map_err(|e| e.to_string()))
+                .map_err(|e| format!("Invalid config fragment {}: {}", path.display(), e))?;
+            Ok((path, fragment))
+        })
+        .collect()
+}
+
+/// Merge `fragment` over `base`
+pub fn merge(base: &mut Table, fragment: Table) {
+    for (key, value) in fragment {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(nested)) => merge(existing, nested),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut base: Table = r#"
+[server]
+host = "0.0.0.0"
+port = 8080
+
+[proxy]
+default = "registry-1.docker.io"
+mirrors = { "registry-1.docker.io" = ["https://mirror.gcr.io"] }
+"#
+        .parse()
+        .unwrap();
+        let fragment: Table = r#"
+[server]
+port = 9090
+
+[proxy.mirrors]
+"registry-1.docker.io" = ["https://mirror.example.com"]
+
+[auth.credentials."ghcr.io"]
+username = "bot"
+"#
+        .parse()
+        .unwrap();
+        merge(&mut base, fragment);
+        assert_eq!(base["server"]["host"].as_str(), Some("0.0.0.0"));
+        assert_eq!(base["server"]["port"].as_integer(), Some(9090));
+        assert_eq!(
+            base["proxy"]["default"].as_str(),
+            Some("registry-1.docker.io")
+        );
+        // Arrays are replaced, not appended to
+        assert_eq!(
+            base["proxy"]["mirrors"]["registry-1.docker.io"],
+            Value::Array(vec![Value::String(
+                "https://mirror.example.com".to_string()
+            )])
+        );
+        assert_eq!(
+            base["auth"]["credentials"]["ghcr.io"]["username"].as_str(),
+            Some("bot")
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let dir =
+            std::env::temp_dir().join(format!("docker-proxy-config-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("20-late.toml"), "[log]\nlevel = \"warn\"\n").unwrap();
+        fs::write(dir.join("10-early.toml"), "[log]\nlevel = \"debug\"\n").unwrap();
+        fs::write(dir.join(".hidden.toml"), "not toml").unwrap();
+        fs::write(dir.join("README"), "not toml").unwrap();
+
+        let fragments = load(&dir).unwrap();
+        let names: Vec<_> = fragments
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["10-early.toml", "20-late.toml"]);
+
+        fs::write(dir.join("30-broken.toml"), "[log\n").unwrap();
+        assert!(load(&dir).unwrap_err().contains("30-broken.toml"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(load(&dir).unwrap().is_empty());
+        assert_eq!(
+            dir_for(Path::new("/config/config.toml")),
+            Path::new("/config/config.d")
+        );
+    }
+}
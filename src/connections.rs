@@ -0,0 +1,376 @@
+/// Upstream connection limits
+///
+/// The HTTP client opens a new connection whenever a request finds no idle
+/// one, so a burst of pulls can open hundreds of connections to a registry
+/// and exhaust file descriptors on a small VM. `[client]
+/// max_connections_per_host` caps the requests in flight per registry host;
+/// an HTTP/1.1 connection carries one request at a time and idle ones are
+/// reused before new ones are opened, so this caps the connections too. A
+/// request keeps its connection until its response body is read or dropped.
+/// Across all registries, `max_manifest_fetches` and `max_blob_fetches` cap
+/// the manifest and blob downloads in flight, so thousands of nodes pulling
+/// at once queue up (in arrival order) instead of tripping a registry's
+/// abuse protection. Connections in use per host and fetches by kind are
+/// exported at `/metrics`.
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+use reqwest::ResponseBuilderExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::ClientConfig;
+use crate::egress::label_value;
+
+/// Connections to one registry host
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostConnections {
+    /// Requests holding a connection
+    pub open: usize,
+    /// Requests waiting for one under the limit
+    pub waiting: usize,
+}
+
+/// What an upstream fetch downloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchKind {
+    Manifest,
+    Blob,
+}
+
+impl FetchKind {
+    /// Name used in metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            FetchKind::Manifest => "manifest",
+            FetchKind::Blob => "blob",
+        }
+    }
+}
+
+// Requests in flight under an optional limit
+#[derive(Default)]
+struct Slots {
+    /// None without a limit
+    semaphore: Option<Arc<Semaphore>>,
+    open: AtomicUsize,
+    waiting: AtomicUsize,
+}
+
+impl Slots {
+    fn new(limit: usize) -> Self {
+        Self {
+            semaphore: (limit > 0).then(|| Arc::new(Semaphore::new(limit))),
+            ..Self::default()
+        }
+    }
+
+    // Wait for a free slot; waiting requests are served in arrival order
+    async fn acquire(self: Arc<Self>, what: &str) -> ConnectionSlot {
+        let permit = match &self.semaphore {
+            Some(semaphore) => {
+                let permit = match Arc::clone(semaphore).try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        tracing::debug!("Waiting for {}", what);
+                        self.waiting.fetch_add(1, Ordering::Relaxed);
+                        let _waiting = Waiting(&self.waiting);
+                        Arc::clone(semaphore)
+                            .acquire_owned()
+                            .await
+                            .expect("connection semaphore is never closed")
+                    }
+                };
+                Some(permit)
+            }
+            None => None,
+        };
+        self.open.fetch_add(1, Ordering::Relaxed);
+        ConnectionSlot {
+            slots: self,
+            _permit: permit,
+        }
+    }
+
+    fn snapshot(&self) -> HostConnections {
+        HostConnections {
+            open: self.open.load(Ordering::Relaxed),
+            waiting: self.waiting.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct ConnectionLimits {
+    /// 0 means no limit
+    max_per_host: usize,
+    hosts: Mutex<BTreeMap<String, Arc<Slots>>>,
+    manifests: Arc<Slots>,
+    blobs: Arc<Slots>,
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self {
+            max_per_host: config.max_connections_per_host,
+            hosts: Mutex::new(BTreeMap::new()),
+            manifests: Arc::new(Slots::new(config.max_manifest_fetches)),
+            blobs: Arc::new(Slots::new(config.max_blob_fetches)),
+        }
+    }
+
+    /// Wait until a connection to `host` may be used
+    pub async fn acquire(&self, host: &str) -> ConnectionSlot {
+        let slots = {
+            let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+            let slots = hosts
+                .entry(host.to_string())
+                .or_insert_with(|| Arc::new(Slots::new(self.max_per_host)));
+            Arc::clone(slots)
+        };
+        slots
+            .acquire(&format!("an upstream connection to {}", host))
+            .await
+    }
+
+    /// Wait until a fetch of `kind` may start; hold the slot with its
+    /// response (`hold`) to count the download of the body
+    pub async fn acquire_fetch(&self, kind: FetchKind) -> ConnectionSlot {
+        let slots = match kind {
+            FetchKind::Manifest => &self.manifests,
+            FetchKind::Blob => &self.blobs,
+        };
+        Arc::clone(slots)
+            .acquire(&format!("an upstream {} fetch", kind.label()))
+            .await
+    }
+
+    /// Fetches in flight and waiting, by kind
+    pub fn fetches(&self) -> [(FetchKind, HostConnections); 2] {
+        [
+            (FetchKind::Manifest, self.manifests.snapshot()),
+            (FetchKind::Blob, self.blobs.snapshot()),
+        ]
+    }
+
+    /// Connections by registry host
+    pub fn snapshot(&self) -> BTreeMap<String, HostConnections> {
+        self.hosts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(host, slots)| (host.clone(), slots.snapshot()))
+            .collect()
+    }
+}
+
+// Counts a waiting request, including one whose wait is cancelled
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The right to use a connection to a host, or to fetch, given back on drop
+pub struct ConnectionSlot {
+    slots: Arc<Slots>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.slots.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// `response` holding `slot` until its body is read or dropped
+pub fn hold(response: reqwest::Response, slot: ConnectionSlot) -> reqwest::Response {
+    let url = response.url().clone();
+    let (mut parts, body) = axum::http::Response::<reqwest::Body>::from(response).into_parts();
+    // reqwest keeps the response URL in an extension only its builder can set
+    if let Ok(with_url) = axum::http::Response::builder().url(url).body(()) {
+        parts.extensions.extend(with_url.into_parts().0.extensions);
+    }
+    let body = reqwest::Body::wrap(SlotBody { body, _slot: slot });
+    reqwest::Response::from(axum::http::Response::from_parts(parts, body))
+}
+
+struct SlotBody {
+    body: reqwest::Body,
+    _slot: ConnectionSlot,
+}
+
+impl HttpBody for SlotBody {
+    type Data = Bytes;
+    type Error = reqwest::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, reqwest::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Render connection and fetch gauges in the Prometheus text exposition format
+pub fn to_prometheus(
+    hosts: &BTreeMap<String, HostConnections>,
+    fetches: &[(FetchKind, HostConnections)],
+) -> String {
+    let mut metrics = String::new();
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_open_connections Upstream connections carrying a request, idle pooled ones excluded\n\
+         # TYPE docker_proxy_upstream_open_connections gauge\n",
+    );
+    for (host, connections) in hosts {
+        metrics.push_str(&format!(
+            "docker_proxy_upstream_open_connections{{registry=\"{}\"}} {}\n",
+            label_value(host),
+            connections.open
+        ));
+    }
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_connection_waits Upstream requests waiting for a connection under max_connections_per_host\n\
+         # TYPE docker_proxy_upstream_connection_waits gauge\n",
+    );
+    for (host, connections) in hosts {
+        metrics.push_str(&format!(
+            "docker_proxy_upstream_connection_waits{{registry=\"{}\"}} {}\n",
+            label_value(host),
+            connections.waiting
+        ));
+    }
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_fetches Upstream manifest and blob fetches in flight\n\
+         # TYPE docker_proxy_upstream_fetches gauge\n",
+    );
+    for (kind, fetches) in fetches {
+        metrics.push_str(&format!(
+            "docker_proxy_upstream_fetches{{kind=\"{}\"}} {}\n",
+            kind.label(),
+            fetches.open
+        ));
+    }
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_fetch_waits Upstream fetches queued under max_manifest_fetches or max_blob_fetches\n\
+         # TYPE docker_proxy_upstream_fetch_waits gauge\n",
+    );
+    for (kind, fetches) in fetches {
+        metrics.push_str(&format!(
+            "docker_proxy_upstream_fetch_waits{{kind=\"{}\"}} {}\n",
+            kind.label(),
+            fetches.waiting
+        ));
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        let limits = Arc::new(ConnectionLimits::new(&ClientConfig {
+            max_connections_per_host: 1,
+            ..ClientConfig::default()
+        }));
+        let first = limits.acquire("ghcr.io").await;
+        let other_host = limits.acquire("quay.io").await;
+
+        let waiter = tokio::spawn({
+            let limits = Arc::clone(&limits);
+            async move { limits.acquire("ghcr.io").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            limits.snapshot()["ghcr.io"],
+            HostConnections {
+                open: 1,
+                waiting: 1
+            }
+        );
+        assert!(!waiter.is_finished());
+
+        // Released with the response body
+        let response = reqwest::Response::from(
+            axum::http::Response::builder()
+                .url("https://ghcr.io/v2/".parse().unwrap())
+                .body("body")
+                .unwrap(),
+        );
+        let response = hold(response, first);
+        assert_eq!(response.url().as_str(), "https://ghcr.io/v2/");
+        assert_eq!(response.content_length(), Some(4));
+        assert_eq!(&response.bytes().await.unwrap()[..], b"body");
+        let second = waiter.await.unwrap();
+        assert_eq!(limits.snapshot()["ghcr.io"].waiting, 0);
+
+        drop((second, other_host));
+        let rendered = to_prometheus(&limits.snapshot(), &limits.fetches());
+        assert!(
+            rendered.contains("docker_proxy_upstream_open_connections{registry=\"ghcr.io\"} 0")
+        );
+        assert!(
+            rendered.contains("docker_proxy_upstream_connection_waits{registry=\"quay.io\"} 0")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_limits() {
+        let limits = Arc::new(ConnectionLimits::new(&ClientConfig {
+            max_blob_fetches: 1,
+            ..ClientConfig::default()
+        }));
+        let blob = limits.acquire_fetch(FetchKind::Blob).await;
+        // Manifests have their own limit, here none
+        let _manifests = (
+            limits.acquire_fetch(FetchKind::Manifest).await,
+            limits.acquire_fetch(FetchKind::Manifest).await,
+        );
+        let waiter = tokio::spawn({
+            let limits = Arc::clone(&limits);
+            async move { limits.acquire_fetch(FetchKind::Blob).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            limits.fetches(),
+            [
+                (
+                    FetchKind::Manifest,
+                    HostConnections {
+                        open: 2,
+                        waiting: 0
+                    }
+                ),
+                (
+                    FetchKind::Blob,
+                    HostConnections {
+                        open: 1,
+                        waiting: 1
+                    }
+                ),
+            ]
+        );
+        drop(blob);
+        let _blob = waiter.await.unwrap();
+        let rendered = to_prometheus(&limits.snapshot(), &limits.fetches());
+        assert!(rendered.contains("docker_proxy_upstream_fetches{kind=\"blob\"} 1\n"));
+        assert!(rendered.contains("docker_proxy_upstream_fetch_waits{kind=\"blob\"} 0\n"));
+    }
+}
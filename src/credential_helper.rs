@@ -0,0 +1,211 @@
+/// Registry credentials from Docker credential helper programs
+///
+/// A helper is run as `docker-credential-<name> get` (or by its path) with
+/// the registry's server URL on stdin, and answers with a JSON object
+/// holding `Username` and `Secret`, the protocol the Docker CLI uses. Its
+/// answer is kept for a few minutes, as helpers are often slow and the
+/// proxy asks for credentials whenever it needs a new registry token.
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::clock::Clock;
+use crate::config::RegistryCredentials;
+use crate::router;
+
+/// How long a helper's answer is reused
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+/// How long a helper may take to answer
+const HELPER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct CredentialHelpers {
+    clock: Arc<dyn Clock>,
+    /// Answers by registry host, `None` when the helper had no credentials
+    cache: Mutex<HashMap<String, (Option<RegistryCredentials>, Instant)>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperResponse {
+    username: String,
+    secret: String,
+}
+
+impl CredentialHelpers {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Credentials `helper` holds for `host`
+    pub async fn get(&self, host: &str, helper: &str) -> Option<RegistryCredentials> {
+        let now = self.clock.instant();
+        if let Some((credentials, fetched)) = self.lock().get(host)
+            && now.duration_since(*fetched) < CACHE_TTL
+        {
+            return credentials.clone();
+        }
+        let credentials = match run(helper, &server_url(host)).await {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                tracing::warn!(registry = %host, helper = %helper, "Credential helper failed: {}", e);
+                None
+            }
+        };
+        self.lock()
+            .insert(host.to_string(), (credentials.clone(), now));
+        credentials
+    }
+
+    /// Forget the answer for `host` after the registry rejected it
+    pub fn invalidate(&self, host: &str) {
+        self.lock().remove(host);
+    }
+
+    fn lock(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<String, (Option<RegistryCredentials>, Instant)>> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Docker Hub is known to helpers by its legacy index URL
+fn server_url(host: &str) -> String {
+    if host == router::DOCKER_HUB_HOST || router::is_docker_hub_alias(host) {
+        "https://index.docker.io/v1/".to_string()
+    } else {
+        host.to_string()
+    }
+}
+
+// Program run for a helper name or path
+fn program(helper: &str) -> String {
+    if helper.contains('/') {
+        helper.to_string()
+    } else {
+        format!("docker-credential-{}", helper)
+    }
+}
+
+// Ask the helper for credentials; `Ok(None)` when it has none for the server
+async fn run(helper: &str, server_url: &str) -> Result<Option<RegistryCredentials>, String> {
+    let program = program(helper);
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("failed to run {}: {}", program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(server_url.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    let output = tokio::time::timeout(HELPER_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| format!("{} did not answer in time", program))?
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stdout);
+        let message = match message.trim() {
+            "" => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            message => message.to_string(),
+        };
+        // the message helpers print when they have nothing for the server
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        return Err(format!(
+            "{} exited with {}: {}",
+            program, output.status, message
+        ));
+    }
+    let response: HelperResponse =
+        serde_json::from_slice(&output.stdout).map_err(|e| e.to_string())?;
+    Ok(Some(RegistryCredentials {
+        username: response.username,
+        password: response.secret,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    // A helper script answering for one server, logging each run
+    fn write_helper(dir: &std::path::Path) -> String {
+        let path = dir.join("docker-credential-test");
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\nread server\necho run >> {log}\n\
+                 if [ \"$server\" = registry.lab ]; then\n\
+                 echo '{{\"ServerURL\":\"registry.lab\",\"Username\":\"ci\",\"Secret\":\"s3cret\"}}'\n\
+                 else\necho 'credentials not found in native keychain'\nexit 1\nfi\n",
+                log = dir.join("runs").display()
+            ),
+        )
+        .unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn runs(dir: &std::path::Path) -> usize {
+        std::fs::read_to_string(dir.join("runs"))
+            .map(|log| log.lines().count())
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let dir =
+            std::env::temp_dir().join(format!("docker-proxy-helper-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let helper = write_helper(&dir);
+        let clock = ManualClock::new(0);
+        let helpers = CredentialHelpers::new(clock.clone());
+
+        let credentials = helpers.get("registry.lab", &helper).await.unwrap();
+        assert_eq!(credentials.username, "ci");
+        assert_eq!(credentials.password, "s3cret");
+        assert!(helpers.get("ghcr.io", &helper).await.is_none());
+        assert_eq!(runs(&dir), 2);
+
+        // answers are reused until they expire or are invalidated
+        helpers.get("registry.lab", &helper).await.unwrap();
+        helpers.get("ghcr.io", &helper).await;
+        assert_eq!(runs(&dir), 2);
+        helpers.invalidate("registry.lab");
+        helpers.get("registry.lab", &helper).await.unwrap();
+        assert_eq!(runs(&dir), 3);
+        clock.advance(CACHE_TTL);
+        helpers.get("ghcr.io", &helper).await;
+        assert_eq!(runs(&dir), 4);
+
+        assert!(helpers.get("quay.io", "nonexistent-helper").await.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_server_url() {
+        assert_eq!(
+            server_url("registry-1.docker.io"),
+            "https://index.docker.io/v1/"
+        );
+        assert_eq!(server_url("ghcr.io"), "ghcr.io");
+        assert_eq!(program("ecr-login"), "docker-credential-ecr-login");
+        assert_eq!(program("/usr/local/bin/helper"), "/usr/local/bin/helper");
+    }
+}
@@ -0,0 +1,269 @@
+/// Connectivity diagnostics for upstream registries
+///
+/// Runs DNS resolution, TCP connects over IPv4 and IPv6, a TLS handshake and
+/// a `/v2/` probe, recording the timing of every phase.
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::net::TcpStream;
+
+use crate::proxy::DockerProxy;
+
+/// Timeout applied to each individual phase
+const PHASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+pub struct DiagnosticReport {
+    pub host: String,
+    pub port: u16,
+    pub dns: DnsResult,
+    pub tcp: Vec<TcpResult>,
+    pub tls: Option<PhaseResult>,
+    pub registry: Option<RegistryProbe>,
+    pub total_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DnsResult {
+    pub duration_ms: f64,
+    pub addresses: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TcpResult {
+    pub address: String,
+    pub family: &'static str,
+    pub duration_ms: f64,
+    pub connected: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PhaseResult {
+    pub address: String,
+    pub duration_ms: f64,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegistryProbe {
+    pub url: String,
+    pub duration_ms: f64,
+    pub status: Option<u16>,
+    /// `/v2/` answering 200 or 401 means the registry API is reachable
+    pub reachable: bool,
+    pub api_version: Option<String>,
+    pub auth_challenge: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Split `host`, `host:port` or `[v6]:port` into host and port (default 443)
+pub fn parse_target(target: &str) -> Result<(String, u16), String> {
+    let target = target.trim();
+    if target.is_empty() || target.contains('/') || target.contains('@') {
+        return Err(format!("invalid host '{}'", target));
+    }
+
+    if let Some(rest) = target.strip_prefix('[') {
+        let (host, after) = rest
+            .split_once(']')
+            .ok_or_else(|| format!("invalid host '{}'", target))?;
+        let port = match after.strip_prefix(':') {
+            Some(p) => p.parse().map_err(|_| format!("invalid port '{}'", p))?,
+            None if after.is_empty() => 443,
+            None => return Err(format!("invalid host '{}'", target)),
+        };
+        return Ok((host.to_string(), port));
+    }
+
+    match target.rsplit_once(':') {
+        // a bare IPv6 address without brackets has no port
+        Some((host, _)) if host.contains(':') => Ok((target.to_string(), 443)),
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| format!("invalid port '{}'", port))?;
+            Ok((host.to_string(), port))
+        }
+        None => Ok((target.to_string(), 443)),
+    }
+}
+
+/// Run all diagnostic phases against `host:port`
+pub async fn run(proxy: &DockerProxy, host: &str, port: u16) -> DiagnosticReport {
+    let total_start = Instant::now();
+
+    let dns_start = Instant::now();
+    let (addresses, dns_error) =
+        match tokio::time::timeout(PHASE_TIMEOUT, tokio::net::lookup_host((host, port))).await {
+            Ok(Ok(addrs)) => (addrs.collect::<Vec<SocketAddr>>(), None),
+            Ok(Err(e)) => (Vec::new(), Some(e.to_string())),
+            Err(_) => (Vec::new(), Some("timed out".to_string())),
+        };
+    let dns = DnsResult {
+        duration_ms: elapsed_ms(dns_start),
+        addresses: addresses.iter().map(|a| a.ip().to_string()).collect(),
+        error: dns_error,
+    };
+
+    // Probe the first address of each family so dual-stack problems show up
+    let mut tcp = Vec::new();
+    let mut connected = None;
+    let candidates = [
+        addresses.iter().find(|a| a.is_ipv6()),
+        addresses.iter().find(|a| a.is_ipv4()),
+    ];
+    for addr in candidates.into_iter().flatten() {
+        let start = Instant::now();
+        let result = tokio::time::timeout(PHASE_TIMEOUT, TcpStream::connect(addr)).await;
+        let duration_ms = elapsed_ms(start);
+        let family = if addr.is_ipv6() { "ipv6" } else { "ipv4" };
+        let (ok, error) = match result {
+            Ok(Ok(stream)) => {
+                if connected.is_none() {
+                    connected = Some((*addr, stream));
+                }
+                (true, None)
+            }
+            Ok(Err(e)) => (false, Some(e.to_string())),
+            Err(_) => (false, Some("timed out".to_string())),
+        };
+        tcp.push(TcpResult {
+            address: addr.to_string(),
+            family,
+            duration_ms,
+            connected: ok,
+            error,
+        });
+    }
+
+    let tls = match connected {
+        Some((addr, stream)) => Some(tls_handshake(host, addr, stream).await),
+        None => None,
+    };
+
+    let registry = if dns.error.is_none() {
+        Some(probe_registry(proxy, host, port).await)
+    } else {
+        None
+    };
+
+    DiagnosticReport {
+        host: host.to_string(),
+        port,
+        dns,
+        tcp,
+        tls,
+        registry,
+        total_ms: elapsed_ms(total_start),
+    }
+}
+
+// TLS handshake using the same native TLS stack as the upstream client
+async fn tls_handshake(host: &str, addr: SocketAddr, stream: TcpStream) -> PhaseResult {
+    let start = Instant::now();
+    let result = match native_tls::TlsConnector::new() {
+        Ok(connector) => {
+            let connector = tokio_native_tls::TlsConnector::from(connector);
+            match tokio::time::timeout(PHASE_TIMEOUT, connector.connect(host, stream)).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timed out".to_string()),
+            }
+        }
+        Err(e) => Err(e.to_string()),
+    };
+
+    PhaseResult {
+        address: addr.to_string(),
+        duration_ms: elapsed_ms(start),
+        ok: result.is_ok(),
+        error: result.err(),
+    }
+}
+
+async fn probe_registry(proxy: &DockerProxy, host: &str, port: u16) -> RegistryProbe {
+    let authority = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    let url = if port == 443 {
+        format!("https://{}/v2/", authority)
+    } else {
+        format!("https://{}:{}/v2/", authority, port)
+    };
+
+    let start = Instant::now();
+    match proxy.probe_v2(&url, PHASE_TIMEOUT).await {
+        Ok(resp) => {
+            let status = resp.status();
+            let header = |name: &str| {
+                resp.headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|s| s.to_string())
+            };
+            RegistryProbe {
+                duration_ms: elapsed_ms(start),
+                status: Some(status.as_u16()),
+                reachable: status.is_success() || status == reqwest::StatusCode::UNAUTHORIZED,
+                api_version: header("docker-distribution-api-version"),
+                auth_challenge: header("www-authenticate"),
+                error: None,
+                url,
+            }
+        }
+        Err(e) => RegistryProbe {
+            duration_ms: elapsed_ms(start),
+            status: None,
+            reachable: false,
+            api_version: None,
+            auth_challenge: None,
+            error: Some(e.to_string()),
+            url,
+        },
+    }
+}
+
+fn elapsed_ms(start: Instant) -> f64 {
+    (start.elapsed().as_secs_f64() * 100_000.0).round() / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target("registry-1.docker.io"),
+            Ok(("registry-1.docker.io".to_string(), 443))
+        );
+        assert_eq!(
+            parse_target("localhost:5000"),
+            Ok(("localhost".to_string(), 5000))
+        );
+        assert_eq!(
+            parse_target("[2001:db8::1]:8443"),
+            Ok(("2001:db8::1".to_string(), 8443))
+        );
+        assert_eq!(parse_target("[::1]"), Ok(("::1".to_string(), 443)));
+        assert_eq!(
+            parse_target("2001:db8::1"),
+            Ok(("2001:db8::1".to_string(), 443))
+        );
+    }
+
+    #[test]
+    fn test_parse_target_rejects_invalid() {
+        assert!(parse_target("").is_err());
+        assert!(parse_target("ghcr.io/owner").is_err());
+        assert!(parse_target("user@ghcr.io").is_err());
+        assert!(parse_target("ghcr.io:https").is_err());
+        assert!(parse_target("[::1]x").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+/// Registry credentials from a Docker CLI `config.json`
+///
+/// `[auth] docker_config` points at a file in the format `docker login`
+/// writes, so existing credential files can be mounted into the proxy as
+/// they are. Entries of `auths` hold a base64 `auth` of "user:password" or
+/// separate `username` and `password` fields; they are keyed by registry,
+/// with or without a scheme and path (Docker Hub's is
+/// "https://index.docker.io/v1/"). Registries listed in `credHelpers`, and
+/// those with empty `auths` entries when a `credsStore` is set, get their
+/// credentials from that helper program instead.
+use std::collections::HashMap;
+
+use base64::Engine;
+use serde::Deserialize;
+
+use crate::config::RegistryCredentials;
+use crate::router;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DockerConfigFile {
+    auths: HashMap<String, AuthEntry>,
+    #[serde(rename = "credsStore")]
+    creds_store: String,
+    #[serde(rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AuthEntry {
+    auth: String,
+    username: String,
+    password: String,
+}
+
+/// Credentials and credential helpers of a Docker config, keyed by registry
+/// host (with port, if any); Docker Hub's entries are keyed by its API host
+#[derive(Debug, Default)]
+pub struct DockerConfig {
+    pub credentials: HashMap<String, RegistryCredentials>,
+    /// Helper names, the suffixes of `docker-credential-<name>` programs
+    pub helpers: HashMap<String, String>,
+}
+
+/// Read the Docker config at `path`
+pub fn load(path: &str) -> Result<DockerConfig, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read Docker config {}: {}", path, e))?;
+    parse(&content).map_err(|e| format!("Invalid Docker config {}: {}", path, e))
+}
+
+fn parse(content: &str) -> Result<DockerConfig, String> {
+    let file: DockerConfigFile = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let mut config = DockerConfig::default();
+    for (key, entry) in &file.auths {
+        let host = registry_host(key);
+        let (username, password) = if !entry.auth.is_empty() {
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(entry.auth.trim())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or_else(|| format!("auth of {} is not valid base64", key))?;
+            let (username, password) = decoded
+                .split_once(':')
+                .ok_or_else(|| format!("auth of {} is not \"user:password\"", key))?;
+            (username.to_string(), password.to_string())
+        } else {
+            (entry.username.clone(), entry.password.clone())
+        };
+        // entries of registries whose credentials live in the store are empty
+        if username.is_empty() {
+            if !file.creds_store.is_empty() {
+                config.helpers.insert(host, file.creds_store.clone());
+            }
+            continue;
+        }
+        config
+            .credentials
+            .insert(host, RegistryCredentials { username, password });
+    }
+    for (key, helper) in &file.cred_helpers {
+        if !helper.is_empty() {
+            let host = registry_host(key);
+            config.credentials.remove(&host);
+            config.helpers.insert(host, helper.clone());
+        }
+    }
+    Ok(config)
+}
+
+// "https://index.docker.io/v1/" -> "registry-1.docker.io", "ghcr.io" -> "ghcr.io"
+fn registry_host(key: &str) -> String {
+    let host = key
+        .split_once("://")
+        .map_or(key, |(_, rest)| rest)
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    if router::is_docker_hub_alias(&host) {
+        router::DOCKER_HUB_HOST.to_string()
+    } else {
+        host
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = parse(
+            r#"{
+  "auths": {
+    "https://index.docker.io/v1/": { "auth": "Ym90OmRja3JfcGF0OnNlY3JldA==" },
+    "ghcr.io": { "username": "owner", "password": "ghp_token" },
+    "registry.lab:5000": {},
+    "quay.io": { "auth": "" }
+  },
+  "credsStore": "desktop",
+  "credHelpers": { "123456789012.dkr.ecr.eu-west-1.amazonaws.com": "ecr-login" }
+}"#,
+        )
+        .unwrap();
+        let credentials = &config.credentials;
+        assert_eq!(credentials.len(), 2);
+        let hub = &credentials["registry-1.docker.io"];
+        assert_eq!(hub.username, "bot");
+        assert_eq!(hub.password, "dckr_pat:secret");
+        assert_eq!(credentials["ghcr.io"].password, "ghp_token");
+        assert_eq!(config.helpers.len(), 3);
+        assert_eq!(config.helpers["registry.lab:5000"], "desktop");
+        assert_eq!(config.helpers["quay.io"], "desktop");
+        assert_eq!(
+            config.helpers["123456789012.dkr.ecr.eu-west-1.amazonaws.com"],
+            "ecr-login"
+        );
+
+        assert!(parse(r#"{"auths": {"ghcr.io": {"auth": "not base64!"}}}"#).is_err());
+        let empty = parse(r#"{}"#).unwrap();
+        assert!(empty.credentials.is_empty() && empty.helpers.is_empty());
+        assert!(parse("not json").is_err());
+    }
+
+    #[test]
+    fn test_registry_host() {
+        assert_eq!(
+            registry_host("https://index.docker.io/v1/"),
+            "registry-1.docker.io"
+        );
+        assert_eq!(registry_host("docker.io"), "registry-1.docker.io");
+        assert_eq!(
+            registry_host("http://Registry.Lab:5000"),
+            "registry.lab:5000"
+        );
+        assert_eq!(registry_host("ghcr.io"), "ghcr.io");
+    }
+}
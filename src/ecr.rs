@@ -0,0 +1,558 @@
+/// Authentication with Amazon ECR upstreams
+///
+/// ECR registries (`<account>.dkr.ecr.<region>.amazonaws.com`) take Basic
+/// credentials for the user "AWS" with a password from ECR's
+/// `GetAuthorizationToken` API, valid for 12 hours. The proxy calls the API
+/// with IAM credentials from `AWS_ACCESS_KEY_ID` / `AWS_SECRET_ACCESS_KEY`
+/// (and `AWS_SESSION_TOKEN`), or else the EC2 instance profile through
+/// IMDSv2, signing the request with Signature Version 4. Tokens are cached
+/// per registry and fetched again shortly before they expire. The SDKs'
+/// `AWS_ENDPOINT_URL_ECR`, `AWS_EC2_METADATA_SERVICE_ENDPOINT` and
+/// `AWS_EC2_METADATA_DISABLED` variables are honoured.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::clock::Clock;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A token is replaced once less than this much of its lifetime is left
+const REFRESH_MARGIN_SECS: u64 = 30 * 60;
+/// After a failed token request, the next attempt waits at least this long
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(30);
+const API_TARGET: &str = "AmazonEC2ContainerRegistry_V20150921.GetAuthorizationToken";
+const DEFAULT_METADATA_ENDPOINT: &str = "http://169.254.169.254";
+const METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// An ECR registry named by its host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EcrRegistry {
+    pub account: String,
+    pub region: String,
+    /// Host of the regional ECR API
+    pub api_host: String,
+}
+
+impl EcrRegistry {
+    /// `None` unless `host` is an ECR registry, e.g.
+    /// "123456789012.dkr.ecr.eu-west-1.amazonaws.com"
+    pub fn parse(host: &str) -> Option<Self> {
+        let (account, rest) = host.split_once('.')?;
+        if account.len() != 12 || !account.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let (fips, rest) = match rest.strip_prefix("dkr.ecr-fips.") {
+            Some(rest) => (true, rest),
+            None => (false, rest.strip_prefix("dkr.ecr.")?),
+        };
+        let (region, domain) = rest.split_once('.')?;
+        if region.is_empty() || !matches!(domain, "amazonaws.com" | "amazonaws.com.cn") {
+            return None;
+        }
+        let api_host = if fips {
+            format!("ecr-fips.{}.{}", region, domain)
+        } else {
+            format!("api.ecr.{}.{}", region, domain)
+        };
+        Some(Self {
+            account: account.to_string(),
+            region: region.to_string(),
+            api_host,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+#[derive(Default)]
+struct Tokens {
+    /// `Authorization` value and expiry (Unix seconds) by registry host
+    valid: HashMap<String, (String, u64)>,
+    /// When a token request for a registry host last failed
+    failed: HashMap<String, Instant>,
+}
+
+pub struct EcrAuth {
+    client: reqwest::Client,
+    clock: Arc<dyn Clock>,
+    /// Credentials from the environment; the instance profile is asked otherwise
+    static_credentials: Option<AwsCredentials>,
+    /// Instance metadata service, unless disabled
+    metadata_endpoint: Option<String>,
+    /// Replaces the regional API URL
+    endpoint_url: Option<String>,
+    tokens: Mutex<Tokens>,
+    /// Held while requesting a token, so concurrent pulls share one request
+    refresh: tokio::sync::Mutex<()>,
+}
+
+impl EcrAuth {
+    /// Provider reading its settings from the process environment
+    pub fn new(client: reqwest::Client, clock: Arc<dyn Clock>) -> Self {
+        Self::with_env(client, clock, |name| std::env::var(name).ok())
+    }
+
+    fn with_env(
+        client: reqwest::Client,
+        clock: Arc<dyn Clock>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let env = |name: &str| env(name).filter(|value| !value.is_empty());
+        let static_credentials = env("AWS_ACCESS_KEY_ID")
+            .zip(env("AWS_SECRET_ACCESS_KEY"))
+            .map(|(access_key_id, secret_access_key)| AwsCredentials {
+                access_key_id,
+                secret_access_key,
+                session_token: env("AWS_SESSION_TOKEN"),
+            });
+        let metadata_endpoint = (!env("AWS_EC2_METADATA_DISABLED")
+            .is_some_and(|value| value.eq_ignore_ascii_case("true")))
+        .then(|| {
+            env("AWS_EC2_METADATA_SERVICE_ENDPOINT")
+                .unwrap_or_else(|| DEFAULT_METADATA_ENDPOINT.to_string())
+                .trim_end_matches('/')
+                .to_string()
+        });
+        Self {
+            client,
+            clock,
+            static_credentials,
+            metadata_endpoint,
+            endpoint_url: env("AWS_ENDPOINT_URL_ECR")
+                .map(|url| url.trim_end_matches('/').to_string()),
+            tokens: Mutex::new(Tokens::default()),
+            refresh: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// `Authorization` value for requests to `host`; `None` for hosts that
+    /// are not ECR registries or when no token can be obtained
+    pub async fn authorization(&self, host: &str) -> Option<String> {
+        let registry = EcrRegistry::parse(host)?;
+        if let Some(value) = self.cached(host) {
+            return Some(value);
+        }
+        let _refresh = self.refresh.lock().await;
+        if let Some(value) = self.cached(host) {
+            return Some(value);
+        }
+        if self.lock().failed.get(host).is_some_and(|failed| {
+            self.clock.instant().duration_since(*failed) < RETRY_AFTER_FAILURE
+        }) {
+            return None;
+        }
+        match self.request_token(&registry).await {
+            Ok((value, expires_at)) => {
+                tracing::info!(registry = %host, "Obtained ECR authorization token");
+                let mut tokens = self.lock();
+                tokens.failed.remove(host);
+                tokens
+                    .valid
+                    .insert(host.to_string(), (value.clone(), expires_at));
+                Some(value)
+            }
+            Err(e) => {
+                tracing::warn!(registry = %host, "ECR token request failed: {}", e);
+                self.lock()
+                    .failed
+                    .insert(host.to_string(), self.clock.instant());
+                None
+            }
+        }
+    }
+
+    /// Forget the token of `host` after the registry rejected it
+    pub fn invalidate(&self, host: &str) {
+        self.lock().valid.remove(host);
+    }
+
+    fn cached(&self, host: &str) -> Option<String> {
+        let now = self.clock.now_secs();
+        self.lock()
+            .valid
+            .get(host)
+            .filter(|(_, expires_at)| now + REFRESH_MARGIN_SECS < *expires_at)
+            .map(|(value, _)| value.clone())
+    }
+
+    // Call GetAuthorizationToken for the registry's account
+    async fn request_token(&self, registry: &EcrRegistry) -> Result<(String, u64), String> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Response {
+            authorization_data: Vec<AuthorizationData>,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct AuthorizationData {
+            authorization_token: String,
+            expires_at: f64,
+        }
+
+        let credentials = self.credentials().await?;
+        let url = match &self.endpoint_url {
+            Some(url) => format!("{}/", url),
+            None => format!("https://{}/", registry.api_host),
+        };
+        let host = reqwest::Url::parse(&url)
+            .ok()
+            .and_then(|url| {
+                let host = url.host_str()?;
+                Some(match url.port() {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host.to_string(),
+                })
+            })
+            .ok_or_else(|| format!("invalid ECR endpoint {}", url))?;
+        let body = serde_json::json!({ "registryIds": [registry.account] }).to_string();
+        let amz_date = amz_date(self.clock.now_secs());
+
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host),
+            ("x-amz-date", amz_date.clone()),
+            ("x-amz-target", API_TARGET.to_string()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = sign(
+            &credentials,
+            &registry.region,
+            "ecr",
+            "POST",
+            "/",
+            &headers,
+            body.as_bytes(),
+            &amz_date,
+        );
+        let mut request = self
+            .client
+            .post(&url)
+            .header(reqwest::header::AUTHORIZATION, authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.header(*name, value);
+        }
+        let response = request.body(body).send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        let text = response.text().await.map_err(|e| e.to_string())?;
+        if !status.is_success() {
+            return Err(format!("{}: {}", status, text));
+        }
+        let data = serde_json::from_str::<Response>(&text)
+            .map_err(|e| e.to_string())?
+            .authorization_data
+            .into_iter()
+            .next()
+            .ok_or("no authorization data in the response")?;
+        // the token is base64 of "AWS:<password>", as a Basic credential
+        base64::engine::general_purpose::STANDARD
+            .decode(&data.authorization_token)
+            .map_err(|e| e.to_string())?;
+        Ok((
+            format!("Basic {}", data.authorization_token),
+            data.expires_at as u64,
+        ))
+    }
+
+    // Credentials from the environment, or else the instance profile
+    async fn credentials(&self) -> Result<AwsCredentials, String> {
+        if let Some(credentials) = &self.static_credentials {
+            return Ok(credentials.clone());
+        }
+        let Some(endpoint) = &self.metadata_endpoint else {
+            return Err("no AWS credentials in the environment".to_string());
+        };
+        self.instance_credentials(endpoint).await.map_err(|e| {
+            format!(
+                "no AWS credentials in the environment or instance profile: {}",
+                e
+            )
+        })
+    }
+
+    // Role credentials through IMDSv2: a session token, the role name, then
+    // the role's credentials
+    async fn instance_credentials(&self, endpoint: &str) -> Result<AwsCredentials, String> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct RoleCredentials {
+            access_key_id: String,
+            secret_access_key: String,
+            token: String,
+        }
+
+        let session = self
+            .client
+            .put(format!("{}/latest/api/token", endpoint))
+            .header("x-aws-ec2-metadata-token-ttl-seconds", "300")
+            .timeout(METADATA_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .text()
+            .await
+            .map_err(|e| e.to_string())?;
+        let get = |path: String| {
+            self.client
+                .get(format!("{}{}", endpoint, path))
+                .header("x-aws-ec2-metadata-token", &session)
+                .timeout(METADATA_TIMEOUT)
+                .send()
+        };
+        let roles = get("/latest/meta-data/iam/security-credentials/".to_string())
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .text()
+            .await
+            .map_err(|e| e.to_string())?;
+        let role = roles
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .ok_or("the instance has no IAM role")?;
+        let credentials: RoleCredentials = get(format!(
+            "/latest/meta-data/iam/security-credentials/{}",
+            role
+        ))
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+        Ok(AwsCredentials {
+            access_key_id: credentials.access_key_id,
+            secret_access_key: credentials.secret_access_key,
+            session_token: Some(credentials.token),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tokens> {
+        self.tokens.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Signature Version 4 `Authorization` value for a request with the given
+// lowercase headers (which must include host and x-amz-date) and no query
+#[allow(clippy::too_many_arguments)]
+fn sign(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+    amz_date: &str,
+) -> String {
+    let mut headers: Vec<_> = headers.iter().collect();
+    headers.sort_by_key(|(name, _)| *name);
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method,
+        path,
+        canonical_headers,
+        signed_headers,
+        hex::encode(Sha256::digest(body))
+    );
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = [date, region, service, "aws4_request"].iter().fold(
+        format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+        |key, part| hmac(&key, part.as_bytes()),
+    );
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex::encode(hmac(&key, string_to_sign.as_bytes()))
+    )
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// "YYYYMMDDTHHMMSSZ" of Unix seconds (Howard Hinnant's civil_from_days)
+fn amz_date(secs: u64) -> String {
+    let (days, time) = (secs / 86_400, secs % 86_400);
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn provider(clock: Arc<ManualClock>, env: &[(&str, &str)]) -> EcrAuth {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        EcrAuth::with_env(reqwest::Client::new(), clock, |name| env.get(name).cloned())
+    }
+
+    #[test]
+    fn test_parse_registry() {
+        let registry = EcrRegistry::parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com").unwrap();
+        assert_eq!(registry.account, "123456789012");
+        assert_eq!(registry.region, "eu-west-1");
+        assert_eq!(registry.api_host, "api.ecr.eu-west-1.amazonaws.com");
+        assert_eq!(
+            EcrRegistry::parse("123456789012.dkr.ecr.cn-north-1.amazonaws.com.cn")
+                .unwrap()
+                .api_host,
+            "api.ecr.cn-north-1.amazonaws.com.cn"
+        );
+        assert_eq!(
+            EcrRegistry::parse("123456789012.dkr.ecr-fips.us-east-1.amazonaws.com")
+                .unwrap()
+                .api_host,
+            "ecr-fips.us-east-1.amazonaws.com"
+        );
+        assert!(EcrRegistry::parse("public.ecr.aws").is_none());
+        assert!(EcrRegistry::parse("12345.dkr.ecr.eu-west-1.amazonaws.com").is_none());
+        assert!(EcrRegistry::parse("123456789012.dkr.ecr.eu-west-1.example.com").is_none());
+        assert!(EcrRegistry::parse("registry-1.docker.io").is_none());
+    }
+
+    #[test]
+    fn test_sign() {
+        // "get-vanilla" from the AWS Signature Version 4 test suite
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let amz_date = amz_date(1_440_938_160);
+        assert_eq!(amz_date, "20150830T123600Z");
+        let headers = [
+            ("host", "example.amazonaws.com".to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        assert_eq!(
+            sign(
+                &credentials,
+                "us-east-1",
+                "service",
+                "GET",
+                "/",
+                &headers,
+                b"",
+                &amz_date
+            ),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+        assert_eq!(super::amz_date(951_868_799), "20000229T235959Z");
+    }
+
+    #[test]
+    fn test_env() {
+        let clock = ManualClock::new(0);
+        let ecr = provider(
+            clock.clone(),
+            &[
+                ("AWS_ACCESS_KEY_ID", "AKID"),
+                ("AWS_SECRET_ACCESS_KEY", "secret"),
+                ("AWS_EC2_METADATA_DISABLED", "true"),
+                ("AWS_ENDPOINT_URL_ECR", "http://localhost:4566/"),
+            ],
+        );
+        let credentials = ecr.static_credentials.as_ref().unwrap();
+        assert_eq!(credentials.access_key_id, "AKID");
+        assert!(credentials.session_token.is_none());
+        assert!(ecr.metadata_endpoint.is_none());
+        assert_eq!(ecr.endpoint_url.as_deref(), Some("http://localhost:4566"));
+
+        let ecr = provider(clock, &[("AWS_ACCESS_KEY_ID", "AKID")]);
+        assert!(ecr.static_credentials.is_none());
+        assert_eq!(
+            ecr.metadata_endpoint.as_deref(),
+            Some(DEFAULT_METADATA_ENDPOINT)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authorization() {
+        let host = "123456789012.dkr.ecr.eu-west-1.amazonaws.com";
+        let clock = ManualClock::new(1_000_000);
+        // nothing listens on the endpoint, so token requests fail
+        let ecr = provider(
+            clock.clone(),
+            &[
+                ("AWS_ACCESS_KEY_ID", "AKID"),
+                ("AWS_SECRET_ACCESS_KEY", "secret"),
+                ("AWS_ENDPOINT_URL_ECR", "http://127.0.0.1:9"),
+            ],
+        );
+        assert_eq!(ecr.authorization("ghcr.io").await, None);
+        assert_eq!(ecr.authorization(host).await, None);
+        assert!(ecr.lock().failed.contains_key(host));
+
+        let expires_at = 1_000_000 + 12 * 3600;
+        ecr.lock()
+            .valid
+            .insert(host.to_string(), ("Basic QVdTOnB3".to_string(), expires_at));
+        assert_eq!(
+            ecr.authorization(host).await.as_deref(),
+            Some("Basic QVdTOnB3")
+        );
+        // within the refresh margin of expiry, a new token is requested
+        clock.advance(Duration::from_secs(12 * 3600 - REFRESH_MARGIN_SECS));
+        assert_eq!(ecr.authorization(host).await, None);
+
+        ecr.invalidate(host);
+        assert!(ecr.lock().valid.is_empty());
+    }
+}
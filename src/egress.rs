@@ -0,0 +1,187 @@
+/// Egress savings accounting
+///
+/// Manifest and blob bytes served to clients are counted per upstream
+/// registry by where they came from: the cache (hits, stale manifests and
+/// requests that waited for another request's fetch) or the registry itself.
+/// Manifest GETs are counted too, as they are what registries such as Docker
+/// Hub rate-limit. The counters cover the time since startup and are exposed
+/// in `/api/stats` and as Prometheus metrics at `/metrics`.
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// Where a response's content came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Cache,
+    Upstream,
+}
+
+impl Source {
+    /// Source for an `X-Docker-Proxy-Cache` status
+    pub fn from_cache_status(status: &str) -> Option<Self> {
+        match status {
+            "hit" | "stale" | "coalesced" => Some(Source::Cache),
+            "miss" => Some(Source::Upstream),
+            _ => None,
+        }
+    }
+}
+
+/// Counters for one registry
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct RegistryEgress {
+    pub cached_bytes: u64,
+    pub upstream_bytes: u64,
+    pub cached_manifests: u64,
+    pub upstream_manifests: u64,
+}
+
+impl RegistryEgress {
+    /// Share of the bytes served that the cache saved fetching, 0.0 to 1.0
+    pub fn saved_ratio(&self) -> f64 {
+        let total = self.cached_bytes + self.upstream_bytes;
+        if total == 0 {
+            0.0
+        } else {
+            self.cached_bytes as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct EgressStats {
+    registries: Mutex<BTreeMap<String, RegistryEgress>>,
+}
+
+impl EgressStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a manifest served for `registry`
+    pub fn record_manifest(&self, registry: &str, source: Source, bytes: u64) {
+        self.record(registry, source, bytes, 1);
+    }
+
+    /// Count blob bytes served for `registry`
+    pub fn record_blob(&self, registry: &str, source: Source, bytes: u64) {
+        self.record(registry, source, bytes, 0);
+    }
+
+    fn record(&self, registry: &str, source: Source, bytes: u64, manifests: u64) {
+        let mut registries = self.registries.lock().unwrap_or_else(|e| e.into_inner());
+        let egress = match registries.get_mut(registry) {
+            Some(egress) => egress,
+            None => registries.entry(registry.to_string()).or_default(),
+        };
+        match source {
+            Source::Cache => {
+                egress.cached_bytes += bytes;
+                egress.cached_manifests += manifests;
+            }
+            Source::Upstream => {
+                egress.upstream_bytes += bytes;
+                egress.upstream_manifests += manifests;
+            }
+        }
+    }
+
+    /// Counters by registry host
+    pub fn snapshot(&self) -> BTreeMap<String, RegistryEgress> {
+        self.registries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Render counters in the Prometheus text exposition format
+pub fn to_prometheus(registries: &BTreeMap<String, RegistryEgress>) -> String {
+    let mut metrics = String::new();
+    metrics.push_str(
+        "# HELP docker_proxy_served_bytes_total Manifest and blob bytes served to clients, by where they came from\n\
+         # TYPE docker_proxy_served_bytes_total counter\n",
+    );
+    for (registry, egress) in registries {
+        for (source, bytes) in [
+            ("cache", egress.cached_bytes),
+            ("upstream", egress.upstream_bytes),
+        ] {
+            metrics.push_str(&format!(
+                "docker_proxy_served_bytes_total{{registry=\"{}\",source=\"{}\"}} {}\n",
+                label_value(registry),
+                source,
+                bytes
+            ));
+        }
+    }
+    metrics.push_str(
+        "# HELP docker_proxy_manifest_pulls_total Manifest GETs served to clients, by where they came from\n\
+         # TYPE docker_proxy_manifest_pulls_total counter\n",
+    );
+    for (registry, egress) in registries {
+        for (source, pulls) in [
+            ("cache", egress.cached_manifests),
+            ("upstream", egress.upstream_manifests),
+        ] {
+            metrics.push_str(&format!(
+                "docker_proxy_manifest_pulls_total{{registry=\"{}\",source=\"{}\"}} {}\n",
+                label_value(registry),
+                source,
+                pulls
+            ));
+        }
+    }
+    metrics
+}
+
+/// Escape a Prometheus label value (backslash, quote and line feed)
+pub fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_render() {
+        let stats = EgressStats::new();
+        assert_eq!(Source::from_cache_status("stale"), Some(Source::Cache));
+        assert_eq!(Source::from_cache_status("miss"), Some(Source::Upstream));
+        assert_eq!(Source::from_cache_status("bypass"), None);
+
+        stats.record_manifest("registry-1.docker.io", Source::Upstream, 500);
+        stats.record_blob("registry-1.docker.io", Source::Upstream, 1_000);
+        stats.record_manifest("registry-1.docker.io", Source::Cache, 500);
+        stats.record_blob("registry-1.docker.io", Source::Cache, 3_000);
+        stats.record_blob("ghcr.io", Source::Cache, 10);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot["registry-1.docker.io"],
+            RegistryEgress {
+                cached_bytes: 3_500,
+                upstream_bytes: 1_500,
+                cached_manifests: 1,
+                upstream_manifests: 1,
+            }
+        );
+        assert_eq!(snapshot["registry-1.docker.io"].saved_ratio(), 0.7);
+        assert_eq!(RegistryEgress::default().saved_ratio(), 0.0);
+
+        let metrics = to_prometheus(&snapshot);
+        assert!(metrics.contains(
+            "docker_proxy_served_bytes_total{registry=\"ghcr.io\",source=\"cache\"} 10\n"
+        ));
+        assert!(metrics.contains(
+            "docker_proxy_manifest_pulls_total{registry=\"registry-1.docker.io\",source=\"upstream\"} 1\n"
+        ));
+        assert_eq!(label_value("a\"b\\"), "a\\\"b\\\\");
+    }
+}
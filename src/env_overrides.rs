@@ -0,0 +1,150 @@
+/// Configuration overrides from the environment
+///
+/// Containers are usually configured through environment variables rather
+/// than a baked-in file, so `DOCKER_PROXY__<SECTION>__<KEY>=<value>` sets
+/// `key` of `[section]` on top of config.toml, e.g.
+/// `DOCKER_PROXY__SERVER__PORT=8080` or `DOCKER_PROXY__CACHE__ENABLED=true`.
+/// Nested tables take more segments (`DOCKER_PROXY__SERVER__TLS__CERT_FILE`).
+/// Names are matched case-insensitively against the keys in the file, with
+/// `_` also matching `-` (`GHCR_TOKEN` sets `ghcr-token`), and lowercased
+/// otherwise. Values are read as TOML (numbers, booleans, arrays
+/// such as `["a", "b"]`) and as plain strings when they do not parse, or
+/// when the file already holds a string there.
+use toml::{Table, Value};
+
+/// Prefix of overriding variables
+pub const PREFIX: &str = "DOCKER_PROXY__";
+
+/// Apply the overriding variables among `vars` to `table`, returning the
+/// names of those applied
+pub fn apply(
+    table: &mut Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Vec<String>, String> {
+    let mut applied = Vec::new();
+    for (name, value) in vars {
+        let Some(path) = name.strip_prefix(PREFIX) else {
+            continue;
+        };
+        let segments: Vec<&str> = path.split("__").collect();
+        if segments.iter().any(|segment| segment.is_empty()) {
+            return Err(format!("Invalid configuration override {}", name));
+        }
+        set(table, &segments, &value).map_err(|e| format!("{}: {}", name, e))?;
+        applied.push(name);
+    }
+    applied.sort();
+    Ok(applied)
+}
+
+// Set the value at `path`, creating the tables on the way
+fn set(table: &mut Table, path: &[&str], value: &str) -> Result<(), String> {
+    let (first, rest) = path.split_first().ok_or("empty path")?;
+    let key = table
+        .keys()
+        .find(|key| same_key(key, first))
+        .cloned()
+        .unwrap_or_else(|| first.to_ascii_lowercase());
+    if rest.is_empty() {
+        let parsed = match table.get(&key) {
+            Some(Value::String(_)) => Value::String(value.to_string()),
+            _ => parse_value(value),
+        };
+        table.insert(key, parsed);
+        return Ok(());
+    }
+    match table
+        .entry(key.clone())
+        .or_insert_with(|| Value::Table(Table::new()))
+    {
+        Value::Table(nested) => set(nested, rest, value),
+        _ => Err(format!("{} is not a table", key)),
+    }
+}
+
+fn same_key(key: &str, name: &str) -> bool {
+    key.len() == name.len()
+        && key
+            .bytes()
+            .zip(name.bytes())
+            .all(|(a, b)| a.eq_ignore_ascii_case(&b) || (a == b'-' && b == b'_'))
+}
+
+// The value as TOML, or as a string
+fn parse_value(value: &str) -> Value {
+    format!("value = {}", value)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut table: Table = r#"
+[server]
+port = 8080
+
+[log]
+logFilePath = "/app/logs/docker-proxy.log"
+level = "info"
+
+[auth]
+ghcr-token = ""
+"#
+        .parse()
+        .unwrap();
+        let applied = apply(
+            &mut table,
+            vars(&[
+                ("DOCKER_PROXY__SERVER__PORT", "9090"),
+                ("DOCKER_PROXY__SERVER__TLS__CERT_FILE", "/tls/cert.pem"),
+                ("DOCKER_PROXY__LOG__LOGFILEPATH", "/var/log/proxy.log"),
+                ("DOCKER_PROXY__LOG__LEVEL", "true"),
+                ("DOCKER_PROXY__CACHE__ENABLED", "true"),
+                ("DOCKER_PROXY__AUTH__GHCR_TOKEN", "ghp_secret"),
+                (
+                    "DOCKER_PROXY__CLIENT__OUTBOUND_NO_PROXY",
+                    r#"["10.0.0.0/8"]"#,
+                ),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(applied.len(), 7);
+        assert_eq!(table["server"]["port"], Value::Integer(9090));
+        assert_eq!(
+            table["server"]["tls"]["cert_file"],
+            Value::String("/tls/cert.pem".to_string())
+        );
+        // Existing keys keep their case and strings stay strings
+        assert_eq!(
+            table["log"]["logFilePath"],
+            Value::String("/var/log/proxy.log".to_string())
+        );
+        assert_eq!(table["log"]["level"], Value::String("true".to_string()));
+        assert_eq!(table["cache"]["enabled"], Value::Boolean(true));
+        assert_eq!(
+            table["auth"]["ghcr-token"],
+            Value::String("ghp_secret".to_string())
+        );
+        assert_eq!(
+            table["client"]["outbound_no_proxy"],
+            Value::Array(vec![Value::String("10.0.0.0/8".to_string())])
+        );
+
+        assert!(apply(&mut table, vars(&[("DOCKER_PROXY__SERVER__PORT__X", "1")])).is_err());
+        assert!(apply(&mut table, vars(&[("DOCKER_PROXY____PORT", "1")])).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use serde::Serialize;
 use thiserror::Error;
 
 /// Custom error types for the Docker proxy
@@ -12,13 +13,12 @@ pub enum ProxyError {
     #[error("Blob not found: {status}")]
     BlobNotFound { status: reqwest::StatusCode },
 
+    #[error("Tag list request failed: {status}")]
+    TagListFailed { status: reqwest::StatusCode },
+
     #[error("Failed to read response body: {0}")]
     ResponseReadError(String),
 
-    #[error("Blob upload not supported")]
-    BlobUploadNotSupported,
-
-    #[allow(dead_code)]
     #[error("Invalid registry URL: {0}")]
     InvalidRegistryUrl(String),
 
@@ -26,11 +26,169 @@ pub enum ProxyError {
     #[error("Authentication failed: {0}")]
     AuthenticationFailed(String),
 
-    #[allow(dead_code)]
+    #[error("Invalid image archive: {0}")]
+    InvalidArchive(String),
+
+    #[error("Upload session not found: {0}")]
+    UploadUnknown(String),
+
+    #[error("Upload range must continue at offset {offset}")]
+    UploadRangeInvalid { offset: u64 },
+
+    #[error("Upload exceeds the local storage limit of {limit} bytes")]
+    UploadTooLarge { limit: u64 },
+
+    #[error("Digest mismatch: expected {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+
+    #[error("Invalid manifest: {0}")]
+    ManifestInvalid(String),
+
+    #[error("Manifest references unknown blob {0}")]
+    ManifestBlobUnknown(String),
+
+    #[error("Upstream did not respond within {0:?}")]
+    UpstreamTimeout(std::time::Duration),
+
+    #[error("Upstream {host} rate limit reached, retry after {retry_after_secs}s")]
+    RateLimited {
+        host: String,
+        retry_after_secs: u64,
+        /// The upstream's rate limit headers, passed on to the client
+        headers: Vec<(String, String)>,
+    },
+
+    #[error("Upstream response rejected: {0}")]
+    UpstreamRejected(String),
+
     #[error("Internal error: {0}")]
     InternalError(String),
 }
 
+/// Where a request to an upstream failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePhase {
+    Dns,
+    Connect,
+    Tls,
+    Timeout,
+    Read,
+    /// The upstream answered with an unexpected status
+    Response,
+    /// The upstream's content failed digest or signature verification
+    Verify,
+    Unknown,
+}
+
+impl FailurePhase {
+    /// Whether retrying the same request may succeed
+    pub fn retryable(self) -> bool {
+        !matches!(
+            self,
+            FailurePhase::Dns | FailurePhase::Tls | FailurePhase::Verify
+        )
+    }
+
+    /// What a user seeing this failure can do about it
+    pub fn advice(self) -> &'static str {
+        match self {
+            FailurePhase::Dns => {
+                "the upstream host name could not be resolved; check the proxy's DNS and upstream configuration"
+            }
+            FailurePhase::Connect => "the upstream could not be reached; retry later",
+            FailurePhase::Tls => {
+                "the TLS handshake with the upstream failed; check its certificate and the proxy's trust store"
+            }
+            FailurePhase::Timeout => "the upstream did not respond in time; retry the request",
+            FailurePhase::Read => "the upstream connection broke off; retry the request",
+            FailurePhase::Response => {
+                "the upstream answered with an unexpected response; retry later"
+            }
+            FailurePhase::Verify => {
+                "the upstream returned content that failed verification; retrying will not help"
+            }
+            FailurePhase::Unknown => "retry later, and report the request id if it persists",
+        }
+    }
+}
+
+impl ProxyError {
+    /// The phase of the upstream request that failed
+    pub fn phase(&self) -> FailurePhase {
+        match self {
+            ProxyError::Network(e) => network_phase(e),
+            ProxyError::ResponseReadError(_) => FailurePhase::Read,
+            ProxyError::UpstreamTimeout(_) => FailurePhase::Timeout,
+            ProxyError::ManifestNotFound { .. }
+            | ProxyError::BlobNotFound { .. }
+            | ProxyError::TagListFailed { .. }
+            | ProxyError::RateLimited { .. }
+            | ProxyError::AuthenticationFailed(_) => FailurePhase::Response,
+            ProxyError::DigestMismatch { .. }
+            | ProxyError::ManifestInvalid(_)
+            | ProxyError::UpstreamRejected(_) => FailurePhase::Verify,
+            _ => FailurePhase::Unknown,
+        }
+    }
+
+    /// Whether the upstream is unreachable, failing or rate limiting, rather
+    /// than answering for the content itself
+    pub fn is_upstream_outage(&self) -> bool {
+        match self {
+            ProxyError::Network(_)
+            | ProxyError::ResponseReadError(_)
+            | ProxyError::UpstreamTimeout(_)
+            | ProxyError::RateLimited { .. } => true,
+            ProxyError::ManifestNotFound { status }
+            | ProxyError::BlobNotFound { status }
+            | ProxyError::TagListFailed { status } => {
+                *status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            _ => false,
+        }
+    }
+
+    /// Host of the upstream URL the error occurred on, when recorded
+    pub fn upstream_host(&self) -> Option<String> {
+        match self {
+            ProxyError::Network(e) => e.url()?.host_str().map(str::to_string),
+            ProxyError::RateLimited { host, .. } => Some(host.clone()),
+            _ => None,
+        }
+    }
+}
+
+// reqwest only flags connect errors as such; resolver and TLS failures are
+// told apart by the messages of the underlying errors
+fn network_phase(error: &reqwest::Error) -> FailurePhase {
+    if error.is_timeout() {
+        return FailurePhase::Timeout;
+    }
+    if error.is_connect() {
+        let mut source = std::error::Error::source(error);
+        while let Some(e) = source {
+            let message = e.to_string().to_ascii_lowercase();
+            if message.contains("dns error") || message.contains("failed to lookup address") {
+                return FailurePhase::Dns;
+            }
+            if message.contains("certificate") || message.contains("tls") || message.contains("ssl")
+            {
+                return FailurePhase::Tls;
+            }
+            source = e.source();
+        }
+        return FailurePhase::Connect;
+    }
+    if error.is_body() || error.is_decode() {
+        return FailurePhase::Read;
+    }
+    if error.is_status() {
+        return FailurePhase::Response;
+    }
+    FailurePhase::Unknown
+}
+
 /// Type alias for Result with ProxyError
 pub type ProxyResult<T> = Result<T, ProxyError>;
 
@@ -39,3 +197,33 @@ impl From<reqwest::Error> for ProxyError {
         ProxyError::Network(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_failure_phase() {
+        // Nothing listens on port 1
+        let error = reqwest::get("http://127.0.0.1:1/v2/").await.unwrap_err();
+        let error = ProxyError::from(error);
+        assert_eq!(error.phase(), FailurePhase::Connect);
+        assert!(error.phase().retryable());
+        assert_eq!(error.upstream_host().as_deref(), Some("127.0.0.1"));
+
+        let mismatch = ProxyError::DigestMismatch {
+            expected: "sha256:a".to_string(),
+            actual: "sha256:b".to_string(),
+        };
+        assert_eq!(mismatch.phase(), FailurePhase::Verify);
+        assert!(!mismatch.phase().retryable());
+        assert_eq!(mismatch.upstream_host(), None);
+        assert_eq!(
+            ProxyError::ResponseReadError("reset".to_string()).phase(),
+            FailurePhase::Read
+        );
+        let timeout = ProxyError::UpstreamTimeout(std::time::Duration::from_secs(60));
+        assert_eq!(timeout.phase(), FailurePhase::Timeout);
+        assert!(timeout.is_upstream_outage());
+    }
+}
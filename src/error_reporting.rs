@@ -0,0 +1,167 @@
+/// Error reporting to Sentry
+///
+/// With `[sentry] dsn` set, requests answered with a server error and panics
+/// are sent to Sentry as events. Each request runs with its own hub whose
+/// scope carries the request ID, method, path and client, so events (panics
+/// included) arrive with the request they happened in. Handlers can attach
+/// the underlying error to the response with `ReportedError`.
+use axum::response::Response;
+use sentry::protocol::{Event, Level};
+
+use crate::config::SentryConfig;
+
+/// The error behind a server error response, for its Sentry event
+#[derive(Debug, Clone)]
+pub struct ReportedError(pub String);
+
+/// Request details attached to events
+pub struct RequestContext<'a> {
+    pub request_id: uuid::Uuid,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub client: &'a str,
+}
+
+/// Start the Sentry client, if configured; events are flushed when the guard
+/// is dropped
+pub fn init(config: &SentryConfig) -> Option<sentry::ClientInitGuard> {
+    if !config.is_enabled() {
+        return None;
+    }
+    let guard = sentry::init((
+        config.dsn.as_str(),
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: (!config.environment.is_empty())
+                .then(|| config.environment.clone().into()),
+            sample_rate: config.sample_rate,
+            ..Default::default()
+        },
+    ));
+    guard.is_enabled().then_some(guard)
+}
+
+/// Whether events are being sent
+pub fn enabled() -> bool {
+    sentry::Hub::current().client().is_some()
+}
+
+/// A hub for one request, its scope describing the request
+pub fn request_hub(context: &RequestContext) -> std::sync::Arc<sentry::Hub> {
+    let hub = std::sync::Arc::new(sentry::Hub::new_from_top(sentry::Hub::current()));
+    hub.configure_scope(|scope| {
+        scope.set_tag("request_id", context.request_id);
+        scope.set_tag("http.method", context.method);
+        scope.set_extra("path", context.path.into());
+        scope.set_user(Some(sentry::User {
+            ip_address: context.client.parse().ok(),
+            id: Some(context.client.to_string()),
+            ..Default::default()
+        }));
+    });
+    hub
+}
+
+/// Report `response` if it is a server error
+pub fn report_response(hub: &sentry::Hub, context: &RequestContext, response: &Response) {
+    let status = response.status();
+    if !status.is_server_error() {
+        return;
+    }
+    let message = match response.extensions().get::<ReportedError>() {
+        Some(ReportedError(error)) => error.clone(),
+        None => format!(
+            "{} {} answered with {}",
+            context.method, context.path, status
+        ),
+    };
+    let mut event = Event {
+        message: Some(message),
+        level: Level::Error,
+        ..Default::default()
+    };
+    event
+        .tags
+        .insert("http.status_code".to_string(), status.as_u16().to_string());
+    hub.capture_event(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    use super::*;
+
+    // Keeps the envelopes a client sends
+    #[derive(Default)]
+    struct Captured(Mutex<Vec<sentry::Envelope>>);
+
+    impl sentry::Transport for Captured {
+        fn send_envelope(&self, envelope: sentry::Envelope) {
+            self.0.lock().unwrap().push(envelope);
+        }
+    }
+
+    #[test]
+    fn test_report_response() {
+        let captured = Arc::new(Captured::default());
+        let transport = Arc::clone(&captured);
+        let client = sentry::Client::from(sentry::ClientOptions {
+            dsn: Some("https://key@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(move |_: &sentry::ClientOptions| {
+                Arc::clone(&transport) as Arc<dyn sentry::Transport>
+            })),
+            ..Default::default()
+        });
+        let top = Arc::new(sentry::Hub::new(
+            Some(Arc::new(client)),
+            Arc::new(sentry::Scope::default()),
+        ));
+        let context = RequestContext {
+            request_id: uuid::Uuid::from_u128(7),
+            method: "GET",
+            path: "/v2/library/nginx/manifests/latest",
+            client: "10.0.0.1",
+        };
+        let hub = sentry::Hub::run(top, || {
+            assert!(enabled());
+            request_hub(&context)
+        });
+
+        report_response(&hub, &context, &StatusCode::OK.into_response());
+        report_response(
+            &hub,
+            &context,
+            &StatusCode::SERVICE_UNAVAILABLE.into_response(),
+        );
+        let mut failed = StatusCode::BAD_GATEWAY.into_response();
+        failed.extensions_mut().insert(ReportedError(
+            "Failed to fetch manifest (registry-1.docker.io, Timeout): timed out".to_string(),
+        ));
+        report_response(&hub, &context, &failed);
+
+        let envelopes = captured.0.lock().unwrap();
+        let events: Vec<_> = envelopes.iter().filter_map(|e| e.event()).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].message.as_deref(),
+            Some("GET /v2/library/nginx/manifests/latest answered with 503 Service Unavailable")
+        );
+        assert_eq!(
+            events[1].message.as_deref(),
+            Some("Failed to fetch manifest (registry-1.docker.io, Timeout): timed out")
+        );
+        assert_eq!(events[1].tags["http.status_code"], "502");
+        assert_eq!(
+            events[1].tags["request_id"],
+            "00000000-0000-0000-0000-000000000007"
+        );
+        assert_eq!(
+            events[1].user.as_ref().unwrap().id.as_deref(),
+            Some("10.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,196 @@
+/// In-memory cache of small, frequently requested regions of cached blobs
+///
+/// Lazy-pulling snapshotters (eStargz, SOCI) read a layer's table of contents
+/// and then individual files with many small range requests. Ranges up to a
+/// configured size are assembled from fixed-size chunks kept in an LRU in
+/// memory, so hot regions such as the TOC at the end of a layer are served
+/// without reading the blob file again for every request. Blobs are
+/// content-addressed, so a chunk never goes stale.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Mutex;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::config::CacheConfig;
+
+/// Size of the cached regions; ranges are served from whole chunks
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+struct Chunk {
+    data: Bytes,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    chunks: HashMap<(String, u64), Chunk>,
+    tick: u64,
+}
+
+pub struct HotRanges {
+    max_range: u64,
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl HotRanges {
+    /// `None` when the fast path is disabled
+    pub fn from_config(config: &CacheConfig) -> Option<Self> {
+        if !config.enabled || config.hot_range_max_kb == 0 {
+            return None;
+        }
+        let capacity = (config.hot_range_memory_mb * 1024 * 1024 / CHUNK_SIZE) as usize;
+        Some(Self {
+            max_range: config.hot_range_max_kb * 1024,
+            capacity: capacity.max(1),
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Whether a range is small enough for the fast path
+    pub fn accepts(&self, range: &Range<u64>) -> bool {
+        range.end - range.start <= self.max_range
+    }
+
+    /// Read `range` of the blob `digest` stored at `path`
+    pub async fn read(
+        &self,
+        digest: &str,
+        path: &Path,
+        range: Range<u64>,
+        blob_size: u64,
+    ) -> io::Result<Bytes> {
+        let first = range.start / CHUNK_SIZE;
+        let last = (range.end.max(1) - 1) / CHUNK_SIZE;
+
+        let mut chunks: Vec<Option<Bytes>> = {
+            let mut state = self.lock();
+            state.tick += 1;
+            let tick = state.tick;
+            (first..=last)
+                .map(|index| {
+                    let chunk = state.chunks.get_mut(&(digest.to_string(), index))?;
+                    chunk.last_used = tick;
+                    Some(chunk.data.clone())
+                })
+                .collect()
+        };
+
+        let missing: Vec<u64> = (first..=last)
+            .zip(&chunks)
+            .filter(|(_, chunk)| chunk.is_none())
+            .map(|(index, _)| index)
+            .collect();
+        if !missing.is_empty() {
+            let path = path.to_path_buf();
+            let indices = missing.clone();
+            let loaded =
+                tokio::task::spawn_blocking(move || read_chunks(&path, &indices, blob_size))
+                    .await
+                    .map_err(io::Error::other)??;
+            let mut state = self.lock();
+            for (index, data) in missing.into_iter().zip(loaded) {
+                chunks[(index - first) as usize] = Some(data.clone());
+                state.tick += 1;
+                let last_used = state.tick;
+                state
+                    .chunks
+                    .insert((digest.to_string(), index), Chunk { data, last_used });
+            }
+            while state.chunks.len() > self.capacity {
+                let oldest = state
+                    .chunks
+                    .iter()
+                    .min_by_key(|(_, chunk)| chunk.last_used)
+                    .map(|(key, _)| key.clone());
+                match oldest {
+                    Some(key) => state.chunks.remove(&key),
+                    None => break,
+                };
+            }
+        }
+
+        let mut body = BytesMut::with_capacity((range.end - range.start) as usize);
+        for (index, chunk) in (first..=last).zip(chunks.into_iter().flatten()) {
+            let chunk_start = index * CHUNK_SIZE;
+            let from = range.start.saturating_sub(chunk_start) as usize;
+            let to = ((range.end - chunk_start) as usize).min(chunk.len());
+            body.extend_from_slice(&chunk[from..to]);
+        }
+        Ok(body.freeze())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Read whole chunks (the last one may be short) from the blob file
+fn read_chunks(path: &Path, indices: &[u64], blob_size: u64) -> io::Result<Vec<Bytes>> {
+    let mut file = File::open(path)?;
+    indices
+        .iter()
+        .map(|index| {
+            let start = index * CHUNK_SIZE;
+            let len = CHUNK_SIZE.min(blob_size.saturating_sub(start)) as usize;
+            let mut buf = vec![0u8; len];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut buf)?;
+            Ok(Bytes::from(buf))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_ranges() {
+        let path = std::env::temp_dir().join(format!("docker-proxy-hot-{}", uuid::Uuid::new_v4()));
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let size = data.len() as u64;
+
+        let config = CacheConfig {
+            enabled: true,
+            hot_range_max_kb: 256,
+            hot_range_memory_mb: 0,
+            ..CacheConfig::default()
+        };
+        let hot = HotRanges::from_config(&config).unwrap();
+        assert!(hot.accepts(&(0..256 * 1024)));
+        assert!(!hot.accepts(&(0..256 * 1024 + 1)));
+
+        // Spanning a chunk boundary, and the short tail chunk (e.g. a TOC)
+        let range = CHUNK_SIZE - 10..CHUNK_SIZE + 10;
+        let body = hot
+            .read("sha256:a", &path, range.clone(), size)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], &data[range.start as usize..range.end as usize]);
+        let tail = size - 50..size;
+        let body = hot
+            .read("sha256:a", &path, tail.clone(), size)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], &data[tail.start as usize..]);
+
+        // A capacity of one chunk keeps only the most recent
+        assert_eq!(hot.lock().chunks.len(), 1);
+        assert!(hot.lock().chunks.contains_key(&("sha256:a".to_string(), 3)));
+
+        // Served from memory once cached, even if the file is gone
+        std::fs::remove_file(&path).unwrap();
+        let body = hot
+            .read("sha256:a", &path, tail.clone(), size)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], &data[tail.start as usize..]);
+        assert!(hot.read("sha256:a", &path, 0..10, size).await.is_err());
+    }
+}
@@ -0,0 +1,631 @@
+/// Seeding the cache from `docker save` archives and OCI image layouts
+///
+/// The archive is read twice: the first pass collects the small metadata
+/// files, the second streams blobs into the cache with their digests
+/// verified. Imported manifests are pinned under the chosen repository name
+/// and served without contacting the upstream registry.
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::{Value as JsonValue, json};
+
+use crate::cache::{BlobCache, ManifestRef};
+use crate::error::{ProxyError, ProxyResult};
+use crate::prefetch;
+
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const OCI_LAYER_TAR: &str = "application/vnd.oci.image.layer.v1.tar";
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Metadata files larger than this are not read into memory
+const MAX_METADATA_SIZE: u64 = 4 * 1024 * 1024;
+/// Maximum depth of nested image indexes and symlink chains
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub manifests: usize,
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+// Metadata collected by the first pass
+#[derive(Default)]
+struct Listing {
+    files: HashMap<String, Vec<u8>>,
+    symlinks: HashMap<String, String>,
+}
+
+/// Import a tar archive (blocking). `tag` overrides the tags recorded in the
+/// archive, which is only allowed when it contains a single image.
+pub fn import_archive(
+    cache: &BlobCache,
+    archive: &Path,
+    name: &str,
+    tag: Option<&str>,
+) -> ProxyResult<ImportSummary> {
+    let listing = read_listing(archive)
+        .map_err(|e| ProxyError::InvalidArchive(format!("unreadable tar archive: {}", e)))?;
+    let mut summary = ImportSummary {
+        name: name.to_string(),
+        ..Default::default()
+    };
+
+    if listing.files.contains_key("index.json") {
+        import_oci_layout(cache, archive, &listing, tag, &mut summary)?;
+    } else if listing.files.contains_key("manifest.json") {
+        import_docker_archive(cache, archive, &listing, tag, &mut summary)?;
+    } else {
+        return Err(ProxyError::InvalidArchive(
+            "neither index.json nor manifest.json found".to_string(),
+        ));
+    }
+
+    tracing::info!(
+        name = %name,
+        tags = ?summary.tags,
+        manifests = summary.manifests,
+        blobs = summary.blobs,
+        bytes = summary.bytes,
+        "Archive imported into cache"
+    );
+    Ok(summary)
+}
+
+fn import_oci_layout(
+    cache: &BlobCache,
+    archive: &Path,
+    listing: &Listing,
+    tag: Option<&str>,
+    summary: &mut ImportSummary,
+) -> ProxyResult<()> {
+    let index = parse_json(listing, "index.json")?;
+    let descriptors = index
+        .get("manifests")
+        .and_then(|m| m.as_array())
+        .filter(|m| !m.is_empty())
+        .ok_or_else(|| ProxyError::InvalidArchive("index.json lists no manifests".to_string()))?;
+    if tag.is_some() && descriptors.len() > 1 {
+        return Err(ProxyError::InvalidArchive(format!(
+            "archive contains {} images, omit the tag to use their own",
+            descriptors.len()
+        )));
+    }
+
+    for_each_file(archive, |path, entry| {
+        let Some(hex) = path.strip_prefix("blobs/sha256/") else {
+            return Ok(());
+        };
+        let (_, size) = cache.store_blob(Some(&format!("sha256:{}", hex)), entry, false)?;
+        summary.blobs += 1;
+        summary.bytes += size;
+        Ok(())
+    })?;
+
+    for descriptor in descriptors {
+        let (digest, media_type) = descriptor_fields(descriptor)?;
+        pin_tree(
+            cache,
+            &summary.name.clone(),
+            &digest,
+            &media_type,
+            0,
+            summary,
+        )?;
+
+        let archived_tag = descriptor
+            .get("annotations")
+            .and_then(|a| a.get(REF_NAME_ANNOTATION))
+            .and_then(|r| r.as_str())
+            .and_then(tag_from_reference);
+        if let Some(tag) = tag.or(archived_tag) {
+            pin_tag(cache, summary, tag, &digest, &media_type);
+        }
+    }
+    Ok(())
+}
+
+fn import_docker_archive(
+    cache: &BlobCache,
+    archive: &Path,
+    listing: &Listing,
+    tag: Option<&str>,
+    summary: &mut ImportSummary,
+) -> ProxyResult<()> {
+    let images = parse_json(listing, "manifest.json")?;
+    let images = images
+        .as_array()
+        .filter(|i| !i.is_empty())
+        .ok_or_else(|| ProxyError::InvalidArchive("manifest.json lists no images".to_string()))?;
+    if tag.is_some() && images.len() > 1 {
+        return Err(ProxyError::InvalidArchive(format!(
+            "archive contains {} images, omit the tag to use their own",
+            images.len()
+        )));
+    }
+
+    // Layers shared between images are stored as symlinks to the first copy
+    let mut wanted = HashSet::new();
+    for image in images {
+        let paths = image
+            .get("Layers")
+            .and_then(|l| l.as_array())
+            .into_iter()
+            .flatten()
+            .chain(image.get("Config"));
+        for path in paths.filter_map(|p| p.as_str()) {
+            wanted.insert(resolve_symlinks(listing, &normalize(path)));
+        }
+    }
+
+    let mut stored: HashMap<String, (String, u64)> = HashMap::new();
+    for_each_file(archive, |path, entry| {
+        if !wanted.contains(path) {
+            return Ok(());
+        }
+        let (digest, size) = cache.store_blob(None, entry, false)?;
+        summary.blobs += 1;
+        summary.bytes += size;
+        stored.insert(path.to_string(), (digest, size));
+        Ok(())
+    })?;
+
+    let descriptor = |path: &str, media_type: &str| {
+        let path = resolve_symlinks(listing, &normalize(path));
+        stored
+            .get(&path)
+            .map(|(digest, size)| json!({"mediaType": media_type, "digest": digest, "size": size}))
+            .ok_or_else(|| ProxyError::InvalidArchive(format!("missing file '{}'", path)))
+    };
+
+    for image in images {
+        let config = image
+            .get("Config")
+            .and_then(|c| c.as_str())
+            .ok_or_else(|| ProxyError::InvalidArchive("image without Config".to_string()))?;
+        let layers = image
+            .get("Layers")
+            .and_then(|l| l.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|l| l.as_str())
+            .map(|l| descriptor(l, OCI_LAYER_TAR))
+            .collect::<ProxyResult<Vec<_>>>()?;
+
+        // Legacy archives carry no manifest, synthesize an OCI one from the
+        // uncompressed layers
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_MANIFEST,
+            "config": descriptor(config, OCI_CONFIG)?,
+            "layers": layers,
+        });
+        let body =
+            serde_json::to_vec(&manifest).map_err(|e| ProxyError::InternalError(e.to_string()))?;
+        let (digest, _) = cache
+            .store_blob(None, &mut body.as_slice(), false)
+            .map_err(store_error)?;
+        pin_tree(
+            cache,
+            &summary.name.clone(),
+            &digest,
+            OCI_MANIFEST,
+            0,
+            summary,
+        )?;
+
+        let archived_tags: Vec<&str> = image
+            .get("RepoTags")
+            .and_then(|t| t.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|t| t.as_str())
+            .filter_map(tag_from_reference)
+            .collect();
+        let tags = match tag {
+            Some(tag) => vec![tag],
+            None => archived_tags,
+        };
+        for tag in tags {
+            pin_tag(cache, summary, tag, &digest, OCI_MANIFEST);
+        }
+    }
+    Ok(())
+}
+
+// Pin a manifest by digest, descending into image indexes, and record the
+// blobs it references as belonging to `name`. Children missing from the
+// archive (e.g. other platforms) are skipped.
+fn pin_tree(
+    cache: &BlobCache,
+    name: &str,
+    digest: &str,
+    media_type: &str,
+    depth: usize,
+    summary: &mut ImportSummary,
+) -> ProxyResult<()> {
+    let blob = cache.lookup(digest).ok_or_else(|| {
+        ProxyError::InvalidArchive(format!("manifest {} missing from archive", digest))
+    })?;
+    cache.pin_manifest(
+        name,
+        digest,
+        ManifestRef {
+            digest: digest.to_string(),
+            media_type: media_type.to_string(),
+        },
+    );
+    summary.manifests += 1;
+
+    if depth >= MAX_DEPTH {
+        return Ok(());
+    }
+    let manifest: JsonValue = std::fs::read(&blob.path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .ok_or_else(|| ProxyError::InvalidArchive(format!("manifest {} is not JSON", digest)))?;
+    for blob in prefetch::blob_digests(&manifest) {
+        cache.record_repository(&blob, name);
+    }
+    for child in manifest
+        .get("manifests")
+        .and_then(|m| m.as_array())
+        .into_iter()
+        .flatten()
+    {
+        let (child_digest, child_type) = descriptor_fields(child)?;
+        if cache.lookup(&child_digest).is_some() {
+            pin_tree(cache, name, &child_digest, &child_type, depth + 1, summary)?;
+        } else {
+            tracing::debug!(digest = %child_digest, "Skipping manifest not present in archive");
+        }
+    }
+    Ok(())
+}
+
+fn pin_tag(
+    cache: &BlobCache,
+    summary: &mut ImportSummary,
+    tag: &str,
+    digest: &str,
+    media_type: &str,
+) {
+    cache.pin_manifest(
+        &summary.name,
+        tag,
+        ManifestRef {
+            digest: digest.to_string(),
+            media_type: media_type.to_string(),
+        },
+    );
+    if !summary.tags.iter().any(|t| t == tag) {
+        summary.tags.push(tag.to_string());
+    }
+}
+
+fn descriptor_fields(descriptor: &JsonValue) -> ProxyResult<(String, String)> {
+    let digest = descriptor
+        .get("digest")
+        .and_then(|d| d.as_str())
+        .ok_or_else(|| ProxyError::InvalidArchive("descriptor without digest".to_string()))?;
+    let media_type = descriptor
+        .get("mediaType")
+        .and_then(|m| m.as_str())
+        .unwrap_or(OCI_MANIFEST);
+    Ok((digest.to_string(), media_type.to_string()))
+}
+
+/// Tag part of an image reference: "1.27", "nginx:1.27" and
+/// "docker.io/library/nginx:1.27" all yield "1.27"
+fn tag_from_reference(reference: &str) -> Option<&str> {
+    let last_segment = reference.rsplit('/').next()?;
+    let tag = match last_segment.rsplit_once(':') {
+        Some((_, tag)) => tag,
+        None if !reference.contains('/') => reference,
+        None => return None,
+    };
+    (!tag.is_empty() && !tag.contains('@')).then_some(tag)
+}
+
+fn parse_json(listing: &Listing, file: &str) -> ProxyResult<JsonValue> {
+    listing
+        .files
+        .get(file)
+        .and_then(|data| serde_json::from_slice(data).ok())
+        .ok_or_else(|| ProxyError::InvalidArchive(format!("{} is not valid JSON", file)))
+}
+
+// First pass: read top-level metadata files and record symlinks
+fn read_listing(archive: &Path) -> io::Result<Listing> {
+    let mut listing = Listing::default();
+    let mut tar = tar::Archive::new(File::open(archive)?);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = normalize(&entry.path()?.to_string_lossy());
+        match entry.header().entry_type() {
+            tar::EntryType::Symlink => {
+                if let Some(target) = entry.link_name()? {
+                    let parent = path.rsplit_once('/').map(|(p, _)| p).unwrap_or("");
+                    let target = normalize(&format!("{}/{}", parent, target.to_string_lossy()));
+                    listing.symlinks.insert(path, target);
+                }
+            }
+            tar::EntryType::Regular
+                if !path.contains('/')
+                    && (path.ends_with(".json") || path == "oci-layout")
+                    && entry.size() <= MAX_METADATA_SIZE =>
+            {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                listing.files.insert(path, data);
+            }
+            _ => {}
+        }
+    }
+    Ok(listing)
+}
+
+// Second pass: hand every regular file to `f`
+fn for_each_file<F>(archive: &Path, mut f: F) -> ProxyResult<()>
+where
+    F: FnMut(&str, &mut dyn Read) -> io::Result<()>,
+{
+    let file = File::open(archive).map_err(|e| ProxyError::InternalError(e.to_string()))?;
+    let mut tar = tar::Archive::new(file);
+    let entries = tar.entries().map_err(store_error)?;
+    for entry in entries {
+        let mut entry = entry.map_err(store_error)?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = normalize(&entry.path().map_err(store_error)?.to_string_lossy());
+        f(&path, &mut entry).map_err(store_error)?;
+    }
+    Ok(())
+}
+
+fn resolve_symlinks(listing: &Listing, path: &str) -> String {
+    let mut path = path.to_string();
+    for _ in 0..MAX_DEPTH {
+        match listing.symlinks.get(&path) {
+            Some(target) => path = target.clone(),
+            None => break,
+        }
+    }
+    path
+}
+
+// Archive paths without "./" prefixes and with ".." resolved
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn store_error(e: io::Error) -> ProxyError {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof => {
+            ProxyError::InvalidArchive(e.to_string())
+        }
+        _ => ProxyError::InternalError(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+    use crate::config::CacheConfig;
+    use sha2::{Digest, Sha256};
+
+    struct TestEnv {
+        cache: BlobCache,
+        dir: std::path::PathBuf,
+    }
+
+    impl TestEnv {
+        fn new() -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("docker-proxy-import-{}", uuid::Uuid::new_v4()));
+            let cache = BlobCache::open(
+                &CacheConfig {
+                    enabled: true,
+                    dir: dir.to_string_lossy().to_string(),
+                    max_size_mb: 0,
+                    ..CacheConfig::default()
+                },
+                clock::system(),
+                clock::os_random(),
+            )
+            .unwrap();
+            Self { cache, dir }
+        }
+
+        fn archive(
+            &self,
+            files: &[(&str, &[u8])],
+            symlinks: &[(&str, &str)],
+        ) -> std::path::PathBuf {
+            let path = self.dir.join("archive.tar");
+            let mut builder = tar::Builder::new(File::create(&path).unwrap());
+            for (name, data) in files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append_data(&mut header, name, *data).unwrap();
+            }
+            for (name, target) in symlinks {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, name, target).unwrap();
+            }
+            builder.finish().unwrap();
+            path
+        }
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{}", hex::encode(Sha256::digest(data)))
+    }
+
+    #[test]
+    fn test_tag_from_reference() {
+        assert_eq!(tag_from_reference("1.27"), Some("1.27"));
+        assert_eq!(tag_from_reference("nginx:1.27"), Some("1.27"));
+        assert_eq!(
+            tag_from_reference("docker.io/library/nginx:1.27"),
+            Some("1.27")
+        );
+        assert_eq!(tag_from_reference("localhost:5000/app:v1"), Some("v1"));
+        assert_eq!(tag_from_reference("localhost:5000/app"), None);
+    }
+
+    #[test]
+    fn test_import_oci_layout() {
+        let env = TestEnv::new();
+        let config = br#"{"architecture":"amd64","os":"linux"}"#;
+        let layer = b"layer contents";
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"{}","config":{{"mediaType":"{}","digest":"{}","size":{}}},"layers":[{{"mediaType":"{}","digest":"{}","size":{}}}]}}"#,
+            OCI_MANIFEST,
+            OCI_CONFIG,
+            sha256(config),
+            config.len(),
+            OCI_LAYER_TAR,
+            sha256(layer),
+            layer.len()
+        );
+        let manifest_digest = sha256(manifest.as_bytes());
+        let index = format!(
+            r#"{{"schemaVersion":2,"manifests":[{{"mediaType":"{}","digest":"{}","size":{},"annotations":{{"{}":"1.0"}}}}]}}"#,
+            OCI_MANIFEST,
+            manifest_digest,
+            manifest.len(),
+            REF_NAME_ANNOTATION
+        );
+        let blob_path = |d: &str| format!("blobs/sha256/{}", d.trim_start_matches("sha256:"));
+        let archive = env.archive(
+            &[
+                ("oci-layout", br#"{"imageLayoutVersion":"1.0.0"}"#),
+                ("index.json", index.as_bytes()),
+                (&blob_path(&manifest_digest), manifest.as_bytes()),
+                (&blob_path(&sha256(config)), config),
+                (&blob_path(&sha256(layer)), layer),
+            ],
+            &[],
+        );
+
+        let summary = import_archive(&env.cache, &archive, "internal/app", None).unwrap();
+        assert_eq!(summary.tags, vec!["1.0"]);
+        assert_eq!(summary.manifests, 1);
+        assert_eq!(summary.blobs, 3);
+
+        let (pinned, _) = env.cache.lookup_manifest("internal/app", "1.0").unwrap();
+        assert_eq!(pinned.digest, manifest_digest);
+        assert_eq!(pinned.media_type, OCI_MANIFEST);
+        assert!(
+            env.cache
+                .lookup_manifest("internal/app", &manifest_digest)
+                .is_some()
+        );
+        assert!(env.cache.lookup(&sha256(layer)).is_some());
+
+        // explicit tag overrides the archived one
+        let summary = import_archive(&env.cache, &archive, "internal/app", Some("pinned")).unwrap();
+        assert_eq!(summary.tags, vec!["pinned"]);
+    }
+
+    #[test]
+    fn test_import_oci_layout_rejects_corrupt_blob() {
+        let env = TestEnv::new();
+        let index = format!(
+            r#"{{"manifests":[{{"mediaType":"{}","digest":"{}","size":2}}]}}"#,
+            OCI_MANIFEST,
+            sha256(b"{}")
+        );
+        let archive = env.archive(
+            &[
+                ("index.json", index.as_bytes()),
+                (
+                    &format!(
+                        "blobs/sha256/{}",
+                        sha256(b"{}").trim_start_matches("sha256:")
+                    ),
+                    b"tampered",
+                ),
+            ],
+            &[],
+        );
+        assert!(matches!(
+            import_archive(&env.cache, &archive, "internal/app", None),
+            Err(ProxyError::InvalidArchive(_))
+        ));
+    }
+
+    #[test]
+    fn test_import_docker_archive() {
+        let env = TestEnv::new();
+        let config = br#"{"architecture":"amd64","os":"linux"}"#;
+        let layer = b"uncompressed layer";
+        let manifest_json = br#"[
+            {"Config": "cfg.json", "RepoTags": ["nginx:1.27", "nginx:latest"], "Layers": ["aaa/layer.tar", "bbb/layer.tar"]}
+        ]"#;
+        let archive = env.archive(
+            &[
+                ("manifest.json", manifest_json),
+                ("cfg.json", config),
+                ("aaa/layer.tar", layer),
+            ],
+            // duplicate layers are symlinked to the first copy
+            &[("bbb/layer.tar", "../aaa/layer.tar")],
+        );
+
+        let summary = import_archive(&env.cache, &archive, "library/nginx", None).unwrap();
+        assert_eq!(summary.tags, vec!["1.27", "latest"]);
+        assert_eq!(summary.blobs, 2);
+
+        let (pinned, blob) = env
+            .cache
+            .lookup_manifest("library/nginx", "latest")
+            .unwrap();
+        assert_eq!(pinned.media_type, OCI_MANIFEST);
+        let manifest: JsonValue =
+            serde_json::from_slice(&std::fs::read(blob.path).unwrap()).unwrap();
+        assert_eq!(manifest["config"]["digest"], sha256(config));
+        assert_eq!(manifest["layers"][0]["digest"], sha256(layer));
+        assert_eq!(manifest["layers"][1]["digest"], sha256(layer));
+        assert_eq!(manifest["layers"][0]["mediaType"], OCI_LAYER_TAR);
+    }
+
+    #[test]
+    fn test_import_rejects_unknown_archive() {
+        let env = TestEnv::new();
+        let archive = env.archive(&[("hello.txt", b"hi")], &[]);
+        assert!(matches!(
+            import_archive(&env.cache, &archive, "internal/app", None),
+            Err(ProxyError::InvalidArchive(_))
+        ));
+    }
+}
@@ -0,0 +1,7 @@
+//! Library target, for tooling that talks to a running proxy
+//!
+//! Only the typed admin and status API client is exported; the server itself
+//! is the `docker-proxy` binary.
+
+#[cfg(feature = "client")]
+pub mod client;
@@ -0,0 +1,296 @@
+/// Load shedding under resource pressure
+///
+/// The resident memory and open file descriptors of the process are sampled
+/// in the background, and requests being handled are counted. While any of
+/// them is past its `[load_shedding]` threshold, low-priority requests
+/// (catalog and tag listings) are refused with 503 and a `Retry-After`, so
+/// pulls keep being served instead of the process running out of memory or
+/// descriptors. Refusals are counted by cause and exported at `/metrics`.
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::http::Method;
+use axum::response::Response;
+use bytes::Bytes;
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+
+use crate::config::LoadSheddingConfig;
+use crate::router::{self, V2Endpoint};
+
+/// Which threshold a refused request was refused for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pressure {
+    Memory,
+    OpenFiles,
+    InFlight,
+}
+
+impl Pressure {
+    const ALL: [Pressure; 3] = [Pressure::Memory, Pressure::OpenFiles, Pressure::InFlight];
+
+    /// Name used in metrics and logs
+    pub fn label(self) -> &'static str {
+        match self {
+            Pressure::Memory => "memory",
+            Pressure::OpenFiles => "open_files",
+            Pressure::InFlight => "in_flight",
+        }
+    }
+}
+
+pub struct LoadShedder {
+    /// Thresholds; 0 is not checked
+    max_memory_bytes: u64,
+    max_open_files: u64,
+    max_in_flight: usize,
+    retry_after_secs: u64,
+    /// Last sampled values
+    memory_bytes: AtomicU64,
+    open_files: AtomicU64,
+    in_flight: AtomicUsize,
+    /// Refused requests in the order of `Pressure::ALL`
+    shed: [AtomicU64; Pressure::ALL.len()],
+}
+
+impl LoadShedder {
+    pub fn new(config: &LoadSheddingConfig) -> Self {
+        Self {
+            max_memory_bytes: config.max_memory_mb * 1024 * 1024,
+            max_open_files: config.max_open_files,
+            max_in_flight: config.max_in_flight,
+            retry_after_secs: config.retry_after_secs,
+            memory_bytes: AtomicU64::new(0),
+            open_files: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            shed: Default::default(),
+        }
+    }
+
+    /// Sample memory and file descriptor use every `interval`
+    pub fn spawn_sample_task(self: Arc<Self>, interval: Duration) {
+        if self.max_memory_bytes == 0 && self.max_open_files == 0 {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let shedder = Arc::clone(&self);
+                if let Err(e) = tokio::task::spawn_blocking(move || shedder.sample()).await {
+                    tracing::warn!("Load shedding sample task failed: {}", e);
+                }
+            }
+        });
+    }
+
+    fn sample(&self) {
+        if let Some(bytes) = resident_memory_bytes() {
+            self.memory_bytes.store(bytes, Ordering::Relaxed);
+        }
+        if let Some(count) = open_files() {
+            self.open_files.store(count, Ordering::Relaxed);
+        }
+    }
+
+    /// The threshold currently exceeded, if any
+    pub fn pressure(&self) -> Option<Pressure> {
+        let over = |value: u64, max: u64| max > 0 && value >= max;
+        if over(
+            self.memory_bytes.load(Ordering::Relaxed),
+            self.max_memory_bytes,
+        ) {
+            Some(Pressure::Memory)
+        } else if over(self.open_files.load(Ordering::Relaxed), self.max_open_files) {
+            Some(Pressure::OpenFiles)
+        } else if over(
+            self.in_flight.load(Ordering::Relaxed) as u64,
+            self.max_in_flight as u64,
+        ) {
+            Some(Pressure::InFlight)
+        } else {
+            None
+        }
+    }
+
+    /// Count a request as in flight until the returned guard is dropped
+    pub fn start(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(Arc::clone(self))
+    }
+
+    /// Count a request refused for `pressure`
+    pub fn record_shed(&self, pressure: Pressure) {
+        let index = Pressure::ALL
+            .iter()
+            .position(|p| *p == pressure)
+            .unwrap_or_default();
+        self.shed[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Retry-After sent with refused requests
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after_secs
+    }
+
+    /// Render load shedding gauges and counters in the Prometheus text
+    /// exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = format!(
+            "# HELP docker_proxy_in_flight_requests Requests being handled\n\
+             # TYPE docker_proxy_in_flight_requests gauge\n\
+             docker_proxy_in_flight_requests {}\n\
+             # HELP docker_proxy_resident_memory_bytes Resident memory of the process, as last sampled\n\
+             # TYPE docker_proxy_resident_memory_bytes gauge\n\
+             docker_proxy_resident_memory_bytes {}\n\
+             # HELP docker_proxy_open_files Open file descriptors of the process, as last sampled\n\
+             # TYPE docker_proxy_open_files gauge\n\
+             docker_proxy_open_files {}\n\
+             # HELP docker_proxy_load_shed_total Low-priority requests refused under resource pressure\n\
+             # TYPE docker_proxy_load_shed_total counter\n",
+            self.in_flight.load(Ordering::Relaxed),
+            self.memory_bytes.load(Ordering::Relaxed),
+            self.open_files.load(Ordering::Relaxed),
+        );
+        for (pressure, shed) in Pressure::ALL.iter().zip(&self.shed) {
+            metrics.push_str(&format!(
+                "docker_proxy_load_shed_total{{reason=\"{}\"}} {}\n",
+                pressure.label(),
+                shed.load(Ordering::Relaxed)
+            ));
+        }
+        metrics
+    }
+}
+
+/// A request in flight
+pub struct InFlight(Arc<LoadShedder>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// `response` keeping its request in flight until the body is sent or
+/// dropped, so streamed blobs count for as long as they are transferred
+pub fn hold(response: Response, in_flight: InFlight) -> Response {
+    response.map(|body| {
+        Body::new(InFlightBody {
+            body,
+            _in_flight: in_flight,
+        })
+    })
+}
+
+struct InFlightBody {
+    body: Body,
+    _in_flight: InFlight,
+}
+
+impl HttpBody for InFlightBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Whether a request may be refused under pressure: listings, which clients
+/// retry and pulls do not need
+pub fn is_low_priority(method: &Method, path: &str) -> bool {
+    let Some(rest) = path.strip_prefix("/v2/") else {
+        return false;
+    };
+    matches!(
+        router::parse_v2_request(method, rest),
+        V2Endpoint::TagList { .. } | V2Endpoint::Catalog
+    )
+}
+
+// VmRSS from /proc/self/status; None where there is no procfs
+fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+// Entries of /proc/self/fd; None where there is no procfs
+fn open_files() -> Option<u64> {
+    let entries = std::fs::read_dir("/proc/self/fd").ok()?;
+    Some(entries.count() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_shedding() {
+        let shedder = Arc::new(LoadShedder::new(&LoadSheddingConfig {
+            max_memory_mb: 512,
+            max_in_flight: 2,
+            ..LoadSheddingConfig::default()
+        }));
+        let first = shedder.start();
+        assert_eq!(shedder.pressure(), None);
+        let second = shedder.start();
+        assert_eq!(shedder.pressure(), Some(Pressure::InFlight));
+        drop(first);
+        assert_eq!(shedder.pressure(), None);
+
+        // A response body keeps its request in flight until dropped
+        let response = hold(Response::new(Body::from("blob")), second);
+        assert_eq!(shedder.in_flight.load(Ordering::Relaxed), 1);
+        drop(response);
+        assert_eq!(shedder.in_flight.load(Ordering::Relaxed), 0);
+
+        // Memory is checked as last sampled; descriptors are not checked
+        shedder
+            .memory_bytes
+            .store(600 * 1024 * 1024, Ordering::Relaxed);
+        shedder.open_files.store(100_000, Ordering::Relaxed);
+        assert_eq!(shedder.pressure(), Some(Pressure::Memory));
+        shedder.record_shed(Pressure::Memory);
+        let rendered = shedder.to_prometheus();
+        assert!(rendered.contains("docker_proxy_load_shed_total{reason=\"memory\"} 1\n"));
+        assert!(rendered.contains("docker_proxy_in_flight_requests 0\n"));
+
+        #[cfg(target_os = "linux")]
+        {
+            shedder.sample();
+            assert!(shedder.memory_bytes.load(Ordering::Relaxed) < 600 * 1024 * 1024);
+            assert!(shedder.open_files.load(Ordering::Relaxed) > 0);
+        }
+
+        assert!(is_low_priority(&Method::GET, "/v2/library/nginx/tags/list"));
+        assert!(is_low_priority(&Method::GET, "/v2/_catalog"));
+        assert!(!is_low_priority(
+            &Method::GET,
+            "/v2/library/nginx/manifests/latest"
+        ));
+        assert!(!is_low_priority(&Method::GET, "/healthz"));
+    }
+}
@@ -0,0 +1,926 @@
+/// Standalone registry mode (`push_mode = "local"`)
+///
+/// Pushed blobs are written into the blob cache as retained entries and
+/// pushed manifests are pinned under their tag and digest, so pulls of pushed
+/// images are answered by the regular cache paths without an upstream.
+/// Upload sessions only live in memory; their partial data sits in the
+/// cache's `tmp/` directory and is discarded on restart. Since retained
+/// entries are exempt from the cache's `max_size`, uploads are refused with
+/// 413 beyond `max_upload_mb` per blob or `max_pushed_mb` in total.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::cache::{self, BlobCache, ManifestRef};
+use crate::clock::{Clock, Random};
+use crate::config::ProxyConfig;
+use crate::error::{ProxyError, ProxyResult};
+use crate::prefetch;
+use crate::router;
+
+/// Upload sessions idle for longer than this are dropped
+const SESSION_TTL_SECS: u64 = 24 * 60 * 60;
+
+const DEFAULT_MANIFEST_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+struct LocalUpload {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    hasher: Sha256,
+    updated_at: u64,
+}
+
+pub struct LocalRegistry {
+    cache: Arc<BlobCache>,
+    /// Uploads are taken out of the map while a chunk is being appended
+    uploads: Mutex<HashMap<String, LocalUpload>>,
+    /// Size limits in bytes; 0 is not checked
+    max_upload: u64,
+    max_pushed: u64,
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn Random>,
+}
+
+impl LocalRegistry {
+    pub fn new(
+        cache: Arc<BlobCache>,
+        config: &ProxyConfig,
+        clock: Arc<dyn Clock>,
+        random: Arc<dyn Random>,
+    ) -> Self {
+        Self {
+            cache,
+            uploads: Mutex::new(HashMap::new()),
+            max_upload: config.max_upload_mb.saturating_mul(1024 * 1024),
+            max_pushed: config.max_pushed_mb.saturating_mul(1024 * 1024),
+            clock,
+            random,
+        }
+    }
+
+    /// Start an upload session for `name`, returning its UUID
+    pub async fn start_upload(&self, name: &str) -> ProxyResult<String> {
+        let path = self.cache.temp_path();
+        tokio::fs::File::create(&path).await.map_err(|e| {
+            ProxyError::InternalError(format!("failed to create upload file: {}", e))
+        })?;
+
+        let uuid = self.random.uuid().to_string();
+        let expired = {
+            let mut uploads = self.lock();
+            let now = self.clock.now_secs();
+            let expired: Vec<PathBuf> = uploads
+                .values()
+                .filter(|u| now.saturating_sub(u.updated_at) > SESSION_TTL_SECS)
+                .map(|u| u.path.clone())
+                .collect();
+            uploads.retain(|_, u| now.saturating_sub(u.updated_at) <= SESSION_TTL_SECS);
+            uploads.insert(
+                uuid.clone(),
+                LocalUpload {
+                    name: name.to_string(),
+                    path,
+                    size: 0,
+                    hasher: Sha256::new(),
+                    updated_at: now,
+                },
+            );
+            expired
+        };
+        for path in expired {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        Ok(uuid)
+    }
+
+    /// Bytes received so far for an upload of `name`
+    pub fn upload_offset(&self, name: &str, uuid: &str) -> ProxyResult<u64> {
+        self.lock()
+            .get(uuid)
+            .filter(|u| u.name == name)
+            .map(|u| u.size)
+            .ok_or_else(|| ProxyError::UploadUnknown(uuid.to_string()))
+    }
+
+    /// Append a chunk to an upload. `start` (from `Content-Range`) must
+    /// continue at the current offset. Returns the new offset.
+    pub async fn append(
+        &self,
+        name: &str,
+        uuid: &str,
+        start: Option<u64>,
+        body: Body,
+    ) -> ProxyResult<u64> {
+        let mut upload = self.take(name, uuid)?;
+        if let Some(start) = start
+            && start != upload.size
+        {
+            let offset = upload.size;
+            self.put_back(uuid, upload);
+            return Err(ProxyError::UploadRangeInvalid { offset });
+        }
+
+        let result = write_body(&mut upload, body, self.upload_limit()).await;
+        let size = upload.size;
+        self.put_back(uuid, upload);
+        result.map(|_| size)
+    }
+
+    /// Append the final chunk, verify the digest and move the blob into the
+    /// cache. The session ends either way once the body has been received.
+    pub async fn finish(
+        &self,
+        name: &str,
+        uuid: &str,
+        digest: &str,
+        body: Body,
+    ) -> ProxyResult<u64> {
+        if cache::parse_sha256_digest(digest).is_none() {
+            return Err(ProxyError::DigestMismatch {
+                expected: digest.to_string(),
+                actual: "unsupported digest".to_string(),
+            });
+        }
+        let mut upload = self.take(name, uuid)?;
+        if let Err(e) = write_body(&mut upload, body, self.upload_limit()).await {
+            self.put_back(uuid, upload);
+            return Err(e);
+        }
+
+        let actual = format!("sha256:{}", hex::encode(upload.hasher.clone().finalize()));
+        if actual != digest {
+            let _ = tokio::fs::remove_file(&upload.path).await;
+            return Err(ProxyError::DigestMismatch {
+                expected: digest.to_string(),
+                actual,
+            });
+        }
+
+        let cache = Arc::clone(&self.cache);
+        let adopted = digest.to_string();
+        let size = upload.size;
+        tokio::task::spawn_blocking(move || cache.adopt(&adopted, &upload.path, size))
+            .await
+            .map_err(|e| ProxyError::InternalError(e.to_string()))?
+            .map_err(|e| ProxyError::InternalError(format!("failed to store blob: {}", e)))?;
+        self.cache.record_repository(digest, name);
+        Ok(size)
+    }
+
+    /// Store a blob sent in a single request
+    pub async fn put_blob(&self, name: &str, digest: &str, body: Body) -> ProxyResult<u64> {
+        let uuid = self.start_upload(name).await?;
+        self.finish(name, &uuid, digest, body).await
+    }
+
+    /// Size of a blob stored for `name`; blobs pushed to other
+    /// repositories are not visible here
+    pub fn blob_size(&self, name: &str, digest: &str) -> Option<u64> {
+        if !self.cache.belongs_to(digest, name) {
+            return None;
+        }
+        self.cache.lookup(digest).map(|blob| blob.size)
+    }
+
+    /// Make a blob stored for `from` available in `name` too, as a
+    /// cross-repository mount; its size, or `None` when `from` lacks it
+    pub fn mount(&self, name: &str, digest: &str, from: &str) -> Option<u64> {
+        let size = self.blob_size(from, digest)?;
+        self.cache.record_repository(digest, name);
+        Some(size)
+    }
+
+    /// Store a manifest and pin it under `reference` and its digest. Every
+    /// blob and child manifest it references must already be stored for
+    /// `name`.
+    /// Returns the manifest digest.
+    pub async fn put_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        content_type: Option<&str>,
+        body: Bytes,
+    ) -> ProxyResult<String> {
+        let manifest: JsonValue = serde_json::from_slice(&body)
+            .map_err(|e| ProxyError::ManifestInvalid(e.to_string()))?;
+        let children = manifest
+            .get("manifests")
+            .and_then(|m| m.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|child| child.get("digest").and_then(|d| d.as_str()))
+            .map(String::from);
+        for digest in prefetch::blob_digests(&manifest)
+            .into_iter()
+            .chain(children)
+        {
+            if self.blob_size(name, &digest).is_none() {
+                return Err(ProxyError::ManifestBlobUnknown(digest));
+            }
+        }
+
+        let media_type = content_type
+            .or_else(|| manifest.get("mediaType").and_then(|m| m.as_str()))
+            .unwrap_or(DEFAULT_MANIFEST_TYPE)
+            .to_string();
+        if (body.len() as u64) > self.upload_limit() {
+            return Err(ProxyError::UploadTooLarge {
+                limit: self.upload_limit(),
+            });
+        }
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+        if reference.contains(':') && reference != digest {
+            return Err(ProxyError::DigestMismatch {
+                expected: reference.to_string(),
+                actual: digest,
+            });
+        }
+        let cache = Arc::clone(&self.cache);
+        let expected = digest.clone();
+        tokio::task::spawn_blocking(move || {
+            cache.store_blob(Some(&expected), &mut body.as_ref(), true)?;
+            Ok::<_, std::io::Error>(())
+        })
+        .await
+        .map_err(|e| ProxyError::InternalError(e.to_string()))?
+        .map_err(|e| ProxyError::InternalError(format!("failed to store manifest: {}", e)))?;
+        self.cache.record_repository(&digest, name);
+
+        let pin = ManifestRef {
+            digest: digest.clone(),
+            media_type,
+        };
+        if reference != digest {
+            self.cache.pin_manifest(name, reference, pin.clone());
+        }
+        self.cache.pin_manifest(name, &digest, pin);
+        Ok(digest)
+    }
+
+    /// Tags pinned for `name`, sorted
+    pub fn tags(&self, name: &str) -> Vec<String> {
+        let prefix = format!("{}:", name);
+        let mut tags: Vec<String> = self
+            .cache
+            .pinned_manifests()
+            .into_iter()
+            .filter_map(|(key, _)| key.strip_prefix(&prefix).map(String::from))
+            .collect();
+        tags.sort();
+        tags
+    }
+
+    /// Repositories with pinned manifests, sorted
+    pub fn repositories(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .cache
+            .pinned_manifests()
+            .into_iter()
+            .filter_map(|(key, _)| {
+                let end = key.find('@').or_else(|| key.rfind(':'))?;
+                Some(key[..end].to_string())
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Delete a blob, or the pins of a manifest reference
+    pub fn delete(&self, name: &str, endpoint: &str, reference: &str) -> bool {
+        if endpoint == "blobs" {
+            if self.blob_size(name, reference).is_none() {
+                return false;
+            }
+            // still stored for the other repositories it was pushed to
+            if self.cache.drop_repository(reference, name) {
+                self.cache.remove(reference);
+            }
+            true
+        } else {
+            let found = self.cache.lookup_manifest(name, reference).is_some();
+            self.cache.unpin_manifest(name, reference);
+            found
+        }
+    }
+
+    // Size an upload may grow to under both limits. Other uploads in
+    // progress are not counted, so concurrent pushes may overshoot
+    // `max_pushed` by at most what they hold.
+    fn upload_limit(&self) -> u64 {
+        let max_upload = match self.max_upload {
+            0 => u64::MAX,
+            max => max,
+        };
+        match self.max_pushed {
+            0 => max_upload,
+            max => max_upload.min(max.saturating_sub(self.cache.retained_size())),
+        }
+    }
+
+    fn take(&self, name: &str, uuid: &str) -> ProxyResult<LocalUpload> {
+        let mut uploads = self.lock();
+        match uploads.get(uuid) {
+            Some(upload) if upload.name == name => Ok(uploads.remove(uuid).unwrap()),
+            _ => Err(ProxyError::UploadUnknown(uuid.to_string())),
+        }
+    }
+
+    fn put_back(&self, uuid: &str, mut upload: LocalUpload) {
+        upload.updated_at = self.clock.now_secs();
+        self.lock().insert(uuid.to_string(), upload);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, LocalUpload>> {
+        self.uploads.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Append a request body to the upload file, refusing to grow it past
+// `limit` bytes. Bytes written before a failed read still count, so the
+// client can resume from the reported offset.
+async fn write_body(upload: &mut LocalUpload, body: Body, limit: u64) -> ProxyResult<()> {
+    let io_error =
+        |e: std::io::Error| ProxyError::InternalError(format!("upload write failed: {}", e));
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(&upload.path)
+        .await
+        .map_err(io_error)?;
+
+    let mut stream = body.into_data_stream();
+    let mut result = Ok(());
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                result = Err(ProxyError::ResponseReadError(e.to_string()));
+                break;
+            }
+        };
+        if upload.size.saturating_add(chunk.len() as u64) > limit {
+            result = Err(ProxyError::UploadTooLarge { limit });
+            break;
+        }
+        if let Err(e) = file.write_all(&chunk).await {
+            result = Err(io_error(e));
+            break;
+        }
+        upload.hasher.update(&chunk);
+        upload.size += chunk.len() as u64;
+    }
+    file.flush().await.map_err(io_error)?;
+    result
+}
+
+// 进行中的上传会话响应：Location、Range、Docker-Upload-UUID
+fn upload_response(status: StatusCode, name: &str, uuid: &str, offset: u64) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("0"));
+    insert_header(
+        &mut headers,
+        header::LOCATION.as_str(),
+        &format!("/v2/{}/blobs/uploads/{}", name, uuid),
+    );
+    insert_header(
+        &mut headers,
+        header::RANGE.as_str(),
+        &format!("0-{}", offset.saturating_sub(1)),
+    );
+    insert_header(&mut headers, "Docker-Upload-UUID", uuid);
+    (status, headers).into_response()
+}
+
+// 已存储的 blob / manifest：201 Created
+fn created_response(name: &str, endpoint: &str, digest: &str) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("0"));
+    insert_header(
+        &mut headers,
+        header::LOCATION.as_str(),
+        &format!("/v2/{}/{}/{}", name, endpoint, digest),
+    );
+    insert_header(&mut headers, "Docker-Content-Digest", digest);
+    (StatusCode::CREATED, headers).into_response()
+}
+
+// 本地存储失败时的纯文本错误响应
+fn error_response(e: ProxyError) -> Response {
+    let status = match &e {
+        ProxyError::UploadUnknown(_) => StatusCode::NOT_FOUND,
+        ProxyError::UploadRangeInvalid { .. } => StatusCode::RANGE_NOT_SATISFIABLE,
+        ProxyError::UploadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        ProxyError::DigestMismatch { .. }
+        | ProxyError::ManifestInvalid(_)
+        | ProxyError::ManifestBlobUnknown(_)
+        | ProxyError::ResponseReadError(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if status.is_server_error() {
+        tracing::error!("Local registry error: {}", e);
+    } else {
+        tracing::warn!("Rejected local registry request: {}", e);
+    }
+    (status, format!("Error: {}", e)).into_response()
+}
+
+// 初始化上传：mount 命中本地 blob 时直接返回 201，携带 digest 时按单请求上传处理，
+// 否则创建上传会话
+// 调用示例：POST /v2/<name>/blobs/uploads/?mount=sha256:<hex>&from=<repo>
+pub async fn upload_init(local: &LocalRegistry, name: &str, query: &str, body: Body) -> Response {
+    if let Some(digest) = router::query_param(query, "mount")
+        && let Some(from) = router::query_param(query, "from")
+        && local.mount(name, &digest, &from).is_some()
+    {
+        return created_response(name, "blobs", &digest);
+    }
+    if let Some(digest) = router::query_param(query, "digest").filter(|d| !d.is_empty()) {
+        return match local.put_blob(name, &digest, body).await {
+            Ok(_) => created_response(name, "blobs", &digest),
+            Err(e) => error_response(e),
+        };
+    }
+    match local.start_upload(name).await {
+        Ok(uuid) => upload_response(StatusCode::ACCEPTED, name, &uuid, 0),
+        Err(e) => error_response(e),
+    }
+}
+
+// 分块上传：Content-Range 的起点必须等于已接收的字节数
+pub async fn upload_chunk(
+    local: &LocalRegistry,
+    name: &str,
+    uuid: &str,
+    headers: &HeaderMap,
+    body: Body,
+) -> Response {
+    let start = match headers.get(header::CONTENT_RANGE) {
+        Some(value) => match value.to_str().ok().and_then(router::parse_content_range) {
+            Some((start, _)) => Some(start),
+            None => {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "Invalid Content-Range for chunk upload",
+                )
+                    .into_response();
+            }
+        },
+        None => None,
+    };
+    match local.append(name, uuid, start, body).await {
+        Ok(offset) => upload_response(StatusCode::ACCEPTED, name, uuid, offset),
+        Err(e) => error_response(e),
+    }
+}
+
+// 完成上传：校验 digest 后将 blob 移入缓存并标记为保留
+// 调用示例：PUT /v2/<name>/blobs/uploads/<uuid>?digest=sha256:<hex>
+pub async fn upload_complete(
+    local: &LocalRegistry,
+    name: &str,
+    uuid: &str,
+    query: Option<&str>,
+    body: Body,
+) -> Response {
+    let Some(digest) = query.and_then(|q| router::query_param(q, "digest")) else {
+        return (StatusCode::BAD_REQUEST, "Missing digest parameter").into_response();
+    };
+    match local.finish(name, uuid, &digest, body).await {
+        Ok(_) => created_response(name, "blobs", &digest),
+        Err(e) => error_response(e),
+    }
+}
+
+// 查询上传进度：204 + Range
+pub fn upload_status(local: &LocalRegistry, name: &str, uuid: &str) -> Response {
+    match local.upload_offset(name, uuid) {
+        Ok(offset) => upload_response(StatusCode::NO_CONTENT, name, uuid, offset),
+        Err(e) => error_response(e),
+    }
+}
+
+// 推送 manifest：存入缓存并固定在 tag 与 digest 下
+pub async fn put_manifest(
+    local: &LocalRegistry,
+    name: &str,
+    reference: &str,
+    content_type: Option<&str>,
+    body: Bytes,
+) -> Response {
+    match local
+        .put_manifest(name, reference, content_type, body)
+        .await
+    {
+        Ok(digest) => created_response(name, "manifests", &digest),
+        Err(e) => error_response(e),
+    }
+}
+
+// 标签列表：按字典序分页（n / last），还有下一页时返回 Link 头
+// 调用示例：GET /v2/<name>/tags/list?n=100&last=v1.2
+pub fn tag_list(local: &LocalRegistry, name: &str, query: Option<&str>) -> Response {
+    let path = format!("/v2/{}/tags/list", name);
+    let (tags, headers) = page(local.tags(name), &path, query);
+    let body = serde_json::json!({ "name": name, "tags": tags });
+    (StatusCode::OK, headers, body.to_string()).into_response()
+}
+
+// 仓库目录：本地固定了 manifest 的仓库，分页同标签列表
+pub fn catalog(local: &LocalRegistry, query: Option<&str>) -> Response {
+    let (repositories, headers) = page(local.repositories(), "/v2/_catalog", query);
+    let body = serde_json::json!({ "repositories": repositories });
+    (StatusCode::OK, headers, body.to_string()).into_response()
+}
+
+// One page of a sorted list plus JSON and `Link` headers for `path`
+fn page(items: Vec<String>, path: &str, query: Option<&str>) -> (Vec<String>, HeaderMap) {
+    let query = query.unwrap_or_default();
+    let n = router::query_param(query, "n").and_then(|n| n.parse::<usize>().ok());
+    let last = router::query_param(query, "last");
+    let (items, more) = paginate(items, n, last.as_deref());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    if more && let (Some(n), Some(last)) = (n, items.last()) {
+        let next = format!(
+            "<{}?n={}&last={}>; rel=\"next\"",
+            path,
+            n,
+            utf8_percent_encode(last, NON_ALPHANUMERIC)
+        );
+        insert_header(&mut headers, header::LINK.as_str(), &next);
+    }
+    (items, headers)
+}
+
+// Items after `last`, at most `n`; the flag tells whether more follow
+fn paginate(items: Vec<String>, n: Option<usize>, last: Option<&str>) -> (Vec<String>, bool) {
+    let mut rest: Vec<String> = match last {
+        Some(last) => items.into_iter().filter(|t| t.as_str() > last).collect(),
+        None => items,
+    };
+    match n {
+        Some(n) if rest.len() > n => {
+            rest.truncate(n);
+            (rest, true)
+        }
+        _ => (rest, false),
+    }
+}
+
+// HEAD blob：只查本地存储，推送前客户端据此判断是否需要上传
+pub fn head_blob(local: &LocalRegistry, name: &str, digest: &str) -> Response {
+    match local.blob_size(name, digest) {
+        Some(size) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            );
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+            insert_header(&mut headers, "Docker-Content-Digest", digest);
+            (StatusCode::OK, headers).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "Blob not found").into_response(),
+    }
+}
+
+// 删除本地 blob 或 manifest 引用
+pub fn delete(local: &LocalRegistry, name: &str, endpoint: &str, reference: &str) -> Response {
+    if local.delete(name, endpoint, reference) {
+        StatusCode::ACCEPTED.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Not Found").into_response()
+    }
+}
+
+fn insert_header(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, SequentialRandom};
+    use crate::config::CacheConfig;
+
+    struct TempRegistry {
+        dir: PathBuf,
+        registry: LocalRegistry,
+    }
+
+    impl TempRegistry {
+        fn new() -> Self {
+            Self::with_limits(&ProxyConfig::default())
+        }
+
+        fn with_limits(proxy: &ProxyConfig) -> Self {
+            let dir = std::env::temp_dir().join(format!("local-registry-{}", uuid::Uuid::new_v4()));
+            let config = CacheConfig {
+                enabled: true,
+                dir: dir.to_string_lossy().into_owned(),
+                max_size_mb: 0,
+                ..CacheConfig::default()
+            };
+            let cache =
+                Arc::new(BlobCache::open(&config, clock::system(), clock::os_random()).unwrap());
+            Self {
+                dir,
+                registry: LocalRegistry::new(
+                    cache,
+                    proxy,
+                    clock::system(),
+                    Arc::new(SequentialRandom::default()),
+                ),
+            }
+        }
+    }
+
+    impl Drop for TempRegistry {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{}", hex::encode(Sha256::digest(data)))
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload() {
+        let temp = TempRegistry::new();
+        let registry = &temp.registry;
+        let uuid = registry.start_upload("lab/app").await.unwrap();
+
+        let offset = registry
+            .append("lab/app", &uuid, Some(0), Body::from("hello "))
+            .await
+            .unwrap();
+        assert_eq!(offset, 6);
+        assert_eq!(registry.upload_offset("lab/app", &uuid).unwrap(), 6);
+
+        // chunks must continue at the current offset
+        assert!(matches!(
+            registry
+                .append("lab/app", &uuid, Some(3), Body::from("x"))
+                .await,
+            Err(ProxyError::UploadRangeInvalid { offset: 6 })
+        ));
+        // sessions are bound to their repository
+        assert!(matches!(
+            registry.upload_offset("lab/other", &uuid),
+            Err(ProxyError::UploadUnknown(_))
+        ));
+
+        let digest = sha256(b"hello world");
+        let size = registry
+            .finish("lab/app", &uuid, &digest, Body::from("world"))
+            .await
+            .unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(registry.blob_size("lab/app", &digest), Some(11));
+        assert!(registry.upload_offset("lab/app", &uuid).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_digest_mismatch() {
+        let temp = TempRegistry::new();
+        let registry = &temp.registry;
+        let digest = sha256(b"expected");
+        assert!(matches!(
+            registry
+                .put_blob("lab/app", &digest, Body::from("actual"))
+                .await,
+            Err(ProxyError::DigestMismatch { .. })
+        ));
+        assert_eq!(registry.blob_size("lab/app", &digest), None);
+    }
+
+    #[tokio::test]
+    async fn test_upload_limits() {
+        let temp = TempRegistry::with_limits(&ProxyConfig {
+            max_upload_mb: 1,
+            max_pushed_mb: 2,
+            ..ProxyConfig::default()
+        });
+        let registry = &temp.registry;
+        let mib = 1024 * 1024;
+
+        let oversized = vec![0u8; mib + 1];
+        assert!(matches!(
+            registry
+                .put_blob("lab/app", &sha256(&oversized), Body::from(oversized))
+                .await,
+            Err(ProxyError::UploadTooLarge { limit }) if limit == mib as u64
+        ));
+
+        // Pushed content adds up to max_pushed_mb
+        for byte in 1..=2u8 {
+            let blob = vec![byte; mib];
+            let digest = sha256(&blob);
+            registry
+                .put_blob("lab/app", &digest, Body::from(blob))
+                .await
+                .unwrap();
+        }
+        let blob = b"one more".to_vec();
+        assert!(matches!(
+            registry
+                .put_blob("lab/app", &sha256(&blob), Body::from(blob))
+                .await,
+            Err(ProxyError::UploadTooLarge { limit: 0 })
+        ));
+        assert!(matches!(
+            registry
+                .put_manifest("lab/app", "v1", None, Bytes::from_static(b"{}"))
+                .await,
+            Err(ProxyError::UploadTooLarge { limit: 0 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_put_manifest() {
+        let temp = TempRegistry::new();
+        let registry = &temp.registry;
+        let config = br#"{"os":"linux"}"#;
+        let config_digest = sha256(config);
+        let manifest = Bytes::from(format!(
+            r#"{{"schemaVersion":2,"mediaType":"{}","config":{{"digest":"{}"}},"layers":[]}}"#,
+            DEFAULT_MANIFEST_TYPE, config_digest
+        ));
+
+        // referenced blobs must be pushed first
+        assert!(matches!(
+            registry
+                .put_manifest("lab/app", "v1", None, manifest.clone())
+                .await,
+            Err(ProxyError::ManifestBlobUnknown(d)) if d == config_digest
+        ));
+
+        registry
+            .put_blob("lab/app", &config_digest, Body::from(&config[..]))
+            .await
+            .unwrap();
+        let digest = registry
+            .put_manifest("lab/app", "v1", None, manifest.clone())
+            .await
+            .unwrap();
+        assert_eq!(digest, sha256(&manifest));
+
+        let cache = &registry.cache;
+        let (pinned, _) = cache.lookup_manifest("lab/app", "v1").unwrap();
+        assert_eq!(pinned.digest, digest);
+        assert_eq!(pinned.media_type, DEFAULT_MANIFEST_TYPE);
+        assert!(cache.lookup_manifest("lab/app", &digest).is_some());
+
+        assert_eq!(registry.tags("lab/app"), vec!["v1"]);
+        assert_eq!(registry.repositories(), vec!["lab/app"]);
+        assert!(registry.tags("lab").is_empty());
+
+        assert!(registry.delete("lab/app", "manifests", "v1"));
+        assert!(cache.lookup_manifest("lab/app", "v1").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_blobs_belong_to_their_repository() {
+        let temp = TempRegistry::new();
+        let registry = &temp.registry;
+        let secret = b"secret layer";
+        let digest = sha256(secret);
+        registry
+            .put_blob("private/app", &digest, Body::from(&secret[..]))
+            .await
+            .unwrap();
+
+        // other repositories cannot see, reference or delete it by digest
+        assert_eq!(registry.blob_size("team/app", &digest), None);
+        assert_eq!(registry.mount("team/app", &digest, "team/base"), None);
+        let manifest = Bytes::from(format!(
+            r#"{{"schemaVersion":2,"config":{{"digest":"{}"}},"layers":[]}}"#,
+            digest
+        ));
+        assert!(matches!(
+            registry
+                .put_manifest("team/app", "v1", None, manifest.clone())
+                .await,
+            Err(ProxyError::ManifestBlobUnknown(_))
+        ));
+        assert!(!registry.delete("team/app", "blobs", &digest));
+
+        // a mount from a repository holding it shares the blob
+        assert_eq!(
+            registry.mount("team/app", &digest, "private/app"),
+            Some(secret.len() as u64)
+        );
+        assert!(
+            registry
+                .put_manifest("team/app", "v1", None, manifest)
+                .await
+                .is_ok()
+        );
+        // deleting it from one repository keeps it for the other
+        assert!(registry.delete("team/app", "blobs", &digest));
+        assert_eq!(registry.blob_size("team/app", &digest), None);
+        assert_eq!(
+            registry.blob_size("private/app", &digest),
+            Some(secret.len() as u64)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_artifact_manifest() {
+        let temp = TempRegistry::new();
+        let registry = &temp.registry;
+        let oci_manifest = "application/vnd.oci.image.manifest.v1+json";
+
+        // Helm chart: OCI image manifest with its own config and layer types
+        let config = br#"{"name":"chart","version":"0.1.0"}"#;
+        let chart = b"chart-tgz";
+        for blob in [&config[..], &chart[..]] {
+            registry
+                .put_blob("charts/app", &sha256(blob), Body::from(blob.to_vec()))
+                .await
+                .unwrap();
+        }
+        let manifest = Bytes::from(format!(
+            r#"{{"schemaVersion":2,"mediaType":"{}","artifactType":"application/vnd.cncf.helm.config.v1+json","config":{{"mediaType":"application/vnd.cncf.helm.config.v1+json","digest":"{}"}},"layers":[{{"mediaType":"application/vnd.cncf.helm.chart.content.v1.tar+gzip","digest":"{}"}}]}}"#,
+            oci_manifest,
+            sha256(config),
+            sha256(chart)
+        ));
+        let digest = registry
+            .put_manifest("charts/app", "0.1.0", Some(oci_manifest), manifest.clone())
+            .await
+            .unwrap();
+        let (pinned, blob) = registry
+            .cache
+            .lookup_manifest("charts/app", "0.1.0")
+            .unwrap();
+        assert_eq!(pinned.digest, digest);
+        assert_eq!(pinned.media_type, oci_manifest);
+        assert_eq!(std::fs::read(blob.path).unwrap(), manifest);
+
+        // Artifact manifest: its blobs must exist, the subject need not
+        let sbom = sha256(b"sbom");
+        let artifact = Bytes::from(format!(
+            r#"{{"mediaType":"application/vnd.oci.artifact.manifest.v1+json","artifactType":"application/spdx+json","blobs":[{{"digest":"{}"}}],"subject":{{"digest":"{}"}}}}"#,
+            sbom,
+            sha256(b"unknown image")
+        ));
+        assert!(matches!(
+            registry
+                .put_manifest("charts/app", "sbom", None, artifact.clone())
+                .await,
+            Err(ProxyError::ManifestBlobUnknown(d)) if d == sbom
+        ));
+        registry
+            .put_blob("charts/app", &sbom, Body::from("sbom"))
+            .await
+            .unwrap();
+        registry
+            .put_manifest("charts/app", "sbom", None, artifact)
+            .await
+            .unwrap();
+        let (pinned, _) = registry
+            .cache
+            .lookup_manifest("charts/app", "sbom")
+            .unwrap();
+        assert_eq!(
+            pinned.media_type,
+            "application/vnd.oci.artifact.manifest.v1+json"
+        );
+    }
+
+    #[test]
+    fn test_paginate() {
+        let tags: Vec<String> = ["a", "b", "c", "d"].iter().map(|t| t.to_string()).collect();
+        assert_eq!(paginate(tags.clone(), None, None), (tags.clone(), false));
+        assert_eq!(
+            paginate(tags.clone(), Some(2), None),
+            (vec!["a".to_string(), "b".to_string()], true)
+        );
+        assert_eq!(
+            paginate(tags.clone(), Some(2), Some("b")),
+            (vec!["c".to_string(), "d".to_string()], false)
+        );
+        assert_eq!(paginate(tags, Some(2), Some("d")), (vec![], false));
+    }
+}
@@ -1,34 +1,52 @@
 use std::fs;
-use std::path::Path;
-use tracing_subscriber::EnvFilter;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime as FileTime};
 use tracing_subscriber::fmt::time::SystemTime;
-use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, reload};
 
-use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
 
-/// Logger initialization from config
+use crate::config::{AccessLogFormat, LogConfig, LogRotation};
+use crate::telemetry;
+
+tokio::task_local! {
+    /// ID of the request being handled, shared between the access log and
+    /// error responses so users can quote it when reporting a failure
+    pub static REQUEST_ID: uuid::Uuid;
+}
+
+/// The ID of the request handled by the current task, if any
+pub fn current_request_id() -> Option<uuid::Uuid> {
+    REQUEST_ID.try_with(|id| *id).ok()
+}
+
+// The access log, once opened by `init_access_log`
+static ACCESS_LOG: OnceLock<(NonBlocking, AccessLogFormat)> = OnceLock::new();
+
+// Handle to replace the level filter of the logger at runtime
+type FilterHandle = reload::Handle<EnvFilter, Layered<Option<telemetry::Layer>, Registry>>;
+static FILTER: OnceLock<FilterHandle> = OnceLock::new();
+
+// How often rotated log files are checked against the retention limits
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Logger initialization from config; `telemetry` exports spans alongside
 pub fn init_logger(
     log_file_path: &str,
     log_level: &str,
+    rotation: LogRotation,
+    telemetry: Option<telemetry::Layer>,
 ) -> Result<Option<WorkerGuard>, Box<dyn std::error::Error>> {
-    // Create log directory if it doesn't exist
-    if let Some(parent) = Path::new(log_file_path).parent()
-        && !parent.as_os_str().is_empty()
-    {
-        fs::create_dir_all(parent)?;
-    }
-
     // Parse log level
     let level = parse_log_level(log_level);
 
     // Create file appender for non-blocking writes
-    let file = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(log_file_path)?;
-
-    let (non_blocking, guard) = tracing_appender::non_blocking(file);
+    let (non_blocking, guard) = open_writer(log_file_path, rotation)?;
 
     // Create file layer with timestamp (JSON format)
     let file_layer = tracing_subscriber::fmt::layer()
@@ -52,9 +70,12 @@ pub fn init_logger(
     let env_filter = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new(level.as_str()))
         .unwrap_or_else(|_| EnvFilter::new("info"));
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    let _ = FILTER.set(handle);
 
     // Combine layers and set as global subscriber
     tracing_subscriber::registry()
+        .with(telemetry)
         .with(env_filter)
         .with(file_layer)
         .with(console_layer)
@@ -63,17 +84,55 @@ pub fn init_logger(
     Ok(Some(guard))
 }
 
+// Non-blocking writer for a log file, creating its directory; rotated files
+// are named "<file>.<date>" next to the configured path
+fn open_writer(
+    log_file_path: &str,
+    rotation: LogRotation,
+) -> Result<(NonBlocking, WorkerGuard), Box<dyn std::error::Error>> {
+    if let Some(parent) = Path::new(log_file_path).parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(match rolling_rotation(rotation) {
+        Some(rotation) => {
+            let path = Path::new(log_file_path);
+            let file_name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .ok_or("Log file path has no file name")?;
+            let appender = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(file_name)
+                .build(log_dir(path))?;
+            tracing_appender::non_blocking(appender)
+        }
+        None => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file_path)?;
+            tracing_appender::non_blocking(file)
+        }
+    })
+}
+
 /// Initialize logger with console output only (useful for development)
 pub fn init_logger_console(
     log_level: &str,
+    telemetry: Option<telemetry::Layer>,
 ) -> Result<Option<WorkerGuard>, Box<dyn std::error::Error>> {
     let level = parse_log_level(log_level);
 
     let env_filter = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new(level.as_str()))
         .unwrap_or_else(|_| EnvFilter::new("info"));
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    let _ = FILTER.set(handle);
 
     tracing_subscriber::registry()
+        .with(telemetry)
         .with(env_filter)
         .with(
             tracing_subscriber::fmt::layer()
@@ -87,6 +146,202 @@ pub fn init_logger_console(
     Ok(None)
 }
 
+/// Change the level of the logger, replacing any RUST_LOG filter
+pub fn set_level(log_level: &str) -> Result<(), String> {
+    let handle = FILTER.get().ok_or("Logger not initialized")?;
+    handle
+        .reload(EnvFilter::new(parse_log_level(log_level)))
+        .map_err(|e| e.to_string())
+}
+
+/// Open the access log configured in `[log]`, if any; it is written by the
+/// request middleware and flushed when the guard is dropped
+pub fn init_access_log(
+    config: &LogConfig,
+) -> Result<Option<WorkerGuard>, Box<dyn std::error::Error>> {
+    if config.access_log_path.is_empty() {
+        return Ok(None);
+    }
+    let (writer, guard) = open_writer(&config.access_log_path, config.rotation)?;
+    ACCESS_LOG
+        .set((writer, config.access_log_format))
+        .map_err(|_| "Access log already initialized")?;
+    Ok(Some(guard))
+}
+
+/// One request, as written to the access log
+pub struct AccessEntry<'a> {
+    pub request_id: uuid::Uuid,
+    /// Client identifier, pseudonymised per `[privacy]`
+    pub client: &'a str,
+    /// Unix seconds when the request arrived
+    pub timestamp: u64,
+    pub method: &'a str,
+    /// Path and query
+    pub target: &'a str,
+    pub version: axum::http::Version,
+    pub status: u16,
+    /// Response Content-Length, when known
+    pub bytes: Option<u64>,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub duration_ms: f64,
+}
+
+/// Write a request to the access log, if one is configured
+pub fn write_access_log(entry: &AccessEntry) {
+    let Some((writer, format)) = ACCESS_LOG.get() else {
+        return;
+    };
+    let mut line = match format {
+        AccessLogFormat::Combined => combined_line(entry),
+        AccessLogFormat::Json => json_line(entry),
+    };
+    line.push('\n');
+    // the writer only queues the line for the logging thread
+    let _ = io::Write::write_all(&mut writer.clone(), line.as_bytes());
+}
+
+// `client - - [17/Oct/2026:13:55:36 +0000] "GET /v2/ HTTP/1.1" 200 2 "-" "docker/27.0"`
+fn combined_line(entry: &AccessEntry) -> String {
+    // "Sat, 17 Oct 2026 13:55:36 GMT"
+    let date =
+        httpdate::fmt_http_date(std::time::UNIX_EPOCH + Duration::from_secs(entry.timestamp));
+    let time = match date.split(' ').collect::<Vec<_>>()[..] {
+        [_, day, month, year, time, _] => format!("{}/{}/{}:{} +0000", day, month, year, time),
+        _ => date,
+    };
+    let quoted = |value: Option<&str>| match value {
+        Some(value) => value.replace('\\', "\\\\").replace('"', "\\\""),
+        None => "-".to_string(),
+    };
+    format!(
+        "{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\"",
+        entry.client,
+        time,
+        entry.method,
+        quoted(Some(entry.target)),
+        entry.version,
+        entry.status,
+        entry.bytes.map_or("-".to_string(), |b| b.to_string()),
+        quoted(entry.referer),
+        quoted(entry.user_agent),
+    )
+}
+
+fn json_line(entry: &AccessEntry) -> String {
+    serde_json::json!({
+        "timestamp": entry.timestamp,
+        "request_id": entry.request_id.to_string(),
+        "client": entry.client,
+        "method": entry.method,
+        "target": entry.target,
+        "protocol": format!("{:?}", entry.version),
+        "status": entry.status,
+        "bytes": entry.bytes,
+        "duration_ms": (entry.duration_ms * 100.0).round() / 100.0,
+        "referer": entry.referer,
+        "user_agent": entry.user_agent,
+    })
+    .to_string()
+}
+
+/// Periodically prune rotated log files according to the retention limits
+pub fn spawn_retention_task(config: LogConfig) {
+    if !config.has_retention() || config.rotation == LogRotation::Never {
+        return;
+    }
+    let max_age =
+        (config.max_age_days > 0).then(|| Duration::from_secs(config.max_age_days * 86400));
+    let max_total_bytes = config.max_total_mb * 1024 * 1024;
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RETENTION_INTERVAL);
+        loop {
+            ticker.tick().await;
+            // the access log is rotated alongside and has the same limits
+            for path in [&config.log_file_path, &config.access_log_path] {
+                if path.is_empty() {
+                    continue;
+                }
+                let path = path.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    prune_rotated_logs(Path::new(&path), max_age, max_total_bytes)
+                })
+                .await;
+                match result {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(removed)) => tracing::info!("Removed {} rotated log files", removed),
+                    Ok(Err(e)) => tracing::warn!("Failed to prune rotated log files: {}", e),
+                    Err(e) => tracing::warn!("Log retention task failed: {}", e),
+                }
+            }
+        }
+    });
+}
+
+/// Delete rotated files of `log_file_path` that are older than `max_age` or,
+/// oldest first, exceed `max_total_bytes` together (0 = unlimited). The
+/// newest file is the one being written and is always kept.
+pub fn prune_rotated_logs(
+    log_file_path: &Path,
+    max_age: Option<Duration>,
+    max_total_bytes: u64,
+) -> io::Result<usize> {
+    let Some(file_name) = log_file_path.file_name().and_then(|n| n.to_str()) else {
+        return Ok(0);
+    };
+    let prefix = format!("{}.", file_name);
+
+    let mut files: Vec<(PathBuf, FileTime, u64)> = Vec::new();
+    for entry in fs::read_dir(log_dir(log_file_path))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !name.to_str().is_some_and(|n| n.starts_with(&prefix)) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push((entry.path(), metadata.modified()?, metadata.len()));
+        }
+    }
+    // Newest first
+    files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| b.0.cmp(&a.0)));
+
+    let now = FileTime::now();
+    let mut total = 0u64;
+    let mut removed = 0;
+    for (index, (path, modified, size)) in files.into_iter().enumerate() {
+        total += size;
+        if index == 0 {
+            continue;
+        }
+        let expired =
+            max_age.is_some_and(|max| now.duration_since(modified).unwrap_or_default() > max);
+        let over_size = max_total_bytes > 0 && total > max_total_bytes;
+        if expired || over_size {
+            fs::remove_file(&path)?;
+            total -= size;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn rolling_rotation(rotation: LogRotation) -> Option<Rotation> {
+    match rotation {
+        LogRotation::Never => None,
+        LogRotation::Hourly => Some(Rotation::HOURLY),
+        LogRotation::Daily => Some(Rotation::DAILY),
+    }
+}
+
+fn log_dir(log_file_path: &Path) -> &Path {
+    match log_file_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
 /// Parse log level string to tracing Level
 fn parse_log_level(level: &str) -> String {
     match level.to_lowercase().as_str() {
@@ -98,3 +353,83 @@ fn parse_log_level(level: &str) -> String {
         _ => "info".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_rotated_logs() {
+        let dir = std::env::temp_dir().join(format!("docker-proxy-logs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("proxy.log");
+        let day = Duration::from_secs(86400);
+        let now = FileTime::now();
+        for (suffix, age_days) in [
+            ("2026-01-04", 0),
+            ("2026-01-03", 1),
+            ("2026-01-02", 2),
+            ("2026-01-01", 3),
+        ] {
+            let path = dir.join(format!("proxy.log.{}", suffix));
+            fs::write(&path, vec![0u8; 100]).unwrap();
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(now - day * age_days).unwrap();
+        }
+        fs::write(dir.join("other.log.2026-01-01"), b"x").unwrap();
+
+        // Older than 2.5 days
+        let removed = prune_rotated_logs(&log_path, Some(day * 5 / 2), 0).unwrap();
+        assert_eq!(removed, 1);
+        assert!(!dir.join("proxy.log.2026-01-01").exists());
+
+        // Two newest fit in 250 bytes
+        let removed = prune_rotated_logs(&log_path, None, 250).unwrap();
+        assert_eq!(removed, 1);
+        assert!(dir.join("proxy.log.2026-01-03").exists());
+        assert!(!dir.join("proxy.log.2026-01-02").exists());
+
+        // The active file is kept even when it alone is over the limit
+        let removed = prune_rotated_logs(&log_path, Some(Duration::ZERO), 10).unwrap();
+        assert_eq!(removed, 1);
+        assert!(dir.join("proxy.log.2026-01-04").exists());
+        assert!(dir.join("other.log.2026-01-01").exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_access_log_lines() {
+        let entry = AccessEntry {
+            request_id: uuid::Uuid::nil(),
+            client: "10.0.0.1",
+            timestamp: 1_760_709_336,
+            method: "GET",
+            target: "/v2/library/nginx/manifests/latest",
+            version: axum::http::Version::HTTP_11,
+            status: 200,
+            bytes: Some(1024),
+            referer: None,
+            user_agent: Some("docker/27.0 \"go\""),
+            duration_ms: 12.345,
+        };
+        assert_eq!(
+            combined_line(&entry),
+            "10.0.0.1 - - [17/Oct/2025:13:55:36 +0000] \
+             \"GET /v2/library/nginx/manifests/latest HTTP/1.1\" 200 1024 \"-\" \"docker/27.0 \\\"go\\\"\""
+        );
+        let json: serde_json::Value = serde_json::from_str(&json_line(&entry)).unwrap();
+        assert_eq!(json["protocol"], "HTTP/1.1");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["bytes"], 1024);
+        assert_eq!(json["duration_ms"], 12.35);
+        assert_eq!(json["referer"], serde_json::Value::Null);
+        assert_eq!(json["user_agent"], "docker/27.0 \"go\"");
+
+        let head = AccessEntry {
+            bytes: None,
+            ..entry
+        };
+        assert!(combined_line(&head).contains("\" 200 - \""));
+    }
+}
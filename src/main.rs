@@ -1,44 +1,186 @@
 use axum::{
     Router,
-    extract::Request,
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderValue, StatusCode, header},
     middleware::{self, Next},
-    response::Response,
-    routing::{get, head, post, put},
+    response::{IntoResponse, Response},
+    routing::{delete, get, head, patch, post, put},
 };
+use sentry::SentryFutureExt;
 use std::sync::Arc;
-use tower_http::compression::CompressionLayer;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{Instrument, info};
 
+mod acr;
 mod api;
+mod api_keys;
+mod args;
+mod auth;
+mod auth_monitor;
+mod bench_server;
+mod cache;
+mod chain;
+#[cfg(feature = "client")]
+mod cli;
+mod client_auth;
+mod clock;
+mod compression;
 mod config;
+mod config_fragments;
+mod connections;
+mod credential_helper;
+mod diagnose;
+mod docker_config;
+mod ecr;
+mod egress;
+mod env_overrides;
 mod error;
+mod error_reporting;
+mod hot_ranges;
+mod import;
+mod load_shedding;
+mod local_registry;
 mod log;
+mod maintenance;
+mod oidc;
+mod policy;
+mod prefetch;
+mod privacy;
 mod proxy;
+mod proxy_protocol;
+mod pull_stats;
+mod quotas;
 mod range;
+mod rate_limits;
+mod request_rates;
+mod resolve;
+mod restart;
 mod router;
+mod runtime_config;
+mod sample_config;
+mod secrets;
+mod shadow;
+mod signing;
+mod spill;
 mod static_files;
-use config::Config;
-use log::{init_logger, init_logger_console};
+mod telemetry;
+mod tls;
+mod tls_listener;
+mod trust;
+mod uploads;
+mod upstream_metrics;
+mod watch;
+use args::Args;
+use clap::Parser;
+use config::PolicyOperation;
+use log::{init_logger, init_logger_console, spawn_retention_task};
 use proxy::DockerProxy;
 use static_files::{serve_root, serve_static};
+use telemetry::Telemetry;
 
 #[tokio::main]
 async fn main() {
-    // Load configuration
-    let config = Config::from_file("/config/config.toml")
-        .or_else(|_| Config::from_file("./config/config.toml"))
-        .expect("Failed to load configuration");
+    let args = Args::parse();
 
-    // Initialize logger based on configuration
-    let _guard = init_logger(config.log_file_path(), &config.log_level_normalized())
-        .or_else(|_| init_logger_console(&config.log_level_normalized()))
-        .expect("Failed to initialize logger");
+    // 基准测试模式：作为合成上游 registry 运行
+    if let Some(addr) = &args.bench_server {
+        let _guard = init_logger_console("info", None).expect("Failed to initialize logger");
+        bench_server::run(addr)
+            .await
+            .expect("Benchmark registry failed");
+        return;
+    }
+
+    match &args.command {
+        // 生成带注释的示例配置
+        Some(args::Command::Init(init)) => std::process::exit(sample_config::run(&args, init)),
+        // 运维子命令：通过管理 API 操作正在运行的实例
+        #[cfg(feature = "client")]
+        Some(args::Command::Admin(command)) => std::process::exit(cli::run(&args, command).await),
+        None => {}
+    }
+
+    // Load configuration (--config, or the default locations), with command line overrides
+    let config = args.load_config().expect("Failed to load configuration");
+    let scratch_dir = config.scratch_dir();
+
+    // 配置了 [sentry] dsn 时上报 5xx 响应与 panic
+    let sentry_guard = error_reporting::init(&config.sentry);
+
+    // Initialize logger based on configuration (the dev profile logs to the console)
+    let telemetry = Telemetry::from_config(&config.telemetry);
+    let layer = || telemetry.as_ref().ok()?.as_ref().map(Telemetry::layer);
+    let _guard = if config.logs_to_console() {
+        init_logger_console(&config.log_level_normalized(), layer())
+    } else {
+        init_logger(
+            config.log_file_path(),
+            &config.log_level_normalized(),
+            config.log.rotation,
+            layer(),
+        )
+        .or_else(|_| init_logger_console(&config.log_level_normalized(), layer()))
+    }
+    .expect("Failed to initialize logger");
 
     info!("Docker Registry Proxy starting");
     info!("Configuration: {}", config.to_display_string());
+    // 导出失败不影响代理本身，仅记录错误
+    let telemetry = match telemetry {
+        Ok(Some(telemetry)) => {
+            info!("Exporting traces to {}", config.telemetry.endpoint);
+            Some(telemetry)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            tracing::error!("Trace export disabled: {}", e);
+            None
+        }
+    };
+    if sentry_guard.is_some() {
+        info!("Reporting server errors and panics to Sentry");
+    }
+
+    // 访问日志独立于应用日志；打开失败时仅记录错误
+    let _access_log_guard = match log::init_access_log(&config.log) {
+        Ok(guard) => guard,
+        Err(e) => {
+            tracing::error!("Failed to open access log: {}", e);
+            None
+        }
+    };
+    spawn_retention_task(config.log.clone());
 
     let proxy = Arc::new(DockerProxy::new(&config));
+    Arc::clone(&proxy).spawn_health_check_task();
+    if let Some(cache) = proxy.cache() {
+        Arc::clone(cache).spawn_flush_task(std::time::Duration::from_secs(
+            config.cache.index_flush_secs,
+        ));
+    }
+    if let Some(stats) = proxy.pull_stats() {
+        Arc::clone(stats).spawn_flush_task(std::time::Duration::from_secs(config.stats.flush_secs));
+    }
+    if let Some(auth) = proxy.client_auth()
+        && config.client_auth.htpasswd_reload_secs > 0
+    {
+        Arc::clone(auth).spawn_reload_task(std::time::Duration::from_secs(
+            config.client_auth.htpasswd_reload_secs,
+        ));
+    }
+    if let Some(quotas) = proxy.quotas() {
+        Arc::clone(quotas)
+            .spawn_flush_task(std::time::Duration::from_secs(config.quotas.flush_secs));
+    }
+    if let Some(shedder) = proxy.load_shedder() {
+        Arc::clone(shedder).spawn_sample_task(std::time::Duration::from_millis(
+            config.load_shedding.sample_interval_ms,
+        ));
+    }
+    if config.watch.is_enabled() {
+        watch::TagWatcher::new(Arc::clone(&proxy), config.watch.clone()).spawn();
+    }
 
     // 构建路由
     let app = Router::new()
@@ -46,6 +188,41 @@ async fn main() {
         .route("/healthz", get(api::healthz))
         // 调试：查看 manifest size vs 实际 blob 大小
         .route("/debug/blob-info", get(api::debug_blob_info))
+        // 连通性诊断：DNS / TCP / TLS / /v2/ 各阶段耗时
+        .route("/admin/diagnose", get(api::admin_diagnose))
+        // 按仓库 / tag 的拉取统计
+        .route("/admin/stats/pulls", get(api::admin_pull_stats))
+        // 离线导入镜像归档到缓存
+        .route("/admin/import", post(api::admin_import))
+        // 预取 / 清除整个镜像
+        .route("/admin/prefetch", post(api::admin_prefetch))
+        .route("/admin/cache/purge", post(api::admin_purge))
+        // 当前生效的配置；运行时调整日志级别、缓存上限与拉取配额
+        .route(
+            "/admin/config",
+            get(api::admin_config).patch(api::admin_patch_config),
+        )
+        // 维护模式：查看 / 开启 / 关闭
+        .route(
+            "/admin/maintenance",
+            get(api::maintenance_status).post(api::admin_maintenance),
+        )
+        // 内置 token 服务（[client_auth] token_service）
+        .route("/token", get(api::token))
+        // 上游认证失败统计
+        .route("/api/auth/status", get(api::auth_status))
+        .route("/api/uploads", get(api::uploads_status))
+        // 运行统计与缓存内容
+        .route("/api/stats", get(api::stats))
+        .route("/api/stats/export", get(api::stats_export))
+        // Prometheus 指标：缓存节省的上游流量、上游延迟与错误
+        .route("/metrics", get(api::metrics))
+        .route("/api/quotas", get(api::quotas_status))
+        .route("/api/cache", get(api::cache_contents))
+        // Web 界面实时面板
+        .route("/api/dashboard", get(api::dashboard))
+        // 候选配置影子评估报告
+        .route("/api/shadow", get(api::shadow_report))
         // static web files served at root (handler below). API routes (/v2/*) are registered earlier.
         .route("/{*file}", get(serve_static))
         // serve web UI at root without redirect
@@ -57,47 +234,353 @@ async fn main() {
         .route("/v2/{*rest}", head(api::v2_head))
         .route("/v2/{*rest}", post(api::v2_post))
         .route("/v2/{*rest}", put(api::v2_put))
-        .layer(middleware::from_fn(log_middleware))
-        .layer(CompressionLayer::new())
+        .route("/v2/{*rest}", patch(api::v2_patch))
+        .route("/v2/{*rest}", delete(api::v2_delete))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&proxy),
+            quota_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&proxy),
+            client_auth_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&proxy),
+            api_key_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&proxy),
+            load_shedding_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&proxy),
+            log_middleware,
+        ))
+        .layer(middleware::from_fn(registry_response_middleware))
+        // blob 本身已压缩，且需保持 Content-Length / Range 语义（级联时也不会二次压缩）
+        .layer(compression::layer(&config.compression))
         .layer(TraceLayer::new_for_http())
-        .with_state(proxy);
+        .with_state(Arc::clone(&proxy));
+
+    // 挂载到路径前缀下（位于已有 ingress 路径之后），响应中的 Location / Link 等同步加前缀；
+    // 带或不带结尾斜杠的前缀都返回 Web 界面
+    let app = match config.server.path_prefix() {
+        "" => app,
+        prefix => Router::new()
+            .route(
+                &format!("{}/", prefix),
+                get({
+                    let proxy = Arc::clone(&proxy);
+                    move || serve_root(State(proxy))
+                }),
+            )
+            .nest(prefix, app)
+            .layer(middleware::from_fn_with_state(
+                Arc::clone(&proxy),
+                path_prefix_middleware,
+            )),
+    };
 
-    let listener = tokio::net::TcpListener::bind(config.server_addr())
+    // 热重启时从旧进程接管监听 socket，也可由 systemd socket activation 或 listen_fd 传入
+    let listener = restart::bind(&config.server_addr(), config.server.listen_fd)
         .await
         .expect("Failed to bind to address");
+    let listen_addr = listener
+        .local_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| config.server_addr());
+    let handed_over = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    let warm_restart = warm_restart(
+        Arc::clone(&proxy),
+        std::os::fd::AsRawFd::as_raw_fd(&listener),
+        Arc::clone(&handed_over),
+    );
+    #[cfg(not(unix))]
+    let warm_restart = std::future::pending::<()>();
 
     info!(
-        "Docker Registry Proxy listening on http://{}",
-        config.server_addr()
+        "Docker Registry Proxy listening on {}://{}",
+        if config.server.tls.is_enabled() {
+            "https"
+        } else {
+            "http"
+        },
+        listen_addr
     );
 
-    axum::serve(listener, app).await.expect("Server error");
+    // 证书有误时在报告就绪前退出，热重启时旧进程继续服务
+    let tls =
+        config.server.tls.is_enabled().then(|| {
+            tls_listener::server_config(&config.server.tls).expect("Failed to set up TLS")
+        });
+
+    restart::notify_ready();
+
+    // HTTPS 时由 TLS 监听器完成握手，并把客户端证书身份作为连接信息传给中间件；
+    // 启用 PROXY protocol 时先读取负载均衡器传来的客户端地址
+    let served = if let Some(tls) = tls {
+        let listener = tls_listener::TlsListener::new(
+            listener,
+            tls,
+            config.server.tls.identity,
+            config.server.proxy_protocol,
+        );
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<tls_listener::ClientConnection>(),
+        )
+        .with_graceful_shutdown(shutdown_signal(warm_restart))
+        .await
+    } else if config.server.proxy_protocol {
+        let listener = proxy_protocol::ProxyProtocolListener::new(listener);
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<tls_listener::ClientConnection>(),
+        )
+        .with_graceful_shutdown(shutdown_signal(warm_restart))
+        .await
+    } else {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal(warm_restart))
+            .await
+    };
+    served.expect("Server error");
+    if let Some(telemetry) = &telemetry {
+        telemetry.shutdown();
+    }
+
+    // 退出前保存缓存索引，避免重启后丢失访问时间等淘汰信息；
+    // 已交接给新进程时由新进程维护索引，不再覆盖
+    if handed_over.load(Ordering::SeqCst) {
+        info!("Docker Registry Proxy drained after warm restart");
+        return;
+    }
+    if let Some(cache) = proxy.cache()
+        && let Err(e) = cache.persist()
+    {
+        tracing::error!("Failed to persist blob cache index: {}", e);
+    }
+    if let Some(stats) = proxy.pull_stats()
+        && let Err(e) = stats.persist()
+    {
+        tracing::error!("Failed to persist pull statistics: {}", e);
+    }
+    if let Some(quotas) = proxy.quotas()
+        && let Err(e) = quotas.persist()
+    {
+        tracing::error!("Failed to persist quota usage: {}", e);
+    }
+    // dev 配置的缓存、统计等临时数据随进程退出删除
+    if let Some(dir) = scratch_dir
+        && let Err(e) = std::fs::remove_dir_all(&dir)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        tracing::error!("Failed to remove {}: {}", dir.display(), e);
+    }
+    info!("Docker Registry Proxy stopped");
+}
+
+// SIGUSR2 触发热重启：保存缓存索引后启动新进程并传递监听 socket，
+// 新进程就绪后返回，当前进程随即停止接受连接并处理完已有请求；启动失败则继续服务
+#[cfg(unix)]
+async fn warm_restart(proxy: Arc<DockerProxy>, listen_fd: i32, handed_over: Arc<AtomicBool>) {
+    let mut signal =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGUSR2: {}", e);
+                return std::future::pending().await;
+            }
+        };
+    loop {
+        signal.recv().await;
+        info!("Warm restart requested");
+        if let Some(cache) = proxy.cache()
+            && let Err(e) = cache.persist()
+        {
+            tracing::error!("Failed to persist blob cache index: {}", e);
+        }
+        if let Some(stats) = proxy.pull_stats()
+            && let Err(e) = stats.persist()
+        {
+            tracing::error!("Failed to persist pull statistics: {}", e);
+        }
+        if let Some(quotas) = proxy.quotas()
+            && let Err(e) = quotas.persist()
+        {
+            tracing::error!("Failed to persist quota usage: {}", e);
+        }
+        match restart::spawn_successor(listen_fd).await {
+            Ok(()) => {
+                info!("New process is serving, draining connections");
+                if let Some(cache) = proxy.cache() {
+                    cache.detach();
+                }
+                if let Some(stats) = proxy.pull_stats() {
+                    stats.detach();
+                }
+                if let Some(quotas) = proxy.quotas() {
+                    quotas.detach();
+                }
+                handed_over.store(true, Ordering::SeqCst);
+                return;
+            }
+            Err(e) => tracing::error!("Warm restart failed, continuing to serve: {}", e),
+        }
+    }
+}
+
+// 等待 Ctrl+C、SIGTERM 或热重启交接完成
+async fn shutdown_signal(warm_restart: impl std::future::Future<Output = ()>) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+        _ = warm_restart => return,
+    }
+    info!("Shutdown signal received");
 }
 
 // 日志中间件：记录请求、响应状态码和耗时（结构化日志）
-async fn log_middleware(request: Request, next: Next) -> Response {
+async fn log_middleware(
+    State(proxy): State<Arc<DockerProxy>>,
+    request: Request,
+    next: Next,
+) -> Response {
     let method = request.method().clone();
     let uri = request.uri().clone();
-    let request_id = uuid::Uuid::new_v4();
+    let request_id = proxy.random().uuid();
     let start = std::time::Instant::now();
+    let timestamp = proxy.clock().now_secs();
+    let version = request.version();
+    let [referer, user_agent] = [header::REFERER, header::USER_AGENT].map(|name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    });
 
-    // 获取客户端 IP（从 X-Forwarded-For 或连接地址）
+    // 获取客户端 IP（PROXY protocol 头优先，其次 X-Forwarded-For），按 [privacy] 配置哈希或省略
     let client_ip = request
-        .headers()
-        .get("x-forwarded-for")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .map(|s| s.to_string())
+        .extensions()
+        .get::<ConnectInfo<tls_listener::ClientConnection>>()
+        .and_then(|ConnectInfo(connection)| connection.source)
+        .map(|source| proxy.client_id(&source.ip().to_string()))
+        .or_else(|| {
+            request
+                .headers()
+                .get("x-forwarded-for")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|s| s.split(',').next())
+                .map(|s| proxy.client_id(s.trim()))
+        })
         .unwrap_or_else(|| "unknown".to_string());
 
-    // 处理请求
-    let response = next.run(request).await;
+    // 候选配置只做影子评估：记录差异，不影响实际处理
+    if let Some(shadow) = proxy.shadow() {
+        shadow.evaluate(&method, uri.path());
+    }
+
+    // 启用 [telemetry] 时为请求建立 span，并延续客户端 traceparent 中的 trace
+    let span = if proxy.telemetry_enabled() {
+        let span = tracing::info_span!(
+            "request",
+            otel.name = %format!("{} {}", method, uri.path()),
+            otel.kind = "server",
+            http.request.method = %method,
+            url.path = %uri.path(),
+            http.response.status_code = tracing::field::Empty,
+            request_id = %request_id,
+        );
+        telemetry::continue_trace(&span, request.headers());
+        span
+    } else {
+        tracing::Span::none()
+    };
+
+    // 处理请求；请求 ID 在处理期间可见，并通过 X-Request-Id 返回给客户端
+    // 启用 [sentry] 时请求在独立的 hub 中处理，上报的事件（包括 panic）附带请求信息
+    let context = error_reporting::RequestContext {
+        request_id,
+        method: method.as_str(),
+        path: uri.path(),
+        client: &client_ip,
+    };
+    let sentry_hub = error_reporting::enabled().then(|| error_reporting::request_hub(&context));
+    let handled = log::REQUEST_ID
+        .scope(request_id, next.run(request))
+        .instrument(span.clone());
+    let mut response = match &sentry_hub {
+        Some(hub) => handled.bind_hub(Arc::clone(hub)).await,
+        None => handled.await,
+    };
+    if let Some(hub) = &sentry_hub {
+        error_reporting::report_response(hub, &context, &response);
+    }
+    span.record("http.response.status_code", response.status().as_u16());
+    if let Ok(value) = HeaderValue::from_str(&request_id.to_string()) {
+        response.headers_mut().insert("X-Request-Id", value);
+    }
+
+    // 按仓库统计拉取次数、流量和客户端，供 /api/stats/export 导出
+    if let Some(stats) = proxy.pull_stats() {
+        record_pull_stats(stats, &method, uri.path(), &response, &client_ip);
+    }
+    // 最近一小时每分钟的请求数，供 /api/dashboard 使用
+    proxy
+        .request_rates()
+        .record(response.status().is_server_error());
+    // 按上游 registry 统计来自缓存与来自上游的流量
+    record_egress(&proxy, &method, uri.path(), &response);
 
     // 计算耗时
     let elapsed = start.elapsed();
     let status = response.status();
     let duration_ms = elapsed.as_secs_f64() * 1000.0;
 
+    // 写入独立的访问日志（[log] access_log_path）
+    log::write_access_log(&log::AccessEntry {
+        request_id,
+        client: &client_ip,
+        timestamp,
+        method: method.as_str(),
+        target: uri.path_and_query().map_or(uri.path(), |p| p.as_str()),
+        version,
+        status: status.as_u16(),
+        bytes: response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+        referer: referer.as_deref(),
+        user_agent: user_agent.as_deref(),
+        duration_ms,
+    });
+
     // 根据状态码选择日志级别，使用结构化字段
     if status.is_server_error() {
         tracing::error!(
@@ -134,4 +617,478 @@ async fn log_middleware(request: Request, next: Next) -> Response {
     response
 }
 
+// 客户端认证：配置了 [client_auth] 时 /v2/ 请求须携带 Basic 凭据（或开启 token 服务时携带本代理签发的
+// Bearer token），配置了 [oidc] 时 /v2/ 和 /admin/ 请求也可携带 OIDC 提供方签发的 token，否则返回 401 和质询；
+// token 有效但没有规则授权时返回 403。未配置 API 密钥时 /api/ 同样须认证。
+// 通过后移除 Authorization 头，避免把凭据转发给上游
+async fn client_auth_middleware(
+    State(proxy): State<Arc<DockerProxy>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let registry = path == "/v2" || path.starts_with("/v2/");
+    let key_authorized = request
+        .extensions()
+        .get::<api_keys::ApiKeyAuthorized>()
+        .is_some();
+    let admin = path.starts_with("/admin/") && !key_authorized;
+    let api = path.starts_with("/api/") && !key_authorized;
+    let client_auth = proxy.client_auth().filter(|_| registry || api);
+    let oidc = proxy.oidc().filter(|_| registry || admin || api);
+    // 已验证的客户端证书（mTLS）即为用户身份，无需其他凭据
+    let certificate = request
+        .extensions()
+        .get::<ConnectInfo<tls_listener::ClientConnection>>()
+        .and_then(|ConnectInfo(connection)| connection.identity.clone())
+        .filter(|_| registry);
+    if client_auth.is_none() && oidc.is_none() {
+        if let Some(identity) = certificate {
+            request
+                .extensions_mut()
+                .insert(client_auth::AuthenticatedUser(identity));
+        }
+        return next.run(request).await;
+    }
+    // 按仓库前缀允许匿名的操作（如匿名拉取、推送须认证）无需凭据
+    let endpoint = path
+        .strip_prefix("/v2/")
+        .map(|rest| router::parse_v2_request(request.method(), rest));
+    // 跨仓库挂载（mount）读取 from 仓库中的 blob，须同时有该仓库的拉取权限
+    let mount_source = match &endpoint {
+        Some(router::V2Endpoint::BlobUploadInit { .. }) => request
+            .uri()
+            .query()
+            .and_then(|query| router::query_param(query, "from")),
+        _ => None,
+    };
+    let anonymous = registry
+        && certificate.is_none()
+        && !request.headers().contains_key(header::AUTHORIZATION)
+        && proxy.anonymous().is_some_and(|anonymous| {
+            endpoint
+                .as_ref()
+                .and_then(router::V2Endpoint::repository)
+                .is_some_and(|name| anonymous.allows(policy::operation(request.method()), name))
+                && mount_source
+                    .as_deref()
+                    .is_none_or(|from| anonymous.allows(PolicyOperation::Pull, from))
+        });
+    if anonymous {
+        return next.run(request).await;
+    }
+
+    // token 须覆盖本次请求所需的 scope
+    let scope = client_auth
+        .filter(|auth| auth.issues_tokens())
+        .and_then(|_| reqwest::Url::parse(&format!("http://proxy{}", request.uri())).ok())
+        .and_then(|url| auth::scope_for(request.method(), &url));
+    let mut user = certificate.or_else(|| {
+        client_auth.and_then(|auth| auth.authorize(request.headers(), scope.as_deref()))
+    });
+    // 匿名 token 只用于仓库访问
+    if api && user.as_deref() == Some("") {
+        user = None;
+    }
+    // 用户须被授予该仓库上的本次操作
+    if let (Some(auth), Some(name)) = (client_auth, user.as_deref())
+        && !name.is_empty()
+        && let Some(repository) = endpoint.as_ref().and_then(router::V2Endpoint::repository)
+    {
+        let operation = policy::operation(request.method());
+        let source = mount_source
+            .as_deref()
+            .map(|from| (PolicyOperation::Pull, from));
+        for (operation, repository) in std::iter::once((operation, repository)).chain(source) {
+            if !auth.permits(name, operation, repository) {
+                tracing::warn!(user = %name, repository = %repository, "Client lacks access");
+                return denied_response(&format!(
+                    "{} may not {} {}",
+                    name,
+                    policy::operation_name(operation),
+                    repository
+                ));
+            }
+        }
+    }
+    let mut message = "authentication required".to_string();
+    if user.is_none()
+        && let Some(oidc) = oidc
+        && let Some(token) = oidc::token_from(request.headers())
+    {
+        match oidc.authorize(&token, request.method(), &path).await {
+            Ok(name) => user = Some(name),
+            Err(oidc::OidcError::Unauthorized(reason)) => {
+                tracing::warn!(reason = %reason, "OIDC token rejected");
+                message = reason;
+            }
+            Err(oidc::OidcError::Forbidden(reason)) => {
+                tracing::warn!(reason = %reason, "OIDC token lacks access");
+                return denied_response(&reason);
+            }
+        }
+    }
+    if let Some(user) = user {
+        request.headers_mut().remove(header::AUTHORIZATION);
+        // 仓库目录只列出用户有拉取权限的仓库
+        let catalog_auth = client_auth
+            .filter(|auth| auth.has_grants())
+            .filter(|_| matches!(endpoint, Some(router::V2Endpoint::Catalog)));
+        // 匿名 token 不对应用户
+        if !user.is_empty() {
+            request
+                .extensions_mut()
+                .insert(client_auth::AuthenticatedUser(user.clone()));
+        }
+        let response = next.run(request).await;
+        return match catalog_auth {
+            Some(auth) => {
+                filter_catalog(response, |repository| {
+                    auth.permits(&user, PolicyOperation::Pull, repository)
+                })
+                .await
+            }
+            None => response,
+        };
+    }
+
+    let challenge = match client_auth {
+        Some(auth) if api => auth.basic_challenge(),
+        Some(auth) => auth.challenge(request.headers(), proxy.path_prefix(), scope.as_deref()),
+        None => oidc.map(oidc::Oidc::challenge).unwrap_or_default(),
+    };
+    let body = serde_json::json!({
+        "errors": [{
+            "code": "UNAUTHORIZED",
+            "message": message,
+        }]
+    });
+    (
+        StatusCode::UNAUTHORIZED,
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::WWW_AUTHENTICATE, challenge),
+            (
+                header::HeaderName::from_static("docker-distribution-api-version"),
+                "registry/2.0".to_string(),
+            ),
+        ],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+// 管理、调试与状态接口（/admin/、/debug/、/api/）须携带 [admin] api_keys 中的密钥；
+// 配置了 OIDC 时 /admin/ 也可改用 OIDC token
+async fn api_key_middleware(
+    State(proxy): State<Arc<DockerProxy>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let admin = path.starts_with("/admin/");
+    let Some(keys) = proxy
+        .api_keys()
+        .filter(|_| admin || path.starts_with("/debug/") || path.starts_with("/api/"))
+    else {
+        return next.run(request).await;
+    };
+    if keys.verify(request.headers()) {
+        request.headers_mut().remove(header::AUTHORIZATION);
+        request.extensions_mut().insert(api_keys::ApiKeyAuthorized);
+        return next.run(request).await;
+    }
+    if admin && proxy.oidc().is_some() && request.headers().contains_key(header::AUTHORIZATION) {
+        return next.run(request).await;
+    }
+    tracing::warn!(path = %path, "Admin request without a valid API key");
+    let body = serde_json::json!({
+        "errors": [{
+            "code": "UNAUTHORIZED",
+            "message": "a valid API key is required",
+        }]
+    });
+    (
+        StatusCode::UNAUTHORIZED,
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::WWW_AUTHENTICATE, "Bearer realm=\"admin\""),
+        ],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+// Keep only the repositories of a catalog page that `allowed` accepts; the
+// page may come back shorter than requested
+async fn filter_catalog(response: Response, allowed: impl Fn(&str) -> bool) -> Response {
+    const MAX_CATALOG_SIZE: usize = 16 * 1024 * 1024;
+    let (mut parts, body) = response.into_parts();
+    if !parts.status.is_success() {
+        return Response::from_parts(parts, body);
+    }
+    let catalog = axum::body::to_bytes(body, MAX_CATALOG_SIZE)
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok());
+    let Some(mut catalog) = catalog else {
+        tracing::warn!("Unreadable catalog response");
+        return (StatusCode::BAD_GATEWAY, "Unreadable catalog response").into_response();
+    };
+    if let Some(repositories) = catalog
+        .get_mut("repositories")
+        .and_then(|r| r.as_array_mut())
+    {
+        repositories.retain(|r| r.as_str().is_some_and(&allowed));
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, axum::body::Body::from(catalog.to_string()))
+}
+
+// 已认证但无权访问时的 403 响应
+fn denied_response(message: &str) -> Response {
+    let body = serde_json::json!({
+        "errors": [{
+            "code": "DENIED",
+            "message": message,
+        }]
+    });
+    (
+        StatusCode::FORBIDDEN,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+// 资源紧张时（内存、文件描述符或处理中的请求数超过 [load_shedding] 阈值）拒绝低优先级请求
+// （目录和标签列表）：503 + Retry-After，保证拉取不受影响
+async fn load_shedding_middleware(
+    State(proxy): State<Arc<DockerProxy>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(shedder) = proxy.load_shedder() else {
+        return next.run(request).await;
+    };
+    if load_shedding::is_low_priority(request.method(), request.uri().path())
+        && let Some(pressure) = shedder.pressure()
+    {
+        tracing::warn!(
+            reason = pressure.label(),
+            path = %request.uri().path(),
+            "Shedding low-priority request"
+        );
+        shedder.record_shed(pressure);
+        let body = serde_json::json!({
+            "errors": [{
+                "code": "UNAVAILABLE",
+                "message": format!("proxy is under load ({}), retry later", pressure.label()),
+            }]
+        });
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [
+                (header::CONTENT_TYPE, "application/json".to_string()),
+                (header::RETRY_AFTER, shedder.retry_after_secs().to_string()),
+            ],
+            body.to_string(),
+        )
+            .into_response();
+    }
+    // 计数持续到响应体发送完毕，流式传输的 blob 也算在内
+    let in_flight = shedder.start();
+    load_shedding::hold(next.run(request).await, in_flight)
+}
+
+// 标记 /v2/ 响应（blob 或其他），供压缩策略判断
+async fn registry_response_middleware(request: Request, next: Next) -> Response {
+    let marker = request
+        .uri()
+        .path()
+        .strip_prefix("/v2/")
+        .map(|rest| match router::parse_v2_path(rest) {
+            router::V2Endpoint::Blob { .. } => compression::RegistryResponse::Blob,
+            _ => compression::RegistryResponse::Other,
+        });
+    let mut response = next.run(request).await;
+    if let Some(marker) = marker {
+        response.extensions_mut().insert(marker);
+    }
+    response
+}
+
+// 为响应头中以 / 开头的地址加上挂载前缀
+async fn path_prefix_middleware(
+    State(proxy): State<Arc<DockerProxy>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    for name in [
+        axum::http::header::LOCATION,
+        axum::http::header::LINK,
+        axum::http::header::WWW_AUTHENTICATE,
+    ] {
+        let rewritten: Vec<HeaderValue> = headers
+            .get_all(&name)
+            .iter()
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|v| router::mount_header_value(proxy.path_prefix(), &name, v))
+                    .and_then(|v| HeaderValue::from_str(&v).ok())
+                    .unwrap_or_else(|| value.clone())
+            })
+            .collect();
+        if rewritten.is_empty() {
+            continue;
+        }
+        headers.remove(&name);
+        for value in rewritten {
+            headers.append(name.clone(), value);
+        }
+    }
+    response
+}
+
+// 按用户的拉取配额：已达上限的用户发起新的拉取（manifest GET）时返回 429，超出流量上限时 blob 下载也返回 429；
+// 成功的拉取计入该用户的用量
+async fn quota_middleware(
+    State(proxy): State<Arc<DockerProxy>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let user = request
+        .extensions()
+        .get::<client_auth::AuthenticatedUser>()
+        .map(|user| user.0.clone());
+    let (Some(quotas), Some(user)) = (proxy.quotas(), user) else {
+        return next.run(request).await;
+    };
+    let Some(pull) = pull_request(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+    if let Err(exceeded) = quotas.check(&user, matches!(pull, Pull::Manifest { .. })) {
+        tracing::warn!(user = %user, limit = exceeded.limit, "Pull quota exceeded");
+        let body = serde_json::json!({
+            "errors": [{
+                "code": "TOOMANYREQUESTS",
+                "message": exceeded.message(),
+                "detail": exceeded,
+            }]
+        });
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [
+                (header::CONTENT_TYPE, "application/json".to_string()),
+                (header::RETRY_AFTER, exceeded.retry_after_secs.to_string()),
+            ],
+            body.to_string(),
+        )
+            .into_response();
+    }
+
+    let response = next.run(request).await;
+    if response.status().is_success() {
+        match pull {
+            Pull::Manifest { .. } => quotas.record_pull(&user),
+            Pull::Blob(_) => quotas.record_bytes(&user, content_length(&response)),
+        }
+    }
+    response
+}
+
+// 拉取类请求：manifest GET 和 blob GET，附带仓库名
+enum Pull {
+    Manifest { name: String, reference: String },
+    Blob(String),
+}
+
+fn pull_request(method: &axum::http::Method, path: &str) -> Option<Pull> {
+    if method != axum::http::Method::GET {
+        return None;
+    }
+    match router::parse_v2_request(method, path.strip_prefix("/v2/")?) {
+        router::V2Endpoint::Manifest { name, reference } => {
+            Some(Pull::Manifest { name, reference })
+        }
+        router::V2Endpoint::Blob { name, .. } => Some(Pull::Blob(name)),
+        _ => None,
+    }
+}
+
+fn content_length(response: &Response) -> u64 {
+    response
+        .headers()
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+// 成功的 manifest GET 计为一次拉取；blob GET 按响应的 Content-Length 累计流量；
+// manifest HEAD 只记下客户端解析的 tag，用于归属之后按 digest 的请求
+fn record_pull_stats(
+    stats: &pull_stats::PullStats,
+    method: &axum::http::Method,
+    path: &str,
+    response: &Response,
+    client: &str,
+) {
+    if !response.status().is_success() {
+        return;
+    }
+    if method == axum::http::Method::HEAD {
+        if let Some(path) = path.strip_prefix("/v2/")
+            && let router::V2Endpoint::Manifest { name, reference } =
+                router::parse_v2_request(method, path)
+        {
+            stats.note_tag(&name, &reference, client);
+        }
+        return;
+    }
+    match pull_request(method, path) {
+        Some(Pull::Manifest { name, reference }) => stats.record_pull(&name, &reference, client),
+        Some(Pull::Blob(name)) => stats.record_bytes(&name, client, content_length(response)),
+        None => {}
+    }
+}
+
+// 成功的 manifest / blob GET 按 X-Docker-Proxy-Cache 区分来自缓存还是上游
+fn record_egress(
+    proxy: &DockerProxy,
+    method: &axum::http::Method,
+    path: &str,
+    response: &Response,
+) {
+    if !response.status().is_success() {
+        return;
+    }
+    let Some(source) = response
+        .headers()
+        .get(chain::CACHE_STATUS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(egress::Source::from_cache_status)
+    else {
+        return;
+    };
+    let bytes = content_length(response);
+    match pull_request(method, path) {
+        Some(Pull::Manifest { name, .. }) => {
+            proxy
+                .egress()
+                .record_manifest(&proxy.upstream_host(&name), source, bytes)
+        }
+        Some(Pull::Blob(name)) => {
+            proxy
+                .egress()
+                .record_blob(&proxy.upstream_host(&name), source, bytes)
+        }
+        None => {}
+    }
+}
+
 // api module declared above
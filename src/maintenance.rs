@@ -0,0 +1,218 @@
+/// Maintenance mode
+///
+/// While maintenance is active the proxy keeps serving what the local cache
+/// can answer and turns away everything that would reach the upstream
+/// registry or change stored content, so upstream credentials can be rotated
+/// or the cache storage migrated without clients seeing half-done state.
+/// Maintenance is entered from the daily windows in the config or manually
+/// through the admin API.
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::clock::Clock;
+use crate::config::MaintenanceConfig;
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// A daily time window in UTC; the end may be past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DailyWindow {
+    start: u64,
+    end: u64,
+}
+
+impl DailyWindow {
+    /// Parse "HH:MM-HH:MM"
+    pub fn parse(window: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "Invalid maintenance window '{}': expected HH:MM-HH:MM",
+                window
+            )
+        };
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time_of_day(start.trim()).ok_or_else(invalid)?;
+        let end = parse_time_of_day(end.trim()).ok_or_else(invalid)?;
+        if start == end {
+            return Err(format!("Maintenance window '{}' is empty", window));
+        }
+        Ok(Self { start, end })
+    }
+
+    // Seconds left in the window, if `now` falls inside it
+    fn remaining(&self, now: u64) -> Option<u64> {
+        let time_of_day = now % SECS_PER_DAY;
+        let length = (self.end + SECS_PER_DAY - self.start) % SECS_PER_DAY;
+        let elapsed = (time_of_day + SECS_PER_DAY - self.start) % SECS_PER_DAY;
+        (elapsed < length).then(|| length - elapsed)
+    }
+}
+
+fn parse_time_of_day(value: &str) -> Option<u64> {
+    let (hours, minutes) = value.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let hours: u64 = hours.parse().ok()?;
+    let minutes: u64 = minutes.parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+}
+
+/// Current maintenance state, as reported to clients and the admin API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MaintenanceStatus {
+    pub reason: String,
+    /// Unix time the maintenance ends, when known
+    pub until: Option<u64>,
+    /// Whether a configured window (rather than an operator) started it
+    pub scheduled: bool,
+    /// Seconds clients should wait before retrying
+    pub retry_after: u64,
+}
+
+struct ManualMaintenance {
+    reason: String,
+    until: Option<u64>,
+}
+
+pub struct Maintenance {
+    windows: Vec<DailyWindow>,
+    reason: String,
+    retry_after_secs: u64,
+    manual: Mutex<Option<ManualMaintenance>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Maintenance {
+    pub fn new(config: &MaintenanceConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            windows: config
+                .windows
+                .iter()
+                .filter_map(|w| DailyWindow::parse(w).ok())
+                .collect(),
+            reason: config.reason.clone(),
+            retry_after_secs: config.retry_after_secs,
+            manual: Mutex::new(None),
+            clock,
+        }
+    }
+
+    /// Enter maintenance until disabled, or for `duration_secs`
+    pub fn enable(&self, reason: Option<String>, duration_secs: Option<u64>) {
+        let manual = ManualMaintenance {
+            reason: reason.unwrap_or_else(|| "Maintenance".to_string()),
+            until: duration_secs.map(|d| self.clock.now_secs().saturating_add(d)),
+        };
+        *self.manual.lock().unwrap_or_else(|e| e.into_inner()) = Some(manual);
+    }
+
+    /// Leave manual maintenance; configured windows still apply
+    pub fn disable(&self) {
+        *self.manual.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// The active maintenance, if any
+    pub fn status(&self) -> Option<MaintenanceStatus> {
+        self.status_at(self.clock.now_secs())
+    }
+
+    fn status_at(&self, now: u64) -> Option<MaintenanceStatus> {
+        {
+            let mut manual = self.manual.lock().unwrap_or_else(|e| e.into_inner());
+            match manual.as_ref() {
+                Some(m) if m.until.is_none_or(|until| until > now) => {
+                    return Some(MaintenanceStatus {
+                        reason: m.reason.clone(),
+                        until: m.until,
+                        scheduled: false,
+                        retry_after: m.until.map_or(self.retry_after_secs, |until| until - now),
+                    });
+                }
+                Some(_) => *manual = None,
+                None => {}
+            }
+        }
+
+        let remaining = self.windows.iter().filter_map(|w| w.remaining(now)).max()?;
+        Some(MaintenanceStatus {
+            reason: self.reason.clone(),
+            until: Some(now + remaining),
+            scheduled: true,
+            retry_after: remaining,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_daily_window() {
+        let window = DailyWindow::parse("02:00-03:30").unwrap();
+        let day = 20_000 * SECS_PER_DAY;
+        assert_eq!(window.remaining(day + 2 * 3600), Some(5400));
+        assert_eq!(window.remaining(day + 3 * 3600), Some(1800));
+        assert_eq!(window.remaining(day + 3 * 3600 + 1800), None);
+        assert_eq!(window.remaining(day + 3600), None);
+
+        let overnight = DailyWindow::parse("23:00-01:00").unwrap();
+        assert_eq!(overnight.remaining(day + 23 * 3600 + 1800), Some(5400));
+        assert_eq!(overnight.remaining(day + 1800), Some(1800));
+        assert_eq!(overnight.remaining(day + 2 * 3600), None);
+
+        assert!(DailyWindow::parse("2:00-03:00").is_err());
+        assert!(DailyWindow::parse("02:00-24:00").is_err());
+        assert!(DailyWindow::parse("02:00").is_err());
+        assert!(DailyWindow::parse("02:00-02:00").is_err());
+    }
+
+    #[test]
+    fn test_manual_and_scheduled_status() {
+        let config = MaintenanceConfig {
+            windows: vec!["02:00-03:00".to_string()],
+            ..MaintenanceConfig::default()
+        };
+        let day = 20_000 * SECS_PER_DAY;
+        let clock = ManualClock::new(day);
+        let maintenance = Maintenance::new(&config, clock.clone());
+
+        assert_eq!(maintenance.status_at(day), None);
+        let scheduled = maintenance.status_at(day + 2 * 3600 + 600).unwrap();
+        assert!(scheduled.scheduled);
+        assert_eq!(scheduled.retry_after, 3000);
+        assert_eq!(scheduled.until, Some(day + 3 * 3600));
+
+        maintenance.enable(Some("Rotating credentials".to_string()), None);
+        let manual = maintenance.status_at(day).unwrap();
+        assert_eq!(manual.reason, "Rotating credentials");
+        assert_eq!(manual.until, None);
+        assert_eq!(manual.retry_after, 300);
+
+        maintenance.disable();
+        assert_eq!(maintenance.status_at(day), None);
+
+        // An expired manual window is cleared
+        *maintenance.manual.lock().unwrap() = Some(ManualMaintenance {
+            reason: "Migration".to_string(),
+            until: Some(day - 1),
+        });
+        assert_eq!(maintenance.status_at(day), None);
+        assert!(maintenance.manual.lock().unwrap().is_none());
+
+        // A timed manual window ends on its own
+        maintenance.enable(None, Some(600));
+        let timed = maintenance.status().unwrap();
+        assert_eq!(timed.until, Some(day + 600));
+        assert_eq!(timed.retry_after, 600);
+        clock.advance(std::time::Duration::from_secs(600));
+        assert_eq!(maintenance.status(), None);
+
+        // An absurd duration ends at the end of time instead of overflowing
+        maintenance.enable(None, Some(u64::MAX));
+        assert_eq!(maintenance.status().unwrap().until, Some(u64::MAX));
+    }
+}
@@ -0,0 +1,403 @@
+/// Repository allow/deny policy
+///
+/// The `[policy]` rules are checked in order against every registry request
+/// that names a repository, and the first matching rule allows or denies it;
+/// requests no rule matches get the default action. Rules see the repository
+/// the request resolves to, not the name the client sent: `host/repository`
+/// with Docker Hub written `docker.io`, so route prefixes and spellings like
+/// `nginx` or `index.docker.io/library/nginx` cannot get around a rule.
+/// Repositories of the default registry also match without the host, e.g.
+/// as `library/nginx`. A rule matches when its glob or regex matches the
+/// whole name, or the name with the requested `:tag` or `@digest` appended,
+/// so `ghcr.io/myorg/*` covers every tag of those repositories while
+/// `.*:latest` only covers manifests requested by the `latest` tag.
+use axum::http::Method;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::config::{PolicyAction, PolicyConfig, PolicyOperation, PolicyRule};
+use crate::router;
+
+/// A compiled policy rule
+pub struct Rule {
+    /// Position in the config, starting at 1
+    number: usize,
+    action: PolicyAction,
+    /// The pattern as configured, e.g. "glob ghcr.io/myorg/*"
+    pattern: String,
+    regex: Regex,
+    operations: Vec<PolicyOperation>,
+    reason: String,
+}
+
+impl Rule {
+    /// Compile the rule at `index` in `[policy] rules`
+    pub fn compile(index: usize, rule: &PolicyRule) -> Result<Self, String> {
+        let number = index + 1;
+        let (pattern, expression) = match (rule.glob.as_str(), rule.regex.as_str()) {
+            ("", "") => {
+                return Err(format!("Policy rule {} needs a glob or a regex", number));
+            }
+            (glob, "") => (format!("glob {}", glob), glob_to_regex(glob)),
+            ("", regex) => (format!("regex {}", regex), format!("^(?:{})$", regex)),
+            _ => {
+                return Err(format!(
+                    "Policy rule {} must have either a glob or a regex, not both",
+                    number
+                ));
+            }
+        };
+        let regex = Regex::new(&expression)
+            .map_err(|e| format!("Policy rule {} has an invalid pattern: {}", number, e))?;
+        Ok(Self {
+            number,
+            action: rule.action,
+            pattern,
+            regex,
+            operations: rule.operations.clone(),
+            reason: rule.reason.clone(),
+        })
+    }
+
+    // `names` pairs each name of the repository with its subject
+    fn matches(&self, operation: PolicyOperation, names: &[(String, String)]) -> bool {
+        (self.operations.is_empty() || self.operations.contains(&operation))
+            && names
+                .iter()
+                .any(|(name, subject)| self.regex.is_match(name) || self.regex.is_match(subject))
+    }
+}
+
+/// Why a request was denied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Denial {
+    /// Name, tag or digest the request was for
+    pub subject: String,
+    /// Number of the matched rule; `None` when the default action denied it
+    pub rule: Option<usize>,
+    pub pattern: Option<String>,
+    pub reason: String,
+}
+
+impl Denial {
+    /// Message for the client's error response
+    pub fn message(&self) -> String {
+        let rule = match (self.rule, &self.pattern) {
+            (Some(number), Some(pattern)) => format!("policy rule {} ({})", number, pattern),
+            _ => "the default policy".to_string(),
+        };
+        if self.reason.is_empty() {
+            format!("access to {} denied by {}", self.subject, rule)
+        } else {
+            format!(
+                "access to {} denied by {}: {}",
+                self.subject, rule, self.reason
+            )
+        }
+    }
+}
+
+pub struct Policy {
+    rules: Vec<Rule>,
+    default: PolicyAction,
+}
+
+impl Policy {
+    /// `None` when the policy allows everything
+    pub fn from_config(config: &PolicyConfig) -> Option<Self> {
+        if config.rules.is_empty() && config.default == PolicyAction::Allow {
+            return None;
+        }
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .filter_map(|(index, rule)| {
+                Rule::compile(index, rule)
+                    .inspect_err(|e| tracing::error!("{}, rule ignored", e))
+                    .ok()
+            })
+            .collect();
+        Some(Self {
+            rules,
+            default: config.default,
+        })
+    }
+
+    /// Check a request for `repository` on the registry at `registry_url`,
+    /// with the manifest tag or digest when it names one. `default` tells
+    /// whether that is the default registry, whose repositories also match
+    /// without the host.
+    pub fn check(
+        &self,
+        method: &Method,
+        registry_url: &str,
+        repository: &str,
+        default: bool,
+        reference: Option<&str>,
+    ) -> Result<(), Denial> {
+        let mut names = vec![format!("{}/{}", policy_host(registry_url), repository)];
+        if default {
+            names.push(repository.to_string());
+        }
+        let names: Vec<(String, String)> = names
+            .into_iter()
+            .map(|name| {
+                let subject = match reference {
+                    Some(reference) if reference.contains(':') => {
+                        format!("{}@{}", name, reference)
+                    }
+                    Some(tag) => format!("{}:{}", name, tag),
+                    None => name.clone(),
+                };
+                (name, subject)
+            })
+            .collect();
+        let subject = names[0].1.clone();
+        let operation = operation(method);
+        let denial = match self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(operation, &names))
+        {
+            Some(rule) if rule.action == PolicyAction::Allow => return Ok(()),
+            Some(rule) => Denial {
+                subject,
+                rule: Some(rule.number),
+                pattern: Some(rule.pattern.clone()),
+                reason: rule.reason.clone(),
+            },
+            None if self.default == PolicyAction::Allow => return Ok(()),
+            None => Denial {
+                subject,
+                rule: None,
+                pattern: None,
+                reason: String::new(),
+            },
+        };
+        Err(denial)
+    }
+}
+
+// Host of a registry URL as rules name it; Docker Hub is `docker.io`
+fn policy_host(registry_url: &str) -> &str {
+    let host = registry_url
+        .split_once("://")
+        .map_or(registry_url, |(_, host)| host)
+        .trim_end_matches('/');
+    if host == router::DOCKER_HUB_HOST || router::is_docker_hub_alias(host) {
+        "docker.io"
+    } else {
+        host
+    }
+}
+
+/// Operation a request method performs on a repository
+pub fn operation(method: &Method) -> PolicyOperation {
+    match *method {
+        Method::GET | Method::HEAD => PolicyOperation::Pull,
+        Method::DELETE => PolicyOperation::Delete,
+        _ => PolicyOperation::Push,
+    }
+}
+
+/// Name of an operation as used in token scopes and messages
+pub fn operation_name(operation: PolicyOperation) -> &'static str {
+    match operation {
+        PolicyOperation::Pull => "pull",
+        PolicyOperation::Push => "push",
+        PolicyOperation::Delete => "delete",
+    }
+}
+
+/// Anchored regex for a glob: `**` matches anything, `*` and `?` stay within
+/// a path segment
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn policy(toml: &str) -> Policy {
+        let config: PolicyConfig = toml::from_str(toml).unwrap();
+        config.validate().unwrap();
+        Policy::from_config(&config).unwrap()
+    }
+
+    // Resolve `name` as the proxy does, with Docker Hub as the default
+    // registry and the `ghcr/` route prefix pointing at ghcr.io
+    fn check(
+        policy: &Policy,
+        method: &Method,
+        name: &str,
+        reference: Option<&str>,
+    ) -> Result<(), Denial> {
+        let default_url = format!("https://{}", router::DOCKER_HUB_HOST);
+        let routes = HashMap::from([("ghcr".to_string(), "https://ghcr.io".to_string())]);
+        let (registry_url, repository) =
+            router::split_registry_and_name(&default_url, &routes, &HashMap::new(), name);
+        policy.check(
+            method,
+            &registry_url,
+            &repository,
+            registry_url == default_url,
+            reference,
+        )
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        let regex = Regex::new(&glob_to_regex("ghcr.io/myorg/*")).unwrap();
+        assert!(regex.is_match("ghcr.io/myorg/app"));
+        assert!(!regex.is_match("ghcr.io/myorg/team/app"));
+        assert!(!regex.is_match("ghcrxio/myorg/app"));
+
+        let regex = Regex::new(&glob_to_regex("**:latest")).unwrap();
+        assert!(regex.is_match("ghcr.io/myorg/team/app:latest"));
+        assert!(!regex.is_match("library/nginx:1.27"));
+    }
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let policy = policy(
+            r#"
+[[rules]]
+action = "deny"
+regex = ".*:latest"
+reason = "pin a version"
+
+[[rules]]
+action = "allow"
+glob = "ghcr.io/myorg/*"
+
+[[rules]]
+action = "deny"
+glob = "ghcr.io/**"
+reason = "only myorg images from GHCR"
+"#,
+        );
+
+        assert!(check(&policy, &Method::GET, "ghcr.io/myorg/app", Some("v1")).is_ok());
+        assert!(check(&policy, &Method::GET, "ghcr.io/myorg/app", None).is_ok());
+        assert!(check(&policy, &Method::GET, "library/nginx", Some("1.27")).is_ok());
+        // digests are not tags
+        assert!(check(&policy, &Method::GET, "library/nginx", Some("sha256:abc")).is_ok());
+
+        let latest = check(&policy, &Method::GET, "ghcr.io/myorg/app", Some("latest")).unwrap_err();
+        assert_eq!(latest.rule, Some(1));
+        assert_eq!(
+            latest.message(),
+            "access to ghcr.io/myorg/app:latest denied by policy rule 1 (regex .*:latest): pin a version"
+        );
+
+        let other = check(&policy, &Method::HEAD, "ghcr.io/other/app", None).unwrap_err();
+        assert_eq!(other.rule, Some(3));
+        assert_eq!(other.reason, "only myorg images from GHCR");
+    }
+
+    #[test]
+    fn test_operations_and_default() {
+        let policy = policy(
+            r#"
+default = "deny"
+
+[[rules]]
+action = "allow"
+glob = "team/*"
+operations = ["pull"]
+"#,
+        );
+        assert!(check(&policy, &Method::GET, "team/app", Some("v1")).is_ok());
+        let push = check(&policy, &Method::PUT, "team/app", Some("v1")).unwrap_err();
+        assert_eq!(push.rule, None);
+        assert_eq!(
+            push.message(),
+            "access to docker.io/team/app:v1 denied by the default policy"
+        );
+    }
+
+    #[test]
+    fn test_rules_see_the_resolved_repository() {
+        let policy = policy(
+            r#"
+[[rules]]
+action = "deny"
+glob = "ghcr.io/evil/*"
+
+[[rules]]
+action = "deny"
+glob = "library/nginx"
+operations = ["pull"]
+
+[[rules]]
+action = "deny"
+glob = "docker.io/library/redis"
+"#,
+        );
+
+        // a route prefix resolves to the registry the rule names
+        let routed = check(&policy, &Method::GET, "ghcr/evil/x", Some("v1")).unwrap_err();
+        assert_eq!(routed.rule, Some(1));
+        assert_eq!(routed.subject, "ghcr.io/evil/x:v1");
+        assert!(check(&policy, &Method::GET, "ghcr.io/evil/x", None).is_err());
+        assert!(check(&policy, &Method::GET, "ghcr/good/x", None).is_ok());
+
+        // every spelling of a Docker Hub repository is the same repository
+        for name in [
+            "nginx",
+            "library/nginx",
+            "docker.io/library/nginx",
+            "index.docker.io/nginx",
+            "registry-1.docker.io/library/nginx",
+        ] {
+            let denial = check(&policy, &Method::GET, name, Some("1.27")).unwrap_err();
+            assert_eq!(denial.rule, Some(2), "{}", name);
+            assert_eq!(denial.subject, "docker.io/library/nginx:1.27");
+        }
+        assert!(check(&policy, &Method::GET, "redis", None).is_err());
+        // names without a host only match the default registry
+        assert!(check(&policy, &Method::GET, "ghcr.io/library/nginx", None).is_ok());
+        assert!(check(&policy, &Method::GET, "ghcr/library/nginx", None).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let config: PolicyConfig = toml::from_str(
+            r#"
+[[rules]]
+action = "deny"
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        let config: PolicyConfig = toml::from_str(
+            r#"
+[[rules]]
+action = "deny"
+regex = "(unclosed"
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().unwrap_err().contains("rule 1"));
+
+        assert!(Policy::from_config(&PolicyConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,502 @@
+/// Warming and purging the blob cache with whole images
+///
+/// Resolves a tag to its image manifest(s) and pulls the config and layer
+/// blobs through the proxy into the local cache, so the first `docker pull`
+/// after a release is served from disk. Blobs are downloaded several at a
+/// time (`[cache] prefetch_concurrency`), each retried after upstream outages
+/// (`prefetch_retries`). Purging walks the same manifests and drops their
+/// pins and the blobs no other pinned manifest or local push still needs.
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt, stream};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+
+use crate::cache;
+use crate::error::{ProxyError, ProxyResult};
+use crate::proxy::DockerProxy;
+
+/// Wait before the first retry of a blob, doubled for each further one
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PrefetchSummary {
+    pub manifests: usize,
+    /// Blobs downloaded into the cache by this run
+    pub blobs_fetched: usize,
+    /// Blobs that were already cached
+    pub blobs_present: usize,
+    pub bytes_fetched: u64,
+}
+
+/// Pre-fetch `name:reference` into the cache. Multi-arch images are narrowed
+/// down to `platforms` ("os/arch[/variant]").
+pub async fn prefetch_image(
+    proxy: &DockerProxy,
+    name: &str,
+    reference: &str,
+    platforms: &[String],
+) -> ProxyResult<PrefetchSummary> {
+    if proxy.cache().is_none() {
+        return Err(ProxyError::InternalError(
+            "blob cache is disabled".to_string(),
+        ));
+    }
+
+    let mut summary = PrefetchSummary::default();
+    let (_, body) = proxy.get_manifest(name, reference, &[]).await?;
+    let manifest = parse_manifest(&body)?;
+    summary.manifests += 1;
+
+    let image_manifests = match manifest.get("manifests").and_then(|m| m.as_array()) {
+        Some(children) => {
+            let mut resolved = Vec::new();
+            for digest in select_platforms(children, platforms) {
+                let (_, body) = proxy.get_manifest(name, &digest, &[]).await?;
+                resolved.push(parse_manifest(&body)?);
+                summary.manifests += 1;
+            }
+            resolved
+        }
+        None => vec![manifest],
+    };
+
+    // platforms share blobs, download each once
+    let mut seen = HashSet::new();
+    let digests: Vec<String> = image_manifests
+        .iter()
+        .flat_map(blob_digests)
+        .filter(|digest| seen.insert(digest.clone()))
+        .collect();
+    let mut downloads = stream::iter(digests)
+        .map(|digest| async move {
+            with_retries(proxy.prefetch_retries(), RETRY_DELAY, &digest, || {
+                prefetch_blob(proxy, name, &digest)
+            })
+            .await
+        })
+        .buffer_unordered(proxy.prefetch_concurrency());
+    while let Some(result) = downloads.next().await {
+        match result? {
+            Some(size) => {
+                summary.blobs_fetched += 1;
+                summary.bytes_fetched += size;
+            }
+            None => summary.blobs_present += 1,
+        }
+    }
+
+    tracing::info!(
+        image = %name,
+        reference = %reference,
+        fetched = summary.blobs_fetched,
+        present = summary.blobs_present,
+        bytes = summary.bytes_fetched,
+        "Prefetch finished"
+    );
+    Ok(summary)
+}
+
+// Run `attempt` again while it fails with an upstream outage, up to
+// `retries` more times, waiting `delay` (doubled each time) in between
+async fn with_retries<T, F, Fut>(
+    retries: u32,
+    delay: Duration,
+    digest: &str,
+    mut attempt: F,
+) -> ProxyResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = ProxyResult<T>>,
+{
+    let mut failures = 0;
+    loop {
+        match attempt().await {
+            Err(e) if e.is_upstream_outage() && failures < retries => {
+                failures += 1;
+                tracing::warn!(
+                    digest = %digest,
+                    attempt = failures,
+                    "Prefetch of blob failed, retrying: {}",
+                    e
+                );
+                tokio::time::sleep(delay * 2u32.pow(failures - 1)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Download a single blob into the cache; `None` when it was already cached
+async fn prefetch_blob(proxy: &DockerProxy, name: &str, digest: &str) -> ProxyResult<Option<u64>> {
+    let Some(cache) = proxy.cache() else {
+        return Ok(None);
+    };
+    if cache.lookup(digest).is_some() {
+        return Ok(None);
+    }
+
+    let response = proxy.get_blob(name, digest, Vec::new()).await?;
+    if !response.status().is_success() {
+        return Err(ProxyError::BlobNotFound {
+            status: response.status(),
+        });
+    }
+    let Some(writer) = cache.writer(digest, response.content_length()).await else {
+        // not cacheable, or another request is already filling it
+        return Ok(None);
+    };
+    let writer = writer.for_repository(name);
+
+    cache::tee(proxy.blob_body(digest, response), writer)
+        .try_for_each(|_| async { Ok(()) })
+        .await
+        .map_err(|e| ProxyError::ResponseReadError(e.to_string()))?;
+
+    match cache.lookup(digest) {
+        Some(blob) => Ok(Some(blob.size)),
+        None => Err(ProxyError::InternalError(format!(
+            "failed to store {} in the cache",
+            digest
+        ))),
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PurgeSummary {
+    pub manifests: usize,
+    pub blobs_removed: usize,
+    /// Blobs left in place: pushed locally, or still referenced by other
+    /// pinned manifests
+    pub blobs_kept: usize,
+    pub bytes_freed: u64,
+}
+
+/// Remove `name:reference` from the cache: its pins, its manifests and the
+/// config and layer blobs of every platform. Blobs still referenced by other
+/// pinned manifests and retained (locally pushed) blobs are kept.
+pub async fn purge_image(
+    proxy: &DockerProxy,
+    name: &str,
+    reference: &str,
+) -> ProxyResult<PurgeSummary> {
+    let Some(cache) = proxy.cache() else {
+        return Err(ProxyError::InternalError(
+            "blob cache is disabled".to_string(),
+        ));
+    };
+
+    let mut summary = PurgeSummary::default();
+    let (digest, manifest) = resolve_manifest(proxy, name, reference).await?;
+    let mut manifest_digests = vec![digest];
+    let mut image_manifests = Vec::new();
+    match manifest.get("manifests").and_then(|m| m.as_array()) {
+        Some(children) => {
+            for child in children
+                .iter()
+                .filter_map(|c| c.get("digest").and_then(|d| d.as_str()))
+            {
+                match resolve_manifest(proxy, name, child).await {
+                    Ok((digest, manifest)) => {
+                        manifest_digests.push(digest);
+                        image_manifests.push(manifest);
+                    }
+                    Err(e) => tracing::debug!(digest = %child, "Skipping child manifest: {}", e),
+                }
+            }
+        }
+        None => image_manifests.push(manifest),
+    }
+
+    summary.manifests = manifest_digests.len();
+    cache.unpin_manifest(name, reference);
+    for digest in &manifest_digests {
+        cache.unpin_manifest(name, digest);
+    }
+
+    let mut kept = pinned_digests(cache).await;
+    kept.extend(
+        cache
+            .entries()
+            .into_iter()
+            .filter(|(_, entry)| entry.retained)
+            .map(|(digest, _)| digest),
+    );
+    let blobs = image_manifests.iter().flat_map(blob_digests);
+    let digests: HashSet<String> = manifest_digests.iter().cloned().chain(blobs).collect();
+    for digest in digests {
+        if kept.contains(&digest) {
+            summary.blobs_kept += 1;
+        } else if let Some(blob) = cache.lookup(&digest) {
+            cache.remove(&digest);
+            summary.blobs_removed += 1;
+            summary.bytes_freed += blob.size;
+        }
+    }
+
+    tracing::info!(
+        image = %name,
+        reference = %reference,
+        removed = summary.blobs_removed,
+        kept = summary.blobs_kept,
+        bytes = summary.bytes_freed,
+        "Purge finished"
+    );
+    Ok(summary)
+}
+
+// A manifest and its digest, from the pinned copy when there is one
+async fn resolve_manifest(
+    proxy: &DockerProxy,
+    name: &str,
+    reference: &str,
+) -> ProxyResult<(String, JsonValue)> {
+    if let Some(cache) = proxy.cache()
+        && let Some((pinned, blob)) = cache.lookup_manifest(name, reference)
+        && let Ok(body) = tokio::fs::read(&blob.path).await
+    {
+        return Ok((pinned.digest, parse_manifest(&body)?));
+    }
+    let (_, body) = proxy.get_manifest(name, reference, &[]).await?;
+    let digest = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+    Ok((digest, parse_manifest(&body)?))
+}
+
+// Digests referenced by the pinned manifests: the manifests themselves, the
+// children of indexes and the blobs of every manifest stored in the cache
+async fn pinned_digests(cache: &cache::BlobCache) -> HashSet<String> {
+    let mut digests = HashSet::new();
+    let mut pending: Vec<String> = cache
+        .pinned_manifests()
+        .into_iter()
+        .map(|(_, pin)| pin.digest)
+        .collect();
+    while let Some(digest) = pending.pop() {
+        if !digests.insert(digest.clone()) {
+            continue;
+        }
+        let Some(blob) = cache.lookup(&digest) else {
+            continue;
+        };
+        let Ok(body) = tokio::fs::read(&blob.path).await else {
+            continue;
+        };
+        let Ok(manifest) = parse_manifest(&body) else {
+            continue;
+        };
+        pending.extend(
+            manifest
+                .get("manifests")
+                .and_then(|m| m.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|child| child.get("digest").and_then(|d| d.as_str()))
+                .map(String::from),
+        );
+        digests.extend(blob_digests(&manifest));
+    }
+    digests
+}
+
+fn parse_manifest(body: &[u8]) -> ProxyResult<JsonValue> {
+    serde_json::from_slice(body)
+        .map_err(|e| ProxyError::ResponseReadError(format!("invalid manifest: {}", e)))
+}
+
+// Child manifest digests of an index matching one of `platforms`
+fn select_platforms(children: &[JsonValue], platforms: &[String]) -> Vec<String> {
+    children
+        .iter()
+        .filter(|child| {
+            let Some(platform) = child.get("platform") else {
+                return false;
+            };
+            let field = |key: &str| platform.get(key).and_then(|v| v.as_str()).unwrap_or("");
+            platforms.iter().any(|wanted| {
+                let mut parts = wanted.split('/');
+                parts.next() == Some(field("os"))
+                    && parts.next() == Some(field("architecture"))
+                    && parts
+                        .next()
+                        .is_none_or(|variant| variant == field("variant"))
+            })
+        })
+        .filter_map(|child| child.get("digest").and_then(|d| d.as_str()))
+        .map(String::from)
+        .collect()
+}
+
+/// Config and layer digests of an image manifest, or the blobs of an OCI
+/// artifact manifest. The `subject` is a manifest and not included.
+pub fn blob_digests(manifest: &JsonValue) -> Vec<String> {
+    let config = manifest.get("config").into_iter();
+    let layers = ["layers", "blobs"]
+        .into_iter()
+        .filter_map(|field| manifest.get(field).and_then(|l| l.as_array()))
+        .flatten();
+    config
+        .chain(layers)
+        .filter_map(|d| d.get("digest").and_then(|d| d.as_str()))
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_with_retries() {
+        let outage = || ProxyError::BlobNotFound {
+            status: reqwest::StatusCode::SERVICE_UNAVAILABLE,
+        };
+
+        // Outages are retried until an attempt succeeds
+        let mut attempts = 0;
+        let result = with_retries(2, Duration::ZERO, "sha256:a", || {
+            attempts += 1;
+            let result = if attempts < 3 { Err(outage()) } else { Ok(7) };
+            async move { result }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(attempts, 3);
+
+        // ...but only `retries` times
+        let mut attempts = 0;
+        let result: ProxyResult<()> = with_retries(1, Duration::ZERO, "sha256:a", || {
+            attempts += 1;
+            async { Err(outage()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+
+        // Missing blobs are not
+        let mut attempts = 0;
+        let result: ProxyResult<()> = with_retries(2, Duration::ZERO, "sha256:a", || {
+            attempts += 1;
+            async {
+                Err(ProxyError::BlobNotFound {
+                    status: reqwest::StatusCode::NOT_FOUND,
+                })
+            }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_purge_keeps_shared_and_pushed_blobs() {
+        let dir = std::env::temp_dir().join(format!("purge-{}", uuid::Uuid::new_v4()));
+        let config = crate::config::Config::from_str(&format!(
+            "[cache]\nenabled = true\ndir = \"{}\"\n",
+            dir.display()
+        ))
+        .unwrap();
+        let proxy = DockerProxy::new(&config);
+        let cache = proxy.cache().unwrap();
+
+        let store = |content: &str, retained: bool| {
+            cache
+                .store_blob(None, &mut content.as_bytes(), retained)
+                .unwrap()
+                .0
+        };
+        let shared = store("shared layer", false);
+        let own = store("own layer", false);
+        let pushed = store("pushed layer", true);
+        let pin = |name: &str, layers: &[&String]| {
+            let layers: Vec<_> = layers
+                .iter()
+                .map(|digest| serde_json::json!({ "digest": digest }))
+                .collect();
+            let manifest = serde_json::json!({ "schemaVersion": 2, "layers": layers });
+            let digest = store(&manifest.to_string(), false);
+            let pin = cache::ManifestRef {
+                digest: digest.clone(),
+                media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+            };
+            cache.pin_manifest(name, "v1", pin);
+            digest
+        };
+        let manifest = pin("lab/app", &[&shared, &own, &pushed]);
+        pin("lab/other", &[&shared]);
+
+        let summary = purge_image(&proxy, "lab/app", "v1").await.unwrap();
+        assert_eq!(summary.manifests, 1);
+        assert_eq!(summary.blobs_removed, 2);
+        assert_eq!(summary.blobs_kept, 2);
+        for (digest, kept) in [
+            (&manifest, false),
+            (&own, false),
+            (&shared, true),
+            (&pushed, true),
+        ] {
+            assert_eq!(cache.lookup(digest).is_some(), kept, "{}", digest);
+        }
+        assert!(cache.lookup_manifest("lab/app", "v1").is_none());
+        assert!(cache.lookup_manifest("lab/other", "v1").is_some());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_select_platforms() {
+        let index: JsonValue = serde_json::from_str(
+            r#"{"manifests": [
+                {"digest": "sha256:amd64", "platform": {"os": "linux", "architecture": "amd64"}},
+                {"digest": "sha256:armv7", "platform": {"os": "linux", "architecture": "arm", "variant": "v7"}},
+                {"digest": "sha256:arm64", "platform": {"os": "linux", "architecture": "arm64", "variant": "v8"}},
+                {"digest": "sha256:attest", "platform": {"os": "unknown", "architecture": "unknown"}}
+            ]}"#,
+        )
+        .unwrap();
+        let children = index["manifests"].as_array().unwrap();
+
+        assert_eq!(
+            select_platforms(children, &["linux/amd64".to_string()]),
+            vec!["sha256:amd64"]
+        );
+        // without a variant any variant matches
+        assert_eq!(
+            select_platforms(
+                children,
+                &["linux/arm64".to_string(), "linux/arm/v6".to_string()]
+            ),
+            vec!["sha256:arm64"]
+        );
+        assert!(select_platforms(children, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_blob_digests() {
+        let manifest: JsonValue = serde_json::from_str(
+            r#"{
+                "schemaVersion": 2,
+                "config": {"digest": "sha256:cfg"},
+                "layers": [{"digest": "sha256:l1"}, {"digest": "sha256:l2"}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            blob_digests(&manifest),
+            vec!["sha256:cfg", "sha256:l1", "sha256:l2"]
+        );
+        assert!(blob_digests(&serde_json::json!({})).is_empty());
+
+        let artifact: JsonValue = serde_json::from_str(
+            r#"{
+                "mediaType": "application/vnd.oci.artifact.manifest.v1+json",
+                "artifactType": "application/spdx+json",
+                "blobs": [{"digest": "sha256:sbom"}],
+                "subject": {"digest": "sha256:image"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(blob_digests(&artifact), vec!["sha256:sbom"]);
+    }
+}
@@ -1,42 +1,589 @@
-use crate::config::Config;
+use crate::acr::AcrAuth;
+use crate::api_keys::ApiKeys;
+use crate::auth::{self, TokenCache};
+use crate::auth_monitor::AuthMonitor;
+use crate::cache::BlobCache;
+use crate::chain::UpstreamProxy;
+use crate::client_auth::{Anonymous, ClientAuth};
+use crate::clock::{self, Clock, Random};
+use crate::config::{AuthConfig, Config, PushMode, RegistryCredentials, RegistryOptions};
+use crate::connections::{self, ConnectionLimits, FetchKind};
+use crate::credential_helper::CredentialHelpers;
+use crate::ecr::{EcrAuth, EcrRegistry};
+use crate::egress::EgressStats;
 use crate::error::{ProxyError, ProxyResult};
+use crate::hot_ranges::HotRanges;
+use crate::load_shedding::LoadShedder;
+use crate::local_registry::LocalRegistry;
+use crate::maintenance::Maintenance;
+use crate::oidc::Oidc;
+use crate::policy::{Denial, Policy};
+use crate::privacy::{self, ClientIdentifier};
+use crate::pull_stats::PullStats;
+use crate::quotas::Quotas;
+use crate::rate_limits::RateLimits;
+use crate::request_rates::RequestRates;
+use crate::resolve::DnsResolver;
+use crate::router;
+use crate::runtime_config::RuntimeConfig;
+use crate::shadow::ShadowEvaluator;
+use crate::signing::ResponseSigner;
+use crate::spill::SpillPolicy;
+use crate::telemetry;
+use crate::tls::UpstreamClients;
+use crate::trust::TrustMetadata;
+use crate::uploads::{UploadSession, UploadSessions};
+use crate::upstream_metrics::{UpstreamError, UpstreamMetrics};
+use base64::Engine;
+use futures_util::{Stream, StreamExt, stream};
 use reqwest::Method;
 use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::Instrument;
+
+/// Upper bound on `Link`-paginated tag list requests
+const MAX_TAG_PAGES: usize = 50;
+
+/// Manifest types requested when the client did not send an Accept header.
+/// OCI artifacts (Helm charts, signatures, SBOMs) use the OCI image manifest
+/// with an `artifactType`, or the artifact manifest.
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.oci.artifact.manifest.v1+json",
+];
+
+/// Limits of one upstream request; unset ones leave the client's
+#[derive(Debug, Clone, Copy, Default)]
+struct RequestTimeouts {
+    /// Until the response starts
+    first_byte: Option<Duration>,
+    /// Until the response body has been read
+    total: Option<Duration>,
+}
+
+impl RequestTimeouts {
+    fn from_secs(first_byte_secs: u64, total_secs: u64) -> Self {
+        let secs = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+        Self {
+            first_byte: secs(first_byte_secs),
+            total: secs(total_secs),
+        }
+    }
+}
+
+/// Outcome of a health check of the default registry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegistryHealth {
+    pub healthy: bool,
+    /// Unix time of the check
+    pub checked_at: u64,
+}
 
 pub struct DockerProxy {
-    client: reqwest::Client,
+    clients: UpstreamClients,
     registry_url: String,
+    cache: Option<Arc<BlobCache>>,
+    hot_ranges: Option<HotRanges>,
+    auth_monitor: AuthMonitor,
+    auth: AuthConfig,
+    tokens: TokenCache,
+    signer: Option<ResponseSigner>,
+    allow_delete: bool,
+    uploads: UploadSessions,
+    local: Option<LocalRegistry>,
+    client_ids: Box<dyn ClientIdentifier>,
+    maintenance: Maintenance,
+    load_shedder: Option<Arc<LoadShedder>>,
+    policy: Option<Policy>,
+    client_auth: Option<Arc<ClientAuth>>,
+    oidc: Option<Oidc>,
+    anonymous: Option<Anonymous>,
+    api_keys: Option<ApiKeys>,
+    credential_helpers: CredentialHelpers,
+    ecr: EcrAuth,
+    acr: AcrAuth,
+    shadow: Option<ShadowEvaluator>,
+    upstream_proxy: Option<UpstreamProxy>,
+    trust: Option<TrustMetadata>,
+    pull_stats: Option<Arc<PullStats>>,
+    quotas: Option<Arc<Quotas>>,
+    egress: EgressStats,
+    upstream_metrics: Arc<UpstreamMetrics>,
+    connections: ConnectionLimits,
+    rate_limits: RateLimits,
+    request_rates: RequestRates,
+    runtime_config: RuntimeConfig,
+    /// Whether spans are created for export over OTLP
+    telemetry_enabled: bool,
+    web_root: std::path::PathBuf,
+    path_prefix: String,
+    /// Mirror URLs keyed by registry host, without trailing slashes
+    mirrors: HashMap<String, Vec<String>>,
+    mirror_timeout: Duration,
+    /// Delay before a slow manifest fetch is also sent to the next mirror
+    hedge_delay: Option<Duration>,
+    token_timeout: Option<Duration>,
+    manifest_timeouts: RequestTimeouts,
+    blob_timeouts: RequestTimeouts,
+    /// Whether blobs are hashed as they stream from the upstream
+    verify_blob_digests: bool,
+    /// How long a registry health check result is reused; None checks on
+    /// every call
+    health_check_interval: Option<Duration>,
+    registry_health: Mutex<Option<RegistryHealth>>,
+    prefetch_concurrency: usize,
+    prefetch_retries: u32,
+    spill: SpillPolicy,
+    /// Upstream registry URLs keyed by name prefix
+    routes: HashMap<String, String>,
+    registries: HashMap<String, RegistryOptions>,
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn Random>,
 }
 
 impl DockerProxy {
     pub fn new(config: &Config) -> Self {
+        Self::with_clock(config, clock::system(), clock::os_random())
+    }
+
+    /// Build the proxy with the given time and identifier sources, which are
+    /// shared with every subsystem
+    pub fn with_clock(config: &Config, clock: Arc<dyn Clock>, random: Arc<dyn Random>) -> Self {
         // Normalize default registry URL from config
-        let mut registry_url = config.default_registry().to_string();
-        if !registry_url.starts_with("http://") && !registry_url.starts_with("https://") {
-            registry_url = format!("https://{}", registry_url);
-        }
-
-        // Build client without automatic content decoding to preserve blob sizes
-        let client = reqwest::Client::builder()
-            .no_gzip()
-            .no_brotli()
-            .no_deflate()
-            .build()
-            .unwrap_or_else(|e| {
-                tracing::warn!("Failed to build custom client, using default: {}", e);
-                reqwest::Client::new()
-            });
+        // Docker Hub aliases such as docker.io only redirect, use the API host
+        let registry_url = router::registry_endpoint(&config.default_registry_url());
+
+        let resolver = DnsResolver::new(&config.resolve, Arc::clone(&clock));
+        let clients =
+            UpstreamClients::from_config(&config.proxy, &config.client, Arc::new(resolver));
+
+        let cache = if config.cache.enabled {
+            match BlobCache::open(&config.cache, Arc::clone(&clock), Arc::clone(&random)) {
+                Ok(cache) => Some(Arc::new(cache)),
+                Err(e) => {
+                    tracing::error!("Failed to open blob cache, caching disabled: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let local = match (&config.proxy.push_mode, &cache) {
+            (PushMode::Local, Some(cache)) => Some(LocalRegistry::new(
+                Arc::clone(cache),
+                &config.proxy,
+                Arc::clone(&clock),
+                Arc::clone(&random),
+            )),
+            (PushMode::Local, None) => {
+                tracing::error!("Local push mode needs the blob cache, forwarding pushes upstream");
+                None
+            }
+            (PushMode::Forward, _) => None,
+        };
+
+        let shadow = if config.shadow.is_enabled() {
+            ShadowEvaluator::load(config, Arc::clone(&clock))
+                .inspect(|_| {
+                    tracing::info!(
+                        "Shadow evaluating candidate config {}",
+                        config.shadow.candidate
+                    )
+                })
+                .inspect_err(|e| tracing::error!("{}, shadow evaluation disabled", e))
+                .ok()
+        } else {
+            None
+        };
+
+        if let Some(credentials) = config.auth.credentials_for(router::DOCKER_HUB_HOST) {
+            tracing::info!(
+                "Docker Hub requests authenticated as {}",
+                credentials.username
+            );
+        }
 
         Self {
-            client,
+            anonymous: Anonymous::from_config(&config.client_auth),
+            api_keys: ApiKeys::from_config(&config.admin),
+            oidc: Oidc::from_config(
+                &config.oidc,
+                clients.for_url(&config.oidc.issuer).clone(),
+                Arc::clone(&clock),
+            ),
+            credential_helpers: CredentialHelpers::new(Arc::clone(&clock)),
+            ecr: EcrAuth::new(clients.shared().clone(), Arc::clone(&clock)),
+            acr: AcrAuth::new(clients.shared().clone(), Arc::clone(&clock)),
+            clients,
             registry_url,
+            cache,
+            hot_ranges: HotRanges::from_config(&config.cache),
+            auth_monitor: AuthMonitor::new(Arc::clone(&clock)),
+            auth: config.auth.clone(),
+            tokens: TokenCache::new(Arc::clone(&clock)),
+            signer: ResponseSigner::from_config(&config.cache),
+            allow_delete: config.proxy.allow_delete,
+            uploads: UploadSessions::new(Arc::clone(&clock)),
+            local,
+            client_ids: privacy::from_config(&config.privacy),
+            maintenance: Maintenance::new(&config.maintenance, Arc::clone(&clock)),
+            load_shedder: config
+                .load_shedding
+                .is_enabled()
+                .then(|| Arc::new(LoadShedder::new(&config.load_shedding))),
+            policy: Policy::from_config(&config.policy),
+            client_auth: ClientAuth::from_config(
+                &config.client_auth,
+                Arc::clone(&clock),
+                Arc::clone(&random),
+            )
+            .map(Arc::new),
+            shadow,
+            upstream_proxy: UpstreamProxy::from_config(&config.chain),
+            trust: TrustMetadata::from_config(&config.trust, Arc::clone(&clock)),
+            pull_stats: config
+                .stats
+                .enabled
+                .then(|| Arc::new(PullStats::open(&config.stats, Arc::clone(&clock)))),
+            quotas: config
+                .quotas
+                .enabled
+                .then(|| Arc::new(Quotas::open(&config.quotas, Arc::clone(&clock)))),
+            egress: EgressStats::new(),
+            upstream_metrics: Arc::new(UpstreamMetrics::new()),
+            connections: ConnectionLimits::new(&config.client),
+            rate_limits: RateLimits::new(&config.client, Arc::clone(&clock)),
+            request_rates: RequestRates::new(Arc::clone(&clock)),
+            runtime_config: RuntimeConfig::new(config),
+            telemetry_enabled: config.telemetry.enabled,
+            web_root: config.web_root(),
+            path_prefix: config.server.path_prefix().to_string(),
+            mirrors: config
+                .proxy
+                .mirrors
+                .iter()
+                .map(|(host, urls)| {
+                    let urls = urls
+                        .iter()
+                        .map(|url| url.trim_end_matches('/').to_string())
+                        .collect();
+                    // requests for docker.io go to the Docker Hub API host
+                    let host = if router::is_docker_hub_alias(host) {
+                        router::DOCKER_HUB_HOST.to_string()
+                    } else {
+                        host.clone()
+                    };
+                    (host, urls)
+                })
+                .collect(),
+            mirror_timeout: Duration::from_secs(config.proxy.mirror_timeout_secs),
+            hedge_delay: (config.proxy.hedge_delay_ms > 0)
+                .then(|| Duration::from_millis(config.proxy.hedge_delay_ms)),
+            token_timeout: (config.client.token_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.client.token_timeout_secs)),
+            manifest_timeouts: RequestTimeouts::from_secs(0, config.client.manifest_timeout_secs),
+            blob_timeouts: RequestTimeouts::from_secs(
+                config.client.blob_first_byte_timeout_secs,
+                config.client.blob_timeout_secs,
+            ),
+            verify_blob_digests: config.proxy.verify_blob_digests,
+            health_check_interval: (config.server.health_check_interval_secs > 0)
+                .then(|| Duration::from_secs(config.server.health_check_interval_secs)),
+            registry_health: Mutex::new(None),
+            prefetch_concurrency: config.cache.prefetch_concurrency,
+            prefetch_retries: config.cache.prefetch_retries,
+            spill: SpillPolicy {
+                threshold: (config.proxy.spill_threshold_mb * 1024 * 1024) as usize,
+                dir: if config.proxy.spill_dir.is_empty() {
+                    std::env::temp_dir()
+                } else {
+                    std::path::PathBuf::from(&config.proxy.spill_dir)
+                },
+            },
+            routes: config.proxy.routes.clone(),
+            registries: config.proxy.registries.clone(),
+            clock,
+            random,
+        }
+    }
+
+    /// Source of the current time
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Source of generated identifiers
+    pub fn random(&self) -> &dyn Random {
+        self.random.as_ref()
+    }
+
+    /// Upstream authentication failure tracking
+    pub fn auth_monitor(&self) -> &AuthMonitor {
+        &self.auth_monitor
+    }
+
+    /// Signer for cache-served content, if a signing key is configured
+    pub fn signer(&self) -> Option<&ResponseSigner> {
+        self.signer.as_ref()
+    }
+
+    /// In-progress blob upload sessions
+    pub fn uploads(&self) -> &UploadSessions {
+        &self.uploads
+    }
+
+    /// Local registry storage, when pushes are stored instead of forwarded
+    pub fn local_registry(&self) -> Option<&LocalRegistry> {
+        self.local.as_ref()
+    }
+
+    /// Client identifier (e.g. IP) as it may be logged or recorded
+    pub fn client_id(&self, raw: &str) -> String {
+        self.client_ids.identify(raw)
+    }
+
+    /// Maintenance mode state
+    pub fn maintenance(&self) -> &Maintenance {
+        &self.maintenance
+    }
+
+    /// Load shedding under resource pressure, if any threshold is configured
+    pub fn load_shedder(&self) -> Option<&Arc<LoadShedder>> {
+        self.load_shedder.as_ref()
+    }
+
+    /// Check a request for the client-facing `name` against the `[policy]`
+    /// rules, which see the upstream repository the name resolves to
+    pub fn check_policy(
+        &self,
+        method: &Method,
+        name: &str,
+        reference: Option<&str>,
+    ) -> Result<(), Denial> {
+        let Some(policy) = &self.policy else {
+            return Ok(());
+        };
+        let (registry_url, repository) = self.split_registry_and_name(name);
+        let default = registry_url == router::registry_endpoint(&self.registry_url);
+        policy.check(method, &registry_url, &repository, default, reference)
+    }
+
+    /// Whether clients may be limited to some repositories, by client auth
+    /// grants, anonymous access rules or OIDC; cached blobs are then only
+    /// served under repositories they are known to belong to
+    pub fn restricts_repositories(&self) -> bool {
+        self.client_auth
+            .as_ref()
+            .is_some_and(|auth| auth.has_grants())
+            || self.anonymous.is_some()
+            || self.oidc.is_some()
+    }
+
+    /// Authentication required from clients, if configured
+    pub fn client_auth(&self) -> Option<&Arc<ClientAuth>> {
+        self.client_auth.as_ref()
+    }
+
+    /// Operations allowed without credentials, if any
+    pub fn anonymous(&self) -> Option<&Anonymous> {
+        self.anonymous.as_ref()
+    }
+
+    /// Whether request spans are exported (`[telemetry]`)
+    pub fn telemetry_enabled(&self) -> bool {
+        self.telemetry_enabled
+    }
+
+    /// Keys required on the admin and debug endpoints, if configured
+    pub fn api_keys(&self) -> Option<&ApiKeys> {
+        self.api_keys.as_ref()
+    }
+
+    /// Validation of tokens from an OIDC provider, if configured
+    pub fn oidc(&self) -> Option<&Oidc> {
+        self.oidc.as_ref()
+    }
+
+    /// Shadow evaluation of a candidate config, if one is configured
+    pub fn shadow(&self) -> Option<&ShadowEvaluator> {
+        self.shadow.as_ref()
+    }
+
+    /// The upstream docker-proxy serving `name`, when chained; names with an
+    /// explicit registry host go elsewhere
+    pub fn upstream_proxy(&self, name: &str) -> Option<&UpstreamProxy> {
+        let upstream = self.upstream_proxy.as_ref()?;
+        (self.split_registry_and_name(name).0 == self.registry_url).then_some(upstream)
+    }
+
+    /// Content trust metadata passthrough, if enabled
+    pub fn trust(&self) -> Option<&TrustMetadata> {
+        self.trust.as_ref()
+    }
+
+    /// Per-repository pull statistics, if enabled
+    pub fn pull_stats(&self) -> Option<&Arc<PullStats>> {
+        self.pull_stats.as_ref()
+    }
+
+    /// Per-user pull quotas, if enabled
+    pub fn quotas(&self) -> Option<&Arc<Quotas>> {
+        self.quotas.as_ref()
+    }
+
+    /// Bytes served from the cache versus fetched upstream, by registry
+    pub fn egress(&self) -> &EgressStats {
+        &self.egress
+    }
+
+    /// Latencies and failures of upstream requests, by registry
+    pub fn upstream_metrics(&self) -> &UpstreamMetrics {
+        &self.upstream_metrics
+    }
+
+    /// Connections to upstream registries
+    pub fn connections(&self) -> &ConnectionLimits {
+        &self.connections
+    }
+
+    /// Rate limits reported by upstream registries
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.rate_limits
+    }
+
+    /// Requests handled per minute over the last hour
+    pub fn request_rates(&self) -> &RequestRates {
+        &self.request_rates
+    }
+
+    /// Host of the upstream registry serving `name`
+    pub fn runtime_config(&self) -> &RuntimeConfig {
+        &self.runtime_config
+    }
+
+    /// Apply a JSON merge patch of the options adjustable at runtime, see
+    /// `RuntimeConfig`
+    pub fn patch_config(&self, patch: &JsonValue) -> Result<(), String> {
+        self.runtime_config.update(patch, |current, patched| {
+            let cache_changed = patched.cache.max_size_mb != current.cache.max_size_mb;
+            if cache_changed && self.cache.is_none() {
+                return Err("The blob cache is disabled".to_string());
+            }
+            let quotas_changed = patched.quotas.default != current.quotas.default
+                || patched.quotas.users != current.quotas.users;
+            if quotas_changed && self.quotas.is_none() {
+                return Err("Pull quotas are disabled".to_string());
+            }
+            if patched.log.normalized_level() != current.log.normalized_level() {
+                crate::log::set_level(&patched.log.level)?;
+            }
+            if let Some(cache) = self.cache.as_ref().filter(|_| cache_changed) {
+                cache.set_max_size(patched.cache.max_size_mb * 1024 * 1024);
+            }
+            if let Some(quotas) = self.quotas.as_ref().filter(|_| quotas_changed) {
+                quotas.set_limits(&patched.quotas);
+            }
+            Ok(())
+        })
+    }
+
+    pub fn upstream_host(&self, name: &str) -> String {
+        let (registry_url, _) = self.split_registry_and_name(name);
+        match registry_url.split_once("://") {
+            Some((_, host)) => host.to_string(),
+            None => registry_url,
         }
     }
 
-    pub async fn get_manifest(&self, name: &str, reference: &str) -> ProxyResult<(String, String)> {
+    /// Host of the upstream registry serving `name` and the repository there,
+    /// e.g. `("registry-1.docker.io", "library/nginx")` for `nginx`
+    pub fn upstream_repository(&self, name: &str) -> (String, String) {
+        let (registry_url, repository) = self.split_registry_and_name(name);
+        let host = match registry_url.split_once("://") {
+            Some((_, host)) => host.to_string(),
+            None => registry_url,
+        };
+        (host, repository)
+    }
+
+    /// Directory the web UI is served from
+    pub fn web_root(&self) -> &std::path::Path {
+        &self.web_root
+    }
+
+    /// URL prefix the service is mounted under; empty at the root
+    pub fn path_prefix(&self) -> &str {
+        &self.path_prefix
+    }
+
+    /// Whether DELETE requests are forwarded upstream
+    pub fn deletes_allowed(&self) -> bool {
+        self.allow_delete
+    }
+
+    /// The local blob cache, if enabled
+    pub fn cache(&self) -> Option<&Arc<BlobCache>> {
+        self.cache.as_ref()
+    }
+
+    /// In-memory cache of hot blob regions for small range requests, if enabled
+    pub fn hot_ranges(&self) -> Option<&HotRanges> {
+        self.hot_ranges.as_ref()
+    }
+
+    /// Blobs of an image pre-fetched at once
+    pub fn prefetch_concurrency(&self) -> usize {
+        self.prefetch_concurrency
+    }
+
+    /// Extra attempts for a blob pre-fetch after an upstream outage
+    pub fn prefetch_retries(&self) -> u32 {
+        self.prefetch_retries
+    }
+
+    /// How bodies read in full are buffered
+    pub fn spill_policy(&self) -> &SpillPolicy {
+        &self.spill
+    }
+
+    /// Fetch a manifest, negotiating its type with the client's `accept`
+    /// values (all known manifest types when empty). The body is returned
+    /// byte for byte so its digest is preserved.
+    pub async fn get_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        accept: &[&str],
+    ) -> ProxyResult<(String, bytes::Bytes)> {
+        let response = self.get_manifest_stream(name, reference, accept).await?;
+        let content_type = manifest_content_type(&response);
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| ProxyError::ResponseReadError(e.to_string()))?;
+        verify_manifest_digest(reference, &body)?;
+
+        Ok((content_type, body))
+    }
+
+    /// The upstream's successful manifest response, its body not yet read so
+    /// it can be streamed to the client; check it with `DigestVerifier`
+    pub async fn get_manifest_stream(
+        &self,
+        name: &str,
+        reference: &str,
+        accept: &[&str],
+    ) -> ProxyResult<reqwest::Response> {
         // allow name to include a registry prefix (e.g. "ghcr.io/vansour/gh-proxy")
         let (registry_url, image_name) = self.split_registry_and_name(name);
-        let url = format!("{}/v2/{}/manifests/{}", registry_url, image_name, reference);
+        let url = upstream_url(&registry_url, &image_name, "manifests", reference);
 
         tracing::info!(
             registry = %registry_url,
@@ -45,21 +592,10 @@ impl DockerProxy {
             "Fetching manifest"
         );
 
+        // the fetch counts against max_manifest_fetches until its body is read
+        let fetch = self.connections.acquire_fetch(FetchKind::Manifest).await;
         let response = self
-            .fetch_with_auth(
-                Method::GET,
-                &url,
-                Some(vec![
-                    (
-                        "Accept",
-                        "application/vnd.docker.distribution.manifest.v2+json",
-                    ),
-                    (
-                        "Accept",
-                        "application/vnd.docker.distribution.manifest.list.v2+json",
-                    ),
-                ]),
-            )
+            .fetch_manifest(Method::GET, &url, Some(accept_headers(accept)))
             .await?;
 
         if !response.status().is_success() {
@@ -67,25 +603,18 @@ impl DockerProxy {
                 status: response.status(),
             });
         }
-
-        let content_type = response
-            .headers()
-            .get("content-type")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("application/json")
-            .to_string();
-
-        let body = response
-            .text()
-            .await
-            .map_err(|e| ProxyError::ResponseReadError(e.to_string()))?;
-
-        Ok((content_type, body))
+        Ok(connections::hold(response, fetch))
     }
 
-    pub async fn head_manifest(&self, name: &str, reference: &str) -> ProxyResult<(String, u64)> {
+    /// Content type, length and (if reported) digest of a manifest
+    pub async fn head_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        accept: &[&str],
+    ) -> ProxyResult<(String, u64, Option<String>)> {
         let (registry_url, image_name) = self.split_registry_and_name(name);
-        let url = format!("{}/v2/{}/manifests/{}", registry_url, image_name, reference);
+        let url = upstream_url(&registry_url, &image_name, "manifests", reference);
 
         tracing::info!(
             registry = %registry_url,
@@ -94,15 +623,9 @@ impl DockerProxy {
             "HEAD request for manifest"
         );
 
+        let _fetch = self.connections.acquire_fetch(FetchKind::Manifest).await;
         let response = self
-            .fetch_with_auth(
-                Method::HEAD,
-                &url,
-                Some(vec![(
-                    "Accept",
-                    "application/vnd.docker.distribution.manifest.v2+json",
-                )]),
-            )
+            .fetch_manifest(Method::HEAD, &url, Some(accept_headers(accept)))
             .await?;
 
         if !response.status().is_success() {
@@ -125,12 +648,25 @@ impl DockerProxy {
             .and_then(|s| s.parse::<u64>().ok())
             .unwrap_or(0);
 
-        Ok((content_type, content_length))
+        let digest = response
+            .headers()
+            .get("docker-content-digest")
+            .and_then(|h| h.to_str().ok())
+            .map(String::from);
+
+        Ok((content_type, content_length, digest))
     }
 
-    pub async fn get_blob(&self, name: &str, digest: &str) -> ProxyResult<reqwest::Response> {
+    /// Fetch a blob; `range_headers` (the client's `Range` and `If-Range`)
+    /// are forwarded, so the upstream may answer 206 with part of it
+    pub async fn get_blob(
+        &self,
+        name: &str,
+        digest: &str,
+        range_headers: Vec<(&str, &str)>,
+    ) -> ProxyResult<reqwest::Response> {
         let (registry_url, image_name) = self.split_registry_and_name(name);
-        let url = format!("{}/v2/{}/blobs/{}", registry_url, image_name, digest);
+        let url = upstream_url(&registry_url, &image_name, "blobs", digest);
 
         tracing::info!(
             registry = %registry_url,
@@ -139,15 +675,75 @@ impl DockerProxy {
             "Fetching blob"
         );
 
-        let response = self.fetch_with_auth(Method::GET, &url, None).await?;
+        let extra_headers = (!range_headers.is_empty()).then_some(range_headers);
+        // the download counts against max_blob_fetches until its body is read
+        let fetch = self.connections.acquire_fetch(FetchKind::Blob).await;
+        let response = self
+            .fetch_read(Method::GET, &url, extra_headers, self.blob_timeouts)
+            .await?;
 
         // 始终返回上游响应，由上层根据状态码决定如何处理
-        Ok(response)
+        Ok(connections::hold(response, fetch))
+    }
+
+    /// The body of a blob fetched with `get_blob`, hashed as it streams when
+    /// `verify_blob_digests` is set. A mismatch with `digest` is logged,
+    /// counted for the registry and takes the place of the last chunk as an
+    /// error, so the client sees a broken transfer and a cache fill
+    /// (`cache::tee`) downstream is dropped. The digest is checked once the
+    /// announced length has been seen (the server stops polling a body at its
+    /// Content-Length) or at the end of the body. Partial (206) responses are
+    /// passed through unchecked.
+    pub fn blob_body(
+        &self,
+        digest: &str,
+        response: reqwest::Response,
+    ) -> impl Stream<Item = Result<bytes::Bytes, std::io::Error>> + Send + 'static {
+        let verifier = (self.verify_blob_digests && response.status() == reqwest::StatusCode::OK)
+            .then(|| DigestVerifier::for_reference(digest))
+            .flatten();
+        let expected_size = response.content_length();
+        let host = url_host(response.url().as_str()).unwrap_or_default();
+        let metrics = Arc::clone(&self.upstream_metrics);
+        let mismatch = move |e: ProxyError| {
+            tracing::error!(upstream = %host, "Upstream blob failed verification: {}", e);
+            metrics.record_digest_mismatch(&host);
+            std::io::Error::other(e)
+        };
+        stream::unfold(
+            Some((Box::pin(response.bytes_stream()), verifier, 0u64, mismatch)),
+            move |state| async move {
+                let (mut inner, mut verifier, mut seen, mismatch) = state?;
+                let chunk = match inner.next().await {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => return Some((Err(std::io::Error::other(e)), None)),
+                    None => {
+                        let e = verifier.map(DigestVerifier::finish)?.err()?;
+                        return Some((Err(mismatch(e)), None));
+                    }
+                };
+                if let Some(v) = verifier.as_mut() {
+                    v.update(&chunk);
+                }
+                seen += chunk.len() as u64;
+                if expected_size == Some(seen)
+                    && let Some(Err(e)) = verifier.take().map(DigestVerifier::finish)
+                {
+                    return Some((Err(mismatch(e)), None));
+                }
+                Some((Ok(chunk), Some((inner, verifier, seen, mismatch))))
+            },
+        )
     }
 
-    pub async fn head_blob(&self, name: &str, digest: &str) -> ProxyResult<u64> {
+    /// Headers of the upstream's answer to a HEAD request for a blob
+    pub async fn head_blob(
+        &self,
+        name: &str,
+        digest: &str,
+    ) -> ProxyResult<reqwest::header::HeaderMap> {
         let (registry_url, image_name) = self.split_registry_and_name(name);
-        let url = format!("{}/v2/{}/blobs/{}", registry_url, image_name, digest);
+        let url = upstream_url(&registry_url, &image_name, "blobs", digest);
 
         tracing::info!(
             registry = %registry_url,
@@ -156,22 +752,144 @@ impl DockerProxy {
             "HEAD request for blob"
         );
 
-        let response = self.fetch_with_auth(Method::HEAD, &url, None).await?;
+        let _fetch = self.connections.acquire_fetch(FetchKind::Blob).await;
+        let response = self
+            .fetch_read(Method::HEAD, &url, None, self.blob_timeouts)
+            .await?;
 
         if !response.status().is_success() {
             return Err(ProxyError::BlobNotFound {
                 status: response.status(),
             });
         }
+        Ok(response.headers().clone())
+    }
 
-        let content_length = response
+    /// List all tags of a repository, following `Link` pagination
+    pub async fn list_tags(&self, name: &str) -> ProxyResult<Vec<String>> {
+        let (registry_url, image_name) = self.split_registry_and_name(name);
+        let mut url = upstream_url(&registry_url, &image_name, "tags", "list");
+        let mut tags = Vec::new();
+
+        for _ in 0..MAX_TAG_PAGES {
+            let response = self
+                .fetch_read(Method::GET, &url, None, RequestTimeouts::default())
+                .await?;
+            if !response.status().is_success() {
+                return Err(ProxyError::TagListFailed {
+                    status: response.status(),
+                });
+            }
+
+            let next = response
+                .headers()
+                .get("link")
+                .and_then(|h| h.to_str().ok())
+                .and_then(next_page_link)
+                .map(|next| response.url().join(&next));
+            let origin = response.url().origin();
+            let body: JsonValue = response
+                .json()
+                .await
+                .map_err(|e| ProxyError::ResponseReadError(e.to_string()))?;
+            // registries return `"tags": null` for repositories without tags
+            if let Some(page) = body.get("tags").and_then(|t| t.as_array()) {
+                tags.extend(page.iter().filter_map(|t| t.as_str()).map(String::from));
+            }
+
+            // Only pages on the registry that answered are followed, so the
+            // registry's credentials are never sent to a host it points at
+            match next {
+                Some(Ok(next)) if next.origin() == origin => {
+                    url = format!("{}{}", registry_url, next.path());
+                    if let Some(query) = next.query() {
+                        url.push('?');
+                        url.push_str(query);
+                    }
+                }
+                Some(_) => {
+                    tracing::warn!(image = %image_name, "Tag list truncated at a link to another host");
+                    return Ok(tags);
+                }
+                None => return Ok(tags),
+            }
+        }
+
+        tracing::warn!(image = %image_name, "Tag list truncated after {} pages", MAX_TAG_PAGES);
+        Ok(tags)
+    }
+
+    /// Fetch one page of a repository's tag list, passing the `n` and `last`
+    /// pagination parameters through
+    pub async fn tags_page(
+        &self,
+        name: &str,
+        query: Option<&str>,
+    ) -> ProxyResult<reqwest::Response> {
+        let (registry_url, image_name) = self.split_registry_and_name(name);
+        let url = upstream_url(&registry_url, &image_name, "tags", "list");
+        self.get_page(&url, query).await
+    }
+
+    /// Fetch a content trust metadata file for `gun` from the trust server
+    pub async fn get_trust_metadata(
+        &self,
+        trust: &TrustMetadata,
+        gun: &str,
+        file: &str,
+    ) -> ProxyResult<reqwest::Response> {
+        let url = format!(
+            "{}/v2/{}/_trust/tuf/{}",
+            trust.server(),
+            router::encode_repository_path(gun),
+            router::encode_repository_path(file)
+        );
+        tracing::info!(gun = %gun, file = %file, "Fetching trust metadata");
+        self.fetch_with_auth(Method::GET, &url, None, None, RequestTimeouts::default())
+            .await
+    }
+
+    /// Fetch one page of the default registry's repository catalog
+    pub async fn catalog_page(&self, query: Option<&str>) -> ProxyResult<reqwest::Response> {
+        let url = format!("{}/v2/_catalog", self.registry_url);
+        self.get_page(&url, query).await
+    }
+
+    // GET a paginated list endpoint with the client's `n` and `last`
+    async fn get_page(&self, url: &str, query: Option<&str>) -> ProxyResult<reqwest::Response> {
+        let mut url =
+            reqwest::Url::parse(url).map_err(|e| ProxyError::InvalidRegistryUrl(e.to_string()))?;
+        let query = query.unwrap_or_default();
+        for param in ["n", "last"] {
+            if let Some(value) = router::query_param(query, param) {
+                url.query_pairs_mut().append_pair(param, &value);
+            }
+        }
+        self.fetch_read(Method::GET, url.as_str(), None, RequestTimeouts::default())
+            .await
+    }
+
+    /// Rewrite the `rel="next"` target of an upstream `Link` header into the
+    /// proxy's namespace; `name` is `None` for the catalog
+    pub fn client_next_link(
+        &self,
+        name: Option<&str>,
+        response: &reqwest::Response,
+    ) -> Option<String> {
+        let next = response
             .headers()
-            .get("content-length")
+            .get("link")
             .and_then(|h| h.to_str().ok())
-            .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(0);
-
-        Ok(content_length)
+            .and_then(next_page_link)?;
+        let resolved = response.url().join(&next).ok()?;
+        let target = match name {
+            Some(name) => self.rewrite_upstream_url(name, &resolved)?,
+            None => {
+                let query = resolved.query().map(|q| format!("?{}", q));
+                format!("/v2/_catalog{}", query.unwrap_or_default())
+            }
+        };
+        Some(format!("<{}>; rel=\"next\"", target))
     }
 
     /// 调试用：获取指定镜像+digest 的 manifest size 和实际 blob 大小
@@ -183,22 +901,15 @@ impl DockerProxy {
     ) -> ProxyResult<(u64, u64)> {
         // 1. 获取 manifest（v2 schema）并解析 size
         let (registry_url, image_name) = self.split_registry_and_name(name);
-        let manifest_url = format!("{}/v2/{}/manifests/{}", registry_url, image_name, reference);
+        let manifest_url = upstream_url(&registry_url, &image_name, "manifests", reference);
 
         let manifest_resp = self
             .fetch_with_auth(
                 Method::GET,
                 &manifest_url,
-                Some(vec![
-                    (
-                        "Accept",
-                        "application/vnd.docker.distribution.manifest.v2+json",
-                    ),
-                    (
-                        "Accept",
-                        "application/vnd.docker.distribution.manifest.list.v2+json",
-                    ),
-                ]),
+                Some(accept_headers(&[])),
+                None,
+                self.manifest_timeouts,
             )
             .await?;
 
@@ -229,8 +940,10 @@ impl DockerProxy {
         }
 
         // 2. 获取 blob，统计实际字节数
-        let blob_url = format!("{}/v2/{}/blobs/{}", registry_url, image_name, digest);
-        let blob_resp = self.fetch_with_auth(Method::GET, &blob_url, None).await?;
+        let blob_url = upstream_url(&registry_url, &image_name, "blobs", digest);
+        let blob_resp = self
+            .fetch_with_auth(Method::GET, &blob_url, None, None, self.blob_timeouts)
+            .await?;
 
         if !blob_resp.status().is_success() {
             return Err(ProxyError::BlobNotFound {
@@ -250,79 +963,1036 @@ impl DockerProxy {
         Ok((manifest_size, actual_size))
     }
 
-    pub async fn initiate_blob_upload(&self, _name: &str) -> ProxyResult<String> {
-        Err(ProxyError::BlobUploadNotSupported)
-    }
+    /// Forward a blob upload request (POST/PATCH/PUT) to the upstream registry,
+    /// streaming the request body. `uuid` is `None` for the initial
+    /// `POST .../blobs/uploads/`.
+    pub async fn forward_blob_upload(
+        &self,
+        method: Method,
+        name: &str,
+        uuid: Option<&str>,
+        query: Option<&str>,
+        headers: Vec<(&str, &str)>,
+        body: reqwest::Body,
+    ) -> ProxyResult<reqwest::Response> {
+        let (registry_url, image_name) = self.split_registry_and_name(name);
+        let query = query.filter(|q| !q.is_empty());
+        let mut url = match uuid {
+            // a client resuming without the upstream `_state` gets the last known session URL
+            Some(uuid) if query.is_none() => match self.uploads.get(name, uuid) {
+                Some(session) => session.upstream_location,
+                None => upstream_url(&registry_url, &image_name, "blobs/uploads", uuid),
+            },
+            Some(uuid) => upstream_url(&registry_url, &image_name, "blobs/uploads", uuid),
+            None => format!(
+                "{}/v2/{}/blobs/uploads/",
+                registry_url,
+                router::encode_repository_path(&image_name)
+            ),
+        };
+        if let Some(query) = query {
+            url.push('?');
+            url.push_str(&self.upstream_upload_query(query));
+        }
 
-    /// Check health of the default registry
-    /// Returns true if the registry is reachable and responding
-    pub async fn check_registry_health(&self) -> bool {
-        let url = format!("{}/v2/", self.registry_url);
+        tracing::info!(
+            registry = %registry_url,
+            image = %image_name,
+            method = %method,
+            upload = uuid.unwrap_or("-"),
+            "Forwarding blob upload"
+        );
 
-        match self
-            .client
-            .get(&url)
-            .timeout(std::time::Duration::from_secs(5))
-            .send()
-            .await
+        let response = self
+            .fetch_with_auth(
+                method.clone(),
+                &url,
+                Some(headers),
+                Some(body),
+                RequestTimeouts::default(),
+            )
+            .await?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::ACCEPTED || status == reqwest::StatusCode::NO_CONTENT {
+            self.uploads
+                .record(name, response.url(), response.headers());
+        } else if let Some(uuid) = uuid
+            && ((method == Method::PUT && status.is_success())
+                || status == reqwest::StatusCode::NOT_FOUND)
         {
-            Ok(resp) => {
-                // Registry should return 200 or 401 (401 means it's working but needs auth)
-                let status = resp.status();
-                status.is_success() || status == reqwest::StatusCode::UNAUTHORIZED
-            }
-            Err(e) => {
-                tracing::warn!("Registry health check failed: {}", e);
-                false
-            }
+            self.uploads.complete(uuid);
         }
+        Ok(response)
     }
 
-    /// Get the default registry URL
-    pub fn get_registry_url(&self) -> &str {
-        &self.registry_url
-    }
-
-    // Helper: perform a simple HTTP request with optional extra headers (no auth handling)
-    async fn fetch_with_auth(
+    /// Push a manifest to the upstream registry, forwarding the client's
+    /// credentials if it sent any
+    pub async fn put_manifest(
         &self,
-        method: Method,
-        url: &str,
-        extra_headers: Option<Vec<(&str, &str)>>,
+        name: &str,
+        reference: &str,
+        content_type: &str,
+        authorization: Option<&str>,
+        body: bytes::Bytes,
     ) -> ProxyResult<reqwest::Response> {
-        let mut req = self.client.request(method, url);
-        if let Some(hs) = &extra_headers {
-            for (k, v) in hs.iter() {
-                req = req.header(*k, *v);
-            }
-        }
+        let (registry_url, image_name) = self.split_registry_and_name(name);
+        let url = upstream_url(&registry_url, &image_name, "manifests", reference);
 
-        let resp = req.send().await?;
-        Ok(resp)
+        tracing::info!(
+            registry = %registry_url,
+            image = %image_name,
+            reference = %reference,
+            "Pushing manifest"
+        );
+
+        let mut headers = vec![("Content-Type", content_type)];
+        headers.extend(authorization.map(|value| ("Authorization", value)));
+        self.fetch_with_auth(
+            Method::PUT,
+            &url,
+            Some(headers),
+            Some(reqwest::Body::from(body)),
+            RequestTimeouts::default(),
+        )
+        .await
+    }
+
+    /// Delete a manifest or blob upstream (`endpoint` is "manifests" or
+    /// "blobs"). The client's credentials are forwarded so the registry
+    /// decides whether the caller may delete.
+    pub async fn delete(
+        &self,
+        name: &str,
+        endpoint: &str,
+        reference: &str,
+        authorization: Option<&str>,
+    ) -> ProxyResult<reqwest::Response> {
+        let (registry_url, image_name) = self.split_registry_and_name(name);
+        let url = upstream_url(&registry_url, &image_name, endpoint, reference);
+
+        tracing::info!(
+            registry = %registry_url,
+            image = %image_name,
+            reference = %reference,
+            endpoint = %endpoint,
+            "Deleting upstream object"
+        );
+
+        let headers = authorization.map(|value| vec![("Authorization", value)]);
+        self.fetch_with_auth(
+            Method::DELETE,
+            &url,
+            headers,
+            None,
+            RequestTimeouts::default(),
+        )
+        .await
+    }
+
+    /// Map an upstream `Location` header back onto this proxy's `/v2/<name>/`
+    /// namespace so clients keep talking to the proxy. Locations outside the
+    /// upstream repository (e.g. storage redirects) are returned unchanged.
+    pub fn client_location(&self, name: &str, response: &reqwest::Response) -> Option<String> {
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)?
+            .to_str()
+            .ok()?;
+        let resolved = match response.url().join(location) {
+            Ok(url) => url,
+            Err(_) => return Some(location.to_string()),
+        };
+        Some(
+            self.rewrite_upstream_url(name, &resolved)
+                .unwrap_or_else(|| location.to_string()),
+        )
+    }
+
+    /// Client-facing `Location` of a tracked upload session
+    pub fn client_upload_location(&self, session: &UploadSession) -> Option<String> {
+        let url = reqwest::Url::parse(&session.upstream_location).ok()?;
+        self.rewrite_upstream_url(&session.name, &url)
+    }
+
+    // Rewrite an upstream URL inside the repository's namespace to `/v2/<name>/...`
+    fn rewrite_upstream_url(&self, name: &str, resolved: &reqwest::Url) -> Option<String> {
+        let (_, image_name) = self.split_registry_and_name(name);
+        let prefix = format!("/v2/{}/", router::encode_repository_path(&image_name));
+        let rest = resolved.path().strip_prefix(&prefix)?;
+
+        let mut rewritten = format!("/v2/{}/{}", router::encode_repository_path(name), rest);
+        if let Some(query) = resolved.query() {
+            rewritten.push('?');
+            rewritten.push_str(query);
+        }
+        Some(rewritten)
+    }
+
+    /// Monolithic upload (`POST .../blobs/uploads/?digest=<digest>` with the
+    /// blob as body). Not every registry implements it, so it is translated
+    /// into a new upload session completed by a single PUT that streams the
+    /// body upstream.
+    pub async fn monolithic_upload(
+        &self,
+        name: &str,
+        digest: &str,
+        headers: Vec<(&str, &str)>,
+        body: reqwest::Body,
+    ) -> ProxyResult<reqwest::Response> {
+        let (registry_url, image_name) = self.split_registry_and_name(name);
+        let url = format!(
+            "{}/v2/{}/blobs/uploads/",
+            registry_url,
+            router::encode_repository_path(&image_name)
+        );
+
+        // the session is opened with the client's credentials, like the PUT
+        let mut init_headers = vec![("Content-Length", "0")];
+        init_headers.extend(
+            headers
+                .iter()
+                .filter(|(k, _)| k.eq_ignore_ascii_case("authorization"))
+                .copied(),
+        );
+        let init = self
+            .fetch_with_auth(
+                Method::POST,
+                &url,
+                Some(init_headers),
+                None,
+                RequestTimeouts::default(),
+            )
+            .await?;
+        if init.status() != reqwest::StatusCode::ACCEPTED {
+            return Ok(init);
+        }
+        let Some(mut location) = init
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|l| init.url().join(l).ok())
+        else {
+            return Err(ProxyError::InternalError(
+                "upload session response without Location".to_string(),
+            ));
+        };
+        location.query_pairs_mut().append_pair("digest", digest);
+        // frees its connection for the PUT under max_connections_per_host
+        drop(init);
+
+        tracing::info!(
+            registry = %registry_url,
+            image = %image_name,
+            digest = %digest,
+            "Forwarding monolithic blob upload"
+        );
+        self.fetch_with_auth(
+            Method::PUT,
+            location.as_str(),
+            Some(headers),
+            Some(body),
+            RequestTimeouts::default(),
+        )
+        .await
+    }
+
+    // Cross-repository mounts name the source repository with `from`, which
+    // must be translated into the upstream's namespace like the target name
+    fn upstream_upload_query(&self, query: &str) -> String {
+        let Ok(mut url) = reqwest::Url::parse("http://upstream/") else {
+            return query.to_string();
+        };
+        url.set_query(Some(query));
+        if !url.query_pairs().any(|(k, _)| k == "from") {
+            return query.to_string();
+        }
+
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(k, v)| {
+                if k == "from" {
+                    (k.into_owned(), self.split_registry_and_name(&v).1)
+                } else {
+                    (k.into_owned(), v.into_owned())
+                }
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+        url.query().unwrap_or_default().to_string()
+    }
+
+    /// Check health of the default registry
+    /// Returns true if the registry is reachable and responding
+    pub async fn check_registry_health(&self) -> bool {
+        let url = format!("{}/v2/", self.registry_url);
+
+        match self
+            .clients
+            .for_url(&url)
+            .get(&url)
+            .timeout(std::time::Duration::from_secs(5))
+            .send()
+            .await
+        {
+            Ok(resp) => {
+                // Registry should return 200 or 401 (401 means it's working but needs auth)
+                let status = resp.status();
+                status.is_success() || status == reqwest::StatusCode::UNAUTHORIZED
+            }
+            Err(e) => {
+                tracing::warn!("Registry health check failed: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Health of the default registry: with a health check interval, the
+    /// result of the last background check (made now if there was none
+    /// yet), else a live check
+    pub async fn registry_health(&self) -> RegistryHealth {
+        if self.health_check_interval.is_some()
+            && let Some(health) = *self
+                .registry_health
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+        {
+            return health;
+        }
+        self.refresh_registry_health().await
+    }
+
+    /// Re-check the default registry's health every health check interval,
+    /// so `/healthz` probes do not each cause an upstream request
+    pub fn spawn_health_check_task(self: Arc<Self>) {
+        let Some(interval) = self.health_check_interval else {
+            return;
+        };
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                self.refresh_registry_health().await;
+            }
+        });
+    }
+
+    async fn refresh_registry_health(&self) -> RegistryHealth {
+        let health = RegistryHealth {
+            healthy: self.check_registry_health().await,
+            checked_at: self.clock.now_secs(),
+        };
+        *self
+            .registry_health
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(health);
+        health
+    }
+
+    /// Probe a registry's `/v2/` endpoint with the upstream client
+    pub async fn probe_v2(
+        &self,
+        url: &str,
+        timeout: std::time::Duration,
+    ) -> ProxyResult<reqwest::Response> {
+        let resp = self
+            .clients
+            .for_url(url)
+            .get(url)
+            .timeout(timeout)
+            .send()
+            .await?;
+        Ok(resp)
+    }
+
+    /// Get the default registry URL
+    pub fn get_registry_url(&self) -> &str {
+        &self.registry_url
+    }
+
+    // Perform a read (GET/HEAD) through the mirrors configured for the URL's
+    // registry, in order: a mirror answering with a 5xx, failing or not
+    // responding within the mirror timeout is skipped for the next one. The
+    // last candidate's result is returned whatever it is.
+    async fn fetch_read(
+        &self,
+        method: Method,
+        url: &str,
+        extra_headers: Option<Vec<(&str, &str)>>,
+        timeouts: RequestTimeouts,
+    ) -> ProxyResult<reqwest::Response> {
+        let candidates = self.mirror_urls(url);
+        self.fetch_mirrors(method, url, &candidates, extra_headers, timeouts)
+            .await
+    }
+
+    // A manifest read, hedged when a hedge delay is configured and the
+    // registry has several mirrors: once the first mirror has not answered
+    // within the delay, the next one is asked as well and the first usable
+    // response wins (the next one is asked at once when the first fails).
+    // Should both fail, the remaining mirrors are tried in order.
+    async fn fetch_manifest(
+        &self,
+        method: Method,
+        url: &str,
+        extra_headers: Option<Vec<(&str, &str)>>,
+    ) -> ProxyResult<reqwest::Response> {
+        let timeouts = self.manifest_timeouts;
+        let candidates = self.mirror_urls(url);
+        let (Some(delay), [primary, backup, rest @ ..]) = (self.hedge_delay, candidates.as_slice())
+        else {
+            return self
+                .fetch_mirrors(method, url, &candidates, extra_headers, timeouts)
+                .await;
+        };
+        let usable = |result: &ProxyResult<reqwest::Response>| matches!(result, Ok(response) if !response.status().is_server_error());
+        let first = self.mirror_attempt(
+            method.clone(),
+            primary,
+            extra_headers.clone(),
+            timeouts,
+            false,
+        );
+        let second = self.mirror_attempt(
+            method.clone(),
+            backup,
+            extra_headers.clone(),
+            timeouts,
+            rest.is_empty(),
+        );
+        tokio::pin!(first, second);
+        let (mirror_url, result) = tokio::select! {
+            result = &mut first => match result {
+                result if usable(&result) => (primary, result),
+                result => {
+                    log_mirror_failure(primary, &result);
+                    (backup, second.await)
+                }
+            },
+            () = tokio::time::sleep(delay) => {
+                let mirror = primary.split("/v2/").next().unwrap_or_default();
+                tracing::info!(mirror = %mirror, url = %url, "Mirror slow, hedging with the next one");
+                tokio::select! {
+                    result = &mut first => match result {
+                        result if usable(&result) => (primary, result),
+                        result => {
+                            log_mirror_failure(primary, &result);
+                            (backup, second.await)
+                        }
+                    },
+                    result = &mut second => match result {
+                        result if usable(&result) => (backup, result),
+                        result => {
+                            log_mirror_failure(backup, &result);
+                            (primary, first.await)
+                        }
+                    },
+                }
+            }
+        };
+        if usable(&result) || rest.is_empty() {
+            let mirror = mirror_url.split("/v2/").next().unwrap_or_default();
+            tracing::info!(mirror = %mirror, url = %url, "Served by mirror");
+            return result;
+        }
+        log_mirror_failure(mirror_url, &result);
+        self.fetch_mirrors(method, url, rest, extra_headers, timeouts)
+            .await
+    }
+
+    // One read from a mirror, bounded by the mirror timeout unless it is the
+    // last candidate
+    async fn mirror_attempt(
+        &self,
+        method: Method,
+        mirror_url: &str,
+        extra_headers: Option<Vec<(&str, &str)>>,
+        timeouts: RequestTimeouts,
+        last: bool,
+    ) -> ProxyResult<reqwest::Response> {
+        let attempt = self.fetch_with_auth(method, mirror_url, extra_headers, None, timeouts);
+        if last {
+            return attempt.await;
+        }
+        tokio::time::timeout(self.mirror_timeout, attempt)
+            .await
+            .map_err(|_| ProxyError::UpstreamTimeout(self.mirror_timeout))?
+    }
+
+    // Read from `candidates` in order as described for `fetch_read`, or from
+    // `url` itself when there are none
+    async fn fetch_mirrors(
+        &self,
+        method: Method,
+        url: &str,
+        candidates: &[String],
+        extra_headers: Option<Vec<(&str, &str)>>,
+        timeouts: RequestTimeouts,
+    ) -> ProxyResult<reqwest::Response> {
+        let Some((last, mirrors)) = candidates.split_last() else {
+            return self
+                .fetch_with_auth(method, url, extra_headers, None, timeouts)
+                .await;
+        };
+        for mirror_url in mirrors {
+            let mirror = mirror_url.split("/v2/").next().unwrap_or_default();
+            let attempt = self.fetch_with_auth(
+                method.clone(),
+                mirror_url,
+                extra_headers.clone(),
+                None,
+                timeouts,
+            );
+            match tokio::time::timeout(self.mirror_timeout, attempt).await {
+                Ok(Ok(response)) if !response.status().is_server_error() => {
+                    tracing::info!(mirror = %mirror, url = %url, "Served by mirror");
+                    return Ok(response);
+                }
+                Ok(Ok(response)) => {
+                    tracing::warn!(mirror = %mirror, status = %response.status(), "Mirror failed, trying next");
+                }
+                Ok(Err(e)) => tracing::warn!(mirror = %mirror, "Mirror failed, trying next: {}", e),
+                Err(_) => tracing::warn!(mirror = %mirror, "Mirror timed out, trying next"),
+            }
+        }
+        let response = self
+            .fetch_with_auth(method, last, extra_headers, None, timeouts)
+            .await?;
+        let mirror = last.split("/v2/").next().unwrap_or_default();
+        tracing::info!(mirror = %mirror, url = %url, "Served by mirror");
+        Ok(response)
+    }
+
+    // The URL as served by each mirror of its registry; empty without mirrors
+    fn mirror_urls(&self, url: &str) -> Vec<String> {
+        let Ok(parsed) = reqwest::Url::parse(url) else {
+            return Vec::new();
+        };
+        let host = match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Vec::new(),
+        };
+        let Some(mirrors) = self.mirrors.get(&host) else {
+            return Vec::new();
+        };
+        let query = parsed.query().map(|q| format!("?{}", q));
+        mirrors
+            .iter()
+            .map(|mirror| {
+                let query = query.as_deref().unwrap_or_default();
+                format!("{}{}{}", mirror, parsed.path(), query)
+            })
+            .collect()
+    }
+
+    // Helper: perform an upstream request, negotiating a bearer token for the
+    // repository scope it needs. Requests carrying their own Authorization
+    // header are sent as-is.
+    async fn fetch_with_auth(
+        &self,
+        method: Method,
+        url: &str,
+        extra_headers: Option<Vec<(&str, &str)>>,
+        body: Option<reqwest::Body>,
+        timeouts: RequestTimeouts,
+    ) -> ProxyResult<reqwest::Response> {
+        let span = if self.telemetry_enabled {
+            tracing::info_span!(
+                "upstream_fetch",
+                otel.kind = "client",
+                http.request.method = %method,
+                url.full = %url,
+                http.response.status_code = tracing::field::Empty,
+            )
+        } else {
+            tracing::Span::none()
+        };
+        let host = url_host(url);
+        // while a registry is rate limiting, fail without adding to its count
+        if let Some(host) = &host {
+            self.rate_limits.check(host)?;
+        }
+        let slot = match &host {
+            Some(host) => Some(self.connections.acquire(host).await),
+            None => None,
+        };
+        let start = std::time::Instant::now();
+        let result = self
+            .send_with_auth(method, url, extra_headers, body, timeouts)
+            .instrument(span.clone())
+            .await;
+        if let Ok(resp) = &result {
+            span.record("http.response.status_code", resp.status().as_u16());
+        }
+        if let Some(host) = &host {
+            self.upstream_metrics
+                .record(host, start.elapsed(), UpstreamError::classify(&result));
+        }
+        let result = result.and_then(|resp| match &host {
+            Some(host) => self.rate_limits.observe(host, &resp).map(|()| resp),
+            None => Ok(resp),
+        });
+        match slot {
+            Some(slot) => result.map(|resp| connections::hold(resp, slot)),
+            None => result,
+        }
+    }
+
+    async fn send_with_auth(
+        &self,
+        method: Method,
+        url: &str,
+        extra_headers: Option<Vec<(&str, &str)>>,
+        body: Option<reqwest::Body>,
+        timeouts: RequestTimeouts,
+    ) -> ProxyResult<reqwest::Response> {
+        let extra_headers = extra_headers.unwrap_or_default();
+        let has_authorization = extra_headers
+            .iter()
+            .any(|(k, _)| k.eq_ignore_ascii_case("authorization"));
+        let parsed = reqwest::Url::parse(url).ok();
+        // ECR takes Basic credentials, configured or from its token API,
+        // instead of bearer tokens
+        let host = url_host(url);
+        let needed_scope = parsed.as_ref().and_then(|u| auth::scope_for(&method, u));
+        let repository = needed_scope.as_deref().and_then(auth::scope_repository);
+        let use_credentials = needed_scope
+            .as_deref()
+            .is_none_or(|scope| self.may_use_credentials(scope));
+        let ecr_authorization = match &host {
+            Some(host)
+                if !has_authorization && use_credentials && EcrRegistry::parse(host).is_some() =>
+            {
+                match self.upstream_credentials(host, repository).await {
+                    Some(credentials) => Some(format!(
+                        "Basic {}",
+                        base64::engine::general_purpose::STANDARD
+                            .encode(format!("{}:{}", credentials.username, credentials.password))
+                    )),
+                    None => self.ecr.authorization(host).await,
+                }
+            }
+            _ => None,
+        };
+        let scope = needed_scope.filter(|_| !has_authorization && ecr_authorization.is_none());
+        let origin = parsed
+            .as_ref()
+            .map(|u| u.origin().ascii_serialization())
+            .unwrap_or_default();
+
+        // Buffered bodies can be replayed after a 401; streamed bodies cannot,
+        // so writes negotiate their token up front
+        let replay = match &body {
+            None => Some(None),
+            Some(b) => b.as_bytes().map(|bytes| Some(bytes.to_vec())),
+        };
+
+        let mut token = scope
+            .as_deref()
+            .and_then(|scope| self.tokens.get(&origin, scope));
+        if token.is_none()
+            && replay.is_none()
+            && let Some(scope) = &scope
+        {
+            token = self.negotiate_token(&origin, scope).await;
+        }
+
+        let client = self.clients.for_url(url);
+        let trace_headers = telemetry::trace_headers(&tracing::Span::current());
+        let send = |body: Option<reqwest::Body>, token: Option<&str>| {
+            let mut req = client.request(method.clone(), url);
+            for (k, v) in extra_headers.iter() {
+                req = req.header(*k, *v);
+            }
+            for (k, v) in trace_headers.iter() {
+                req = req.header(k, v);
+            }
+            if let Some(authorization) = &ecr_authorization {
+                req = req.header(reqwest::header::AUTHORIZATION, authorization);
+            }
+            if let Some(token) = token {
+                req = req.bearer_auth(token);
+            }
+            if let Some(body) = body {
+                req = req.body(body);
+            }
+            if let Some(total) = timeouts.total {
+                req = req.timeout(total);
+            }
+            let response = req.send();
+            async move {
+                match timeouts.first_byte {
+                    Some(limit) => tokio::time::timeout(limit, response)
+                        .await
+                        .map_err(|_| ProxyError::UpstreamTimeout(limit))?
+                        .map_err(ProxyError::from),
+                    None => response.await.map_err(ProxyError::from),
+                }
+            }
+        };
+
+        let mut resp = send(body, token.as_deref()).await?;
+        if resp.status() == reqwest::StatusCode::UNAUTHORIZED
+            && ecr_authorization.is_some()
+            && let Some(host) = &host
+        {
+            self.ecr.invalidate(host);
+            self.invalidate_credentials(host);
+        }
+        if resp.status() == reqwest::StatusCode::UNAUTHORIZED
+            && let Some(scope) = &scope
+            && let Some(replay) = replay
+        {
+            if token.is_some() {
+                self.tokens.invalidate(&origin, scope);
+            }
+            if let Some(challenge) = bearer_challenge(resp.headers())
+                && let Some(token) = self.request_token(&origin, &challenge, scope).await
+            {
+                resp = send(replay.map(reqwest::Body::from), Some(&token)).await?;
+            }
+        }
+
+        self.auth_monitor.observe(&method, &resp);
+        Ok(resp)
+    }
+
+    // Obtain a token before sending a request whose body cannot be replayed,
+    // reading the challenge from the registry's /v2/ endpoint
+    async fn negotiate_token(&self, origin: &str, scope: &str) -> Option<String> {
+        let url = format!("{}/v2/", origin);
+        let req = self.clients.for_url(&url).get(&url);
+        let resp = self.with_token_timeout(req).send().await.ok()?;
+        if resp.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return None;
+        }
+        let challenge = bearer_challenge(resp.headers())?;
+        self.request_token(origin, &challenge, scope).await
+    }
+
+    // Request a token for `scope` from the challenge's realm: with the
+    // registry's refresh token when it issued one, else authenticating with
+    // the credentials for the registry if any. Push and delete scopes are
+    // requested anonymously unless writes may use the credentials.
+    async fn request_token(
+        &self,
+        origin: &str,
+        challenge: &auth::Challenge,
+        scope: &str,
+    ) -> Option<String> {
+        let span = if self.telemetry_enabled {
+            tracing::info_span!("token_exchange", registry = %origin, scope = %scope)
+        } else {
+            tracing::Span::none()
+        };
+        async {
+            let host = origin
+                .split_once("://")
+                .map(|(_, host)| host)
+                .unwrap_or(origin);
+
+            let repository = auth::scope_repository(scope);
+            // refresh tokens belong to the credentials they were issued for
+            let refresh_key = match repository.and_then(|r| self.auth.credential_namespace(host, r))
+            {
+                Some(namespace) => format!("{} {}", origin, namespace),
+                None => origin.to_string(),
+            };
+            let use_credentials = self.may_use_credentials(scope);
+            if use_credentials && let Some(refresh_token) = self.tokens.refresh_token(&refresh_key)
+            {
+                if let Some(token) = self
+                    .refresh_access_token(&refresh_key, origin, challenge, scope, &refresh_token)
+                    .await
+                {
+                    return Some(token);
+                }
+                self.tokens.forget_refresh_token(&refresh_key);
+            }
+            let credentials = match use_credentials {
+                true => self.upstream_credentials(host, repository).await,
+                false => None,
+            };
+            // credential helpers hand out identity tokens as refresh tokens
+            if let Some(credentials) = &credentials
+                && credentials.username == auth::IDENTITY_TOKEN_USER
+            {
+                return self
+                    .refresh_access_token(
+                        &refresh_key,
+                        origin,
+                        challenge,
+                        scope,
+                        &credentials.password,
+                    )
+                    .await;
+            }
+
+            let url = auth::token_url(challenge, scope, credentials.is_some())?;
+            let mut req = self.clients.for_url(url.as_str()).get(url);
+            if let Some(credentials) = credentials {
+                req = req.basic_auth(credentials.username, Some(credentials.password));
+            }
+            let resp = match self.with_token_timeout(req).send().await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    tracing::warn!(registry = %origin, "Token request failed: {}", e);
+                    return None;
+                }
+            };
+            if !resp.status().is_success() {
+                if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+                    self.invalidate_credentials(host);
+                }
+                tracing::warn!(
+                    registry = %origin,
+                    scope = %scope,
+                    status = resp.status().as_u16(),
+                    "Token request rejected"
+                );
+                return None;
+            }
+
+            let body = resp.text().await.ok()?;
+            let (token, lifetime) = auth::parse_token_response(&body)?;
+            tracing::debug!(registry = %origin, scope = %scope, "Obtained registry token");
+            if use_credentials && let Some(refresh_token) = auth::parse_refresh_token(&body) {
+                self.tokens.set_refresh_token(&refresh_key, refresh_token);
+            }
+            self.tokens.insert(origin, scope, token.clone(), lifetime);
+            Some(token)
+        }
+        .instrument(span)
+        .await
+    }
+
+    // Exchange a refresh token for a token for `scope` with the OAuth2
+    // `refresh_token` grant; a rotated refresh token is kept under `refresh_key`
+    async fn refresh_access_token(
+        &self,
+        refresh_key: &str,
+        origin: &str,
+        challenge: &auth::Challenge,
+        scope: &str,
+        refresh_token: &str,
+    ) -> Option<String> {
+        let realm = challenge.param("realm")?;
+        let req = self
+            .clients
+            .for_url(realm)
+            .post(realm)
+            .form(&auth::refresh_form(challenge, scope, refresh_token));
+        let resp = self.with_token_timeout(req).send().await;
+        let resp = match resp {
+            Ok(resp) if resp.status().is_success() => resp,
+            Ok(resp) => {
+                tracing::warn!(
+                    registry = %origin,
+                    scope = %scope,
+                    status = resp.status().as_u16(),
+                    "Refresh token rejected"
+                );
+                return None;
+            }
+            Err(e) => {
+                tracing::warn!(registry = %origin, "Token request failed: {}", e);
+                return None;
+            }
+        };
+
+        let body = resp.text().await.ok()?;
+        let (token, lifetime) = auth::parse_token_response(&body)?;
+        tracing::debug!(registry = %origin, scope = %scope, "Refreshed registry token");
+        // registries may rotate the refresh token with each use
+        if let Some(refresh_token) = auth::parse_refresh_token(&body) {
+            self.tokens.set_refresh_token(refresh_key, refresh_token);
+        }
+        self.tokens.insert(origin, scope, token.clone(), lifetime);
+        Some(token)
+    }
+
+    // Token requests, including their body, are bounded by the token timeout
+    fn with_token_timeout(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self.token_timeout {
+            Some(timeout) => req.timeout(timeout),
+            None => req,
+        }
+    }
+
+    // Whether a token for `scope` may be requested with the configured
+    // credentials: always for pulls, for pushes and deletes only when
+    // `auth.write_with_credentials` is set (clients are then authenticated)
+    fn may_use_credentials(&self, scope: &str) -> bool {
+        self.auth.write_with_credentials || !auth::is_write_scope(scope)
+    }
+
+    // Credentials for a registry host, or a repository of it: configured
+    // ones, else those of its credential helper, else an ACR refresh token
+    // from the Azure identity
+    async fn upstream_credentials(
+        &self,
+        host: &str,
+        repository: Option<&str>,
+    ) -> Option<RegistryCredentials> {
+        let configured = match repository {
+            Some(repository) => self.auth.credentials_for_repository(host, repository),
+            None => self.auth.credentials_for(host),
+        };
+        if configured.is_some() {
+            return configured;
+        }
+        match self.auth.credential_helper(host) {
+            Some(helper) => self.credential_helpers.get(host, helper).await,
+            None => self.acr.credentials(host).await,
+        }
+    }
+
+    // Forget credentials obtained for a host once the registry rejects them
+    fn invalidate_credentials(&self, host: &str) {
+        self.credential_helpers.invalidate(host);
+        self.acr.invalidate(host);
     }
 
     // If `name` is like "ghcr.io/owner/repo" return ("https://ghcr.io", "owner/repo")
     // Otherwise return (self.registry_url.clone(), normalized_name)
     fn split_registry_and_name(&self, name: &str) -> (String, String) {
-        if let Some(pos) = name.find('/') {
-            let first = &name[..pos];
-            // treat as registry when first segment looks like a host (contains dot or colon)
-            if first.contains('.') || first.contains(':') {
-                let registry_url = format!("https://{}", first);
-                let rest = &name[pos + 1..];
-                return (registry_url, rest.to_string());
-            }
+        router::split_registry_and_name(&self.registry_url, &self.routes, &self.registries, name)
+    }
+}
+
+// Log a mirror's failed answer before the next candidate is used
+fn log_mirror_failure(mirror_url: &str, result: &ProxyResult<reqwest::Response>) {
+    let mirror = mirror_url.split("/v2/").next().unwrap_or_default();
+    match result {
+        Ok(response) => {
+            tracing::warn!(mirror = %mirror, status = %response.status(), "Mirror failed")
         }
-        (self.registry_url.clone(), self.normalize_image_name(name))
+        Err(e) => tracing::warn!(mirror = %mirror, "Mirror failed: {}", e),
     }
+}
 
-    // 规范化镜像名称：如果没有指定registry，默认使用library
-    fn normalize_image_name(&self, name: &str) -> String {
-        if name.contains('/') {
-            name.to_string()
-        } else {
-            format!("library/{}", name)
+// The Bearer challenge among a response's WWW-Authenticate headers
+fn bearer_challenge(headers: &reqwest::header::HeaderMap) -> Option<auth::Challenge> {
+    headers
+        .get_all(reqwest::header::WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(auth::parse_challenges)
+        .find(|c| c.scheme == "bearer")
+}
+
+// "host[:port]" of a URL
+fn url_host(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+// Accept headers for a manifest request, defaulting to every known type
+fn accept_headers<'a>(accept: &[&'a str]) -> Vec<(&'static str, &'a str)> {
+    let accept = if accept.is_empty() {
+        MANIFEST_MEDIA_TYPES
+    } else {
+        accept
+    };
+    accept.iter().map(|value| ("Accept", *value)).collect()
+}
+
+// Build an upstream API URL, percent-encoding the repository name and reference
+fn upstream_url(registry_url: &str, image_name: &str, endpoint: &str, reference: &str) -> String {
+    format!(
+        "{}/v2/{}/{}/{}",
+        registry_url,
+        router::encode_repository_path(image_name),
+        endpoint,
+        router::encode_path_segment(reference)
+    )
+}
+
+// Extract the target of a `Link: <url>; rel="next"` header
+fn next_page_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let (target, params) = link.split_once(';')?;
+        let is_next = params.split(';').any(|p| {
+            p.trim()
+                .strip_prefix("rel=")
+                .is_some_and(|rel| rel.trim_matches('"') == "next")
+        });
+        let target = target.trim().strip_prefix('<')?.strip_suffix('>')?;
+        is_next.then(|| target.to_string())
+    })
+}
+
+/// When a manifest was requested by digest, check the body hashes to it so a
+/// tampering or broken upstream cannot serve different content
+fn verify_manifest_digest(reference: &str, body: &[u8]) -> ProxyResult<()> {
+    let Some(mut verifier) = DigestVerifier::for_reference(reference) else {
+        return Ok(());
+    };
+    verifier.update(body);
+    verifier.finish()
+}
+
+/// Content type of an upstream manifest response
+pub fn manifest_content_type(response: &reqwest::Response) -> String {
+    response
+        .headers()
+        .get("content-type")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("application/json")
+        .to_string()
+}
+
+/// Checks content fetched by digest as it streams past
+pub struct DigestVerifier {
+    expected: String,
+    hasher: DigestHasher,
+}
+
+enum DigestHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl DigestVerifier {
+    /// `None` for tags and digests of unknown algorithms
+    pub fn for_reference(reference: &str) -> Option<Self> {
+        let hasher = match reference.split_once(':') {
+            Some(("sha256", _)) => DigestHasher::Sha256(Sha256::new()),
+            Some(("sha512", _)) => DigestHasher::Sha512(Sha512::new()),
+            _ => return None,
+        };
+        Some(Self {
+            expected: reference.to_string(),
+            hasher,
+        })
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        match &mut self.hasher {
+            DigestHasher::Sha256(hasher) => hasher.update(chunk),
+            DigestHasher::Sha512(hasher) => hasher.update(chunk),
+        }
+    }
+
+    /// Whether the content seen matches the digest
+    pub fn finish(self) -> ProxyResult<()> {
+        let actual = match self.hasher {
+            DigestHasher::Sha256(hasher) => format!("sha256:{}", hex::encode(hasher.finalize())),
+            DigestHasher::Sha512(hasher) => format!("sha512:{}", hex::encode(hasher.finalize())),
+        };
+        if actual != self.expected {
+            return Err(ProxyError::DigestMismatch {
+                expected: self.expected,
+                actual,
+            });
         }
+        Ok(())
     }
 }
 
@@ -358,24 +2028,232 @@ ghcr-token = ""
         assert_eq!(registry, "https://ghcr.io");
         assert_eq!(name, "vansour/docker-proxy");
 
-        // Test with docker.io registry
+        // docker.io only redirects; requests go to the registry API host
         let (registry, name) = proxy.split_registry_and_name("docker.io/library/ubuntu");
-        assert_eq!(registry, "https://docker.io");
+        assert_eq!(registry, "https://registry-1.docker.io");
+        assert_eq!(name, "library/ubuntu");
+        let (registry, name) = proxy.split_registry_and_name("docker.io/ubuntu");
+        assert_eq!(registry, "https://registry-1.docker.io");
         assert_eq!(name, "library/ubuntu");
+        let (registry, name) = proxy.split_registry_and_name("index.docker.io/vansour/app");
+        assert_eq!(registry, "https://registry-1.docker.io");
+        assert_eq!(name, "vansour/app");
 
         // Test without registry (should use default and add library prefix)
         let (registry, name) = proxy.split_registry_and_name("ubuntu");
-        assert_eq!(registry, "https://docker.io");
+        assert_eq!(registry, "https://registry-1.docker.io");
         assert_eq!(name, "library/ubuntu");
 
         // Test with owner/repo format
         let (registry, name) = proxy.split_registry_and_name("vansour/myimage");
-        assert_eq!(registry, "https://docker.io");
+        assert_eq!(registry, "https://registry-1.docker.io");
         assert_eq!(name, "vansour/myimage");
     }
 
     #[test]
-    fn test_normalize_image_name() {
+    fn test_split_registry_and_name_deep_nesting() {
+        let config = Config::from_str(
+            r#"
+[server]
+host = "0.0.0.0"
+port = 8080
+
+[log]
+logFilePath = "/tmp/test.log"
+level = "info"
+
+[proxy]
+default = "docker.io"
+
+[auth]
+ghcr-token = ""
+"#,
+        )
+        .expect("Failed to parse test config");
+
+        let proxy = DockerProxy::new(&config);
+
+        // GitLab-style group/subgroup/project/image
+        let (registry, name) =
+            proxy.split_registry_and_name("registry.gitlab.com/group/subgroup/project/image");
+        assert_eq!(registry, "https://registry.gitlab.com");
+        assert_eq!(name, "group/subgroup/project/image");
+
+        // Nested names on the default registry keep every segment
+        let (registry, name) = proxy.split_registry_and_name("group/subgroup/project/image");
+        assert_eq!(registry, "https://registry-1.docker.io");
+        assert_eq!(name, "group/subgroup/project/image");
+
+        let (registry, name) = proxy.split_registry_and_name("localhost/team/app");
+        assert_eq!(registry, "https://localhost");
+        assert_eq!(name, "team/app");
+    }
+
+    #[test]
+    fn test_split_registry_and_name_insecure() {
+        let config = Config::from_str(
+            r#"
+[server]
+host = "0.0.0.0"
+port = 8080
+
+[log]
+logFilePath = "/tmp/test.log"
+level = "info"
+
+[proxy]
+default = "registry.lab:5000"
+
+[proxy.registries."registry.lab:5000"]
+insecure = true
+
+[proxy.registries."localhost:5000"]
+insecure = true
+
+[auth]
+ghcr-token = ""
+"#,
+        )
+        .expect("Failed to parse test config");
+
+        let proxy = DockerProxy::new(&config);
+        assert_eq!(proxy.get_registry_url(), "http://registry.lab:5000");
+
+        let (registry, name) = proxy.split_registry_and_name("localhost:5000/team/app");
+        assert_eq!(registry, "http://localhost:5000");
+        assert_eq!(name, "team/app");
+
+        // Other hosts, including the same host on another port, keep TLS
+        let (registry, _) = proxy.split_registry_and_name("localhost:5001/team/app");
+        assert_eq!(registry, "https://localhost:5001");
+        let (registry, _) = proxy.split_registry_and_name("ghcr.io/owner/repo");
+        assert_eq!(registry, "https://ghcr.io");
+    }
+
+    #[test]
+    fn test_split_registry_and_name_routes() {
+        let config = Config::from_str(
+            r#"
+[server]
+host = "0.0.0.0"
+port = 8080
+
+[log]
+logFilePath = "/tmp/test.log"
+level = "info"
+
+[proxy]
+default = "docker.io"
+
+[proxy.routes]
+ghcr = "https://ghcr.io/"
+hub = "https://registry-1.docker.io"
+
+[auth]
+ghcr-token = ""
+"#,
+        )
+        .expect("Failed to parse test config");
+
+        let proxy = DockerProxy::new(&config);
+
+        let (registry, name) = proxy.split_registry_and_name("ghcr/vansour/docker-proxy");
+        assert_eq!(registry, "https://ghcr.io");
+        assert_eq!(name, "vansour/docker-proxy");
+
+        // Official images behind a route still get the library prefix
+        let (registry, name) = proxy.split_registry_and_name("hub/nginx");
+        assert_eq!(registry, "https://registry-1.docker.io");
+        assert_eq!(name, "library/nginx");
+
+        // A bare route name is a repository on the default registry
+        let (registry, name) = proxy.split_registry_and_name("ghcr");
+        assert_eq!(registry, "https://registry-1.docker.io");
+        assert_eq!(name, "library/ghcr");
+
+        assert!(
+            Config::from_str(
+                r#"
+[server]
+host = "0.0.0.0"
+port = 8080
+
+[log]
+logFilePath = "/tmp/test.log"
+
+[proxy]
+default = "docker.io"
+
+[proxy.routes]
+"ghcr.io" = "https://ghcr.io"
+"#,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_upstream_url_encoding() {
+        assert_eq!(
+            upstream_url(
+                "https://registry.gitlab.com",
+                "group/subgroup/project/image",
+                "manifests",
+                "v1.0"
+            ),
+            "https://registry.gitlab.com/v2/group/subgroup/project/image/manifests/v1.0"
+        );
+        assert_eq!(
+            upstream_url("https://ghcr.io", "owner/my repo", "blobs", "sha256:abc"),
+            "https://ghcr.io/v2/owner/my%20repo/blobs/sha256:abc"
+        );
+        assert_eq!(
+            upstream_url("https://ghcr.io", "owner/repo", "manifests", "v1#x"),
+            "https://ghcr.io/v2/owner/repo/manifests/v1%23x"
+        );
+    }
+
+    #[test]
+    fn test_next_page_link() {
+        assert_eq!(
+            next_page_link(r#"</v2/library/nginx/tags/list?n=100&last=1.25>; rel="next""#),
+            Some("/v2/library/nginx/tags/list?n=100&last=1.25".to_string())
+        );
+        assert_eq!(
+            next_page_link(
+                r#"<https://a.example/first>; rel="prev", <https://a.example/next>; rel=next"#
+            ),
+            Some("https://a.example/next".to_string())
+        );
+        assert_eq!(next_page_link(r#"</v2/x/tags/list>; rel="prev""#), None);
+        assert_eq!(next_page_link("garbage"), None);
+    }
+
+    #[test]
+    fn test_verify_manifest_digest() {
+        let body = br#"{"schemaVersion":2}"#;
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(body)));
+        assert!(verify_manifest_digest(&digest, body).is_ok());
+        assert!(verify_manifest_digest("latest", body).is_ok());
+        assert!(matches!(
+            verify_manifest_digest(&digest, b"{}"),
+            Err(ProxyError::DigestMismatch { .. })
+        ));
+        let sha512 = format!("sha512:{}", hex::encode(Sha512::digest(body)));
+        assert!(verify_manifest_digest(&sha512, body).is_ok());
+        assert!(verify_manifest_digest(&sha512, b"{}").is_err());
+
+        // Streamed in chunks
+        let mut verifier = DigestVerifier::for_reference(&digest).unwrap();
+        for chunk in body.chunks(5) {
+            verifier.update(chunk);
+        }
+        assert!(verifier.finish().is_ok());
+        assert!(DigestVerifier::for_reference("latest").is_none());
+    }
+
+    #[test]
+    fn test_upstream_upload_query_translates_mount_source() {
         let config = Config::from_str(
             r#"
 [server]
@@ -397,17 +2275,34 @@ ghcr-token = ""
 
         let proxy = DockerProxy::new(&config);
 
+        assert_eq!(
+            proxy.upstream_upload_query("mount=sha256%3Aabc&from=ghcr.io%2Fowner%2Fbase"),
+            "mount=sha256%3Aabc&from=owner%2Fbase"
+        );
+        assert_eq!(
+            proxy.upstream_upload_query("mount=sha256:abc&from=ubuntu"),
+            "mount=sha256%3Aabc&from=library%2Fubuntu"
+        );
+        // Queries without `from` are passed through byte for byte
+        assert_eq!(
+            proxy.upstream_upload_query("_state=abc%3D%3D&digest=sha256:abc"),
+            "_state=abc%3D%3D&digest=sha256:abc"
+        );
+    }
+
+    #[test]
+    fn test_normalize_image_name() {
         // Single name should get library prefix
-        assert_eq!(proxy.normalize_image_name("ubuntu"), "library/ubuntu");
-        assert_eq!(proxy.normalize_image_name("nginx"), "library/nginx");
+        assert_eq!(router::normalize_image_name("ubuntu"), "library/ubuntu");
+        assert_eq!(router::normalize_image_name("nginx"), "library/nginx");
 
         // Name with slash should remain unchanged
         assert_eq!(
-            proxy.normalize_image_name("vansour/docker-proxy"),
+            router::normalize_image_name("vansour/docker-proxy"),
             "vansour/docker-proxy"
         );
         assert_eq!(
-            proxy.normalize_image_name("library/ubuntu"),
+            router::normalize_image_name("library/ubuntu"),
             "library/ubuntu"
         );
     }
@@ -436,7 +2331,636 @@ ghcr-token = ""
         .expect("Failed to parse test config");
 
         let proxy = DockerProxy::new(&config);
-        assert_eq!(proxy.get_registry_url(), "https://docker.io");
+        assert_eq!(proxy.get_registry_url(), "https://registry-1.docker.io");
+    }
+
+    #[test]
+    fn test_mirror_urls() {
+        let config = Config::from_str(
+            r#"
+[server]
+host = "0.0.0.0"
+port = 8080
+
+[log]
+logFilePath = "/tmp/test.log"
+level = "info"
+
+[proxy]
+default = "registry-1.docker.io"
+
+[proxy.mirrors]
+"registry-1.docker.io" = ["https://hub-mirror.example.com/", "https://registry-1.docker.io"]
+
+[auth]
+ghcr-token = ""
+"#,
+        )
+        .expect("Failed to parse test config");
+
+        let proxy = DockerProxy::new(&config);
+        assert_eq!(
+            proxy.mirror_urls("https://registry-1.docker.io/v2/library/nginx/tags/list?n=10"),
+            vec![
+                "https://hub-mirror.example.com/v2/library/nginx/tags/list?n=10",
+                "https://registry-1.docker.io/v2/library/nginx/tags/list?n=10",
+            ]
+        );
+        assert!(
+            proxy
+                .mirror_urls("https://ghcr.io/v2/owner/app/manifests/v1")
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blob_timeouts() {
+        use axum::{Router, body::Body, extract::Path, routing::get};
+
+        // A registry slow to answer for "stalled", and slow to send the
+        // body of "trickle"
+        let app = Router::new().route(
+            "/v2/test/{name}/blobs/{digest}",
+            get(|Path((name, _)): Path<(String, String)>| async move {
+                if name == "stalled" {
+                    tokio::time::sleep(Duration::from_secs(3)).await;
+                }
+                let chunks = futures_util::stream::unfold(0, |sent| async move {
+                    if sent == 3 {
+                        return None;
+                    }
+                    tokio::time::sleep(Duration::from_millis(800)).await;
+                    Some((Ok::<_, std::io::Error>(bytes::Bytes::from("x")), sent + 1))
+                });
+                Body::from_stream(chunks)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = Config::from_str(&format!(
+            r#"
+[proxy]
+default = "http://{}"
+
+[client]
+blob_first_byte_timeout_secs = 1
+blob_timeout_secs = 2
+"#,
+            addr
+        ))
+        .unwrap();
+        let proxy = DockerProxy::new(&config);
+        let digest = format!("sha256:{}", "0".repeat(64));
+
+        let error = proxy
+            .get_blob("test/stalled", &digest, vec![])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, ProxyError::UpstreamTimeout(limit) if limit == Duration::from_secs(1))
+        );
+        assert_eq!(error.phase(), crate::error::FailurePhase::Timeout);
+
+        // The response starts in time but the body takes longer than allowed
+        let response = proxy
+            .get_blob("test/trickle", &digest, vec![])
+            .await
+            .unwrap();
+        assert!(response.bytes().await.unwrap_err().is_timeout());
+    }
+
+    #[tokio::test]
+    async fn test_hedged_manifest_fetch() {
+        use axum::{Router, extract::Path, http::StatusCode, routing::get};
+
+        // Mirrors as path prefixes of one server: "slow" takes 3s to answer,
+        // "broken" fails
+        let app = Router::new().route(
+            "/{mirror}/v2/test/app/manifests/{reference}",
+            get(|Path((mirror, _)): Path<(String, String)>| async move {
+                match mirror.as_str() {
+                    "slow" => tokio::time::sleep(Duration::from_secs(3)).await,
+                    "broken" => return (StatusCode::INTERNAL_SERVER_ERROR, mirror),
+                    _ => {}
+                }
+                (StatusCode::OK, mirror)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let proxy = |mirrors: &[&str]| {
+            let mirrors: Vec<String> = mirrors
+                .iter()
+                .map(|mirror| format!("\"http://{}/{}\"", addr, mirror))
+                .collect();
+            let config = Config::from_str(&format!(
+                "[proxy]\ndefault = \"http://{addr}\"\nhedge_delay_ms = 200\n\n\
+                 [proxy.mirrors]\n\"{addr}\" = [{}]\n",
+                mirrors.join(", "),
+            ))
+            .unwrap();
+            DockerProxy::new(&config)
+        };
+
+        // The next mirror answers while the first is still busy
+        let start = std::time::Instant::now();
+        let (_, body) = proxy(&["slow", "fast"])
+            .get_manifest("test/app", "latest", &[])
+            .await
+            .unwrap();
+        assert_eq!(body, "fast");
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // A failing first mirror hands over at once, and the remaining
+        // mirrors are tried when both fail
+        let (_, body) = proxy(&["broken", "fast"])
+            .get_manifest("test/app", "latest", &[])
+            .await
+            .unwrap();
+        assert_eq!(body, "fast");
+        let (_, body) = proxy(&["broken", "broken", "last"])
+            .get_manifest("test/app", "latest", &[])
+            .await
+            .unwrap();
+        assert_eq!(body, "last");
+    }
+
+    #[tokio::test]
+    async fn test_list_tags_follows_same_origin_links() {
+        use axum::{
+            Router, extract::Query, http::header::LINK, response::IntoResponse, routing::get,
+        };
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let foreign_hits = Arc::new(AtomicUsize::new(0));
+        let hits = Arc::clone(&foreign_hits);
+        let foreign = Router::new().fallback(move || {
+            hits.fetch_add(1, Ordering::SeqCst);
+            async { r#"{"tags":["foreign"]}"# }
+        });
+        let foreign_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let foreign_addr = foreign_listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(foreign_listener, foreign).await });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Page 1 links to page 2 by absolute URL, page 2 to another host
+        let app = Router::new().route(
+            "/v2/test/app/tags/list",
+            get(
+                move |Query(query): Query<HashMap<String, String>>| async move {
+                    match query.get("last").map(String::as_str) {
+                        None => (
+                            [(
+                                LINK,
+                                format!(
+                                    "<http://{}/v2/test/app/tags/list?last=a>; rel=\"next\"",
+                                    addr
+                                ),
+                            )],
+                            r#"{"tags":["a"]}"#,
+                        )
+                            .into_response(),
+                        _ => (
+                            [(
+                                LINK,
+                                format!(
+                                    "<http://{}/v2/test/app/tags/list?last=b>; rel=\"next\"",
+                                    foreign_addr
+                                ),
+                            )],
+                            r#"{"tags":["b"]}"#,
+                        )
+                            .into_response(),
+                    }
+                },
+            ),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config =
+            Config::from_str(&format!("[proxy]\ndefault = \"http://{}\"\n", addr)).unwrap();
+        let proxy = DockerProxy::new(&config);
+        assert_eq!(proxy.list_tags("test/app").await.unwrap(), ["a", "b"]);
+        assert_eq!(foreign_hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_writes_skip_configured_credentials() {
+        use axum::{
+            Router,
+            extract::Query,
+            http::{HeaderMap, StatusCode, header},
+            response::IntoResponse,
+        };
+        use std::collections::HashMap;
+        use std::sync::Mutex;
+
+        // The token server records each scope and whether Basic credentials came with it
+        let requests: Arc<Mutex<Vec<(String, bool)>>> = Arc::default();
+        let recorded = Arc::clone(&requests);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route(
+                "/token",
+                axum::routing::get(
+                    move |Query(query): Query<HashMap<String, String>>, headers: HeaderMap| async move {
+                        let scope = query.get("scope").cloned().unwrap_or_default();
+                        let basic = headers
+                            .get(header::AUTHORIZATION)
+                            .is_some_and(|v| v.as_bytes().starts_with(b"Basic "));
+                        recorded.lock().unwrap().push((scope, basic));
+                        r#"{"token":"t"}"#
+                    },
+                ),
+            )
+            .fallback(move |headers: HeaderMap| async move {
+                if headers.get(header::AUTHORIZATION).is_some() {
+                    return (StatusCode::CREATED, r#"{"tags":[]}"#).into_response();
+                }
+                let challenge = format!(
+                    "Bearer realm=\"http://{}/token\",service=\"reg\"",
+                    addr
+                );
+                (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, challenge)]).into_response()
+            });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = |extra: &str| {
+            Config::from_str(&format!(
+                "[proxy]\ndefault = \"http://{addr}\"\n\
+                 [client_auth]\nusers = {{ ci = \"secret\" }}\n\
+                 [auth]\n{extra}\n\
+                 [auth.credentials.\"{addr}\"]\nusername = \"operator\"\npassword = \"p\"\n"
+            ))
+            .unwrap()
+        };
+        let proxy = DockerProxy::new(&config(""));
+        proxy.list_tags("test/app").await.unwrap();
+        let pushed = proxy
+            .put_manifest("test/app", "v1", "application/json", None, "{}".into())
+            .await
+            .unwrap();
+        assert_eq!(pushed.status(), reqwest::StatusCode::CREATED);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                ("repository:test/app:pull".to_string(), true),
+                ("repository:test/app:push,pull".to_string(), false),
+            ]
+        );
+
+        requests.lock().unwrap().clear();
+        let proxy = DockerProxy::new(&config("write_with_credentials = true"));
+        proxy
+            .put_manifest("test/app", "v1", "application/json", None, "{}".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [("repository:test/app:push,pull".to_string(), true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_flow() {
+        use axum::{
+            Form, Router,
+            extract::Query,
+            http::{HeaderMap, StatusCode, header},
+            response::IntoResponse,
+        };
+        use std::collections::HashMap;
+        use std::sync::Mutex;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // The realm issues "r1" with a password token, rotates it to "r2" on
+        // refresh, and rejects refresh tokens once `reject` is set
+        let requests: Arc<Mutex<Vec<String>>> = Arc::default();
+        let reject = Arc::new(AtomicBool::new(false));
+        let (fetched, refreshed, rejecting) = (
+            Arc::clone(&requests),
+            Arc::clone(&requests),
+            Arc::clone(&reject),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route(
+                "/token",
+                axum::routing::get(
+                    move |Query(query): Query<HashMap<String, String>>, headers: HeaderMap| async move {
+                        let basic = headers
+                            .get(header::AUTHORIZATION)
+                            .is_some_and(|v| v.as_bytes().starts_with(b"Basic "));
+                        let offline = query.get("offline_token").is_some_and(|v| v == "true");
+                        fetched
+                            .lock()
+                            .unwrap()
+                            .push(format!("get basic={} offline={}", basic, offline));
+                        r#"{"token":"t","refresh_token":"r1"}"#
+                    },
+                )
+                .post(move |Form(form): Form<HashMap<String, String>>| async move {
+                    let grant = form.get("grant_type").cloned().unwrap_or_default();
+                    let token = form.get("refresh_token").cloned().unwrap_or_default();
+                    refreshed.lock().unwrap().push(format!("post {} {}", grant, token));
+                    if rejecting.load(Ordering::SeqCst) {
+                        return (StatusCode::UNAUTHORIZED, "").into_response();
+                    }
+                    r#"{"access_token":"t","refresh_token":"r2"}"#.into_response()
+                }),
+            )
+            .fallback(move |headers: HeaderMap| async move {
+                if headers.get(header::AUTHORIZATION).is_some() {
+                    return r#"{"tags":[]}"#.into_response();
+                }
+                let challenge = format!(
+                    "Bearer realm=\"http://{}/token\",service=\"reg\"",
+                    addr
+                );
+                (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, challenge)]).into_response()
+            });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = Config::from_str(&format!(
+            "[proxy]\ndefault = \"http://{addr}\"\n\
+             [auth.credentials.\"{addr}\"]\nusername = \"operator\"\npassword = \"p\"\n"
+        ))
+        .unwrap();
+        let proxy = DockerProxy::new(&config);
+        let origin = format!("http://{}", addr);
+
+        // The password flow asks for a refresh token and keeps it
+        proxy.list_tags("test/a").await.unwrap();
+        assert_eq!(proxy.tokens.refresh_token(&origin).as_deref(), Some("r1"));
+        // Another scope is obtained with the refresh grant, which rotates it
+        proxy.list_tags("test/b").await.unwrap();
+        assert_eq!(proxy.tokens.refresh_token(&origin).as_deref(), Some("r2"));
+        // A rejected refresh token is dropped for the password flow
+        reject.store(true, Ordering::SeqCst);
+        proxy.list_tags("test/c").await.unwrap();
+        assert_eq!(proxy.tokens.refresh_token(&origin).as_deref(), Some("r1"));
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                "get basic=true offline=true",
+                "post refresh_token r1",
+                "post refresh_token r2",
+                "get basic=true offline=true",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_namespaced_credentials() {
+        use axum::{
+            Form, Router,
+            http::{HeaderMap, StatusCode, header},
+            response::IntoResponse,
+        };
+        use base64::Engine;
+        use std::collections::HashMap;
+        use std::sync::Mutex;
+
+        // The realm names tokens after the user they were issued to, and
+        // records who asked and with which refresh token
+        let requests: Arc<Mutex<Vec<String>>> = Arc::default();
+        let (fetched, refreshed) = (Arc::clone(&requests), Arc::clone(&requests));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route(
+                "/token",
+                axum::routing::get(move |headers: HeaderMap| async move {
+                    let user = headers
+                        .get(header::AUTHORIZATION)
+                        .and_then(|v| v.to_str().ok()?.strip_prefix("Basic ").map(str::to_string))
+                        .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok())
+                        .map(|v| {
+                            String::from_utf8_lossy(&v)
+                                .split(':')
+                                .next()
+                                .unwrap_or_default()
+                                .to_string()
+                        })
+                        .unwrap_or_default();
+                    fetched.lock().unwrap().push(format!("get {}", user));
+                    format!(
+                        r#"{{"token":"token-{0}","refresh_token":"refresh-{0}"}}"#,
+                        user
+                    )
+                })
+                .post(
+                    move |Form(form): Form<HashMap<String, String>>| async move {
+                        let token = form.get("refresh_token").cloned().unwrap_or_default();
+                        let user = token.trim_start_matches("refresh-").to_string();
+                        refreshed.lock().unwrap().push(format!("post {}", token));
+                        format!(r#"{{"token":"token-{}"}}"#, user)
+                    },
+                ),
+            )
+            .fallback(move |headers: HeaderMap| async move {
+                if headers.get(header::AUTHORIZATION).is_some() {
+                    return r#"{"tags":[]}"#.into_response();
+                }
+                let challenge = format!("Bearer realm=\"http://{}/token\",service=\"reg\"", addr);
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, challenge)],
+                )
+                    .into_response()
+            });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = Config::from_str(&format!(
+            "[proxy]\ndefault = \"http://{addr}\"\n\
+             [auth.credentials.\"{addr}/org-a/*\"]\nusername = \"alice\"\npassword = \"a\"\n\
+             [auth.credentials.\"{addr}/org-b/*\"]\nusername = \"bob\"\npassword = \"b\"\n"
+        ))
+        .unwrap();
+        let proxy = DockerProxy::new(&config);
+        let origin = format!("http://{}", addr);
+
+        // Each namespace authenticates as its own user, and its tokens are
+        // kept apart from the other's
+        proxy.list_tags("org-a/app").await.unwrap();
+        proxy.list_tags("org-b/app").await.unwrap();
+        assert_eq!(
+            proxy
+                .tokens
+                .get(&origin, "repository:org-a/app:pull")
+                .as_deref(),
+            Some("token-alice")
+        );
+        assert_eq!(
+            proxy
+                .tokens
+                .get(&origin, "repository:org-b/app:pull")
+                .as_deref(),
+            Some("token-bob")
+        );
+        let refresh = |namespace: &str| {
+            proxy
+                .tokens
+                .refresh_token(&format!("{} {}/{}/*", origin, addr, namespace))
+        };
+        assert_eq!(refresh("org-a").as_deref(), Some("refresh-alice"));
+        assert_eq!(refresh("org-b").as_deref(), Some("refresh-bob"));
+        assert_eq!(proxy.tokens.refresh_token(&origin), None);
+
+        // Further repositories refresh with their own namespace's token
+        proxy.list_tags("org-b/other").await.unwrap();
+        proxy.list_tags("org-a/other").await.unwrap();
+        assert_eq!(
+            proxy
+                .tokens
+                .get(&origin, "repository:org-a/other:pull")
+                .as_deref(),
+            Some("token-alice")
+        );
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                "get alice",
+                "get bob",
+                "post refresh-bob",
+                "post refresh-alice"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blob_digest_verification() {
+        use axum::{Router, routing::get};
+
+        // Every blob has the content "hello", matching only this digest
+        let hello = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let app = Router::new().route("/v2/test/app/blobs/{digest}", get(|| async { "hello" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = Config::from_str(&format!(
+            "[proxy]\ndefault = \"http://{}\"\nverify_blob_digests = true\n",
+            addr
+        ))
+        .unwrap();
+        let proxy = DockerProxy::new(&config);
+        let read = |digest: &'static str| {
+            let proxy = &proxy;
+            async move {
+                let response = proxy
+                    .get_blob("test/app", digest, Vec::new())
+                    .await
+                    .unwrap();
+                proxy
+                    .blob_body(digest, response)
+                    .collect::<Vec<_>>()
+                    .await
+                    .into_iter()
+                    .collect::<Result<Vec<_>, _>>()
+            }
+        };
+
+        assert_eq!(read(hello).await.unwrap().concat(), b"hello");
+        // The corrupt blob's last chunk is replaced by the error
+        let error = read("sha256:0000000000000000000000000000000000000000000000000000000000000000")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Digest mismatch"));
+        let host = addr.to_string();
+        assert_eq!(
+            proxy.upstream_metrics().snapshot()[&host].digest_mismatches,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_head_blob_headers() {
+        use axum::{Router, routing::get};
+
+        let app = Router::new().route(
+            "/v2/test/app/blobs/{digest}",
+            get(|| async {
+                (
+                    [
+                        (
+                            "content-type",
+                            "application/vnd.oci.image.layer.v1.tar+gzip",
+                        ),
+                        ("docker-content-digest", "sha256:abc"),
+                        ("etag", "\"sha256:abc\""),
+                    ],
+                    "layer",
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config =
+            Config::from_str(&format!("[proxy]\ndefault = \"http://{}\"\n", addr)).unwrap();
+        let headers = DockerProxy::new(&config)
+            .head_blob("test/app", "sha256:abc")
+            .await
+            .unwrap();
+        assert_eq!(headers["docker-content-digest"], "sha256:abc");
+        assert_eq!(
+            headers["content-type"],
+            "application/vnd.oci.image.layer.v1.tar+gzip"
+        );
+        assert_eq!(headers["etag"], "\"sha256:abc\"");
+        assert_eq!(headers["content-length"], "5");
+    }
+
+    #[tokio::test]
+    async fn test_cached_registry_health() {
+        use axum::{Router, http::StatusCode, routing::get};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let checks = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/v2/",
+            get({
+                let checks = Arc::clone(&checks);
+                move || async move {
+                    checks.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::UNAUTHORIZED
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let proxy = |interval: u64| {
+            let config = Config::from_str(&format!(
+                "[server]\nhealth_check_interval_secs = {interval}\n\n\
+                 [proxy]\ndefault = \"http://{addr}\"\n",
+            ))
+            .unwrap();
+            DockerProxy::new(&config)
+        };
+
+        // The first call checks, later ones reuse its result
+        let cached = proxy(3600);
+        assert!(cached.registry_health().await.healthy);
+        assert!(cached.registry_health().await.healthy);
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+
+        // Without an interval every call checks
+        let live = proxy(0);
+        live.registry_health().await;
+        live.registry_health().await;
+        assert_eq!(checks.load(Ordering::SeqCst), 3);
     }
 
     #[test]
@@ -0,0 +1,257 @@
+/// HAProxy PROXY protocol on the listener
+///
+/// TCP load balancers that do not speak HTTP cannot add `X-Forwarded-For`,
+/// so every request would appear to come from the load balancer. With
+/// `[server] proxy_protocol = true` each connection must start with a PROXY
+/// protocol header (version 1, text, or version 2, binary) naming the real
+/// client address, which then takes the place of `X-Forwarded-For`.
+/// Connections without a valid header are dropped, so only enable it behind
+/// a load balancer that always sends one. With TLS the header precedes the
+/// handshake.
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::tls_listener::ClientConnection;
+
+/// How long a load balancer may take to send the header
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Start of a version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY protocol header at the start of `stream`, leaving the rest
+/// of the stream untouched. The client address is None for connections the
+/// load balancer opened itself (health checks) or of unknown family.
+pub async fn read_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let mut addresses = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(header[0], header[1], &addresses).map_err(invalid);
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY protocol header".to_string()));
+    }
+    // Byte by byte, not to read past the header into the request
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header too long".to_string()));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line).map_err(|e| invalid(e.to_string()))?;
+    parse_v1(line).map_err(invalid)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Client address in a version 1 header line, e.g.
+/// `PROXY TCP4 192.0.2.10 198.51.100.1 56324 443\r\n`
+pub fn parse_v1(line: &str) -> Result<Option<SocketAddr>, String> {
+    let fields: Vec<&str> = line
+        .strip_suffix("\r\n")
+        .ok_or("PROXY protocol header without CRLF")?
+        .split(' ')
+        .collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| format!("Invalid PROXY protocol source address: {}", source))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(format!("{} header with address {}", family, ip));
+            }
+            let port = port
+                .parse()
+                .map_err(|_| format!("Invalid PROXY protocol source port: {}", port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(format!("Invalid PROXY protocol header: {:?}", line)),
+    }
+}
+
+/// Client address in the version/command and family bytes and the address
+/// block of a version 2 header
+pub fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> Result<Option<SocketAddr>, String> {
+    if version_command >> 4 != 2 {
+        return Err(format!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        ));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the load balancer's own connection
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(format!("Unsupported PROXY protocol command {}", command)),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family >> 4 {
+        // AF_INET: source, destination, source port, destination port
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            Ok(Some(SocketAddr::new(ip.into(), port(32))))
+        }
+        1 | 2 => Err("Truncated PROXY protocol addresses".to_string()),
+        // AF_UNSPEC, AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+/// Plain HTTP listener reading a PROXY protocol header from every connection
+pub struct ProxyProtocolListener {
+    connections: mpsc::Receiver<(TcpStream, ClientConnection)>,
+}
+
+impl ProxyProtocolListener {
+    pub fn new(listener: TcpListener) -> Self {
+        let (sender, connections) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, addr) = tokio::select! {
+                    // serving has stopped; release the socket
+                    _ = sender.closed() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::warn!("Failed to accept connection: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                };
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                        Ok(Ok(source)) => {
+                            let connection = ClientConnection {
+                                source,
+                                ..ClientConnection::default()
+                            };
+                            let _ = sender.send((stream, connection)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!(peer = %addr, "Dropping connection: {}", e),
+                        Err(_) => {
+                            tracing::debug!(peer = %addr, "PROXY protocol header timed out")
+                        }
+                    }
+                });
+            }
+        });
+        Self { connections }
+    }
+}
+
+impl Listener for ProxyProtocolListener {
+    type Io = TcpStream;
+    type Addr = ClientConnection;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // the accept task only stops once this listener is gone
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(ClientConnection::default())
+    }
+}
+
+impl Connected<IncomingStream<'_, ProxyProtocolListener>> for ClientConnection {
+    fn connect_info(stream: IncomingStream<'_, ProxyProtocolListener>) -> Self {
+        stream.remote_addr().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.10 198.51.100.1 56324 443\r\n"),
+            Ok(Some("192.0.2.10:56324".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::10 2001:db8::1 56324 443\r\n"),
+            Ok(Some("[2001:db8::10]:56324".parse().unwrap()))
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n"), Ok(None));
+        assert!(parse_v1("PROXY TCP4 2001:db8::10 198.51.100.1 56324 443\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.10 198.51.100.1 56324\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.10 198.51.100.1 56324 443").is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut ipv4 = vec![192, 0, 2, 10, 198, 51, 100, 1];
+        ipv4.extend(56324u16.to_be_bytes());
+        ipv4.extend(443u16.to_be_bytes());
+        assert_eq!(
+            parse_v2(0x21, 0x11, &ipv4),
+            Ok(Some("192.0.2.10:56324".parse().unwrap()))
+        );
+        // LOCAL health checks carry no client
+        assert_eq!(parse_v2(0x20, 0x00, &[]), Ok(None));
+        assert_eq!(parse_v2(0x21, 0x31, &[0; 216]), Ok(None));
+        assert!(parse_v2(0x21, 0x11, &ipv4[..8]).is_err());
+        assert!(parse_v2(0x11, 0x11, &ipv4).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut stream: &[u8] =
+            b"PROXY TCP4 192.0.2.10 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.0.2.10:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x21, 0, 36]);
+        v2.extend(Ipv6Addr::LOCALHOST.octets());
+        v2.extend(Ipv6Addr::UNSPECIFIED.octets());
+        v2.extend([0x1f, 0x90, 0x01, 0xbb]);
+        v2.extend(b"GET /");
+        let mut stream = &v2[..];
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("[::1]:8080".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET /");
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+}
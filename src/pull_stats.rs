@@ -0,0 +1,549 @@
+/// Persisted per-repository pull statistics
+///
+/// Each successful manifest GET counts as a pull and each blob GET adds the
+/// bytes served, bucketed by repository and UTC day together with the set of
+/// client identifiers seen (already pseudonymised per `[privacy]`). The
+/// buckets are written to a JSON file periodically and on shutdown, and days
+/// older than the retention are dropped when saving.
+///
+/// Within a repository, pulls and bytes are also split by tag. Manifests
+/// fetched by digest and blobs carry no tag, so they are attributed to the
+/// tag the same client last resolved in that repository, if recent; a pull
+/// by digest without one is counted under the digest and such blob bytes
+/// under an empty tag.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::config::StatsConfig;
+
+const FILE_VERSION: u32 = 1;
+pub const SECS_PER_DAY: u64 = 86_400;
+/// How long a tag resolved by a client claims its untagged requests
+const TAG_ATTRIBUTION_TTL: Duration = Duration::from_secs(10 * 60);
+/// Bound on the remembered (client, repository) tags
+const MAX_RECENT_TAGS: usize = 10_000;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct DayStats {
+    pulls: u64,
+    bytes: u64,
+    clients: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tags: BTreeMap<String, TagStats>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct TagStats {
+    pulls: u64,
+    bytes: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StatsFile {
+    version: u32,
+    /// Repository statistics keyed by days since the Unix epoch
+    days: BTreeMap<u64, BTreeMap<String, DayStats>>,
+}
+
+/// Totals for one repository over a range of days
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RepositoryStats {
+    pub repository: String,
+    pub pulls: u64,
+    pub bytes: u64,
+    pub unique_clients: usize,
+}
+
+/// Totals for one tag of a repository over a range of days
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TagPulls {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+    pub pulls: u64,
+    pub bytes: u64,
+}
+
+pub struct PullStats {
+    path: PathBuf,
+    retention_days: u64,
+    days: Mutex<BTreeMap<u64, BTreeMap<String, DayStats>>>,
+    /// Tag each client last resolved per repository
+    recent_tags: Mutex<HashMap<(String, String), (String, Instant)>>,
+    dirty: AtomicBool,
+    detached: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+impl PullStats {
+    /// Load the statistics file; a missing or unreadable file starts empty
+    pub fn open(config: &StatsConfig, clock: Arc<dyn Clock>) -> Self {
+        let path = PathBuf::from(&config.file);
+        let days = match fs::read(&path) {
+            Ok(data) => match serde_json::from_slice::<StatsFile>(&data) {
+                Ok(file) if file.version == FILE_VERSION => file.days,
+                Ok(file) => {
+                    tracing::warn!(
+                        "Ignoring stats file with unsupported version {}",
+                        file.version
+                    );
+                    BTreeMap::new()
+                }
+                Err(e) => {
+                    tracing::warn!("Stats file is corrupt, starting empty: {}", e);
+                    BTreeMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                tracing::warn!("Failed to read stats file, starting empty: {}", e);
+                BTreeMap::new()
+            }
+        };
+        Self {
+            path,
+            retention_days: config.retention_days,
+            days: Mutex::new(days),
+            recent_tags: Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+            detached: AtomicBool::new(false),
+            clock,
+        }
+    }
+
+    /// Count a manifest pull of `repository` by `client`; `reference` is the
+    /// tag or digest requested
+    pub fn record_pull(&self, repository: &str, reference: &str, client: &str) {
+        let tag = if is_digest(reference) {
+            self.recent_tag(repository, client)
+                .unwrap_or_else(|| reference.to_string())
+        } else {
+            self.note_tag(repository, reference, client);
+            reference.to_string()
+        };
+        self.record(repository, &tag, client, 1, 0);
+    }
+
+    /// Remember that `client` resolved `tag` of `repository` (e.g. with a
+    /// HEAD request) without counting a pull
+    pub fn note_tag(&self, repository: &str, tag: &str, client: &str) {
+        if is_digest(tag) {
+            return;
+        }
+        let now = self.clock.instant();
+        let mut recent = self.recent_tags.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() >= MAX_RECENT_TAGS {
+            recent.retain(|_, (_, seen)| now.duration_since(*seen) < TAG_ATTRIBUTION_TTL);
+            if recent.len() >= MAX_RECENT_TAGS {
+                recent.clear();
+            }
+        }
+        recent.insert(
+            (client.to_string(), repository.to_string()),
+            (tag.to_string(), now),
+        );
+    }
+
+    /// Add `bytes` of blob data served from `repository` to `client`
+    pub fn record_bytes(&self, repository: &str, client: &str, bytes: u64) {
+        let tag = self.recent_tag(repository, client).unwrap_or_default();
+        self.record(repository, &tag, client, 0, bytes);
+    }
+
+    fn record(&self, repository: &str, tag: &str, client: &str, pulls: u64, bytes: u64) {
+        let mut days = self.lock();
+        let day = days
+            .entry(self.today())
+            .or_default()
+            .entry(repository.to_string())
+            .or_default();
+        day.pulls += pulls;
+        day.bytes += bytes;
+        if !day.clients.contains(client) {
+            day.clients.insert(client.to_string());
+        }
+        let tag = day.tags.entry(tag.to_string()).or_default();
+        tag.pulls += pulls;
+        tag.bytes += bytes;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // The tag `client` resolved in `repository` within the attribution window
+    fn recent_tag(&self, repository: &str, client: &str) -> Option<String> {
+        let recent = self.recent_tags.lock().unwrap_or_else(|e| e.into_inner());
+        let (tag, seen) = recent.get(&(client.to_string(), repository.to_string()))?;
+        (self.clock.instant().duration_since(*seen) < TAG_ATTRIBUTION_TTL).then(|| tag.clone())
+    }
+
+    /// Per-repository totals over the last `days` days including today,
+    /// most pulled first
+    pub fn summary(&self, days: u64) -> Vec<RepositoryStats> {
+        let today = self.today();
+        let first = today.saturating_sub(days.saturating_sub(1));
+        let mut totals: BTreeMap<&str, (u64, u64, BTreeSet<&str>)> = BTreeMap::new();
+        let state = self.lock();
+        for repositories in state.range(first..=today).map(|(_, r)| r) {
+            for (repository, day) in repositories {
+                let total = totals.entry(repository).or_default();
+                total.0 += day.pulls;
+                total.1 += day.bytes;
+                total.2.extend(day.clients.iter().map(String::as_str));
+            }
+        }
+        let mut summary: Vec<_> = totals
+            .into_iter()
+            .map(|(repository, (pulls, bytes, clients))| RepositoryStats {
+                repository: repository.to_string(),
+                pulls,
+                bytes,
+                unique_clients: clients.len(),
+            })
+            .collect();
+        summary.sort_by(|a, b| b.pulls.cmp(&a.pulls).then(b.bytes.cmp(&a.bytes)));
+        summary
+    }
+
+    /// Per-tag totals for the days `first..=last` (days since the Unix
+    /// epoch), empty when `first` is after `last`; `upstream` maps a
+    /// repository name as pulled to its registry host and repository there
+    pub fn tag_summary(
+        &self,
+        first: u64,
+        last: u64,
+        upstream: impl Fn(&str) -> (String, String),
+    ) -> Vec<TagPulls> {
+        if first > last {
+            return Vec::new();
+        }
+        let mut totals: BTreeMap<(&str, &str), (u64, u64)> = BTreeMap::new();
+        let state = self.lock();
+        for repositories in state.range(first..=last).map(|(_, r)| r) {
+            for (repository, day) in repositories {
+                for (tag, stats) in &day.tags {
+                    let total = totals.entry((repository, tag)).or_default();
+                    total.0 += stats.pulls;
+                    total.1 += stats.bytes;
+                }
+            }
+        }
+        totals
+            .into_iter()
+            .map(|((name, tag), (pulls, bytes))| {
+                let (registry, repository) = upstream(name);
+                TagPulls {
+                    registry,
+                    repository,
+                    tag: tag.to_string(),
+                    pulls,
+                    bytes,
+                }
+            })
+            .collect()
+    }
+
+    /// Days since the Unix epoch (UTC) of a Unix timestamp
+    pub fn day_of(secs: u64) -> u64 {
+        secs / SECS_PER_DAY
+    }
+
+    /// Drop days past the retention and write the file atomically
+    /// (temp file + rename)
+    pub fn persist(&self) -> io::Result<()> {
+        if self.detached.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.dirty.store(false, Ordering::Relaxed);
+        let file = {
+            let mut days = self.lock();
+            let oldest = self
+                .today()
+                .saturating_sub(self.retention_days.saturating_sub(1));
+            days.retain(|day, _| *day >= oldest);
+            StatsFile {
+                version: FILE_VERSION,
+                days: days.clone(),
+            }
+        };
+
+        let result = serde_json::to_vec(&file)
+            .map_err(io::Error::other)
+            .and_then(|data| {
+                if let Some(parent) = self.path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut tmp = self.path.clone().into_os_string();
+                tmp.push(".tmp");
+                fs::write(&tmp, data)?;
+                fs::rename(&tmp, &self.path)
+            });
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Leave the file to a process that took over (warm restart)
+    pub fn detach(&self) {
+        self.detached.store(true, Ordering::Relaxed);
+    }
+
+    /// Periodically persist the statistics while they have unsaved changes
+    pub fn spawn_flush_task(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if !self.dirty.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let stats = Arc::clone(&self);
+                match tokio::task::spawn_blocking(move || stats.persist()).await {
+                    Ok(Ok(())) => tracing::debug!("Pull statistics persisted"),
+                    Ok(Err(e)) => tracing::warn!("Failed to persist pull statistics: {}", e),
+                    Err(e) => tracing::warn!("Pull statistics flush task failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Days since the Unix epoch (UTC)
+    pub fn today(&self) -> u64 {
+        Self::day_of(self.clock.now_secs())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, BTreeMap<String, DayStats>>> {
+        self.days.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Render totals as CSV with a header row
+pub fn to_csv(rows: &[RepositoryStats]) -> String {
+    let mut csv = String::from("repository,pulls,bytes,unique_clients\r\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{}\r\n",
+            csv_field(&row.repository),
+            row.pulls,
+            row.bytes,
+            row.unique_clients
+        ));
+    }
+    csv
+}
+
+// Quote a field when it contains a separator, quote or line break (RFC 4180)
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Digests are "<algorithm>:<hex>"; tags cannot contain ':'
+fn is_digest(reference: &str) -> bool {
+    reference.contains(':')
+}
+
+/// Parse a range such as `30d` or `4w` into a number of days
+pub fn parse_range(range: &str) -> Option<u64> {
+    let days = if let Some(count) = range.strip_suffix('d') {
+        count.parse().ok()?
+    } else if let Some(count) = range.strip_suffix('w') {
+        count.parse::<u64>().ok()?.checked_mul(7)?
+    } else {
+        return None;
+    };
+    (days > 0).then_some(days)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn test_config() -> StatsConfig {
+        let file = std::env::temp_dir()
+            .join(format!("docker-proxy-stats-{}", uuid::Uuid::new_v4()))
+            .join("stats.json");
+        StatsConfig {
+            enabled: true,
+            file: file.to_string_lossy().into_owned(),
+            ..StatsConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("30d"), Some(30));
+        assert_eq!(parse_range("2w"), Some(14));
+        assert_eq!(parse_range("0d"), None);
+        assert_eq!(parse_range("30"), None);
+        assert_eq!(parse_range("d"), None);
+        assert_eq!(parse_range(""), None);
+        assert_eq!(parse_range("1é"), None);
+        assert_eq!(parse_range("é"), None);
+    }
+
+    #[test]
+    fn test_summary_and_persist() {
+        let config = test_config();
+        let clock = ManualClock::new(20_000 * SECS_PER_DAY);
+        let stats = PullStats::open(&config, clock.clone());
+        stats.record_pull("library/nginx", "latest", "10.0.0.1");
+        stats.record_pull("library/nginx", "latest", "10.0.0.2");
+        stats.record_bytes("library/nginx", "10.0.0.1", 1000);
+        stats.record_pull("library/alpine", "3.20", "10.0.0.1");
+        // Older days only count when the range covers them
+        stats.lock().entry(19_990).or_default().insert(
+            "library/alpine".to_string(),
+            DayStats {
+                pulls: 5,
+                bytes: 500,
+                clients: BTreeSet::from(["10.0.0.3".to_string()]),
+                ..DayStats::default()
+            },
+        );
+
+        let week = stats.summary(7);
+        assert_eq!(
+            week,
+            vec![
+                RepositoryStats {
+                    repository: "library/nginx".to_string(),
+                    pulls: 2,
+                    bytes: 1000,
+                    unique_clients: 2,
+                },
+                RepositoryStats {
+                    repository: "library/alpine".to_string(),
+                    pulls: 1,
+                    bytes: 0,
+                    unique_clients: 1,
+                },
+            ]
+        );
+        let month = stats.summary(30);
+        assert_eq!(month[0].repository, "library/alpine");
+        assert_eq!(month[0].pulls, 6);
+        assert_eq!(month[0].unique_clients, 2);
+
+        stats.persist().unwrap();
+        let reopened = PullStats::open(&config, clock.clone());
+        assert_eq!(reopened.summary(30), month);
+
+        // Days past the retention are dropped when saving
+        clock.advance(Duration::from_secs(85 * SECS_PER_DAY));
+        reopened.persist().unwrap();
+        let pruned = PullStats::open(&config, clock);
+        assert_eq!(pruned.summary(90).len(), 2);
+        assert_eq!(pruned.summary(90)[1].pulls, 1);
+
+        let _ = fs::remove_dir_all(PathBuf::from(&config.file).parent().unwrap());
+    }
+
+    #[test]
+    fn test_tag_summary() {
+        let config = test_config();
+        let clock = ManualClock::new(20_000 * SECS_PER_DAY);
+        let stats = PullStats::open(&config, clock.clone());
+        let digest = "sha256:0123456789abcdef";
+
+        // The index by tag, then the platform manifest and blobs untagged
+        stats.record_pull("nginx", "1.27", "10.0.0.1");
+        stats.record_pull("nginx", digest, "10.0.0.1");
+        stats.record_bytes("nginx", "10.0.0.1", 700);
+        // A tag resolved with HEAD claims the pull by digest
+        stats.note_tag("nginx", "latest", "10.0.0.2");
+        stats.record_pull("nginx", digest, "10.0.0.2");
+        stats.record_bytes("nginx", "10.0.0.2", 300);
+        // Without a recent tag, pulls count under the digest
+        clock.advance(TAG_ATTRIBUTION_TTL);
+        stats.record_pull("nginx", digest, "10.0.0.1");
+        stats.record_bytes("nginx", "10.0.0.1", 50);
+
+        let today = stats.today();
+        let rows = stats.tag_summary(today, today, |name| {
+            (
+                "registry-1.docker.io".to_string(),
+                format!("library/{}", name),
+            )
+        });
+        let row = |tag: &str, pulls, bytes| TagPulls {
+            registry: "registry-1.docker.io".to_string(),
+            repository: "library/nginx".to_string(),
+            tag: tag.to_string(),
+            pulls,
+            bytes,
+        };
+        assert_eq!(
+            rows,
+            vec![
+                row("", 0, 50),
+                row("1.27", 2, 700),
+                row("latest", 1, 300),
+                row(digest, 1, 0),
+            ]
+        );
+        // Tag totals add up to the repository's
+        assert_eq!(stats.summary(1)[0].pulls, 4);
+        assert_eq!(stats.summary(1)[0].bytes, 1050);
+        assert!(
+            stats
+                .tag_summary(0, today - 1, |n| (String::new(), n.to_string()))
+                .is_empty()
+        );
+
+        stats.persist().unwrap();
+        let reopened = PullStats::open(&config, clock);
+        assert_eq!(
+            reopened.tag_summary(today, today, |name| {
+                (
+                    "registry-1.docker.io".to_string(),
+                    format!("library/{}", name),
+                )
+            }),
+            rows
+        );
+        let _ = fs::remove_dir_all(PathBuf::from(&config.file).parent().unwrap());
+    }
+
+    #[test]
+    fn test_tag_summary_empty_range() {
+        let config = test_config();
+        let clock = ManualClock::new(20_000 * SECS_PER_DAY);
+        let stats = PullStats::open(&config, clock);
+        stats.record_pull("nginx", "latest", "10.0.0.1");
+
+        // A range that ends before it starts has no days instead of panicking
+        let today = stats.today();
+        let upstream = |n: &str| (String::new(), n.to_string());
+        assert!(stats.tag_summary(today + 1, today, upstream).is_empty());
+        assert!(stats.tag_summary(u64::MAX, 0, upstream).is_empty());
+        assert_eq!(stats.tag_summary(today, u64::MAX, upstream).len(), 1);
+        let _ = fs::remove_dir_all(PathBuf::from(&config.file).parent().unwrap());
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let rows = vec![RepositoryStats {
+            repository: "ghcr.io/owner/repo,odd".to_string(),
+            pulls: 3,
+            bytes: 42,
+            unique_clients: 1,
+        }];
+        assert_eq!(
+            to_csv(&rows),
+            "repository,pulls,bytes,unique_clients\r\n\"ghcr.io/owner/repo,odd\",3,42,1\r\n"
+        );
+    }
+}
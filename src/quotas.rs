@@ -0,0 +1,475 @@
+/// Per-user pull quotas
+///
+/// Every successful manifest GET by a `[client_auth]` or `[oidc]` user counts
+/// as a pull
+/// and every blob GET adds the bytes served, in counters that start over each
+/// UTC day and calendar month. Once a user reaches one of their limits, new
+/// pulls are refused until the window resets. Reaching a transfer limit also
+/// refuses blob downloads, while a pull limit leaves the blobs of pulls
+/// already started alone. The counters are written to a JSON file
+/// periodically and on shutdown.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::config::{QuotaConfig, QuotaLimits};
+
+const FILE_VERSION: u32 = 1;
+const SECS_PER_DAY: u64 = 86_400;
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Pulls and bytes of one user in the current day and month
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Days since the Unix epoch the daily counters belong to
+    pub day: u64,
+    pub daily_pulls: u64,
+    pub daily_bytes: u64,
+    /// Months since January 1970 the monthly counters belong to
+    pub month: u64,
+    pub monthly_pulls: u64,
+    pub monthly_bytes: u64,
+}
+
+impl Usage {
+    // Start the counters over when the day or month has changed
+    fn roll(&mut self, day: u64) {
+        if self.day != day {
+            self.day = day;
+            self.daily_pulls = 0;
+            self.daily_bytes = 0;
+        }
+        let month = month_of(day);
+        if self.month != month {
+            self.month = month;
+            self.monthly_pulls = 0;
+            self.monthly_bytes = 0;
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QuotaFile {
+    version: u32,
+    users: BTreeMap<String, Usage>,
+}
+
+/// A limit the user has reached
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Exceeded {
+    pub user: String,
+    /// "daily pulls", "monthly pulls", "daily MiB" or "monthly MiB"
+    pub limit: &'static str,
+    pub allowed: u64,
+    /// Seconds until the window resets
+    pub retry_after_secs: u64,
+}
+
+impl Exceeded {
+    /// Message for the client's error response
+    pub fn message(&self) -> String {
+        format!(
+            "pull quota of {} {} exceeded for user {}, resets in {}s",
+            self.allowed, self.limit, self.user, self.retry_after_secs
+        )
+    }
+}
+
+/// Usage and limits of a user, for the quota report
+#[derive(Debug, Clone, Serialize)]
+pub struct UserQuota {
+    pub user: String,
+    pub usage: Usage,
+    pub limits: QuotaLimits,
+}
+
+/// Default limits and those of users with their own
+struct Limits {
+    default: QuotaLimits,
+    users: BTreeMap<String, QuotaLimits>,
+}
+
+impl Limits {
+    fn from_config(config: &QuotaConfig) -> Self {
+        Self {
+            default: config.default,
+            users: config
+                .users
+                .iter()
+                .map(|(user, limits)| (user.clone(), *limits))
+                .collect(),
+        }
+    }
+}
+
+pub struct Quotas {
+    path: PathBuf,
+    limits: RwLock<Limits>,
+    users: Mutex<BTreeMap<String, Usage>>,
+    dirty: AtomicBool,
+    detached: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Quotas {
+    /// Load the usage file; a missing or unreadable file starts empty
+    pub fn open(config: &QuotaConfig, clock: Arc<dyn Clock>) -> Self {
+        let path = PathBuf::from(&config.file);
+        let users = match fs::read(&path) {
+            Ok(data) => match serde_json::from_slice::<QuotaFile>(&data) {
+                Ok(file) if file.version == FILE_VERSION => file.users,
+                Ok(file) => {
+                    tracing::warn!(
+                        "Ignoring quota file with unsupported version {}",
+                        file.version
+                    );
+                    BTreeMap::new()
+                }
+                Err(e) => {
+                    tracing::warn!("Quota file is corrupt, starting empty: {}", e);
+                    BTreeMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                tracing::warn!("Failed to read quota file, starting empty: {}", e);
+                BTreeMap::new()
+            }
+        };
+        Self {
+            path,
+            limits: RwLock::new(Limits::from_config(config)),
+            users: Mutex::new(users),
+            dirty: AtomicBool::new(false),
+            detached: AtomicBool::new(false),
+            clock,
+        }
+    }
+
+    /// Check whether `user` may start a pull (a manifest GET) or, with
+    /// `manifest` false, download a blob
+    pub fn check(&self, user: &str, manifest: bool) -> Result<(), Exceeded> {
+        let limits = self.limits_of(user);
+        let now = self.clock.now_secs();
+        let day = now / SECS_PER_DAY;
+        let usage = {
+            let mut users = self.lock();
+            match users.get_mut(user) {
+                Some(usage) => {
+                    usage.roll(day);
+                    usage.clone()
+                }
+                None => return Ok(()),
+            }
+        };
+        let until_tomorrow = (day + 1) * SECS_PER_DAY - now;
+        let until_next_month = first_day_of_month(month_of(day) + 1) * SECS_PER_DAY - now;
+        let checks = [
+            (
+                manifest,
+                "daily pulls",
+                usage.daily_pulls,
+                limits.daily_pulls,
+                until_tomorrow,
+            ),
+            (
+                manifest,
+                "monthly pulls",
+                usage.monthly_pulls,
+                limits.monthly_pulls,
+                until_next_month,
+            ),
+            (
+                true,
+                "daily MiB",
+                usage.daily_bytes / BYTES_PER_MB,
+                limits.daily_mb,
+                until_tomorrow,
+            ),
+            (
+                true,
+                "monthly MiB",
+                usage.monthly_bytes / BYTES_PER_MB,
+                limits.monthly_mb,
+                until_next_month,
+            ),
+        ];
+        match checks
+            .into_iter()
+            .find(|&(applies, _, used, allowed, _)| applies && allowed > 0 && used >= allowed)
+        {
+            Some((_, limit, _, allowed, retry_after_secs)) => Err(Exceeded {
+                user: user.to_string(),
+                limit,
+                allowed,
+                retry_after_secs,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Count a manifest pull by `user`
+    pub fn record_pull(&self, user: &str) {
+        self.record(user, 1, 0);
+    }
+
+    /// Add `bytes` of blob data served to `user`
+    pub fn record_bytes(&self, user: &str, bytes: u64) {
+        self.record(user, 0, bytes);
+    }
+
+    fn record(&self, user: &str, pulls: u64, bytes: u64) {
+        let day = self.clock.now_secs() / SECS_PER_DAY;
+        let mut users = self.lock();
+        let usage = users.entry(user.to_string()).or_default();
+        usage.roll(day);
+        usage.daily_pulls += pulls;
+        usage.monthly_pulls += pulls;
+        usage.daily_bytes += bytes;
+        usage.monthly_bytes += bytes;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Current usage and limits of every user that pulled this month
+    pub fn report(&self) -> Vec<UserQuota> {
+        let day = self.clock.now_secs() / SECS_PER_DAY;
+        let mut users = self.lock();
+        users
+            .iter_mut()
+            .filter_map(|(user, usage)| {
+                usage.roll(day);
+                (usage.monthly_pulls > 0 || usage.monthly_bytes > 0).then(|| UserQuota {
+                    user: user.clone(),
+                    usage: usage.clone(),
+                    limits: self.limits_of(user),
+                })
+            })
+            .collect()
+    }
+
+    /// Drop users without usage this month and write the file atomically
+    /// (temp file + rename)
+    pub fn persist(&self) -> io::Result<()> {
+        if self.detached.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.dirty.store(false, Ordering::Relaxed);
+        let file = {
+            let month = month_of(self.clock.now_secs() / SECS_PER_DAY);
+            let mut users = self.lock();
+            users.retain(|_, usage| {
+                usage.month >= month && (usage.monthly_pulls > 0 || usage.monthly_bytes > 0)
+            });
+            QuotaFile {
+                version: FILE_VERSION,
+                users: users.clone(),
+            }
+        };
+
+        let result = serde_json::to_vec(&file)
+            .map_err(io::Error::other)
+            .and_then(|data| {
+                if let Some(parent) = self.path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut tmp = self.path.clone().into_os_string();
+                tmp.push(".tmp");
+                fs::write(&tmp, data)?;
+                fs::rename(&tmp, &self.path)
+            });
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Leave the file to a process that took over (warm restart)
+    pub fn detach(&self) {
+        self.detached.store(true, Ordering::Relaxed);
+    }
+
+    /// Periodically persist the usage while it has unsaved changes
+    pub fn spawn_flush_task(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if !self.dirty.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let quotas = Arc::clone(&self);
+                match tokio::task::spawn_blocking(move || quotas.persist()).await {
+                    Ok(Ok(())) => tracing::debug!("Quota usage persisted"),
+                    Ok(Err(e)) => tracing::warn!("Failed to persist quota usage: {}", e),
+                    Err(e) => tracing::warn!("Quota flush task failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Replace the limits with those of `config`; usage is kept
+    pub fn set_limits(&self, config: &QuotaConfig) {
+        *self.limits.write().unwrap_or_else(|e| e.into_inner()) = Limits::from_config(config);
+    }
+
+    fn limits_of(&self, user: &str) -> QuotaLimits {
+        let limits = self.limits.read().unwrap_or_else(|e| e.into_inner());
+        limits.users.get(user).copied().unwrap_or(limits.default)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Usage>> {
+        self.users.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Months since January 1970 of a day since the Unix epoch, using the
+// proleptic Gregorian calendar (Howard Hinnant's civil_from_days)
+fn month_of(day: u64) -> u64 {
+    let z = day + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year - 1970) * 12 + month - 1
+}
+
+// Day since the Unix epoch a month (as counted by `month_of`) starts on
+fn first_day_of_month(month: u64) -> u64 {
+    let (year, month) = (1970 + month / 12, month % 12 + 1);
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    // 2024-02-28 12:00:00 UTC
+    const START: u64 = 1_709_121_600;
+
+    fn test_config() -> QuotaConfig {
+        let file = std::env::temp_dir()
+            .join(format!("docker-proxy-quotas-{}", uuid::Uuid::new_v4()))
+            .join("quotas.json");
+        QuotaConfig {
+            enabled: true,
+            file: file.to_string_lossy().into_owned(),
+            default: QuotaLimits {
+                daily_pulls: 2,
+                monthly_mb: 1,
+                ..QuotaLimits::default()
+            },
+            users: std::collections::HashMap::from([(
+                "ci".to_string(),
+                QuotaLimits {
+                    monthly_pulls: 3,
+                    ..QuotaLimits::default()
+                },
+            )]),
+            ..QuotaConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_calendar() {
+        // 1970-01-01, 2024-02-29 (leap day), 2024-03-01
+        assert_eq!(month_of(0), 0);
+        assert_eq!(month_of(19_782), 649);
+        assert_eq!(month_of(19_783), 650);
+        assert_eq!(first_day_of_month(650), 19_783);
+        assert_eq!(first_day_of_month(0), 0);
+        assert_eq!(first_day_of_month(12), 365);
+    }
+
+    #[test]
+    fn test_limits_and_windows() {
+        let config = test_config();
+        let clock = ManualClock::new(START);
+        let quotas = Quotas::open(&config, clock.clone());
+
+        assert!(quotas.check("dev", true).is_ok());
+        quotas.record_pull("dev");
+        quotas.record_pull("dev");
+        let exceeded = quotas.check("dev", true).unwrap_err();
+        assert_eq!(exceeded.limit, "daily pulls");
+        assert_eq!(exceeded.retry_after_secs, 12 * 3600);
+        assert_eq!(
+            exceeded.message(),
+            "pull quota of 2 daily pulls exceeded for user dev, resets in 43200s"
+        );
+        // blobs of pulls already started are still served
+        assert!(quotas.check("dev", false).is_ok());
+        quotas.record_bytes("dev", BYTES_PER_MB);
+        assert_eq!(quotas.check("dev", false).unwrap_err().limit, "monthly MiB");
+
+        // user limits replace the default ones
+        for _ in 0..3 {
+            assert!(quotas.check("ci", true).is_ok());
+            quotas.record_pull("ci");
+        }
+        quotas.record_bytes("ci", 10 * BYTES_PER_MB);
+        assert!(quotas.check("ci", false).is_ok());
+        let exceeded = quotas.check("ci", true).unwrap_err();
+        assert_eq!(exceeded.limit, "monthly pulls");
+        // 2024 is a leap year: the month ends after Feb 29
+        assert_eq!(exceeded.retry_after_secs, 36 * 3600);
+
+        // the next day resets daily pulls but not the month's transfer
+        clock.advance(Duration::from_secs(SECS_PER_DAY));
+        assert!(quotas.check("dev", true).is_err());
+        assert!(quotas.check("ci", true).is_err());
+        // a new month resets everything
+        clock.advance(Duration::from_secs(SECS_PER_DAY));
+        assert!(quotas.check("dev", true).is_ok());
+        assert!(quotas.check("ci", true).is_ok());
+
+        // new limits apply to the usage so far
+        quotas.record_pull("dev");
+        let mut lower = config.clone();
+        lower.default.daily_pulls = 1;
+        quotas.set_limits(&lower);
+        assert_eq!(quotas.check("dev", true).unwrap_err().limit, "daily pulls");
+    }
+
+    #[test]
+    fn test_persist_and_report() {
+        let config = test_config();
+        let clock = ManualClock::new(START);
+        let quotas = Quotas::open(&config, clock.clone());
+        quotas.record_pull("ci");
+        quotas.record_bytes("ci", 42);
+        quotas.persist().unwrap();
+
+        let reopened = Quotas::open(&config, clock.clone());
+        let report = reopened.report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].user, "ci");
+        assert_eq!(report[0].usage.daily_pulls, 1);
+        assert_eq!(report[0].usage.monthly_bytes, 42);
+        assert_eq!(report[0].limits.monthly_pulls, 3);
+
+        // last month's users are dropped
+        clock.advance(Duration::from_secs(2 * SECS_PER_DAY));
+        assert!(reopened.report().is_empty());
+        reopened.persist().unwrap();
+        assert!(Quotas::open(&config, clock).report().is_empty());
+        let _ = fs::remove_dir_all(PathBuf::from(&config.file).parent().unwrap());
+    }
+}
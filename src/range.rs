@@ -1,54 +1,203 @@
 /// Range request support for HTTP partial content delivery
+use axum::body::Body;
 use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use bytes::Bytes;
+use futures_util::{Stream, stream};
+use std::collections::VecDeque;
+use std::io::{self, SeekFrom};
 use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
-/// Parse HTTP Range header
-/// Example: "bytes=0-1023" or "bytes=1024-"
-pub fn parse_range_header(range_header: &str, file_size: u64) -> Option<Range<u64>> {
-    let range_header = range_header.trim();
+/// Default read size when streaming file ranges
+pub const READ_CHUNK: usize = 64 * 1024;
 
-    // Only support bytes range
-    if !range_header.starts_with("bytes=") {
-        return None;
+/// Maximum number of ranges honoured in one request; larger range sets are
+/// ignored and the full content is served
+pub const MAX_RANGES: usize = 16;
+
+/// Outcome of evaluating a Range header against a resource
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable Range header: serve the full content with 200
+    Full,
+    /// Satisfiable ranges (exclusive ends), sorted and with overlaps merged
+    Partial(Vec<Range<u64>>),
+    /// Valid syntax but nothing satisfiable: 416 with `Content-Range: bytes */<size>`
+    Unsatisfiable,
+}
+
+/// Evaluate an HTTP Range header (RFC 9110 section 14)
+/// Example: "bytes=0-1023", "bytes=1024-", "bytes=-500" or "bytes=0-99,200-299"
+///
+/// Syntactically invalid headers are ignored rather than rejected, as the
+/// RFC requires; unsatisfiable specs are dropped from a range set.
+pub fn evaluate_range_header(range_header: &str, file_size: u64) -> RangeRequest {
+    let Some(range_set) = range_header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+
+    let specs: Vec<&str> = range_set
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return RangeRequest::Full;
     }
 
-    let range_spec = &range_header[6..]; // Skip "bytes="
-    let parts: Vec<&str> = range_spec.split('-').collect();
+    let mut ranges = Vec::with_capacity(specs.len());
+    for spec in specs {
+        match parse_range_spec(spec, file_size) {
+            Some(Some(range)) => ranges.push(range),
+            Some(None) => {}
+            None => return RangeRequest::Full,
+        }
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
 
-    if parts.len() != 2 {
-        return None;
+    // Merge overlapping or adjacent ranges
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
     }
+    RangeRequest::Partial(merged)
+}
 
-    // Parse start and end
-    let start = if parts[0].is_empty() {
-        // Suffix range: "-500" means last 500 bytes
-        if let Ok(suffix_length) = parts[1].parse::<u64>() {
-            if suffix_length == 0 || suffix_length >= file_size {
-                return Some(0..file_size);
-            }
-            return Some(file_size - suffix_length..file_size);
+/// Validators of the representation a range request targets, used to
+/// evaluate `If-Range`
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    /// Strong entity tag, including the quotes
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Validators of a file, derived from its size and modification time
+    pub fn for_file(metadata: &std::fs::Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let mtime = modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            etag: Some(format!("\"{:x}-{:x}\"", metadata.len(), mtime)),
+            last_modified: modified,
         }
-        return None;
-    } else {
-        parts[0].parse::<u64>().ok()?
+    }
+
+    /// Add `ETag` / `Last-Modified` to a response
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        if let Some(value) = self
+            .etag
+            .as_deref()
+            .and_then(|etag| HeaderValue::from_str(etag).ok())
+        {
+            headers.insert(header::ETAG, value);
+        }
+        if let Some(value) = self
+            .last_modified
+            .and_then(|t| HeaderValue::from_str(&httpdate::fmt_http_date(t)).ok())
+        {
+            headers.insert(header::LAST_MODIFIED, value);
+        }
+    }
+}
+
+/// Whether an `If-Range` value still matches the current representation.
+/// Only strong entity tags and exact dates match (RFC 9110 section 13.1.5),
+/// so weak tags and unparseable values always fall back to the full content.
+pub fn if_range_matches(value: &str, validators: &Validators) -> bool {
+    let value = value.trim();
+    if value.starts_with('"') {
+        return validators.etag.as_deref() == Some(value);
+    }
+    if value.starts_with("W/") {
+        return false;
+    }
+    match (httpdate::parse_http_date(value), validators.last_modified) {
+        // HTTP dates have one second resolution
+        (Ok(date), Some(modified)) => truncate_to_secs(modified) == date,
+        _ => false,
+    }
+}
+
+/// Evaluate the `Range` header of a request, ignoring it when `If-Range` no
+/// longer matches so a resumed download restarts with the new content
+pub fn evaluate_request(
+    headers: &HeaderMap,
+    file_size: u64,
+    validators: &Validators,
+) -> RangeRequest {
+    let Some(range_header) = headers.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+        return RangeRequest::Full;
     };
+    if let Some(if_range) = headers.get(header::IF_RANGE) {
+        let matches = if_range
+            .to_str()
+            .is_ok_and(|value| if_range_matches(value, validators));
+        if !matches {
+            tracing::debug!(if_range = ?if_range, "If-Range mismatch, serving full content");
+            return RangeRequest::Full;
+        }
+    }
+    evaluate_range_header(range_header, file_size)
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+// Parse one range spec. `None` for invalid syntax, `Some(None)` for a valid
+// but unsatisfiable spec.
+fn parse_range_spec(spec: &str, file_size: u64) -> Option<Option<Range<u64>>> {
+    let (first, last) = spec.split_once('-')?;
+    let number = |s: &str| {
+        if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            s.parse::<u64>().ok()
+        } else {
+            None
+        }
+    };
+
+    if first.is_empty() {
+        // Suffix range: "-500" means last 500 bytes
+        let suffix_length = number(last)?;
+        if suffix_length == 0 || file_size == 0 {
+            return Some(None);
+        }
+        return Some(Some(file_size.saturating_sub(suffix_length)..file_size));
+    }
 
-    let end = if parts[1].is_empty() {
+    let start = number(first)?;
+    let end = if last.is_empty() {
         // Open-ended range: "1024-" means from 1024 to end
         file_size
     } else {
         // Explicit end: "0-1023" means bytes 0 to 1023 inclusive
         // Add 1 to convert from inclusive end to exclusive end for Rust Range
-        let end_inclusive = parts[1].parse::<u64>().ok()?;
-        (end_inclusive + 1).min(file_size)
+        let end_inclusive = number(last)?;
+        if end_inclusive < start {
+            return None;
+        }
+        end_inclusive.saturating_add(1).min(file_size)
     };
 
-    // Validate range
-    if start >= file_size || start >= end {
-        return None;
+    if start >= file_size {
+        return Some(None);
     }
-
-    Some(start..end)
+    Some(Some(start..end))
 }
 
 /// Create response headers for Range request
@@ -90,57 +239,336 @@ pub fn create_range_headers(
     Ok((StatusCode::PARTIAL_CONTENT, headers))
 }
 
+/// Headers for a 416 response: `Content-Range: bytes */<size>`
+pub fn unsatisfiable_headers(file_size: u64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", file_size)) {
+        headers.insert(header::CONTENT_RANGE, value);
+    }
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers
+}
+
+/// Complete length from a response `Content-Range` (`bytes <a>-<b>/<size>`
+/// or `bytes */<size>`); `None` when it is unknown (`*`) or malformed
+pub fn content_range_size(value: &str) -> Option<u64> {
+    let (_, size) = value.trim().strip_prefix("bytes ")?.rsplit_once('/')?;
+    size.parse().ok()
+}
+
+/// Layout of a multipart/byteranges body
+pub struct MultipartRanges {
+    boundary: String,
+    parts: Vec<(Bytes, Range<u64>)>,
+    trailer: Bytes,
+}
+
+impl MultipartRanges {
+    pub fn new(ranges: &[Range<u64>], file_size: u64, content_type: &str) -> Self {
+        let boundary = uuid::Uuid::new_v4().simple().to_string();
+        let parts = ranges
+            .iter()
+            .map(|range| {
+                let header = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    range.start,
+                    range.end - 1,
+                    file_size
+                );
+                (Bytes::from(header), range.clone())
+            })
+            .collect();
+        let trailer = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+        Self {
+            boundary,
+            parts,
+            trailer,
+        }
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// Total body length including part headers and the closing boundary
+    pub fn content_length(&self) -> u64 {
+        self.parts
+            .iter()
+            .map(|(header, range)| header.len() as u64 + (range.end - range.start))
+            .sum::<u64>()
+            + self.trailer.len() as u64
+    }
+}
+
+/// Build a 206 response streaming `ranges` of `file` in reads of up to
+/// `read_chunk` bytes: a single range with `Content-Range`, several as
+/// multipart/byteranges
+pub fn range_response(
+    file: tokio::fs::File,
+    ranges: Vec<Range<u64>>,
+    file_size: u64,
+    content_type: &str,
+    read_chunk: usize,
+) -> Result<(StatusCode, HeaderMap, Body), ()> {
+    if let [range] = ranges.as_slice() {
+        let (status, headers) = create_range_headers(range, file_size, content_type)?;
+        let parts = vec![(Bytes::new(), range.clone())];
+        let body = Body::from_stream(file_parts_stream(file, parts, Bytes::new(), read_chunk));
+        return Ok((status, headers, body));
+    }
+
+    let multipart = MultipartRanges::new(&ranges, file_size, content_type);
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        multipart
+            .content_type()
+            .parse::<HeaderValue>()
+            .map_err(|_| ())?,
+    );
+    headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(multipart.content_length()),
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let body = Body::from_stream(file_parts_stream(
+        file,
+        multipart.parts,
+        multipart.trailer,
+        read_chunk,
+    ));
+    Ok((StatusCode::PARTIAL_CONTENT, headers, body))
+}
+
+// Emit each part header followed by its file slice, then the trailer
+fn file_parts_stream(
+    file: tokio::fs::File,
+    parts: Vec<(Bytes, Range<u64>)>,
+    trailer: Bytes,
+    read_chunk: usize,
+) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static {
+    let state = (file, VecDeque::from(parts), 0u64, Some(trailer));
+    stream::unfold(Some(state), move |state| async move {
+        let (mut file, mut parts, mut remaining, mut trailer) = state?;
+        if remaining > 0 {
+            let mut buf = vec![0u8; remaining.min(read_chunk as u64) as usize];
+            return match file.read(&mut buf).await {
+                Ok(0) => Some((Err(io::Error::from(io::ErrorKind::UnexpectedEof)), None)),
+                Ok(n) => {
+                    buf.truncate(n);
+                    remaining -= n as u64;
+                    Some((
+                        Ok(Bytes::from(buf)),
+                        Some((file, parts, remaining, trailer)),
+                    ))
+                }
+                Err(e) => Some((Err(e), None)),
+            };
+        }
+        if let Some((header, range)) = parts.pop_front() {
+            if let Err(e) = file.seek(SeekFrom::Start(range.start)).await {
+                return Some((Err(e), None));
+            }
+            remaining = range.end - range.start;
+            return Some((Ok(header), Some((file, parts, remaining, trailer))));
+        }
+        trailer.take().map(|t| (Ok(t), None))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn single(range: Range<u64>) -> RangeRequest {
+        RangeRequest::Partial(vec![range])
+    }
+
+    fn partial(ranges: &[Range<u64>]) -> RangeRequest {
+        RangeRequest::Partial(ranges.to_vec())
+    }
+
+    fn request(range: &str, if_range: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, range.parse().unwrap());
+        if let Some(if_range) = if_range {
+            headers.insert(header::IF_RANGE, if_range.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_if_range() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        let validators = Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some(modified),
+        };
+        let date = httpdate::fmt_http_date(modified);
+
+        assert!(if_range_matches("\"abc\"", &validators));
+        assert!(!if_range_matches("\"other\"", &validators));
+        assert!(!if_range_matches("W/\"abc\"", &validators));
+        assert!(if_range_matches(&date, &validators));
+        assert!(!if_range_matches(
+            "Mon, 01 Jan 2024 00:00:00 GMT",
+            &validators
+        ));
+        assert!(!if_range_matches("not a date", &validators));
+        assert!(!if_range_matches(&date, &Validators::default()));
+
+        assert_eq!(
+            evaluate_request(&request("bytes=0-9", None), 100, &validators),
+            single(0..10)
+        );
+        assert_eq!(
+            evaluate_request(&request("bytes=0-9", Some("\"abc\"")), 100, &validators),
+            single(0..10)
+        );
+        // changed content restarts the download
+        assert_eq!(
+            evaluate_request(&request("bytes=0-9", Some("\"old\"")), 100, &validators),
+            RangeRequest::Full
+        );
+        assert_eq!(
+            evaluate_request(&HeaderMap::new(), 100, &validators),
+            RangeRequest::Full
+        );
+    }
+
     #[test]
     fn test_parse_range_basic() {
         // Basic range: 0-1023
-        let range = parse_range_header("bytes=0-1023", 10000);
-        assert_eq!(range, Some(0..1024));
+        let range = evaluate_range_header("bytes=0-1023", 10000);
+        assert_eq!(range, single(0..1024));
 
         // Open-ended range: 1024-
-        let range = parse_range_header("bytes=1024-", 10000);
-        assert_eq!(range, Some(1024..10000));
+        let range = evaluate_range_header("bytes=1024-", 10000);
+        assert_eq!(range, single(1024..10000));
 
         // Suffix range: -500 (last 500 bytes)
-        let range = parse_range_header("bytes=-500", 10000);
-        assert_eq!(range, Some(9500..10000));
+        let range = evaluate_range_header("bytes=-500", 10000);
+        assert_eq!(range, single(9500..10000));
     }
 
     #[test]
     fn test_parse_range_edge_cases() {
         // Range exceeds file size
-        let range = parse_range_header("bytes=0-20000", 10000);
-        assert_eq!(range, Some(0..10000));
+        let range = evaluate_range_header("bytes=0-20000", 10000);
+        assert_eq!(range, single(0..10000));
 
-        // Start equals file size
-        let range = parse_range_header("bytes=10000-", 10000);
-        assert_eq!(range, None);
+        // Start equals file size: valid syntax but unsatisfiable
+        let range = evaluate_range_header("bytes=10000-", 10000);
+        assert_eq!(range, RangeRequest::Unsatisfiable);
 
-        // Start > end
-        let range = parse_range_header("bytes=5000-1000", 10000);
-        assert_eq!(range, None);
+        // Zero-length suffix is unsatisfiable
+        let range = evaluate_range_header("bytes=-0", 10000);
+        assert_eq!(range, RangeRequest::Unsatisfiable);
+
+        // Start > end is invalid syntax, the header is ignored
+        let range = evaluate_range_header("bytes=5000-1000", 10000);
+        assert_eq!(range, RangeRequest::Full);
 
         // Invalid format
-        let range = parse_range_header("bytes=abc-def", 10000);
-        assert_eq!(range, None);
+        let range = evaluate_range_header("bytes=abc-def", 10000);
+        assert_eq!(range, RangeRequest::Full);
+        let range = evaluate_range_header("bytes=+1-2", 10000);
+        assert_eq!(range, RangeRequest::Full);
 
         // Not bytes range
-        let range = parse_range_header("items=0-10", 10000);
-        assert_eq!(range, None);
+        let range = evaluate_range_header("items=0-10", 10000);
+        assert_eq!(range, RangeRequest::Full);
     }
 
     #[test]
     fn test_parse_range_small_file() {
         // Suffix larger than file
-        let range = parse_range_header("bytes=-5000", 1000);
-        assert_eq!(range, Some(0..1000));
+        let range = evaluate_range_header("bytes=-5000", 1000);
+        assert_eq!(range, single(0..1000));
 
         // Normal range on small file
-        let range = parse_range_header("bytes=0-499", 1000);
-        assert_eq!(range, Some(0..500));
+        let range = evaluate_range_header("bytes=0-499", 1000);
+        assert_eq!(range, single(0..500));
+
+        // Nothing is satisfiable in an empty file
+        let range = evaluate_range_header("bytes=0-", 0);
+        assert_eq!(range, RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn test_parse_multiple_ranges() {
+        let range = evaluate_range_header("bytes=0-99, 200-299", 1000);
+        assert_eq!(range, partial(&[0..100, 200..300]));
+
+        // Unsatisfiable specs are dropped from the set
+        let range = evaluate_range_header("bytes=0-99,5000-6000", 1000);
+        assert_eq!(range, single(0..100));
+        let range = evaluate_range_header("bytes=5000-6000,7000-", 1000);
+        assert_eq!(range, RangeRequest::Unsatisfiable);
+
+        // Overlapping and adjacent ranges are merged and sorted
+        let range = evaluate_range_header("bytes=500-599,0-99,50-149,150-199", 1000);
+        assert_eq!(range, partial(&[0..200, 500..600]));
+
+        // One invalid spec invalidates the whole header
+        let range = evaluate_range_header("bytes=0-99,x-y", 1000);
+        assert_eq!(range, RangeRequest::Full);
+
+        // Too many ranges are ignored
+        let many = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+            .collect::<Vec<_>>()
+            .join(",");
+        let range = evaluate_range_header(&format!("bytes={}", many), 10000);
+        assert_eq!(range, RangeRequest::Full);
+    }
+
+    #[test]
+    fn test_unsatisfiable_headers() {
+        let headers = unsatisfiable_headers(10000);
+        assert_eq!(headers.get(header::CONTENT_RANGE).unwrap(), "bytes */10000");
+    }
+
+    #[test]
+    fn test_content_range_size() {
+        assert_eq!(content_range_size("bytes 1024-2047/10000"), Some(10000));
+        assert_eq!(content_range_size("bytes */10000"), Some(10000));
+        assert_eq!(content_range_size("bytes 0-99/*"), None);
+        assert_eq!(content_range_size("0-99/100"), None);
+    }
+
+    #[tokio::test]
+    async fn test_multipart_range_response() {
+        let path = std::env::temp_dir().join(format!("range-{}", uuid::Uuid::new_v4()));
+        tokio::fs::write(&path, b"0123456789abcdefghij")
+            .await
+            .unwrap();
+        let file = tokio::fs::File::open(&path).await.unwrap();
+
+        let (status, headers, body) =
+            range_response(file, vec![0..3, 10..12], 20, "text/plain", 4).unwrap();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        let content_type = headers[header::CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        assert_eq!(
+            headers[header::CONTENT_LENGTH].to_str().unwrap(),
+            body.len().to_string()
+        );
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-2/20\r\n\r\n012\
+             \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 10-11/20\r\n\r\nab\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(body, expected.as_bytes());
     }
 
     #[test]
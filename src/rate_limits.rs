@@ -0,0 +1,374 @@
+/// Upstream rate limit tracking
+///
+/// Registries report their pull quota in `RateLimit-Limit` and
+/// `RateLimit-Remaining` headers (Docker Hub: `100;w=21600`, pulls per window
+/// of seconds). The values last seen are kept per upstream host and shown in
+/// `/healthz` and `/metrics`, so quota running low is visible before pulls
+/// fail. After a 429 the host is backed off for its `Retry-After` (or
+/// `rate_limit_backoff_secs` without one): requests to it fail at once with a
+/// 429 carrying the upstream's rate limit headers, instead of adding to the
+/// count the registry holds against the proxy.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::StatusCode;
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+
+use crate::clock::Clock;
+use crate::config::ClientConfig;
+use crate::egress::label_value;
+use crate::error::{ProxyError, ProxyResult};
+
+/// Rate limit state of one upstream host
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RateLimitStatus {
+    /// Requests allowed per window, as last reported
+    pub limit: Option<u64>,
+    /// Requests left in the current window, as last reported
+    pub remaining: Option<u64>,
+    pub window_secs: Option<u64>,
+    /// 429 responses received
+    pub rate_limited: u64,
+    /// Seconds until requests are sent again, while backing off
+    pub backoff_secs: Option<u64>,
+}
+
+#[derive(Default)]
+struct HostState {
+    limit: Option<u64>,
+    remaining: Option<u64>,
+    window_secs: Option<u64>,
+    rate_limited: u64,
+    backoff_until: Option<Instant>,
+    /// Rate limit headers of the last 429, repeated to clients while backing off
+    headers: Vec<(String, String)>,
+}
+
+/// Longest Retry-After honored; larger values back off for this long
+const MAX_RETRY_AFTER_SECS: u64 = 24 * 60 * 60;
+
+pub struct RateLimits {
+    /// None when 429s are passed on without backing off
+    backoff: Option<Duration>,
+    hosts: Mutex<BTreeMap<String, HostState>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimits {
+    pub fn new(config: &ClientConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            backoff: (config.rate_limit_backoff_secs > 0)
+                .then(|| Duration::from_secs(config.rate_limit_backoff_secs)),
+            hosts: Mutex::new(BTreeMap::new()),
+            clock,
+        }
+    }
+
+    /// Fail while `host` is backed off after a 429
+    pub fn check(&self, host: &str) -> ProxyResult<()> {
+        let hosts = self.lock();
+        let Some(state) = hosts.get(host) else {
+            return Ok(());
+        };
+        match remaining_secs(state.backoff_until, self.clock.instant()) {
+            Some(retry_after_secs) => Err(ProxyError::RateLimited {
+                host: host.to_string(),
+                retry_after_secs,
+                headers: state.headers.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Record the rate limit headers of a response from `host`; a 429 is
+    /// turned into an error and starts the backoff
+    pub fn observe(&self, host: &str, response: &reqwest::Response) -> ProxyResult<()> {
+        let headers = response.headers();
+        let limit = header_quota(headers, "ratelimit-limit");
+        let remaining = header_quota(headers, "ratelimit-remaining");
+        let limited = response.status() == StatusCode::TOO_MANY_REQUESTS;
+        if limit.is_none() && remaining.is_none() && !limited {
+            return Ok(());
+        }
+
+        let mut hosts = self.lock();
+        let state = hosts.entry(host.to_string()).or_default();
+        if let Some((limit, window)) = limit {
+            state.limit = Some(limit);
+            state.window_secs = window.or(state.window_secs);
+        }
+        if let Some((remaining, window)) = remaining {
+            state.remaining = Some(remaining);
+            state.window_secs = window.or(state.window_secs);
+        }
+        if !limited {
+            return Ok(());
+        }
+
+        state.rate_limited += 1;
+        state.headers = forwarded_headers(headers);
+        let retry_after = headers
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(|secs| Duration::from_secs(secs.min(MAX_RETRY_AFTER_SECS)));
+        let retry_after = retry_after.or(self.backoff).unwrap_or_default();
+        if self.backoff.is_some() {
+            state.backoff_until = Some(self.clock.instant() + retry_after);
+        }
+        let retry_after_secs = retry_after.as_secs();
+        tracing::warn!(
+            upstream = %host,
+            retry_after_secs,
+            "Upstream rate limit reached"
+        );
+        Err(ProxyError::RateLimited {
+            host: host.to_string(),
+            retry_after_secs,
+            headers: state.headers.clone(),
+        })
+    }
+
+    /// Rate limit state of every host that reported one
+    pub fn snapshot(&self) -> BTreeMap<String, RateLimitStatus> {
+        let now = self.clock.instant();
+        self.lock()
+            .iter()
+            .map(|(host, state)| {
+                let status = RateLimitStatus {
+                    limit: state.limit,
+                    remaining: state.remaining,
+                    window_secs: state.window_secs,
+                    rate_limited: state.rate_limited,
+                    backoff_secs: remaining_secs(state.backoff_until, now),
+                };
+                (host.clone(), status)
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, HostState>> {
+        self.hosts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The upstream's rate limit headers, passed on to clients
+pub fn forwarded_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            name.starts_with("ratelimit-")
+                || name.starts_with("x-ratelimit-")
+                || name.starts_with("docker-ratelimit-")
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+// Whole seconds left until `until`, rounded up; None once it has passed
+fn remaining_secs(until: Option<Instant>, now: Instant) -> Option<u64> {
+    let left = until?.checked_duration_since(now)?;
+    (!left.is_zero()).then(|| left.as_secs() + u64::from(left.subsec_nanos() > 0))
+}
+
+// A quota header such as "100;w=21600": the count and, if given, the window
+fn header_quota(headers: &HeaderMap, name: &str) -> Option<(u64, Option<u64>)> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let mut parts = value.split(';');
+    let count = parts.next()?.trim().parse().ok()?;
+    let window = parts
+        .filter_map(|part| part.trim().strip_prefix("w="))
+        .find_map(|w| w.parse().ok());
+    Some((count, window))
+}
+
+/// Render rate limit gauges in the Prometheus text exposition format
+pub fn to_prometheus(hosts: &BTreeMap<String, RateLimitStatus>) -> String {
+    let mut metrics = String::from(
+        "# HELP docker_proxy_upstream_rate_limit Requests allowed per window, as reported by the upstream\n\
+         # TYPE docker_proxy_upstream_rate_limit gauge\n",
+    );
+    for (host, status) in hosts {
+        if let Some(limit) = status.limit {
+            metrics.push_str(&format!(
+                "docker_proxy_upstream_rate_limit{{registry=\"{}\"}} {}\n",
+                label_value(host),
+                limit
+            ));
+        }
+    }
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_rate_limit_remaining Requests left in the current window, as reported by the upstream\n\
+         # TYPE docker_proxy_upstream_rate_limit_remaining gauge\n",
+    );
+    for (host, status) in hosts {
+        if let Some(remaining) = status.remaining {
+            metrics.push_str(&format!(
+                "docker_proxy_upstream_rate_limit_remaining{{registry=\"{}\"}} {}\n",
+                label_value(host),
+                remaining
+            ));
+        }
+    }
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_rate_limited_total 429 responses received from the upstream\n\
+         # TYPE docker_proxy_upstream_rate_limited_total counter\n",
+    );
+    for (host, status) in hosts {
+        metrics.push_str(&format!(
+            "docker_proxy_upstream_rate_limited_total{{registry=\"{}\"}} {}\n",
+            label_value(host),
+            status.rate_limited
+        ));
+    }
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_rate_limit_backoff_seconds Seconds until requests are sent to the upstream again\n\
+         # TYPE docker_proxy_upstream_rate_limit_backoff_seconds gauge\n",
+    );
+    for (host, status) in hosts {
+        metrics.push_str(&format!(
+            "docker_proxy_upstream_rate_limit_backoff_seconds{{registry=\"{}\"}} {}\n",
+            label_value(host),
+            status.backoff_secs.unwrap_or_default()
+        ));
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn response(status: u16, headers: &[(&str, &str)]) -> reqwest::Response {
+        let mut builder = axum::http::Response::builder().status(status);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        reqwest::Response::from(builder.body(Vec::new()).unwrap())
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let clock = ManualClock::new(1_000_000);
+        let limits = RateLimits::new(
+            &ClientConfig {
+                rate_limit_backoff_secs: 60,
+                ..ClientConfig::default()
+            },
+            clock.clone(),
+        );
+        let hub = "registry-1.docker.io";
+
+        // Responses without rate limit headers are not tracked
+        limits.observe("ghcr.io", &response(200, &[])).unwrap();
+        limits
+            .observe(
+                hub,
+                &response(
+                    200,
+                    &[
+                        ("ratelimit-limit", "100;w=21600"),
+                        ("ratelimit-remaining", "1;w=21600"),
+                    ],
+                ),
+            )
+            .unwrap();
+        let status = RateLimitStatus {
+            limit: Some(100),
+            remaining: Some(1),
+            window_secs: Some(21600),
+            ..RateLimitStatus::default()
+        };
+        assert_eq!(
+            limits.snapshot(),
+            BTreeMap::from([(hub.to_string(), status.clone())])
+        );
+
+        // A 429 backs the host off for its Retry-After
+        let error = limits
+            .observe(
+                hub,
+                &response(
+                    429,
+                    &[
+                        ("retry-after", "30"),
+                        ("ratelimit-remaining", "0;w=21600"),
+                        ("docker-ratelimit-source", "203.0.113.7"),
+                    ],
+                ),
+            )
+            .unwrap_err();
+        let ProxyError::RateLimited {
+            retry_after_secs,
+            headers,
+            ..
+        } = &error
+        else {
+            panic!("unexpected error {:?}", error);
+        };
+        assert_eq!(*retry_after_secs, 30);
+        assert!(headers.contains(&("ratelimit-remaining".to_string(), "0;w=21600".to_string())));
+        assert!(headers.contains(&(
+            "docker-ratelimit-source".to_string(),
+            "203.0.113.7".to_string()
+        )));
+        assert!(error.is_upstream_outage());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(limits.check("ghcr.io").is_ok());
+        match limits.check(hub) {
+            Err(ProxyError::RateLimited {
+                retry_after_secs, ..
+            }) => assert_eq!(retry_after_secs, 20),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            limits.snapshot()[hub],
+            RateLimitStatus {
+                remaining: Some(0),
+                rate_limited: 1,
+                backoff_secs: Some(20),
+                ..status
+            }
+        );
+        let rendered = to_prometheus(&limits.snapshot());
+        assert!(rendered.contains(
+            "docker_proxy_upstream_rate_limit_remaining{registry=\"registry-1.docker.io\"} 0\n"
+        ));
+        assert!(rendered.contains(
+            "docker_proxy_upstream_rate_limited_total{registry=\"registry-1.docker.io\"} 1\n"
+        ));
+
+        // Without Retry-After the configured backoff applies
+        clock.advance(Duration::from_secs(20));
+        assert!(limits.check(hub).is_ok());
+        limits.observe(hub, &response(429, &[])).unwrap_err();
+        clock.advance(Duration::from_secs(59));
+        assert!(limits.check(hub).is_err());
+        clock.advance(Duration::from_secs(1));
+        assert!(limits.check(hub).is_ok());
+
+        // An absurd Retry-After is capped rather than overflowing the clock
+        let error = limits
+            .observe(
+                hub,
+                &response(429, &[("retry-after", "18446744073709551615")]),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ProxyError::RateLimited {
+                retry_after_secs: MAX_RETRY_AFTER_SECS,
+                ..
+            }
+        ));
+        assert_eq!(
+            limits.snapshot()[hub].backoff_secs,
+            Some(MAX_RETRY_AFTER_SECS)
+        );
+    }
+}
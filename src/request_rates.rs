@@ -0,0 +1,136 @@
+/// Recent request rates for the web UI dashboard
+///
+/// Requests handled are counted in one-minute buckets covering the last
+/// hour, together with those that failed with a server error.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::clock::Clock;
+
+/// Minutes of history kept
+pub const WINDOW_MINUTES: u64 = 60;
+
+/// Requests in one minute
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct MinuteRate {
+    /// Unix seconds the minute starts at
+    pub minute: u64,
+    pub requests: u64,
+    pub server_errors: u64,
+}
+
+pub struct RequestRates {
+    clock: Arc<dyn Clock>,
+    /// Oldest first; minutes without requests are missing
+    minutes: Mutex<VecDeque<MinuteRate>>,
+}
+
+impl RequestRates {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            minutes: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Count a request handled now
+    pub fn record(&self, server_error: bool) {
+        let minute = self.current_minute();
+        let mut minutes = self.lock();
+        if minutes.back().is_none_or(|m| m.minute != minute) {
+            minutes.push_back(MinuteRate {
+                minute,
+                ..MinuteRate::default()
+            });
+        }
+        while minutes
+            .front()
+            .is_some_and(|m| m.minute + WINDOW_MINUTES * 60 <= minute)
+        {
+            minutes.pop_front();
+        }
+        if let Some(rate) = minutes.back_mut() {
+            rate.requests += 1;
+            rate.server_errors += u64::from(server_error);
+        }
+    }
+
+    /// Every minute of the window, oldest first, the last being the
+    /// current (partial) one
+    pub fn per_minute(&self) -> Vec<MinuteRate> {
+        let current = self.current_minute();
+        let first = current.saturating_sub((WINDOW_MINUTES - 1) * 60);
+        let minutes = self.lock();
+        (first..=current)
+            .step_by(60)
+            .map(|minute| {
+                minutes
+                    .iter()
+                    .find(|m| m.minute == minute)
+                    .cloned()
+                    .unwrap_or(MinuteRate {
+                        minute,
+                        ..MinuteRate::default()
+                    })
+            })
+            .collect()
+    }
+
+    fn current_minute(&self) -> u64 {
+        let now = self.clock.now_secs();
+        now - now % 60
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<MinuteRate>> {
+        self.minutes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_per_minute() {
+        let clock = ManualClock::new(1_000_030);
+        let rates = RequestRates::new(clock.clone());
+        rates.record(false);
+        rates.record(true);
+        clock.advance(Duration::from_secs(120));
+        rates.record(false);
+
+        let minutes = rates.per_minute();
+        assert_eq!(minutes.len(), WINDOW_MINUTES as usize);
+        assert_eq!(
+            minutes[WINDOW_MINUTES as usize - 1],
+            MinuteRate {
+                minute: 1_000_140,
+                requests: 1,
+                server_errors: 0,
+            }
+        );
+        assert_eq!(minutes[WINDOW_MINUTES as usize - 2].requests, 0);
+        assert_eq!(
+            minutes[WINDOW_MINUTES as usize - 3],
+            MinuteRate {
+                minute: 1_000_020,
+                requests: 2,
+                server_errors: 1,
+            }
+        );
+
+        // Minutes past the window are dropped
+        clock.advance(Duration::from_secs(WINDOW_MINUTES * 60));
+        rates.record(false);
+        assert_eq!(rates.lock().len(), 1);
+        assert_eq!(
+            rates.per_minute().iter().map(|m| m.requests).sum::<u64>(),
+            1
+        );
+    }
+}
@@ -0,0 +1,156 @@
+/// Name resolution for upstream clients
+///
+/// Every new upstream connection needs an address, and on some networks the
+/// system resolver is slow or flaky enough to dominate pull latency. Looked
+/// up addresses are therefore reused for `[resolve] cache_ttl_secs`, and
+/// hosts listed under `[resolve.hosts]` are pinned to static addresses
+/// without asking DNS at all.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::clock::Clock;
+use crate::config::ResolveConfig;
+
+/// Caching resolver with static overrides, shared by all upstream clients
+pub struct DnsResolver {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    overrides: HashMap<String, Vec<SocketAddr>>,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    cache: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>,
+}
+
+impl DnsResolver {
+    pub fn new(config: &ResolveConfig, clock: Arc<dyn Clock>) -> Self {
+        // Port 0 is replaced by the port of the URL being connected to
+        let overrides = config
+            .hosts
+            .iter()
+            .map(|(host, addresses)| {
+                let addresses = addresses
+                    .iter()
+                    .filter_map(|a| a.parse::<IpAddr>().ok())
+                    .map(|ip| SocketAddr::new(ip, 0))
+                    .collect();
+                (host.to_ascii_lowercase(), addresses)
+            })
+            .collect();
+        Self {
+            inner: Arc::new(Inner {
+                overrides,
+                ttl: Duration::from_secs(config.cache_ttl_secs),
+                clock,
+                cache: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+impl Inner {
+    // Static or cached addresses of `host`, if any
+    fn known(&self, host: &str) -> Option<Vec<SocketAddr>> {
+        if let Some(addresses) = self.overrides.get(host) {
+            return Some(addresses.clone());
+        }
+        let now = self.clock.instant();
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        match cache.get(host) {
+            Some((expires, addresses)) if *expires > now => Some(addresses.clone()),
+            Some(_) => {
+                cache.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn remember(&self, host: &str, addresses: &[SocketAddr]) {
+        if self.ttl.is_zero() || addresses.is_empty() {
+            return;
+        }
+        let now = self.clock.instant();
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.retain(|_, (expires, _)| *expires > now);
+        cache.insert(host.to_string(), (now + self.ttl, addresses.to_vec()));
+    }
+}
+
+impl Resolve for DnsResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let inner = Arc::clone(&self.inner);
+        Box::pin(async move {
+            let host = name.as_str().to_ascii_lowercase();
+            if let Some(addresses) = inner.known(&host) {
+                return Ok(Box::new(addresses.into_iter()) as Addrs);
+            }
+            let addresses: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            tracing::debug!(host = %host, addresses = addresses.len(), "Resolved upstream host");
+            inner.remember(&host, &addresses);
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    async fn resolve(resolver: &DnsResolver, host: &str) -> Vec<SocketAddr> {
+        resolver
+            .resolve(Name::from_str(host).unwrap())
+            .await
+            .unwrap()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_dns_resolver() {
+        let clock = ManualClock::new(1_700_000_000);
+        let config = ResolveConfig {
+            cache_ttl_secs: 60,
+            hosts: HashMap::from([(
+                "Registry-1.Docker.io".to_string(),
+                vec!["203.0.113.7".to_string(), "2001:db8::7".to_string()],
+            )]),
+        };
+        let resolver = DnsResolver::new(&config, clock.clone());
+
+        // Overrides never reach DNS and match case-insensitively
+        let pinned = resolve(&resolver, "registry-1.docker.io").await;
+        assert_eq!(
+            pinned,
+            vec![
+                "203.0.113.7:0".parse().unwrap(),
+                "[2001:db8::7]:0".parse().unwrap()
+            ]
+        );
+
+        let addresses = resolve(&resolver, "localhost").await;
+        assert!(!addresses.is_empty());
+        assert_eq!(resolver.inner.known("localhost"), Some(addresses));
+
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(resolver.inner.known("localhost"), None);
+
+        let uncached = DnsResolver::new(
+            &ResolveConfig {
+                cache_ttl_secs: 0,
+                ..ResolveConfig::default()
+            },
+            clock,
+        );
+        resolve(&uncached, "localhost").await;
+        assert_eq!(uncached.inner.known("localhost"), None);
+    }
+}
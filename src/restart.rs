@@ -0,0 +1,192 @@
+/// Warm restart by handing the listening socket to a new process
+///
+/// On SIGUSR2 the running process starts its executable again (which may
+/// have been replaced by an upgrade), passing the listener's file descriptor
+/// and one end of a socket pair through the environment. The new process
+/// takes over the listener, finishes initialising and reports ready; only
+/// then does the old one stop accepting and drain its open connections.
+/// Connections queued on the socket in between are accepted by the new
+/// process, so none are refused. If the new process fails to come up, the
+/// old one keeps serving.
+///
+/// The old process exits once drained, so this is meant for bare-metal
+/// installs; in a container the proxy is PID 1 and the container would stop.
+///
+/// The listener may also come from systemd socket activation (`LISTEN_FDS`)
+/// or be inherited as `[server] listen_fd`; systemd then holds the socket
+/// while the service restarts, so connections queue instead of being
+/// refused. Readiness is reported to systemd (`Type=notify`) as well.
+use std::io;
+
+use tokio::net::TcpListener;
+
+/// Listener file descriptor inherited from the process being replaced
+const LISTEN_FD_ENV: &str = "DOCKER_PROXY_LISTEN_FD";
+/// Socket the new process reports readiness on
+const READY_FD_ENV: &str = "DOCKER_PROXY_READY_FD";
+/// First descriptor passed by systemd socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::fd::RawFd = 3;
+/// How long the new process may take to load its config and cache index
+#[cfg(unix)]
+const READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Bind `addr`, or take over the listener passed by the process being
+/// replaced, by systemd or as `listen_fd`, in that order
+pub async fn bind(addr: &str, listen_fd: Option<i32>) -> io::Result<TcpListener> {
+    #[cfg(unix)]
+    {
+        let inherited = inherited_fd(LISTEN_FD_ENV)
+            .map(|fd| (fd, "previous process"))
+            .or_else(|| systemd_fd().map(|fd| (fd, "systemd")))
+            .or_else(|| listen_fd.map(|fd| (fd, "listen_fd")));
+        if let Some((fd, from)) = inherited {
+            use std::os::fd::FromRawFd;
+
+            tracing::info!(fd, "Taking over listener from {}", from);
+            // Child processes such as credential helpers must not keep it open
+            // SAFETY: fcntl only changes the flags of the descriptor
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the descriptor was passed for exactly this purpose and
+            // nothing else in this process owns it
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            return TcpListener::from_std(listener);
+        }
+    }
+    #[cfg(not(unix))]
+    if listen_fd.is_some() {
+        tracing::warn!("listen_fd is only supported on Unix, binding {}", addr);
+    }
+    TcpListener::bind(addr).await
+}
+
+/// Tell the process being replaced, if any, and systemd that this one is
+/// serving
+pub fn notify_ready() {
+    #[cfg(unix)]
+    if let Err(e) = notify_systemd() {
+        tracing::warn!("Failed to report readiness to systemd: {}", e);
+    }
+    #[cfg(unix)]
+    if let Some(fd) = inherited_fd(READY_FD_ENV) {
+        use std::io::Write;
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: as for the listener, the descriptor was passed for this
+        let mut ready = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+        if let Err(e) = ready.write_all(b"1") {
+            tracing::warn!("Failed to report readiness to previous process: {}", e);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inherited_fd(var: &str) -> Option<std::os::fd::RawFd> {
+    std::env::var(var).ok()?.parse().ok()
+}
+
+// The socket passed by systemd socket activation; the variables are meant
+// for this process only, not for a warm restart successor
+#[cfg(unix)]
+fn systemd_fd() -> Option<std::os::fd::RawFd> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let count: i32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != std::process::id() || count < 1 {
+        return None;
+    }
+    if count > 1 {
+        tracing::warn!("systemd passed {} sockets, using the first", count);
+    }
+    Some(SD_LISTEN_FDS_START)
+}
+
+// sd_notify: READY=1 on $NOTIFY_SOCKET, with the main PID so that systemd
+// follows a warm restart successor (requires NotifyAccess=all)
+#[cfg(unix)]
+fn notify_systemd() -> io::Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let message = format!("READY=1\nMAINPID={}", std::process::id());
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    // A leading @ names a socket in the abstract namespace
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(message.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(message.as_bytes(), path)?;
+    Ok(())
+}
+
+/// Start the current executable again with the listener `listen_fd` and wait
+/// until it reports ready
+#[cfg(unix)]
+pub async fn spawn_successor(listen_fd: std::os::fd::RawFd) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+    use tokio::io::AsyncReadExt;
+
+    let (ready, child_ready) = std::os::unix::net::UnixStream::pair()?;
+    let ready_fd = child_ready.as_raw_fd();
+
+    let mut command = std::process::Command::new(current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FD_ENV, listen_fd.to_string())
+        .env(READY_FD_ENV, ready_fd.to_string());
+    // SAFETY: fcntl is async-signal-safe; it only clears close-on-exec on
+    // the two descriptors the new process inherits
+    unsafe {
+        command.pre_exec(move || {
+            for fd in [listen_fd, ready_fd] {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    // Only the new process holds the other end now, so its exit shows as EOF
+    drop(child_ready);
+    tracing::info!(
+        pid = child.id(),
+        "Started new process, waiting for it to be ready"
+    );
+
+    ready.set_nonblocking(true)?;
+    let mut ready = tokio::net::UnixStream::from_std(ready)?;
+    let mut byte = [0u8; 1];
+    let result = match tokio::time::timeout(READY_TIMEOUT, ready.read(&mut byte)).await {
+        Ok(Ok(1)) => Ok(()),
+        Ok(Ok(_)) => Err(io::Error::other("new process exited before becoming ready")),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "new process did not become ready in time",
+        )),
+    };
+    if result.is_err() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    result
+}
+
+// The running executable; after an upgrade replaced it on disk, Linux
+// reports the old inode's path with a " (deleted)" suffix
+#[cfg(unix)]
+fn current_exe() -> io::Result<std::path::PathBuf> {
+    let exe = std::env::current_exe()?;
+    match exe.to_str().and_then(|s| s.strip_suffix(" (deleted)")) {
+        Some(path) => Ok(path.into()),
+        None => Ok(exe),
+    }
+}
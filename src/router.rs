@@ -1,59 +1,325 @@
+use std::collections::HashMap;
+
+use axum::http::{HeaderName, Method, header};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+
+use crate::config::{RegistryOptions, registry_host_url};
+
 /// Docker Registry V2 API endpoint types
 #[derive(Debug, PartialEq)]
 pub enum V2Endpoint {
-    /// GET/HEAD manifest: /v2/{name}/manifests/{reference}
+    /// GET/HEAD/PUT/DELETE manifest: /v2/{name}/manifests/{reference}
     Manifest { name: String, reference: String },
-    /// GET/HEAD blob: /v2/{name}/blobs/{digest}
+    /// GET/HEAD/DELETE blob: /v2/{name}/blobs/{digest}
     Blob { name: String, digest: String },
     /// POST blob upload: /v2/{name}/blobs/uploads/
     BlobUploadInit { name: String },
     /// PUT blob upload: /v2/{name}/blobs/uploads/{uuid}
     BlobUploadComplete { name: String, uuid: String },
+    /// PATCH blob upload chunk: /v2/{name}/blobs/uploads/{uuid}
+    BlobUploadChunk { name: String, uuid: String },
+    /// GET blob upload status: /v2/{name}/blobs/uploads/{uuid}
+    BlobUploadStatus { name: String, uuid: String },
+    /// GET tag list: /v2/{name}/tags/list
+    TagList { name: String },
+    /// GET repository catalog: /v2/_catalog
+    Catalog,
+    /// GET content trust metadata: /v2/{gun}/_trust/tuf/{file}, where the
+    /// file may name a delegated role, e.g. `targets/releases.json`
+    TrustMetadata { gun: String, file: String },
     /// Unknown or unsupported endpoint
     Unknown,
 }
 
+impl V2Endpoint {
+    /// Repository the endpoint belongs to; `None` for the catalog and
+    /// unknown endpoints
+    pub fn repository(&self) -> Option<&str> {
+        match self {
+            V2Endpoint::Manifest { name, .. }
+            | V2Endpoint::Blob { name, .. }
+            | V2Endpoint::BlobUploadInit { name }
+            | V2Endpoint::BlobUploadComplete { name, .. }
+            | V2Endpoint::BlobUploadChunk { name, .. }
+            | V2Endpoint::BlobUploadStatus { name, .. }
+            | V2Endpoint::TagList { name }
+            | V2Endpoint::TrustMetadata { gun: name, .. } => Some(name),
+            V2Endpoint::Catalog | V2Endpoint::Unknown => None,
+        }
+    }
+}
+
+/// Characters that must be escaped inside a single URL path segment
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
 /// Parse Docker Registry V2 API path
 ///
+/// The endpoint is identified from the end of the path, so repository names
+/// may contain any number of segments (e.g. GitLab-style
+/// `group/subgroup/project/image`), including segments such as `blobs` or
+/// `manifests`.
+///
 /// # Arguments
 /// * `rest` - The path after /v2/, e.g. "library/ubuntu/manifests/latest"
 ///
 /// # Returns
 /// The parsed endpoint type with extracted parameters
 pub fn parse_v2_path(rest: &str) -> V2Endpoint {
+    // Clients send the upload init request as ".../blobs/uploads/"
+    let rest = rest.strip_suffix('/').unwrap_or(rest);
+    if rest == "_catalog" {
+        return V2Endpoint::Catalog;
+    }
+    if let Some((gun, file)) = rest.split_once("/_trust/tuf/")
+        && let Some(gun) = repository_name(&gun.split('/').collect::<Vec<_>>())
+        && let Some(file) = repository_name(&file.split('/').collect::<Vec<_>>())
+    {
+        return V2Endpoint::TrustMetadata { gun, file };
+    }
     let parts: Vec<&str> = rest.split('/').collect();
+    let n = parts.len();
+
+    // Blob upload init: .../blobs/uploads
+    if n >= 3
+        && parts[n - 2] == "blobs"
+        && parts[n - 1] == "uploads"
+        && let Some(name) = repository_name(&parts[..n - 2])
+    {
+        return V2Endpoint::BlobUploadInit { name };
+    }
 
-    // Check for manifests endpoint: .../manifests/{reference}
-    if let Some(i) = parts.iter().position(|&p| p == "manifests")
-        && i + 1 < parts.len()
+    // Blob upload complete: .../blobs/uploads/{uuid}
+    if n >= 4
+        && parts[n - 3] == "blobs"
+        && parts[n - 2] == "uploads"
+        && !parts[n - 1].is_empty()
+        && let Some(name) = repository_name(&parts[..n - 3])
     {
-        let name = parts[..i].join("/");
-        let reference = parts[i + 1].to_string();
-        return V2Endpoint::Manifest { name, reference };
-    }
-
-    // Check for blobs endpoint: .../blobs/{digest}
-    if let Some(i) = parts.iter().position(|&p| p == "blobs") {
-        // Blob upload complete: .../blobs/uploads/{uuid}
-        if i + 2 < parts.len() && parts[i + 1] == "uploads" {
-            let name = parts[..i].join("/");
-            let uuid = parts[i + 2].to_string();
-            return V2Endpoint::BlobUploadComplete { name, uuid };
+        let uuid = parts[n - 1].to_string();
+        return V2Endpoint::BlobUploadComplete { name, uuid };
+    }
+
+    if n >= 3 && !parts[n - 1].is_empty() {
+        let Some(name) = repository_name(&parts[..n - 2]) else {
+            return V2Endpoint::Unknown;
+        };
+        let last = parts[n - 1].to_string();
+        match parts[n - 2] {
+            // Manifest: .../manifests/{reference}
+            "manifests" => {
+                return V2Endpoint::Manifest {
+                    name,
+                    reference: last,
+                };
+            }
+            // Regular blob access: .../blobs/{digest}
+            "blobs" => return V2Endpoint::Blob { name, digest: last },
+            // Tag list: .../tags/list
+            "tags" if last == "list" => return V2Endpoint::TagList { name },
+            _ => {}
         }
-        // Blob upload init: .../blobs/uploads/
-        if i + 1 < parts.len() && parts[i + 1] == "uploads" && i + 2 == parts.len() {
-            let name = parts[..i].join("/");
-            return V2Endpoint::BlobUploadInit { name };
+    }
+
+    V2Endpoint::Unknown
+}
+
+/// Parse a V2 API path for a specific HTTP method
+///
+/// Upload session URLs are shared by GET (query progress), PATCH (append a
+/// chunk) and PUT (complete the upload), so the method decides which endpoint
+/// is meant.
+pub fn parse_v2_request(method: &Method, rest: &str) -> V2Endpoint {
+    match parse_v2_path(rest) {
+        V2Endpoint::BlobUploadComplete { name, uuid } if method == Method::PATCH => {
+            V2Endpoint::BlobUploadChunk { name, uuid }
         }
-        // Regular blob access: .../blobs/{digest}
-        if i + 1 < parts.len() {
-            let name = parts[..i].join("/");
-            let digest = parts[i + 1].to_string();
-            return V2Endpoint::Blob { name, digest };
+        V2Endpoint::BlobUploadComplete { name, uuid } if method == Method::GET => {
+            V2Endpoint::BlobUploadStatus { name, uuid }
         }
+        endpoint => endpoint,
     }
+}
 
-    V2Endpoint::Unknown
+/// Upstream registry URL and repository for a client-facing name. A first
+/// path segment that is a configured route prefix selects that route's
+/// registry, one that looks like a host (contains a dot or colon, or is
+/// `localhost`) selects that registry, over plain HTTP when it is configured
+/// as insecure; other names go to the default one.
+pub fn split_registry_and_name(
+    default_registry_url: &str,
+    routes: &HashMap<String, String>,
+    registries: &HashMap<String, RegistryOptions>,
+    name: &str,
+) -> (String, String) {
+    if let Some((first, rest)) = name.split_once('/') {
+        if let Some(registry_url) = routes.get(first) {
+            return (
+                registry_endpoint(registry_url.trim_end_matches('/')),
+                normalize_image_name(rest),
+            );
+        }
+        if first.contains('.') || first.contains(':') || first == "localhost" {
+            // Docker Hub names keep their `library/` default behind the host
+            if is_docker_hub_alias(first) || first == DOCKER_HUB_HOST {
+                return (
+                    format!("https://{}", DOCKER_HUB_HOST),
+                    normalize_image_name(rest),
+                );
+            }
+            return (registry_host_url(registries, first), rest.to_string());
+        }
+    }
+    (
+        registry_endpoint(default_registry_url),
+        normalize_image_name(name),
+    )
+}
+
+/// Host serving Docker Hub's registry API
+pub const DOCKER_HUB_HOST: &str = "registry-1.docker.io";
+
+/// Whether `host` is a Docker Hub alias that does not serve the registry API
+/// itself (`docker.io` only answers with redirects)
+pub fn is_docker_hub_alias(host: &str) -> bool {
+    ["docker.io", "index.docker.io", "registry.hub.docker.com"]
+        .iter()
+        .any(|alias| host.eq_ignore_ascii_case(alias))
+}
+
+/// The URL the registry API is served from: Docker Hub aliases become
+/// `https://registry-1.docker.io`, other URLs are returned unchanged
+pub fn registry_endpoint(registry_url: &str) -> String {
+    let host = registry_url
+        .strip_prefix("https://")
+        .or_else(|| registry_url.strip_prefix("http://"))
+        .unwrap_or(registry_url)
+        .trim_end_matches('/');
+    if is_docker_hub_alias(host) {
+        format!("https://{}", DOCKER_HUB_HOST)
+    } else {
+        registry_url.to_string()
+    }
+}
+
+/// Single-segment names are official images under `library/`
+pub fn normalize_image_name(name: &str) -> String {
+    if name.contains('/') {
+        name.to_string()
+    } else {
+        format!("library/{}", name)
+    }
+}
+
+/// Percent-decoded value of a query string parameter
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse("http://query/").ok()?;
+    url.set_query(Some(query));
+    url.query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// Split an image reference (`name`, `name:tag` or `name@digest`) into the
+/// repository name and reference, defaulting to `latest`
+pub fn parse_image_reference(image: &str) -> Option<(String, String)> {
+    let image = image.trim();
+    let (name, reference) = match image.split_once('@') {
+        Some((name, digest)) => (name, digest.to_string()),
+        None => match image.rsplit_once(':') {
+            // a colon before the last '/' belongs to a registry port
+            Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+            _ => (image, "latest".to_string()),
+        },
+    };
+    if reference.is_empty() {
+        return None;
+    }
+    match parse_v2_path(&format!("{}/manifests/{}", name, reference)) {
+        V2Endpoint::Manifest { name: parsed, .. } if parsed == name => {
+            Some((name.to_string(), reference))
+        }
+        _ => None,
+    }
+}
+
+/// Parse a chunk upload `Content-Range` header (`<start>-<end>`, inclusive)
+pub fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let value = value.trim();
+    // Some clients send the HTTP-style "bytes " prefix
+    let value = value.strip_prefix("bytes ").unwrap_or(value);
+    let (start, end) = value.split_once('-')?;
+    let start = start.trim().parse::<u64>().ok()?;
+    let end = end.trim().parse::<u64>().ok()?;
+    (start <= end).then_some((start, end))
+}
+
+/// Join repository name segments, rejecting empty and dot segments that
+/// would otherwise be collapsed when building the upstream URL
+fn repository_name(segments: &[&str]) -> Option<String> {
+    if segments.is_empty()
+        || segments
+            .iter()
+            .any(|s| s.is_empty() || *s == "." || *s == "..")
+    {
+        return None;
+    }
+    Some(segments.join("/"))
+}
+
+/// Percent-encode a single path segment (tag, digest, upload UUID)
+pub fn encode_path_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT).to_string()
+}
+
+/// Percent-encode a repository name segment by segment, keeping the `/` separators
+pub fn encode_repository_path(name: &str) -> String {
+    name.split('/')
+        .map(encode_path_segment)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Add the service's path prefix to root-relative URLs in a response header:
+/// a `Location`, the targets of a `Link` and the realm of a `WWW-Authenticate`
+/// challenge. Returns `None` when the value has nothing to rewrite.
+pub fn mount_header_value(prefix: &str, name: &HeaderName, value: &str) -> Option<String> {
+    let root_relative = |rest: &str| rest.starts_with('/') && !rest.starts_with("//");
+    if name == header::LOCATION {
+        root_relative(value).then(|| format!("{}{}", prefix, value))
+    } else {
+        let marker = if name == header::LINK {
+            "<"
+        } else if name == header::WWW_AUTHENTICATE {
+            "realm=\""
+        } else {
+            return None;
+        };
+        let mut rewritten = String::with_capacity(value.len() + prefix.len());
+        let mut changed = false;
+        let mut rest = value;
+        while let Some(pos) = rest.find(marker) {
+            let (head, tail) = rest.split_at(pos + marker.len());
+            rewritten.push_str(head);
+            if root_relative(tail) {
+                rewritten.push_str(prefix);
+                changed = true;
+            }
+            rest = tail;
+        }
+        rewritten.push_str(rest);
+        changed.then_some(rewritten)
+    }
 }
 
 #[cfg(test)]
@@ -169,4 +435,323 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_parse_blob_upload_init_trailing_slash() {
+        // docker push posts to ".../blobs/uploads/"
+        let endpoint = parse_v2_path("library/ubuntu/blobs/uploads/");
+        assert_eq!(
+            endpoint,
+            V2Endpoint::BlobUploadInit {
+                name: "library/ubuntu".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_deeply_nested_names() {
+        // GitLab-style group/subgroup/project/image names
+        let endpoint =
+            parse_v2_path("registry.gitlab.com/group/subgroup/project/image/manifests/v1");
+        assert_eq!(
+            endpoint,
+            V2Endpoint::Manifest {
+                name: "registry.gitlab.com/group/subgroup/project/image".to_string(),
+                reference: "v1".to_string()
+            }
+        );
+
+        let endpoint = parse_v2_path("group/a/b/c/d/e/blobs/sha256:0123");
+        assert_eq!(
+            endpoint,
+            V2Endpoint::Blob {
+                name: "group/a/b/c/d/e".to_string(),
+                digest: "sha256:0123".to_string()
+            }
+        );
+
+        let endpoint = parse_v2_path("group/subgroup/project/image/blobs/uploads/abc-123");
+        assert_eq!(
+            endpoint,
+            V2Endpoint::BlobUploadComplete {
+                name: "group/subgroup/project/image".to_string(),
+                uuid: "abc-123".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_names_containing_endpoint_keywords() {
+        // Only the trailing segments select the endpoint
+        let endpoint = parse_v2_path("group/blobs/project/manifests/latest");
+        assert_eq!(
+            endpoint,
+            V2Endpoint::Manifest {
+                name: "group/blobs/project".to_string(),
+                reference: "latest".to_string()
+            }
+        );
+
+        let endpoint = parse_v2_path("group/manifests/uploads/blobs/sha256:abcd");
+        assert_eq!(
+            endpoint,
+            V2Endpoint::Blob {
+                name: "group/manifests/uploads".to_string(),
+                digest: "sha256:abcd".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_dot_and_empty_segments() {
+        assert_eq!(
+            parse_v2_path("../../etc/manifests/latest"),
+            V2Endpoint::Unknown
+        );
+        assert_eq!(
+            parse_v2_path("library/./ubuntu/manifests/latest"),
+            V2Endpoint::Unknown
+        );
+        assert_eq!(
+            parse_v2_path("library//ubuntu/blobs/sha256:abcd"),
+            V2Endpoint::Unknown
+        );
+        assert_eq!(parse_v2_path("manifests/latest"), V2Endpoint::Unknown);
+    }
+
+    #[test]
+    fn test_encode_repository_path() {
+        assert_eq!(
+            encode_repository_path("group/subgroup/project/image"),
+            "group/subgroup/project/image"
+        );
+        // Decoded characters from the client request are re-encoded per segment
+        assert_eq!(
+            encode_repository_path("group/my image/100%"),
+            "group/my%20image/100%25"
+        );
+        assert_eq!(encode_path_segment("sha256:abcd"), "sha256:abcd");
+        assert_eq!(encode_path_segment("v1?x#y"), "v1%3Fx%23y");
+        assert_eq!(encode_path_segment("a/b"), "a%2Fb");
+    }
+
+    #[test]
+    fn test_parse_v2_request_upload_chunk() {
+        let path = "group/subgroup/image/blobs/uploads/550e8400-e29b-41d4-a716-446655440000";
+        assert_eq!(
+            parse_v2_request(&Method::PATCH, path),
+            V2Endpoint::BlobUploadChunk {
+                name: "group/subgroup/image".to_string(),
+                uuid: "550e8400-e29b-41d4-a716-446655440000".to_string()
+            }
+        );
+        assert_eq!(
+            parse_v2_request(&Method::PUT, path),
+            V2Endpoint::BlobUploadComplete {
+                name: "group/subgroup/image".to_string(),
+                uuid: "550e8400-e29b-41d4-a716-446655440000".to_string()
+            }
+        );
+        assert_eq!(
+            parse_v2_request(&Method::GET, path),
+            V2Endpoint::BlobUploadStatus {
+                name: "group/subgroup/image".to_string(),
+                uuid: "550e8400-e29b-41d4-a716-446655440000".to_string()
+            }
+        );
+        // Other endpoints are not affected by the method
+        assert_eq!(
+            parse_v2_request(&Method::PATCH, "library/ubuntu/manifests/latest"),
+            parse_v2_path("library/ubuntu/manifests/latest")
+        );
+    }
+
+    #[test]
+    fn test_parse_v2_request_delete() {
+        assert_eq!(
+            parse_v2_request(&Method::DELETE, "ghcr.io/owner/repo/manifests/sha256:abc"),
+            V2Endpoint::Manifest {
+                name: "ghcr.io/owner/repo".to_string(),
+                reference: "sha256:abc".to_string()
+            }
+        );
+        assert_eq!(
+            parse_v2_request(&Method::DELETE, "library/ubuntu/blobs/sha256:def"),
+            V2Endpoint::Blob {
+                name: "library/ubuntu".to_string(),
+                digest: "sha256:def".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(
+            query_param("digest=sha256%3Aabc&x=1", "digest"),
+            Some("sha256:abc".to_string())
+        );
+        assert_eq!(
+            query_param("mount=sha256:abc&from=a/b", "from"),
+            Some("a/b".to_string())
+        );
+        assert_eq!(query_param("x=1", "digest"), None);
+        assert_eq!(query_param("", "digest"), None);
+    }
+
+    #[test]
+    fn test_parse_tag_list() {
+        assert_eq!(
+            parse_v2_path("library/nginx/tags/list"),
+            V2Endpoint::TagList {
+                name: "library/nginx".to_string()
+            }
+        );
+        assert_eq!(
+            parse_v2_path("owner/blobs/app/tags/list"),
+            V2Endpoint::TagList {
+                name: "owner/blobs/app".to_string()
+            }
+        );
+        // a tag literally named "list" is still a manifest reference
+        assert_eq!(
+            parse_v2_path("app/manifests/list"),
+            V2Endpoint::Manifest {
+                name: "app".to_string(),
+                reference: "list".to_string()
+            }
+        );
+        assert_eq!(parse_v2_path("tags/list"), V2Endpoint::Unknown);
+        assert_eq!(parse_v2_path("_catalog"), V2Endpoint::Catalog);
+    }
+
+    #[test]
+    fn test_parse_trust_metadata() {
+        assert_eq!(
+            parse_v2_path("docker.io/library/nginx/_trust/tuf/root.json"),
+            V2Endpoint::TrustMetadata {
+                gun: "docker.io/library/nginx".to_string(),
+                file: "root.json".to_string()
+            }
+        );
+        assert_eq!(
+            parse_v2_path("app/_trust/tuf/targets/releases.json"),
+            V2Endpoint::TrustMetadata {
+                gun: "app".to_string(),
+                file: "targets/releases.json".to_string()
+            }
+        );
+        assert_eq!(parse_v2_path("_trust/tuf/root.json"), V2Endpoint::Unknown);
+        assert_eq!(
+            parse_v2_path("app/_trust/tuf/../root.json"),
+            V2Endpoint::Unknown
+        );
+    }
+
+    #[test]
+    fn test_parse_image_reference() {
+        let parse = |image| parse_image_reference(image);
+        assert_eq!(
+            parse("library/nginx"),
+            Some(("library/nginx".to_string(), "latest".to_string()))
+        );
+        assert_eq!(
+            parse("ghcr.io/owner/app:v1.2"),
+            Some(("ghcr.io/owner/app".to_string(), "v1.2".to_string()))
+        );
+        assert_eq!(
+            parse("localhost:5000/app"),
+            Some(("localhost:5000/app".to_string(), "latest".to_string()))
+        );
+        assert_eq!(
+            parse("app@sha256:abc"),
+            Some(("app".to_string(), "sha256:abc".to_string()))
+        );
+        assert_eq!(parse("app:"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range("0-1023"), Some((0, 1023)));
+        assert_eq!(parse_content_range("1024-2047"), Some((1024, 2047)));
+        assert_eq!(parse_content_range("bytes 0-99"), Some((0, 99)));
+        assert_eq!(parse_content_range("10-5"), None);
+        assert_eq!(parse_content_range("0-"), None);
+        assert_eq!(parse_content_range("bytes=0-10"), None);
+        assert_eq!(parse_content_range("abc"), None);
+    }
+
+    #[test]
+    fn test_mount_header_value() {
+        let prefix = "/registry-proxy";
+        assert_eq!(
+            mount_header_value(
+                prefix,
+                &header::LOCATION,
+                "/v2/library/nginx/blobs/uploads/1"
+            )
+            .as_deref(),
+            Some("/registry-proxy/v2/library/nginx/blobs/uploads/1")
+        );
+        // Absolute and protocol-relative URLs point elsewhere
+        assert_eq!(
+            mount_header_value(prefix, &header::LOCATION, "https://storage.example/blob"),
+            None
+        );
+        assert_eq!(
+            mount_header_value(prefix, &header::LOCATION, "//storage.example/blob"),
+            None
+        );
+        assert_eq!(
+            mount_header_value(
+                prefix,
+                &header::LINK,
+                r#"</v2/_catalog?n=2&last=b>; rel="next""#
+            )
+            .as_deref(),
+            Some(r#"</registry-proxy/v2/_catalog?n=2&last=b>; rel="next""#)
+        );
+        assert_eq!(
+            mount_header_value(
+                prefix,
+                &header::WWW_AUTHENTICATE,
+                r#"Bearer realm="/token",service="proxy""#
+            )
+            .as_deref(),
+            Some(r#"Bearer realm="/registry-proxy/token",service="proxy""#)
+        );
+        assert_eq!(
+            mount_header_value(
+                prefix,
+                &header::WWW_AUTHENTICATE,
+                r#"Bearer realm="https://auth.docker.io/token""#
+            ),
+            None
+        );
+        assert_eq!(
+            mount_header_value(prefix, &header::CONTENT_TYPE, "/v2/"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_registry_endpoint() {
+        assert_eq!(
+            registry_endpoint("https://docker.io"),
+            "https://registry-1.docker.io"
+        );
+        assert_eq!(
+            registry_endpoint("https://Index.Docker.IO/"),
+            "https://registry-1.docker.io"
+        );
+        assert_eq!(
+            registry_endpoint("https://registry-1.docker.io"),
+            "https://registry-1.docker.io"
+        );
+        assert_eq!(registry_endpoint("https://ghcr.io"), "https://ghcr.io");
+        assert_eq!(
+            registry_endpoint("http://docker.io.example.com"),
+            "http://docker.io.example.com"
+        );
+    }
 }
@@ -0,0 +1,197 @@
+/// Effective configuration, adjustable at runtime (`/admin/config`)
+///
+/// `GET /admin/config` shows the configuration the proxy runs with (the
+/// config file, fragments, environment overrides and profile applied), with
+/// credentials masked. `PATCH /admin/config` takes a JSON merge patch
+/// (RFC 7396) of it, limited to the options that can change without a
+/// restart: `[log] level`, `[cache] max_size_mb` and the `[quotas]` limits.
+/// Changes are not written to the config file, so they last until the next
+/// restart.
+use std::sync::Mutex;
+
+use serde_json::{Map, Value};
+use toml::Table;
+
+use crate::config::Config;
+use crate::secrets;
+
+/// Options a patch may change, as key paths
+const ADJUSTABLE: &[&[&str]] = &[
+    &["log", "level"],
+    &["cache", "max_size_mb"],
+    &["quotas", "default"],
+    &["quotas", "users"],
+];
+
+pub struct RuntimeConfig {
+    config: Mutex<Config>,
+}
+
+impl RuntimeConfig {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: Mutex::new(config.clone()),
+        }
+    }
+
+    /// The effective configuration with credentials masked
+    pub fn masked(&self) -> Result<Value, String> {
+        let config = self.lock().clone();
+        let mut table = Table::try_from(config).map_err(|e| e.to_string())?;
+        secrets::mask(&mut table);
+        serde_json::to_value(table).map_err(|e| e.to_string())
+    }
+
+    /// Merge `patch` into the configuration and hand the current and the
+    /// patched one to `apply`, which puts the change into effect; the
+    /// patched configuration is kept if it succeeds
+    pub fn update(
+        &self,
+        patch: &Value,
+        apply: impl FnOnce(&Config, &Config) -> Result<(), String>,
+    ) -> Result<(), String> {
+        if !patch.is_object() {
+            return Err("The patch must be a JSON object".to_string());
+        }
+        check_adjustable(patch, &mut Vec::new())?;
+        let mut config = self.lock();
+        let mut value = serde_json::to_value(&*config).map_err(|e| e.to_string())?;
+        merge_patch(&mut value, patch);
+        let patched: Config =
+            serde_json::from_value(value).map_err(|e| format!("Invalid configuration: {}", e))?;
+        patched.log.validate()?;
+        patched.cache.validate()?;
+        patched.quotas.validate()?;
+        apply(&config, &patched)?;
+        *config = patched;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Config> {
+        self.config.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Every option the patch sets must be adjustable or within one
+fn check_adjustable(patch: &Value, path: &mut Vec<String>) -> Result<(), String> {
+    let Value::Object(fields) = patch else {
+        return Err(format!("{} must be an object", path.join(".")));
+    };
+    for (key, value) in fields {
+        path.push(key.clone());
+        let within = |adjustable: &&[&str]| {
+            adjustable
+                .iter()
+                .zip(path.iter())
+                .all(|(a, b)| *a == b.as_str())
+        };
+        if !ADJUSTABLE
+            .iter()
+            .any(|adjustable| adjustable.len() <= path.len() && within(adjustable))
+        {
+            if !ADJUSTABLE.iter().any(within) {
+                return Err(format!("{} cannot be changed at runtime", path.join(".")));
+            }
+            check_adjustable(value, path)?;
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+// JSON merge patch: objects are merged key by key, null removes a key and
+// any other value replaces the one before
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(fields) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        return;
+    };
+    for (key, value) in fields {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_update() {
+        let config = Config::from_str(
+            r#"
+[log]
+level = "info"
+
+[auth]
+ghcr-token = "ghp_secret"
+
+[quotas.users.ci]
+daily_pulls = 10
+"#,
+        )
+        .unwrap();
+        let runtime = RuntimeConfig::new(&config);
+        let masked = runtime.masked().unwrap();
+        assert_eq!(masked["auth"]["ghcr-token"], secrets::MASK);
+        assert_eq!(masked["log"]["level"], "info");
+
+        let patch = json!({
+            "log": { "level": "debug" },
+            "quotas": { "default": { "daily_pulls": 100 }, "users": { "ci": null } },
+        });
+        runtime
+            .update(&patch, |current, patched| {
+                assert_eq!(current.log.level, "info");
+                assert_eq!(patched.quotas.default.daily_pulls, 100);
+                assert!(patched.quotas.users.is_empty());
+                Ok(())
+            })
+            .unwrap();
+        let masked = runtime.masked().unwrap();
+        assert_eq!(masked["log"]["level"], "debug");
+        assert_eq!(masked["quotas"]["default"]["daily_pulls"], 100);
+
+        // Only the adjustable options, with valid values
+        let unchanged = |_: &Config, _: &Config| -> Result<(), String> { unreachable!() };
+        let error = runtime
+            .update(&json!({ "log": { "logFilePath": "/tmp/x" } }), unchanged)
+            .unwrap_err();
+        assert_eq!(error, "log.logFilePath cannot be changed at runtime");
+        assert!(
+            runtime
+                .update(&json!({ "log": "debug" }), unchanged)
+                .is_err()
+        );
+        assert!(runtime.update(&json!([]), unchanged).is_err());
+        assert!(
+            runtime
+                .update(&json!({ "log": { "level": "loud" } }), unchanged)
+                .is_err()
+        );
+        assert!(
+            runtime
+                .update(&json!({ "cache": { "max_size_mb": -1 } }), unchanged)
+                .is_err()
+        );
+
+        // Nothing is kept when applying fails
+        let failing = |_: &Config, _: &Config| Err("cache disabled".to_string());
+        assert!(
+            runtime
+                .update(&json!({ "cache": { "max_size_mb": 1 } }), failing)
+                .is_err()
+        );
+        assert_eq!(runtime.masked().unwrap()["cache"]["max_size_mb"], 10240);
+    }
+}
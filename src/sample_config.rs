@@ -0,0 +1,359 @@
+/// Annotated sample configuration (`docker-proxy init`)
+///
+/// The sample is generated from the config structs: every option appears
+/// with its default value, preceded by its doc comment, so it cannot drift
+/// from what the proxy actually reads. Options without a default are
+/// listed commented out. `--systemd <path>` also writes a unit file running
+/// the proxy with the written config.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value as Schema;
+use toml::{Table, Value};
+
+use crate::args::{Args, DEFAULT_CONFIG_PATHS};
+use crate::config::Config;
+
+#[derive(Debug, clap::Args)]
+pub struct InitArgs {
+    /// Print the sample instead of writing it to --config [default: ./config/config.toml]
+    #[arg(long)]
+    pub stdout: bool,
+    /// Overwrite existing files
+    #[arg(long)]
+    pub force: bool,
+    /// Also write a systemd unit running the proxy with this config, e.g.
+    /// /etc/systemd/system/docker-proxy.service
+    #[arg(long, value_name = "PATH")]
+    pub systemd: Option<PathBuf>,
+}
+
+/// Write the sample config (and unit), returning the process exit code
+pub fn run(args: &Args, init: &InitArgs) -> i32 {
+    let sample = generate();
+    if init.stdout {
+        print!("{}", sample);
+        return 0;
+    }
+    let path = args
+        .config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATHS[1]));
+    let mut files = vec![(path.clone(), sample)];
+    if let Some(unit_path) = &init.systemd {
+        let config_path = std::path::absolute(&path).unwrap_or(path);
+        files.push((unit_path.clone(), systemd_unit(&config_path)));
+    }
+    for (path, content) in files {
+        if path.exists() && !init.force {
+            eprintln!(
+                "Error: {} exists (use --force to overwrite)",
+                path.display()
+            );
+            return 1;
+        }
+        let written = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+            _ => Ok(()),
+        }
+        .and_then(|()| fs::write(&path, content));
+        if let Err(e) = written {
+            eprintln!("Error: {}: {}", path.display(), e);
+            return 1;
+        }
+        println!("Wrote {}", path.display());
+    }
+    0
+}
+
+/// Every option with its default and documentation, as TOML
+pub fn generate() -> String {
+    let schema = schemars::schema_for!(Config);
+    let root = schema.as_value();
+    let defaults = Table::try_from(Config::default()).expect("defaults serialize to TOML");
+    let mut out = format!(
+        "# docker-proxy {} configuration, generated by `docker-proxy init`\n\
+         # Every option is listed with its default; delete the ones you do not change.\n\
+         # Options can also be set as DOCKER_PROXY__<SECTION>__<KEY> environment variables\n\
+         # and credentials read from files with <option>_file.\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    write_table(&mut out, root, root, "", &defaults);
+    out
+}
+
+// The options of the table at `path`, then its sub-tables
+fn write_table(out: &mut String, root: &Schema, schema: &Schema, path: &str, defaults: &Table) {
+    let properties = schema
+        .get("properties")
+        .and_then(Schema::as_object)
+        .cloned()
+        .unwrap_or_default();
+    // Field order from the defaults, then options without one
+    let mut keys: Vec<&String> = defaults.keys().collect();
+    let mut unset: Vec<&String> = properties
+        .keys()
+        .filter(|key| !defaults.contains_key(*key))
+        .collect();
+    unset.sort();
+    keys.extend(unset);
+
+    let mut tables = Vec::new();
+    for key in keys {
+        let Some(property) = properties.get(key) else {
+            continue;
+        };
+        let resolved = resolve(root, property);
+        let path = match path {
+            "" => toml_key(key),
+            path => format!("{}.{}", path, toml_key(key)),
+        };
+        match defaults.get(key) {
+            // Sub-tables follow the options of this one
+            Some(Value::Table(nested)) if is_struct(resolved) => {
+                tables.push((path, property, Some((resolved, nested))));
+            }
+            _ if entry_schema(root, resolved).is_some() => tables.push((path, property, None)),
+            default => {
+                out.push('\n');
+                write_comment(out, &description(root, property));
+                write_choices(out, resolved);
+                match default {
+                    Some(value) => out.push_str(&format!("{} = {}\n", toml_key(key), value)),
+                    None => out.push_str(&format!(
+                        "# {} = {}\n",
+                        toml_key(key),
+                        placeholder(root, property)
+                    )),
+                }
+            }
+        }
+    }
+    for (path, property, table) in tables {
+        out.push('\n');
+        write_comment(out, &description(root, property));
+        match table {
+            Some((resolved, nested)) => {
+                out.push_str(&format!("[{}]\n", path));
+                write_table(out, root, resolved, &path, nested);
+            }
+            None => write_example(out, root, resolve(root, property), &path),
+        }
+    }
+}
+
+// Schema of the entries of a map or of an array of tables
+fn entry_schema<'a>(root: &'a Schema, schema: &'a Schema) -> Option<&'a Schema> {
+    if let Some(entry) = schema
+        .get("additionalProperties")
+        .filter(|entry| entry.is_object())
+    {
+        return Some(resolve(root, entry));
+    }
+    schema
+        .get("items")
+        .map(|item| resolve(root, item))
+        .filter(|item| is_struct(item))
+}
+
+// A commented-out entry of an empty map or array of tables
+fn write_example(out: &mut String, root: &Schema, schema: &Schema, path: &str) {
+    let Some(entry) = entry_schema(root, schema) else {
+        return;
+    };
+    let Some(fields) = entry.get("properties").and_then(Schema::as_object) else {
+        // Map of plain values
+        out.push_str(&format!("# [{}]\n", path));
+        out.push_str(&format!("# \"<name>\" = {}\n", placeholder(root, entry)));
+        return;
+    };
+    match schema.get("items") {
+        Some(_) => out.push_str(&format!("# [[{}]]\n", path)),
+        None => out.push_str(&format!("# [{}.\"<name>\"]\n", path)),
+    }
+    for (key, field) in fields {
+        let value = field
+            .get("default")
+            .and_then(|default| Value::try_from(default).ok())
+            .map(|value| value.to_string())
+            .unwrap_or_else(|| placeholder(root, field));
+        let line = format!("# {} = {}", toml_key(key), value);
+        match description(root, field).replace('\n', " ") {
+            text if text.is_empty() => out.push_str(&format!("{}\n", line)),
+            text => out.push_str(&format!("{} # {}\n", line, text)),
+        }
+    }
+}
+
+fn write_comment(out: &mut String, text: &str) {
+    for line in text.lines() {
+        out.push_str(&format!("# {}\n", line).replace("# \n", "#\n"));
+    }
+}
+
+// The values of an enum option, with their documentation
+fn write_choices(out: &mut String, schema: &Schema) {
+    for (value, text) in choices(schema) {
+        match text {
+            Some(text) => out.push_str(&format!("#   {}: {}\n", value, text.replace('\n', " "))),
+            None => out.push_str(&format!("#   {}\n", value)),
+        }
+    }
+}
+
+// Values of an enum with their documentation; undocumented variants are
+// grouped under `enum`
+fn choices(schema: &Schema) -> Vec<(&Schema, Option<&str>)> {
+    let variants = match schema.get("oneOf").and_then(Schema::as_array) {
+        Some(variants) => variants.iter().collect(),
+        None => vec![schema],
+    };
+    variants
+        .into_iter()
+        .flat_map(|variant| {
+            let text = variant.get("description").and_then(Schema::as_str);
+            let values: Vec<&Schema> = match (variant.get("const"), variant.get("enum")) {
+                (Some(value), _) => vec![value],
+                (None, Some(Schema::Array(values))) => values.iter().collect(),
+                _ => Vec::new(),
+            };
+            values.into_iter().map(move |value| (value, text))
+        })
+        .collect()
+}
+
+// The schema a `$ref` points to
+fn resolve<'a>(root: &'a Schema, schema: &'a Schema) -> &'a Schema {
+    let reference = schema.get("$ref").or_else(|| {
+        // Option<T> of a referenced type: anyOf [T, null]
+        schema
+            .get("anyOf")?
+            .as_array()?
+            .iter()
+            .find_map(|variant| variant.get("$ref"))
+    });
+    reference
+        .and_then(Schema::as_str)
+        .and_then(|reference| reference.strip_prefix("#/"))
+        .and_then(|pointer| root.pointer(&format!("/{}", pointer)))
+        .unwrap_or(schema)
+}
+
+fn is_struct(schema: &Schema) -> bool {
+    schema.get("properties").is_some()
+}
+
+// The option's documentation, else its type's
+fn description(root: &Schema, property: &Schema) -> String {
+    property
+        .get("description")
+        .or_else(|| resolve(root, property).get("description"))
+        .and_then(Schema::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+// Example value of an option without a default
+fn placeholder(root: &Schema, property: &Schema) -> String {
+    let resolved = resolve(root, property);
+    if let Some(properties) = resolved.get("properties").and_then(Schema::as_object) {
+        let fields: Vec<String> = properties
+            .iter()
+            .map(|(key, field)| format!("{} = {}", toml_key(key), placeholder(root, field)))
+            .collect();
+        return format!("{{ {} }}", fields.join(", "));
+    }
+    if let Some((value, _)) = choices(resolved).first() {
+        return value.to_string();
+    }
+    let types = match resolved.get("type") {
+        Some(Schema::Array(types)) => types.clone(),
+        Some(single) => vec![single.clone()],
+        None => Vec::new(),
+    };
+    let value = if types.iter().any(|t| t == "boolean") {
+        "false"
+    } else if types.iter().any(|t| t == "integer" || t == "number") {
+        "0"
+    } else if types.iter().any(|t| t == "array") {
+        "[]"
+    } else {
+        "\"\""
+    };
+    value.to_string()
+}
+
+fn toml_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+    {
+        key.to_string()
+    } else {
+        Value::String(key.to_string()).to_string()
+    }
+}
+
+/// systemd unit running the proxy with the config at `config_path`
+pub fn systemd_unit(config_path: &Path) -> String {
+    let executable =
+        std::env::current_exe().unwrap_or_else(|_| PathBuf::from("/usr/local/bin/docker-proxy"));
+    format!(
+        "[Unit]\n\
+         Description=Docker registry pull-through proxy\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         # readiness is reported once the listener and cache are up\n\
+         Type=notify\n\
+         # SIGUSR2 hands the socket to a new process, which then reports as MAINPID\n\
+         NotifyAccess=all\n\
+         ExecStart={} --config {}\n\
+         ExecReload=/bin/kill -USR2 $MAINPID\n\
+         Restart=on-failure\n\
+         LimitNOFILE=65536\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        executable.display(),
+        config_path.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let sample = generate();
+        // Reading the sample back yields the defaults
+        let config = Config::from_str(&sample).unwrap();
+        assert_eq!(
+            toml::to_string(&config).unwrap(),
+            toml::to_string(&Config::default()).unwrap()
+        );
+
+        assert!(sample.contains("\n# Server configuration\n[server]\n"));
+        assert!(sample.contains("# Port to listen on\nport = 8080\n"));
+        assert!(sample.contains("\n# HTTPS on the listener\n[server.tls]\n"));
+        // Options without a default are commented out, enums list their values
+        assert!(sample.contains("# listen_fd = 0\n"));
+        assert!(sample.contains("#   \"dev\": Local development"));
+        assert!(sample.contains("# profile = \"dev\"\n"));
+        assert!(sample.contains("# dockerhub = { password = \"\", username = \"\" }\n"));
+        assert!(sample.contains("ghcr-token = \"\"\n"));
+        // Maps and arrays of tables get a commented-out example entry
+        assert!(sample.contains("# [proxy.registries.\"<name>\"]\n"));
+        assert!(sample.contains("# [[policy.rules]]\n"));
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let unit = systemd_unit(Path::new("/etc/docker-proxy/config.toml"));
+        assert!(unit.contains("Type=notify\n"));
+        assert!(unit.contains(" --config /etc/docker-proxy/config.toml\n"));
+    }
+}
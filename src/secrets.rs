@@ -0,0 +1,304 @@
+/// Credentials kept out of the config file
+///
+/// Every credential option `x` may instead be given as `x_file`
+/// (`ghcr-token-file` for `ghcr-token`), a file holding the value, such as a
+/// Docker or Kubernetes secret; a trailing newline is dropped and `api_keys`
+/// files hold one key per line. `${NAME}` in a credential value is replaced
+/// by the environment variable NAME. Both are resolved whenever the
+/// configuration is loaded, so rotating a secret takes a restart
+/// (SIGUSR2 re-executes the proxy without dropping connections). Where the
+/// configuration is shown (`/admin/config`) credentials are masked.
+use std::fs;
+
+use toml::{Table, Value};
+
+/// How a credential option holds its value
+#[derive(Clone, Copy)]
+enum Shape {
+    One,
+    /// A list of values, one per line in a file
+    Lines,
+}
+
+/// Credential options as key paths, `*` matching every key of a table
+const CREDENTIALS: &[(&[&str], Shape)] = &[
+    (&["auth", "ghcr-token"], Shape::One),
+    (&["auth", "dockerhub", "username"], Shape::One),
+    (&["auth", "dockerhub", "password"], Shape::One),
+    (&["auth", "credentials", "*", "username"], Shape::One),
+    (&["auth", "credentials", "*", "password"], Shape::One),
+    (&["cache", "signing_key"], Shape::One),
+    (&["chain", "upstream_signing_key"], Shape::One),
+    (&["client_auth", "token_key"], Shape::One),
+    (&["admin", "api_keys"], Shape::Lines),
+    (&["sentry", "dsn"], Shape::One),
+];
+
+/// Other options masked when the configuration is shown
+const SENSITIVE: &[&[&str]] = &[
+    &["privacy", "salt"],
+    &["client_auth", "users", "*"],
+    &["telemetry", "headers", "*"],
+];
+
+/// Shown in place of a masked value
+pub const MASK: &str = "********";
+
+/// Read the `_file` credential options and expand the `${NAME}` references
+/// of credentials in `table`, returning whether it changed
+pub fn resolve(table: &mut Table) -> Result<bool, String> {
+    let mut changed = false;
+    for (path, shape) in CREDENTIALS {
+        changed |= resolve_at(table, path, *shape, "")?;
+    }
+    Ok(changed)
+}
+
+fn resolve_at(table: &mut Table, path: &[&str], shape: Shape, at: &str) -> Result<bool, String> {
+    let (first, rest) = path.split_first().expect("credential paths are not empty");
+    if rest.is_empty() {
+        return resolve_option(table, first, shape, at);
+    }
+    let mut changed = false;
+    for (key, value) in table.iter_mut() {
+        if (*first == "*" || same_key(key, first))
+            && let Value::Table(nested) = value
+        {
+            let at = if at.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", at, key)
+            };
+            changed |= resolve_at(nested, rest, shape, &at)?;
+        }
+    }
+    Ok(changed)
+}
+
+fn resolve_option(table: &mut Table, name: &str, shape: Shape, at: &str) -> Result<bool, String> {
+    let mut changed = false;
+    let file_name = match name.contains('-') {
+        true => format!("{}-file", name),
+        false => format!("{}_file", name),
+    };
+    let key = find_key(table, name).unwrap_or_else(|| name.to_string());
+    if let Some(file_key) = find_key(table, &file_name) {
+        let Some(Value::String(path)) = table.remove(&file_key) else {
+            return Err(format!("[{}] {} must be a file path", at, file_name));
+        };
+        if table.contains_key(&key) {
+            return Err(format!("[{}] {} and {} are both set", at, name, file_name));
+        }
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format!("[{}] {}: {}: {}", at, file_name, path, e))?;
+        let value = match shape {
+            Shape::One => Value::String(content.trim_end_matches(['\r', '\n']).to_string()),
+            Shape::Lines => Value::Array(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(|line| Value::String(line.to_string()))
+                    .collect(),
+            ),
+        };
+        table.insert(key.clone(), value);
+        changed = true;
+    }
+    let values: Vec<&mut String> = match table.get_mut(&key) {
+        Some(Value::String(value)) => vec![value],
+        Some(Value::Array(items)) => items
+            .iter_mut()
+            .filter_map(|item| match item {
+                Value::String(value) => Some(value),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    for value in values {
+        if value.contains("${") {
+            *value = expand(value).map_err(|e| format!("[{}] {}: {}", at, name, e))?;
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+/// Replace the credentials and other sensitive values in `table` with MASK;
+/// empty ones are left as they are
+pub fn mask(table: &mut Table) {
+    let credentials = CREDENTIALS.iter().map(|(path, _)| *path);
+    for path in credentials.chain(SENSITIVE.iter().copied()) {
+        mask_at(table, path);
+    }
+}
+
+fn mask_at(table: &mut Table, path: &[&str]) {
+    let Some((first, rest)) = path.split_first() else {
+        return;
+    };
+    for (key, value) in table.iter_mut() {
+        if *first != "*" && !same_key(key, first) {
+            continue;
+        }
+        match value {
+            Value::Table(nested) if !rest.is_empty() => mask_at(nested, rest),
+            _ if rest.is_empty() => mask_value(value),
+            _ => {}
+        }
+    }
+}
+
+fn mask_value(value: &mut Value) {
+    match value {
+        Value::String(text) if !text.is_empty() => *text = MASK.to_string(),
+        Value::Array(items) => items.iter_mut().for_each(mask_value),
+        _ => {}
+    }
+}
+
+// Environment overrides create keys with underscores, e.g. `ghcr_token`
+fn same_key(key: &str, name: &str) -> bool {
+    key.len() == name.len()
+        && key
+            .bytes()
+            .zip(name.bytes())
+            .all(|(a, b)| a == b || (a == b'-' && b == b'_') || (a == b'_' && b == b'-'))
+}
+
+fn find_key(table: &Table, name: &str) -> Option<String> {
+    table.keys().find(|key| same_key(key, name)).cloned()
+}
+
+/// `value` with each `${NAME}` replaced by the environment variable NAME
+pub fn expand(value: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| "unterminated ${ reference".to_string())?;
+        let name = &rest[start + 2..start + end];
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            return Err(format!("invalid environment variable name {:?}", name));
+        }
+        let variable =
+            std::env::var(name).map_err(|_| format!("environment variable {} is not set", name))?;
+        expanded.push_str(&variable);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        // PATH and HOME are set in every test environment
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(expand("plain").unwrap(), "plain");
+        assert_eq!(expand("${PATH}").unwrap(), path);
+        assert_eq!(expand("a-${PATH}-b$c").unwrap(), format!("a-{}-b$c", path));
+        assert!(expand("${DOCKER_PROXY_TEST_UNSET_VARIABLE}").is_err());
+        assert!(expand("${PATH").is_err());
+        assert!(expand("${PA TH}").is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir =
+            std::env::temp_dir().join(format!("docker-proxy-secrets-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ghcr"), "ghp_secret\n").unwrap();
+        fs::write(dir.join("keys"), "key-one\n\nkey-two\n").unwrap();
+        let mut table: Table = format!(
+            r#"
+[admin]
+api_keys_file = "{dir}/keys"
+
+[auth]
+ghcr-token-file = "{dir}/ghcr"
+
+[auth.credentials."quay.io"]
+username = "robot"
+password = "${{HOME}}"
+"#,
+            dir = dir.display()
+        )
+        .parse()
+        .unwrap();
+        assert!(resolve(&mut table).unwrap());
+        assert_eq!(table["auth"]["ghcr-token"].as_str(), Some("ghp_secret"));
+        assert!(
+            !table["auth"]
+                .as_table()
+                .unwrap()
+                .contains_key("ghcr-token-file")
+        );
+        assert_eq!(
+            table["admin"]["api_keys"],
+            Value::Array(vec![
+                Value::String("key-one".to_string()),
+                Value::String("key-two".to_string())
+            ])
+        );
+        assert_eq!(
+            table["auth"]["credentials"]["quay.io"]["password"].as_str(),
+            Some(std::env::var("HOME").unwrap().as_str())
+        );
+        // Nothing left to resolve
+        assert!(!resolve(&mut table).unwrap());
+
+        // Spelled the way environment overrides create keys
+        let mut table: Table = format!("[auth]\nghcr_token_file = \"{}/ghcr\"", dir.display())
+            .parse()
+            .unwrap();
+        resolve(&mut table).unwrap();
+        assert_eq!(table["auth"]["ghcr-token"].as_str(), Some("ghp_secret"));
+
+        let mut both: Table = format!(
+            "[auth]\nghcr-token = \"x\"\nghcr-token-file = \"{}/ghcr\"",
+            dir.display()
+        )
+        .parse()
+        .unwrap();
+        assert!(resolve(&mut both).is_err());
+        let mut missing: Table = "[sentry]\ndsn_file = \"/nonexistent/dsn\"".parse().unwrap();
+        assert!(resolve(&mut missing).unwrap_err().contains("dsn_file"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mask() {
+        let mut table: Table = r#"
+[admin]
+api_keys = ["key-one", "key-two"]
+
+[auth]
+ghcr-token = ""
+dockerhub = { username = "bot", password = "secret" }
+
+[client_auth]
+realm = "docker-proxy"
+users = { ci = "$2y$05$hash" }
+"#
+        .parse()
+        .unwrap();
+        mask(&mut table);
+        assert_eq!(
+            table["admin"]["api_keys"],
+            Value::Array(vec![Value::String(MASK.to_string()); 2])
+        );
+        // Unset credentials stay visibly unset
+        assert_eq!(table["auth"]["ghcr-token"].as_str(), Some(""));
+        assert_eq!(table["auth"]["dockerhub"]["username"].as_str(), Some(MASK));
+        assert_eq!(table["auth"]["dockerhub"]["password"].as_str(), Some(MASK));
+        assert_eq!(table["client_auth"]["users"]["ci"].as_str(), Some(MASK));
+        assert_eq!(table["client_auth"]["realm"].as_str(), Some("docker-proxy"));
+    }
+}
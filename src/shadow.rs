@@ -0,0 +1,518 @@
+/// Shadow evaluation of a candidate configuration
+///
+/// A candidate config is loaded next to the active one and every registry
+/// request is routed through both: where the candidate would choose a
+/// different upstream, push handling, delete policy, maintenance,
+/// credentials or `[policy]` outcome, the difference is logged and counted. Nothing the candidate
+/// decides is enforced, so a policy change can be checked against live
+/// traffic before the config is switched.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use axum::http::Method;
+use serde::Serialize;
+
+use crate::clock::Clock;
+use crate::config::{Config, PushMode};
+use crate::maintenance::Maintenance;
+use crate::policy;
+use crate::router::{self, V2Endpoint};
+
+/// Distinct differences kept in the report; later ones are only counted
+const MAX_DIFFERENCES: usize = 100;
+
+/// One way the candidate config decides differently from the active one
+#[derive(Debug, Clone, Serialize)]
+pub struct Difference {
+    pub field: &'static str,
+    pub active: String,
+    pub candidate: String,
+    pub count: u64,
+    /// First request that showed the difference, as "METHOD path"
+    pub example: String,
+}
+
+/// Summary of the shadow evaluation so far
+#[derive(Debug, Clone, Serialize)]
+pub struct ShadowReport {
+    pub candidate: String,
+    pub started_at: u64,
+    /// Unix time evaluation stops, if limited
+    pub until: Option<u64>,
+    pub requests: u64,
+    pub differing_requests: u64,
+    pub differences: Vec<Difference>,
+}
+
+// A configuration as far as request decisions are concerned
+struct Policy {
+    config: Config,
+    registry_url: String,
+    maintenance: Maintenance,
+    rules: Option<policy::Policy>,
+}
+
+impl Policy {
+    fn new(config: Config, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            registry_url: config.default_registry_url(),
+            maintenance: Maintenance::new(&config.maintenance, clock),
+            rules: policy::Policy::from_config(&config.policy),
+            config,
+        }
+    }
+
+    fn decide(&self, method: &Method, endpoint: &V2Endpoint) -> BTreeMap<&'static str, String> {
+        decide(
+            &self.config,
+            &self.registry_url,
+            self.maintenance.status().is_some(),
+            self.rules.as_ref(),
+            method,
+            endpoint,
+        )
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    requests: u64,
+    differing_requests: u64,
+    differences: HashMap<(&'static str, String, String), Difference>,
+}
+
+pub struct ShadowEvaluator {
+    active: Policy,
+    candidate: Policy,
+    candidate_path: String,
+    started_at: u64,
+    until: Option<u64>,
+    tally: Mutex<Tally>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ShadowEvaluator {
+    /// Load the candidate config named in the active config's `[shadow]`
+    /// section
+    pub fn load(active: &Config, clock: Arc<dyn Clock>) -> Result<Self, String> {
+        let path = &active.shadow.candidate;
+        let candidate = Config::from_file(path)
+            .map_err(|e| format!("Failed to load candidate config {}: {}", path, e))?;
+        Ok(Self::new(active.clone(), candidate, path, clock))
+    }
+
+    fn new(active: Config, candidate: Config, candidate_path: &str, clock: Arc<dyn Clock>) -> Self {
+        let started_at = clock.now_secs();
+        let until = match active.shadow.duration_hours {
+            0 => None,
+            hours => Some(started_at + hours * 3600),
+        };
+        Self {
+            active: Policy::new(active, Arc::clone(&clock)),
+            candidate: Policy::new(candidate, Arc::clone(&clock)),
+            candidate_path: candidate_path.to_string(),
+            started_at,
+            until,
+            tally: Mutex::new(Tally::default()),
+            clock,
+        }
+    }
+
+    /// Compare the decisions both configs make for a request
+    pub fn evaluate(&self, method: &Method, path: &str) {
+        if self
+            .until
+            .is_some_and(|until| self.clock.now_secs() >= until)
+        {
+            return;
+        }
+        let Some(rest) = path.strip_prefix("/v2/").filter(|r| !r.is_empty()) else {
+            return;
+        };
+        // Trust metadata goes to the trust server whatever the registry config
+        let endpoint = router::parse_v2_request(method, rest);
+        if matches!(
+            endpoint,
+            V2Endpoint::Unknown | V2Endpoint::TrustMetadata { .. }
+        ) {
+            return;
+        }
+
+        let active = self.active.decide(method, &endpoint);
+        let candidate = self.candidate.decide(method, &endpoint);
+
+        let mut tally = self.tally.lock().unwrap_or_else(|e| e.into_inner());
+        tally.requests += 1;
+        let mut differs = false;
+        for (field, active_value) in &active {
+            let candidate_value = &candidate[field];
+            if active_value == candidate_value {
+                continue;
+            }
+            differs = true;
+            let key = (*field, active_value.clone(), candidate_value.clone());
+            if let Some(difference) = tally.differences.get_mut(&key) {
+                difference.count += 1;
+                tracing::debug!(field = %field, method = %method, path = %path, "Shadow config differs");
+                continue;
+            }
+            tracing::info!(
+                field = %field,
+                active = %active_value,
+                candidate = %candidate_value,
+                method = %method,
+                path = %path,
+                "Shadow config decides differently"
+            );
+            if tally.differences.len() < MAX_DIFFERENCES {
+                tally.differences.insert(
+                    key,
+                    Difference {
+                        field,
+                        active: active_value.clone(),
+                        candidate: candidate_value.clone(),
+                        count: 1,
+                        example: format!("{} {}", method, path),
+                    },
+                );
+            }
+        }
+        if differs {
+            tally.differing_requests += 1;
+        }
+    }
+
+    /// Differences seen so far, most frequent first
+    pub fn report(&self) -> ShadowReport {
+        let tally = self.tally.lock().unwrap_or_else(|e| e.into_inner());
+        let mut differences: Vec<Difference> = tally.differences.values().cloned().collect();
+        differences.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.field.cmp(b.field))
+                .then_with(|| a.active.cmp(&b.active))
+        });
+        ShadowReport {
+            candidate: self.candidate_path.clone(),
+            started_at: self.started_at,
+            until: self.until,
+            requests: tally.requests,
+            differing_requests: tally.differing_requests,
+            differences,
+        }
+    }
+}
+
+// The routing and policy decisions a config makes for one request
+fn decide(
+    config: &Config,
+    registry_url: &str,
+    in_maintenance: bool,
+    rules: Option<&policy::Policy>,
+    method: &Method,
+    endpoint: &V2Endpoint,
+) -> BTreeMap<&'static str, String> {
+    let name = match endpoint {
+        V2Endpoint::Manifest { name, .. }
+        | V2Endpoint::Blob { name, .. }
+        | V2Endpoint::BlobUploadInit { name }
+        | V2Endpoint::BlobUploadComplete { name, .. }
+        | V2Endpoint::BlobUploadChunk { name, .. }
+        | V2Endpoint::BlobUploadStatus { name, .. }
+        | V2Endpoint::TagList { name } => Some(name.as_str()),
+        V2Endpoint::Catalog | V2Endpoint::TrustMetadata { .. } | V2Endpoint::Unknown => None,
+    };
+    let (upstream_url, repository) = match name {
+        Some(name) => router::split_registry_and_name(
+            registry_url,
+            &config.proxy.routes,
+            &config.proxy.registries,
+            name,
+        ),
+        None => (registry_url.to_string(), "_catalog".to_string()),
+    };
+    let host = upstream_url
+        .split_once("://")
+        .map_or(upstream_url.as_str(), |(_, host)| host);
+    let reference = match endpoint {
+        V2Endpoint::Manifest { reference, .. } => Some(reference.as_str()),
+        _ => None,
+    };
+    let verdict = match (rules, name) {
+        (Some(rules), Some(_)) => {
+            let default = upstream_url == router::registry_endpoint(registry_url);
+            rules.check(method, &upstream_url, &repository, default, reference)
+        }
+        _ => Ok(()),
+    };
+    let policy = match verdict {
+        Ok(()) => "allowed".to_string(),
+        Err(denial) => match denial.rule {
+            Some(number) => format!("denied by rule {}", number),
+            None => "denied by default".to_string(),
+        },
+    };
+
+    let local = config.proxy.push_mode == PushMode::Local && config.cache.enabled;
+    let write = matches!(*method, Method::POST | Method::PUT | Method::PATCH);
+    let handling = if in_maintenance {
+        "maintenance"
+    } else if *method == Method::DELETE && !config.proxy.allow_delete {
+        "rejected"
+    } else if local
+        && (write
+            || *method == Method::DELETE
+            || matches!(endpoint, V2Endpoint::TagList { .. } | V2Endpoint::Catalog))
+    {
+        "local"
+    } else {
+        "upstream"
+    };
+    let credentials = if config
+        .auth
+        .credentials_for_repository(host, &repository)
+        .is_some()
+    {
+        "configured"
+    } else {
+        "anonymous"
+    };
+
+    BTreeMap::from([
+        ("upstream", format!("{}/{}", host, repository)),
+        ("handling", handling.to_string()),
+        ("credentials", credentials.to_string()),
+        ("policy", policy),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn config(proxy: &str, extra: &str) -> Config {
+        Config::from_str(&format!(
+            r#"
+[server]
+host = "0.0.0.0"
+port = 8080
+
+[log]
+logFilePath = "/tmp/test.log"
+level = "info"
+
+[proxy]
+{}
+
+[auth]
+ghcr-token = ""
+
+{}
+"#,
+            proxy, extra
+        ))
+        .expect("Failed to parse test config")
+    }
+
+    #[test]
+    fn test_decide() {
+        let active = config(r#"default = "registry-1.docker.io""#, "");
+        let url = active.default_registry_url();
+        let pull = V2Endpoint::Manifest {
+            name: "nginx".to_string(),
+            reference: "latest".to_string(),
+        };
+        let decision = decide(&active, &url, false, None, &Method::GET, &pull);
+        assert_eq!(decision["upstream"], "registry-1.docker.io/library/nginx");
+        assert_eq!(decision["handling"], "upstream");
+        assert_eq!(decision["credentials"], "anonymous");
+
+        let dockerhub = config(
+            r#"default = "registry-1.docker.io""#,
+            r#"[auth.dockerhub]
+username = "bot"
+password = "dckr_pat_secret"
+"#,
+        );
+        let decision = decide(&dockerhub, &url, false, None, &Method::GET, &pull);
+        assert_eq!(decision["credentials"], "configured");
+        assert!(dockerhub.auth.credentials_for("docker.io").is_some());
+        assert!(dockerhub.auth.credentials_for("ghcr.io").is_none());
+
+        let delete = decide(&active, &url, false, None, &Method::DELETE, &pull);
+        assert_eq!(delete["handling"], "rejected");
+        let maintenance = decide(&active, &url, true, None, &Method::GET, &pull);
+        assert_eq!(maintenance["handling"], "maintenance");
+
+        let ghcr = V2Endpoint::BlobUploadInit {
+            name: "ghcr.io/owner/app".to_string(),
+        };
+        let candidate = config(
+            r#"default = "ghcr.io"
+push_mode = "local""#,
+            r#"[auth.credentials."ghcr.io"]
+username = "bot"
+password = "secret"
+
+[cache]
+enabled = true
+dir = "/tmp/docker-proxy-shadow-test"
+"#,
+        );
+        let candidate_url = candidate.default_registry_url();
+        let decision = decide(
+            &candidate,
+            &candidate_url,
+            false,
+            None,
+            &Method::POST,
+            &ghcr,
+        );
+        assert_eq!(decision["upstream"], "ghcr.io/owner/app");
+        assert_eq!(decision["handling"], "local");
+        assert_eq!(decision["credentials"], "configured");
+
+        let namespaced = config(
+            r#"default = "ghcr.io""#,
+            r#"[auth.credentials."ghcr.io/org-a/*"]
+username = "a"
+password = "token-a"
+
+[auth.credentials."ghcr.io/org-a/team"]
+username = "team"
+password = "token-team"
+"#,
+        );
+        let url = namespaced.default_registry_url();
+        let org_a = V2Endpoint::Manifest {
+            name: "ghcr.io/org-a/app".to_string(),
+            reference: "latest".to_string(),
+        };
+        let org_b = V2Endpoint::Manifest {
+            name: "ghcr.io/org-b/app".to_string(),
+            reference: "latest".to_string(),
+        };
+        let decide_credentials = |endpoint| {
+            decide(&namespaced, &url, false, None, &Method::GET, endpoint)["credentials"].clone()
+        };
+        assert_eq!(decide_credentials(&org_a), "configured");
+        assert_eq!(decide_credentials(&org_b), "anonymous");
+        let auth = &namespaced.auth;
+        assert_eq!(
+            auth.credential_namespace("ghcr.io", "org-a/team/app"),
+            Some("ghcr.io/org-a/team")
+        );
+        assert_eq!(
+            auth.credentials_for_repository("ghcr.io", "org-a/app")
+                .unwrap()
+                .password,
+            "token-a"
+        );
+        assert_eq!(auth.credential_namespace("ghcr.io", "org-ab/app"), None);
+        assert_eq!(auth.credential_namespace("quay.io", "org-a/app"), None);
+    }
+
+    #[test]
+    fn test_policy_differences() {
+        let active = config(r#"default = "registry-1.docker.io""#, "");
+        let candidate = config(
+            r#"default = "registry-1.docker.io""#,
+            r#"[policy]
+default = "deny"
+
+[[policy.rules]]
+action = "allow"
+glob = "docker.io/library/*"
+
+[[policy.rules]]
+action = "deny"
+regex = ".*:latest"
+"#,
+        );
+        let shadow = ShadowEvaluator::new(
+            active,
+            candidate,
+            "candidate.toml",
+            ManualClock::new(1_700_000_000),
+        );
+
+        shadow.evaluate(&Method::GET, "/v2/nginx/manifests/latest");
+        shadow.evaluate(&Method::GET, "/v2/team/app/manifests/latest");
+        shadow.evaluate(&Method::GET, "/v2/ghcr.io/owner/app/blobs/sha256:abc");
+
+        let report = shadow.report();
+        assert_eq!(report.requests, 3);
+        assert_eq!(report.differing_requests, 2);
+        let mut differences: Vec<_> = report
+            .differences
+            .iter()
+            .map(|d| (d.field, d.active.as_str(), d.candidate.as_str()))
+            .collect();
+        differences.sort();
+        assert_eq!(
+            differences,
+            [
+                ("policy", "allowed", "denied by default"),
+                ("policy", "allowed", "denied by rule 2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_evaluate_reports_differences() {
+        let active = config(r#"default = "registry-1.docker.io""#, "");
+        let candidate = config(
+            r#"default = "mirror.example.com"
+allow_delete = true"#,
+            "",
+        );
+        let shadow = ShadowEvaluator::new(
+            active,
+            candidate,
+            "candidate.toml",
+            ManualClock::new(1_700_000_000),
+        );
+
+        shadow.evaluate(&Method::GET, "/v2/library/nginx/manifests/latest");
+        shadow.evaluate(&Method::GET, "/v2/library/nginx/manifests/1.27");
+        shadow.evaluate(&Method::GET, "/v2/ghcr.io/owner/app/manifests/v1");
+        shadow.evaluate(&Method::DELETE, "/v2/ghcr.io/owner/app/manifests/v1");
+        shadow.evaluate(&Method::GET, "/v2/");
+        shadow.evaluate(&Method::GET, "/healthz");
+
+        let report = shadow.report();
+        assert_eq!(report.candidate, "candidate.toml");
+        assert_eq!(report.requests, 4);
+        assert_eq!(report.differing_requests, 3);
+        assert_eq!(report.differences.len(), 2);
+        assert_eq!(report.differences[0].field, "upstream");
+        assert_eq!(report.differences[0].count, 2);
+        assert_eq!(
+            report.differences[0].candidate,
+            "mirror.example.com/library/nginx"
+        );
+        assert_eq!(
+            report.differences[0].example,
+            "GET /v2/library/nginx/manifests/latest"
+        );
+        assert_eq!(report.differences[1].field, "handling");
+        assert_eq!(report.differences[1].active, "rejected");
+        assert_eq!(report.differences[1].candidate, "upstream");
+    }
+
+    #[test]
+    fn test_evaluation_stops_after_duration() {
+        let mut active = config(r#"default = "registry-1.docker.io""#, "");
+        active.shadow.duration_hours = 1;
+        let candidate = config(r#"default = "mirror.example.com""#, "");
+        let clock = ManualClock::new(1_700_000_000);
+        let shadow = ShadowEvaluator::new(active, candidate, "candidate.toml", clock.clone());
+        assert_eq!(shadow.report().until, Some(1_700_003_600));
+
+        shadow.evaluate(&Method::GET, "/v2/library/nginx/manifests/latest");
+        clock.advance(std::time::Duration::from_secs(3600));
+        shadow.evaluate(&Method::GET, "/v2/library/nginx/manifests/latest");
+        assert_eq!(shadow.report().requests, 1);
+    }
+}
@@ -0,0 +1,174 @@
+/// Bodies the proxy has to read in full
+///
+/// A few responses must be complete before they can be used: a manifest
+/// served without an upstream digest is hashed before its headers go out, and
+/// the copy kept for `serve_stale_on_error` is stored once the fetch ends.
+/// Such bodies are kept in memory up to `[proxy] spill_threshold_mb` and
+/// continue in a temp file beyond it, so a huge body does not sit in RAM.
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::body::Body;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+/// Where bodies spill and from what size
+#[derive(Debug, Clone)]
+pub struct SpillPolicy {
+    /// Bytes kept in memory before spilling
+    pub threshold: usize,
+    pub dir: PathBuf,
+}
+
+impl SpillPolicy {
+    /// An empty buffer following this policy
+    pub fn buffer(&self) -> SpillBuffer {
+        SpillBuffer {
+            policy: self.clone(),
+            memory: Vec::new(),
+            file: None,
+            len: 0,
+        }
+    }
+}
+
+/// A body being collected
+pub struct SpillBuffer {
+    policy: SpillPolicy,
+    memory: Vec<u8>,
+    file: Option<(SpillFile, tokio::fs::File)>,
+    len: u64,
+}
+
+impl SpillBuffer {
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if self.file.is_none() && self.memory.len() + chunk.len() > self.policy.threshold {
+            tokio::fs::create_dir_all(&self.policy.dir).await?;
+            let spill = SpillFile {
+                path: self
+                    .policy
+                    .dir
+                    .join(format!("docker-proxy-spill-{}", uuid::Uuid::new_v4())),
+            };
+            let mut file = tokio::fs::File::create(&spill.path).await?;
+            file.write_all(&self.memory).await?;
+            self.memory = Vec::new();
+            tracing::debug!(path = %spill.path.display(), "Spilling body to disk");
+            self.file = Some((spill, file));
+        }
+        match &mut self.file {
+            Some((_, file)) => file.write_all(chunk).await?,
+            None => self.memory.extend_from_slice(chunk),
+        }
+        self.len += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// The complete body
+    pub async fn finish(self) -> io::Result<Spilled> {
+        match self.file {
+            Some((spill, mut file)) => {
+                file.flush().await?;
+                Ok(Spilled::File(Arc::new(spill), self.len))
+            }
+            None => Ok(Spilled::Memory(Bytes::from(self.memory))),
+        }
+    }
+}
+
+/// A complete body, in memory or in a temp file removed once the last clone
+/// is dropped
+#[derive(Clone)]
+pub enum Spilled {
+    Memory(Bytes),
+    File(Arc<SpillFile>, u64),
+}
+
+impl Spilled {
+    pub fn size(&self) -> u64 {
+        match self {
+            Spilled::Memory(bytes) => bytes.len() as u64,
+            Spilled::File(_, len) => *len,
+        }
+    }
+
+    /// Blocking reader over the body
+    pub fn reader(&self) -> io::Result<Box<dyn Read + Send + '_>> {
+        match self {
+            Spilled::Memory(bytes) => Ok(Box::new(&bytes[..])),
+            Spilled::File(spill, _) => Ok(Box::new(std::fs::File::open(&spill.path)?)),
+        }
+    }
+
+    /// Response body streaming the content
+    pub async fn into_body(self) -> io::Result<Body> {
+        match self {
+            Spilled::Memory(bytes) => Ok(Body::from(bytes)),
+            Spilled::File(spill, _) => {
+                let file = tokio::fs::File::open(&spill.path).await?;
+                // the temp file stays until the stream is dropped
+                let stream = ReaderStream::new(file).map(move |chunk| {
+                    let _ = &spill;
+                    chunk
+                });
+                Ok(Body::from_stream(stream))
+            }
+        }
+    }
+}
+
+/// Temp file holding a spilled body
+pub struct SpillFile {
+    path: PathBuf,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spill_beyond_threshold() {
+        let dir = std::env::temp_dir().join(format!("docker-proxy-spill-{}", uuid::Uuid::new_v4()));
+        let policy = SpillPolicy {
+            threshold: 8,
+            dir: dir.clone(),
+        };
+
+        let mut small = policy.buffer();
+        small.write(b"abcd").await.unwrap();
+        small.write(b"efgh").await.unwrap();
+        assert!(matches!(small.finish().await.unwrap(), Spilled::Memory(_)));
+
+        let mut large = policy.buffer();
+        large.write(b"abcdef").await.unwrap();
+        large.write(b"ghij").await.unwrap();
+        let spilled = large.finish().await.unwrap();
+        let Spilled::File(spill, _) = &spilled else {
+            panic!("expected a spilled body");
+        };
+        let path = spill.path.clone();
+        assert_eq!(spilled.size(), 10);
+
+        let mut content = Vec::new();
+        spilled.reader().unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"abcdefghij");
+        let body = spilled.clone().into_body().await.unwrap();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(&bytes[..], b"abcdefghij");
+
+        // Removed with the last reference
+        assert!(path.exists());
+        drop(spilled);
+        assert!(!path.exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -1,12 +1,15 @@
+use std::sync::Arc;
+
 use axum::{
     body::Body,
-    extract::Path,
+    extract::{Path, State},
     http::{HeaderMap, HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
 use bytes::Bytes;
 use tokio_util::io::ReaderStream;
 
+use crate::proxy::DockerProxy;
 use crate::range;
 
 /// 静态文件服务配置常量
@@ -52,17 +55,21 @@ pub fn get_content_type(path: &str) -> &'static str {
 }
 
 // 安全的静态文件服务：使用 canonicalize 和白名单防止路径穿越，支持流式传输和 Range 请求
-pub async fn serve_static(headers: HeaderMap, Path(file): Path<String>) -> impl IntoResponse {
-    use std::path::PathBuf;
-
+pub async fn serve_static(
+    State(proxy): State<Arc<DockerProxy>>,
+    headers: HeaderMap,
+    Path(file): Path<String>,
+) -> impl IntoResponse {
     // 白名单：只允许这些文件扩展名
     const ALLOWED_EXTENSIONS: &[&str] = &[
         "html", "htm", "css", "js", "json", "svg", "png", "jpg", "jpeg", "gif", "webp", "ico",
         "woff", "woff2", "ttf", "eot",
     ];
 
-    // 基础目录
-    let base_dir = PathBuf::from("/app/web");
+    // 基础目录（由配置或 profile 决定，可能是相对路径，规范化后再比较）
+    let Ok(base_dir) = tokio::fs::canonicalize(proxy.web_root()).await else {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    };
 
     // 清理和规范化路径
     let mut requested_path = file.trim_start_matches('/').to_string();
@@ -137,15 +144,31 @@ pub async fn serve_static(headers: HeaderMap, Path(file): Path<String>) -> impl
     // 根据文件扩展名确定 Content-Type
     let ctype = get_content_type(&requested_path);
 
-    // 检查是否是 Range 请求
-    let range_request = headers
-        .get(header::RANGE)
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| range::parse_range_header(s, file_size));
-
-    // 如果是 Range 请求，返回部分内容
-    if let Some(range) = range_request {
-        return serve_range(&canonical_path, range, file_size, ctype, &requested_path).await;
+    // 检查是否是 Range 请求；If-Range 与当前文件不匹配时返回完整文件
+    let validators = range::Validators::for_file(&metadata);
+    let range_request = range::evaluate_request(&headers, file_size, &validators);
+
+    // 如果是 Range 请求，返回部分内容；范围均不可满足时返回 416
+    match range_request {
+        range::RangeRequest::Full => {}
+        range::RangeRequest::Partial(ranges) => {
+            return serve_range(
+                &canonical_path,
+                ranges,
+                file_size,
+                ctype,
+                &validators,
+                &requested_path,
+            )
+            .await;
+        }
+        range::RangeRequest::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                range::unsatisfiable_headers(file_size),
+            )
+                .into_response();
+        }
     }
 
     // 构建响应头（完整文件）
@@ -176,6 +199,7 @@ pub async fn serve_static(headers: HeaderMap, Path(file): Path<String>) -> impl
     if let Ok(ar_value) = "bytes".parse() {
         response_headers.insert(header::ACCEPT_RANGES, ar_value);
     }
+    validators.insert_headers(&mut response_headers);
 
     use static_file_config::STREAM_THRESHOLD;
 
@@ -215,25 +239,16 @@ pub async fn serve_static(headers: HeaderMap, Path(file): Path<String>) -> impl
     }
 }
 
-// 处理 Range 请求，返回部分内容
+// 处理 Range 请求，返回部分内容（多个范围时使用 multipart/byteranges）
 pub async fn serve_range(
     file_path: &std::path::Path,
-    range: std::ops::Range<u64>,
+    ranges: Vec<std::ops::Range<u64>>,
     file_size: u64,
     content_type: &str,
+    validators: &range::Validators,
     requested_path: &str,
 ) -> Response {
-    use tokio::io::{AsyncReadExt, AsyncSeekExt};
-
-    let (status, headers) = match range::create_range_headers(&range, file_size, content_type) {
-        Ok(result) => result,
-        Err(_) => {
-            tracing::error!("Failed to create range headers");
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
-        }
-    };
-
-    let mut file = match tokio::fs::File::open(file_path).await {
+    let file = match tokio::fs::File::open(file_path).await {
         Ok(f) => f,
         Err(e) => {
             tracing::error!("Failed to open file for range request: {}", e);
@@ -241,40 +256,31 @@ pub async fn serve_range(
         }
     };
 
-    if let Err(e) = file.seek(std::io::SeekFrom::Start(range.start)).await {
-        tracing::error!("Failed to seek file: {}", e);
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
-    }
-
-    let range_length = range.end - range.start;
-
     tracing::debug!(
         file_path = %requested_path,
-        range_start = range.start,
-        range_end = range.end,
-        range_length = range_length,
+        ranges = ?ranges,
         "Serving range request"
     );
 
-    let mut buffer = vec![0u8; range_length as usize];
-    match file.read_exact(&mut buffer).await {
-        Ok(_) => {
-            let content = Bytes::from(buffer);
-            (status, headers, content).into_response()
+    match range::range_response(file, ranges, file_size, content_type, range::READ_CHUNK) {
+        Ok((status, mut headers, body)) => {
+            validators.insert_headers(&mut headers);
+            (status, headers, body).into_response()
         }
-        Err(e) => {
-            tracing::error!("Failed to read range from file: {}", e);
+        Err(_) => {
+            tracing::error!("Failed to create range headers");
             (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
         }
     }
 }
 
-// Serve the UI index at root (no redirect)
-pub async fn serve_root() -> impl IntoResponse {
-    let full = "/app/web/index.html".to_string();
+// Serve the UI index at root (no redirect). Under a path prefix the page
+// gets a <base> so its relative asset and API links resolve below it.
+pub async fn serve_root(State(proxy): State<Arc<DockerProxy>>) -> impl IntoResponse {
+    let full = proxy.web_root().join("index.html");
     match tokio::fs::read(&full).await {
         Ok(bytes) => {
-            let content = Bytes::from(bytes);
+            let content = Bytes::from(with_base_href(bytes, proxy.path_prefix()));
             let mut headers = HeaderMap::new();
             if let Ok(ct_value) = "text/html; charset=utf-8".parse() {
                 headers.insert(header::CONTENT_TYPE, ct_value);
@@ -294,11 +300,31 @@ pub async fn serve_root() -> impl IntoResponse {
     }
 }
 
+// Insert `<base href="<prefix>/">` right after the opening <head> tag
+fn with_base_href(html: Vec<u8>, prefix: &str) -> Vec<u8> {
+    if prefix.is_empty() {
+        return html;
+    }
+    let Some(pos) = html
+        .windows(6)
+        .position(|w| w.eq_ignore_ascii_case(b"<head>"))
+    else {
+        return html;
+    };
+    let base = format!("\n    <base href=\"{}/\">", prefix);
+    let mut out = Vec::with_capacity(html.len() + base.len());
+    out.extend_from_slice(&html[..pos + 6]);
+    out.extend_from_slice(base.as_bytes());
+    out.extend_from_slice(&html[pos + 6..]);
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_stream_threshold() {
         use static_file_config::STREAM_THRESHOLD;
 
@@ -339,4 +365,14 @@ mod tests {
         );
         assert_eq!(get_content_type("unknown.xyz"), "application/octet-stream");
     }
+
+    #[test]
+    fn test_with_base_href() {
+        let html = b"<html><head><title>x</title></head></html>".to_vec();
+        assert_eq!(with_base_href(html.clone(), ""), html);
+        assert_eq!(
+            String::from_utf8(with_base_href(html, "/registry-proxy")).unwrap(),
+            "<html><head>\n    <base href=\"/registry-proxy/\"><title>x</title></head></html>"
+        );
+    }
 }
@@ -0,0 +1,158 @@
+/// Distributed tracing over OpenTelemetry
+///
+/// With `[telemetry] enabled`, spans for client requests, upstream fetches
+/// and registry token exchanges are exported to an OTLP/HTTP collector. A
+/// client's W3C `traceparent` header makes its request span part of the
+/// client's trace, and upstream requests carry the proxy's own
+/// `traceparent`, so a slow pull can be followed through a chain of proxies
+/// to the registry.
+use std::collections::HashMap;
+
+use axum::http::HeaderMap;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::Registry;
+
+use crate::config::TelemetryConfig;
+
+/// Layer exporting spans, added to the logging subscriber
+pub type Layer = Box<dyn tracing_subscriber::Layer<Registry> + Send + Sync>;
+
+/// The exporter; spans still buffered are sent by `shutdown`
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// `None` unless telemetry is enabled
+    pub fn from_config(config: &TelemetryConfig) -> Result<Option<Self>, String> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(traces_endpoint(&config.endpoint))
+            .with_headers(config.headers.clone())
+            .build()
+            .map_err(|e| format!("Failed to create OTLP exporter: {}", e))?;
+        let sampler =
+            Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(sampler)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(config.service_name.clone())
+                    .build(),
+            )
+            .build();
+        Ok(Some(Self { provider }))
+    }
+
+    /// Layer turning spans into exported ones, to install with the logger
+    pub fn layer(&self) -> Layer {
+        Box::new(tracing_opentelemetry::layer().with_tracer(self.provider.tracer("docker-proxy")))
+    }
+
+    /// Export the spans still buffered
+    pub fn shutdown(&self) {
+        if let Err(e) = self.provider.shutdown() {
+            tracing::warn!("Failed to flush traces: {}", e);
+        }
+    }
+}
+
+/// Make `span` a child of the trace in a request's `traceparent`, if any
+pub fn continue_trace(span: &tracing::Span, headers: &HeaderMap) {
+    let context = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    let _ = span.set_parent(context);
+}
+
+/// `traceparent` (and `tracestate`) headers naming `span` as the parent of
+/// an outgoing request
+pub fn trace_headers(span: &tracing::Span) -> Vec<(String, String)> {
+    let mut headers = HeaderInjector(HashMap::new());
+    TraceContextPropagator::new().inject_context(&span.context(), &mut headers);
+    headers.0.into_iter().collect()
+}
+
+// "http://collector:4318" -> "http://collector:4318/v1/traces"; URLs with a
+// path are used as they are
+fn traces_endpoint(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    let path_start = endpoint
+        .split_once("://")
+        .map_or(0, |(scheme, _)| scheme.len() + 3);
+    if endpoint[path_start..].contains('/') {
+        endpoint.to_string()
+    } else {
+        format!("{}/v1/traces", endpoint)
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector(HashMap<String, String>);
+
+impl Injector for HeaderInjector {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_string(), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traces_endpoint() {
+        assert_eq!(
+            traces_endpoint("http://collector:4318"),
+            "http://collector:4318/v1/traces"
+        );
+        assert_eq!(
+            traces_endpoint("https://otlp.example.com/"),
+            "https://otlp.example.com/v1/traces"
+        );
+        assert_eq!(
+            traces_endpoint("https://otlp.example.com/api/v1/traces"),
+            "https://otlp.example.com/api/v1/traces"
+        );
+    }
+
+    #[test]
+    fn test_trace_headers() {
+        // without a tracing layer spans have no context to propagate
+        assert!(trace_headers(&tracing::Span::none()).is_empty());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+        let context = TraceContextPropagator::new().extract(&HeaderExtractor(&headers));
+        let mut injected = HeaderInjector(HashMap::new());
+        TraceContextPropagator::new().inject_context(&context, &mut injected);
+        assert_eq!(
+            injected.0["traceparent"],
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+/// HTTP clients for upstream registries
+///
+/// All upstreams share one client that trusts the system roots plus the
+/// `[proxy] ca_file` bundle. Registries listed under `[proxy.registries]`
+/// with their own `ca_file` or with `skip_tls_verify` get a client of their
+/// own, so an internal registry signed by a corporate CA (or with a
+/// self-signed certificate in a lab) does not weaken TLS for the others, as
+/// do registries with an `http_version` of their own and registries that
+/// require mutual TLS, whose client certificate is only presented to them. Timeouts, pooling,
+/// HTTP version, User-Agent and outbound proxy of every client follow
+/// `[client]`, except for registries marked `direct`, and all of them
+/// resolve hosts through the shared `[resolve]` resolver.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::{Certificate, Client, Identity, NoProxy, Proxy};
+
+use crate::config::{ClientConfig, HttpVersion, ProxyConfig, RegistryOptions};
+use crate::resolve::DnsResolver;
+
+pub struct UpstreamClients {
+    default: Client,
+    /// Clients keyed by registry host (with port, if any)
+    by_host: HashMap<String, Client>,
+}
+
+impl UpstreamClients {
+    /// Build the clients; a CA bundle that cannot be loaded is logged and
+    /// left out, so the affected upstreams fail verification instead of the
+    /// proxy failing to start
+    pub fn from_config(
+        config: &ProxyConfig,
+        client: &ClientConfig,
+        resolver: Arc<DnsResolver>,
+    ) -> Self {
+        let shared_roots = load_bundle(&config.ca_file);
+        let default = build_client(
+            client,
+            &RegistryOptions::default(),
+            &resolver,
+            &shared_roots,
+            None,
+        );
+
+        let by_host = config
+            .registries
+            .iter()
+            .filter(|(_, options)| {
+                !options.ca_file.is_empty()
+                    || options.skip_tls_verify
+                    || options.http_version.is_some()
+                    || !options.client_cert_file.is_empty()
+                    || options.direct
+            })
+            .map(|(host, options)| {
+                if options.skip_tls_verify {
+                    tracing::warn!(registry = %host, "TLS certificate verification disabled");
+                }
+                let mut roots = shared_roots.clone();
+                roots.extend(load_bundle(&options.ca_file));
+                let identity = load_identity(&options.client_cert_file, &options.client_key_file);
+                (
+                    host.clone(),
+                    build_client(client, options, &resolver, &roots, identity),
+                )
+            })
+            .collect();
+
+        Self { default, by_host }
+    }
+
+    /// Client for requests to `url`
+    pub fn for_url(&self, url: &str) -> &Client {
+        if self.by_host.is_empty() {
+            return &self.default;
+        }
+        reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| {
+                let host = url.host_str()?;
+                match url.port() {
+                    Some(port) => self.by_host.get(&format!("{}:{}", host, port)),
+                    None => self.by_host.get(host),
+                }
+            })
+            .unwrap_or(&self.default)
+    }
+
+    /// Client for hosts without options of their own
+    pub fn shared(&self) -> &Client {
+        &self.default
+    }
+}
+
+// Certificates from a PEM bundle; empty when no file is configured
+fn load_bundle(path: &str) -> Vec<Certificate> {
+    if path.is_empty() {
+        return Vec::new();
+    }
+    let certificates = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|pem| Certificate::from_pem_bundle(&pem).map_err(|e| e.to_string()));
+    match certificates {
+        Ok(certificates) if !certificates.is_empty() => {
+            tracing::info!(
+                "Loaded {} CA certificate(s) from {}",
+                certificates.len(),
+                path
+            );
+            certificates
+        }
+        Ok(_) => {
+            tracing::error!("CA bundle {} contains no certificates", path);
+            Vec::new()
+        }
+        Err(e) => {
+            tracing::error!("Failed to load CA bundle {}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+// Client certificate and key for mutual TLS; None when not configured or
+// unusable, in which case the registry will refuse the handshake
+fn load_identity(cert_file: &str, key_file: &str) -> Option<Identity> {
+    if cert_file.is_empty() {
+        return None;
+    }
+    let identity = std::fs::read(cert_file)
+        .and_then(|cert| Ok((cert, std::fs::read(key_file)?)))
+        .map_err(|e| e.to_string())
+        .and_then(|(cert, key)| Identity::from_pkcs8_pem(&cert, &key).map_err(|e| e.to_string()));
+    match identity {
+        Ok(identity) => {
+            tracing::info!("Loaded client certificate from {}", cert_file);
+            Some(identity)
+        }
+        Err(e) => {
+            tracing::error!("Failed to load client certificate {}: {}", cert_file, e);
+            None
+        }
+    }
+}
+
+// Client without automatic content decoding to preserve blob sizes
+fn build_client(
+    config: &ClientConfig,
+    registry: &RegistryOptions,
+    resolver: &Arc<DnsResolver>,
+    roots: &[Certificate],
+    identity: Option<Identity>,
+) -> Client {
+    let mut builder = Client::builder()
+        .dns_resolver(Arc::clone(resolver))
+        .no_gzip()
+        .no_brotli()
+        .no_deflate()
+        .danger_accept_invalid_certs(registry.skip_tls_verify)
+        .pool_idle_timeout(secs(config.pool_idle_timeout_secs));
+    if let Some(timeout) = secs(config.connect_timeout_secs) {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = secs(config.timeout_secs) {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(max) = config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    builder = match registry.http_version.unwrap_or(config.http_version) {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+    // Without an outbound proxy configured, reqwest reads HTTP_PROXY,
+    // HTTPS_PROXY, ALL_PROXY and NO_PROXY from the environment
+    if registry.direct {
+        builder = builder.no_proxy();
+    } else if let Some(proxy) = outbound_proxy(config) {
+        builder = builder.proxy(proxy);
+    }
+    if !config.user_agent.is_empty() {
+        builder = builder.user_agent(&config.user_agent);
+    }
+    if let Some(identity) = identity {
+        builder = builder.identity(identity);
+    }
+    for certificate in roots {
+        builder = builder.add_root_certificate(certificate.clone());
+    }
+    builder.build().unwrap_or_else(|e| {
+        tracing::warn!("Failed to build custom client, using default: {}", e);
+        Client::new()
+    })
+}
+
+// The configured outbound proxy, if any
+fn outbound_proxy(config: &ClientConfig) -> Option<Proxy> {
+    if config.outbound_proxy.is_empty() {
+        return None;
+    }
+    match Proxy::all(&config.outbound_proxy) {
+        Ok(proxy) => {
+            let no_proxy = NoProxy::from_string(&config.outbound_no_proxy.join(","));
+            Some(proxy.no_proxy(no_proxy))
+        }
+        Err(e) => {
+            tracing::error!("Invalid outbound proxy, connecting directly: {}", e);
+            None
+        }
+    }
+}
+
+// 0 means no limit
+fn secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ResolveConfig;
+
+    #[test]
+    fn test_clients_per_host() {
+        let config: ProxyConfig = toml::from_str(
+            r#"
+default = "registry-1.docker.io"
+
+[registries."registry.lab:5000"]
+skip_tls_verify = true
+
+[registries."localhost:5000"]
+insecure = true
+
+[registries."ghcr.io"]
+http_version = "http1"
+
+[registries."registry.corp.example"]
+direct = true
+"#,
+        )
+        .unwrap();
+        let resolver = DnsResolver::new(&ResolveConfig::default(), crate::clock::system());
+        let clients =
+            UpstreamClients::from_config(&config, &ClientConfig::default(), Arc::new(resolver));
+        assert_eq!(clients.by_host.len(), 3);
+        assert!(std::ptr::eq(
+            clients.for_url("https://registry.lab:5000/v2/team/app/manifests/v1"),
+            &clients.by_host["registry.lab:5000"]
+        ));
+        assert!(std::ptr::eq(
+            clients.for_url("https://registry.lab/v2/"),
+            &clients.default
+        ));
+        assert!(std::ptr::eq(
+            clients.for_url("https://ghcr.io/v2/owner/repo/blobs/sha256:abc"),
+            &clients.by_host["ghcr.io"]
+        ));
+        assert!(std::ptr::eq(clients.for_url("not a url"), &clients.default));
+    }
+
+    #[test]
+    fn test_load_bundle() {
+        assert!(load_bundle("").is_empty());
+        assert!(load_bundle("/nonexistent/ca.pem").is_empty());
+
+        let path =
+            std::env::temp_dir().join(format!("docker-proxy-ca-{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "not a certificate").unwrap();
+        assert!(load_bundle(&path.to_string_lossy()).is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_load_identity() {
+        assert!(load_identity("", "").is_none());
+        assert!(load_identity("/nonexistent/client.pem", "/nonexistent/client.key").is_none());
+
+        let path =
+            std::env::temp_dir().join(format!("docker-proxy-client-{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "not a certificate").unwrap();
+        let path = path.to_string_lossy();
+        assert!(load_identity(&path, &path).is_none());
+        let _ = std::fs::remove_file(path.as_ref());
+    }
+}
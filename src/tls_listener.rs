@@ -0,0 +1,259 @@
+/// HTTPS listener with optional client certificate authentication
+///
+/// With `[server.tls]` configured, connections are accepted over TLS. Each
+/// handshake runs in its own task, so a slow or stalled client does not hold
+/// up the others. With `client_ca_file` set, clients are asked for a
+/// certificate signed by one of those CAs (required unless
+/// `require_client_cert = false`), and the identity in a verified
+/// certificate, its subject CN or first SAN, is passed to handlers as
+/// [`ClientConnection`] connect info, to be treated as the authenticated
+/// user. With `[server] proxy_protocol` the PROXY protocol header is read
+/// before the handshake.
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use rustls::RootCertStore;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+use x509_parser::extensions::GeneralName;
+
+use crate::config::{CertIdentity, ServerTlsConfig};
+use crate::proxy_protocol;
+
+/// How long a client may take to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, ClientConnection)>,
+}
+
+/// What the listener knows about a connection's client; the listener's
+/// address type, so it is available as connect info
+#[derive(Debug, Clone, Default)]
+pub struct ClientConnection {
+    /// Identity in the verified client certificate, if any
+    pub identity: Option<String>,
+    /// Client address from the PROXY protocol header, if any
+    pub source: Option<SocketAddr>,
+}
+
+impl TlsListener {
+    /// Accept TLS connections on `listener`, naming clients by the `identity`
+    /// in their certificates, after a PROXY protocol header if
+    /// `proxy_protocol` is set
+    pub fn new(
+        listener: TcpListener,
+        server: Arc<rustls::ServerConfig>,
+        identity: CertIdentity,
+        proxy_protocol: bool,
+    ) -> Self {
+        let acceptor = TlsAcceptor::from(server);
+        let (sender, connections) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, addr) = tokio::select! {
+                    // serving has stopped; release the socket
+                    _ = sender.closed() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::warn!("Failed to accept connection: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                };
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let source = if proxy_protocol {
+                        let header = proxy_protocol::read_header(&mut stream);
+                        match tokio::time::timeout(proxy_protocol::HEADER_TIMEOUT, header).await {
+                            Ok(Ok(source)) => source,
+                            Ok(Err(e)) => {
+                                tracing::debug!(peer = %addr, "Dropping connection: {}", e);
+                                return;
+                            }
+                            Err(_) => {
+                                tracing::debug!(peer = %addr, "PROXY protocol header timed out");
+                                return;
+                            }
+                        }
+                    } else {
+                        None
+                    };
+                    let addr = source.unwrap_or(addr);
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let identity = stream
+                                .get_ref()
+                                .1
+                                .peer_certificates()
+                                .and_then(|chain| chain.first())
+                                .and_then(|certificate| identity_of(certificate, identity));
+                            let connection = ClientConnection { identity, source };
+                            let _ = sender.send((stream, connection)).await;
+                        }
+                        Ok(Err(e)) => {
+                            tracing::debug!(client = %addr, "TLS handshake failed: {}", e)
+                        }
+                        Err(_) => tracing::debug!(client = %addr, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+        Self { connections }
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = ClientConnection;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // the accept task only stops once this listener is gone
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(ClientConnection::default())
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientConnection {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        stream.remote_addr().clone()
+    }
+}
+
+/// Server configuration from the certificate, key and client CA files
+pub fn server_config(config: &ServerTlsConfig) -> Result<Arc<rustls::ServerConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let chain = CertificateDer::pem_file_iter(&config.cert_file)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to load {}: {}", config.cert_file, e))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_file)
+        .map_err(|e| format!("Failed to load {}: {}", config.key_file, e))?;
+    let builder = rustls::ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = if config.verifies_clients() {
+        let mut roots = RootCertStore::empty();
+        let certificates = CertificateDer::pem_file_iter(&config.client_ca_file)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to load {}: {}", config.client_ca_file, e))?;
+        let (added, _) = roots.add_parsable_certificates(certificates);
+        if added == 0 {
+            return Err(format!(
+                "{} contains no CA certificates",
+                config.client_ca_file
+            ));
+        }
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+        let verifier = if config.require_client_cert {
+            verifier
+        } else {
+            verifier.allow_unauthenticated()
+        };
+        builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+    } else {
+        builder.with_no_client_auth()
+    };
+    let mut server = builder
+        .with_single_cert(chain, key)
+        .map_err(|e| format!("Invalid server certificate or key: {}", e))?;
+    server.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(server))
+}
+
+// User name in a client certificate
+fn identity_of(certificate: &CertificateDer<'_>, source: CertIdentity) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+    let identity = match source {
+        CertIdentity::Cn => certificate
+            .subject()
+            .iter_common_name()
+            .next()?
+            .as_str()
+            .ok()?
+            .to_string(),
+        CertIdentity::San => certificate
+            .subject_alternative_name()
+            .ok()??
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None,
+            })?,
+    };
+    (!identity.is_empty()).then_some(identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Self-signed, CN=ci-runner, SAN email:ci@example.com, DNS:runner.example.com
+    const CLIENT_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIB0zCCAXqgAwIBAgIUbt/2S5kS+nrTpdZ5bnogShvyyQUwCgYIKoZIzj0EAwIw
+JjEQMA4GA1UECgwHRXhhbXBsZTESMBAGA1UEAwwJY2ktcnVubmVyMCAXDTI2MTAx
+NzA3MDA0MVoYDzIxMjYwOTIzMDcwMDQxWjAmMRAwDgYDVQQKDAdFeGFtcGxlMRIw
+EAYDVQQDDAljaS1ydW5uZXIwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAARQ6ILW
+6dZyqEigEcaJOlLYE6YCtFhSp219GqmPzRHrbAjwDmW0Yjt0IaTrZrdbEDdEp0wz
+Oo/VWkoCmjfBiVgHo4GDMIGAMB0GA1UdDgQWBBSBwUcs9h7SA0FpfYfOaZMe5WeX
+LDAfBgNVHSMEGDAWgBSBwUcs9h7SA0FpfYfOaZMe5WeXLDAPBgNVHRMBAf8EBTAD
+AQH/MC0GA1UdEQQmMCSBDmNpQGV4YW1wbGUuY29tghJydW5uZXIuZXhhbXBsZS5j
+b20wCgYIKoZIzj0EAwIDRwAwRAIgRIoWzcRxNHnjVCnuWhiv+JjLHScRgybPjVhN
+cKaC00ECIDD7WVMz2OVACcX/EJcVkU+zrNQrVM4fRws90L4DWA39
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_identity_of() {
+        let certificate = CertificateDer::from_pem_slice(CLIENT_CERT.as_bytes()).unwrap();
+        assert_eq!(
+            identity_of(&certificate, CertIdentity::Cn).as_deref(),
+            Some("ci-runner")
+        );
+        assert_eq!(
+            identity_of(&certificate, CertIdentity::San).as_deref(),
+            Some("ci@example.com")
+        );
+        assert_eq!(
+            identity_of(&CertificateDer::from(b"garbage".to_vec()), CertIdentity::Cn),
+            None
+        );
+    }
+
+    #[test]
+    fn test_server_config_errors() {
+        let config = ServerTlsConfig {
+            cert_file: "/nonexistent/server.pem".to_string(),
+            key_file: "/nonexistent/server.key".to_string(),
+            ..ServerTlsConfig::default()
+        };
+        assert!(server_config(&config).is_err());
+
+        let invalid = ServerTlsConfig {
+            client_ca_file: "/config/ca.pem".to_string(),
+            ..ServerTlsConfig::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
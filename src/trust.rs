@@ -0,0 +1,270 @@
+/// Content trust (TUF) metadata passthrough
+///
+/// Docker Content Trust clients fetch signed metadata from a Notary server at
+/// `/v2/<gun>/_trust/tuf/<role>.json`. With `[trust]` enabled these requests
+/// are forwarded to the configured server and the bodies relayed byte for
+/// byte, since clients verify their signatures. Responses are kept in memory
+/// only as long as the server's `Cache-Control` allows, and hits carry an
+/// `Age` header so clients see the same freshness as from the server.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use bytes::Bytes;
+
+use crate::clock::Clock;
+use crate::config::TrustConfig;
+
+/// Response headers relayed to clients and kept with cached metadata
+const RELAYED_HEADERS: [header::HeaderName; 5] = [
+    header::CONTENT_TYPE,
+    header::CACHE_CONTROL,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::EXPIRES,
+];
+
+/// A metadata response as relayed to the client
+#[derive(Clone)]
+pub struct TrustResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+struct Entry {
+    response: TrustResponse,
+    stored_at: Instant,
+    max_age: Duration,
+}
+
+pub struct TrustMetadata {
+    server: String,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl TrustMetadata {
+    /// `None` unless trust metadata passthrough is enabled
+    pub fn from_config(config: &TrustConfig, clock: Arc<dyn Clock>) -> Option<Self> {
+        config.enabled.then(|| Self {
+            server: config.server_url().to_string(),
+            max_entries: config.cache_entries,
+            entries: Mutex::new(HashMap::new()),
+            clock,
+        })
+    }
+
+    /// Trust server URL, without a trailing slash
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    /// A fresh cached response for `gun` and `file`, with its `Age` header set
+    pub fn lookup(&self, gun: &str, file: &str) -> Option<TrustResponse> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let key = cache_key(gun, file);
+        let entry = entries.get(&key)?;
+        let age = self.clock.instant().duration_since(entry.stored_at);
+        if age >= entry.max_age {
+            entries.remove(&key);
+            return None;
+        }
+        let mut response = entry.response.clone();
+        response
+            .headers
+            .insert(header::AGE, HeaderValue::from(age.as_secs()));
+        Some(response)
+    }
+
+    /// Build the client response from upstream headers and body, caching it
+    /// when it is a success the server allows shared caches to store
+    pub fn store(
+        &self,
+        gun: &str,
+        file: &str,
+        status: StatusCode,
+        upstream_headers: &reqwest::header::HeaderMap,
+        body: Bytes,
+    ) -> TrustResponse {
+        let mut headers = HeaderMap::new();
+        for name in RELAYED_HEADERS {
+            if let Some(value) = upstream_headers
+                .get(name.as_str())
+                .and_then(|v| HeaderValue::from_bytes(v.as_bytes()).ok())
+            {
+                headers.insert(name, value);
+            }
+        }
+        let response = TrustResponse {
+            status,
+            headers,
+            body,
+        };
+
+        let max_age = response
+            .headers
+            .get(header::CACHE_CONTROL)
+            .and_then(|v| v.to_str().ok())
+            .and_then(shared_max_age);
+        if status == StatusCode::OK
+            && self.max_entries > 0
+            && let Some(max_age) = max_age.filter(|age| *age > 0)
+        {
+            let now = self.clock.instant();
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            entries.retain(|_, entry| now.duration_since(entry.stored_at) < entry.max_age);
+            if entries.len() >= self.max_entries
+                && let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.stored_at)
+                    .map(|(key, _)| key.clone())
+            {
+                entries.remove(&oldest);
+            }
+            entries.insert(
+                cache_key(gun, file),
+                Entry {
+                    response: response.clone(),
+                    stored_at: now,
+                    max_age: Duration::from_secs(max_age),
+                },
+            );
+        }
+        response
+    }
+}
+
+fn cache_key(gun: &str, file: &str) -> String {
+    format!("{}/{}", gun, file)
+}
+
+// Seconds a shared cache may keep a response under its Cache-Control header;
+// `None` when it must not be stored or revalidated without the server
+fn shared_max_age(cache_control: &str) -> Option<u64> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in cache_control.split(',') {
+        let directive = directive.trim().to_ascii_lowercase();
+        match directive.split_once('=') {
+            Some(("max-age", value)) => max_age = value.trim_matches('"').parse().ok(),
+            Some(("s-maxage", value)) => s_maxage = value.trim_matches('"').parse().ok(),
+            _ if matches!(directive.as_str(), "no-store" | "no-cache" | "private") => {
+                return None;
+            }
+            _ => {}
+        }
+    }
+    s_maxage.or(max_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn trust(cache_entries: usize, clock: Arc<ManualClock>) -> TrustMetadata {
+        TrustMetadata::from_config(
+            &TrustConfig {
+                enabled: true,
+                cache_entries,
+                ..TrustConfig::default()
+            },
+            clock,
+        )
+        .unwrap()
+    }
+
+    fn upstream_headers(cache_control: &str) -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("cache-control", cache_control.parse().unwrap());
+        headers.insert("x-other", "dropped".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_shared_max_age() {
+        assert_eq!(shared_max_age("max-age=300"), Some(300));
+        assert_eq!(shared_max_age("public, max-age=300, s-maxage=60"), Some(60));
+        assert_eq!(shared_max_age("no-store"), None);
+        assert_eq!(shared_max_age("max-age=300, private"), None);
+        assert_eq!(shared_max_age("must-revalidate"), None);
+    }
+
+    #[test]
+    fn test_store_and_lookup() {
+        let clock = ManualClock::new(0);
+        let trust = trust(1, clock.clone());
+        let gun = "docker.io/library/nginx";
+        let body = Bytes::from_static(br#"{"signed":{},"signatures":[]}"#);
+
+        let response = trust.store(
+            gun,
+            "root.json",
+            StatusCode::OK,
+            &upstream_headers("max-age=300"),
+            body.clone(),
+        );
+        assert_eq!(response.body, body);
+        assert!(response.headers.get("x-other").is_none());
+
+        let cached = trust.lookup(gun, "root.json").unwrap();
+        assert_eq!(cached.body, body);
+        assert_eq!(cached.headers[header::CACHE_CONTROL], "max-age=300");
+        assert_eq!(cached.headers[header::AGE], "0");
+        clock.advance(Duration::from_secs(120));
+        let cached = trust.lookup(gun, "root.json").unwrap();
+        assert_eq!(cached.headers[header::AGE], "120");
+
+        // Uncacheable responses and failures are relayed but not stored
+        trust.store(
+            gun,
+            "timestamp.json",
+            StatusCode::OK,
+            &upstream_headers("no-cache"),
+            body.clone(),
+        );
+        trust.store(
+            gun,
+            "targets/releases.json",
+            StatusCode::NOT_FOUND,
+            &upstream_headers("max-age=300"),
+            body.clone(),
+        );
+        assert!(trust.lookup(gun, "timestamp.json").is_none());
+        assert!(trust.lookup(gun, "targets/releases.json").is_none());
+
+        // The oldest entry makes room once the cache is full
+        trust.store(
+            gun,
+            "targets.json",
+            StatusCode::OK,
+            &upstream_headers("max-age=300"),
+            body,
+        );
+        assert!(trust.lookup(gun, "targets.json").is_some());
+        assert!(trust.lookup(gun, "root.json").is_none());
+    }
+
+    #[test]
+    fn test_entries_expire_after_max_age() {
+        let clock = ManualClock::new(0);
+        let trust = trust(10, clock.clone());
+        let gun = "docker.io/library/nginx";
+        trust.store(
+            gun,
+            "timestamp.json",
+            StatusCode::OK,
+            &upstream_headers("s-maxage=60"),
+            Bytes::from_static(b"{}"),
+        );
+
+        clock.advance(Duration::from_secs(59));
+        assert!(trust.lookup(gun, "timestamp.json").is_some());
+        clock.advance(Duration::from_secs(1));
+        assert!(trust.lookup(gun, "timestamp.json").is_none());
+    }
+}
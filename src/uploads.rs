@@ -0,0 +1,257 @@
+/// Tracking of in-progress blob upload sessions
+///
+/// Every upstream upload response carries the session URL (`Location`) and
+/// the bytes received so far (`Range`). Recording them per upload UUID lets a
+/// client that lost its state resume with `GET /v2/<name>/blobs/uploads/<uuid>`,
+/// even when the request no longer carries the upstream's `_state` parameter.
+/// A session is only found under the repository it was started for, and
+/// listings show a UUID prefix, so it cannot be resumed from elsewhere.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use reqwest::Url;
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+
+use crate::clock::Clock;
+
+/// Sessions idle for longer than this are dropped
+const SESSION_TTL_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadSession {
+    /// Listed by its first characters only
+    #[serde(serialize_with = "serialize_uuid_prefix")]
+    pub uuid: String,
+    /// Repository name as used by the client
+    pub name: String,
+    /// Absolute upstream session URL, including its query
+    #[serde(skip)]
+    pub upstream_location: String,
+    /// Number of bytes the upstream has acknowledged
+    pub offset: u64,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+impl UploadSession {
+    /// `Range` header value for the received bytes (`0-0` when empty, as the
+    /// reference registry does)
+    pub fn range(&self) -> String {
+        format!("0-{}", self.offset.saturating_sub(1))
+    }
+}
+
+pub struct UploadSessions {
+    sessions: Mutex<HashMap<String, UploadSession>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl UploadSessions {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Record the session state from an upstream upload response made to
+    /// `url`. Responses without a session `Location` are ignored.
+    pub fn record(&self, name: &str, url: &Url, headers: &HeaderMap) {
+        let Some(location) = headers
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|l| url.join(l).ok())
+        else {
+            return;
+        };
+        let Some(uuid) = session_uuid(&location) else {
+            return;
+        };
+        let offset = headers
+            .get(reqwest::header::RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_received_range)
+            .unwrap_or(0);
+
+        let now = self.clock.now_secs();
+        let mut sessions = self.lock();
+        sessions.retain(|_, s| now.saturating_sub(s.updated_at) < SESSION_TTL_SECS);
+        let session = sessions
+            .entry(uuid.clone())
+            .or_insert_with(|| UploadSession {
+                uuid,
+                name: name.to_string(),
+                upstream_location: String::new(),
+                offset: 0,
+                started_at: now,
+                updated_at: now,
+            });
+        session.upstream_location = location.to_string();
+        session.offset = offset;
+        session.updated_at = now;
+    }
+
+    /// The session `uuid` of repository `name`
+    pub fn get(&self, name: &str, uuid: &str) -> Option<UploadSession> {
+        self.lock().get(uuid).filter(|s| s.name == name).cloned()
+    }
+
+    /// Sessions still in progress, oldest first
+    pub fn snapshot(&self) -> Vec<UploadSession> {
+        let now = self.clock.now_secs();
+        let mut sessions = self.lock();
+        sessions.retain(|_, s| now.saturating_sub(s.updated_at) < SESSION_TTL_SECS);
+        let mut list: Vec<UploadSession> = sessions.values().cloned().collect();
+        list.sort_by(|a, b| a.started_at.cmp(&b.started_at).then(a.uuid.cmp(&b.uuid)));
+        list
+    }
+
+    /// Forget a finished or abandoned session
+    pub fn complete(&self, uuid: &str) {
+        self.lock().remove(uuid);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, UploadSession>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn serialize_uuid_prefix<S: serde::Serializer>(
+    uuid: &str,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let end = uuid.char_indices().nth(8).map_or(uuid.len(), |(i, _)| i);
+    serializer.serialize_str(&uuid[..end])
+}
+
+// The upload UUID is the last segment of `.../blobs/uploads/<uuid>`
+fn session_uuid(location: &Url) -> Option<String> {
+    let mut segments = location.path_segments()?.rev();
+    let uuid = segments.next().filter(|s| !s.is_empty())?;
+    (segments.next() == Some("uploads") && segments.next() == Some("blobs"))
+        .then(|| uuid.to_string())
+}
+
+/// Parse an upload `Range: 0-<last>` header into the number of bytes received
+fn parse_received_range(value: &str) -> Option<u64> {
+    let (start, end) = value.trim().trim_start_matches("bytes=").split_once('-')?;
+    if start.trim() != "0" {
+        return None;
+    }
+    end.trim().parse::<u64>().ok()?.checked_add(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, ManualClock};
+
+    fn headers(location: &str, range: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::LOCATION, location.parse().unwrap());
+        if let Some(range) = range {
+            headers.insert(reqwest::header::RANGE, range.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_received_range() {
+        assert_eq!(parse_received_range("0-1023"), Some(1024));
+        assert_eq!(parse_received_range("bytes=0-0"), Some(1));
+        assert_eq!(parse_received_range("10-20"), None);
+        assert_eq!(parse_received_range("garbage"), None);
+        assert_eq!(parse_received_range("0-18446744073709551615"), None);
+    }
+
+    #[test]
+    fn test_record_and_complete() {
+        let sessions = UploadSessions::new(clock::system());
+        let url = Url::parse("https://ghcr.io/v2/owner/repo/blobs/uploads/").unwrap();
+
+        sessions.record(
+            "ghcr.io/owner/repo",
+            &url,
+            &headers(
+                "/v2/owner/repo/blobs/uploads/abc-123?_state=s1",
+                Some("0-0"),
+            ),
+        );
+        let session = sessions.get("ghcr.io/owner/repo", "abc-123").unwrap();
+        assert_eq!(session.name, "ghcr.io/owner/repo");
+        assert_eq!(
+            session.upstream_location,
+            "https://ghcr.io/v2/owner/repo/blobs/uploads/abc-123?_state=s1"
+        );
+
+        // a chunk moves the offset and the upstream state forward
+        sessions.record(
+            "ghcr.io/owner/repo",
+            &url,
+            &headers(
+                "/v2/owner/repo/blobs/uploads/abc-123?_state=s2",
+                Some("0-4095"),
+            ),
+        );
+        let session = sessions.get("ghcr.io/owner/repo", "abc-123").unwrap();
+        assert_eq!(session.offset, 4096);
+        assert_eq!(session.range(), "0-4095");
+        assert!(session.upstream_location.ends_with("_state=s2"));
+
+        let snapshot = sessions.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        let json = serde_json::to_value(&snapshot[0]).unwrap();
+        assert_eq!(json["offset"], 4096);
+        assert_eq!(json["uuid"], "abc-123");
+        let long = UploadSession {
+            uuid: "0123456789abcdef".to_string(),
+            ..snapshot[0].clone()
+        };
+        assert_eq!(serde_json::to_value(&long).unwrap()["uuid"], "01234567");
+        // sessions are bound to their repository
+        assert!(sessions.get("owner/other", "abc-123").is_none());
+        // the upstream URL carries upstream state and is not exposed
+        assert!(json.get("upstream_location").is_none());
+
+        sessions.complete("abc-123");
+        assert!(sessions.get("ghcr.io/owner/repo", "abc-123").is_none());
+        assert!(sessions.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_record_ignores_non_session_locations() {
+        let sessions = UploadSessions::new(clock::system());
+        let url = Url::parse("https://ghcr.io/v2/owner/repo/blobs/uploads/abc").unwrap();
+        sessions.record(
+            "owner/repo",
+            &url,
+            &headers("/v2/owner/repo/blobs/sha256:abc", None),
+        );
+        assert!(sessions.lock().is_empty());
+    }
+
+    #[test]
+    fn test_idle_sessions_expire() {
+        let clock = ManualClock::new(1_700_000_000);
+        let sessions = UploadSessions::new(clock.clone());
+        let url = Url::parse("https://ghcr.io/v2/owner/repo/blobs/uploads/").unwrap();
+        let location = "/v2/owner/repo/blobs/uploads/abc-123";
+
+        sessions.record("owner/repo", &url, &headers(location, Some("0-0")));
+        assert_eq!(
+            sessions.get("owner/repo", "abc-123").unwrap().started_at,
+            1_700_000_000
+        );
+
+        clock.advance(std::time::Duration::from_secs(SESSION_TTL_SECS));
+        sessions.record("owner/repo", &url, &headers(location, Some("0-1023")));
+        let session = sessions.get("owner/repo", "abc-123").unwrap();
+        assert_eq!(session.updated_at, 1_700_000_000 + SESSION_TTL_SECS);
+        assert_eq!(session.offset, 1024);
+
+        clock.advance(std::time::Duration::from_secs(SESSION_TTL_SECS));
+        assert!(sessions.snapshot().is_empty());
+        assert!(sessions.get("owner/repo", "abc-123").is_none());
+    }
+}
@@ -0,0 +1,250 @@
+/// Upstream latency and error metrics
+///
+/// Every request the proxy sends to a registry is timed until its response
+/// headers arrive, including any token exchange it needed, and recorded in
+/// a latency histogram per registry host. Timeouts, other network errors,
+/// 5xx responses and authentication failures are counted per host too, so a
+/// registry slowing down or failing pulls stands out, as are blobs whose
+/// content did not match their digest. Exposed as Prometheus metrics at
+/// `/metrics`.
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::egress::label_value;
+use crate::error::{ProxyError, ProxyResult};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Why an upstream request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamError {
+    Timeout,
+    Network,
+    ServerError,
+    Auth,
+}
+
+impl UpstreamError {
+    const ALL: [UpstreamError; 4] = [
+        UpstreamError::Timeout,
+        UpstreamError::Network,
+        UpstreamError::ServerError,
+        UpstreamError::Auth,
+    ];
+
+    /// Name used in metrics and the dashboard
+    pub fn label(self) -> &'static str {
+        match self {
+            UpstreamError::Timeout => "timeout",
+            UpstreamError::Network => "network",
+            UpstreamError::ServerError => "server_error",
+            UpstreamError::Auth => "auth",
+        }
+    }
+
+    /// The failure, if any, of an upstream request's outcome
+    pub fn classify(result: &ProxyResult<reqwest::Response>) -> Option<Self> {
+        match result {
+            Ok(response) if response.status().is_server_error() => Some(Self::ServerError),
+            Ok(response)
+                if matches!(
+                    response.status(),
+                    reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+                ) =>
+            {
+                Some(Self::Auth)
+            }
+            Ok(_) => None,
+            Err(ProxyError::Network(e)) if e.is_timeout() => Some(Self::Timeout),
+            Err(ProxyError::UpstreamTimeout(_)) => Some(Self::Timeout),
+            Err(ProxyError::Network(_)) => Some(Self::Network),
+            Err(ProxyError::AuthenticationFailed(_)) => Some(Self::Auth),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Latencies and failures of one registry host
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HostMetrics {
+    /// Requests per bucket of `BUCKETS`, the last one past all bounds
+    pub buckets: [u64; BUCKETS.len() + 1],
+    pub count: u64,
+    pub sum_secs: f64,
+    /// Failures in the order of `UpstreamError::ALL`
+    pub errors: [u64; UpstreamError::ALL.len()],
+    /// How the most recent request failed, if it did
+    pub last_error: Option<UpstreamError>,
+    /// Blobs whose content did not match their digest
+    pub digest_mismatches: u64,
+}
+
+impl HostMetrics {
+    /// Failures of one kind
+    pub fn errors(&self, kind: UpstreamError) -> u64 {
+        self.errors[kind as usize]
+    }
+
+    /// Failures of any kind
+    pub fn total_errors(&self) -> u64 {
+        self.errors.iter().sum()
+    }
+}
+
+#[derive(Default)]
+pub struct UpstreamMetrics {
+    hosts: Mutex<BTreeMap<String, HostMetrics>>,
+}
+
+impl UpstreamMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a request to `host` that took `elapsed`
+    pub fn record(&self, host: &str, elapsed: Duration, error: Option<UpstreamError>) {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let metrics = match hosts.get_mut(host) {
+            Some(metrics) => metrics,
+            None => hosts.entry(host.to_string()).or_default(),
+        };
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(BUCKETS.len());
+        metrics.buckets[bucket] += 1;
+        metrics.count += 1;
+        metrics.sum_secs += secs;
+        if let Some(error) = error {
+            metrics.errors[error as usize] += 1;
+        }
+        metrics.last_error = error;
+    }
+
+    /// Record a blob from `host` whose content did not match its digest
+    pub fn record_digest_mismatch(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        hosts.entry(host.to_string()).or_default().digest_mismatches += 1;
+    }
+
+    /// Metrics by registry host
+    pub fn snapshot(&self) -> BTreeMap<String, HostMetrics> {
+        self.hosts.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// Render metrics in the Prometheus text exposition format
+pub fn to_prometheus(hosts: &BTreeMap<String, HostMetrics>) -> String {
+    let mut metrics = String::new();
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_request_duration_seconds Time until an upstream registry's response headers arrived\n\
+         # TYPE docker_proxy_upstream_request_duration_seconds histogram\n",
+    );
+    for (host, host_metrics) in hosts {
+        let registry = label_value(host);
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(&host_metrics.buckets) {
+            cumulative += count;
+            metrics.push_str(&format!(
+                "docker_proxy_upstream_request_duration_seconds_bucket{{registry=\"{}\",le=\"{}\"}} {}\n",
+                registry, bound, cumulative
+            ));
+        }
+        metrics.push_str(&format!(
+            "docker_proxy_upstream_request_duration_seconds_bucket{{registry=\"{}\",le=\"+Inf\"}} {}\n\
+             docker_proxy_upstream_request_duration_seconds_sum{{registry=\"{}\"}} {}\n\
+             docker_proxy_upstream_request_duration_seconds_count{{registry=\"{}\"}} {}\n",
+            registry, host_metrics.count, registry, host_metrics.sum_secs, registry, host_metrics.count
+        ));
+    }
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_errors_total Failed upstream registry requests, by kind\n\
+         # TYPE docker_proxy_upstream_errors_total counter\n",
+    );
+    for (host, host_metrics) in hosts {
+        for kind in UpstreamError::ALL {
+            metrics.push_str(&format!(
+                "docker_proxy_upstream_errors_total{{registry=\"{}\",kind=\"{}\"}} {}\n",
+                label_value(host),
+                kind.label(),
+                host_metrics.errors(kind)
+            ));
+        }
+    }
+    metrics.push_str(
+        "# HELP docker_proxy_upstream_digest_mismatches_total Upstream blobs whose content did not match their digest\n\
+         # TYPE docker_proxy_upstream_digest_mismatches_total counter\n",
+    );
+    for (host, host_metrics) in hosts {
+        metrics.push_str(&format!(
+            "docker_proxy_upstream_digest_mismatches_total{{registry=\"{}\"}} {}\n",
+            label_value(host),
+            host_metrics.digest_mismatches
+        ));
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_render() {
+        let metrics = UpstreamMetrics::new();
+        metrics.record("ghcr.io", Duration::from_millis(3), None);
+        metrics.record("ghcr.io", Duration::from_millis(40), None);
+        metrics.record(
+            "ghcr.io",
+            Duration::from_secs(60),
+            Some(UpstreamError::Timeout),
+        );
+        metrics.record(
+            "ghcr.io",
+            Duration::from_millis(80),
+            Some(UpstreamError::ServerError),
+        );
+        metrics.record_digest_mismatch("ghcr.io");
+
+        let snapshot = metrics.snapshot();
+        let ghcr = &snapshot["ghcr.io"];
+        assert_eq!(ghcr.count, 4);
+        assert_eq!(ghcr.buckets[0], 1);
+        assert_eq!(ghcr.buckets[3], 1);
+        assert_eq!(ghcr.buckets[4], 1);
+        assert_eq!(ghcr.buckets[BUCKETS.len()], 1);
+        assert_eq!(ghcr.errors(UpstreamError::Timeout), 1);
+        assert_eq!(ghcr.errors(UpstreamError::ServerError), 1);
+        assert_eq!(ghcr.errors(UpstreamError::Auth), 0);
+        assert_eq!(ghcr.total_errors(), 2);
+        assert_eq!(ghcr.last_error, Some(UpstreamError::ServerError));
+        assert_eq!(ghcr.digest_mismatches, 1);
+
+        let text = to_prometheus(&snapshot);
+        assert!(text.contains(
+            "docker_proxy_upstream_request_duration_seconds_bucket{registry=\"ghcr.io\",le=\"0.05\"} 2\n"
+        ));
+        assert!(text.contains(
+            "docker_proxy_upstream_request_duration_seconds_bucket{registry=\"ghcr.io\",le=\"30\"} 3\n"
+        ));
+        assert!(text.contains(
+            "docker_proxy_upstream_request_duration_seconds_bucket{registry=\"ghcr.io\",le=\"+Inf\"} 4\n"
+        ));
+        assert!(text.contains(
+            "docker_proxy_upstream_request_duration_seconds_count{registry=\"ghcr.io\"} 4\n"
+        ));
+        assert!(text.contains(
+            "docker_proxy_upstream_errors_total{registry=\"ghcr.io\",kind=\"timeout\"} 1\n"
+        ));
+        assert!(
+            text.contains(
+                "docker_proxy_upstream_digest_mismatches_total{registry=\"ghcr.io\"} 1\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,245 @@
+/// Watching upstream repositories for new tags
+///
+/// Configured repositories are polled on an interval. Tags that were not
+/// present on the previous poll are reported to every `TagNotifier` (the log
+/// and, if configured, a webhook) and optionally pre-fetched into the blob
+/// cache. The first poll after startup only records a baseline.
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+
+use crate::config::WatchConfig;
+use crate::prefetch;
+use crate::proxy::DockerProxy;
+
+/// Timeout for a single webhook delivery
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// New tags detected in a watched repository
+#[derive(Debug, Clone, Serialize)]
+pub struct TagEvent {
+    pub event: &'static str,
+    pub repository: String,
+    pub tags: Vec<String>,
+    pub detected_at: u64,
+}
+
+impl TagEvent {
+    fn new_tags(repository: &str, tags: Vec<String>) -> Self {
+        Self {
+            event: "new_tags",
+            repository: repository.to_string(),
+            tags,
+            detected_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Receiver of tag events
+pub trait TagNotifier: Send + Sync {
+    fn notify<'a>(&'a self, event: &'a TagEvent) -> BoxFuture<'a, ()>;
+}
+
+/// Writes tag events to the log
+pub struct LogNotifier;
+
+impl TagNotifier for LogNotifier {
+    fn notify<'a>(&'a self, event: &'a TagEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            tracing::info!(
+                repository = %event.repository,
+                tags = ?event.tags,
+                "New upstream tags detected"
+            );
+        })
+    }
+}
+
+/// POSTs tag events as JSON to a webhook
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+        }
+    }
+}
+
+impl TagNotifier for WebhookNotifier {
+    fn notify<'a>(&'a self, event: &'a TagEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let result = self
+                .client
+                .post(&self.url)
+                .timeout(WEBHOOK_TIMEOUT)
+                .json(event)
+                .send()
+                .await;
+            match result {
+                Ok(resp) if resp.status().is_success() => {}
+                Ok(resp) => tracing::warn!(
+                    url = %self.url,
+                    status = resp.status().as_u16(),
+                    "Tag webhook rejected event"
+                ),
+                Err(e) => tracing::warn!(url = %self.url, "Tag webhook delivery failed: {}", e),
+            }
+        })
+    }
+}
+
+pub struct TagWatcher {
+    proxy: Arc<DockerProxy>,
+    config: WatchConfig,
+    notifiers: Vec<Box<dyn TagNotifier>>,
+    known: HashMap<String, BTreeSet<String>>,
+}
+
+impl TagWatcher {
+    /// Build a watcher with the notifiers enabled in `config`
+    pub fn new(proxy: Arc<DockerProxy>, config: WatchConfig) -> Self {
+        let mut notifiers: Vec<Box<dyn TagNotifier>> = vec![Box::new(LogNotifier)];
+        if !config.webhook_url.is_empty() {
+            notifiers.push(Box::new(WebhookNotifier::new(&config.webhook_url)));
+        }
+        Self {
+            proxy,
+            config,
+            notifiers,
+            known: HashMap::new(),
+        }
+    }
+
+    /// Poll forever in a background task
+    pub fn spawn(mut self) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                if self.proxy.maintenance().status().is_some() {
+                    tracing::debug!("Skipping tag watch poll during maintenance");
+                    continue;
+                }
+                self.poll().await;
+            }
+        });
+    }
+
+    async fn poll(&mut self) {
+        for repository in self.config.repositories.clone() {
+            let tags = match self.proxy.list_tags(&repository).await {
+                Ok(tags) => tags,
+                Err(e) => {
+                    tracing::warn!(repository = %repository, "Failed to list tags: {}", e);
+                    continue;
+                }
+            };
+
+            let Some(new_tags) = record_tags(&mut self.known, &repository, tags) else {
+                continue;
+            };
+            if new_tags.is_empty() {
+                continue;
+            }
+
+            let event = TagEvent::new_tags(&repository, new_tags);
+            for notifier in &self.notifiers {
+                notifier.notify(&event).await;
+            }
+
+            if self.config.prefetch {
+                for tag in &event.tags {
+                    if let Err(e) = prefetch::prefetch_image(
+                        &self.proxy,
+                        &repository,
+                        tag,
+                        &self.config.prefetch_platforms,
+                    )
+                    .await
+                    {
+                        tracing::warn!(repository = %repository, tag = %tag, "Prefetch failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Replace the known tag set of `repository`, returning the tags that are
+/// new. `None` on the first observation, which only establishes a baseline.
+fn record_tags(
+    known: &mut HashMap<String, BTreeSet<String>>,
+    repository: &str,
+    tags: Vec<String>,
+) -> Option<Vec<String>> {
+    let current: BTreeSet<String> = tags.into_iter().collect();
+    let previous = known.insert(repository.to_string(), current.clone())?;
+    Some(current.difference(&previous).cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(list: &[&str]) -> Vec<String> {
+        list.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_record_tags() {
+        let mut known = HashMap::new();
+        // first poll is the baseline
+        assert_eq!(
+            record_tags(&mut known, "library/nginx", tags(&["1.26", "1.27"])),
+            None
+        );
+        assert_eq!(
+            record_tags(&mut known, "library/nginx", tags(&["1.26", "1.27"])),
+            Some(vec![])
+        );
+        assert_eq!(
+            record_tags(
+                &mut known,
+                "library/nginx",
+                tags(&["1.27", "1.28", "latest"])
+            ),
+            Some(tags(&["1.28", "latest"]))
+        );
+        // a removed tag that comes back is reported again
+        assert_eq!(
+            record_tags(
+                &mut known,
+                "library/nginx",
+                tags(&["1.26", "1.27", "1.28", "latest"])
+            ),
+            Some(tags(&["1.26"]))
+        );
+        // repositories are tracked independently
+        assert_eq!(
+            record_tags(&mut known, "ghcr.io/owner/repo", tags(&["v1"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_event_payload() {
+        let event = TagEvent::new_tags("library/nginx", tags(&["1.28"]));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "new_tags");
+        assert_eq!(json["repository"], "library/nginx");
+        assert_eq!(json["tags"], serde_json::json!(["1.28"]));
+        assert!(json["detected_at"].as_u64().unwrap() > 0);
+    }
+}
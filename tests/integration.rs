@@ -0,0 +1,340 @@
+//! End-to-end tests against a real `registry:2` container
+//!
+//! Each test starts its own registry with testcontainers and runs the proxy
+//! binary in front of it. They need a Docker daemon and are only built with
+//! the `integration` feature:
+//!
+//! ```sh
+//! cargo test --features integration --test integration
+//! ```
+#![cfg(feature = "integration")]
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{ContainerAsync, GenericImage, ImageExt};
+
+const REGISTRY_PORT: u16 = 5000;
+const MANIFEST_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const SIGNING_KEY: &str = "integration-test-key";
+
+/// Registry container plus the base URL it is reachable at
+struct Registry {
+    _container: ContainerAsync<GenericImage>,
+    url: String,
+}
+
+async fn start_registry(htpasswd: Option<&str>) -> Registry {
+    let mut image = GenericImage::new("registry", "2")
+        .with_exposed_port(REGISTRY_PORT.tcp())
+        .with_wait_for(WaitFor::message_on_stderr("listening on"))
+        .with_env_var("REGISTRY_STORAGE_DELETE_ENABLED", "true");
+    if let Some(htpasswd) = htpasswd {
+        image = image
+            .with_env_var("REGISTRY_AUTH", "htpasswd")
+            .with_env_var("REGISTRY_AUTH_HTPASSWD_REALM", "integration")
+            .with_env_var("REGISTRY_AUTH_HTPASSWD_PATH", "/auth/htpasswd")
+            .with_copy_to("/auth/htpasswd", htpasswd.as_bytes().to_vec());
+    }
+
+    let container = image.start().await.expect("failed to start registry:2");
+    let host = container.get_host().await.expect("registry host");
+    let port = container
+        .get_host_port_ipv4(REGISTRY_PORT)
+        .await
+        .expect("registry port");
+    Registry {
+        _container: container,
+        url: format!("http://{}:{}", host, port),
+    }
+}
+
+/// The proxy binary running against a registry, killed on drop
+struct Proxy {
+    child: Child,
+    dir: PathBuf,
+    url: String,
+}
+
+impl Proxy {
+    async fn start(registry: &Registry, cache: bool) -> Self {
+        let port = free_port();
+        let dir = std::env::temp_dir().join(format!("docker-proxy-it-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("config")).unwrap();
+
+        let mut config = format!(
+            r#"
+[server]
+host = "127.0.0.1"
+port = {port}
+
+[log]
+logFilePath = "{log}"
+level = "debug"
+
+[proxy]
+default = "{registry}"
+
+[auth]
+ghcr-token = ""
+"#,
+            port = port,
+            log = dir.join("proxy.log").display(),
+            registry = registry.url,
+        );
+        if cache {
+            config.push_str(&format!(
+                "\n[cache]\nenabled = true\ndir = \"{}\"\nsigning_key = \"{}\"\n",
+                dir.join("cache").display(),
+                SIGNING_KEY
+            ));
+        }
+        std::fs::write(dir.join("config/config.toml"), config).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_docker-proxy"))
+            .current_dir(&dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start docker-proxy");
+        let proxy = Self {
+            child,
+            dir,
+            url: format!("http://127.0.0.1:{}", port),
+        };
+        proxy.wait_ready().await;
+        proxy
+    }
+
+    async fn wait_ready(&self) {
+        let client = reqwest::Client::new();
+        for _ in 0..100 {
+            if let Ok(resp) = client.get(format!("{}/healthz", self.url)).send().await
+                && resp.status().is_success()
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("docker-proxy did not become ready");
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .map(|a| a.port())
+        .expect("no free port")
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+/// Monolithic blob upload (POST then PUT) against `base`, which may be the
+/// registry itself or the proxy
+async fn push_blob(client: &reqwest::Client, base: &str, repository: &str, data: &[u8]) -> String {
+    let digest = sha256_digest(data);
+    let resp = client
+        .post(format!("{}/v2/{}/blobs/uploads/", base, repository))
+        .header("Content-Length", "0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 202, "upload init failed");
+    let location = resp.headers()["location"].to_str().unwrap().to_string();
+    let location = reqwest::Url::parse(base).unwrap().join(&location).unwrap();
+
+    let separator = if location.query().is_some() { '&' } else { '?' };
+    let resp = client
+        .put(format!("{}{}digest={}", location, separator, digest))
+        .header("Content-Type", "application/octet-stream")
+        .body(data.to_vec())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201, "upload completion failed");
+    digest
+}
+
+/// Push a single-layer image as `repository:tag`, returning (manifest digest, layer)
+async fn push_image(
+    client: &reqwest::Client,
+    base: &str,
+    repository: &str,
+    tag: &str,
+) -> (String, Vec<u8>) {
+    let config =
+        br#"{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+    let layer: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+    let config_digest = push_blob(client, base, repository, config).await;
+    let layer_digest = push_blob(client, base, repository, &layer).await;
+
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST_TYPE,
+        "config": {
+            "mediaType": "application/vnd.docker.container.image.v1+json",
+            "digest": config_digest,
+            "size": config.len(),
+        },
+        "layers": [{
+            "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+            "digest": layer_digest,
+            "size": layer.len(),
+        }],
+    })
+    .to_string();
+    let resp = client
+        .put(format!("{}/v2/{}/manifests/{}", base, repository, tag))
+        .header("Content-Type", MANIFEST_TYPE)
+        .body(manifest.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201, "manifest push failed");
+    (sha256_digest(manifest.as_bytes()), layer)
+}
+
+#[tokio::test]
+async fn test_pull_through() {
+    let registry = start_registry(None).await;
+    let client = reqwest::Client::new();
+    let (_, layer) = push_image(&client, &registry.url, "fixtures/app", "v1").await;
+    let proxy = Proxy::start(&registry, false).await;
+
+    let resp = client
+        .get(format!("{}/v2/fixtures/app/manifests/v1", proxy.url))
+        .header("Accept", MANIFEST_TYPE)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let manifest: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(manifest["schemaVersion"], 2);
+
+    let layer_digest = manifest["layers"][0]["digest"].as_str().unwrap();
+    let resp = client
+        .get(format!(
+            "{}/v2/fixtures/app/blobs/{}",
+            proxy.url, layer_digest
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.bytes().await.unwrap().as_ref(), layer.as_slice());
+
+    // unknown tags are passed through as errors
+    let resp = client
+        .get(format!("{}/v2/fixtures/app/manifests/missing", proxy.url))
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_client_error());
+}
+
+#[tokio::test]
+async fn test_blob_cache() {
+    let registry = start_registry(None).await;
+    let client = reqwest::Client::new();
+    let (_, layer) = push_image(&client, &registry.url, "fixtures/cached", "v1").await;
+    let layer_digest = sha256_digest(&layer);
+    let proxy = Proxy::start(&registry, true).await;
+    let url = format!("{}/v2/fixtures/cached/blobs/{}", proxy.url, layer_digest);
+
+    // the first pull streams from upstream and fills the cache
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers().get("x-docker-proxy-signature").is_none());
+    assert_eq!(resp.bytes().await.unwrap().as_ref(), layer.as_slice());
+
+    // cache hits are signed and support ranges
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers().get("x-docker-proxy-signature").is_some());
+    assert_eq!(resp.bytes().await.unwrap().as_ref(), layer.as_slice());
+
+    let resp = client
+        .get(&url)
+        .header("Range", "bytes=100-199")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.bytes().await.unwrap().as_ref(), &layer[100..200]);
+}
+
+#[tokio::test]
+async fn test_push_through_proxy() {
+    let registry = start_registry(None).await;
+    let proxy = Proxy::start(&registry, false).await;
+    let client = reqwest::Client::new();
+
+    let (manifest_digest, layer) = push_image(&client, &proxy.url, "fixtures/pushed", "v1").await;
+
+    // everything pushed through the proxy is visible on the registry
+    let resp = client
+        .head(format!(
+            "{}/v2/fixtures/pushed/blobs/{}",
+            registry.url,
+            sha256_digest(&layer)
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let resp = client
+        .get(format!("{}/v2/fixtures/pushed/manifests/v1", registry.url))
+        .header("Accept", MANIFEST_TYPE)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers()["docker-content-digest"].to_str().unwrap(),
+        manifest_digest
+    );
+}
+
+#[tokio::test]
+async fn test_auth_failures_are_reported() {
+    let hash = bcrypt::hash("secret", 5).unwrap();
+    let registry = start_registry(Some(&format!("user:{}\n", hash))).await;
+    let proxy = Proxy::start(&registry, false).await;
+    let client = reqwest::Client::new();
+
+    let resp = client
+        .get(format!("{}/v2/fixtures/private/manifests/v1", proxy.url))
+        .send()
+        .await
+        .unwrap();
+    assert!(!resp.status().is_success());
+
+    let status: serde_json::Value = client
+        .get(format!("{}/api/auth/status", proxy.url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let upstreams = status["upstreams"].as_array().unwrap();
+    assert_eq!(upstreams.len(), 1);
+    assert!(upstreams[0]["total_failures"].as_u64().unwrap() >= 1);
+    assert_eq!(upstreams[0]["recent"][0]["kind"], "unauthorized");
+}
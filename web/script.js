@@ -13,12 +13,21 @@
         error: document.getElementById('dockerImageError'),
         clearButton: document.getElementById('clearInputButton'),
         versionBadge: document.getElementById('versionBadge'),
+        uploadList: document.getElementById('uploadList'),
+        requestRate: document.getElementById('requestRate'),
+        cacheUsage: document.getElementById('cacheUsage'),
+        topImageList: document.getElementById('topImageList'),
+        upstreamList: document.getElementById('upstreamList'),
     };
 
     // ============ 常量配置 ============
     const CONFIG = {
         TOAST_DURATION: 3000,
-        API_HEALTH: '/healthz',
+        API_HEALTH: 'healthz',
+        API_UPLOADS: 'api/uploads',
+        UPLOADS_POLL_INTERVAL: 5000,
+        API_DASHBOARD: 'api/dashboard',
+        DASHBOARD_POLL_INTERVAL: 10000,
         DEBOUNCE_DELAY: 300,
     };
 
@@ -263,6 +272,98 @@
         }
     }
 
+    /**
+     * 格式化字节数
+     */
+    function formatBytes(bytes) {
+        const units = ['B', 'KB', 'MB', 'GB'];
+        let value = bytes;
+        let unit = 0;
+        while (value >= 1024 && unit < units.length - 1) {
+            value /= 1024;
+            unit++;
+        }
+        return `${value.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+    }
+
+    /**
+     * 用文本行填充列表，无数据时显示占位文本
+     */
+    function fillList(list, lines, emptyText) {
+        list.replaceChildren();
+        if (lines.length === 0) {
+            const item = document.createElement('li');
+            item.className = 'info-card__text';
+            item.textContent = emptyText;
+            list.appendChild(item);
+            return;
+        }
+        for (const line of lines) {
+            const item = document.createElement('li');
+            item.className = 'upload-list__item';
+            item.textContent = line;
+            list.appendChild(item);
+        }
+    }
+
+    /**
+     * 获取进行中的推送并更新进度列表
+     */
+    async function fetchUploads() {
+        if (!DOM.uploadList) return;
+        try {
+            const resp = await fetch(CONFIG.API_UPLOADS, { cache: 'no-store' });
+            if (!resp.ok) throw new Error(`status ${resp.status}`);
+            const data = await resp.json();
+            const uploads = Array.isArray(data?.uploads) ? data.uploads : [];
+
+            fillList(
+                DOM.uploadList,
+                uploads.map((upload) => `${upload.name}（${String(upload.uuid).slice(0, 8)}）已接收 ${formatBytes(upload.offset)}`),
+                '暂无进行中的推送',
+            );
+        } catch (err) {
+            console.warn('无法获取推送进度:', err);
+        }
+    }
+
+    /**
+     * 获取实时面板数据：请求速率、缓存、热门镜像与上游状态
+     */
+    async function fetchDashboard() {
+        if (!DOM.requestRate) return;
+        try {
+            const resp = await fetch(CONFIG.API_DASHBOARD, { cache: 'no-store' });
+            if (!resp.ok) throw new Error(`status ${resp.status}`);
+            const data = await resp.json();
+
+            const minutes = Array.isArray(data?.requests?.per_minute) ? data.requests.per_minute : [];
+            const hourly = minutes.reduce((sum, m) => sum + m.requests, 0);
+            DOM.requestRate.textContent = `${data?.requests?.last_minute ?? 0} 次/分钟（近一小时 ${hourly} 次）`;
+
+            const cache = data?.cache;
+            DOM.cacheUsage.textContent = cache
+                ? `${formatBytes(cache.bytes)}${cache.max_bytes ? ` / ${formatBytes(cache.max_bytes)}` : ''}，命中率 ${(cache.hit_ratio * 100).toFixed(1)}%`
+                : '未启用';
+
+            const images = Array.isArray(data?.top_images) ? data.top_images : [];
+            fillList(
+                DOM.topImageList,
+                images.map((image) => `${image.repository}：${image.pulls} 次，${formatBytes(image.bytes)}`),
+                data?.top_images ? '暂无数据' : '未启用拉取统计',
+            );
+
+            const upstreams = Array.isArray(data?.upstreams) ? data.upstreams : [];
+            fillList(
+                DOM.upstreamList,
+                upstreams.map((u) => `${u.status === 'healthy' ? '✓' : '✗'} ${u.registry}：${u.avg_latency_ms.toFixed(0)} ms，错误率 ${(u.error_rate * 100).toFixed(1)}%`),
+                '暂无请求',
+            );
+        } catch (err) {
+            console.warn('无法获取面板数据:', err);
+        }
+    }
+
     // ============ 事件监听器 ============
     function setupEventListeners() {
         DOM.form.addEventListener('submit', (e) => {
@@ -295,6 +396,10 @@
         setupEventListeners();
         updateClearButtonVisibility();
         fetchHealthVersion();
+        fetchUploads();
+        setInterval(fetchUploads, CONFIG.UPLOADS_POLL_INTERVAL);
+        fetchDashboard();
+        setInterval(fetchDashboard, CONFIG.DASHBOARD_POLL_INTERVAL);
     }
 
     // 页面加载完成后初始化